@@ -1,2 +1,84 @@
+pub mod access;
+pub mod aggregate;
 pub mod arithmetic;
+pub mod array;
+pub mod audit;
+pub mod autosave;
+pub mod batch;
+pub mod bench;
+pub mod budget;
+pub mod builder;
+pub mod bytecode;
+pub mod clipboard;
+pub mod compare;
+pub mod compat;
+pub mod conditional;
+pub mod conformance;
+pub mod consolidate;
+#[cfg(feature = "connectors")]
+pub mod connectors;
+pub mod contract;
+pub mod cost;
+pub mod csv;
+pub mod currency;
+pub mod datasource;
+pub mod dedup;
+pub mod dependency;
+pub mod diff;
+pub mod encoding;
+pub mod eval;
+pub mod export;
+pub mod feed;
+pub mod fixed_width;
+pub mod format;
+pub mod freeze;
+pub mod functions;
+#[cfg(feature = "gpu")]
+pub mod gpu;
+pub mod graph;
+pub mod heatmap;
+pub mod import;
+pub mod import_profile;
+pub mod journal;
 pub mod kernel;
+pub mod lookup;
+pub mod matcher;
+pub mod memory;
+pub mod metrics;
+pub mod naming;
+pub mod obfuscate;
+pub mod outline;
+pub mod parser;
+pub mod pool;
+pub mod profile;
+pub mod range;
+pub mod recorder;
+pub mod refactor;
+pub mod refresh;
+pub mod rows;
+pub mod schedule;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+pub mod serialize;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod settings;
+pub mod signature;
+mod shift_jis;
+pub mod snapshot;
+pub mod sparkline;
+pub mod split;
+pub mod strings;
+pub mod structure;
+pub mod tail;
+pub mod template;
+pub mod text;
+pub mod theme;
+pub mod transform;
+pub mod units;
+pub mod value_parser;
+pub mod warning;
+pub mod window;
+pub mod workbook;
+pub mod worksheet;
+mod xml;
@@ -0,0 +1,350 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{CellId, Formula, Kernel, Value};
+use super::serialize::value_to_raw;
+use super::workbook::{Workbook, WorkbookError};
+use super::worksheet::{SheetError, Worksheet};
+use std::collections::HashSet;
+
+/// Permissions tags ranges of a sheet with permission labels. A cell may
+/// carry several labels when tagged ranges overlap.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Permissions {
+    ranges: Vec<(CellId, CellId, String)>,
+}
+
+fn contains(start: CellId, end: CellId, cell_id: CellId) -> bool {
+    (start.row()..=end.row()).contains(&cell_id.row())
+        && (start.col()..=end.col()).contains(&cell_id.col())
+}
+
+impl Permissions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tag the range from `start` to `end` with `label`.
+    pub fn tag(&mut self, start: CellId, end: CellId, label: &str) {
+        let (top, bottom) = (start.row().min(end.row()), start.row().max(end.row()));
+        let (left, right) = (start.col().min(end.col()), start.col().max(end.col()));
+        self.ranges.push((CellId::new(top, left), CellId::new(bottom, right), label.to_string()));
+    }
+
+    /// Remove every range tagged with `label`.
+    pub fn untag(&mut self, label: &str) {
+        self.ranges.retain(|(_, _, existing)| existing != label);
+    }
+
+    /// Get the labels of a cell.
+    pub fn labels(&self, cell_id: CellId) -> impl Iterator<Item=&str> {
+        self.ranges.iter()
+            .filter(move |(start, end, _)| contains(*start, *end, cell_id))
+            .map(|(_, _, label)| label.as_str())
+    }
+
+    /// Iterate the tagged ranges.
+    pub fn ranges(&self) -> impl Iterator<Item=(CellId, CellId, &str)> {
+        self.ranges.iter().map(|(start, end, label)| (*start, *end, label.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+/// Protection is whether a sheet is protected, and the ranges whose
+/// formulas protection hides, like the `hidden` flag of cell styles in
+/// spreadsheets. Formulas are only hidden while the sheet is protected.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Protection {
+    protected: bool,
+    hidden: Vec<(CellId, CellId)>,
+}
+
+impl Protection {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn protect(&mut self) {
+        self.protected = true;
+    }
+
+    pub fn unprotect(&mut self) {
+        self.protected = false;
+    }
+
+    pub fn is_protected(&self) -> bool {
+        self.protected
+    }
+
+    /// Flag the formulas of the range from `start` to `end` as hidden.
+    pub fn hide_formulas(&mut self, start: CellId, end: CellId) {
+        let (top, bottom) = (start.row().min(end.row()), start.row().max(end.row()));
+        let (left, right) = (start.col().min(end.col()), start.col().max(end.col()));
+        self.hidden.push((CellId::new(top, left), CellId::new(bottom, right)));
+    }
+
+    /// Clear the hidden flag of every range containing a cell.
+    pub fn show_formulas(&mut self, cell_id: CellId) {
+        self.hidden.retain(|(start, end)| !contains(*start, *end, cell_id));
+    }
+
+    /// Whether the formula of a cell is flagged as hidden, whether or not
+    /// the sheet is protected.
+    pub fn is_flagged(&self, cell_id: CellId) -> bool {
+        self.hidden.iter().any(|(start, end)| contains(*start, *end, cell_id))
+    }
+
+    /// Whether the formula of a cell is hidden: flagged on a protected
+    /// sheet.
+    pub fn is_formula_hidden(&self, cell_id: CellId) -> bool {
+        self.protected && self.is_flagged(cell_id)
+    }
+}
+
+/// Redaction is what a cell hidden from a consumer is replaced with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Redaction {
+    /// Leave the cell empty.
+    #[default]
+    Blank,
+    /// Replace the cell with a hash of its contents keyed with a secret, so
+    /// equal values can still be matched up without being revealed. Without
+    /// the key, values which can be guessed, like round salaries or names
+    /// from a staff list, can't be confirmed by hashing the guesses. Exports
+    /// with the same key hash values alike; keep the key secret and change
+    /// it to stop exports from being matched up with each other.
+    Hash([u8; 16]),
+}
+
+/// Hash data with SipHash-2-4 under a 128-bit key, which is stable across
+/// runs and platforms.
+fn siphash(key: &[u8; 16], data: &[u8]) -> u64 {
+    #[inline]
+    fn round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+    let k0 = u64::from_le_bytes(key[..8].try_into().expect("the key has 16 bytes"));
+    let k1 = u64::from_le_bytes(key[8..].try_into().expect("the key has 16 bytes"));
+    let mut v = [k0 ^ 0x736f6d6570736575, k1 ^ 0x646f72616e646f6d, k0 ^ 0x6c7967656e657261, k1 ^ 0x7465646279746573];
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let m = u64::from_le_bytes(chunk.try_into().expect("chunks have 8 bytes"));
+        v[3] ^= m;
+        round(&mut v);
+        round(&mut v);
+        v[0] ^= m;
+    }
+    let mut last = [0; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    let m = u64::from_le_bytes(last) | (data.len() as u64) << 56;
+    v[3] ^= m;
+    round(&mut v);
+    round(&mut v);
+    v[0] ^= m;
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+/// AccessPolicy decides which cells a consumer of an export may see. A cell
+/// is visible when every one of its labels is allowed; untagged cells are
+/// always visible. Formulas referring to hidden cells are exported as their
+/// computed values so the hidden inputs can't be read from them, as are
+/// formulas protection hides if the policy is hiding formulas.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccessPolicy {
+    allowed: HashSet<String>,
+    redaction: Redaction,
+    hide_formulas: bool,
+}
+
+impl AccessPolicy {
+    pub fn new(redaction: Redaction) -> Self {
+        Self{allowed: HashSet::new(), redaction, hide_formulas: false}
+    }
+
+    pub fn allow(mut self, label: &str) -> Self {
+        self.allowed.insert(label.to_string());
+        self
+    }
+
+    /// Export the formulas protection hides as their computed values.
+    pub fn hiding_formulas(mut self) -> Self {
+        self.hide_formulas = true;
+        self
+    }
+
+    pub fn redaction(&self) -> Redaction {
+        self.redaction
+    }
+
+    pub fn can_see<T: Arithmetic>(&self, sheet: &Worksheet<T>, cell_id: CellId) -> bool {
+        sheet.permissions().labels(cell_id).all(|label| self.allowed.contains(label))
+    }
+
+    fn redact_raw(&self, raw: &str) -> String {
+        match self.redaction {
+            Redaction::Blank => String::new(),
+            Redaction::Hash(key) => format!("'#{:016x}", siphash(&key, raw.as_bytes())),
+        }
+    }
+
+    /// Whether a formula on a sheet refers to a cell this policy hides.
+    /// `lookup` finds the sheet a qualified reference refers to.
+    fn refers_to_hidden<'a, T: Arithmetic>(
+        &self,
+        formula: &Formula<T>,
+        own: &'a Worksheet<T>,
+        lookup: &dyn Fn(&str) -> Option<&'a Worksheet<T>>,
+    ) -> bool {
+        let mut hidden = false;
+        formula.clone().rewrite_references(&mut |sheet, reference| {
+            let target = match sheet {
+                None => Some(own),
+                Some(name) => lookup(name),
+            };
+            let Some(target) = target else { return };
+            let range = match reference {
+                Formula::CellRef(cell_id) => (*cell_id, *cell_id),
+                Formula::CellRange(start, end) => (*start, *end),
+                _ => return,
+            };
+            if target.permissions().is_empty() {
+                return;
+            }
+            hidden |= CellId::range(range.0, range.1).any(|cell_id| !self.can_see(target, cell_id));
+        });
+        hidden
+    }
+
+    /// Build the redacted copy of a sheet. `evaluate` computes the value of
+    /// a formula cell which has to be cached.
+    fn redact_with<'a, T, E>(
+        &self,
+        sheet: &'a Worksheet<T>,
+        lookup: &dyn Fn(&str) -> Option<&'a Worksheet<T>>,
+        evaluate: &dyn Fn(CellId) -> Result<Value<T>, E>,
+    ) -> Result<Worksheet<T>, E>
+    where T: Arithmetic {
+        let mut redacted = Worksheet::new();
+        for cell_id in sheet.cell_ids() {
+            let cell = sheet.cell(cell_id).expect("cell ids come from the sheet");
+            let raw = if !self.can_see(sheet, cell_id) {
+                self.redact_raw(cell.raw())
+            } else {
+                match cell.formula() {
+                    Some(_) if self.hide_formulas && sheet.protection().is_formula_hidden(cell_id) => value_to_raw(&evaluate(cell_id)?),
+                    Some(formula) if self.refers_to_hidden(formula, sheet, lookup) => value_to_raw(&evaluate(cell_id)?),
+                    _ => cell.raw().to_string(),
+                }
+            };
+            redacted.set_cell(cell_id, raw);
+        }
+        Ok(redacted)
+    }
+
+    /// Redact a single sheet for export.
+    pub fn redact_sheet<T: Arithmetic>(&self, sheet: &Worksheet<T>) -> Result<Worksheet<T>, SheetError> {
+        self.redact_with(sheet, &|_| None, &|cell_id| sheet.evaluate_cell(cell_id))
+    }
+
+    /// Redact every sheet of a workbook for export. Formulas referring to
+    /// hidden cells on other sheets are cached as well.
+    pub fn redact_workbook<T: Arithmetic>(&self, workbook: &Workbook<T>) -> Result<Workbook<T>, WorkbookError> {
+        let mut redacted = Workbook::new();
+        for (name, sheet) in workbook.sheets() {
+            let sheet = self.redact_with(
+                sheet,
+                &|other| workbook.sheet(other),
+                &|cell_id| workbook.evaluate_cell(name, cell_id),
+            )?;
+            redacted.insert_sheet(name, sheet)?;
+        }
+        for query in workbook.queries() {
+            redacted.add_query(query.clone());
+        }
+        Ok(redacted)
+    }
+}
+
+impl<T: Arithmetic> Worksheet<T> {
+    /// Get the contents of a cell as the formula bar shows them: the raw
+    /// contents, or the computed value of a formula protection hides.
+    pub fn contents(&self, cell_id: CellId) -> Result<Option<String>, SheetError> {
+        let Some(cell) = self.cell(cell_id) else { return Ok(None) };
+        match cell.formula() {
+            Some(_) if self.protection().is_formula_hidden(cell_id) => Ok(Some(value_to_raw(&self.evaluate_cell(cell_id)?))),
+            _ => Ok(Some(cell.raw().to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn siphash_matches_reference_vectors() {
+        let key = std::array::from_fn(|index| index as u8);
+        assert_eq!(siphash(&key, b""), 0x726fdb47dd0e0e31);
+        assert_eq!(siphash(&key, &(0..15).collect::<Vec<u8>>()), 0xa129ca6149be45e5);
+        assert_eq!(siphash(&key, &(0..8).collect::<Vec<u8>>()), 0x93f5f5799a932462);
+    }
+
+    #[test]
+    fn hashes_hidden_cells_with_the_key() {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), "52000".to_string());
+        sheet.set_cell(CellId::new(1, 0), "52000".to_string());
+        sheet.set_cell(CellId::new(0, 1), "=A1*2".to_string());
+        sheet.permissions_mut().tag(CellId::new(0, 0), CellId::new(1, 0), "salary");
+        let redact = |key| AccessPolicy::new(Redaction::Hash(key)).redact_sheet(&sheet).unwrap();
+        let raw = |sheet: &Worksheet, row, col| sheet.cell(CellId::new(row, col)).map(|cell| cell.raw().to_string());
+        let (first, second) = (redact([1; 16]), redact([2; 16]));
+        assert_eq!(raw(&first, 0, 0), raw(&first, 1, 0));
+        assert_ne!(raw(&first, 0, 0), raw(&second, 0, 0));
+        assert_eq!(raw(&first, 0, 0), Some(format!("'#{:016x}", siphash(&[1; 16], b"52000"))));
+        assert_eq!(raw(&first, 0, 1).as_deref(), Some("104000"));
+        assert!(AccessPolicy::new(Redaction::Blank).redact_sheet(&sheet).unwrap().cell(CellId::new(0, 0)).is_none());
+    }
+
+    #[test]
+    fn protection_hides_flagged_formulas() {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), "21".to_string());
+        sheet.set_cell(CellId::new(0, 1), "=A1*2".to_string());
+        sheet.set_cell(CellId::new(0, 2), "=A1+1".to_string());
+        sheet.protection_mut().hide_formulas(CellId::new(1, 1), CellId::new(0, 1));
+        assert!(sheet.protection().is_flagged(CellId::new(0, 1)));
+        assert_eq!(sheet.contents(CellId::new(0, 1)).unwrap().as_deref(), Some("=A1*2"));
+        sheet.protection_mut().protect();
+        assert_eq!(sheet.contents(CellId::new(0, 1)).unwrap().as_deref(), Some("42"));
+        assert_eq!(sheet.contents(CellId::new(0, 2)).unwrap().as_deref(), Some("=A1+1"));
+        assert_eq!(sheet.contents(CellId::new(5, 5)).unwrap(), None);
+
+        let raw = |sheet: &Worksheet, col| sheet.cell(CellId::new(0, col)).map(|cell| cell.raw().to_string());
+        let exported = AccessPolicy::new(Redaction::Blank).hiding_formulas().redact_sheet(&sheet).unwrap();
+        assert_eq!(raw(&exported, 1).as_deref(), Some("42"));
+        assert_eq!(raw(&exported, 2).as_deref(), Some("=A1+1"));
+        let kept = AccessPolicy::new(Redaction::Blank).redact_sheet(&sheet).unwrap();
+        assert_eq!(raw(&kept, 1).as_deref(), Some("=A1*2"));
+
+        sheet.protection_mut().show_formulas(CellId::new(1, 1));
+        assert!(!sheet.protection().is_formula_hidden(CellId::new(0, 1)));
+        sheet.protection_mut().hide_formulas(CellId::new(0, 1), CellId::new(0, 1));
+        sheet.protection_mut().unprotect();
+        assert!(!sheet.protection().is_formula_hidden(CellId::new(0, 1)));
+    }
+}
@@ -0,0 +1,275 @@
+use super::arithmetic::{Arithmetic, Floating};
+use super::kernel::{CellError, Primitive, Value};
+use std::cmp::Ordering;
+
+/// AggregateFunction is the calculation `AGGREGATE` performs, chosen by its
+/// first argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AggregateFunction {
+    Average,
+    Count,
+    CountA,
+    Max,
+    Min,
+    Product,
+    StdevSample,
+    StdevPopulation,
+    Sum,
+    VarSample,
+    VarPopulation,
+    Median,
+    Mode,
+    Large,
+    Small,
+    PercentileInc,
+    QuartileInc,
+    PercentileExc,
+    QuartileExc,
+}
+
+const FUNCTIONS: [AggregateFunction; 19] = [
+    AggregateFunction::Average,
+    AggregateFunction::Count,
+    AggregateFunction::CountA,
+    AggregateFunction::Max,
+    AggregateFunction::Min,
+    AggregateFunction::Product,
+    AggregateFunction::StdevSample,
+    AggregateFunction::StdevPopulation,
+    AggregateFunction::Sum,
+    AggregateFunction::VarSample,
+    AggregateFunction::VarPopulation,
+    AggregateFunction::Median,
+    AggregateFunction::Mode,
+    AggregateFunction::Large,
+    AggregateFunction::Small,
+    AggregateFunction::PercentileInc,
+    AggregateFunction::QuartileInc,
+    AggregateFunction::PercentileExc,
+    AggregateFunction::QuartileExc,
+];
+
+impl AggregateFunction {
+    /// Get the function of a function number from 1 to 19.
+    pub fn from_number(number: i64) -> Option<Self> {
+        usize::try_from(number).ok()
+            .and_then(|number| number.checked_sub(1))
+            .and_then(|index| FUNCTIONS.get(index))
+            .copied()
+    }
+
+    /// Whether the function takes a `k` argument after a single array,
+    /// like `LARGE`.
+    pub fn takes_k(&self) -> bool {
+        matches!(self, Self::Large | Self::Small | Self::PercentileInc | Self::QuartileInc | Self::PercentileExc | Self::QuartileExc)
+    }
+
+    /// Apply the function to the values left after the options skipped
+    /// some. Only numbers count, except for `COUNTA`.
+    pub fn apply<T: Arithmetic>(&self, values: &[Value<T>], k: Option<T>) -> Result<T, CellError> {
+        let mut numbers = values.iter()
+            .filter_map(|value| match value {
+                Value::Primitive(Primitive::Number(number)) => Some(number.value()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let count = |len: usize| -> T { Floating::from_f64(len as f64) };
+        let zero = count(0);
+        let sum = numbers.iter().fold(zero, |sum, x| sum + *x);
+        match self {
+            Self::Average if numbers.is_empty() => Err(CellError::Div0),
+            Self::Average => Ok(sum / count(numbers.len())),
+            Self::Count => Ok(count(numbers.len())),
+            Self::CountA => Ok(count(values.iter().filter(|value| !matches!(value, Value::Empty)).count())),
+            Self::Max => Ok(numbers.into_iter().reduce(|max, x| if x > max { x } else { max }).unwrap_or(zero)),
+            Self::Min => Ok(numbers.into_iter().reduce(|min, x| if x < min { x } else { min }).unwrap_or(zero)),
+            Self::Product => Ok(numbers.into_iter().fold(count(1), |product, x| product * x)),
+            Self::Sum => Ok(sum),
+            Self::StdevSample | Self::StdevPopulation | Self::VarSample | Self::VarPopulation => {
+                let sample = matches!(self, Self::StdevSample | Self::VarSample);
+                let degrees = numbers.len().checked_sub(sample as usize).filter(|degrees| *degrees > 0).ok_or(CellError::Div0)?;
+                let mean = sum / count(numbers.len());
+                let variance = numbers.iter().fold(zero, |sum, x| sum + (*x - mean) * (*x - mean)) / count(degrees);
+                Ok(match self {
+                    Self::StdevSample | Self::StdevPopulation => variance.sqrt(),
+                    _ => variance,
+                })
+            },
+            Self::Mode => mode(&numbers),
+            _ => {
+                sort(&mut numbers);
+                if numbers.is_empty() {
+                    return Err(CellError::Num);
+                }
+                let k = k.unwrap_or(zero);
+                match self {
+                    Self::Median => percentile(&numbers, Floating::from_f64(0.5), false),
+                    Self::Large | Self::Small => {
+                        let k = k.to_f64().ceil();
+                        if k < 1.0 || k > numbers.len() as f64 {
+                            return Err(CellError::Num);
+                        }
+                        let index = k as usize - 1;
+                        Ok(match self {
+                            Self::Large => numbers[numbers.len() - 1 - index],
+                            _ => numbers[index],
+                        })
+                    },
+                    Self::PercentileInc => percentile(&numbers, k, false),
+                    Self::PercentileExc => percentile(&numbers, k, true),
+                    Self::QuartileInc | Self::QuartileExc => {
+                        let quartile = k.to_f64().trunc();
+                        let valid = match self {
+                            Self::QuartileInc => (0.0..=4.0).contains(&quartile),
+                            _ => (1.0..=3.0).contains(&quartile),
+                        };
+                        if !valid {
+                            return Err(CellError::Num);
+                        }
+                        percentile(&numbers, Floating::from_f64(quartile / 4.0), *self == Self::QuartileExc)
+                    },
+                    _ => unreachable!("other functions are handled above"),
+                }
+            },
+        }
+    }
+}
+
+fn sort<T: Arithmetic>(numbers: &mut [T]) {
+    numbers.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+}
+
+/// Get the most frequent number, the first of them if several are. Numbers
+/// which never repeat have no mode.
+fn mode<T: Arithmetic>(numbers: &[T]) -> Result<T, CellError> {
+    let mut best = None;
+    let mut best_count = 1;
+    for (index, x) in numbers.iter().enumerate() {
+        let count = numbers[index..].iter().filter(|y| *y == x).count();
+        if count > best_count {
+            best = Some(*x);
+            best_count = count;
+        }
+    }
+    best.ok_or(CellError::NA)
+}
+
+/// Interpolate the `k`th percentile of sorted numbers, inclusively of the
+/// smallest and largest like `PERCENTILE.INC` or exclusively like
+/// `PERCENTILE.EXC`.
+fn percentile<T: Arithmetic>(sorted: &[T], k: T, exclusive: bool) -> Result<T, CellError> {
+    let k = k.to_f64();
+    let n = sorted.len() as f64;
+    let rank = match exclusive {
+        false if (0.0..=1.0).contains(&k) => k * (n - 1.0),
+        true if k > 0.0 && k < 1.0 => k * (n + 1.0) - 1.0,
+        _ => return Err(CellError::Num),
+    };
+    if rank < 0.0 || rank > n - 1.0 {
+        return Err(CellError::Num);
+    }
+    let below = rank.floor() as usize;
+    let fraction: T = Floating::from_f64(rank - rank.floor());
+    Ok(match sorted.get(below + 1) {
+        Some(above) => sorted[below] + (*above - sorted[below]) * fraction,
+        None => sorted[below],
+    })
+}
+
+/// AggregateOptions is what `AGGREGATE` skips in its references, chosen by
+/// its second argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AggregateOptions {
+    /// Skip cells which are themselves aggregates, so subtotals are not
+    /// counted twice.
+    pub ignore_nested: bool,
+    /// Skip rows hidden by collapsed outline groups.
+    pub ignore_hidden: bool,
+    /// Skip errors, such as `#DIV/0!` from empty subtotals, instead of
+    /// returning the first.
+    pub ignore_errors: bool,
+}
+
+impl AggregateOptions {
+    /// Get the options of an option number from 0 to 7.
+    pub fn from_number(number: i64) -> Option<Self> {
+        let (ignore_nested, ignore_hidden, ignore_errors) = match number {
+            0 => (true, false, false),
+            1 => (true, true, false),
+            2 => (true, false, true),
+            3 => (true, true, true),
+            4 => (false, false, false),
+            5 => (false, true, false),
+            6 => (false, false, true),
+            7 => (false, true, true),
+            _ => return None,
+        };
+        Some(Self{ignore_nested, ignore_hidden, ignore_errors})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::Numeric;
+
+    fn numbers(numbers: &[f64]) -> Vec<Value<f64>> {
+        numbers.iter().map(|x| Value::Primitive(Primitive::Number(Numeric::new(*x, None)))).collect()
+    }
+
+    fn apply(number: i64, values: &[Value<f64>], k: Option<f64>) -> Result<f64, CellError> {
+        AggregateFunction::from_number(number).unwrap().apply(values, k)
+    }
+
+    #[test]
+    fn function_numbers_follow_aggregate() {
+        assert_eq!(AggregateFunction::from_number(1), Some(AggregateFunction::Average));
+        assert_eq!(AggregateFunction::from_number(9), Some(AggregateFunction::Sum));
+        assert_eq!(AggregateFunction::from_number(19), Some(AggregateFunction::QuartileExc));
+        assert!(AggregateFunction::from_number(0).is_none() && AggregateFunction::from_number(20).is_none());
+        assert!(AggregateFunction::Large.takes_k() && !AggregateFunction::Median.takes_k());
+        assert_eq!(AggregateOptions::from_number(6), Some(AggregateOptions{ignore_nested: false, ignore_hidden: false, ignore_errors: true}));
+        assert!(AggregateOptions::from_number(8).is_none());
+    }
+
+    #[test]
+    fn functions_skip_values_which_are_not_numbers() {
+        let mut values = numbers(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]);
+        values.push(Value::Primitive(Primitive::Text("x".to_string())));
+        values.push(Value::Empty);
+        assert_eq!(apply(1, &values, None), Ok(5.0));
+        assert_eq!(apply(2, &values, None), Ok(8.0));
+        assert_eq!(apply(3, &values, None), Ok(9.0));
+        assert_eq!(apply(4, &values, None), Ok(9.0));
+        assert_eq!(apply(5, &values, None), Ok(2.0));
+        assert_eq!(apply(8, &values, None), Ok(2.0));
+        assert_eq!(apply(11, &values, None), Ok(4.0));
+        assert_eq!(apply(10, &values, None), Ok(32.0 / 7.0));
+        assert_eq!(apply(12, &values, None), Ok(4.5));
+        assert_eq!(apply(13, &values, None), Ok(4.0));
+        assert_eq!(apply(14, &values, Some(2.0)), Ok(7.0));
+        assert_eq!(apply(15, &values, Some(2.0)), Ok(4.0));
+    }
+
+    #[test]
+    fn percentiles_interpolate() {
+        let values = numbers(&[4.0, 1.0, 3.0, 2.0]);
+        assert_eq!(apply(16, &values, Some(0.25)), Ok(1.75));
+        assert_eq!(apply(18, &values, Some(0.25)), Ok(1.25));
+        assert_eq!(apply(17, &values, Some(2.0)), Ok(2.5));
+        assert_eq!(apply(19, &values, Some(1.0)), Ok(1.25));
+        assert_eq!(apply(17, &values, Some(5.0)), Err(CellError::Num));
+        assert_eq!(apply(18, &values, Some(1.0)), Err(CellError::Num));
+        assert_eq!(apply(12, &[], None), Err(CellError::Num));
+    }
+
+    #[test]
+    fn empty_and_unique_inputs_give_errors() {
+        assert_eq!(apply(1, &[], None), Err(CellError::Div0));
+        assert_eq!(apply(7, &numbers(&[1.0]), None), Err(CellError::Div0));
+        assert_eq!(apply(8, &numbers(&[1.0]), None), Ok(0.0));
+        assert_eq!(apply(13, &numbers(&[1.0, 2.0]), None), Err(CellError::NA));
+        assert_eq!(apply(14, &numbers(&[1.0, 2.0]), Some(3.0)), Err(CellError::Num));
+        assert_eq!(apply(9, &[], None), Ok(0.0));
+    }
+}
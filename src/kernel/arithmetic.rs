@@ -9,10 +9,14 @@ pub trait Floating: std::str::FromStr {
     fn log(self, base: Self) -> Self;
     fn pow(self, exp: Self) -> Self;
     fn from_f64(number: f64) -> Self;
+    fn to_f64(self) -> f64;
+    /// Get the shortest text which parses back to exactly this number, if
+    /// the type can be written as text.
+    fn to_text(self) -> Option<String>;
 }
 
 macro_rules! impl_floating_for {
-    ($t:ty, $conv:expr) => {
+    ($t:ty, $conv:expr, $back:expr, $text:expr) => {
         impl Floating for $t {
             fn sqrt(self) -> Self {
                 Self::sqrt(self)
@@ -45,15 +49,24 @@ macro_rules! impl_floating_for {
             fn from_f64(number: f64) -> Self {
                 $conv(number)
             }
+
+            fn to_f64(self) -> f64 {
+                $back(self)
+            }
+
+            fn to_text(self) -> Option<String> {
+                $text(self)
+            }
         }
     };
 }
 
-impl_floating_for!(f32, |x| x as f32);
-impl_floating_for!(f64, |x| x);
+impl_floating_for!(f32, |x| x as f32, |x| x as f64, |x: f32| Some(x.to_string()));
+impl_floating_for!(f64, |x| x, |x| x, |x: f64| Some(x.to_string()));
 
+// f128 cannot be formatted yet.
 #[cfg(feature = "f128")]
-impl_floating_for!(f128, |x| x.into());
+impl_floating_for!(f128, |x| x.into(), |x| x as f64, |_| None);
 
 pub trait Arithmetic:
     Add<Output=Self> +
@@ -65,8 +78,11 @@ pub trait Arithmetic:
     MulAssign +
     DivAssign +
     Floating +
+    PartialEq +
+    PartialOrd +
     Copy +
-    Sized
+    Sized +
+    'static
 {}
 
 impl Arithmetic for f32 {}
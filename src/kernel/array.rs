@@ -0,0 +1,126 @@
+/// Array2D is a rectangular buffer of values stored row by row, such as the
+/// evaluated cells of a range.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Array2D<T> {
+    rows: usize,
+    cols: usize,
+    values: Vec<T>,
+}
+
+impl<T> Array2D<T> {
+    /// Create an array from values stored row by row, or None if there are
+    /// not exactly `rows * cols` values.
+    pub fn from_vec(rows: usize, cols: usize, values: Vec<T>) -> Option<Self> {
+        (rows.checked_mul(cols) == Some(values.len())).then_some(Self{rows, cols, values})
+    }
+
+    /// Create an array by calling `f` with the row and column of every
+    /// element, row by row.
+    pub fn from_fn(rows: usize, cols: usize, mut f: impl FnMut(usize, usize) -> T) -> Self {
+        let mut values = Vec::with_capacity(rows * cols);
+        for row in 0..rows {
+            for col in 0..cols {
+                values.push(f(row, col));
+            }
+        }
+        Self{rows, cols, values}
+    }
+
+    /// Create an array like `from_fn`, stopping at the first error.
+    pub fn try_from_fn<E>(rows: usize, cols: usize, mut f: impl FnMut(usize, usize) -> Result<T, E>) -> Result<Self, E> {
+        let mut values = Vec::with_capacity(rows * cols);
+        for row in 0..rows {
+            for col in 0..cols {
+                values.push(f(row, col)?);
+            }
+        }
+        Ok(Self{rows, cols, values})
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn get(&self, row: usize, col: usize) -> Option<&T> {
+        (row < self.rows && col < self.cols).then(|| &self.values[row * self.cols + col])
+    }
+
+    pub fn get_mut(&mut self, row: usize, col: usize) -> Option<&mut T> {
+        (row < self.rows && col < self.cols).then(|| &mut self.values[row * self.cols + col])
+    }
+
+    /// Get the values of a row.
+    pub fn row(&self, row: usize) -> Option<&[T]> {
+        (row < self.rows).then(|| &self.values[row * self.cols..(row + 1) * self.cols])
+    }
+
+    /// Iterate the rows as slices.
+    pub fn iter_rows(&self) -> impl Iterator<Item=&[T]> {
+        (0..self.rows).map(|row| &self.values[row * self.cols..(row + 1) * self.cols])
+    }
+
+    /// Get the values row by row.
+    pub fn values(&self) -> &[T] {
+        &self.values
+    }
+
+    pub fn into_values(self) -> Vec<T> {
+        self.values
+    }
+
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Array2D<U> {
+        Array2D{rows: self.rows, cols: self.cols, values: self.values.into_iter().map(f).collect()}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::{CellId, Kernel, Primitive, Value};
+    use crate::kernel::workbook::Workbook;
+    use crate::kernel::worksheet::Worksheet;
+
+    #[test]
+    fn arrays_are_stored_row_by_row() {
+        assert!(Array2D::from_vec(2, 2, vec![1, 2, 3]).is_none());
+        let array = Array2D::from_fn(2, 3, |row, col| row * 10 + col);
+        assert_eq!((array.rows(), array.cols()), (2, 3));
+        assert_eq!(array.get(1, 2), Some(&12));
+        assert_eq!(array.get(2, 0), None);
+        assert_eq!(array.row(1), Some(&[10, 11, 12][..]));
+        assert_eq!(array.iter_rows().count(), 2);
+        assert_eq!(array.clone().map(|value| value * 2).values(), [0, 2, 4, 20, 22, 24]);
+        assert_eq!(Array2D::from_vec(2, 3, array.clone().into_values()), Some(array));
+        let failed = Array2D::try_from_fn(2, 2, |row, col| if row + col == 2 { Err((row, col)) } else { Ok(0) });
+        assert_eq!(failed, Err((1, 1)));
+    }
+
+    #[test]
+    fn ranges_evaluate_with_a_row_per_row() {
+        let number = |value: &Value<f64>| match value {
+            Value::Primitive(Primitive::Number(number)) => Some(number.value()),
+            _ => None,
+        };
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::parse("A1").unwrap(), "2".to_string());
+        sheet.set_cell(CellId::parse("B1").unwrap(), "=A1*3".to_string());
+        sheet.set_cell(CellId::parse("A2").unwrap(), "=B1+1".to_string());
+        let values = sheet.evaluate_range(CellId::parse("B2").unwrap(), CellId::parse("A1").unwrap()).unwrap();
+        assert_eq!(values.values().iter().map(number).collect::<Vec<_>>(), [Some(2.0), Some(6.0), Some(7.0), None]);
+
+        let mut workbook: Workbook = Workbook::new();
+        workbook.insert_sheet("Sheet1", sheet).unwrap();
+        let values = workbook.evaluate_range("Sheet1", CellId::parse("A1").unwrap(), CellId::parse("A2").unwrap()).unwrap();
+        assert_eq!((values.rows(), values.cols()), (2, 1));
+        assert_eq!(values.get(1, 0).and_then(number), Some(7.0));
+        assert!(workbook.evaluate_range("Missing", CellId::new(0, 0), CellId::new(0, 0)).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{CellId, Kernel};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Get the precedents of a cell, or nothing if it does not hold a formula.
+pub fn precedents<K, E, T>(kernel: &K, cell_id: CellId) -> Vec<CellId>
+where K: Kernel<E, T>, E: std::error::Error, T: Arithmetic {
+    kernel.get_cell(cell_id)
+        .and_then(|cell| cell.formula().map(|formula| formula.precedents()))
+        .unwrap_or_default()
+}
+
+/// Follow an error value from `cell_id` back to the cell it originated in.
+///
+/// At each step the first precedent which also evaluates to an error is
+/// followed. The chain ends at a cell none of whose precedents are errors.
+pub fn trace_error<K, E, T>(kernel: &K, cell_id: CellId) -> Result<Vec<CellId>, E>
+where K: Kernel<E, T>, E: std::error::Error, T: Arithmetic {
+    if !kernel.evaluate_cell(cell_id)?.is_error() {
+        return Ok(Vec::new());
+    }
+
+    let mut chain = vec![cell_id];
+    let mut visited = HashSet::from([cell_id]);
+    let mut current = cell_id;
+    'trace: loop {
+        for precedent in precedents(kernel, current) {
+            if visited.contains(&precedent) {
+                continue;
+            }
+            if kernel.evaluate_cell(precedent)?.is_error() {
+                chain.push(precedent);
+                visited.insert(precedent);
+                current = precedent;
+                continue 'trace;
+            }
+        }
+        return Ok(chain);
+    }
+}
+
+/// The most paths `path_between` finds. Formulas fanning in and out can
+/// make the number of paths grow exponentially with their depth.
+pub const MAX_PATHS: usize = 10_000;
+
+/// Find every dependency path leading from `from` to its precedent `to`, up
+/// to `MAX_PATHS` of them.
+pub fn path_between<K, E, T>(kernel: &K, from: CellId, to: CellId) -> Vec<Vec<CellId>>
+where K: Kernel<E, T>, E: std::error::Error, T: Arithmetic {
+    let reaching = reaching(kernel, from, to);
+    let mut paths = Vec::new();
+    if !reaching.contains(&from) {
+        return paths;
+    }
+    let mut path = vec![from];
+    let mut on_path = HashSet::from([from]);
+    collect_paths(kernel, to, &reaching, &mut path, &mut on_path, &mut paths);
+    paths
+}
+
+/// Get the cells among `from` and its precedents which `to` is a precedent
+/// of, so the search for paths never follows a precedent leading nowhere.
+fn reaching<K, E, T>(kernel: &K, from: CellId, to: CellId) -> HashSet<CellId>
+where K: Kernel<E, T>, E: std::error::Error, T: Arithmetic {
+    let mut dependents = HashMap::<CellId, Vec<CellId>>::new();
+    let mut visited = HashSet::from([from]);
+    let mut queue = VecDeque::from([from]);
+    while let Some(current) = queue.pop_front() {
+        if current == to {
+            continue;
+        }
+        for precedent in precedents(kernel, current) {
+            dependents.entry(precedent).or_default().push(current);
+            if visited.insert(precedent) {
+                queue.push_back(precedent);
+            }
+        }
+    }
+    let mut reaching = HashSet::new();
+    if !visited.contains(&to) {
+        return reaching;
+    }
+    let mut queue = VecDeque::from([to]);
+    reaching.insert(to);
+    while let Some(current) = queue.pop_front() {
+        for dependent in dependents.get(&current).into_iter().flatten() {
+            if reaching.insert(*dependent) {
+                queue.push_back(*dependent);
+            }
+        }
+    }
+    reaching
+}
+
+fn collect_paths<K, E, T>(
+    kernel: &K,
+    to: CellId,
+    reaching: &HashSet<CellId>,
+    path: &mut Vec<CellId>,
+    on_path: &mut HashSet<CellId>,
+    paths: &mut Vec<Vec<CellId>>,
+)
+where K: Kernel<E, T>, E: std::error::Error, T: Arithmetic {
+    let current = *path.last().expect("path is never empty");
+    let mut seen = HashSet::new();
+    for precedent in precedents(kernel, current) {
+        // Cycles are not paths, and a range listing a cell twice is one edge.
+        if paths.len() >= MAX_PATHS || !reaching.contains(&precedent) || on_path.contains(&precedent) || !seen.insert(precedent) {
+            continue;
+        }
+        path.push(precedent);
+        if precedent == to {
+            paths.push(path.clone());
+        } else {
+            on_path.insert(precedent);
+            collect_paths(kernel, to, reaching, path, on_path, paths);
+            on_path.remove(&precedent);
+        }
+        path.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::{Cell, CellError, Value};
+    use std::collections::HashMap;
+    use crate::kernel::worksheet::Worksheet;
+
+    /// Values is a kernel of values without cells, such as errors.
+    struct Values(HashMap<CellId, Value>);
+
+    impl Kernel<std::fmt::Error> for Values {
+        fn get_cell(&self, _: CellId) -> Option<Cell<f64>> {
+            None
+        }
+
+        fn evaluate_cell(&self, cell_id: CellId) -> Result<Value, std::fmt::Error> {
+            Ok(self.0.get(&cell_id).cloned().unwrap_or(Value::Raw))
+        }
+
+        fn set_cell(&mut self, _: CellId, _: String) {}
+    }
+
+    #[test]
+    fn errors_are_traced_from_the_cell_holding_them() {
+        let kernel = Values(HashMap::from([(CellId::new(0, 0), Value::Error(CellError::Div0))]));
+        assert_eq!(kernel.trace_error(CellId::new(0, 0)).unwrap(), vec![CellId::new(0, 0)]);
+        assert!(kernel.trace_error(CellId::new(0, 1)).unwrap().is_empty());
+        assert!(precedents(&kernel, CellId::new(0, 0)).is_empty());
+        assert!(kernel.path_between(CellId::new(0, 0), CellId::new(0, 1)).is_empty());
+    }
+
+    #[test]
+    fn finds_every_path() {
+        let mut sheet: Worksheet = Worksheet::new();
+        for (cell_id, data) in [("A1", "1"), ("B1", "=A1*2"), ("C1", "=A1+B1"), ("D1", "=C1+B1+E1"), ("E1", "=5")] {
+            sheet.set_cell(CellId::parse(cell_id).unwrap(), data.to_string());
+        }
+        let id = |cell_id| CellId::parse(cell_id).unwrap();
+        let mut paths = path_between(&sheet, id("D1"), id("A1"));
+        paths.sort_by_key(|path| path.iter().map(|cell_id| (cell_id.row(), cell_id.col())).collect::<Vec<_>>());
+        let mut expected = vec![
+            vec![id("D1"), id("C1"), id("A1")],
+            vec![id("D1"), id("C1"), id("B1"), id("A1")],
+            vec![id("D1"), id("B1"), id("A1")],
+        ];
+        expected.sort_by_key(|path| path.iter().map(|cell_id| (cell_id.row(), cell_id.col())).collect::<Vec<_>>());
+        assert_eq!(paths, expected);
+        assert!(path_between(&sheet, id("D1"), id("F1")).is_empty());
+        assert!(path_between(&sheet, id("A1"), id("D1")).is_empty());
+    }
+
+    #[test]
+    fn prunes_and_bounds_wide_graphs() {
+        // Each row sums both cells of the row above, so there are 2^40
+        // paths from the bottom to A1, next to a wide fan of dead ends.
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), "1".to_string());
+        sheet.set_cell(CellId::new(0, 1), "1".to_string());
+        for row in 1..=40 {
+            for col in 0..2 {
+                sheet.set_cell(CellId::new(row, col), format!("=A{}+B{}+SUM(C1:Z{})", row, row, row));
+            }
+        }
+        let paths = path_between(&sheet, CellId::new(40, 0), CellId::new(0, 0));
+        assert_eq!(paths.len(), MAX_PATHS);
+        assert!(paths.iter().all(|path| path.len() == 41 && path.last() == Some(&CellId::new(0, 0))));
+    }
+}
@@ -0,0 +1,350 @@
+use super::arithmetic::Arithmetic;
+use super::csv::{records, write_grid};
+use super::journal::{escape, unescape, JournalError, Journaled};
+use super::kernel::{CellId, Formula, Kernel};
+use super::workbook::{Workbook, WorkbookError};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The first line of the manifest of every autosave.
+const HEADER: &str = "xlnt-autosave 1";
+
+/// The file listing the sheets and names of an autosave.
+const MANIFEST: &str = "workbook.txt";
+
+/// SaveStatus is the progress of the saves of an `AutoSave`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SaveStatus {
+    /// Whether a save is being written.
+    pub saving: bool,
+    /// The revision of the latest save which is on disk.
+    pub durable_revision: Option<u64>,
+    /// The error of the latest save, if it failed.
+    pub last_error: Option<String>,
+}
+
+/// The raw contents of the cells of a sheet.
+type Cells = Vec<(CellId, String)>;
+
+/// Snapshot is the state of a workbook to save, taken so the workbook can
+/// be changed while it is written.
+struct Snapshot {
+    revision: u64,
+    /// The sheets in workbook order, with their cells if they changed since
+    /// the previous save.
+    sheets: Vec<(String, Option<Cells>)>,
+    names: Vec<(String, String)>,
+}
+
+impl Snapshot {
+    /// Take a snapshot of a workbook with the cells of the sheets `changed`
+    /// picks.
+    fn take<T: Arithmetic>(workbook: &Workbook<T>, revision: u64, changed: impl Fn(&str) -> bool) -> Self {
+        let sheets = workbook.sheets()
+            .map(|(name, sheet)| {
+                let cells = changed(name).then(|| sheet.cells().map(|(cell_id, cell)| (cell_id, cell.raw().to_string())).collect());
+                (name.to_string(), cells)
+            })
+            .collect();
+        let names = workbook.names().map(|(name, formula)| (name.to_string(), formula.to_string())).collect();
+        Self{revision, sheets, names}
+    }
+}
+
+/// Get the file of a sheet, named after the hex digits of its lowercased
+/// name so any sheet name makes a valid file name.
+fn sheet_file(name: &str) -> String {
+    let hex = name.to_lowercase().bytes().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    format!("sheet-{}.csv", hex)
+}
+
+/// Write a file next to its final path and move it in place, so a crash
+/// leaves either the old or the new file.
+fn write_atomically(path: &Path, write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>) -> io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&temporary)?);
+    write(&mut writer)?;
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+/// Write a snapshot one sheet at a time, then the manifest naming them.
+fn write_snapshot(dir: &Path, snapshot: &Snapshot) -> io::Result<()> {
+    for (name, cells) in &snapshot.sheets {
+        let Some(cells) = cells else { continue };
+        let ids = cells.iter().map(|(cell_id, _)| *cell_id).collect::<Vec<_>>();
+        let raw = cells.iter().map(|(cell_id, raw)| (*cell_id, raw.as_str())).collect::<HashMap<_, _>>();
+        write_atomically(&dir.join(sheet_file(name)), |writer| {
+            write_grid(&ids, |cell_id| raw.get(&cell_id).copied(), writer, ',')
+        })?;
+    }
+    write_atomically(&dir.join(MANIFEST), |writer| {
+        writeln!(writer, "{}", HEADER)?;
+        writeln!(writer, "revision\t{}", snapshot.revision)?;
+        for (name, _) in &snapshot.sheets {
+            writeln!(writer, "sheet\t{}", escape(name))?;
+        }
+        for (name, formula) in &snapshot.names {
+            writeln!(writer, "name\t{}\t{}", escape(name), escape(formula))?;
+        }
+        Ok(())
+    })?;
+    // Remove the files of sheets which are gone, now that the manifest no
+    // longer names them.
+    let kept = snapshot.sheets.iter().map(|(name, _)| sheet_file(name)).collect::<Vec<_>>();
+    for entry in fs::read_dir(dir)? {
+        let file = entry?.file_name().to_string_lossy().into_owned();
+        if file.starts_with("sheet-") && file.ends_with(".csv") && !kept.contains(&file) {
+            fs::remove_file(dir.join(file))?;
+        }
+    }
+    Ok(())
+}
+
+/// AutoSave saves a journaled workbook to a directory in the background
+/// every so often: one file per sheet, rewriting only the sheets which
+/// changed, and a manifest of the sheets and defined names. The sheets are
+/// written on a thread of their own from a snapshot of their raw contents,
+/// so the workbook can be changed and evaluated meanwhile. Once a save is
+/// on disk the entries of the journal it covers are dropped, so
+/// `load_autosave` followed by `Workbook::recover` restores every mutation.
+/// Number formats and other sheet settings are not saved.
+pub struct AutoSave {
+    dir: PathBuf,
+    interval: Duration,
+    last_snapshot: Option<Instant>,
+    /// The revision of the latest snapshot.
+    revision: Option<u64>,
+    /// Whether the next snapshot has to include every sheet, such as the
+    /// first or after a failed save.
+    full: bool,
+    status: Arc<(Mutex<SaveStatus>, Condvar)>,
+    sender: Option<Sender<Snapshot>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AutoSave {
+    /// Start saving a workbook to a directory, created if needed, with a
+    /// full save right away. Later saves are started by `poll`, at most
+    /// once every `interval`.
+    pub fn attach<T: Arithmetic>(journaled: &mut Journaled<T>, dir: impl AsRef<Path>, interval: Duration) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let status = Arc::new((Mutex::new(SaveStatus::default()), Condvar::new()));
+        let (sender, receiver) = mpsc::channel::<Snapshot>();
+        let thread = {
+            let (dir, status) = (dir.clone(), status.clone());
+            thread::spawn(move || {
+                for snapshot in receiver {
+                    let result = write_snapshot(&dir, &snapshot);
+                    let (lock, finished) = &*status;
+                    let mut status = lock.lock().unwrap_or_else(|e| e.into_inner());
+                    status.saving = false;
+                    match result {
+                        Ok(()) => {
+                            status.durable_revision = Some(snapshot.revision);
+                            status.last_error = None;
+                        },
+                        Err(e) => status.last_error = Some(e.to_string()),
+                    }
+                    finished.notify_all();
+                }
+            })
+        };
+        let mut autosave = Self{
+            dir,
+            interval,
+            last_snapshot: None,
+            revision: None,
+            full: true,
+            status,
+            sender: Some(sender),
+            thread: Some(thread),
+        };
+        autosave.snapshot(journaled);
+        Ok(autosave)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn status(&self) -> SaveStatus {
+        self.status.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Drop the journal entries of the latest durable save, and start a new
+    /// save if the interval passed and the workbook changed. Call this
+    /// regularly, such as after every batch of edits. Returns whether a
+    /// save was started.
+    pub fn poll<T: Arithmetic>(&mut self, journaled: &mut Journaled<T>) -> Result<bool, JournalError> {
+        let status = self.status();
+        if let Some(durable) = status.durable_revision {
+            journaled.checkpoint_through(durable)?;
+        }
+        if status.saving {
+            return Ok(false);
+        }
+        if status.last_error.is_some() {
+            self.full = true;
+        }
+        let due = self.last_snapshot.is_none_or(|last| last.elapsed() >= self.interval);
+        if !due || self.revision == Some(journaled.revision()) && !self.full {
+            return Ok(false);
+        }
+        self.snapshot(journaled);
+        Ok(true)
+    }
+
+    /// Save now whatever changed, and wait for the save to be on disk.
+    pub fn flush<T: Arithmetic>(&mut self, journaled: &mut Journaled<T>) -> Result<SaveStatus, JournalError> {
+        self.wait();
+        if self.revision != Some(journaled.revision()) || self.full {
+            self.snapshot(journaled);
+        }
+        let status = self.wait();
+        if let Some(error) = &status.last_error {
+            return Err(io::Error::other(error.clone()).into());
+        }
+        if let Some(durable) = status.durable_revision {
+            journaled.checkpoint_through(durable)?;
+        }
+        Ok(status)
+    }
+
+    /// Wait until no save is being written, and get the status.
+    pub fn wait(&self) -> SaveStatus {
+        let (lock, finished) = &*self.status;
+        let status = lock.lock().unwrap_or_else(|e| e.into_inner());
+        finished.wait_while(status, |status| status.saving).unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn snapshot<T: Arithmetic>(&mut self, journaled: &mut Journaled<T>) {
+        let dirty = journaled.take_dirty();
+        let snapshot = Snapshot::take(journaled.workbook(), journaled.revision(), |name| self.full || dirty.contains(&name.to_lowercase()));
+        self.status.0.lock().unwrap_or_else(|e| e.into_inner()).saving = true;
+        if let Some(sender) = &self.sender {
+            if sender.send(snapshot).is_err() {
+                let mut status = self.status.0.lock().unwrap_or_else(|e| e.into_inner());
+                status.saving = false;
+                status.last_error = Some("the autosave thread stopped".to_string());
+            }
+        }
+        self.last_snapshot = Some(Instant::now());
+        self.revision = Some(journaled.revision());
+        self.full = false;
+    }
+}
+
+impl Drop for AutoSave {
+    /// Finish the save being written and stop the thread.
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Save a whole workbook to a directory as an `AutoSave` would, at a
+/// revision, so `load_autosave` can load it.
+pub fn save_workbook<T: Arithmetic>(workbook: &Workbook<T>, dir: impl AsRef<Path>, revision: u64) -> io::Result<()> {
+    fs::create_dir_all(dir.as_ref())?;
+    write_snapshot(dir.as_ref(), &Snapshot::take(workbook, revision, |_| true))
+}
+
+/// Load the workbook an `AutoSave` saved to a directory, and get the
+/// revision it was saved at.
+pub fn load_autosave<T: Arithmetic>(dir: impl AsRef<Path>) -> Result<(Workbook<T>, u64), JournalError> {
+    let dir = dir.as_ref();
+    let manifest = fs::read_to_string(dir.join(MANIFEST))?;
+    let mut lines = manifest.lines().enumerate();
+    if lines.next().map(|(_, line)| line) != Some(HEADER) {
+        return Err(JournalError::Corrupt(1));
+    }
+    let mut workbook = Workbook::new();
+    let mut revision = 0;
+    for (index, line) in lines {
+        let fields = line.split('\t').map(unescape).collect::<Option<Vec<_>>>().ok_or(JournalError::Corrupt(index + 1))?;
+        match fields.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+            ["revision", number] => revision = number.parse().map_err(|_| JournalError::Corrupt(index + 1))?,
+            ["sheet", name] => {
+                let text = fs::read_to_string(dir.join(sheet_file(name)))?;
+                let sheet = workbook.add_sheet(name)?;
+                for (row, record) in records(&text, ',').map_err(|_| JournalError::Corrupt(index + 1))?.into_iter().enumerate() {
+                    for (col, field) in record.into_iter().enumerate() {
+                        if !field.is_empty() {
+                            sheet.set_cell(CellId::new(row as u32, col as u32), field);
+                        }
+                    }
+                }
+            },
+            ["name", name, formula] => {
+                let formula = Formula::try_from(*formula).map_err(|_| WorkbookError::InvalidName(name.to_string()))?;
+                workbook.define_name(name, formula)?;
+            },
+            _ => return Err(JournalError::Corrupt(index + 1)),
+        }
+    }
+    Ok((workbook, revision))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::journal::{read_journal, Journal, Mutation};
+
+    #[test]
+    fn saves_rewrite_dirty_sheets_and_checkpoint_the_journal() {
+        let dir = std::env::temp_dir().join(format!("xlnt-autosave-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let journal_path = dir.join("journal.log");
+        let mut journaled = Journaled::<f64>::new(Workbook::new(), Journal::open(&journal_path).unwrap());
+        journaled.apply(Mutation::AddSheet("Data".to_string())).unwrap();
+        journaled.apply(Mutation::AddSheet("Other".to_string())).unwrap();
+        journaled.set_cell("Data", CellId::new(0, 0), "1".to_string()).unwrap();
+        journaled.set_cell("Other", CellId::new(0, 0), "x,y".to_string()).unwrap();
+        journaled.apply(Mutation::DefineName{name: "Rate".to_string(), formula: "Data!A1".to_string()}).unwrap();
+
+        let saves = dir.join("saves");
+        let mut autosave = AutoSave::attach(&mut journaled, &saves, Duration::from_secs(3600)).unwrap();
+        assert_eq!(autosave.flush(&mut journaled).unwrap().durable_revision, Some(5));
+        assert!(read_journal(&journal_path).unwrap().is_empty());
+        assert!(!autosave.poll(&mut journaled).unwrap());
+
+        // A sheet which did not change is not written again.
+        fs::write(saves.join(sheet_file("Other")), "untouched\n").unwrap();
+        journaled.set_cell("Data", CellId::new(1, 0), "=A1*2".to_string()).unwrap();
+        assert!(!autosave.poll(&mut journaled).unwrap());
+        let status = autosave.flush(&mut journaled).unwrap();
+        assert_eq!((status.saving, status.durable_revision, status.last_error), (false, Some(6), None));
+        drop(autosave);
+
+        let (mut workbook, revision) = load_autosave::<f64>(&saves).unwrap();
+        assert_eq!(revision, 6);
+        assert_eq!(workbook.recover(&journal_path).unwrap(), 0);
+        assert_eq!(workbook.sheet("Data").unwrap().cell(CellId::new(1, 0)).unwrap().raw(), "=A1*2");
+        assert_eq!(workbook.sheet("Other").unwrap().cell(CellId::new(0, 0)).unwrap().raw(), "untouched");
+        assert!(workbook.name("Rate").is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn loading_checks_the_manifest() {
+        let dir = std::env::temp_dir().join(format!("xlnt-autosave-corrupt-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(MANIFEST), "something else\n").unwrap();
+        assert!(matches!(load_autosave::<f64>(&dir), Err(JournalError::Corrupt(1))));
+        fs::write(dir.join(MANIFEST), format!("{}\nrevision\tmany\n", HEADER)).unwrap();
+        assert!(matches!(load_autosave::<f64>(&dir), Err(JournalError::Corrupt(2))));
+        assert_eq!(sheet_file("Ab"), "sheet-6162.csv");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+use super::arithmetic::Arithmetic;
+use super::csv::{write_csv, CsvOptions};
+use super::template::{Template, TemplateData, TemplateError};
+use super::workbook::{Workbook, WorkbookError};
+use super::worksheet::Worksheet;
+use thiserror::Error;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+#[derive(Error, Debug)]
+pub enum BatchError {
+    #[error("record {index}: {source}")]
+    Template{index: usize, source: TemplateError},
+
+    #[error(transparent)]
+    Workbook(#[from] WorkbookError),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// BatchSink receives the sheets rendered by a `BatchRenderer`, one per
+/// record, as soon as each is rendered.
+pub trait BatchSink<T: Arithmetic=f64> {
+    fn write(&mut self, index: usize, name: &str, sheet: Worksheet<T>) -> Result<(), BatchError>;
+}
+
+/// CsvDirectory writes every rendered sheet to its own file in a directory,
+/// so only one rendered sheet is held in memory at a time.
+pub struct CsvDirectory {
+    directory: PathBuf,
+    options: CsvOptions,
+    written: Vec<PathBuf>,
+}
+
+impl CsvDirectory {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self{directory: directory.into(), options: CsvOptions::default(), written: Vec::new()}
+    }
+
+    pub fn with_options(mut self, options: CsvOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Get the paths of the files written so far.
+    pub fn written(&self) -> &[PathBuf] {
+        &self.written
+    }
+}
+
+impl<T: Arithmetic> BatchSink<T> for CsvDirectory {
+    fn write(&mut self, _index: usize, name: &str, sheet: Worksheet<T>) -> Result<(), BatchError> {
+        let path = self.directory.join(format!("{}.csv", name));
+        let mut writer = BufWriter::new(File::create(&path)?);
+        write_csv(&sheet, &mut writer, &self.options)?;
+        self.written.push(path);
+        Ok(())
+    }
+}
+
+/// Collecting rendered sheets into a workbook produces one workbook with a
+/// sheet per record.
+impl<T: Arithmetic> BatchSink<T> for Workbook<T> {
+    fn write(&mut self, _index: usize, name: &str, sheet: Worksheet<T>) -> Result<(), BatchError> {
+        self.insert_sheet(name, sheet)?;
+        Ok(())
+    }
+}
+
+type Namer = Box<dyn Fn(usize, &TemplateData) -> String>;
+
+/// BatchRenderer renders a template once per record, mail merge style, and
+/// streams every result into a sink.
+pub struct BatchRenderer<'a, T: Arithmetic=f64> {
+    template: &'a Template<T>,
+    namer: Namer,
+}
+
+impl<'a, T: Arithmetic> BatchRenderer<'a, T> {
+    /// Create a renderer naming outputs `Record 1`, `Record 2` and so on.
+    pub fn new(template: &'a Template<T>) -> Self {
+        Self{template, namer: Box::new(|index, _| format!("Record {}", index + 1))}
+    }
+
+    /// Name outputs after a field of each record, falling back to the
+    /// default name for records without it.
+    pub fn named_by(mut self, path: &str) -> Self {
+        let path = path.to_string();
+        self.namer = Box::new(move |index, record| match record.get(&path) {
+            Some(TemplateData::Value(name)) => name.clone(),
+            _ => format!("Record {}", index + 1),
+        });
+        self
+    }
+
+    pub fn named_with(mut self, namer: impl Fn(usize, &TemplateData) -> String + 'static) -> Self {
+        self.namer = Box::new(namer);
+        self
+    }
+
+    /// Render every record into `sink`, returning the number of records
+    /// rendered. Stops at the first failure.
+    pub fn render<I, S>(&self, records: I, sink: &mut S) -> Result<usize, BatchError>
+    where I: IntoIterator<Item=TemplateData>, S: BatchSink<T> {
+        let mut count = 0;
+        for (index, record) in records.into_iter().enumerate() {
+            let sheet = self.template.render(&record)
+                .map_err(|source| BatchError::Template{index, source})?;
+            sink.write(index, &(self.namer)(index, &record), sheet)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::{CellId, Kernel};
+
+    fn template() -> Template {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), "{{name}}".to_string());
+        sheet.set_cell(CellId::new(0, 1), "{{score}}".to_string());
+        Template::new(sheet).unwrap()
+    }
+
+    fn records() -> Vec<TemplateData> {
+        vec![
+            TemplateData::record().with("name", "Ann").with("score", 3.0).with("file", "ann"),
+            TemplateData::record().with("name", "Bob").with("score", 4.0),
+        ]
+    }
+
+    #[test]
+    fn renders_a_sheet_per_record_into_a_workbook() {
+        let template = template();
+        let mut workbook = Workbook::new();
+        assert_eq!(BatchRenderer::new(&template).named_by("file").render(records(), &mut workbook).unwrap(), 2);
+        let names = workbook.sheets().map(|(name, _)| name.to_string()).collect::<Vec<_>>();
+        assert_eq!(names, ["ann", "Record 2"]);
+        assert_eq!(workbook.sheet("ann").unwrap().cell(CellId::new(0, 0)).unwrap().raw(), "Ann");
+    }
+
+    #[test]
+    fn streams_records_to_csv_files() {
+        let directory = std::env::temp_dir().join(format!("xlnt-batch-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let template = template();
+        let mut sink = CsvDirectory::new(&directory);
+        BatchRenderer::new(&template).named_with(|index, _| format!("out-{}", index)).render(records(), &mut sink).unwrap();
+        assert_eq!(sink.written(), [directory.join("out-0.csv"), directory.join("out-1.csv")]);
+        assert!(std::fs::read_to_string(&sink.written()[0]).unwrap().starts_with("Ann,3"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn stops_at_the_first_record_failing_to_render() {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), "{{#each items}}".to_string());
+        sheet.set_cell(CellId::new(1, 0), "{{name}}".to_string());
+        sheet.set_cell(CellId::new(2, 0), "{{/each}}".to_string());
+        let template = Template::new(sheet).unwrap();
+        let records = vec![
+            TemplateData::record().with("items", vec![TemplateData::record().with("name", "Tea")]),
+            TemplateData::record().with("items", "not a list"),
+        ];
+        let mut workbook = Workbook::new();
+        let result = BatchRenderer::new(&template).render(records, &mut workbook);
+        assert!(matches!(result, Err(BatchError::Template{index: 1, ..})), "{:?}", result.err());
+        assert_eq!(workbook.len(), 1);
+    }
+}
@@ -0,0 +1,201 @@
+use super::arithmetic::Arithmetic;
+use super::dependency::{DependencyGraph, Precedent};
+use super::kernel::CellId;
+use super::workbook::{Workbook, WorkbookError};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+/// Edit sets the raw contents of a cell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    pub sheet: String,
+    pub cell_id: CellId,
+    pub data: String,
+}
+
+/// Script is a sequence of edits to replay against a workbook, such as the
+/// inputs a user changes while working with a model.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Script {
+    edits: Vec<Edit>,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(mut self, sheet: &str, cell_id: CellId, data: &str) -> Self {
+        self.edits.push(Edit{sheet: sheet.to_string(), cell_id, data: data.to_string()});
+        self
+    }
+
+    pub fn edits(&self) -> &[Edit] {
+        &self.edits
+    }
+}
+
+/// Recalc is which formulas are recalculated after every edit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Recalc {
+    /// Every formula of the workbook.
+    Full,
+    /// The edited cell and the formulas which depend on it directly or
+    /// through other cells, on any sheet.
+    Affected,
+}
+
+/// EditTiming is how long recalculating after one edit took.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EditTiming {
+    /// The index of the edit in the script.
+    pub edit: usize,
+    pub elapsed: Duration,
+    /// The number of cells recalculated.
+    pub cells: usize,
+}
+
+/// BenchReport holds the timings of replaying a script.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BenchReport {
+    pub timings: Vec<EditTiming>,
+}
+
+impl BenchReport {
+    pub fn total(&self) -> Duration {
+        self.timings.iter().map(|timing| timing.elapsed).sum()
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.timings.len() {
+            0 => Duration::ZERO,
+            len => self.total() / len as u32,
+        }
+    }
+
+    pub fn max(&self) -> Duration {
+        self.timings.iter().map(|timing| timing.elapsed).max().unwrap_or_default()
+    }
+
+    /// Get the latency which `fraction` of the edits did not exceed, such
+    /// as 0.95 for the 95th percentile.
+    pub fn percentile(&self, fraction: f64) -> Duration {
+        let mut elapsed = self.timings.iter().map(|timing| timing.elapsed).collect::<Vec<_>>();
+        elapsed.sort();
+        match elapsed.len() {
+            0 => Duration::ZERO,
+            len => elapsed[((fraction.clamp(0.0, 1.0) * len as f64).ceil() as usize).clamp(1, len) - 1],
+        }
+    }
+
+    /// Get the number of cells recalculated over all edits.
+    pub fn cells(&self) -> usize {
+        self.timings.iter().map(|timing| timing.cells).sum()
+    }
+
+    /// Get how many times faster this run was than `baseline`, such as a run
+    /// of the same script before restructuring a model.
+    pub fn speedup(&self, baseline: &Self) -> f64 {
+        baseline.total().as_secs_f64() / self.total().as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+/// Whether a precedent of a formula on `own` refers to a cell of `sheet`.
+fn refers_to(precedent: &Precedent, own: &str, sheet: &str, cell_id: CellId) -> bool {
+    let (target, local) = match precedent {
+        Precedent::Cell{sheet: Some(target), cell_id} => (target.as_str(), Precedent::Cell{sheet: None, cell_id: *cell_id}),
+        Precedent::Range{sheet: Some(target), start, end} => (target.as_str(), Precedent::Range{sheet: None, start: *start, end: *end}),
+        Precedent::Name{..} => return false,
+        precedent => (own, precedent.clone()),
+    };
+    target.eq_ignore_ascii_case(sheet) && local.covers(cell_id)
+}
+
+/// Find the formulas of a workbook depending on a cell, with the cell.
+pub(crate) fn affected<T: Arithmetic>(workbook: &Workbook<T>, graphs: &[(&str, DependencyGraph)], sheet: &str, cell_id: CellId) -> Vec<(String, CellId)> {
+    let mut found = HashSet::from([(sheet.to_lowercase(), cell_id)]);
+    let mut order = vec![(sheet.to_string(), cell_id)];
+    let mut index = 0;
+    while let Some((sheet, cell_id)) = order.get(index).cloned() {
+        index += 1;
+        for (own, graph) in graphs {
+            let formulas = workbook.sheet(own).into_iter().flat_map(|own| own.cells()).filter(|(_, cell)| cell.formula().is_some());
+            for (dependent, _) in formulas {
+                let depends = graph.precedents(dependent).any(|precedent| refers_to(precedent, own, &sheet, cell_id));
+                if depends && found.insert((own.to_lowercase(), dependent)) {
+                    order.push((own.to_string(), dependent));
+                }
+            }
+        }
+    }
+    order
+}
+
+/// Replay a script against a workbook, recalculating after every edit, and
+/// time the recalculations. Finding the affected cells is not timed.
+pub fn run<T: Arithmetic>(workbook: &mut Workbook<T>, script: &Script, recalc: Recalc) -> Result<BenchReport, WorkbookError> {
+    let mut report = BenchReport::default();
+    for (edit, Edit{sheet, cell_id, data}) in script.edits().iter().enumerate() {
+        workbook.set_cell(sheet, *cell_id, data.clone())?;
+        let cells = match recalc {
+            Recalc::Full => workbook.sheets()
+                .flat_map(|(name, sheet)| {
+                    sheet.cells().filter(|(_, cell)| cell.formula().is_some()).map(move |(cell_id, _)| (name.to_string(), cell_id))
+                })
+                .collect::<Vec<_>>(),
+            Recalc::Affected => {
+                let graphs = workbook.sheets().map(|(name, sheet)| (name, sheet.dependency_graph())).collect::<Vec<_>>();
+                affected(workbook, &graphs, sheet, *cell_id)
+            },
+        };
+        let start = Instant::now();
+        for (sheet, cell_id) in &cells {
+            workbook.evaluate_cell(sheet, *cell_id)?;
+        }
+        report.timings.push(EditTiming{edit, elapsed: start.elapsed(), cells: cells.len()});
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workbook() -> Workbook {
+        let mut workbook: Workbook = Workbook::new();
+        workbook.add_sheet("Inputs").unwrap();
+        workbook.add_sheet("Model").unwrap();
+        for (sheet, cell_id, data) in [
+            ("Inputs", "A1", "1"), ("Inputs", "B1", "=A1*2"), ("Inputs", "C1", "=B1+1"),
+            ("Inputs", "D1", "10"), ("Inputs", "E1", "=D1"), ("Model", "A1", "=Inputs!C1"), ("Model", "B1", "=SUM(Inputs!D1:E1)"),
+        ] {
+            workbook.set_cell(sheet, CellId::parse(cell_id).unwrap(), data.to_string()).unwrap();
+        }
+        workbook
+    }
+
+    #[test]
+    fn affected_recalcs_follow_dependents_across_sheets() {
+        let script = Script::new().set("Inputs", CellId::parse("A1").unwrap(), "2").set("inputs", CellId::parse("D1").unwrap(), "20");
+        let full = run(&mut workbook(), &script, Recalc::Full).unwrap();
+        assert_eq!(full.timings.iter().map(|timing| timing.cells).collect::<Vec<_>>(), [5, 5]);
+        let affected = run(&mut workbook(), &script, Recalc::Affected).unwrap();
+        assert_eq!(affected.timings.iter().map(|timing| (timing.edit, timing.cells)).collect::<Vec<_>>(), [(0, 4), (1, 3)]);
+        assert_eq!(affected.cells(), 7);
+        assert!(run(&mut workbook(), &Script::new().set("Missing", CellId::new(0, 0), "1"), Recalc::Full).is_err());
+    }
+
+    #[test]
+    fn reports_summarize_timings() {
+        let timing = |edit: usize, millis: u64| EditTiming{edit, elapsed: Duration::from_millis(millis), cells: 1};
+        let report = BenchReport{timings: (0..20).map(|edit| timing(edit, edit as u64 + 1)).collect()};
+        assert_eq!(report.total(), Duration::from_millis(210));
+        assert_eq!(report.mean(), Duration::from_micros(10_500));
+        assert_eq!(report.max(), Duration::from_millis(20));
+        assert_eq!(report.percentile(0.95), Duration::from_millis(19));
+        assert_eq!(report.percentile(0.0), Duration::from_millis(1));
+        assert_eq!(BenchReport::default().percentile(0.5), Duration::ZERO);
+        let baseline = BenchReport{timings: vec![timing(0, 420)]};
+        assert_eq!(report.speedup(&baseline), 2.0);
+    }
+}
@@ -0,0 +1,126 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{Formula, FormulaParseError};
+use super::parser::{ParseOptions, MAX_FORMULA_LENGTH, MAX_NESTING};
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum BudgetError {
+    #[error("input exceeds {max} bytes")]
+    InputTooLarge{max: usize},
+
+    #[error("more than {max} cells")]
+    TooManyCells{max: usize},
+
+    #[error("string of {length} bytes exceeds {max} bytes")]
+    StringTooLong{length: usize, max: usize},
+
+    #[error("formula of {length} characters exceeds {max} characters")]
+    FormulaTooLong{length: usize, max: usize},
+
+    #[error("formula nesting exceeds {max} levels")]
+    NestingTooDeep{max: usize},
+
+    #[error("expansion ratio of {ratio} exceeds {max}")]
+    ExpansionRatio{ratio: u64, max: u64},
+}
+
+/// Budget bounds the resources spent parsing a file, so servers ingesting
+/// untrusted uploads are protected from decompression bombs and
+/// pathological formulas. Readers check their input against it as they go
+/// and fail with a `BudgetError` as soon as a limit is exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Budget {
+    /// The maximum size of the input after decompression.
+    pub max_input_bytes: usize,
+    pub max_cells: usize,
+    /// The maximum size of a single cell's contents.
+    pub max_string_length: usize,
+    pub max_formula_length: usize,
+    /// The maximum nesting of parentheses, function calls and operators in a
+    /// formula.
+    pub max_nesting: usize,
+    /// The maximum ratio of decompressed to compressed size of a package.
+    pub max_expansion_ratio: u64,
+}
+
+impl Default for Budget {
+    /// Limits which accommodate anything a spreadsheet application can save,
+    /// short of formulas nesting deeper than `MAX_NESTING`.
+    fn default() -> Self {
+        Self{
+            max_input_bytes: usize::MAX,
+            max_cells: 1048576 * 16384,
+            max_string_length: 32767,
+            max_formula_length: MAX_FORMULA_LENGTH,
+            max_nesting: MAX_NESTING,
+            max_expansion_ratio: u64::MAX,
+        }
+    }
+}
+
+impl Budget {
+    /// Tight limits suitable for files uploaded by untrusted users.
+    pub fn untrusted() -> Self {
+        Self{
+            max_input_bytes: 64 * 1024 * 1024,
+            max_cells: 1_000_000,
+            max_string_length: 32767,
+            max_formula_length: MAX_FORMULA_LENGTH,
+            max_nesting: MAX_NESTING,
+            max_expansion_ratio: 100,
+        }
+    }
+
+    /// Get parse options enforcing the formula limits of this budget.
+    pub fn parse_options(&self) -> ParseOptions {
+        ParseOptions{
+            max_length: Some(self.max_formula_length),
+            max_nesting: Some(self.max_nesting),
+            ..ParseOptions::default()
+        }
+    }
+
+    pub fn check_input(&self, bytes: usize) -> Result<(), BudgetError> {
+        if bytes > self.max_input_bytes {
+            return Err(BudgetError::InputTooLarge{max: self.max_input_bytes});
+        }
+        Ok(())
+    }
+
+    pub fn check_cells(&self, cells: usize) -> Result<(), BudgetError> {
+        if cells > self.max_cells {
+            return Err(BudgetError::TooManyCells{max: self.max_cells});
+        }
+        Ok(())
+    }
+
+    pub fn check_string(&self, string: &str) -> Result<(), BudgetError> {
+        if string.len() > self.max_string_length {
+            return Err(BudgetError::StringTooLong{length: string.len(), max: self.max_string_length});
+        }
+        Ok(())
+    }
+
+    /// Check the contents of a cell, if they are a formula, against the
+    /// formula limits. Formulas which fail to parse for other reasons are
+    /// left for the cell to report.
+    pub fn check_formula<T: Arithmetic>(&self, contents: &str) -> Result<(), BudgetError> {
+        if let Some(formula) = contents.trim().strip_prefix('=') {
+            match Formula::<T>::parse(formula, &self.parse_options()) {
+                Err(FormulaParseError::TooLong{length, max}) => return Err(BudgetError::FormulaTooLong{length, max}),
+                Err(FormulaParseError::TooDeep{max}) => return Err(BudgetError::NestingTooDeep{max}),
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the sizes of a compressed part before inflating it.
+    pub fn check_expansion(&self, compressed: u64, expanded: u64) -> Result<(), BudgetError> {
+        let ratio = expanded / compressed.max(1);
+        if ratio > self.max_expansion_ratio {
+            return Err(BudgetError::ExpansionRatio{ratio, max: self.max_expansion_ratio});
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+use super::arithmetic::Arithmetic;
+use super::format::Locale;
+use super::kernel::{escape_text, CellId, Formula, Kernel};
+use super::workbook::{Workbook, WorkbookError};
+use super::worksheet::Worksheet;
+
+/// CellData is what the builders accept as the contents of a cell: numbers,
+/// booleans and text, which is kept as text even if it looks like a number
+/// or formula.
+pub trait CellData {
+    /// Get the raw contents of a cell holding this data.
+    fn to_raw(&self) -> String;
+}
+
+macro_rules! impl_cell_data_for {
+    ($($t:ty),*) => {
+        $(impl CellData for $t {
+            fn to_raw(&self) -> String {
+                self.to_string()
+            }
+        })*
+    };
+}
+
+impl_cell_data_for!(i32, i64, u32, u64, usize, f32, f64);
+
+impl CellData for bool {
+    fn to_raw(&self) -> String {
+        if *self { "TRUE" } else { "FALSE" }.to_string()
+    }
+}
+
+impl CellData for &str {
+    fn to_raw(&self) -> String {
+        escape_text(self)
+    }
+}
+
+impl CellData for String {
+    fn to_raw(&self) -> String {
+        escape_text(self)
+    }
+}
+
+/// SheetBuilder fills a sheet with cells addressed in A1 notation.
+pub struct SheetBuilder<T: Arithmetic=f64> {
+    sheet: Worksheet<T>,
+    error: Option<WorkbookError>,
+}
+
+impl<T: Arithmetic> Default for SheetBuilder<T> {
+    fn default() -> Self {
+        Self{sheet: Worksheet::new(), error: None}
+    }
+}
+
+impl<T: Arithmetic> SheetBuilder<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set a cell, keeping the first invalid reference to report on build.
+    fn set(mut self, reference: &str, raw: String) -> Self {
+        match CellId::parse(reference) {
+            Ok(cell_id) => self.sheet.set_cell(cell_id, raw),
+            Err(_) => {
+                self.error.get_or_insert_with(|| WorkbookError::InvalidReference(reference.to_string()));
+            },
+        }
+        self
+    }
+
+    pub fn cell(self, reference: &str, data: impl CellData) -> Self {
+        self.set(reference, data.to_raw())
+    }
+
+    /// Set a formula, with or without its leading `=`.
+    pub fn formula(self, reference: &str, formula: &str) -> Self {
+        let raw = match formula.trim_start().starts_with('=') {
+            true => formula.to_string(),
+            false => format!("={}", formula),
+        };
+        self.set(reference, raw)
+    }
+
+    pub fn number_format(mut self, reference: &str, code: &str) -> Self {
+        match CellId::parse(reference) {
+            Ok(cell_id) => self.sheet.set_number_format(cell_id, code),
+            Err(_) => {
+                self.error.get_or_insert_with(|| WorkbookError::InvalidReference(reference.to_string()));
+            },
+        }
+        self
+    }
+
+    /// Get the sheet, or the first invalid reference.
+    pub fn build(self) -> Result<Worksheet<T>, WorkbookError> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.sheet),
+        }
+    }
+}
+
+/// WorkbookBuilder assembles a workbook sheet by sheet, such as
+/// `Workbook::builder().sheet("Data", |s| s.cell("A1", 1).formula("B1", "=A1*2")).build()`.
+pub struct WorkbookBuilder<T: Arithmetic=f64> {
+    sheets: Vec<(String, SheetBuilder<T>)>,
+    names: Vec<(String, String)>,
+    locale: Option<Locale>,
+}
+
+impl<T: Arithmetic> Default for WorkbookBuilder<T> {
+    fn default() -> Self {
+        Self{sheets: Vec::new(), names: Vec::new(), locale: None}
+    }
+}
+
+impl<T: Arithmetic> WorkbookBuilder<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a sheet filled by `f`.
+    pub fn sheet(mut self, name: &str, f: impl FnOnce(SheetBuilder<T>) -> SheetBuilder<T>) -> Self {
+        self.sheets.push((name.to_string(), f(SheetBuilder::new())));
+        self
+    }
+
+    /// Define a name for a formula, with or without its leading `=`.
+    pub fn name(mut self, name: &str, formula: &str) -> Self {
+        self.names.push((name.to_string(), formula.to_string()));
+        self
+    }
+
+    pub fn locale(mut self, locale: Locale) -> Self {
+        self.locale = Some(locale);
+        self
+    }
+
+    pub fn build(self) -> Result<Workbook<T>, WorkbookError> {
+        let mut workbook = Workbook::new();
+        for (name, sheet) in self.sheets {
+            workbook.insert_sheet(&name, sheet.build()?)?;
+        }
+        for (name, formula) in self.names {
+            let source = formula.trim_start();
+            let parsed = Formula::try_from(source.strip_prefix('=').unwrap_or(source))
+                .map_err(|_| WorkbookError::InvalidName(name.clone()))?;
+            workbook.define_name(&name, parsed)?;
+        }
+        if let Some(locale) = self.locale {
+            workbook.set_locale(locale);
+        }
+        Ok(workbook)
+    }
+}
+
+impl<T: Arithmetic> Workbook<T> {
+    pub fn builder() -> WorkbookBuilder<T> {
+        WorkbookBuilder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+
+    #[test]
+    fn builders_fill_sheets_and_names() {
+        let locale = Locale{decimal_separator: ',', thousands_separator: '.', ..Locale::default()};
+        let workbook: Workbook = Workbook::builder()
+            .sheet("Data", |sheet| sheet.cell("A1", 2).cell("A2", 1.5).cell("A3", true).cell("A4", "=not a formula").formula("B1", "A1*Rate").formula("B2", "=SUM(A1:A2)").number_format("B2", "0.00"))
+            .name("Rate", "=10")
+            .locale(locale.clone())
+            .build()
+            .unwrap();
+        let data = workbook.sheet("Data").unwrap();
+        assert_eq!(data.cell(CellId::parse("A4").unwrap()).unwrap().raw(), "'=not a formula");
+        assert_eq!(data.number_format(CellId::parse("B2").unwrap()), "0.00");
+        assert!(matches!(workbook.evaluate_cell("Data", CellId::parse("B1").unwrap()).unwrap(), Value::Primitive(Primitive::Number(number)) if number.value() == 20.0));
+        assert!(matches!(workbook.evaluate_cell("Data", CellId::parse("A3").unwrap()).unwrap(), Value::Primitive(Primitive::Bool(true))));
+        assert_eq!(workbook.locale(), &locale);
+    }
+
+    #[test]
+    fn builders_report_the_first_invalid_input() {
+        let sheet = SheetBuilder::<f64>::new().cell("A0", 1).number_format("ZZZZ1", "0").build();
+        assert!(matches!(sheet, Err(WorkbookError::InvalidReference(reference)) if reference == "A0"));
+        let workbook = Workbook::<f64>::builder().sheet("Data", |sheet| sheet).sheet("data", |sheet| sheet).build();
+        assert!(matches!(workbook, Err(WorkbookError::DuplicateSheet(_))));
+        let workbook = Workbook::<f64>::builder().name("A1", "1").build();
+        assert!(matches!(workbook, Err(WorkbookError::InvalidName(_))));
+    }
+}
@@ -0,0 +1,461 @@
+use super::arithmetic::Arithmetic;
+use super::eval::{to_number, Arith, Comparable, Unary};
+use super::kernel::{CellError, CellId, Formula, FunctionKind, Numeric, Primitive, Value};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+
+/// A register of the machine running a `Program`.
+pub(crate) type Reg = u32;
+
+/// Slot is the contents of a register: a value, or an operand coerced the
+/// way an operator takes it, with the error coercing it gave instead.
+#[derive(Debug, Clone, Default)]
+pub(crate) enum Slot<T: Arithmetic> {
+    #[default]
+    Empty,
+    Value(Value<T>),
+    Number(Numeric<T>),
+    Comparable(Comparable<T>),
+    Text(String),
+    Error(CellError),
+}
+
+impl<T: Arithmetic> Slot<T> {
+    /// Get the value of a register holding a value, a number or an error.
+    pub(crate) fn into_value(self) -> Value<T> {
+        match self {
+            Self::Value(value) => value,
+            Self::Number(number) => Value::Primitive(Primitive::Number(number)),
+            Self::Error(e) => Value::Error(e),
+            _ => unreachable!("operands are read as values"),
+        }
+    }
+}
+
+/// Op is an instruction of a `Program`. Each writes at most one register,
+/// and reads registers written by instructions before it.
+#[derive(Debug, Clone)]
+pub(crate) enum Op<T: Arithmetic> {
+    Const{dst: Reg, slot: Slot<T>},
+    /// Evaluate a cell, optionally of another sheet.
+    Load{dst: Reg, sheet: Option<String>, cell_id: CellId},
+    /// Evaluate a cell, optionally of another sheet, to a number.
+    LoadNumber{dst: Reg, sheet: Option<String>, cell_id: CellId},
+    /// Evaluate a formula the machine has no instructions for by walking it.
+    Tree{dst: Reg, formula: Formula<T>},
+    /// Coerce a value to a number.
+    Number{dst: Reg, src: Reg},
+    /// Coerce a value to an operand of a comparison.
+    Comparable{dst: Reg, src: Reg},
+    /// Evaluate a cell of the sheet to an operand of a comparison.
+    ComparableCell{dst: Reg, cell_id: CellId},
+    /// Convert a value to the text concatenation joins.
+    Text{dst: Reg, src: Reg},
+    /// Evaluate a cell of the sheet to the text concatenation joins.
+    TextCell{dst: Reg, cell_id: CellId},
+    /// If a coerced operand is an error, write it to `dst` as the result of
+    /// the operator and jump past the code of the other operand.
+    Bail{src: Reg, dst: Reg, target: usize},
+    Arith{dst: Reg, op: Arith, lhs: Reg, rhs: Reg},
+    Unary{dst: Reg, op: Unary, src: Reg},
+    Concat{dst: Reg, lhs: Reg, rhs: Reg},
+    Compare{dst: Reg, op: fn(Ordering) -> bool, lhs: Reg, rhs: Reg},
+    /// Jump if a number is zero.
+    JumpUnless{cond: Reg, target: usize},
+    Jump{target: usize},
+    Move{dst: Reg, src: Reg},
+}
+
+/// Program is a formula compiled to flat instructions for a register
+/// machine, so formulas evaluated over and over, as in simulations and
+/// streaming recalcs, skip the recursive walk of their tree. Operators and
+/// `IF` compile to instructions, with jumps keeping the order in which the
+/// tree is evaluated and the operands it skips, while other functions and
+/// defined names are evaluated as trees from within the program. Operators
+/// on constants are folded as the formula is compiled.
+#[derive(Debug, Clone)]
+pub struct Program<T: Arithmetic=f64> {
+    ops: Vec<Op<T>>,
+    registers: usize,
+    /// The registers of the latest run, kept to run again without
+    /// allocating them anew.
+    scratch: RefCell<Vec<Slot<T>>>,
+    result: Reg,
+    /// The functions compiled to instructions, which the program cannot
+    /// run while a registered function shadows them.
+    inlined: Vec<FunctionKind>,
+}
+
+impl<T: Arithmetic> Program<T> {
+    /// Compile a formula, or get None if it has no operators or `IF` to
+    /// compile, so running it would only walk its tree.
+    pub fn compile(formula: &Formula<T>) -> Option<Self> {
+        let mut compiler = Compiler{ops: Vec::new(), registers: 0, inlined: Vec::new()};
+        let result = compiler.formula(formula);
+        if matches!(compiler.ops.as_slice(), [Op::Tree{..}] | [Op::Load{..}]) {
+            return None;
+        }
+        let scratch = RefCell::new(Vec::new());
+        Some(Self{ops: compiler.ops, registers: compiler.registers, scratch, result, inlined: compiler.inlined})
+    }
+
+    /// Get the number of instructions.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Get the bytes the instructions and registers take on the heap.
+    pub(crate) fn heap_bytes(&self) -> usize {
+        let registers = self.scratch.try_borrow().map_or(0, |scratch| scratch.capacity());
+        self.ops.capacity() * std::mem::size_of::<Op<T>>() + registers * std::mem::size_of::<Slot<T>>()
+            + self.inlined.capacity() * std::mem::size_of::<FunctionKind>()
+    }
+
+    pub(crate) fn ops(&self) -> &[Op<T>] {
+        &self.ops
+    }
+
+    /// Take registers to run the program with, to give back with
+    /// `restore`. Nested runs get registers of their own.
+    pub(crate) fn registers(&self) -> Vec<Slot<T>> {
+        let mut registers = self.scratch.try_borrow_mut().map(|mut scratch| std::mem::take(&mut *scratch)).unwrap_or_default();
+        registers.resize(self.registers, Slot::Empty);
+        registers
+    }
+
+    pub(crate) fn restore(&self, registers: Vec<Slot<T>>) {
+        if let Ok(mut scratch) = self.scratch.try_borrow_mut() {
+            *scratch = registers;
+        }
+    }
+
+    pub(crate) fn result(&self) -> Reg {
+        self.result
+    }
+
+    pub(crate) fn inlined(&self) -> &[FunctionKind] {
+        &self.inlined
+    }
+}
+
+struct Compiler<T: Arithmetic> {
+    ops: Vec<Op<T>>,
+    registers: usize,
+    inlined: Vec<FunctionKind>,
+}
+
+impl<T: Arithmetic> Compiler<T> {
+    fn register(&mut self) -> Reg {
+        self.registers += 1;
+        (self.registers - 1) as Reg
+    }
+
+    /// Emit an instruction writing a new register, and get the register.
+    fn emit(&mut self, op: impl FnOnce(Reg) -> Op<T>) -> Reg {
+        let dst = self.register();
+        self.ops.push(op(dst));
+        dst
+    }
+
+    /// Set the target of the jump at `at` to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let next = self.ops.len();
+        match &mut self.ops[at] {
+            Op::Bail{target, ..} | Op::JumpUnless{target, ..} | Op::Jump{target} => *target = next,
+            _ => unreachable!("only jumps are patched"),
+        }
+    }
+
+    fn constant(&mut self, value: Value<T>) -> Reg {
+        self.emit(|dst| Op::Const{dst, slot: Slot::Value(value)})
+    }
+
+    /// Get the constant a register holds, if the latest instruction wrote it.
+    fn last_constant(&self, reg: Reg) -> Option<&Slot<T>> {
+        match self.ops.last() {
+            Some(Op::Const{dst, slot}) if *dst == reg => Some(slot),
+            _ => None,
+        }
+    }
+
+    /// Replace the constant the latest instruction wrote with another.
+    fn fold(&mut self, fold: impl FnOnce(Slot<T>) -> Slot<T>) {
+        if let Some(Op::Const{slot, ..}) = self.ops.last_mut() {
+            *slot = fold(std::mem::take(slot));
+        }
+    }
+
+    fn value(&mut self, value: &Value<T>) -> Reg {
+        match value {
+            Value::Formula(formula) => self.formula(formula),
+            Value::FormulaParseError(_) => self.constant(Value::Error(CellError::Name)),
+            value => self.constant(value.clone()),
+        }
+    }
+
+    fn number(&mut self, value: &Value<T>) -> Reg {
+        if let Value::Formula(formula) = value {
+            let load = match formula {
+                Formula::CellRef(cell_id) => Some((None, *cell_id)),
+                Formula::SheetRef(sheet, target) => match **target {
+                    Formula::CellRef(cell_id) => Some((Some(sheet.clone()), cell_id)),
+                    _ => None,
+                },
+                _ => None,
+            };
+            if let Some((sheet, cell_id)) = load {
+                return self.emit(|dst| Op::LoadNumber{dst, sheet, cell_id});
+            }
+        }
+        let src = self.value(value);
+        // Arithmetic gives numbers already.
+        if matches!(self.ops.last(), Some(Op::Arith{dst, ..} | Op::Unary{dst, ..}) if *dst == src) {
+            return src;
+        }
+        if self.last_constant(src).is_some() {
+            self.fold(|slot| match to_number(&slot.into_value()) {
+                Ok(number) => Slot::Number(number),
+                Err(e) => Slot::Error(e),
+            });
+            return src;
+        }
+        self.emit(|dst| Op::Number{dst, src})
+    }
+
+    fn comparable(&mut self, value: &Value<T>) -> Reg {
+        if let Value::Formula(Formula::CellRef(cell_id)) = value {
+            return self.emit(|dst| Op::ComparableCell{dst, cell_id: *cell_id});
+        }
+        let src = self.value(value);
+        if self.last_constant(src).is_some() {
+            self.fold(|slot| match Comparable::of(slot.into_value()) {
+                Ok(comparable) => Slot::Comparable(comparable),
+                Err(e) => Slot::Error(e),
+            });
+            return src;
+        }
+        self.emit(|dst| Op::Comparable{dst, src})
+    }
+
+    fn text(&mut self, value: &Value<T>) -> Reg {
+        match value {
+            Value::Formula(Formula::CellRef(cell_id)) => self.emit(|dst| Op::TextCell{dst, cell_id: *cell_id}),
+            value => {
+                let src = self.value(value);
+                self.emit(|dst| Op::Text{dst, src})
+            },
+        }
+    }
+
+    /// Compile an operator taking the right hand operand only if the left
+    /// hand one coerced without error, folding arithmetic on constants.
+    fn binary(&mut self, lhs: Reg, rhs: &Value<T>, coerce: fn(&mut Self, &Value<T>) -> Reg, op: impl FnOnce(Reg, Reg, Reg) -> Op<T>) -> Reg {
+        let dst = self.register();
+        let bail = match self.last_constant(lhs) {
+            Some(slot) if !matches!(slot, Slot::Error(_)) => None,
+            _ => {
+                self.ops.push(Op::Bail{src: lhs, dst, target: 0});
+                Some(self.ops.len() - 1)
+            },
+        };
+        let rhs = coerce(self, rhs);
+        let op = op(dst, lhs, rhs);
+        let folded = match (&op, self.ops.as_slice()) {
+            (Op::Arith{op, ..}, [.., Op::Const{dst: a, slot: a_slot}, Op::Const{dst: b, slot: b_slot}]) if (*a, *b) == (lhs, rhs) => match (a_slot, b_slot) {
+                (Slot::Number(a), Slot::Number(b)) => Some(match op.apply(a.clone(), b.clone()) {
+                    Ok(number) => Slot::Number(number),
+                    Err(e) => Slot::Error(e),
+                }),
+                (Slot::Number(_), Slot::Error(e)) => Some(Slot::Error(*e)),
+                _ => None,
+            },
+            _ => None,
+        };
+        match folded {
+            Some(slot) if bail.is_none() => {
+                self.ops.truncate(self.ops.len() - 2);
+                self.ops.push(Op::Const{dst, slot});
+            },
+            _ => self.ops.push(op),
+        }
+        if let Some(bail) = bail {
+            self.patch(bail);
+        }
+        dst
+    }
+
+    fn formula(&mut self, formula: &Formula<T>) -> Reg {
+        let arith = |op| move |dst, lhs, rhs| Op::Arith{dst, op, lhs, rhs};
+        let compare = |op| move |dst, lhs, rhs| Op::Compare{dst, op, lhs, rhs};
+        match formula {
+            Formula::NumberLit(number) => self.constant(Value::Primitive(Primitive::Number(number.clone()))),
+            Formula::TextLit(text) => self.constant(Value::Primitive(Primitive::Text(text.clone()))),
+            Formula::BoolLit(b) => self.constant(Value::Primitive(Primitive::Bool(*b))),
+            Formula::CellRef(cell_id) => self.emit(|dst| Op::Load{dst, sheet: None, cell_id: *cell_id}),
+            Formula::SheetRef(sheet, target) => match **target {
+                Formula::CellRef(cell_id) => self.emit(|dst| Op::Load{dst, sheet: Some(sheet.clone()), cell_id}),
+                _ => self.emit(|dst| Op::Tree{dst, formula: formula.clone()}),
+            },
+            Formula::Add(lhs, rhs) | Formula::Sub(lhs, rhs) | Formula::Mul(lhs, rhs) | Formula::Div(lhs, rhs) | Formula::Pow(lhs, rhs) => {
+                let op = match formula {
+                    Formula::Add(..) => Arith::Add,
+                    Formula::Sub(..) => Arith::Sub,
+                    Formula::Mul(..) => Arith::Mul,
+                    Formula::Div(..) => Arith::Div,
+                    _ => Arith::Pow,
+                };
+                let lhs = self.number(lhs);
+                self.binary(lhs, rhs, Self::number, arith(op))
+            },
+            Formula::Neg(operand) | Formula::Percent(operand) => {
+                let op = if matches!(formula, Formula::Neg(_)) { Unary::Neg } else { Unary::Percent };
+                let src = self.number(operand);
+                if let Some(Slot::Number(_)) = self.last_constant(src) {
+                    self.fold(|slot| match slot {
+                        Slot::Number(number) => Slot::Number(op.apply(number)),
+                        slot => slot,
+                    });
+                    return src;
+                }
+                self.emit(|dst| Op::Unary{dst, op, src})
+            },
+            // Both operands are evaluated even if the first is an error.
+            Formula::Concat(lhs, rhs) => {
+                let lhs = self.text(lhs);
+                let rhs = self.text(rhs);
+                self.emit(|dst| Op::Concat{dst, lhs, rhs})
+            },
+            Formula::Cmp(lhs, rhs) | Formula::Lt(lhs, rhs) | Formula::Gr(lhs, rhs)
+                | Formula::Le(lhs, rhs) | Formula::Ge(lhs, rhs) | Formula::Ne(lhs, rhs) => {
+                let op: fn(Ordering) -> bool = match formula {
+                    Formula::Cmp(..) => Ordering::is_eq,
+                    Formula::Lt(..) => Ordering::is_lt,
+                    Formula::Gr(..) => Ordering::is_gt,
+                    Formula::Le(..) => Ordering::is_le,
+                    Formula::Ge(..) => Ordering::is_ge,
+                    _ => Ordering::is_ne,
+                };
+                let lhs = self.comparable(lhs);
+                self.binary(lhs, rhs, Self::comparable, compare(op))
+            },
+            Formula::Function{kind: FunctionKind::If, arguments} if (1..=3).contains(&arguments.len()) => self.branch(arguments),
+            formula => self.emit(|dst| Op::Tree{dst, formula: formula.clone()}),
+        }
+    }
+
+    /// Compile `IF`, evaluating only the branch taken. A missing branch
+    /// gives whether the condition held.
+    fn branch(&mut self, arguments: &[Value<T>]) -> Reg {
+        if !self.inlined.contains(&FunctionKind::If) {
+            self.inlined.push(FunctionKind::If);
+        }
+        let cond = self.number(&arguments[0]);
+        let dst = self.register();
+        let bail = self.ops.len();
+        self.ops.push(Op::Bail{src: cond, dst, target: 0});
+        let unless = self.ops.len();
+        self.ops.push(Op::JumpUnless{cond, target: 0});
+        self.arm(dst, arguments.get(1), true);
+        let jump = self.ops.len();
+        self.ops.push(Op::Jump{target: 0});
+        self.patch(unless);
+        self.arm(dst, arguments.get(2), false);
+        self.patch(jump);
+        self.patch(bail);
+        dst
+    }
+
+    fn arm(&mut self, dst: Reg, value: Option<&Value<T>>, taken: bool) {
+        match value {
+            Some(value) => {
+                let src = self.value(value);
+                self.ops.push(Op::Move{dst, src});
+            },
+            None => self.ops.push(Op::Const{dst, slot: Slot::Value(Value::Primitive(Primitive::Bool(taken)))}),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::Kernel;
+    use crate::kernel::worksheet::Worksheet;
+
+    /// Inputs in column A: a number, zero, text, a percentage, TRUE, an
+    /// error and a number written as text.
+    const INPUTS: [&str; 7] = ["4", "0", "abc", "25%", "TRUE", "=1/0", "'7"];
+
+    fn sheet(compile_after: Option<u32>, formulas: &[&str]) -> Worksheet {
+        let mut sheet = Worksheet::new();
+        sheet.set_compile_after(compile_after);
+        for (row, input) in INPUTS.iter().enumerate() {
+            sheet.set_cell(CellId::new(row as u32, 0), input.to_string());
+        }
+        for (row, formula) in formulas.iter().enumerate() {
+            sheet.set_cell(CellId::new(row as u32, 1), formula.to_string());
+        }
+        sheet
+    }
+
+    /// Evaluate formulas interpreted and compiled, and check they agree
+    /// every time they are evaluated.
+    fn assert_equivalent(formulas: &[&str]) {
+        let tree = sheet(None, formulas);
+        let compiled = sheet(Some(1), formulas);
+        for _ in 0..3 {
+            for (row, formula) in formulas.iter().enumerate() {
+                let cell_id = CellId::new(row as u32, 1);
+                let expected = format!("{:?}", tree.evaluate_cell(cell_id).unwrap());
+                let actual = format!("{:?}", compiled.evaluate_cell(cell_id).unwrap());
+                assert_eq!(actual, expected, "{}", formula);
+            }
+        }
+        assert_eq!(tree.compiled_count(), 0);
+        assert!(compiled.compiled_count() > 0);
+    }
+
+    #[test]
+    fn compiles_only_formulas_with_operators() {
+        let compile = |source: &str| Program::<f64>::compile(&Formula::try_from(source).unwrap());
+        assert!(compile("A1").is_none());
+        assert!(compile("SUM(A1:A3)").is_none());
+        assert!(compile("A1*2+1").is_some_and(|program| !program.is_empty()));
+        assert!(compile("IF(A1,A2,A3)").is_some());
+    }
+
+    #[test]
+    fn errors_match_the_tree() {
+        assert_equivalent(&[
+            "=A1/A2", "=A1+A3", "=A6+1", "=-A6", "=A6&\"x\"", "=A6>1", "=A1^-A2*0+A2^-1",
+            "=(-8)^(1/3)", "=A1/A2+A6", "=A6+A1/A2", "=IFERROR(A1/A2,A1)+1", "=A8*2+1",
+        ]);
+    }
+
+    #[test]
+    fn if_short_circuits_like_the_tree() {
+        assert_equivalent(&[
+            "=IF(A1>0,A1,A1/A2)", "=IF(A2,A6,A1+1)", "=IF(A1,A1*2)", "=IF(A2,1)", "=IF(A3,1,2)+1",
+            "=IF(A6,1,2)+1", "=IF(A1>3,IF(A2,1,A1&\"!\"),A6)&\"\"", "=IF(A5,A4,A6)*2", "=IF(A7,1,2)+0",
+        ]);
+    }
+
+    #[test]
+    fn percentages_match_the_tree() {
+        assert_equivalent(&[
+            "=A4+A4", "=A1+A4", "=A4+1", "=A4*2+0", "=A1%+1", "=-A4+A4", "=A4-A1", "=50%+A1", "=(A1+A4)%+0",
+        ]);
+    }
+
+    #[test]
+    fn concatenation_and_comparison_match_the_tree() {
+        assert_equivalent(&[
+            "=A1&A3&A5", "=A4&\"\"", "=A1&A2+1", "=1&2=\"12\"", "=A3=\"ABC\"", "=A3<\"abd\"",
+            "=A1>A3", "=A5=TRUE", "=A9&\"x\"", "=A9=0", "=A7+1&A7", "=A1<>A1+0",
+        ]);
+    }
+}
@@ -0,0 +1,324 @@
+use super::arithmetic::Arithmetic;
+use super::csv::{quote_field, records};
+use super::format::Locale;
+use super::kernel::{escape_text, CellId, Kernel, NumericAttribute, Primitive, Value};
+use super::range::Range;
+use super::worksheet::Worksheet;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ClipboardError {
+    #[error("clipboard holds no cells")]
+    Empty,
+
+    #[error("line {0}: unterminated quoted field")]
+    UnterminatedQuote(usize),
+
+    #[error("pasting at {anchor} would extend past the sheet")]
+    OutOfBounds{anchor: CellId},
+}
+
+/// ClipboardData holds the two representations spreadsheets put on the
+/// clipboard when copying cells: an HTML table, and tab separated plain
+/// text. Hosts place both on the system clipboard under the names their
+/// platform uses for HTML and text.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClipboardData {
+    pub html: String,
+    pub text: String,
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\n', "<br>")
+}
+
+fn unescape_html(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest.find(';').map(|end| (&rest[1..end], end));
+        let decoded = entity.and_then(|(name, end)| {
+            let c = match name {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ => match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                    None => name.strip_prefix('#').and_then(|dec| dec.parse().ok()).and_then(char::from_u32),
+                },
+            };
+            c.map(|c| (c, end))
+        });
+        match decoded {
+            Some((c, end)) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            },
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            },
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// Find the value of an attribute in the text of an opening tag. Attributes
+/// without a value, like `x:str`, have an empty value.
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut search = 0;
+    while let Some(found) = lower[search..].find(name) {
+        let at = search + found;
+        search = at + name.len();
+        let after = &tag[search..];
+        let bounded = lower[..at].ends_with(char::is_whitespace)
+            && !after.starts_with(|c: char| c.is_alphanumeric() || c == ':' || c == '-');
+        if !bounded {
+            continue;
+        }
+        let Some(value) = after.trim_start().strip_prefix('=') else { return Some(String::new()) };
+        let value = value.trim_start();
+        let value = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value[1..].split(quote).next().unwrap_or(""),
+            _ => value.split(|c: char| c.is_whitespace() || c == '>').next().unwrap_or(""),
+        };
+        return Some(unescape_html(value));
+    }
+    None
+}
+
+/// Get the text of an HTML fragment, with tags removed. Line breaks in the
+/// source are whitespace, only `<br>` breaks lines.
+fn inner_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    loop {
+        let start = rest.find('<').unwrap_or(rest.len());
+        text.push_str(&unescape_html(&rest[..start].replace(['\r', '\n'], " ")));
+        if start == rest.len() {
+            break;
+        }
+        let end = rest[start..].find('>').map(|end| start + end + 1).unwrap_or(rest.len());
+        if rest[start..end].to_ascii_lowercase().starts_with("<br") {
+            text.push('\n');
+        }
+        rest = &rest[end..];
+    }
+    text.trim_matches(' ').to_string()
+}
+
+/// PastedCell is a cell read from an HTML clipboard table.
+struct PastedCell {
+    text: String,
+    formula: Option<String>,
+    number: Option<String>,
+    is_text: bool,
+}
+
+/// Find the next `<td>` or `<th>` tag in lowercased HTML.
+fn next_cell_tag(lower: &str) -> Option<usize> {
+    let mut search = 0;
+    while let Some(found) = lower[search..].find("<t") {
+        let at = search + found;
+        let tag = &lower[at + 2..];
+        if (tag.starts_with('d') || tag.starts_with('h')) && tag[1..].starts_with(|c: char| c.is_whitespace() || c == '>') {
+            return Some(at);
+        }
+        search = at + 2;
+    }
+    None
+}
+
+/// Read the cells of the first table of an HTML clipboard payload. Content
+/// outside the table, like the header of the Windows clipboard format, is
+/// ignored.
+fn html_cells(html: &str) -> Vec<Vec<PastedCell>> {
+    let lower = html.to_ascii_lowercase();
+    let mut rows = Vec::new();
+    let mut pos = 0;
+    while let Some(found) = lower[pos..].find("<tr") {
+        let row_start = pos + found;
+        let row_end = lower[row_start..].find("</tr").map(|end| row_start + end).unwrap_or(lower.len());
+        let mut cells = Vec::new();
+        let mut cell_pos = row_start;
+        while let Some(found) = next_cell_tag(&lower[cell_pos..row_end]) {
+            let tag_start = cell_pos + found;
+            let tag_end = lower[tag_start..].find('>').map(|end| tag_start + end + 1).unwrap_or(row_end).min(row_end);
+            let tag = &html[tag_start..tag_end];
+            let content_end = lower[tag_end..row_end].find("</t").map(|end| tag_end + end).unwrap_or(row_end);
+            cells.push(PastedCell{
+                text: inner_text(&html[tag_end..content_end]),
+                formula: attribute(tag, "x:fmla"),
+                number: attribute(tag, "x:num").filter(|number| number.parse::<f64>().is_ok()),
+                is_text: attribute(tag, "x:str").is_some(),
+            });
+            cell_pos = content_end.max(tag_end);
+        }
+        rows.push(cells);
+        pos = row_end.max(row_start + 3);
+    }
+    rows
+}
+
+/// Get the raw contents of a pasted number, keeping a percent or currency
+/// attribute its displayed text shows.
+fn with_attribute<T: Arithmetic>(number: &str, text: &str) -> String {
+    let attribute = match Primitive::<T>::try_from(text) {
+        Ok(Primitive::Number(shown)) => shown.attr().cloned(),
+        _ => None,
+    };
+    match (attribute, number.parse::<f64>()) {
+        (Some(NumericAttribute::Percent), Ok(value)) => format!("{}%", value * 100.0),
+        (Some(NumericAttribute::Currency(symbol)), _) => format!("{}{}", symbol, number),
+        _ => number.to_string(),
+    }
+}
+
+impl<T: Arithmetic> Range<'_, T> {
+    /// Copy the range to the clipboard. The text is tab separated raw cell
+    /// contents, so pasting it into a spreadsheet keeps formulas. The HTML
+    /// table shows formatted values and carries formulas, full precision
+    /// numbers and text markers in the attributes spreadsheet applications
+    /// read when pasting. Rows and columns hidden by collapsed outline
+    /// groups are left out of both.
+    pub fn to_clipboard(&self) -> ClipboardData {
+        let outline = self.sheet().outline();
+        let rows = (self.start().row()..=self.end().row()).filter(|row| !outline.is_row_hidden(*row)).collect::<Vec<_>>();
+        let cols = (self.start().col()..=self.end().col()).filter(|col| !outline.is_col_hidden(*col)).collect::<Vec<_>>();
+        let mut html = String::from("<html xmlns:x=\"urn:schemas-microsoft-com:office:excel\">\n<body>\n<table>\n");
+        let mut text = String::new();
+        for row in &rows {
+            html.push_str("<tr>");
+            let mut fields = Vec::new();
+            for col in &cols {
+                let cell_id = CellId::new(*row, *col);
+                let raw = self.sheet().cell(cell_id).map(|cell| cell.raw()).unwrap_or_default();
+                fields.push(quote_field(raw, '\t'));
+
+                let mut attributes = String::new();
+                if let Some(cell) = self.sheet().cell(cell_id) {
+                    if cell.formula().is_some() {
+                        attributes.push_str(&format!(" x:fmla=\"{}\"", escape_html(raw.trim())));
+                    } else if matches!(cell.value(), Value::Raw) && escape_text(cell.text()) != cell.text() {
+                        attributes.push_str(" x:str");
+                    }
+                    if let Ok(Value::Primitive(Primitive::Number(number))) = self.sheet().evaluate_cell(cell_id) {
+                        attributes.push_str(&format!(" x:num=\"{}\"", number.value().to_f64()));
+                    }
+                }
+                let displayed = self.sheet().formatted(cell_id, &Locale::default()).unwrap_or_else(|_| "#REF!".to_string());
+                html.push_str(&format!("<td{}>{}</td>", attributes, escape_html(&displayed)));
+            }
+            html.push_str("</tr>\n");
+            text.push_str(&fields.join("\t"));
+            text.push_str("\r\n");
+        }
+        html.push_str("</table>\n</body>\n</html>\n");
+        ClipboardData{html, text}
+    }
+}
+
+impl<'a, T: Arithmetic> Range<'a, T> {
+    /// Paste clipboard data into a sheet with its top left cell at `anchor`,
+    /// overwriting the cells it covers, and get the range pasted. The HTML
+    /// table is preferred when present, since it tells text, numbers and
+    /// formulas apart; otherwise the tab separated text is entered as if
+    /// typed.
+    /// Formulas are pasted as written, without adjusting references.
+    pub fn from_clipboard(sheet: &'a mut Worksheet<T>, anchor: CellId, data: &ClipboardData) -> Result<Self, ClipboardError> {
+        let rows = if data.html.to_ascii_lowercase().contains("<td") {
+            html_cells(&data.html).into_iter()
+                .map(|row| row.into_iter()
+                    .map(|cell| match (cell.formula, cell.number, cell.is_text) {
+                        (Some(formula), _, _) => formula,
+                        (None, Some(number), _) => with_attribute::<T>(&number, &cell.text),
+                        (None, None, true) => escape_text(&cell.text),
+                        (None, None, false) => cell.text,
+                    })
+                    .collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        } else {
+            records(data.text.trim_end_matches(['\r', '\n']), '\t')
+                .map_err(ClipboardError::UnterminatedQuote)?
+        };
+
+        let height = rows.len() as u32;
+        let width = rows.iter().map(|row| row.len()).max().unwrap_or(0) as u32;
+        if height == 0 || width == 0 {
+            return Err(ClipboardError::Empty);
+        }
+        let end = anchor.row().checked_add(height - 1)
+            .zip(anchor.col().checked_add(width - 1))
+            .map(|(row, col)| CellId::new(row, col))
+            .ok_or(ClipboardError::OutOfBounds{anchor})?;
+
+        for (row, fields) in rows.into_iter().enumerate() {
+            for col in 0..width as usize {
+                let raw = fields.get(col).cloned().unwrap_or_default();
+                sheet.set_cell(CellId::new(anchor.row() + row as u32, anchor.col() + col as u32), raw);
+            }
+        }
+        Ok(Range::new(sheet, anchor, end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sheet() -> Worksheet {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::parse("A1").unwrap(), "1".to_string());
+        sheet.set_cell(CellId::parse("B1").unwrap(), "=A1+2".to_string());
+        sheet.set_text(CellId::parse("A2").unwrap(), "007");
+        sheet.set_cell(CellId::parse("B2").unwrap(), "a <b> & \"c\"".to_string());
+        sheet
+    }
+
+    #[test]
+    fn copies_raw_text_and_an_html_table_of_values() {
+        let sheet = sheet();
+        let data = sheet.range(CellId::parse("A1").unwrap(), CellId::parse("B2").unwrap()).to_clipboard();
+        assert_eq!(data.text, "1\t=A1+2\r\n'007\t\"a <b> & \"\"c\"\"\"\r\n");
+        assert!(data.html.contains(" x:fmla=\"=A1+2\""), "{}", data.html);
+        assert!(data.html.contains(">3</td></tr>"), "{}", data.html);
+        assert!(data.html.contains("<td x:str>007</td><td>a &lt;b&gt; &amp; &quot;c&quot;</td>"), "{}", data.html);
+    }
+
+    #[test]
+    fn pastes_what_it_copies() {
+        let source = sheet();
+        let data = source.range(CellId::parse("A1").unwrap(), CellId::parse("B2").unwrap()).to_clipboard();
+        for data in [data.clone(), ClipboardData{html: String::new(), text: data.text.clone()}] {
+            let mut target: Worksheet = Worksheet::new();
+            let pasted = Range::from_clipboard(&mut target, CellId::parse("C3").unwrap(), &data).unwrap();
+            assert_eq!((pasted.start(), pasted.end()), (CellId::parse("C3").unwrap(), CellId::parse("D4").unwrap()));
+            for (from, to) in [("A1", "C3"), ("B1", "D3"), ("A2", "C4"), ("B2", "D4")] {
+                let raw = |sheet: &Worksheet, cell_id: &str| sheet.cell(CellId::parse(cell_id).unwrap()).map(|cell| cell.raw().to_string());
+                assert_eq!(raw(&target, to), raw(&source, from), "{}", from);
+            }
+        }
+    }
+
+    #[test]
+    fn pasting_needs_cells_inside_the_sheet() {
+        let mut sheet: Worksheet = Worksheet::new();
+        let empty = ClipboardData{html: String::new(), text: String::new()};
+        assert!(matches!(Range::from_clipboard(&mut sheet, CellId::new(0, 0), &empty), Err(ClipboardError::Empty)));
+        let wide = ClipboardData{html: String::new(), text: "1\t2".to_string()};
+        assert!(matches!(Range::from_clipboard(&mut sheet, CellId::new(0, u32::MAX), &wide), Err(ClipboardError::OutOfBounds{..})));
+        let open = ClipboardData{html: String::new(), text: "\"1\t2".to_string()};
+        assert!(matches!(Range::from_clipboard(&mut sheet, CellId::new(0, 0), &open), Err(ClipboardError::UnterminatedQuote(_))));
+    }
+}
@@ -0,0 +1,181 @@
+use super::arithmetic::Arithmetic;
+use super::diff::{ChangeKind, FormatPart, WorkbookDiff};
+use super::kernel::{CellId, Kernel};
+use super::worksheet::Worksheet;
+
+/// Category groups the entries of a compare report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Category {
+    Sheet,
+    Formula,
+    Value,
+    Range,
+    Format,
+}
+
+impl Category {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Sheet => "Sheet",
+            Self::Formula => "Formula",
+            Self::Value => "Value",
+            Self::Range => "Range",
+            Self::Format => "Format",
+        }
+    }
+}
+
+/// ReportEntry is one line of a compare report.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportEntry {
+    pub sheet: String,
+    /// The cell or range affected, empty for whole sheets.
+    pub location: String,
+    pub category: Category,
+    pub change: &'static str,
+    pub before: String,
+    pub after: String,
+}
+
+/// CompareReport is a human readable comparison of two workbooks, listing
+/// added and removed sheets, new, changed and removed formulas and values,
+/// moved ranges, and changed number formats and styles. It can be
+/// rendered as a worksheet or as HTML.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompareReport {
+    pub entries: Vec<ReportEntry>,
+}
+
+fn change_label(kind: ChangeKind) -> &'static str {
+    match kind {
+        ChangeKind::Added => "Added",
+        ChangeKind::Removed => "Removed",
+        ChangeKind::Changed => "Changed",
+    }
+}
+
+fn range_label(range: (CellId, CellId)) -> String {
+    if range.0 == range.1 {
+        range.0.to_string()
+    } else {
+        format!("{}:{}", range.0, range.1)
+    }
+}
+
+pub(crate) fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl CompareReport {
+    pub fn new(diff: &WorkbookDiff) -> Self {
+        let mut entries = Vec::new();
+        for (sheets, change) in [(&diff.added_sheets, "Added"), (&diff.removed_sheets, "Removed")] {
+            for sheet in sheets {
+                entries.push(ReportEntry{
+                    sheet: sheet.clone(),
+                    location: String::new(),
+                    category: Category::Sheet,
+                    change,
+                    before: String::new(),
+                    after: String::new(),
+                });
+            }
+        }
+        for change in &diff.changes {
+            entries.push(ReportEntry{
+                sheet: change.sheet.clone(),
+                location: change.cell_id.to_string(),
+                category: if change.is_formula() { Category::Formula } else { Category::Value },
+                change: change_label(change.kind),
+                before: change.before.clone().unwrap_or_default(),
+                after: change.after.clone().unwrap_or_default(),
+            });
+        }
+        for moved in &diff.moved {
+            entries.push(ReportEntry{
+                sheet: moved.sheet.clone(),
+                location: range_label(moved.from),
+                category: Category::Range,
+                change: "Moved",
+                before: range_label(moved.from),
+                after: range_label(moved.to),
+            });
+        }
+        for change in &diff.formats {
+            entries.push(ReportEntry{
+                sheet: change.sheet.clone(),
+                location: change.cell_id.to_string(),
+                category: Category::Format,
+                change: match change.part {
+                    FormatPart::NumberFormat => "Number format",
+                    FormatPart::Style => "Style",
+                },
+                before: change.before.clone(),
+                after: change.after.clone(),
+            });
+        }
+        entries.sort_by(|a, b| (a.category, &a.sheet).cmp(&(b.category, &b.sheet)));
+        Self{entries}
+    }
+
+    /// Count the entries in a category.
+    pub fn count(&self, category: Category) -> usize {
+        self.entries.iter().filter(|entry| entry.category == category).count()
+    }
+
+    /// Render the report as a worksheet with a header row.
+    pub fn to_worksheet<T: Arithmetic>(&self) -> Worksheet<T> {
+        let mut sheet = Worksheet::new();
+        let header = ["Sheet", "Location", "Category", "Change", "Before", "After"];
+        for (col, title) in header.iter().enumerate() {
+            sheet.set_cell(CellId::new(0, col as u32), title.to_string());
+        }
+        for (row, entry) in self.entries.iter().enumerate() {
+            let columns = [
+                entry.sheet.as_str(),
+                entry.location.as_str(),
+                entry.category.label(),
+                entry.change,
+                entry.before.as_str(),
+                entry.after.as_str(),
+            ];
+            for (col, text) in columns.iter().enumerate() {
+                sheet.set_text(CellId::new(row as u32 + 1, col as u32), text);
+            }
+        }
+        sheet
+    }
+
+    /// Render the report as a standalone HTML document.
+    pub fn to_html(&self) -> String {
+        let mut html = String::from("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Spreadsheet Compare</title></head>\n<body>\n");
+        html.push_str("<h1>Spreadsheet Compare</h1>\n<ul>\n");
+        for category in [Category::Sheet, Category::Formula, Category::Value, Category::Range, Category::Format] {
+            html.push_str(&format!("<li>{}: {} changes</li>\n", category.label(), self.count(category)));
+        }
+        html.push_str("</ul>\n<table>\n<tr><th>Sheet</th><th>Location</th><th>Category</th><th>Change</th><th>Before</th><th>After</th></tr>\n");
+        for entry in &self.entries {
+            html.push_str(&format!(
+                "<tr class=\"{}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                entry.change.to_lowercase().replace(' ', "-"),
+                escape_html(&entry.sheet),
+                escape_html(&entry.location),
+                entry.category.label(),
+                entry.change,
+                escape_html(&entry.before),
+                escape_html(&entry.after),
+            ));
+        }
+        html.push_str("</table>\n</body>\n</html>\n");
+        html
+    }
+}
+
+impl From<&WorkbookDiff> for CompareReport {
+    fn from(diff: &WorkbookDiff) -> Self {
+        Self::new(diff)
+    }
+}
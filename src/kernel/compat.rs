@@ -0,0 +1,214 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{CellId, Formula, FunctionKind, Value};
+use super::workbook::Workbook;
+use thiserror::Error;
+
+/// Target is an application a workbook is exported for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Target {
+    Excel2016,
+    Excel365,
+    LibreOffice,
+}
+
+/// Limits are the bounds a target application imposes on a workbook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub sheet_name_length: usize,
+    pub rows: u32,
+    pub columns: u32,
+    /// The maximum number of characters of a formula, `=` excluded.
+    pub formula_length: usize,
+    /// The maximum number of nested function calls, if limited.
+    pub nesting_depth: Option<usize>,
+}
+
+impl Target {
+    pub fn limits(&self) -> Limits {
+        match self {
+            Self::Excel2016 | Self::Excel365 => Limits{
+                sheet_name_length: 31,
+                rows: 1048576,
+                columns: 16384,
+                formula_length: 8192,
+                nesting_depth: Some(64),
+            },
+            Self::LibreOffice => Limits{
+                sheet_name_length: 31,
+                rows: 1048576,
+                columns: 16384,
+                formula_length: 8192,
+                nesting_depth: None,
+            },
+        }
+    }
+
+    /// Whether the target can evaluate a function. No target keeps the
+    /// data snapshots `CHANGEDSINCE` compares against.
+    pub fn supports(&self, kind: FunctionKind) -> bool {
+        !matches!((self, kind), (Self::LibreOffice, FunctionKind::CubeValue) | (_, FunctionKind::ChangedSince))
+    }
+}
+
+/// Characters which may not appear in a sheet name.
+const SHEET_NAME_FORBIDDEN: [char; 7] = ['\\', '/', '?', '*', '[', ']', ':'];
+
+/// Problem is a way in which a workbook exceeds what a target supports.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    #[error("sheet name is empty")]
+    EmptySheetName,
+
+    #[error("sheet name has {length} characters, at most {max} are allowed")]
+    SheetNameTooLong{length: usize, max: usize},
+
+    #[error("sheet name contains {0:?}")]
+    InvalidSheetNameChar(char),
+
+    #[error("sheet name starts or ends with an apostrophe")]
+    SheetNameApostrophe,
+
+    #[error("cell is outside the {rows} by {columns} grid")]
+    OutOfBounds{rows: u32, columns: u32},
+
+    #[error("formula has {length} characters, at most {max} are allowed")]
+    FormulaTooLong{length: usize, max: usize},
+
+    #[error("functions are nested {depth} levels deep, at most {max} are allowed")]
+    NestingTooDeep{depth: usize, max: usize},
+
+    #[error("function {} is not supported", .0.name())]
+    UnsupportedFunction(FunctionKind),
+}
+
+/// Violation is a problem found at a sheet or one of its cells.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub sheet: String,
+    /// The offending cell, or `None` for problems with the sheet itself.
+    pub cell_id: Option<CellId>,
+    pub problem: Problem,
+}
+
+fn sheet_name_problems(name: &str, limits: &Limits) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let length = name.chars().count();
+    if length == 0 {
+        problems.push(Problem::EmptySheetName);
+    }
+    if length > limits.sheet_name_length {
+        problems.push(Problem::SheetNameTooLong{length, max: limits.sheet_name_length});
+    }
+    if let Some(c) = name.chars().find(|c| SHEET_NAME_FORBIDDEN.contains(c)) {
+        problems.push(Problem::InvalidSheetNameChar(c));
+    }
+    if name.starts_with('\'') || name.ends_with('\'') {
+        problems.push(Problem::SheetNameApostrophe);
+    }
+    problems
+}
+
+/// Get the deepest nesting of function calls in a formula, and collect the
+/// functions it calls.
+fn function_nesting<T: Arithmetic>(formula: &Formula<T>, functions: &mut Vec<FunctionKind>) -> usize {
+    let own = match formula {
+        Formula::Function{kind, ..} => {
+            if !functions.contains(kind) {
+                functions.push(*kind);
+            }
+            1
+        },
+        Formula::Custom{..} => 1,
+        _ => 0,
+    };
+    let nested = match formula {
+        Formula::SheetRef(_, target) => function_nesting(target, functions),
+        _ => formula.operands().into_iter()
+            .map(|operand| match operand {
+                Value::Formula(operand) => function_nesting(operand, functions),
+                _ => 0,
+            })
+            .max()
+            .unwrap_or(0),
+    };
+    own + nested
+}
+
+impl<T: Arithmetic> Workbook<T> {
+    /// Check the workbook against the limits of a target application before
+    /// exporting it. Styles are not modelled, so limits on the number of
+    /// styles are not checked.
+    pub fn validate_compatibility(&self, target: Target) -> Vec<Violation> {
+        let limits = target.limits();
+        let mut violations = Vec::new();
+        for (name, sheet) in self.sheets() {
+            let mut report = |cell_id, problem| violations.push(Violation{sheet: name.to_string(), cell_id, problem});
+            for problem in sheet_name_problems(name, &limits) {
+                report(None, problem);
+            }
+            for cell_id in sheet.cell_ids() {
+                if cell_id.row() >= limits.rows || cell_id.col() >= limits.columns {
+                    report(Some(cell_id), Problem::OutOfBounds{rows: limits.rows, columns: limits.columns});
+                }
+                let cell = sheet.cell(cell_id).expect("cell ids come from the sheet");
+                let Some(formula) = cell.formula() else { continue };
+                let length = cell.raw().trim().chars().count() - 1;
+                if length > limits.formula_length {
+                    report(Some(cell_id), Problem::FormulaTooLong{length, max: limits.formula_length});
+                }
+                let mut functions = Vec::new();
+                let depth = function_nesting(formula, &mut functions);
+                if let Some(max) = limits.nesting_depth.filter(|max| depth > *max) {
+                    report(Some(cell_id), Problem::NestingTooDeep{depth, max});
+                }
+                for kind in functions.into_iter().filter(|kind| !target.supports(*kind)) {
+                    report(Some(cell_id), Problem::UnsupportedFunction(kind));
+                }
+            }
+        }
+        violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(workbook: &Workbook, target: Target) -> Vec<(Option<String>, Problem)> {
+        workbook.validate_compatibility(target).into_iter()
+            .map(|violation| (violation.cell_id.map(|cell_id| cell_id.to_string()), violation.problem))
+            .collect()
+    }
+
+    #[test]
+    fn reports_sheet_names_targets_reject() {
+        let mut workbook = Workbook::new();
+        workbook.add_sheet("Q1: Sales").unwrap();
+        workbook.add_sheet(&"x".repeat(32)).unwrap();
+        workbook.add_sheet("'quoted'").unwrap();
+        let problems = problems(&workbook, Target::Excel365).into_iter().map(|(_, problem)| problem).collect::<Vec<_>>();
+        assert_eq!(problems, [
+            Problem::InvalidSheetNameChar(':'),
+            Problem::SheetNameTooLong{length: 32, max: 31},
+            Problem::SheetNameApostrophe,
+        ]);
+    }
+
+    #[test]
+    fn reports_cells_beyond_the_limits() {
+        let mut workbook = Workbook::new();
+        workbook.add_sheet("Sheet1").unwrap();
+        workbook.set_cell("Sheet1", CellId::new(1048576, 0), "1".to_string()).unwrap();
+        let nested = format!("={}1{}", "SUM(".repeat(65), ")".repeat(65));
+        workbook.set_cell("Sheet1", CellId::new(0, 1), nested).unwrap();
+        workbook.set_cell("Sheet1", CellId::new(0, 2), "=CUBEVALUE(A1,A2)".to_string()).unwrap();
+        assert_eq!(problems(&workbook, Target::Excel2016), [
+            (Some("B1".to_string()), Problem::NestingTooDeep{depth: 65, max: 64}),
+            (Some("A1048577".to_string()), Problem::OutOfBounds{rows: 1048576, columns: 16384}),
+        ]);
+        assert_eq!(problems(&workbook, Target::LibreOffice), [
+            (Some("C1".to_string()), Problem::UnsupportedFunction(FunctionKind::CubeValue)),
+            (Some("A1048577".to_string()), Problem::OutOfBounds{rows: 1048576, columns: 16384}),
+        ]);
+    }
+}
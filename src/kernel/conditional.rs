@@ -0,0 +1,196 @@
+use super::arithmetic::Arithmetic;
+use super::array::Array2D;
+use super::kernel::{Primitive, Value};
+use super::range::Range;
+use super::worksheet::SheetError;
+
+/// Color is an RGB color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Self{r, g, b}
+    }
+
+    /// Get the color a fraction of the way from this color to another.
+    pub fn lerp(self, other: Self, fraction: f64) -> Self {
+        let fraction = fraction.clamp(0.0, 1.0);
+        let mix = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * fraction).round() as u8;
+        Self{r: mix(self.r, other.r), g: mix(self.g, other.g), b: mix(self.b, other.b)}
+    }
+}
+
+/// Threshold is a point of the numbers of a range a conditional format is
+/// relative to. Percents and percentiles are fractions, such as 0.5 for
+/// the middle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Threshold {
+    Min,
+    Max,
+    Number(f64),
+    /// The fraction of the way from the smallest to the largest number.
+    Percent(f64),
+    /// The number which a fraction of the numbers do not exceed, as by
+    /// `PERCENTILE.INC`.
+    Percentile(f64),
+}
+
+impl Threshold {
+    /// Resolve the threshold against the numbers of a range, in order.
+    fn resolve(&self, sorted: &[f64]) -> f64 {
+        let (min, max) = (sorted[0], sorted[sorted.len() - 1]);
+        match *self {
+            Self::Min => min,
+            Self::Max => max,
+            Self::Number(number) => number,
+            Self::Percent(fraction) => min + (max - min) * fraction.clamp(0.0, 1.0),
+            Self::Percentile(fraction) => {
+                let rank = fraction.clamp(0.0, 1.0) * (sorted.len() - 1) as f64;
+                let below = rank.floor() as usize;
+                match sorted.get(below + 1) {
+                    Some(above) => sorted[below] + (above - sorted[below]) * rank.fract(),
+                    None => sorted[below],
+                }
+            },
+        }
+    }
+}
+
+/// Visual is how a rendering host draws one cell of a conditional format.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Visual {
+    /// A data bar filling this fraction of the cell.
+    Bar(f64),
+    /// A fill color.
+    Color(Color),
+    /// The icon at this index of the icon set, from the lowest.
+    Icon(usize),
+}
+
+/// ConditionalFormat is a format whose look depends on the numbers of a
+/// range, like the data bars, color scales and icon sets of spreadsheets.
+/// The kernel computes the visuals so hosts only draw them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConditionalFormat {
+    /// Bars as long as the number is between the thresholds.
+    DataBar{min: Threshold, max: Threshold},
+    /// Colors interpolated between stops, which are in ascending order.
+    ColorScale{stops: Vec<(Threshold, Color)>},
+    /// One icon more than there are thresholds: a number gets the index of
+    /// the highest threshold it reaches, in ascending order, plus one.
+    IconSet{thresholds: Vec<Threshold>},
+}
+
+impl ConditionalFormat {
+    /// Create data bars from the smallest to the largest number.
+    pub fn data_bar() -> Self {
+        Self::DataBar{min: Threshold::Min, max: Threshold::Max}
+    }
+
+    /// Create a color scale from the smallest to the largest number.
+    pub fn color_scale(low: Color, high: Color) -> Self {
+        Self::ColorScale{stops: vec![(Threshold::Min, low), (Threshold::Max, high)]}
+    }
+
+    /// Create a set of `icons` icons, splitting the numbers into equal
+    /// parts between the smallest and the largest.
+    pub fn icon_set(icons: usize) -> Self {
+        let thresholds = (1..icons).map(|index| Threshold::Percent(index as f64 / icons as f64)).collect();
+        Self::IconSet{thresholds}
+    }
+
+    /// Compute the visual of every cell of a range. Cells which do not
+    /// evaluate to numbers get none, as do all cells of a color scale
+    /// without stops.
+    pub fn compute<T: Arithmetic>(&self, range: &Range<'_, T>) -> Result<Array2D<Option<Visual>>, SheetError> {
+        let numbers = range.evaluate()?.map(|value| match value {
+            Value::Primitive(Primitive::Number(number)) => Some(number.value().to_f64()),
+            _ => None,
+        });
+        let mut sorted = numbers.values().iter().flatten().copied().filter(|number| !number.is_nan()).collect::<Vec<_>>();
+        if sorted.is_empty() {
+            return Ok(numbers.map(|_| None));
+        }
+        sorted.sort_by(f64::total_cmp);
+        Ok(numbers.map(|number| number.and_then(|number| self.visual(number, &sorted))))
+    }
+
+    fn visual(&self, number: f64, sorted: &[f64]) -> Option<Visual> {
+        let visual = match self {
+            Self::DataBar{min, max} => {
+                let (min, max) = (min.resolve(sorted), max.resolve(sorted));
+                match max > min {
+                    true => Visual::Bar(((number - min) / (max - min)).clamp(0.0, 1.0)),
+                    false => Visual::Bar(if number >= max { 1.0 } else { 0.0 }),
+                }
+            },
+            Self::ColorScale{stops} => {
+                let stops = stops.iter().map(|(threshold, color)| (threshold.resolve(sorted), *color)).collect::<Vec<_>>();
+                let &(_, mut color) = stops.first()?;
+                for pair in stops.windows(2) {
+                    let ((low, from), (high, to)) = (pair[0], pair[1]);
+                    if number >= high {
+                        color = to;
+                    } else if number > low {
+                        color = from.lerp(to, (number - low) / (high - low));
+                        break;
+                    } else {
+                        break;
+                    }
+                }
+                Visual::Color(color)
+            },
+            Self::IconSet{thresholds} => {
+                Visual::Icon(thresholds.iter().filter(|threshold| number >= threshold.resolve(sorted)).count())
+            },
+        };
+        Some(visual)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::{CellId, Kernel};
+    use crate::kernel::worksheet::Worksheet;
+
+    fn sheet() -> Worksheet {
+        let mut sheet: Worksheet = Worksheet::new();
+        for (row, data) in ["0", "25", "=A1+50", "x", "100"].iter().enumerate() {
+            sheet.set_cell(CellId::new(row as u32, 0), data.to_string());
+        }
+        sheet
+    }
+
+    fn visuals(format: &ConditionalFormat) -> Vec<Option<Visual>> {
+        let sheet = sheet();
+        format.compute(&sheet.range(CellId::new(0, 0), CellId::new(4, 0))).unwrap().into_values()
+    }
+
+    #[test]
+    fn bars_and_icons_follow_the_numbers_of_the_range() {
+        assert_eq!(visuals(&ConditionalFormat::data_bar()), [Some(Visual::Bar(0.0)), Some(Visual::Bar(0.25)), Some(Visual::Bar(0.5)), None, Some(Visual::Bar(1.0))]);
+        let capped = ConditionalFormat::DataBar{min: Threshold::Number(50.0), max: Threshold::Percentile(1.0)};
+        assert_eq!(visuals(&capped)[1], Some(Visual::Bar(0.0)));
+        let icons = visuals(&ConditionalFormat::icon_set(3));
+        assert_eq!(icons, [Some(Visual::Icon(0)), Some(Visual::Icon(0)), Some(Visual::Icon(1)), None, Some(Visual::Icon(2))]);
+    }
+
+    #[test]
+    fn color_scales_interpolate_between_stops() {
+        let (black, white, red) = (Color::rgb(0, 0, 0), Color::rgb(255, 255, 255), Color::rgb(255, 0, 0));
+        let two = visuals(&ConditionalFormat::color_scale(black, white));
+        assert_eq!(two[2], Some(Visual::Color(Color::rgb(128, 128, 128))));
+        let three = ConditionalFormat::ColorScale{stops: vec![(Threshold::Min, black), (Threshold::Percentile(0.5), white), (Threshold::Max, red)]};
+        let three = visuals(&three);
+        assert_eq!(three[0], Some(Visual::Color(black)));
+        assert_eq!(three[4], Some(Visual::Color(red)));
+        assert_eq!(visuals(&ConditionalFormat::ColorScale{stops: Vec::new()})[0], None);
+        assert_eq!(Threshold::Percentile(0.5).resolve(&[0.0, 25.0, 50.0, 100.0]), 37.5);
+    }
+}
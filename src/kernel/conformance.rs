@@ -0,0 +1,418 @@
+use super::arithmetic::Arithmetic;
+use super::format::to_serial;
+use super::journal::{escape, unescape};
+use super::kernel::{CellError, CellId, Formula, Primitive, Value};
+use super::workbook::{Workbook, WorkbookError};
+use thiserror::Error;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+#[derive(Error, Debug)]
+pub enum ConformanceError {
+    /// A fixture could not be read, as reported by its reader.
+    #[error("{path}: {message}")]
+    Fixture{path: PathBuf, message: String},
+
+    #[error("{path}, line {line}: {message}")]
+    Golden{path: PathBuf, line: usize, message: String},
+
+    #[error(transparent)]
+    Workbook(#[from] WorkbookError),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Expected is a result Excel cached for a formula, or the result the kernel
+/// computed in the same terms. Dates and times are serial numbers, as Excel
+/// stores them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expected {
+    Empty,
+    Number(f64),
+    Text(String),
+    Bool(bool),
+    Error(CellError),
+}
+
+const ERRORS: [CellError; 9] = [
+    CellError::Null, CellError::Div0, CellError::Value, CellError::Ref, CellError::Name,
+    CellError::Num, CellError::NA, CellError::Spill, CellError::Calc,
+];
+
+impl Expected {
+    /// Get the result of a formula as Excel would cache it.
+    pub fn of<T: Arithmetic>(value: &Value<T>) -> Self {
+        match value {
+            Value::Empty => Self::Empty,
+            Value::Primitive(Primitive::Number(number)) => Self::Number(number.value().to_f64()),
+            Value::Primitive(Primitive::Bool(b)) => Self::Bool(*b),
+            Value::Primitive(Primitive::Text(text)) => Self::Text(text.clone()),
+            Value::Primitive(Primitive::Date(date)) => Self::Number(to_serial(*date)),
+            Value::Primitive(Primitive::Time(time)) => Self::Number(time.num_milliseconds() as f64 / 86_400_000.0),
+            Value::Primitive(Primitive::IPAddress([a, b, c, d])) => Self::Text(format!("{}.{}.{}.{}", a, b, c, d)),
+            Value::Error(e) => Self::Error(*e),
+            Value::FormulaParseError(_) => Self::Error(CellError::Name),
+            _ => Self::Error(CellError::Value),
+        }
+    }
+
+    /// Parse a result as golden files write it: a number, `TRUE`, `FALSE`,
+    /// an error, quoted text or nothing for an empty result. Anything else
+    /// is text as is.
+    pub fn parse(text: &str) -> Self {
+        if text.is_empty() {
+            return Self::Empty;
+        }
+        if let Some(quoted) = text.strip_prefix('"').and_then(|text| text.strip_suffix('"')) {
+            return Self::Text(quoted.replace("\"\"", "\""));
+        }
+        match text {
+            "TRUE" => return Self::Bool(true),
+            "FALSE" => return Self::Bool(false),
+            _ => {},
+        }
+        if let Some(e) = ERRORS.iter().find(|e| e.to_string() == text) {
+            return Self::Error(*e);
+        }
+        match text.parse::<f64>() {
+            Ok(number) => Self::Number(number),
+            Err(_) => Self::Text(text.to_string()),
+        }
+    }
+
+    /// Whether a result agrees with this one, numbers within `tolerance` of
+    /// each other relative to the larger.
+    pub fn matches(&self, actual: &Self, tolerance: f64) -> bool {
+        match (self, actual) {
+            (Self::Number(expected), Self::Number(actual)) => {
+                let scale = expected.abs().max(actual.abs()).max(1.0);
+                expected == actual || (expected - actual).abs() <= tolerance * scale
+            },
+            (expected, actual) => expected == actual,
+        }
+    }
+}
+
+impl fmt::Display for Expected {
+    /// Write the result the way golden files do.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => Ok(()),
+            Self::Number(number) => write!(f, "{}", number),
+            Self::Text(text) => write!(f, "\"{}\"", text.replace('"', "\"\"")),
+            Self::Bool(b) => write!(f, "{}", if *b { "TRUE" } else { "FALSE" }),
+            Self::Error(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// FixtureCell is a cell of a fixture: its raw contents and, for formulas,
+/// the result Excel cached.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FixtureCell {
+    pub sheet: String,
+    pub cell_id: CellId,
+    pub raw: String,
+    pub expected: Option<Expected>,
+}
+
+/// Fixture is a workbook saved by Excel, as the cells of its sheets.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Fixture {
+    pub name: String,
+    pub cells: Vec<FixtureCell>,
+}
+
+impl Fixture {
+    /// Write the fixture as a golden file: a line per cell of its location,
+    /// raw contents and expected result, separated by tabs, with tabs and
+    /// line breaks escaped. Converting
+    /// `.xlsx` fixtures once lets them be checked without a reader.
+    pub fn write_golden(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writeln!(writer, "# {}", self.name)?;
+        for cell in &self.cells {
+            write!(writer, "{}!{}\t{}", cell.sheet, cell.cell_id, escape(&cell.raw))?;
+            if let Some(expected) = &cell.expected {
+                write!(writer, "\t{}", escape(&expected.to_string()))?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Read a golden file. Results are only read for formulas, and a formula
+    /// without one is not checked. Lines starting with `#` are comments.
+    pub fn read_golden(path: &Path) -> Result<Self, ConformanceError> {
+        let name = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        let mut cells = Vec::new();
+        for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: &str| ConformanceError::Golden{path: path.to_path_buf(), line: index + 1, message: message.to_string()};
+            let mut fields = line.splitn(3, '\t');
+            let location = fields.next().unwrap_or_default();
+            let raw = unescape(fields.next().ok_or_else(|| error("no contents"))?).ok_or_else(|| error("bad escape"))?;
+            let (sheet, cell) = location.rsplit_once('!').ok_or_else(|| error("the location has no sheet"))?;
+            let cell_id = CellId::parse(cell).map_err(|_| error("not a cell"))?;
+            let expected = match fields.next().filter(|_| raw.starts_with('=')) {
+                Some(expected) => Some(Expected::parse(&unescape(expected).ok_or_else(|| error("bad escape"))?)),
+                None => None,
+            };
+            cells.push(FixtureCell{sheet: sheet.to_string(), cell_id, raw, expected});
+        }
+        Ok(Self{name, cells})
+    }
+}
+
+/// FixtureReader reads fixtures from files. Implement it on top of an
+/// `.xlsx` reader to check workbooks saved by Excel directly, taking each
+/// formula from the `f` element of its cell and the cached result from the
+/// `v` element.
+pub trait FixtureReader {
+    fn read(&mut self, path: &Path) -> Result<Fixture, ConformanceError>;
+}
+
+/// GoldenFiles reads fixtures from golden files.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GoldenFiles;
+
+impl FixtureReader for GoldenFiles {
+    fn read(&mut self, path: &Path) -> Result<Fixture, ConformanceError> {
+        Fixture::read_golden(path)
+    }
+}
+
+/// ConformanceOptions tunes how results are compared.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConformanceOptions {
+    /// How far apart numbers may be, relative to the larger one, to agree.
+    pub tolerance: f64,
+}
+
+impl Default for ConformanceOptions {
+    fn default() -> Self {
+        Self{tolerance: 1e-9}
+    }
+}
+
+/// Deviation is a formula whose result differs from the one Excel cached.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Deviation {
+    pub fixture: String,
+    pub sheet: String,
+    pub cell_id: CellId,
+    pub formula: String,
+    pub expected: Expected,
+    /// The result of the kernel, or the error it failed with.
+    pub actual: Result<Expected, String>,
+}
+
+/// Coverage counts the formulas calling a function which were checked, and
+/// those which agreed with Excel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Coverage {
+    pub checked: usize,
+    pub passed: usize,
+}
+
+impl Coverage {
+    fn add(&mut self, other: Self) {
+        self.checked += other.checked;
+        self.passed += other.passed;
+    }
+
+    /// Get the fraction of the checked formulas which agreed with Excel.
+    pub fn pass_rate(&self) -> f64 {
+        match self.checked {
+            0 => 1.0,
+            checked => self.passed as f64 / checked as f64,
+        }
+    }
+}
+
+/// ConformanceReport is how the results of the kernel compare with those
+/// Excel cached, overall and by function. A formula counts towards every
+/// function it calls.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConformanceReport {
+    pub total: Coverage,
+    pub functions: BTreeMap<String, Coverage>,
+    pub deviations: Vec<Deviation>,
+}
+
+/// Collect the names of the functions a formula calls.
+fn function_names<T: Arithmetic>(formula: &Formula<T>, names: &mut BTreeSet<String>) {
+    if let Some(name) = formula.function_name() {
+        names.insert(name.to_uppercase());
+    }
+    if let Formula::SheetRef(_, target) = formula {
+        return function_names(target, names);
+    }
+    for operand in formula.operands() {
+        if let Value::Formula(operand) = operand {
+            function_names(operand, names);
+        }
+    }
+}
+
+impl ConformanceReport {
+    /// Evaluate the formulas of a fixture and compare their results.
+    pub fn check(fixture: &Fixture, options: &ConformanceOptions) -> Result<Self, ConformanceError> {
+        let mut workbook = Workbook::<f64>::new();
+        for cell in &fixture.cells {
+            if workbook.sheet(&cell.sheet).is_none() {
+                workbook.add_sheet(&cell.sheet)?;
+            }
+            workbook.set_cell(&cell.sheet, cell.cell_id, cell.raw.clone())?;
+        }
+        let mut report = Self::default();
+        for cell in &fixture.cells {
+            let Some(expected) = &cell.expected else { continue };
+            let mut names = BTreeSet::new();
+            if let Some(formula) = workbook.sheet(&cell.sheet).and_then(|sheet| sheet.cell(cell.cell_id)).and_then(|cell| cell.formula()) {
+                function_names(formula, &mut names);
+            }
+            let actual = workbook.evaluate_cell(&cell.sheet, cell.cell_id)
+                .map(|value| Expected::of(&value))
+                .map_err(|e| e.to_string());
+            let passed = actual.as_ref().is_ok_and(|actual| expected.matches(actual, options.tolerance));
+            let coverage = Coverage{checked: 1, passed: passed as usize};
+            report.total.add(coverage);
+            for name in names {
+                report.functions.entry(name).or_default().add(coverage);
+            }
+            if !passed {
+                report.deviations.push(Deviation{
+                    fixture: fixture.name.clone(),
+                    sheet: cell.sheet.clone(),
+                    cell_id: cell.cell_id,
+                    formula: cell.raw.clone(),
+                    expected: expected.clone(),
+                    actual,
+                });
+            }
+        }
+        Ok(report)
+    }
+
+    /// Check every fixture of a directory with an extension, in file name
+    /// order, into one report.
+    pub fn check_directory(directory: &Path, extension: &str, reader: &mut impl FixtureReader, options: &ConformanceOptions) -> Result<Self, ConformanceError> {
+        let mut paths = std::fs::read_dir(directory)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.retain(|path| path.extension().is_some_and(|found| found == extension));
+        paths.sort();
+        let mut report = Self::default();
+        for path in paths {
+            report.merge(Self::check(&reader.read(&path)?, options)?);
+        }
+        Ok(report)
+    }
+
+    /// Add the results of another report to this one.
+    pub fn merge(&mut self, other: Self) {
+        self.total.add(other.total);
+        for (name, coverage) in other.functions {
+            self.functions.entry(name).or_default().add(coverage);
+        }
+        self.deviations.extend(other.deviations);
+    }
+
+    /// Get the functions by how often they deviated, worst pass rate first.
+    pub fn worst_functions(&self) -> Vec<(&str, Coverage)> {
+        let mut functions = self.functions.iter()
+            .filter(|(_, coverage)| coverage.passed < coverage.checked)
+            .map(|(name, coverage)| (name.as_str(), *coverage))
+            .collect::<Vec<_>>();
+        functions.sort_by(|a, b| a.1.pass_rate().total_cmp(&b.1.pass_rate()).then(a.0.cmp(b.0)));
+        functions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cell(sheet: &str, row: u32, col: u32, raw: &str, expected: Option<Expected>) -> FixtureCell {
+        FixtureCell{sheet: sheet.to_string(), cell_id: CellId::new(row, col), raw: raw.to_string(), expected}
+    }
+
+    fn fixture() -> Fixture {
+        Fixture{name: "basics".to_string(), cells: vec![
+            cell("Sheet1", 0, 0, "4", None),
+            cell("Sheet1", 1, 0, "=SQRT(A1)", Some(Expected::Number(2.0))),
+            cell("Sheet1", 2, 0, "=SUM(A1:A2)*3", Some(Expected::Number(18.0001))),
+            cell("Sheet1", 3, 0, "=1/0", Some(Expected::Error(CellError::Div0))),
+            cell("Sheet1", 4, 0, "=SQRT(A1)&\"\tx\"", Some(Expected::Text("2\tx".to_string()))),
+            cell("Other", 0, 0, "=Sheet1!A1>3", Some(Expected::Bool(true))),
+        ]}
+    }
+
+    #[test]
+    fn results_parse_as_golden_files_write_them() {
+        for expected in [
+            Expected::Empty,
+            Expected::Number(-1.5),
+            Expected::Text("say \"hi\"".to_string()),
+            Expected::Text("12".to_string()),
+            Expected::Bool(false),
+            Expected::Error(CellError::NA),
+        ] {
+            assert_eq!(Expected::parse(&expected.to_string()), expected);
+        }
+        assert_eq!(Expected::parse("plain"), Expected::Text("plain".to_string()));
+        assert!(Expected::Number(1.0).matches(&Expected::Number(1.0 + 1e-12), 1e-9));
+        assert!(!Expected::Number(1.0).matches(&Expected::Text("1".to_string()), 1e-9));
+    }
+
+    #[test]
+    fn dates_are_serial_numbers() {
+        let date = |year, month, day| Value::<f64>::Primitive(Primitive::Date(chrono::NaiveDate::from_ymd_opt(year, month, day).unwrap()));
+        assert_eq!(Expected::of(&date(2024, 1, 1)), Expected::Number(45292.0));
+        // Serials before March 1900 count 1900-02-29, as Excel's do.
+        assert_eq!(Expected::of(&date(1900, 1, 1)), Expected::Number(1.0));
+        assert_eq!(Expected::of(&date(1900, 3, 1)), Expected::Number(61.0));
+    }
+
+    #[test]
+    fn check_reports_coverage_and_deviations() {
+        let report = ConformanceReport::check(&fixture(), &ConformanceOptions::default()).unwrap();
+        assert_eq!(report.total, Coverage{checked: 5, passed: 4});
+        assert_eq!(report.functions["SQRT"], Coverage{checked: 2, passed: 2});
+        assert_eq!(report.functions["SUM"], Coverage{checked: 1, passed: 0});
+        assert_eq!(report.deviations.len(), 1);
+        assert_eq!(report.deviations[0].formula, "=SUM(A1:A2)*3");
+        assert_eq!(report.deviations[0].actual, Ok(Expected::Number(18.0)));
+        assert_eq!(report.worst_functions(), vec![("SUM", Coverage{checked: 1, passed: 0})]);
+
+        let loose = ConformanceReport::check(&fixture(), &ConformanceOptions{tolerance: 1e-5}).unwrap();
+        assert_eq!(loose.total.pass_rate(), 1.0);
+    }
+
+    #[test]
+    fn golden_files_round_trip() {
+        let dir = std::env::temp_dir().join(format!("xlnt-conformance-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut golden = File::create(dir.join("basics.golden")).unwrap();
+        fixture().write_golden(&mut golden).unwrap();
+        drop(golden);
+        std::fs::write(dir.join("ignored.txt"), "Sheet1!A1\t=1\t2\n").unwrap();
+        std::fs::write(dir.join("broken.bad"), "A1\t=1\n").unwrap();
+
+        assert_eq!(Fixture::read_golden(&dir.join("basics.golden")).unwrap(), fixture());
+        let report = ConformanceReport::check_directory(&dir, "golden", &mut GoldenFiles, &ConformanceOptions::default()).unwrap();
+        assert_eq!(report.total, Coverage{checked: 5, passed: 4});
+        assert!(matches!(
+            ConformanceReport::check_directory(&dir, "bad", &mut GoldenFiles, &ConformanceOptions::default()),
+            Err(ConformanceError::Golden{line: 1, ..})
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{escape_text, CellId, Kernel};
+use super::worksheet::Worksheet;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ConnectorError {
+    #[error("query failed: {0}")]
+    Query(String),
+
+    #[error("row {row} has {found} columns, expected {expected}")]
+    RaggedRow{row: usize, found: usize, expected: usize},
+}
+
+/// SqlValue is a single value of a result set, as reported by a driver.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Bool(bool),
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Date(chrono::NaiveDate),
+    DateTime(chrono::NaiveDateTime),
+}
+
+impl SqlValue {
+    /// Convert the value to the raw contents of a cell, keeping text as text.
+    fn to_raw(&self) -> String {
+        match self {
+            Self::Null => String::new(),
+            Self::Bool(b) => if *b { "TRUE" } else { "FALSE" }.to_string(),
+            Self::Integer(i) => i.to_string(),
+            Self::Real(r) => r.to_string(),
+            Self::Text(text) => escape_text(text),
+            Self::Date(date) => date.format("%Y-%m-%d").to_string(),
+            Self::DateTime(datetime) => escape_text(&datetime.format("%Y-%m-%d %H:%M:%S").to_string()),
+        }
+    }
+}
+
+/// ResultSet is the outcome of a query: named columns and rows of values.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResultSet {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<SqlValue>>,
+}
+
+/// Driver runs queries against a database. Implement it on top of a client
+/// library such as one for Postgres, MySQL or SQLite to import its data.
+pub trait Driver {
+    fn query(&mut self, sql: &str) -> Result<ResultSet, ConnectorError>;
+}
+
+/// LandedRange is the rectangle a result set was written to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LandedRange {
+    pub start: CellId,
+    pub end: CellId,
+    pub rows: usize,
+}
+
+/// Write a result set into a sheet with its top left corner at `anchor`,
+/// optionally preceded by a header row of column names.
+pub fn land<T: Arithmetic>(sheet: &mut Worksheet<T>, anchor: CellId, result: &ResultSet, header: bool) -> Result<LandedRange, ConnectorError> {
+    let width = result.columns.len();
+    for (index, row) in result.rows.iter().enumerate() {
+        if row.len() != width {
+            return Err(ConnectorError::RaggedRow{row: index, found: row.len(), expected: width});
+        }
+    }
+
+    let mut row = anchor.row();
+    if header {
+        for (col, name) in result.columns.iter().enumerate() {
+            sheet.set_text(CellId::new(row, anchor.col() + col as u32), name);
+        }
+        row += 1;
+    }
+    for values in &result.rows {
+        for (col, value) in values.iter().enumerate() {
+            sheet.set_cell(CellId::new(row, anchor.col() + col as u32), value.to_raw());
+        }
+        row += 1;
+    }
+    let height = (row - anchor.row()).max(1);
+    Ok(LandedRange{
+        start: anchor,
+        end: CellId::new(anchor.row() + height - 1, anchor.col() + width.max(1) as u32 - 1),
+        rows: result.rows.len(),
+    })
+}
+
+/// Clear the range a previous import landed, then land `result`.
+pub fn reland<T: Arithmetic>(
+    sheet: &mut Worksheet<T>,
+    previous: Option<LandedRange>,
+    anchor: CellId,
+    result: &ResultSet,
+    header: bool,
+) -> Result<LandedRange, ConnectorError> {
+    if let Some(previous) = previous {
+        for cell_id in CellId::range(previous.start, previous.end) {
+            sheet.set_cell(cell_id, String::new());
+        }
+    }
+    land(sheet, anchor, result, header)
+}
+
+/// QueryImport binds a query to the place its results are landed, so it can
+/// be refreshed. Refreshing clears what the previous run wrote before
+/// landing the new results; formulas depending on the range see the new
+/// data the next time they are evaluated.
+pub struct QueryImport<D: Driver> {
+    driver: D,
+    sql: String,
+    anchor: CellId,
+    header: bool,
+    landed: Option<LandedRange>,
+}
+
+impl<D: Driver> QueryImport<D> {
+    pub fn new(driver: D, sql: impl Into<String>, anchor: CellId) -> Self {
+        Self{driver, sql: sql.into(), anchor, header: true, landed: None}
+    }
+
+    /// Whether to write a header row of column names, on by default.
+    pub fn with_header(mut self, header: bool) -> Self {
+        self.header = header;
+        self
+    }
+
+    pub fn sql(&self) -> &str {
+        &self.sql
+    }
+
+    /// Get the range written by the last refresh.
+    pub fn landed(&self) -> Option<LandedRange> {
+        self.landed
+    }
+
+    /// Run the query and land its results in `sheet`.
+    pub fn refresh<T: Arithmetic>(&mut self, sheet: &mut Worksheet<T>) -> Result<LandedRange, ConnectorError> {
+        let result = self.driver.query(&self.sql)?;
+        let landed = reland(sheet, self.landed.take(), self.anchor, &result, self.header)?;
+        self.landed = Some(landed);
+        Ok(landed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixed(Vec<ResultSet>);
+
+    impl Driver for Fixed {
+        fn query(&mut self, _sql: &str) -> Result<ResultSet, ConnectorError> {
+            match self.0.is_empty() {
+                true => Err(ConnectorError::Query("no more results".to_string())),
+                false => Ok(self.0.remove(0)),
+            }
+        }
+    }
+
+    fn result(rows: Vec<Vec<SqlValue>>) -> ResultSet {
+        ResultSet{columns: vec!["name".to_string(), "amount".to_string()], rows}
+    }
+
+    fn raw(sheet: &Worksheet, cell_id: &str) -> Option<String> {
+        sheet.cell(CellId::parse(cell_id).unwrap()).map(|cell| cell.raw().to_string())
+    }
+
+    #[test]
+    fn lands_results_below_a_header() {
+        let mut sheet: Worksheet = Worksheet::new();
+        let rows = vec![
+            vec![SqlValue::Text("=1+1".to_string()), SqlValue::Integer(3)],
+            vec![SqlValue::Null, SqlValue::Date(chrono::NaiveDate::from_ymd_opt(2024, 2, 29).unwrap())],
+        ];
+        let landed = land(&mut sheet, CellId::parse("B2").unwrap(), &result(rows), true).unwrap();
+        assert_eq!(landed, LandedRange{start: CellId::parse("B2").unwrap(), end: CellId::parse("C4").unwrap(), rows: 2});
+        assert_eq!(raw(&sheet, "B2").as_deref(), Some("name"));
+        assert_eq!(sheet.cell(CellId::parse("B3").unwrap()).unwrap().text(), "=1+1");
+        assert_eq!(raw(&sheet, "C3").as_deref(), Some("3"));
+        assert_eq!(raw(&sheet, "B4"), None);
+        assert_eq!(raw(&sheet, "C4").as_deref(), Some("2024-02-29"));
+    }
+
+    #[test]
+    fn rejects_ragged_rows() {
+        let mut sheet: Worksheet = Worksheet::new();
+        let rows = vec![vec![SqlValue::Integer(1), SqlValue::Integer(2)], vec![SqlValue::Integer(3)]];
+        let result = land(&mut sheet, CellId::new(0, 0), &result(rows), false);
+        assert!(matches!(result, Err(ConnectorError::RaggedRow{row: 1, found: 1, expected: 2})));
+    }
+
+    #[test]
+    fn refreshing_clears_the_previous_results() {
+        let first = result(vec![vec![SqlValue::Text("a".to_string()), SqlValue::Real(1.5)]; 3]);
+        let second = result(vec![vec![SqlValue::Text("b".to_string()), SqlValue::Bool(true)]]);
+        let mut import = QueryImport::new(Fixed(vec![first, second]), "SELECT name, amount FROM t", CellId::new(0, 0));
+        let mut sheet: Worksheet = Worksheet::new();
+        assert_eq!(import.refresh(&mut sheet).unwrap().rows, 3);
+        assert_eq!(sheet.len(), 8);
+        assert_eq!(import.refresh(&mut sheet).unwrap().rows, 1);
+        assert_eq!(sheet.len(), 4);
+        assert_eq!(raw(&sheet, "B2").as_deref(), Some("TRUE"));
+        assert!(matches!(import.refresh(&mut sheet), Err(ConnectorError::Query(_))));
+        assert_eq!(import.landed().map(|landed| landed.rows), Some(1));
+    }
+}
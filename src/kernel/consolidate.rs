@@ -0,0 +1,190 @@
+use super::arithmetic::{Arithmetic, Floating};
+use super::array::Array2D;
+use super::kernel::{CellId, FunctionKind, Kernel, Numeric, Primitive, Value};
+use super::serialize::{quote_sheet, value_to_raw};
+use super::workbook::{Workbook, WorkbookError};
+use super::worksheet::{SheetError, Worksheet};
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ConsolidateError {
+    #[error("nothing to consolidate")]
+    NoSources,
+
+    #[error("writing at {anchor} would extend past the sheet")]
+    OutOfBounds{anchor: CellId},
+}
+
+/// ConsolidateFunction is how the values at the same position of every
+/// source are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConsolidateFunction {
+    Sum,
+    Average,
+    Count,
+}
+
+impl ConsolidateFunction {
+    /// Get the function a link formula calls.
+    pub fn function_kind(&self) -> FunctionKind {
+        match self {
+            Self::Sum => FunctionKind::Sum,
+            Self::Average => FunctionKind::Average,
+            Self::Count => FunctionKind::Count,
+        }
+    }
+
+    fn apply<T: Arithmetic>(&self, numbers: &[T]) -> T {
+        let sum = numbers.iter().fold(Floating::from_f64(0.0), |sum: T, x| sum + *x);
+        let count: T = Floating::from_f64(numbers.len() as f64);
+        match self {
+            Self::Sum => sum,
+            Self::Average => sum / count,
+            Self::Count => count,
+        }
+    }
+}
+
+/// Consolidation aggregates the same range of many sheets, such as one
+/// sheet per month from separate workbooks, into a single block of cells.
+/// Sources are evaluated when added, so the workbooks they come from need
+/// not stay loaded.
+pub struct Consolidation<T: Arithmetic=f64> {
+    function: ConsolidateFunction,
+    start: CellId,
+    end: CellId,
+    sources: Vec<(String, Array2D<Value<T>>)>,
+}
+
+impl<T: Arithmetic> Consolidation<T> {
+    /// Create a consolidation of the range between two corners.
+    pub fn new(function: ConsolidateFunction, first: CellId, second: CellId) -> Self {
+        let start = CellId::new(first.row().min(second.row()), first.col().min(second.col()));
+        let end = CellId::new(first.row().max(second.row()), first.col().max(second.col()));
+        Self{function, start, end, sources: Vec::new()}
+    }
+
+    /// Add the range of a sheet as a source. The label is the sheet name
+    /// link formulas refer to.
+    pub fn add_sheet(&mut self, label: &str, sheet: &Worksheet<T>) -> Result<(), SheetError> {
+        let values = sheet.evaluate_range(self.start, self.end)?;
+        self.sources.push((label.to_string(), values));
+        Ok(())
+    }
+
+    /// Add the range of every sheet of a workbook as a source, labelled
+    /// with the sheet names.
+    pub fn add_workbook(&mut self, workbook: &Workbook<T>) -> Result<(), WorkbookError> {
+        for name in workbook.sheet_names() {
+            let values = workbook.evaluate_range(name, self.start, self.end)?;
+            self.sources.push((name.to_string(), values));
+        }
+        Ok(())
+    }
+
+    /// Get the labels of the sources in the order they were added.
+    pub fn labels(&self) -> impl Iterator<Item=&str> {
+        self.sources.iter().map(|(label, _)| label.as_str())
+    }
+
+    /// Aggregate the value at a position of the range. Positions where no
+    /// source holds a number stay empty, and the first error wins.
+    fn aggregate(&self, row: usize, col: usize) -> Value<T> {
+        let mut numbers = Vec::new();
+        for (_, values) in &self.sources {
+            match values.get(row, col) {
+                Some(Value::Primitive(Primitive::Number(number))) => numbers.push(number.value()),
+                Some(Value::Error(e)) if self.function != ConsolidateFunction::Count => return Value::Error(*e),
+                _ => {},
+            }
+        }
+        if numbers.is_empty() {
+            return Value::Empty;
+        }
+        Value::Primitive(Primitive::Number(Numeric::new(self.function.apply(&numbers), None)))
+    }
+
+    /// Get the raw contents of a formula linking a position to its sources,
+    /// like `=SUM(Jan!B2,Feb!B2)`.
+    fn link(&self, row: usize, col: usize) -> String {
+        let cell_id = CellId::new(self.start.row() + row as u32, self.start.col() + col as u32);
+        let references = self.sources.iter()
+            .map(|(label, _)| format!("{}!{}", quote_sheet(label), cell_id))
+            .collect::<Vec<_>>();
+        format!("={}({})", self.function.function_kind().name(), references.join(","))
+    }
+
+    /// Write the consolidated range into `target` with its top left cell
+    /// at `anchor`. With `links` every cell is a formula referring to the
+    /// sources by label, which stays current when the target lives in a
+    /// workbook with sheets of those names; otherwise the aggregated values
+    /// are written.
+    pub fn write_to(&self, target: &mut Worksheet<T>, anchor: CellId, links: bool) -> Result<(), ConsolidateError> {
+        if self.sources.is_empty() {
+            return Err(ConsolidateError::NoSources);
+        }
+        let rows = self.end.row() - self.start.row();
+        let cols = self.end.col() - self.start.col();
+        if anchor.row().checked_add(rows).is_none() || anchor.col().checked_add(cols).is_none() {
+            return Err(ConsolidateError::OutOfBounds{anchor});
+        }
+        for row in 0..=rows as usize {
+            for col in 0..=cols as usize {
+                let raw = match links {
+                    true => self.link(row, col),
+                    false => value_to_raw(&self.aggregate(row, col)),
+                };
+                target.set_cell(CellId::new(anchor.row() + row as u32, anchor.col() + col as u32), raw);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn month(values: &[&str]) -> Worksheet {
+        let mut sheet: Worksheet = Worksheet::new();
+        for (row, value) in values.iter().enumerate() {
+            sheet.set_cell(CellId::new(row as u32, 0), value.to_string());
+        }
+        sheet
+    }
+
+    fn raw(sheet: &Worksheet, row: u32) -> Option<String> {
+        sheet.cell(CellId::new(row, 0)).map(|cell| cell.raw().to_string())
+    }
+
+    #[test]
+    fn consolidates_values_of_every_source() {
+        let mut sum = Consolidation::new(ConsolidateFunction::Sum, CellId::new(2, 0), CellId::new(0, 0));
+        assert_eq!(sum.write_to(&mut Worksheet::new(), CellId::new(0, 0), false), Err(ConsolidateError::NoSources));
+        sum.add_sheet("Jan", &month(&["1", "x", "=1/0"])).unwrap();
+        sum.add_sheet("Feb", &month(&["2", "", "3"])).unwrap();
+        assert_eq!(sum.labels().collect::<Vec<_>>(), ["Jan", "Feb"]);
+        let mut target: Worksheet = Worksheet::new();
+        sum.write_to(&mut target, CellId::new(0, 0), false).unwrap();
+        assert_eq!(raw(&target, 0).as_deref(), Some("3"));
+        assert_eq!(raw(&target, 1), None);
+        assert_eq!(raw(&target, 2).as_deref(), Some("#DIV/0!"));
+        assert!(matches!(sum.write_to(&mut target, CellId::new(u32::MAX, 0), false), Err(ConsolidateError::OutOfBounds{..})));
+    }
+
+    #[test]
+    fn links_stay_current_in_the_workbook_of_the_sources() {
+        let mut workbook: Workbook = Workbook::new();
+        workbook.insert_sheet("Jan", month(&["1", "2"])).unwrap();
+        workbook.insert_sheet("Feb 2", month(&["3", "5"])).unwrap();
+        let mut average = Consolidation::new(ConsolidateFunction::Average, CellId::new(0, 0), CellId::new(1, 0));
+        average.add_workbook(&workbook).unwrap();
+        let mut summary: Worksheet = Worksheet::new();
+        average.write_to(&mut summary, CellId::new(0, 0), true).unwrap();
+        assert_eq!(raw(&summary, 1).as_deref(), Some("=AVERAGE(Jan!A2,'Feb 2'!A2)"));
+        workbook.insert_sheet("Summary", summary).unwrap();
+        workbook.set_cell("Jan", CellId::new(1, 0), "4".to_string()).unwrap();
+        let value = workbook.evaluate_cell("Summary", CellId::new(1, 0)).unwrap();
+        assert!(matches!(value, Value::Primitive(Primitive::Number(number)) if number.value() == 4.5));
+    }
+}
@@ -0,0 +1,316 @@
+use super::arithmetic::Arithmetic;
+use super::dependency::{collect, DependencyGraph, Precedent};
+use super::kernel::CellId;
+use super::serialize::quote_sheet;
+use super::workbook::{Workbook, WorkbookError};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// PrecedentRule is what the cells a governed cell depends on must be.
+/// Sheet names are case insensitive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PrecedentRule {
+    /// Every precedent is on one of these sheets, such as `Inputs`.
+    OnlySheets(Vec<String>),
+    /// No precedent is on any of these sheets.
+    NotSheets(Vec<String>),
+    /// Every precedent lies within one of these ranges, by sheet and
+    /// corners.
+    WithinRanges(Vec<(String, CellId, CellId)>),
+}
+
+impl PrecedentRule {
+    /// Whether the rule allows a precedent spanning the rectangle between
+    /// two corners of a sheet.
+    fn allows(&self, sheet: &str, start: CellId, end: CellId) -> bool {
+        let named = |sheets: &[String]| sheets.iter().any(|name| name.eq_ignore_ascii_case(sheet));
+        match self {
+            Self::OnlySheets(sheets) => named(sheets),
+            Self::NotSheets(sheets) => !named(sheets),
+            Self::WithinRanges(ranges) => ranges.iter().any(|(name, first, second)| {
+                let rows = first.row().min(second.row())..=first.row().max(second.row());
+                let cols = first.col().min(second.col())..=first.col().max(second.col());
+                name.eq_ignore_ascii_case(sheet)
+                    && rows.contains(&start.row()) && rows.contains(&end.row())
+                    && cols.contains(&start.col()) && cols.contains(&end.col())
+            }),
+        }
+    }
+}
+
+/// ContractScope is which precedents of a governed cell a contract checks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ContractScope {
+    /// The references written in the formulas of the governed cells.
+    Direct,
+    /// The cells without formulas the governed cells depend on, directly
+    /// or through other formulas, which are where their numbers come from.
+    /// A governed cell holding a value instead of a formula is checked
+    /// itself.
+    #[default]
+    Inputs,
+}
+
+/// Contract is an assertion of a model author about the cells a range
+/// depends on, such as that the outputs of a governed model depend only on
+/// its `Inputs` sheet, checked by `Workbook::verify_contracts`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contract {
+    /// The name violations are reported under.
+    pub name: String,
+    pub sheet: String,
+    pub first: CellId,
+    pub last: CellId,
+    pub rule: PrecedentRule,
+    pub scope: ContractScope,
+}
+
+impl Contract {
+    pub fn new(name: &str, sheet: &str, first: CellId, last: CellId, rule: PrecedentRule) -> Self {
+        Self{name: name.to_string(), sheet: sheet.to_string(), first, last, rule, scope: ContractScope::default()}
+    }
+
+    pub fn scope(mut self, scope: ContractScope) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    fn governs(&self, cell_id: CellId) -> bool {
+        (self.first.row().min(self.last.row())..=self.first.row().max(self.last.row())).contains(&cell_id.row())
+            && (self.first.col().min(self.last.col())..=self.first.col().max(self.last.col())).contains(&cell_id.col())
+    }
+}
+
+/// ContractViolation is a governed cell depending on a precedent its
+/// contract does not allow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContractViolation {
+    /// The name of the contract broken.
+    pub contract: String,
+    pub sheet: String,
+    pub cell_id: CellId,
+    /// The precedent breaking the rule, always with its sheet.
+    pub precedent: Precedent,
+    /// The formula cells leading from the governed cell to the one
+    /// referring to the precedent, starting with the governed cell. Empty
+    /// when the governed cell is the precedent itself.
+    pub path: Vec<(String, CellId)>,
+}
+
+impl fmt::Display for ContractViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}!{} breaks {}: depends on {}", quote_sheet(&self.sheet), self.cell_id, self.contract, self.precedent)?;
+        if self.path.len() > 1 {
+            let path = self.path.iter().map(|(sheet, cell_id)| format!("{}!{}", quote_sheet(sheet), cell_id)).collect::<Vec<_>>();
+            write!(f, " through {}", path.join(" -> "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Checker walks the dependencies of governed cells across the sheets of a
+/// workbook.
+struct Checker<'a, T: Arithmetic> {
+    workbook: &'a Workbook<T>,
+    graphs: HashMap<String, DependencyGraph>,
+}
+
+impl<T: Arithmetic> Checker<'_, T> {
+    fn is_formula(&self, sheet: &str, cell_id: CellId) -> bool {
+        self.workbook.sheet(sheet).and_then(|own| own.cell(cell_id)).is_some_and(|cell| cell.formula().is_some())
+    }
+
+    /// Get what a formula cell refers to, with every reference qualified by
+    /// its sheet and defined names replaced by what they refer to.
+    fn precedents(&self, sheet: &str, cell_id: CellId) -> Vec<(String, Precedent)> {
+        let mut pending = self.graphs.get(&sheet.to_lowercase())
+            .map(|graph| graph.precedents(cell_id).cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        let mut names = HashSet::new();
+        let mut precedents = Vec::new();
+        while let Some(precedent) = pending.pop() {
+            match precedent {
+                Precedent::Name{name, ..} => {
+                    if let (true, Some(formula)) = (names.insert(name.to_lowercase()), self.workbook.name(&name)) {
+                        let mut own = HashSet::new();
+                        collect(formula, None, &mut own);
+                        pending.extend(own);
+                    }
+                },
+                Precedent::Cell{sheet: target, cell_id} => {
+                    let target = target.unwrap_or_else(|| sheet.to_string());
+                    precedents.push((target.clone(), Precedent::Cell{sheet: Some(target), cell_id}));
+                },
+                Precedent::Range{sheet: target, start, end} => {
+                    let target = target.unwrap_or_else(|| sheet.to_string());
+                    precedents.push((target.clone(), Precedent::Range{sheet: Some(target), start, end}));
+                },
+            }
+        }
+        precedents.sort_by_cached_key(|(_, precedent)| precedent.to_string());
+        precedents
+    }
+
+    /// Check one governed cell against its contract.
+    fn check(&self, contract: &Contract, cell_id: CellId, violations: &mut Vec<ContractViolation>) {
+        let sheet = contract.sheet.as_str();
+        let mut report = |precedent: Precedent, path: &[(String, CellId)]| violations.push(ContractViolation{
+            contract: contract.name.clone(),
+            sheet: sheet.to_string(),
+            cell_id,
+            precedent,
+            path: path.to_vec(),
+        });
+        if !self.is_formula(sheet, cell_id) {
+            if contract.scope == ContractScope::Inputs && !contract.rule.allows(sheet, cell_id, cell_id) {
+                report(Precedent::Cell{sheet: Some(sheet.to_string()), cell_id}, &[]);
+            }
+            return;
+        }
+
+        let mut seen = HashSet::from([(sheet.to_lowercase(), cell_id)]);
+        let mut stack = vec![vec![(sheet.to_string(), cell_id)]];
+        while let Some(path) = stack.pop() {
+            let (current, current_id) = path.last().cloned().expect("paths are not empty");
+            for (target, precedent) in self.precedents(&current, current_id) {
+                let cells = match (&precedent, contract.scope) {
+                    (Precedent::Cell{cell_id, ..}, _) => vec![*cell_id],
+                    (Precedent::Range{start, end, ..}, ContractScope::Direct) => {
+                        if !contract.rule.allows(&target, *start, *end) {
+                            report(precedent.clone(), &path);
+                        }
+                        continue;
+                    },
+                    (Precedent::Range{start, end, ..}, ContractScope::Inputs) => {
+                        let rows = start.row().min(end.row())..=start.row().max(end.row());
+                        let cols = start.col().min(end.col())..=start.col().max(end.col());
+                        let mut cells = self.workbook.sheet(&target).into_iter()
+                            .flat_map(|own| own.cell_ids())
+                            .filter(|cell_id| rows.contains(&cell_id.row()) && cols.contains(&cell_id.col()))
+                            .collect::<Vec<_>>();
+                        cells.sort_by_key(|cell_id| (cell_id.row(), cell_id.col()));
+                        cells
+                    },
+                    (Precedent::Name{..}, _) => continue,
+                };
+                for target_id in cells {
+                    match contract.scope == ContractScope::Inputs && self.is_formula(&target, target_id) {
+                        true => {
+                            if seen.insert((target.to_lowercase(), target_id)) {
+                                let mut next = path.clone();
+                                next.push((target.clone(), target_id));
+                                stack.push(next);
+                            }
+                        },
+                        false if !contract.rule.allows(&target, target_id, target_id) => {
+                            report(Precedent::Cell{sheet: Some(target.clone()), cell_id: target_id}, &path);
+                        },
+                        false => {},
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<T: Arithmetic> Workbook<T> {
+    /// Hold the cells of a range to a contract on what they depend on.
+    pub fn add_contract(&mut self, contract: Contract) -> Result<(), WorkbookError> {
+        if self.sheet(&contract.sheet).is_none() {
+            return Err(WorkbookError::UnknownSheet(contract.sheet));
+        }
+        self.contracts_mut().push(contract);
+        Ok(())
+    }
+
+    /// Drop the contracts of a name, and get how many there were.
+    pub fn remove_contract(&mut self, name: &str) -> usize {
+        let before = self.contracts().len();
+        self.contracts_mut().retain(|contract| contract.name != name);
+        before - self.contracts().len()
+    }
+
+    /// Walk the dependencies of every cell governed by a contract and
+    /// report those depending on precedents their contract does not allow,
+    /// such as a hard-coded reference to a calculation sheet in an output
+    /// meant to depend only on inputs. Violations are listed by contract,
+    /// then cell row by row.
+    pub fn verify_contracts(&self) -> Result<Vec<ContractViolation>, WorkbookError> {
+        let graphs = self.sheets().map(|(name, sheet)| (name.to_lowercase(), sheet.dependency_graph())).collect();
+        let checker = Checker{workbook: self, graphs};
+        let mut violations = Vec::new();
+        for contract in self.contracts() {
+            let sheet = self.sheet(&contract.sheet).ok_or_else(|| WorkbookError::UnknownSheet(contract.sheet.clone()))?;
+            for cell_id in sheet.cell_ids().into_iter().filter(|cell_id| contract.governs(*cell_id)) {
+                checker.check(contract, cell_id, &mut violations);
+            }
+        }
+        Ok(violations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::Formula;
+
+    fn at(cell_id: &str) -> CellId {
+        CellId::parse(cell_id).unwrap()
+    }
+
+    /// A model whose outputs should depend on its inputs only: A1 does
+    /// through a calculation, A2 reaches into the calculation sheet for a
+    /// hard-coded number, and A3 goes through a defined name.
+    fn workbook() -> Workbook {
+        let mut workbook: Workbook = Workbook::new();
+        for sheet in ["Inputs", "Calc", "Outputs"] {
+            workbook.add_sheet(sheet).unwrap();
+        }
+        for (sheet, cell_id, data) in [
+            ("Inputs", "A1", "100"), ("Inputs", "A2", "0.2"),
+            ("Calc", "A1", "=Inputs!A1*(1+Inputs!A2)"), ("Calc", "B1", "42"),
+            ("Outputs", "A1", "=Calc!A1"), ("Outputs", "A2", "=Calc!A1+Calc!B1"), ("Outputs", "A3", "=Hidden*2"),
+        ] {
+            workbook.set_cell(sheet, at(cell_id), data.to_string()).unwrap();
+        }
+        workbook.define_name("Hidden", Formula::try_from("Calc!B1").unwrap()).unwrap();
+        workbook
+    }
+
+    #[test]
+    fn contracts_report_inputs_outside_their_rule() {
+        let mut workbook = workbook();
+        let rule = PrecedentRule::OnlySheets(vec!["inputs".to_string()]);
+        workbook.add_contract(Contract::new("inputs only", "Outputs", at("A1"), at("A3"), rule)).unwrap();
+        let violations = workbook.verify_contracts().unwrap();
+        assert_eq!(violations.iter().map(|violation| (violation.cell_id, violation.path.len())).collect::<Vec<_>>(), [(at("A2"), 1), (at("A3"), 1)]);
+        assert_eq!(violations[0].precedent, Precedent::Cell{sheet: Some("Calc".to_string()), cell_id: at("B1")});
+        assert_eq!(violations[0].to_string(), "Outputs!A2 breaks inputs only: depends on Calc!B1");
+
+        // Direct references to the calculation sheet break a stricter contract.
+        let direct = Contract::new("direct", "Outputs", at("A1"), at("A1"), PrecedentRule::NotSheets(vec!["Calc".to_string()])).scope(ContractScope::Direct);
+        workbook.add_contract(direct).unwrap();
+        let violations = workbook.verify_contracts().unwrap();
+        assert_eq!(violations.last().unwrap().contract, "direct");
+        assert_eq!(violations.len(), 3);
+        assert_eq!(workbook.remove_contract("direct"), 1);
+        assert_eq!(workbook.remove_contract("direct"), 0);
+        assert_eq!(workbook.contracts().len(), 1);
+    }
+
+    #[test]
+    fn contracts_follow_chains_and_ranges() {
+        let mut workbook = workbook();
+        workbook.set_cell("Calc", at("A2"), "=SUM(Inputs!A1:A5)".to_string()).unwrap();
+        workbook.set_cell("Outputs", at("B1"), "=Calc!A2".to_string()).unwrap();
+        workbook.set_cell("Inputs", at("A5"), "7".to_string()).unwrap();
+        let rule = PrecedentRule::WithinRanges(vec![("Inputs".to_string(), at("A1"), at("A2"))]);
+        workbook.add_contract(Contract::new("rates", "Outputs", at("B1"), at("B1"), rule)).unwrap();
+        let violations = workbook.verify_contracts().unwrap();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].precedent, Precedent::Cell{sheet: Some("Inputs".to_string()), cell_id: at("A5")});
+        assert_eq!(violations[0].path, [("Outputs".to_string(), at("B1")), ("Calc".to_string(), at("A2"))]);
+        assert_eq!(violations[0].to_string(), "Outputs!B1 breaks rates: depends on Inputs!A5 through Outputs!B1 -> Calc!A2");
+        assert!(matches!(workbook.add_contract(Contract::new("none", "Missing", at("A1"), at("A1"), PrecedentRule::NotSheets(Vec::new()))), Err(WorkbookError::UnknownSheet(_))));
+    }
+}
@@ -0,0 +1,248 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{Cell, CellId, Formula, Kernel, Value};
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+use std::ops::Add;
+
+/// The most cells of a range whose formulas are costed one by one. The cost
+/// of the formulas of larger ranges is extrapolated from their first cells.
+const MAX_SCAN: u64 = 65536;
+
+/// Cost is the estimated work of evaluating a formula.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Cost {
+    /// The number of cells read, including those read by the formulas of
+    /// precedents.
+    pub cell_visits: u64,
+    /// The number of operations: one per operator or call, plus one per
+    /// value a function consumes.
+    pub flops: u64,
+}
+
+impl Cost {
+    /// Whether this cost exceeds a limit in cell visits or operations.
+    pub fn exceeds(&self, limit: &Cost) -> bool {
+        self.cell_visits > limit.cell_visits || self.flops > limit.flops
+    }
+
+    fn scale(self, factor: f64) -> Self {
+        let scale = |count: u64| (count as f64 * factor).min(u64::MAX as f64) as u64;
+        Self{cell_visits: scale(self.cell_visits), flops: scale(self.flops)}
+    }
+}
+
+impl Add for Cost {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self{cell_visits: self.cell_visits.saturating_add(other.cell_visits), flops: self.flops.saturating_add(other.flops)}
+    }
+}
+
+/// Get the number of cells of the rectangle between two corners.
+fn area(first: CellId, second: CellId) -> u64 {
+    (first.row().abs_diff(second.row()) as u64 + 1) * (first.col().abs_diff(second.col()) as u64 + 1)
+}
+
+struct Estimate<'a, E, T: Arithmetic, K: Kernel<E, T>>
+where E: std::error::Error {
+    kernel: &'a K,
+    costs: HashMap<(Option<String>, CellId), Cost>,
+    /// The cells being costed, so circular references end.
+    visiting: HashSet<(Option<String>, CellId)>,
+    error: PhantomData<(E, T)>,
+}
+
+/// Frame is a cell being costed by `Estimate::precedents`, with the
+/// precedents left to cost first.
+struct Frame {
+    key: (Option<String>, CellId),
+    precedents: Vec<(Option<String>, CellId)>,
+}
+
+impl<E: std::error::Error, T: Arithmetic, K: Kernel<E, T>> Estimate<'_, E, T, K> {
+    fn lookup(&self, sheet: Option<&str>, cell_id: CellId) -> Option<Cell<T>> {
+        match sheet {
+            Some(sheet) => self.kernel.get_sheet_cell(sheet, cell_id),
+            None => self.kernel.get_cell(cell_id),
+        }
+    }
+
+    /// Get the cost of evaluating the formula of a cell, if any.
+    fn cell(&mut self, sheet: Option<&str>, cell_id: CellId) -> Cost {
+        let key = (sheet.map(str::to_string), cell_id);
+        if let Some(cost) = self.costs.get(&key) {
+            return *cost;
+        }
+        if self.visiting.contains(&key) {
+            return Cost::default();
+        }
+        self.precedents(key.clone());
+        self.costs.get(&key).copied().unwrap_or_default()
+    }
+
+    /// Cost a cell along with all its precedents not costed yet, the
+    /// precedents first. The precedents are walked with a stack of their
+    /// own rather than by recursion, so long chains of references cannot
+    /// overflow the call stack; by the time a cell is costed, the cells it
+    /// refers to are either costed or being costed as part of a cycle.
+    fn precedents(&mut self, key: (Option<String>, CellId)) {
+        let mut stack = Vec::new();
+        self.enter(key, &mut stack);
+        while let Some(frame) = stack.last_mut() {
+            if let Some(next) = frame.precedents.pop() {
+                if !self.costs.contains_key(&next) && !self.visiting.contains(&next) {
+                    self.enter(next, &mut stack);
+                }
+                continue;
+            }
+            let Frame{key, ..} = stack.pop().expect("the stack is not empty");
+            let cell = self.lookup(key.0.as_deref(), key.1);
+            let formula = cell.as_ref().and_then(Cell::formula).expect("only formulas are entered");
+            let cost = self.formula(key.0.as_deref(), formula);
+            self.visiting.remove(&key);
+            self.costs.insert(key, cost);
+        }
+    }
+
+    /// Start costing a cell, if it holds a formula.
+    fn enter(&mut self, key: (Option<String>, CellId), stack: &mut Vec<Frame>) {
+        let cell = self.lookup(key.0.as_deref(), key.1);
+        let Some(formula) = cell.as_ref().and_then(Cell::formula) else { return };
+        let mut precedents = Vec::new();
+        self.references(key.0.as_deref(), formula, &mut precedents);
+        // Popped from the end, so the first precedent is costed first.
+        precedents.reverse();
+        self.visiting.insert(key.clone());
+        stack.push(Frame{key, precedents});
+    }
+
+    /// Collect the cells a formula refers to, on the sheets it refers to
+    /// them on. Ranges are cut to the cells `range` costs.
+    fn references(&self, sheet: Option<&str>, formula: &Formula<T>, cells: &mut Vec<(Option<String>, CellId)>) {
+        match formula {
+            Formula::CellRef(cell_id) => cells.push((sheet.map(str::to_string), *cell_id)),
+            Formula::CellRange(first, second) => {
+                cells.extend(CellId::range(*first, *second).take(MAX_SCAN as usize).map(|cell_id| (sheet.map(str::to_string), cell_id)));
+            },
+            Formula::Name(name) => {
+                if let Some(target) = self.kernel.resolve_name(sheet, name) {
+                    self.references(sheet, &target, cells);
+                }
+            },
+            Formula::SheetRef(sheet, target) => self.references(Some(sheet), target, cells),
+            _ => {
+                for operand in formula.operands() {
+                    if let Value::Formula(operand) = operand {
+                        self.references(sheet, operand, cells);
+                    }
+                }
+            },
+        }
+    }
+
+    fn range(&mut self, sheet: Option<&str>, first: CellId, second: CellId) -> Cost {
+        let cells = area(first, second);
+        let mut precedents = Cost::default();
+        for cell_id in CellId::range(first, second).take(MAX_SCAN as usize) {
+            precedents = precedents + self.cell(sheet, cell_id);
+        }
+        if cells > MAX_SCAN {
+            precedents = precedents.scale(cells as f64 / MAX_SCAN as f64);
+        }
+        Cost{cell_visits: cells, flops: 0} + precedents
+    }
+
+    fn value(&mut self, sheet: Option<&str>, value: &Value<T>) -> Cost {
+        match value {
+            Value::Formula(formula) => self.formula(sheet, formula),
+            _ => Cost::default(),
+        }
+    }
+
+    /// Get the number of values an argument passes to a function.
+    fn consumed(argument: &Value<T>) -> u64 {
+        match argument {
+            Value::Formula(Formula::CellRange(first, second)) => area(*first, *second),
+            Value::Formula(Formula::SheetRef(_, target)) => match **target {
+                Formula::CellRange(first, second) => area(first, second),
+                _ => 1,
+            },
+            _ => 1,
+        }
+    }
+
+    fn formula(&mut self, sheet: Option<&str>, formula: &Formula<T>) -> Cost {
+        match formula {
+            Formula::NumberLit(_) | Formula::TextLit(_) | Formula::BoolLit(_) => Cost::default(),
+            Formula::CellRef(cell_id) => Cost{cell_visits: 1, flops: 0} + self.cell(sheet, *cell_id),
+            Formula::CellRange(first, second) => self.range(sheet, *first, *second),
+            Formula::Name(name) => match self.kernel.resolve_name(sheet, name) {
+                Some(target) => self.formula(sheet, &target),
+                None => Cost::default(),
+            },
+            Formula::SheetRef(sheet, target) => self.formula(Some(sheet), target),
+            Formula::Function{arguments, ..} | Formula::Custom{arguments, ..} => {
+                let consumed = arguments.iter().map(Self::consumed).fold(0, u64::saturating_add);
+                arguments.iter().fold(Cost{cell_visits: 0, flops: 1 + consumed}, |cost, argument| cost + self.value(sheet, argument))
+            },
+            _ => {
+                let own = Cost{cell_visits: 0, flops: 1};
+                formula.operands().into_iter().fold(own, |cost, operand| cost + self.value(sheet, operand))
+            },
+        }
+    }
+}
+
+impl<T: Arithmetic> Formula<T> {
+    /// Estimate the work of evaluating this formula in a kernel, without
+    /// evaluating it, such as to reject or queue expensive formulas. The
+    /// formulas of precedents are costed too, once for every reference to
+    /// them as evaluation does not cache results, which makes this an upper
+    /// bound. Branches which would not be taken are costed all the same.
+    pub fn estimate_cost<E, K>(&self, kernel: &K) -> Cost
+    where E: std::error::Error, K: Kernel<E, T> {
+        let mut estimate = Estimate{kernel, costs: HashMap::new(), visiting: HashSet::new(), error: PhantomData};
+        estimate.formula(None, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::worksheet::Worksheet;
+
+    fn cost(sheet: &Worksheet, formula: &str) -> Cost {
+        Formula::try_from(formula).unwrap().estimate_cost(sheet)
+    }
+
+    #[test]
+    fn precedents_are_costed_once_per_reference() {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), "1".to_string());
+        sheet.set_cell(CellId::new(1, 0), "=A1*2".to_string());
+        assert_eq!(cost(&sheet, "1+2"), Cost{cell_visits: 0, flops: 1});
+        assert_eq!(cost(&sheet, "A2+A2"), Cost{cell_visits: 4, flops: 3});
+        assert_eq!(cost(&sheet, "SUM(A1:A2)"), Cost{cell_visits: 3, flops: 4});
+    }
+
+    #[test]
+    fn circular_references_are_costed_once_around() {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), "=B1+1".to_string());
+        sheet.set_cell(CellId::new(0, 1), "=A1+1".to_string());
+        assert_eq!(cost(&sheet, "A1"), Cost{cell_visits: 3, flops: 2});
+    }
+
+    #[test]
+    fn long_chains_of_precedents_are_costed() {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), "1".to_string());
+        for row in 1..10_000 {
+            sheet.set_cell(CellId::new(row, 0), format!("=A{}+1", row));
+        }
+        assert_eq!(cost(&sheet, "A10000"), Cost{cell_visits: 10_000, flops: 9_999});
+        let limit = Cost{cell_visits: 1_000, flops: 1_000};
+        assert!(cost(&sheet, "A10000").exceeds(&limit));
+    }
+}
@@ -0,0 +1,273 @@
+use super::access::AccessPolicy;
+use super::arithmetic::Arithmetic;
+use super::budget::{Budget, BudgetError};
+use super::encoding::{decode_text, Encoding};
+use super::import::{ImportLimits, ImportReport, ImportSchema, SchemaReport};
+use super::kernel::{CellId, Kernel};
+use super::transform::TransformSpec;
+use super::worksheet::{SheetError, Worksheet};
+use thiserror::Error;
+use std::io::{self, Read, Write};
+
+#[derive(Error, Debug)]
+pub enum CsvError {
+    #[error("line {0}: unterminated quoted field")]
+    UnterminatedQuote(usize),
+
+    #[error("{} of {} imported cells have issues", .0.issues.len(), .0.cells)]
+    TooManyErrors(ImportReport),
+
+    #[error(transparent)]
+    Budget(#[from] BudgetError),
+
+    #[error(transparent)]
+    Sheet(#[from] SheetError),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// CsvOptions configures reading and writing delimited text.
+#[derive(Debug, Clone)]
+pub struct CsvOptions {
+    pub delimiter: char,
+    /// The encoding of imported text, detected if `None`. Text is always
+    /// written as UTF-8.
+    pub encoding: Option<Encoding>,
+    /// Whether imported cells get the number formats their text implies,
+    /// see `Worksheet::infer_number_formats`.
+    pub infer_formats: bool,
+    /// The issues which abort an import.
+    pub limits: ImportLimits,
+    /// The pipelines cleaning imported fields. Fields are entered as they
+    /// are if it is empty.
+    pub transforms: TransformSpec,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self{delimiter: ',', encoding: None, infer_formats: true, limits: ImportLimits::default(), transforms: TransformSpec::default()}
+    }
+}
+
+pub(crate) fn quote_field(field: &str, delimiter: char) -> String {
+    if field.contains([delimiter, '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Write the raw contents of every cell of a sheet as delimited text, from
+/// `A1` to the last used row and column.
+pub fn write_csv<T: Arithmetic, W: Write>(sheet: &Worksheet<T>, writer: &mut W, options: &CsvOptions) -> io::Result<()> {
+    write_grid(&sheet.cell_ids(), |cell_id| sheet.cell(cell_id).map(|cell| cell.raw()), writer, options.delimiter)
+}
+
+/// Write the raw contents `raw` finds for the cells of `ids` as delimited
+/// text, from `A1` to the last used row and column.
+pub(crate) fn write_grid<'a, W: Write>(ids: &[CellId], raw: impl Fn(CellId) -> Option<&'a str>, writer: &mut W, delimiter: char) -> io::Result<()> {
+    let rows = ids.iter().map(|cell_id| cell_id.row() + 1).max().unwrap_or(0);
+    let cols = ids.iter().map(|cell_id| cell_id.col() + 1).max().unwrap_or(0);
+    let mut line = Vec::with_capacity(cols as usize);
+    for row in 0..rows {
+        line.clear();
+        for col in 0..cols {
+            line.push(quote_field(raw(CellId::new(row, col)).unwrap_or_default(), delimiter));
+        }
+        writeln!(writer, "{}", line.join(&delimiter.to_string()))?;
+    }
+    Ok(())
+}
+
+/// Write a sheet as delimited text with the cells `policy` hides redacted.
+pub fn write_csv_redacted<T: Arithmetic, W: Write>(sheet: &Worksheet<T>, writer: &mut W, options: &CsvOptions, policy: &AccessPolicy) -> io::Result<()> {
+    let redacted = policy.redact_sheet(sheet).map_err(io::Error::other)?;
+    write_csv(&redacted, writer, options)
+}
+
+/// Split delimited text into records of fields. Quoted fields may contain
+/// delimiters, doubled quotes and line breaks. Fails with the line of a
+/// quoted field which is not terminated.
+pub(crate) fn records(text: &str, delimiter: char) -> Result<Vec<Vec<String>>, usize> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    let mut quoted = false;
+    let mut quote_line = 0;
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                },
+                '"' => quoted = false,
+                c => {
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    field.push(c);
+                },
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => {
+                quoted = true;
+                quote_line = line;
+            },
+            c if c == delimiter => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => continue,
+            '\n' | '\r' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+                line += 1;
+            },
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(quote_line);
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Read delimited text into a new sheet, starting at `A1`. The text is
+/// transcoded from the encoding of the options, or from the detected one.
+/// Every field is entered as if typed into a cell, so numbers and formulas
+/// are recognized. The input is checked against `budget` while reading, and
+/// formulas which exceed its formula limits fail the whole import.
+pub fn read_csv<T: Arithmetic, R: Read>(reader: R, options: &CsvOptions, budget: &Budget) -> Result<Worksheet<T>, CsvError> {
+    read_csv_with_report(reader, options, budget).map(|(sheet, _)| sheet)
+}
+
+/// Read delimited text like `read_csv`, and report the cells which did not
+/// read as intended. The import fails once the issues exceed the limits of
+/// the options.
+pub fn read_csv_with_report<T: Arithmetic, R: Read>(reader: R, options: &CsvOptions, budget: &Budget) -> Result<(Worksheet<T>, ImportReport), CsvError> {
+    let max = budget.max_input_bytes.saturating_add(1) as u64;
+    let mut bytes = Vec::new();
+    reader.take(max).read_to_end(&mut bytes)?;
+    budget.check_input(bytes.len())?;
+    let (text, _) = decode_text(&bytes, options.encoding);
+    parse_csv_with_report(&text, options, budget)
+}
+
+/// Read delimited text like `read_csv`, and validate the table it holds
+/// against a schema, see `ImportSchema::validate`.
+pub fn read_csv_with_schema<T: Arithmetic, R: Read>(
+    reader: R,
+    options: &CsvOptions,
+    budget: &Budget,
+    schema: &ImportSchema,
+) -> Result<(Worksheet<T>, SchemaReport<T>), CsvError> {
+    let sheet = read_csv(reader, options, budget)?;
+    let report = schema.validate(&sheet)?;
+    Ok((sheet, report))
+}
+
+/// Parse decoded delimited text into a new sheet, like `read_csv`.
+pub fn parse_csv<T: Arithmetic>(text: &str, options: &CsvOptions, budget: &Budget) -> Result<Worksheet<T>, CsvError> {
+    parse_csv_with_report(text, options, budget).map(|(sheet, _)| sheet)
+}
+
+/// Parse decoded delimited text into a new sheet, like `read_csv_with_report`.
+pub fn parse_csv_with_report<T: Arithmetic>(text: &str, options: &CsvOptions, budget: &Budget) -> Result<(Worksheet<T>, ImportReport), CsvError> {
+    let mut sheet = Worksheet::new();
+    let mut report = ImportReport::default();
+    let records = records(text, options.delimiter).map_err(CsvError::UnterminatedQuote)?;
+    let headers = match options.transforms.has_headers() {
+        true => records.first().cloned(),
+        false => None,
+    };
+    for (row, record) in records.into_iter().enumerate() {
+        for (col, field) in record.into_iter().enumerate() {
+            let field = match options.transforms.is_empty() || (headers.is_some() && row == 0) {
+                true => field,
+                false => {
+                    let header = headers.as_ref().and_then(|headers| headers.get(col)).map(String::as_str);
+                    options.transforms.apply::<T>(col as u32, header, &field)
+                },
+            };
+            if field.trim().is_empty() {
+                continue;
+            }
+            budget.check_string(&field)?;
+            budget.check_cells(report.cells + 1)?;
+            budget.check_formula::<T>(&field)?;
+            let cell_id = CellId::new(row as u32, col as u32);
+            sheet.set_cell(cell_id, field);
+            let cell = sheet.cell(cell_id).expect("the field is not empty");
+            report.check(cell_id, cell.raw(), cell.value());
+            if options.limits.too_many_errors(&report) {
+                return Err(CsvError::TooManyErrors(report));
+            }
+        }
+    }
+    if options.limits.error_rate_too_high(&report) {
+        return Err(CsvError::TooManyErrors(report));
+    }
+    if options.infer_formats {
+        sheet.infer_number_formats();
+    }
+    Ok((sheet, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_split_quoted_fields() {
+        let text = "a,\"b,c\",\"say \"\"hi\"\"\"\r\n1,\"two\nlines\",\n\nlast";
+        let records = records(text, ',').unwrap();
+        assert_eq!(records, vec![
+            vec!["a".to_string(), "b,c".to_string(), "say \"hi\"".to_string()],
+            vec!["1".to_string(), "two\nlines".to_string(), String::new()],
+            vec![String::new()],
+            vec!["last".to_string()],
+        ]);
+        assert!(super::records("a;b\n\"open", ';').is_err());
+        assert_eq!(super::records("a;b", ';').unwrap(), vec![vec!["a".to_string(), "b".to_string()]]);
+    }
+
+    #[test]
+    fn sheets_round_trip_through_csv() {
+        let text = "Name,Amount,Note\n\"Smith, J\",12.5,\"said \"\"ok\"\"\"\nLee,=B2*2,\n";
+        let sheet: Worksheet = parse_csv(text, &CsvOptions::default(), &Budget::default()).unwrap();
+        assert_eq!(sheet.cell(CellId::new(1, 0)).map(|cell| cell.text()), Some("Smith, J"));
+        assert!(sheet.cell(CellId::new(2, 1)).and_then(|cell| cell.formula()).is_some());
+        let mut written = Vec::new();
+        write_csv(&sheet, &mut written, &CsvOptions::default()).unwrap();
+        let reread: Worksheet = parse_csv(std::str::from_utf8(&written).unwrap(), &CsvOptions::default(), &Budget::default()).unwrap();
+        let mut cells = sheet.cells().map(|(cell_id, cell)| (cell_id.row(), cell_id.col(), cell.raw().to_string())).collect::<Vec<_>>();
+        let mut reread = reread.cells().map(|(cell_id, cell)| (cell_id.row(), cell_id.col(), cell.raw().to_string())).collect::<Vec<_>>();
+        cells.sort();
+        reread.sort();
+        assert_eq!(cells, reread);
+    }
+
+    #[test]
+    fn unterminated_quotes_fail_the_import() {
+        let result = parse_csv::<f64>("a,b\n\"c,d\n", &CsvOptions::default(), &Budget::default());
+        assert!(matches!(result, Err(CsvError::UnterminatedQuote(2))));
+    }
+
+    #[test]
+    fn imports_infer_number_formats_unless_disabled() {
+        let text = "Rate,Price\n12.5%,\"$1,200\"\n";
+        let sheet: Worksheet = parse_csv(text, &CsvOptions::default(), &Budget::default()).unwrap();
+        assert_eq!(sheet.number_format(CellId::new(1, 0)), "0.0%");
+        assert_eq!(sheet.number_format(CellId::new(1, 1)), "\"$\"#,##0");
+        let options = CsvOptions{infer_formats: false, ..CsvOptions::default()};
+        let sheet: Worksheet = parse_csv(text, &options, &Budget::default()).unwrap();
+        assert_eq!(sheet.number_format(CellId::new(1, 0)), "General");
+    }
+}
@@ -0,0 +1,145 @@
+/// Currency is an ISO 4217 currency with the symbols amounts in it are
+/// written with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Currency {
+    /// The ISO 4217 code, like `USD`.
+    pub code: &'static str,
+    /// The symbol amounts are usually written with, like `$`.
+    pub symbol: &'static str,
+    /// The number of decimals of the minor unit, 2 for cents and 0 for
+    /// currencies without one like the yen.
+    pub minor_units: u8,
+    /// Other symbols the currency is written with, like `US$`.
+    pub aliases: &'static [&'static str],
+}
+
+/// The currencies recognized in cells. A symbol shared by several
+/// currencies, like `$`, stands for the most common one, and the others
+/// are only recognized by their code or a qualified symbol like `CA$`.
+const CURRENCIES: [Currency; 24] = [
+    Currency{code: "USD", symbol: "$", minor_units: 2, aliases: &["US$"]},
+    Currency{code: "EUR", symbol: "€", minor_units: 2, aliases: &[]},
+    Currency{code: "GBP", symbol: "£", minor_units: 2, aliases: &[]},
+    Currency{code: "JPY", symbol: "¥", minor_units: 0, aliases: &["JP¥", "円"]},
+    Currency{code: "CNY", symbol: "CN¥", minor_units: 2, aliases: &["元", "RMB"]},
+    Currency{code: "CHF", symbol: "CHF", minor_units: 2, aliases: &[]},
+    Currency{code: "CAD", symbol: "CA$", minor_units: 2, aliases: &["C$"]},
+    Currency{code: "AUD", symbol: "A$", minor_units: 2, aliases: &["AU$"]},
+    Currency{code: "NZD", symbol: "NZ$", minor_units: 2, aliases: &[]},
+    Currency{code: "HKD", symbol: "HK$", minor_units: 2, aliases: &[]},
+    Currency{code: "SGD", symbol: "S$", minor_units: 2, aliases: &[]},
+    Currency{code: "MXN", symbol: "MX$", minor_units: 2, aliases: &[]},
+    Currency{code: "BRL", symbol: "R$", minor_units: 2, aliases: &[]},
+    Currency{code: "INR", symbol: "₹", minor_units: 2, aliases: &[]},
+    Currency{code: "KRW", symbol: "₩", minor_units: 0, aliases: &[]},
+    Currency{code: "RUB", symbol: "₽", minor_units: 2, aliases: &[]},
+    Currency{code: "TRY", symbol: "₺", minor_units: 2, aliases: &[]},
+    Currency{code: "ILS", symbol: "₪", minor_units: 2, aliases: &[]},
+    Currency{code: "PLN", symbol: "zł", minor_units: 2, aliases: &[]},
+    Currency{code: "SEK", symbol: "SEK", minor_units: 2, aliases: &[]},
+    Currency{code: "NOK", symbol: "NOK", minor_units: 2, aliases: &[]},
+    Currency{code: "DKK", symbol: "DKK", minor_units: 2, aliases: &[]},
+    Currency{code: "KWD", symbol: "KWD", minor_units: 3, aliases: &[]},
+    Currency{code: "BHD", symbol: "BHD", minor_units: 3, aliases: &[]},
+];
+
+impl Currency {
+    /// Get every currency of the catalog.
+    pub fn all() -> &'static [Currency] {
+        &CURRENCIES
+    }
+
+    /// Find a currency by its code, case insensitively, or by one of its
+    /// symbols.
+    pub fn lookup(text: &str) -> Option<&'static Currency> {
+        let text = text.trim();
+        CURRENCIES.iter().find(|currency| currency.code.eq_ignore_ascii_case(text))
+            .or_else(|| CURRENCIES.iter().find(|currency| currency.symbols().any(|symbol| symbol == text)))
+    }
+
+    /// Iterate the symbol and the aliases of the currency.
+    pub fn symbols(&self) -> impl Iterator<Item=&'static str> {
+        std::iter::once(self.symbol).chain(self.aliases.iter().copied())
+    }
+}
+
+/// Get the ISO 4217 code of a currency code or symbol.
+pub fn normalize(text: &str) -> Option<&'static str> {
+    Currency::lookup(text).map(|currency| currency.code)
+}
+
+/// Whether two currency codes or symbols name the same currency, such as
+/// `$`, `US$` and `USD`. Unknown currencies are the same if written alike.
+pub fn same_currency(a: &str, b: &str) -> bool {
+    match (normalize(a), normalize(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Split an amount written with a currency before or after it, like `US$5`
+/// or `5 EUR`, into the currency as written and the amount. Codes must be
+/// upper case, and longer symbols are tried first so `CA$` is not read as
+/// `$`.
+pub(crate) fn split_amount(text: &str) -> Option<(&str, &str)> {
+    let mut written = CURRENCIES.iter()
+        .flat_map(|currency| currency.symbols().chain(std::iter::once(currency.code)))
+        .collect::<Vec<_>>();
+    written.sort_by_key(|written| std::cmp::Reverse(written.chars().count()));
+    written.into_iter().find_map(|written| {
+        let amount = text.strip_prefix(written).or_else(|| text.strip_suffix(written))?;
+        Some((written, amount.trim()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::format::Locale;
+    use crate::kernel::kernel::{NumericAttribute, Primitive, Value};
+
+    fn currency(raw: &str) -> Option<(f64, String)> {
+        match Value::<f64>::from(raw) {
+            Value::Primitive(Primitive::Number(number)) => match number.attr() {
+                Some(NumericAttribute::Currency(currency)) => Some((number.value(), currency.clone())),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn codes_and_symbols_normalize_to_iso_4217() {
+        assert_eq!(normalize("$"), Some("USD"));
+        assert_eq!(normalize("US$"), Some("USD"));
+        assert_eq!(normalize("eur"), Some("EUR"));
+        assert_eq!(normalize("円"), Some("JPY"));
+        assert_eq!(normalize("XYZ"), None);
+        assert!(same_currency("$", "USD"));
+        assert!(!same_currency("$", "CA$"));
+        assert!(same_currency("XYZ", "XYZ"));
+        assert_eq!(Currency::lookup("KWD").unwrap().minor_units, 3);
+        assert!(Currency::all().iter().all(|currency| Currency::lookup(currency.code) == Some(currency)));
+    }
+
+    #[test]
+    fn amounts_keep_their_currency_as_written() {
+        assert_eq!(currency("CA$5"), Some((5.0, "CA$".to_string())));
+        assert_eq!(currency("$5"), Some((5.0, "$".to_string())));
+        assert_eq!(currency("-1,200 EUR"), Some((-1200.0, "EUR".to_string())));
+        assert_eq!(currency("5 eur"), None);
+        let usd = NumericAttribute::Currency("USD".to_string());
+        assert!(usd.is_compatible(&NumericAttribute::Currency("US$".to_string())));
+        assert!(!usd.is_compatible(&NumericAttribute::Currency("€".to_string())));
+        assert!(!usd.is_compatible(&NumericAttribute::Percent));
+    }
+
+    #[test]
+    fn currency_formats_show_the_minor_unit() {
+        let mut locale = Locale{currency_symbol: "¥".to_string(), currency_before: true, ..Locale::default()};
+        assert_eq!(locale.currency_format(), "\"¥\"#,##0;-\"¥\"#,##0");
+        locale.currency_symbol = "KWD".to_string();
+        locale.currency_before = false;
+        assert_eq!(locale.currency_format(), "#,##0.000 \"KWD\";-#,##0.000 \"KWD\"");
+    }
+}
@@ -0,0 +1,216 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{Numeric, Primitive};
+use thiserror::Error;
+use std::collections::HashMap;
+
+/// Member is a single coordinate used to address a value in a data source,
+/// for instance the field `Region` with the item `East`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Member {
+    pub field: String,
+    pub item: String,
+}
+
+impl Member {
+    pub fn new(field: impl Into<String>, item: impl Into<String>) -> Self {
+        Self{field: field.into(), item: item.into()}
+    }
+
+    /// Pair up a flat `field, item, field, item, ...` argument list as used by
+    /// GETPIVOTDATA. Returns None if an item is missing for the last field.
+    pub fn pairs<S: AsRef<str>>(arguments: &[S]) -> Option<Vec<Self>> {
+        if !arguments.len().is_multiple_of(2) {
+            return None;
+        }
+        Some(arguments.chunks(2)
+            .map(|pair| Self::new(pair[0].as_ref(), pair[1].as_ref()))
+            .collect())
+    }
+
+    /// Parse a cube member expression of the form `[Field].[Item]` as used by
+    /// CUBEVALUE.
+    pub fn parse(expression: &str) -> Result<Self, DataSourceError> {
+        let invalid = || DataSourceError::InvalidMember(expression.to_string());
+        let (field, item) = expression.trim().split_once("].[").ok_or_else(invalid)?;
+        let field = field.strip_prefix('[').ok_or_else(invalid)?;
+        let item = item.strip_suffix(']').ok_or_else(invalid)?;
+        Ok(Self::new(field, item))
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum DataSourceError {
+    #[error("unknown data source {0}")]
+    UnknownSource(String),
+
+    #[error("unknown measure {0}")]
+    UnknownMeasure(String),
+
+    #[error("unknown field {0}")]
+    UnknownField(String),
+
+    #[error("invalid member expression {0}")]
+    InvalidMember(String),
+
+    #[error("no data at the requested coordinates")]
+    NoData,
+}
+
+/// DataSource is a structured source of values, such as a pivot cache, an
+/// external cube or a data provider written in Rust, which functions like
+/// GETPIVOTDATA and CUBEVALUE query by member coordinates.
+pub trait DataSource<T: Arithmetic=f64> {
+    /// Look up the value of a measure at the given member coordinates. Fields
+    /// which are not constrained by a member are aggregated over.
+    fn query(&self, measure: &str, members: &[Member]) -> Result<Primitive<T>, DataSourceError>;
+}
+
+/// DataSources is the set of named data sources available during evaluation.
+pub struct DataSources<T: Arithmetic=f64> {
+    sources: HashMap<String, Box<dyn DataSource<T>>>,
+}
+
+impl<T: Arithmetic> Default for DataSources<T> {
+    fn default() -> Self {
+        Self{sources: HashMap::new()}
+    }
+}
+
+impl<T: Arithmetic> DataSources<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a data source, returning the one previously registered under
+    /// the same name. Names are case insensitive.
+    pub fn register(&mut self, name: &str, source: Box<dyn DataSource<T>>) -> Option<Box<dyn DataSource<T>>> {
+        self.sources.insert(name.to_uppercase(), source)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Box<dyn DataSource<T>>> {
+        self.sources.remove(&name.to_uppercase())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn DataSource<T>> {
+        self.sources.get(&name.to_uppercase()).map(|source| source.as_ref())
+    }
+
+    /// Query the named data source.
+    pub fn query(&self, name: &str, measure: &str, members: &[Member]) -> Result<Primitive<T>, DataSourceError> {
+        self.get(name)
+            .ok_or_else(|| DataSourceError::UnknownSource(name.to_string()))?
+            .query(measure, members)
+    }
+}
+
+/// PivotCache is a flat table of records which is aggregated on query, like
+/// the cache behind a pivot table. Each record has one item per field and one
+/// value per measure.
+#[derive(Clone, Debug)]
+pub struct PivotCache<T: Arithmetic=f64> {
+    fields: Vec<String>,
+    measures: Vec<String>,
+    records: Vec<(Vec<String>, Vec<T>)>,
+}
+
+impl<T: Arithmetic> PivotCache<T> {
+    pub fn new(fields: Vec<String>, measures: Vec<String>) -> Self {
+        Self{fields, measures, records: Vec::new()}
+    }
+
+    /// Add a record. Returns false if the record does not have exactly one
+    /// item per field and one value per measure.
+    pub fn push(&mut self, items: Vec<String>, values: Vec<T>) -> bool {
+        if items.len() != self.fields.len() || values.len() != self.measures.len() {
+            return false;
+        }
+        self.records.push((items, values));
+        true
+    }
+
+    fn index_of(names: &[String], name: &str) -> Option<usize> {
+        names.iter().position(|candidate| candidate.eq_ignore_ascii_case(name))
+    }
+}
+
+impl<T: Arithmetic> DataSource<T> for PivotCache<T> {
+    fn query(&self, measure: &str, members: &[Member]) -> Result<Primitive<T>, DataSourceError> {
+        let measure_index = Self::index_of(&self.measures, measure)
+            .ok_or_else(|| DataSourceError::UnknownMeasure(measure.to_string()))?;
+        let constraints = members.iter()
+            .map(|member| {
+                Self::index_of(&self.fields, &member.field)
+                    .map(|index| (index, member.item.as_str()))
+                    .ok_or_else(|| DataSourceError::UnknownField(member.field.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut total: Option<T> = None;
+        for (items, values) in &self.records {
+            if constraints.iter().all(|(index, item)| items[*index].eq_ignore_ascii_case(item)) {
+                let value = values[measure_index];
+                total = Some(match total {
+                    None => value,
+                    Some(sum) => sum + value,
+                });
+            }
+        }
+
+        match total {
+            None => Err(DataSourceError::NoData),
+            Some(sum) => Ok(Primitive::Number(Numeric::new(sum, None))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> PivotCache {
+        let mut cache = PivotCache::new(vec!["Region".to_string(), "Year".to_string()], vec!["Sales".to_string(), "Units".to_string()]);
+        cache.push(vec!["East".to_string(), "2024".to_string()], vec![10.0, 1.0]);
+        cache.push(vec!["West".to_string(), "2024".to_string()], vec![5.0, 2.0]);
+        cache.push(vec!["East".to_string(), "2023".to_string()], vec![7.0, 3.0]);
+        cache
+    }
+
+    fn number(result: Result<Primitive<f64>, DataSourceError>) -> Option<f64> {
+        match result {
+            Ok(Primitive::Number(number)) => Some(number.value()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn members_pair_and_parse() {
+        assert_eq!(Member::pairs(&["Region", "East", "Year", "2024"]), Some(vec![Member::new("Region", "East"), Member::new("Year", "2024")]));
+        assert_eq!(Member::pairs(&["Region"]), None);
+        assert_eq!(Member::parse(" [Region].[East] "), Ok(Member::new("Region", "East")));
+        for invalid in ["Region.East", "[Region].East", "Region].[East]"] {
+            assert_eq!(Member::parse(invalid), Err(DataSourceError::InvalidMember(invalid.to_string())));
+        }
+    }
+
+    #[test]
+    fn pivot_caches_aggregate_unconstrained_fields() {
+        let cache = cache();
+        assert_eq!(number(cache.query("Sales", &[])), Some(22.0));
+        assert_eq!(number(cache.query("units", &[Member::new("region", "EAST")])), Some(4.0));
+        assert_eq!(number(cache.query("Sales", &[Member::new("Region", "East"), Member::new("Year", "2023")])), Some(7.0));
+        assert_eq!(cache.query("Sales", &[Member::new("Region", "North")]).err(), Some(DataSourceError::NoData));
+        assert_eq!(cache.query("Profit", &[]).err(), Some(DataSourceError::UnknownMeasure("Profit".to_string())));
+        assert_eq!(cache.query("Sales", &[Member::new("City", "Oslo")]).err(), Some(DataSourceError::UnknownField("City".to_string())));
+    }
+
+    #[test]
+    fn sources_are_registered_by_case_insensitive_names() {
+        let mut sources = DataSources::new();
+        assert!(sources.register("Sales", Box::new(cache())).is_none());
+        assert!(sources.register("SALES", Box::new(cache())).is_some());
+        assert_eq!(number(sources.query("sales", "Sales", &[])), Some(22.0));
+        assert_eq!(sources.query("Other", "Sales", &[]).err(), Some(DataSourceError::UnknownSource("Other".to_string())));
+        assert!(sources.remove("Sales").is_some());
+        assert!(sources.get("sales").is_none());
+    }
+}
@@ -0,0 +1,242 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{Cell, CellError, CellId, Formula, Kernel, Primitive, Value};
+use super::profile::Key;
+use super::refactor::rewrite_sheet;
+use super::worksheet::{SheetError, Worksheet};
+use std::collections::{HashMap, HashSet};
+
+/// DuplicateOptions configures which rows of a range count as duplicates.
+/// By default every column is compared and text ignores case.
+#[derive(Debug, Clone, Default)]
+pub struct DuplicateOptions {
+    /// The sheet columns compared, or every column of the range if empty.
+    pub key_columns: Vec<u32>,
+    /// Whether text differing only in case is different.
+    pub case_sensitive: bool,
+    /// Whether leading and trailing whitespace of text is ignored.
+    pub trim: bool,
+    /// Whether the first row of the range is a header, which is never a
+    /// duplicate.
+    pub has_header: bool,
+}
+
+/// Duplicate is a row whose key columns equal those of an earlier row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Duplicate {
+    pub row: u32,
+    /// The first row with the same key, which is kept.
+    pub original: u32,
+}
+
+/// RemovedRow is a duplicate row taken out of a range, with its cells.
+#[derive(Debug, Clone)]
+pub struct RemovedRow<T: Arithmetic=f64> {
+    /// The row of the sheet before removing duplicates.
+    pub row: u32,
+    /// The row it duplicated, before removing duplicates.
+    pub original: u32,
+    /// The cells of the row within the range, from its first column.
+    pub cells: Vec<Option<Cell<T>>>,
+}
+
+fn text_key(text: &str, options: &DuplicateOptions) -> Key {
+    let text = if options.trim { text.trim() } else { text };
+    match options.case_sensitive {
+        true => Key::Text(text.to_string()),
+        false => Key::Text(text.to_lowercase()),
+    }
+}
+
+/// Map the row of a reference into a range whose duplicates were removed.
+/// `kept` holds the rows kept, in order, and `original` the row each
+/// removed row duplicated.
+fn moved_row(row: u32, top: u32, kept: &[u32], original: &HashMap<u32, u32>) -> u32 {
+    let row = original.get(&row).copied().unwrap_or(row);
+    top + kept.partition_point(|kept| *kept < row) as u32
+}
+
+impl<T: Arithmetic> Worksheet<T> {
+    /// Find the rows of the range between two corners which duplicate an
+    /// earlier row, comparing the evaluated values of the key columns.
+    /// Numbers are equal if their values are, and text cells compare as
+    /// text.
+    pub fn find_duplicates(&self, first: CellId, second: CellId, options: &DuplicateOptions) -> Result<Vec<Duplicate>, SheetError> {
+        let range = self.range(first, second);
+        let (left, right) = (range.start().col(), range.end().col());
+        if let Some(col) = options.key_columns.iter().find(|col| !(left..=right).contains(*col)) {
+            return Err(SheetError::KeyColumnOutsideRange(*col));
+        }
+        let columns = match options.key_columns.is_empty() {
+            true => (left..=right).collect::<Vec<_>>(),
+            false => options.key_columns.clone(),
+        };
+        let values = range.evaluate()?;
+        let mut seen = HashMap::new();
+        let mut duplicates = Vec::new();
+        for row in options.has_header as usize..values.rows() {
+            let key = columns.iter()
+                .map(|col| {
+                    let col = (col - left) as usize;
+                    match values.get(row, col).expect("position is in the range") {
+                        Value::Empty => Key::Empty,
+                        Value::Raw => text_key(range.cell(row as u32, col as u32).map_or("", |cell| cell.text()), options),
+                        Value::Primitive(primitive) => match primitive {
+                            Primitive::Number(number) => Key::Number(number.value().to_f64().to_bits()),
+                            Primitive::Text(text) => text_key(text, options),
+                            Primitive::Bool(b) => Key::Bool(*b),
+                            Primitive::Date(date) => Key::Date(*date),
+                            Primitive::Time(time) => Key::Time(*time),
+                            Primitive::IPAddress(octets) => Key::IPAddress(*octets),
+                        },
+                        Value::Error(e) => Key::Error(*e),
+                        Value::FormulaParseError(_) => Key::Error(CellError::Name),
+                        Value::Formula(_) | Value::Array(_) => unreachable!("evaluated values are not formulas or arrays"),
+                    }
+                })
+                .collect::<Vec<_>>();
+            let row = range.start().row() + row as u32;
+            match seen.get(&key) {
+                Some(original) => duplicates.push(Duplicate{row, original: *original}),
+                None => {
+                    seen.insert(key, row);
+                },
+            }
+        }
+        Ok(duplicates)
+    }
+
+    /// Remove the rows of the range between two corners which duplicate an
+    /// earlier row, like the Remove Duplicates command of spreadsheets, and
+    /// move the rows below up to close the gaps. Cells outside the range do
+    /// not move. References on this sheet into the range follow the cells
+    /// they refer to, and references to a removed row refer to the row it
+    /// duplicated instead; ranges within the columns of the range shrink to
+    /// the rows kept. Returns the removed rows.
+    pub fn remove_duplicates(&mut self, first: CellId, second: CellId, options: &DuplicateOptions) -> Result<Vec<RemovedRow<T>>, SheetError> {
+        let duplicates = self.find_duplicates(first, second, options)?;
+        if duplicates.is_empty() {
+            return Ok(Vec::new());
+        }
+        let range = self.range(first, second);
+        let (start, end) = (range.start(), range.end());
+        let removed = duplicates.iter().map(|duplicate| duplicate.row).collect::<HashSet<_>>();
+        let original = duplicates.iter().map(|duplicate| (duplicate.row, duplicate.original)).collect::<HashMap<_, _>>();
+        let kept = (start.row()..=end.row()).filter(|row| !removed.contains(row)).collect::<Vec<_>>();
+
+        let mut moved = Vec::new();
+        let mut removed_rows = Vec::new();
+        for row in start.row()..=end.row() {
+            let cells = (start.col()..=end.col())
+                .map(|col| {
+                    let cell_id = CellId::new(row, col);
+                    let format = self.number_format(cell_id).to_string();
+                    self.set_number_format(cell_id, "General");
+                    (self.clear_cell(cell_id), format)
+                })
+                .collect::<Vec<_>>();
+            match original.get(&row) {
+                Some(original) => removed_rows.push(RemovedRow{row, original: *original, cells: cells.into_iter().map(|(cell, _)| cell).collect()}),
+                None => moved.push(cells),
+            }
+        }
+        for (row, cells) in (start.row()..).zip(moved) {
+            for (col, (cell, format)) in (start.col()..).zip(cells) {
+                let cell_id = CellId::new(row, col);
+                if let Some(cell) = cell {
+                    self.set_cell(cell_id, cell.raw().to_string());
+                }
+                self.set_number_format(cell_id, &format);
+            }
+        }
+
+        let rows = start.row()..=end.row();
+        let cols = start.col()..=end.col();
+        let inside = |cell_id: CellId| rows.contains(&cell_id.row()) && cols.contains(&cell_id.col());
+        let top = start.row();
+        rewrite_sheet(self, &mut |reference| {
+            let replacement = match *reference {
+                Formula::CellRef(cell_id) if inside(cell_id) => {
+                    let row = moved_row(cell_id.row(), top, &kept, &original);
+                    if row == cell_id.row() {
+                        return false;
+                    }
+                    Formula::CellRef(CellId::new(row, cell_id.col()))
+                },
+                Formula::CellRange(a, b) => {
+                    let (first, last) = (a.row().min(b.row()), a.row().max(b.row()));
+                    let (left, right) = (a.col().min(b.col()), a.col().max(b.col()));
+                    if !cols.contains(&left) || !cols.contains(&right) || last < top || first > end.row() {
+                        return false;
+                    }
+                    // The corners move to the first and last rows kept between them.
+                    let kept_before = |row: u32| top + kept.partition_point(|kept| *kept < row) as u32;
+                    let new_first = if rows.contains(&first) { kept_before(first) } else { first };
+                    let new_last = if rows.contains(&last) { kept_before(last + 1).checked_sub(1) } else { Some(last) };
+                    let (new_first, new_last) = match new_last {
+                        Some(new_last) if new_last >= new_first => (new_first, new_last),
+                        _ => {
+                            let row = moved_row(first, top, &kept, &original);
+                            (row, row)
+                        },
+                    };
+                    if (new_first, new_last) == (first, last) {
+                        return false;
+                    }
+                    Formula::CellRange(CellId::new(new_first, left), CellId::new(new_last, right))
+                },
+                _ => return false,
+            };
+            *reference = replacement;
+            true
+        });
+        Ok(removed_rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sheet() -> Worksheet {
+        let mut sheet: Worksheet = Worksheet::new();
+        for (row, (name, qty)) in [("Name", "Qty"), ("Ann", "1"), ("ann ", "2"), ("Bob", "3"), ("Ann", "1")].iter().enumerate() {
+            sheet.set_cell(CellId::new(row as u32, 0), name.to_string());
+            sheet.set_cell(CellId::new(row as u32, 1), qty.to_string());
+        }
+        sheet.set_cell(CellId::new(0, 3), "=SUM(B1:B4)".to_string());
+        sheet.set_cell(CellId::new(1, 3), "=B3".to_string());
+        sheet.set_cell(CellId::new(2, 3), "=B4".to_string());
+        sheet
+    }
+
+    fn raw(sheet: &Worksheet, row: u32, col: u32) -> Option<String> {
+        sheet.cell(CellId::new(row, col)).map(|cell| cell.raw().to_string())
+    }
+
+    #[test]
+    fn duplicates_compare_the_key_columns() {
+        let sheet = sheet();
+        let (first, last) = (CellId::new(0, 0), CellId::new(4, 1));
+        let exact = DuplicateOptions{case_sensitive: true, ..DuplicateOptions::default()};
+        assert_eq!(sheet.find_duplicates(first, last, &exact).unwrap(), [Duplicate{row: 4, original: 1}]);
+        let names = DuplicateOptions{key_columns: vec![0], trim: true, has_header: true, ..DuplicateOptions::default()};
+        assert_eq!(sheet.find_duplicates(first, last, &names).unwrap(), [Duplicate{row: 2, original: 1}, Duplicate{row: 4, original: 1}]);
+        let outside = DuplicateOptions{key_columns: vec![3], ..DuplicateOptions::default()};
+        assert!(matches!(sheet.find_duplicates(first, last, &outside), Err(SheetError::KeyColumnOutsideRange(3))));
+    }
+
+    #[test]
+    fn removing_duplicates_moves_rows_and_references() {
+        let mut sheet = sheet();
+        let names = DuplicateOptions{key_columns: vec![0], trim: true, has_header: true, ..DuplicateOptions::default()};
+        let removed = sheet.remove_duplicates(CellId::new(0, 0), CellId::new(4, 1), &names).unwrap();
+        assert_eq!(removed.iter().map(|removed| (removed.row, removed.original)).collect::<Vec<_>>(), [(2, 1), (4, 1)]);
+        assert_eq!(removed[0].cells[0].as_ref().map(|cell| cell.raw()), Some("ann "));
+        assert_eq!((0..5).map(|row| raw(&sheet, row, 0)).collect::<Vec<_>>(), [Some("Name".to_string()), Some("Ann".to_string()), Some("Bob".to_string()), None, None]);
+        assert_eq!(raw(&sheet, 2, 1).as_deref(), Some("3"));
+        assert_eq!(raw(&sheet, 0, 3).as_deref(), Some("=SUM(B1:B3)"));
+        assert_eq!(raw(&sheet, 1, 3).as_deref(), Some("=B2"));
+        assert_eq!(raw(&sheet, 2, 3).as_deref(), Some("=B3"));
+        assert!(sheet.remove_duplicates(CellId::new(0, 0), CellId::new(2, 1), &names).unwrap().is_empty());
+    }
+}
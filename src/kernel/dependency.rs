@@ -0,0 +1,292 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{CellId, Formula, Value};
+use super::memory::{map_bytes, set_bytes};
+use super::serialize::quote_sheet;
+use super::worksheet::Worksheet;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Precedent is something a formula refers to. References without a sheet
+/// are to the sheet of the formula.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Precedent {
+    Cell{sheet: Option<String>, cell_id: CellId},
+    Range{sheet: Option<String>, start: CellId, end: CellId},
+    Name{sheet: Option<String>, name: String},
+}
+
+impl Precedent {
+    /// Whether this precedent is, or contains, a cell of the sheet of the
+    /// formula.
+    pub fn covers(&self, cell_id: CellId) -> bool {
+        match self {
+            Self::Cell{sheet: None, cell_id: own} => *own == cell_id,
+            Self::Range{sheet: None, start, end} => {
+                (start.row().min(end.row())..=start.row().max(end.row())).contains(&cell_id.row())
+                    && (start.col().min(end.col())..=start.col().max(end.col())).contains(&cell_id.col())
+            },
+            _ => false,
+        }
+    }
+
+    /// Whether this precedent shares a cell with the rectangle between two
+    /// corners of the sheet of the formula.
+    pub fn intersects(&self, first: CellId, second: CellId) -> bool {
+        let (start, end) = match self {
+            Self::Cell{sheet: None, cell_id} => (*cell_id, *cell_id),
+            Self::Range{sheet: None, start, end} => (*start, *end),
+            _ => return false,
+        };
+        let overlaps = |a: u32, b: u32, c: u32, d: u32| a.min(b) <= c.max(d) && c.min(d) <= a.max(b);
+        overlaps(start.row(), end.row(), first.row(), second.row()) && overlaps(start.col(), end.col(), first.col(), second.col())
+    }
+}
+
+impl fmt::Display for Precedent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sheet = match self {
+            Self::Cell{sheet, ..} | Self::Range{sheet, ..} | Self::Name{sheet, ..} => sheet,
+        };
+        if let Some(sheet) = sheet {
+            write!(f, "{}!", quote_sheet(sheet))?;
+        }
+        match self {
+            Self::Cell{cell_id, ..} => write!(f, "{}", cell_id),
+            Self::Range{start, end, ..} => write!(f, "{}:{}", start, end),
+            Self::Name{name, ..} => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Dependency is a formula cell referring to a precedent.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Dependency {
+    pub cell_id: CellId,
+    pub precedent: Precedent,
+}
+
+/// DependencyDiff lists the dependencies added and removed between two
+/// snapshots of a dependency graph, sorted by cell row by row.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DependencyDiff {
+    pub added: Vec<Dependency>,
+    pub removed: Vec<Dependency>,
+}
+
+impl DependencyDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+
+    /// Get the cells whose dependencies changed, row by row.
+    pub fn changed_cells(&self) -> Vec<CellId> {
+        let mut cells = self.added.iter().chain(&self.removed)
+            .map(|dependency| dependency.cell_id)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        cells.sort_by_key(|cell_id| (cell_id.row(), cell_id.col()));
+        cells
+    }
+}
+
+/// Collect the references of a formula.
+pub(crate) fn collect<T: Arithmetic>(formula: &Formula<T>, sheet: Option<&str>, precedents: &mut HashSet<Precedent>) {
+    let sheet_name = sheet.map(|sheet| sheet.to_string());
+    match formula {
+        Formula::CellRef(cell_id) => {
+            precedents.insert(Precedent::Cell{sheet: sheet_name, cell_id: *cell_id});
+        },
+        Formula::CellRange(start, end) => {
+            precedents.insert(Precedent::Range{sheet: sheet_name, start: *start, end: *end});
+        },
+        Formula::Name(name) => {
+            precedents.insert(Precedent::Name{sheet: sheet_name, name: name.clone()});
+        },
+        Formula::SheetRef(sheet, target) => collect(target, Some(sheet), precedents),
+        _ => {
+            for operand in formula.operands() {
+                if let Value::Formula(operand) = operand {
+                    collect(operand, sheet, precedents);
+                }
+            }
+        },
+    }
+}
+
+/// DependencyGraph is a snapshot of what the formulas of a sheet refer to.
+/// Taking a snapshot before and after an edit session and diffing them
+/// shows which dependencies the edits added and removed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DependencyGraph {
+    precedents: HashMap<CellId, HashSet<Precedent>>,
+}
+
+impl DependencyGraph {
+    /// Take a snapshot of the dependencies of a sheet.
+    pub fn of<T: Arithmetic>(sheet: &Worksheet<T>) -> Self {
+        let mut precedents = HashMap::new();
+        for (cell_id, cell) in sheet.cells() {
+            if let Some(formula) = cell.formula() {
+                let mut own = HashSet::new();
+                collect(formula, None, &mut own);
+                if !own.is_empty() {
+                    precedents.insert(cell_id, own);
+                }
+            }
+        }
+        Self{precedents}
+    }
+
+    /// Get the bytes the graph takes on the heap.
+    pub(crate) fn heap_bytes(&self) -> usize {
+        let names = |precedent: &Precedent| match precedent {
+            Precedent::Cell{sheet, ..} | Precedent::Range{sheet, ..} => sheet.as_ref().map_or(0, String::capacity),
+            Precedent::Name{sheet, name} => sheet.as_ref().map_or(0, String::capacity) + name.capacity(),
+        };
+        map_bytes(&self.precedents) + self.precedents.values()
+            .map(|own| set_bytes(own) + own.iter().map(names).sum::<usize>())
+            .sum::<usize>()
+    }
+
+    /// Get what a cell refers to.
+    pub fn precedents(&self, cell_id: CellId) -> impl Iterator<Item=&Precedent> {
+        self.precedents.get(&cell_id).into_iter().flatten()
+    }
+
+    /// Get the cells which refer to a cell directly or through other cells,
+    /// row by row.
+    pub fn dependents(&self, cell_id: CellId) -> Vec<CellId> {
+        let mut found = HashSet::new();
+        let mut stack = vec![cell_id];
+        while let Some(current) = stack.pop() {
+            for (dependent, precedents) in &self.precedents {
+                if precedents.iter().any(|precedent| precedent.covers(current)) && found.insert(*dependent) {
+                    stack.push(*dependent);
+                }
+            }
+        }
+        found.remove(&cell_id);
+        let mut dependents = found.into_iter().collect::<Vec<_>>();
+        dependents.sort_by_key(|cell_id| (cell_id.row(), cell_id.col()));
+        dependents
+    }
+
+    /// Get the cells which refer to a cell of any of the rectangles between
+    /// pairs of corners, directly or through other cells, row by row.
+    /// Formulas are matched against whole rectangles, which is much cheaper
+    /// than finding the dependents of each of their cells.
+    pub fn dependents_of_regions(&self, regions: &[(CellId, CellId)]) -> Vec<CellId> {
+        let mut found = HashSet::new();
+        let mut stack = Vec::new();
+        for (dependent, precedents) in &self.precedents {
+            let touched = precedents.iter().any(|precedent| regions.iter().any(|(first, second)| precedent.intersects(*first, *second)));
+            if touched && found.insert(*dependent) {
+                stack.push(*dependent);
+            }
+        }
+        while let Some(current) = stack.pop() {
+            for (dependent, precedents) in &self.precedents {
+                if precedents.iter().any(|precedent| precedent.covers(current)) && found.insert(*dependent) {
+                    stack.push(*dependent);
+                }
+            }
+        }
+        let mut dependents = found.into_iter().collect::<Vec<_>>();
+        dependents.sort_by_key(|cell_id| (cell_id.row(), cell_id.col()));
+        dependents
+    }
+
+    /// Get the dependencies added and removed from `before` to `after`.
+    pub fn diff(before: &Self, after: &Self) -> DependencyDiff {
+        DependencyDiff{added: after.missing_from(before), removed: before.missing_from(after)}
+    }
+
+    /// Get the cells an edit session affects: the cells whose dependencies
+    /// changed and everything which depends on them in this graph.
+    pub fn impact(&self, diff: &DependencyDiff) -> Vec<CellId> {
+        let mut cells = HashSet::new();
+        for cell_id in diff.changed_cells() {
+            cells.insert(cell_id);
+            cells.extend(self.dependents(cell_id));
+        }
+        let mut cells = cells.into_iter().collect::<Vec<_>>();
+        cells.sort_by_key(|cell_id| (cell_id.row(), cell_id.col()));
+        cells
+    }
+
+    /// Get the dependencies of this graph which `other` lacks.
+    fn missing_from(&self, other: &Self) -> Vec<Dependency> {
+        let mut missing = Vec::new();
+        for (cell_id, precedents) in &self.precedents {
+            for precedent in precedents {
+                if !other.precedents.get(cell_id).is_some_and(|others| others.contains(precedent)) {
+                    missing.push(Dependency{cell_id: *cell_id, precedent: precedent.clone()});
+                }
+            }
+        }
+        missing.sort_by_cached_key(|dependency| {
+            (dependency.cell_id.row(), dependency.cell_id.col(), dependency.precedent.to_string())
+        });
+        missing
+    }
+}
+
+impl<T: Arithmetic> Worksheet<T> {
+    /// Take a snapshot of the dependencies of this sheet.
+    pub fn dependency_graph(&self) -> DependencyGraph {
+        DependencyGraph::of(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::Kernel;
+
+    fn id(cell_id: &str) -> CellId {
+        CellId::parse(cell_id).unwrap()
+    }
+
+    fn sheet(cells: &[(&str, &str)]) -> Worksheet {
+        let mut sheet: Worksheet = Worksheet::new();
+        for (cell_id, data) in cells {
+            sheet.set_cell(id(cell_id), data.to_string());
+        }
+        sheet
+    }
+
+    #[test]
+    fn graphs_find_dependents_through_ranges() {
+        let sheet = sheet(&[("A1", "1"), ("B1", "=SUM(A1:A3)"), ("C1", "=B1*2"), ("D1", "=Other!A1+Rate")]);
+        let graph = sheet.dependency_graph();
+        assert_eq!(graph.dependents(id("A2")), [id("B1"), id("C1")]);
+        let mut precedents = graph.precedents(id("D1")).map(Precedent::to_string).collect::<Vec<_>>();
+        precedents.sort();
+        assert_eq!(precedents, ["Other!A1", "Rate"]);
+        assert!(!Precedent::Cell{sheet: Some("Other".to_string()), cell_id: id("A1")}.covers(id("A1")));
+    }
+
+    #[test]
+    fn diffs_show_the_impact_of_edits() {
+        let mut sheet = sheet(&[("A1", "1"), ("A2", "2"), ("B1", "=A1*2"), ("C1", "=B1+1")]);
+        let before = sheet.dependency_graph();
+        sheet.set_cell(id("B1"), "=A2*2".to_string());
+        let after = sheet.dependency_graph();
+        let diff = DependencyGraph::diff(&before, &after);
+        assert_eq!(diff.added, [Dependency{cell_id: id("B1"), precedent: Precedent::Cell{sheet: None, cell_id: id("A2")}}]);
+        assert_eq!(diff.removed, [Dependency{cell_id: id("B1"), precedent: Precedent::Cell{sheet: None, cell_id: id("A1")}}]);
+        assert_eq!(after.impact(&diff), [id("B1"), id("C1")]);
+        assert!(DependencyGraph::diff(&after, &sheet.dependency_graph()).is_empty());
+    }
+
+    #[test]
+    fn regions_find_dependents_of_any_of_their_cells() {
+        let sheet = sheet(&[("B1", "=SUM(A1:A3)"), ("C1", "=B1*2"), ("D1", "=E5"), ("D2", "=Other!A2")]);
+        let graph = sheet.dependency_graph();
+        assert_eq!(graph.dependents_of_regions(&[(id("A3"), id("A9"))]), [id("B1"), id("C1")]);
+        assert_eq!(graph.dependents_of_regions(&[(id("A5"), id("A9")), (id("E1"), id("F5"))]), [id("D1")]);
+        assert!(Precedent::Range{sheet: None, start: id("A1"), end: id("A3")}.intersects(id("C2"), id("A2")));
+        assert!(!Precedent::Cell{sheet: Some("Other".to_string()), cell_id: id("A2")}.intersects(id("A1"), id("A3")));
+    }
+}
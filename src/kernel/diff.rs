@@ -0,0 +1,340 @@
+use super::arithmetic::Arithmetic;
+use super::conditional::Color;
+use super::kernel::CellId;
+use super::theme::ResolvedStyle;
+use super::workbook::Workbook;
+use super::worksheet::Worksheet;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+/// ChangeKind is how a single cell differs between two workbooks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// CellChange is a difference in the contents of one cell.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CellChange {
+    pub sheet: String,
+    pub cell_id: CellId,
+    pub kind: ChangeKind,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+impl CellChange {
+    /// Whether the cell holds a formula before or after the change.
+    pub fn is_formula(&self) -> bool {
+        let is_formula = |raw: &Option<String>| raw.as_ref().is_some_and(|raw| raw.trim_start().starts_with('='));
+        is_formula(&self.before) || is_formula(&self.after)
+    }
+}
+
+/// FormatPart is what about how a cell is drawn changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FormatPart {
+    NumberFormat,
+    Style,
+}
+
+/// FormatChange is a difference in the number format or style of one cell,
+/// described as text. Styles are compared as they are drawn, with their
+/// theme colors and fonts looked up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatChange {
+    pub sheet: String,
+    pub cell_id: CellId,
+    pub part: FormatPart,
+    pub before: String,
+    pub after: String,
+}
+
+/// MovedRange is a block of cells whose contents moved by the same offset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MovedRange {
+    pub sheet: String,
+    /// The corners of the bounding box of the cells before the move.
+    pub from: (CellId, CellId),
+    /// The corners of the bounding box of the cells after the move.
+    pub to: (CellId, CellId),
+    pub cell_count: usize,
+}
+
+/// WorkbookDiff lists the differences between two workbooks. Cells whose
+/// contents moved elsewhere on their sheet are reported as moved ranges
+/// rather than as removed and added cells.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WorkbookDiff {
+    pub added_sheets: Vec<String>,
+    pub removed_sheets: Vec<String>,
+    pub changes: Vec<CellChange>,
+    pub moved: Vec<MovedRange>,
+    pub formats: Vec<FormatChange>,
+}
+
+impl WorkbookDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_sheets.is_empty() && self.removed_sheets.is_empty() && self.changes.is_empty() && self.moved.is_empty() && self.formats.is_empty()
+    }
+}
+
+fn raw_cells<T: Arithmetic>(sheet: &Worksheet<T>) -> HashMap<CellId, &str> {
+    sheet.cells().map(|(cell_id, cell)| (cell_id, cell.raw())).collect()
+}
+
+fn diff_sheet<T: Arithmetic>(name: &str, before: &Worksheet<T>, after: &Worksheet<T>, diff: &mut WorkbookDiff) {
+    let before_cells = raw_cells(before);
+    let after_cells = raw_cells(after);
+
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+    for cell_id in before.cell_ids() {
+        match after_cells.get(&cell_id) {
+            None => removed.push(cell_id),
+            Some(raw) if *raw != before_cells[&cell_id] => diff.changes.push(CellChange{
+                sheet: name.to_string(),
+                cell_id,
+                kind: ChangeKind::Changed,
+                before: Some(before_cells[&cell_id].to_string()),
+                after: Some(raw.to_string()),
+            }),
+            Some(_) => {},
+        }
+    }
+    for cell_id in after.cell_ids() {
+        if !before_cells.contains_key(&cell_id) {
+            added.push(cell_id);
+        }
+    }
+
+    // Pair each removed cell with an added cell holding the same contents,
+    // then group the pairs by the offset they moved by.
+    let mut added_by_raw: HashMap<&str, VecDeque<CellId>> = HashMap::new();
+    for cell_id in &added {
+        added_by_raw.entry(after_cells[cell_id]).or_default().push_back(*cell_id);
+    }
+    let mut moves: BTreeMap<(i64, i64), Vec<(CellId, CellId)>> = BTreeMap::new();
+    let mut moved_from = HashSet::new();
+    for cell_id in &removed {
+        if let Some(target) = added_by_raw.get_mut(before_cells[cell_id]).and_then(VecDeque::pop_front) {
+            let offset = (target.row() as i64 - cell_id.row() as i64, target.col() as i64 - cell_id.col() as i64);
+            moves.entry(offset).or_default().push((*cell_id, target));
+            moved_from.insert(*cell_id);
+        }
+    }
+    let moved_to = moves.values().flatten().map(|(_, to)| *to).collect::<HashSet<_>>();
+    for pairs in moves.values() {
+        let bounds = |cells: &mut dyn Iterator<Item=CellId>| {
+            let cells = cells.collect::<Vec<_>>();
+            let top = cells.iter().map(|c| c.row()).min().unwrap_or(0);
+            let left = cells.iter().map(|c| c.col()).min().unwrap_or(0);
+            let bottom = cells.iter().map(|c| c.row()).max().unwrap_or(0);
+            let right = cells.iter().map(|c| c.col()).max().unwrap_or(0);
+            (CellId::new(top, left), CellId::new(bottom, right))
+        };
+        diff.moved.push(MovedRange{
+            sheet: name.to_string(),
+            from: bounds(&mut pairs.iter().map(|(from, _)| *from)),
+            to: bounds(&mut pairs.iter().map(|(_, to)| *to)),
+            cell_count: pairs.len(),
+        });
+    }
+
+    for cell_id in removed.into_iter().filter(|cell_id| !moved_from.contains(cell_id)) {
+        diff.changes.push(CellChange{
+            sheet: name.to_string(),
+            cell_id,
+            kind: ChangeKind::Removed,
+            before: Some(before_cells[&cell_id].to_string()),
+            after: None,
+        });
+    }
+    for cell_id in added.into_iter().filter(|cell_id| !moved_to.contains(cell_id)) {
+        diff.changes.push(CellChange{
+            sheet: name.to_string(),
+            cell_id,
+            kind: ChangeKind::Added,
+            before: None,
+            after: Some(after_cells[&cell_id].to_string()),
+        });
+    }
+}
+
+/// Describe a style as it is drawn, such as `Calibri, bold, fill #FFC000`.
+fn describe_style(style: Option<ResolvedStyle>) -> String {
+    let Some(style) = style else { return "Default".to_string() };
+    let hex = |color: Color| format!("#{:02X}{:02X}{:02X}", color.r, color.g, color.b);
+    let mut parts = vec![style.font];
+    if style.bold {
+        parts.push("bold".to_string());
+    }
+    if style.italic {
+        parts.push("italic".to_string());
+    }
+    parts.extend(style.font_color.map(|color| format!("color {}", hex(color))));
+    parts.extend(style.fill.map(|color| format!("fill {}", hex(color))));
+    parts.join(", ")
+}
+
+/// Compare the number formats and styles of the cells of a sheet in two
+/// workbooks. Formats belong to positions, so they are compared by cell
+/// even where contents moved.
+fn diff_formats<T: Arithmetic>(name: &str, before: &Workbook<T>, after: &Workbook<T>, diff: &mut WorkbookDiff) {
+    let (Some(old), Some(new)) = (before.sheet(name), after.sheet(name)) else { return };
+    let mut cells = old.format_map().keys().chain(old.style_map().keys())
+        .chain(new.format_map().keys()).chain(new.style_map().keys())
+        .copied()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    cells.sort_by_key(|cell_id| (cell_id.row(), cell_id.col()));
+    for cell_id in cells {
+        let (old_format, new_format) = (old.number_format(cell_id), new.number_format(cell_id));
+        if old_format != new_format {
+            diff.formats.push(FormatChange{
+                sheet: name.to_string(),
+                cell_id,
+                part: FormatPart::NumberFormat,
+                before: old_format.to_string(),
+                after: new_format.to_string(),
+            });
+        }
+        let old_style = before.cell_style(name, cell_id).ok().flatten();
+        let new_style = after.cell_style(name, cell_id).ok().flatten();
+        if old_style != new_style {
+            diff.formats.push(FormatChange{
+                sheet: name.to_string(),
+                cell_id,
+                part: FormatPart::Style,
+                before: describe_style(old_style),
+                after: describe_style(new_style),
+            });
+        }
+    }
+}
+
+/// Compare the cell contents, number formats and styles of two workbooks.
+pub fn diff<T: Arithmetic>(before: &Workbook<T>, after: &Workbook<T>) -> WorkbookDiff {
+    let mut diff = WorkbookDiff::default();
+    for (name, sheet) in before.sheets() {
+        match after.sheet(name) {
+            Some(other) => {
+                diff_sheet(name, sheet, other, &mut diff);
+                diff_formats(name, before, after, &mut diff);
+            },
+            None => diff.removed_sheets.push(name.to_string()),
+        }
+    }
+    for name in after.sheet_names() {
+        if before.sheet(name).is_none() {
+            diff.added_sheets.push(name.to_string());
+        }
+    }
+    diff.changes.sort_by(|a, b| {
+        (&a.sheet, a.cell_id.row(), a.cell_id.col()).cmp(&(&b.sheet, b.cell_id.row(), b.cell_id.col()))
+    });
+    diff
+}
+
+impl<T: Arithmetic> Workbook<T> {
+    /// Compare this workbook against a later version of it.
+    pub fn diff(&self, after: &Workbook<T>) -> WorkbookDiff {
+        diff(self, after)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::compare::{Category, CompareReport};
+    use crate::kernel::conditional::Color;
+    use crate::kernel::theme::{CellStyle, ColorRef};
+
+    fn workbook() -> Workbook {
+        let mut workbook = Workbook::new();
+        workbook.add_sheet("Sheet1").unwrap();
+        for (cell_id, data) in [("A1", "1"), ("A2", "2"), ("A3", "=A1+A2"), ("B1", "x"), ("B2", "y")] {
+            workbook.set_cell("Sheet1", CellId::parse(cell_id).unwrap(), data.to_string()).unwrap();
+        }
+        workbook
+    }
+
+    #[test]
+    fn reports_changed_and_moved_contents() {
+        let before = workbook();
+        let mut after = workbook();
+        after.set_cell("Sheet1", CellId::parse("A2").unwrap(), "3".to_string()).unwrap();
+        after.set_cell("Sheet1", CellId::parse("A3").unwrap(), "=A1*A2".to_string()).unwrap();
+        after.set_cell("Sheet1", CellId::parse("B1").unwrap(), String::new()).unwrap();
+        after.set_cell("Sheet1", CellId::parse("B2").unwrap(), String::new()).unwrap();
+        after.set_cell("Sheet1", CellId::parse("D1").unwrap(), "x".to_string()).unwrap();
+        after.set_cell("Sheet1", CellId::parse("D2").unwrap(), "y".to_string()).unwrap();
+        after.add_sheet("Notes").unwrap();
+
+        let diff = before.diff(&after);
+        assert_eq!(diff.added_sheets, ["Notes"]);
+        let changes = diff.changes.iter().map(|change| (change.cell_id.to_string(), change.kind)).collect::<Vec<_>>();
+        assert_eq!(changes, [("A2".to_string(), ChangeKind::Changed), ("A3".to_string(), ChangeKind::Changed)]);
+        assert_eq!(diff.moved.len(), 1);
+        assert_eq!((diff.moved[0].from, diff.moved[0].to, diff.moved[0].cell_count), ((CellId::new(0, 1), CellId::new(1, 1)), (CellId::new(0, 3), CellId::new(1, 3)), 2));
+
+        let report = CompareReport::new(&diff);
+        assert_eq!((report.count(Category::Sheet), report.count(Category::Formula), report.count(Category::Value), report.count(Category::Range)), (1, 1, 1, 1));
+        assert!(report.to_html().contains("<h1>Spreadsheet Compare</h1>"));
+        assert!(before.diff(&workbook()).is_empty());
+    }
+
+    #[test]
+    fn moves_pair_many_cells_of_the_same_contents() {
+        let mut before = Workbook::<f64>::new();
+        before.add_sheet("Sheet1").unwrap();
+        let mut after = Workbook::<f64>::new();
+        after.add_sheet("Sheet1").unwrap();
+        for row in 0..20_000 {
+            before.set_cell("Sheet1", CellId::new(row, 0), "same".to_string()).unwrap();
+            after.set_cell("Sheet1", CellId::new(row, 1), "same".to_string()).unwrap();
+        }
+        let diff = before.diff(&after);
+        assert!(diff.changes.is_empty());
+        assert_eq!(diff.moved.len(), 1);
+        assert_eq!(diff.moved[0].cell_count, 20_000);
+    }
+
+    #[test]
+    fn reports_changed_number_formats() {
+        let before = workbook();
+        let mut after = workbook();
+        after.sheet_mut("Sheet1").unwrap().set_number_format(CellId::parse("A1").unwrap(), "0.00");
+        // Formats stay with the position when contents move away.
+        after.set_cell("Sheet1", CellId::parse("B1").unwrap(), String::new()).unwrap();
+        after.set_cell("Sheet1", CellId::parse("C1").unwrap(), "x".to_string()).unwrap();
+
+        let diff = before.diff(&after);
+        let formats = diff.formats.iter().map(|change| (change.cell_id.to_string(), change.part, change.before.as_str(), change.after.as_str())).collect::<Vec<_>>();
+        assert_eq!(formats, [("A1".to_string(), FormatPart::NumberFormat, "General", "0.00")]);
+        let report = CompareReport::new(&diff);
+        assert_eq!(report.count(Category::Format), 1);
+        assert!(report.to_html().contains("<tr class=\"number-format\">"));
+    }
+
+    #[test]
+    fn reports_changed_styles_as_drawn() {
+        let mut before = workbook();
+        let plain = before.add_style(CellStyle::new());
+        before.sheet_mut("Sheet1").unwrap().set_style(CellId::parse("A2").unwrap(), Some(plain));
+        let mut after = workbook();
+        let plain = after.add_style(CellStyle::new());
+        let bold = after.add_style(CellStyle::new().bold(true).fill(ColorRef::Rgb(Color{r: 255, g: 192, b: 0})));
+        after.sheet_mut("Sheet1").unwrap().set_style(CellId::parse("A2").unwrap(), Some(plain));
+        after.sheet_mut("Sheet1").unwrap().set_style(CellId::parse("A3").unwrap(), Some(bold));
+
+        let diff = before.diff(&after);
+        let formats = diff.formats.iter().map(|change| (change.cell_id.to_string(), change.part, change.before.as_str(), change.after.as_str())).collect::<Vec<_>>();
+        assert_eq!(formats, [("A3".to_string(), FormatPart::Style, "Default", "Calibri, bold, fill #FFC000")]);
+        let report = CompareReport::new(&diff);
+        assert_eq!(report.count(Category::Format), 1);
+        assert!(report.to_html().contains("<tr class=\"style\">"));
+    }
+}
@@ -0,0 +1,244 @@
+use super::shift_jis;
+use std::sync::OnceLock;
+
+/// Encoding is a character encoding text files are imported from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Windows1252,
+    /// Shift_JIS with the extensions of Windows code page 932.
+    ShiftJis,
+}
+
+/// The characters of Windows-1252 bytes `0x80` through `0x9F`. The other
+/// bytes map to the code point of the same value.
+const WINDOWS_1252: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+/// The number of bytes inspected when guessing an encoding.
+const SAMPLE: usize = 4096;
+
+/// Get the encoding announced by a byte order mark and the length of the mark.
+fn bom(bytes: &[u8]) -> Option<(Encoding, usize)> {
+    if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        Some((Encoding::Utf8, 3))
+    } else if bytes.starts_with(&[0xFF, 0xFE]) {
+        Some((Encoding::Utf16Le, 2))
+    } else if bytes.starts_with(&[0xFE, 0xFF]) {
+        Some((Encoding::Utf16Be, 2))
+    } else {
+        None
+    }
+}
+
+/// Guess UTF-16 without a byte order mark from NUL bytes, which mostly text
+/// in Latin scripts has in every other position.
+fn guess_utf16(sample: &[u8]) -> Option<Encoding> {
+    let even = sample.iter().step_by(2).filter(|b| **b == 0).count();
+    let odd = sample.iter().skip(1).step_by(2).filter(|b| **b == 0).count();
+    let pairs = sample.len() / 2;
+    if pairs == 0 {
+        None
+    } else if odd * 2 > pairs && even * 10 < pairs {
+        Some(Encoding::Utf16Le)
+    } else if even * 2 > pairs && odd * 10 < pairs {
+        Some(Encoding::Utf16Be)
+    } else {
+        None
+    }
+}
+
+fn sjis_rows() -> &'static [Vec<char>] {
+    static ROWS: OnceLock<Vec<Vec<char>>> = OnceLock::new();
+    ROWS.get_or_init(|| shift_jis::ROWS.iter().map(|row| row.chars().collect()).collect())
+}
+
+/// Look up a Shift_JIS double byte character.
+fn sjis_pair(lead: u8, trail: u8) -> Option<char> {
+    let mut row = 0;
+    let mut found = None;
+    for (first, last) in shift_jis::LEADS {
+        if (first..=last).contains(&lead) {
+            found = Some(row + (lead - first) as usize);
+            break;
+        }
+        row += (last - first) as usize + 1;
+    }
+    let column = match trail {
+        0x40..=0x7E => trail as usize - 0x40,
+        0x80..=0xFC => trail as usize - 0x41,
+        _ => return None,
+    };
+    Some(sjis_rows()[found?][column]).filter(|c| *c != char::REPLACEMENT_CHARACTER)
+}
+
+/// Decode Shift_JIS, returning `None` at the first invalid sequence when
+/// `strict`, or replacing invalid sequences otherwise.
+fn decode_sjis(bytes: &[u8], strict: bool) -> Option<String> {
+    let mut text = String::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        let (c, width) = match b {
+            0x00..=0x7F => (Some(b as char), 1),
+            0xA1..=0xDF => (char::from_u32(0xFF61 + (b - 0xA1) as u32), 1),
+            0x81..=0x9F | 0xE0..=0xFC => match bytes.get(i + 1) {
+                Some(&trail) => match sjis_pair(b, trail) {
+                    Some(c) => (Some(c), 2),
+                    None => (None, 1),
+                },
+                None => (None, 1),
+            },
+            _ => (None, 1),
+        };
+        match c {
+            Some(c) => text.push(c),
+            None if strict => return None,
+            None => text.push(char::REPLACEMENT_CHARACTER),
+        }
+        i += width;
+    }
+    Some(text)
+}
+
+/// Weigh the evidence of text which decodes as Shift_JIS, and get the
+/// number of characters which look Japanese and the number which look like
+/// accented Latin letters read wrongly. Double byte characters of two high
+/// bytes look Japanese. A high byte alone after an ASCII letter, which
+/// Shift_JIS reads with the letter after it as in `Cr\xE8me`, and halfwidth
+/// katakana next to ASCII letters, as in `\xC9cole`, look Latin.
+fn sjis_evidence(sample: &[u8]) -> (usize, usize) {
+    let (mut japanese, mut latin) = (0, 0);
+    let mut after_letter = false;
+    let mut i = 0;
+    while i < sample.len() {
+        let b = sample[i];
+        match b {
+            0xA1..=0xDF => {
+                match after_letter || sample.get(i + 1).is_some_and(u8::is_ascii_alphabetic) {
+                    true => latin += 1,
+                    false => japanese += 1,
+                }
+                i += 1;
+            },
+            0x81..=0x9F | 0xE0..=0xFC => {
+                match sample.get(i + 1) {
+                    Some(trail) if *trail >= 0x80 => japanese += 1,
+                    Some(trail) if after_letter && trail.is_ascii_alphabetic() => latin += 1,
+                    _ => {},
+                }
+                i += 2;
+            },
+            _ => i += 1,
+        }
+        after_letter = b.is_ascii_alphabetic();
+    }
+    (japanese, latin)
+}
+
+fn decode_utf16(bytes: &[u8], unit: fn([u8; 2]) -> u16) -> String {
+    let units = bytes.chunks(2).map(|pair| match pair {
+        [a, b] => unit([*a, *b]),
+        _ => 0xFFFD,
+    });
+    char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect()
+}
+
+impl Encoding {
+    /// Guess the encoding of text from its byte order mark, or failing that
+    /// from what its first bytes look like. Text which is valid UTF-8 is
+    /// taken to be UTF-8. Valid Shift_JIS is taken to be Shift_JIS unless
+    /// more of it looks like accented Latin letters than like Japanese, as
+    /// French and German text often decodes as Shift_JIS. Anything else is
+    /// Windows-1252, which can decode anything.
+    pub fn detect(bytes: &[u8]) -> Self {
+        if let Some((encoding, _)) = bom(bytes) {
+            return encoding;
+        }
+        let sample = &bytes[..bytes.len().min(SAMPLE)];
+        if let Some(encoding) = guess_utf16(sample) {
+            return encoding;
+        }
+        match std::str::from_utf8(sample) {
+            Ok(_) => return Self::Utf8,
+            // A multi-byte character cut off by the end of the sample.
+            Err(e) if e.error_len().is_none() && sample.len() < bytes.len() => return Self::Utf8,
+            Err(_) => {},
+        }
+        // The sample may end between the bytes of a double byte character.
+        let truncated = sample.len() < bytes.len();
+        let is_sjis = decode_sjis(sample, true).is_some()
+            || (truncated && decode_sjis(&sample[..sample.len() - 1], true).is_some());
+        if is_sjis && sample.iter().any(|b| *b >= 0x80) {
+            let (japanese, latin) = sjis_evidence(sample);
+            if latin <= japanese {
+                return Self::ShiftJis;
+            }
+        }
+        Self::Windows1252
+    }
+
+    /// Decode text, skipping a byte order mark for this encoding and
+    /// replacing invalid sequences with U+FFFD.
+    pub fn decode(&self, bytes: &[u8]) -> String {
+        let bytes = match bom(bytes) {
+            Some((encoding, length)) if encoding == *self => &bytes[length..],
+            _ => bytes,
+        };
+        match self {
+            Self::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            Self::Utf16Le => decode_utf16(bytes, u16::from_le_bytes),
+            Self::Utf16Be => decode_utf16(bytes, u16::from_be_bytes),
+            Self::Windows1252 => bytes.iter()
+                .map(|b| match b {
+                    0x80..=0x9F => WINDOWS_1252[*b as usize - 0x80],
+                    _ => *b as char,
+                })
+                .collect(),
+            Self::ShiftJis => decode_sjis(bytes, false).expect("lenient decoding never fails"),
+        }
+    }
+}
+
+/// Decode imported text in `encoding`, or in the detected encoding if none
+/// is given. Returns the text and the encoding it was decoded from.
+pub fn decode_text(bytes: &[u8], encoding: Option<Encoding>) -> (String, Encoding) {
+    let encoding = encoding.unwrap_or_else(|| Encoding::detect(bytes));
+    (encoding.decode(bytes), encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_latin_text_which_decodes_as_shift_jis() {
+        for sample in [&b"Cr\xe8me,Br\xfbl\xe9e\n"[..], b"\xc9cole,Fa\xe7ade\n", b"Stra\xdfe,M\xfcller\n"] {
+            assert_eq!(Encoding::detect(sample), Encoding::Windows1252);
+        }
+        assert_eq!(Encoding::Windows1252.decode(b"Cr\xe8me,Br\xfbl\xe9e\n"), "Crème,Brûlée\n");
+    }
+
+    #[test]
+    fn detects_japanese_text() {
+        // 日本語,テスト and ID,ﾃｽﾄ
+        for sample in [&b"\x93\xfa\x96\x7b\x8c\xea,\x83\x65\x83\x58\x83\x67\n"[..], b"ID\x83\x65\x83\x58\x83\x67,\x93\xfa\n", b"ID,\xc3\xbd\xc4\n"] {
+            assert_eq!(Encoding::detect(sample), Encoding::ShiftJis);
+        }
+        assert_eq!(Encoding::ShiftJis.decode(b"\x93\xfa\x96\x7b\x8c\xea"), "日本語");
+    }
+
+    #[test]
+    fn detects_byte_order_marks_and_utf8() {
+        assert_eq!(Encoding::detect(b"\xef\xbb\xbfa,b"), Encoding::Utf8);
+        assert_eq!(Encoding::detect("Crème".as_bytes()), Encoding::Utf8);
+        assert_eq!(Encoding::detect(b"\xff\xfea\x00"), Encoding::Utf16Le);
+        assert_eq!(decode_text(b"a\x00,\x00b\x00", None), ("a,b".to_string(), Encoding::Utf16Le));
+    }
+}
@@ -0,0 +1,1929 @@
+use super::aggregate::{AggregateFunction, AggregateOptions};
+use super::arithmetic::{Arithmetic, Floating};
+use super::array::Array2D;
+use super::bytecode::{Op, Program, Reg, Slot};
+use super::datasource::{DataSources, Member};
+use super::format::{format_value, is_date_code, Locale};
+use super::functions::CustomFunction;
+#[cfg(feature = "gpu")]
+use super::gpu::Offload;
+use super::kernel::{evaluate_rectangle, CellError, CellId, Formula, FunctionKind, Kernel, Numeric, NumericAttribute, Primitive, Value};
+use super::matcher::{MatchOptions, Pattern};
+use super::recorder::Event;
+use super::settings::CalcSettings;
+use super::snapshot::unchanged;
+use super::text::{proper, text_after, text_before, windows_1252_char, windows_1252_code, SplitError, TextSearch, MAX_TEXT_LENGTH};
+#[cfg(feature = "units")]
+use super::units;
+use super::units::convert_numeric;
+use super::warning::CalcWarning;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+
+/// EvalContext is handed to evaluation hooks for a single cell. Pre hooks may
+/// substitute a result or veto evaluation, post hooks see the computed result
+/// and may replace it.
+#[derive(Clone, Debug)]
+pub struct EvalContext<T: Arithmetic=f64> {
+    cell_id: CellId,
+    result: Option<Value<T>>,
+    vetoed: bool,
+}
+
+impl<T: Arithmetic> EvalContext<T> {
+    fn new(cell_id: CellId) -> Self {
+        Self{cell_id, result: None, vetoed: false}
+    }
+
+    pub fn cell_id(&self) -> CellId {
+        self.cell_id
+    }
+
+    /// Get the result of the cell. In pre hooks this is only set if an
+    /// earlier hook substituted a result.
+    pub fn result(&self) -> Option<&Value<T>> {
+        self.result.as_ref()
+    }
+
+    /// Substitute the result of the cell. When done in a pre hook the formula
+    /// is not evaluated at all.
+    pub fn set_result(&mut self, value: Value<T>) {
+        self.result = Some(value);
+    }
+
+    /// Refuse to evaluate the cell, making it evaluate to `error` instead.
+    pub fn veto(&mut self, error: CellError) {
+        self.vetoed = true;
+        self.result = Some(Value::Error(error));
+    }
+
+    pub fn is_vetoed(&self) -> bool {
+        self.vetoed
+    }
+}
+
+/// An evaluation hook, called with the cell being evaluated and its formula.
+pub type EvalHook<T> = Box<dyn Fn(&CellId, &Formula<T>, &mut EvalContext<T>)>;
+
+/// EvalHooks holds the pre and post evaluation hooks registered by an embedder.
+pub struct EvalHooks<T: Arithmetic=f64> {
+    pre: Vec<EvalHook<T>>,
+    post: Vec<EvalHook<T>>,
+}
+
+impl<T: Arithmetic> Default for EvalHooks<T> {
+    fn default() -> Self {
+        Self{pre: Vec::new(), post: Vec::new()}
+    }
+}
+
+impl<T: Arithmetic> EvalHooks<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a hook called before a formula cell is evaluated.
+    pub fn add_pre(&mut self, hook: impl Fn(&CellId, &Formula<T>, &mut EvalContext<T>) + 'static) {
+        self.pre.push(Box::new(hook));
+    }
+
+    /// Register a hook called after a formula cell is evaluated.
+    pub fn add_post(&mut self, hook: impl Fn(&CellId, &Formula<T>, &mut EvalContext<T>) + 'static) {
+        self.post.push(Box::new(hook));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pre.is_empty() && self.post.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.pre.clear();
+        self.post.clear();
+    }
+}
+
+/// Evaluator computes formulas against a kernel. Kernels use it to implement
+/// `Kernel::evaluate_cell`; references are resolved through the kernel so it
+/// stays in charge of caching and cycle detection.
+pub struct Evaluator<'a, K, T: Arithmetic=f64> {
+    kernel: &'a K,
+    hooks: Option<&'a EvalHooks<T>>,
+    sources: Option<&'a DataSources<T>>,
+    settings: CalcSettings,
+    warnings: RefCell<Vec<CalcWarning>>,
+    recording: Option<&'a RefCell<Vec<Event<T>>>>,
+    program: Option<&'a Program<T>>,
+    #[cfg(feature = "gpu")]
+    offload: Option<&'a Offload>,
+    depth: Cell<usize>,
+}
+
+impl<'a, K, T: Arithmetic> Evaluator<'a, K, T> {
+    pub fn new(kernel: &'a K) -> Self {
+        Self{
+            kernel,
+            hooks: None,
+            sources: None,
+            settings: CalcSettings::default(),
+            warnings: RefCell::new(Vec::new()),
+            recording: None,
+            program: None,
+            #[cfg(feature = "gpu")]
+            offload: None,
+            depth: Cell::new(0),
+        }
+    }
+
+    pub fn with_settings(mut self, settings: CalcSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    pub fn with_hooks(mut self, hooks: &'a EvalHooks<T>) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    pub fn with_sources(mut self, sources: &'a DataSources<T>) -> Self {
+        self.sources = Some(sources);
+        self
+    }
+
+    /// Record the events of evaluation into `recording`.
+    pub fn with_recording(mut self, recording: &'a RefCell<Vec<Event<T>>>) -> Self {
+        self.recording = Some(recording);
+        self
+    }
+
+    /// Evaluate the formula of the cell by running `program`, compiled from
+    /// it, unless recording.
+    pub fn with_program(mut self, program: &'a Program<T>) -> Self {
+        self.program = Some(program);
+        self
+    }
+
+    /// Offload large aggregations of dense ranges to the backend of
+    /// `offload`.
+    #[cfg(feature = "gpu")]
+    pub fn with_offload(mut self, offload: &'a Offload) -> Self {
+        self.offload = Some(offload);
+        self
+    }
+
+    /// Record an event at the depth of the operands of the formula being
+    /// evaluated.
+    fn record(&self, event: impl FnOnce(usize) -> Event<T>) {
+        if let Some(recording) = self.recording {
+            recording.borrow_mut().push(event(self.depth.get()));
+        }
+    }
+
+    /// Take the warnings found since the evaluator was created or last asked.
+    pub fn take_warnings(&self) -> Vec<CalcWarning> {
+        std::mem::take(&mut self.warnings.borrow_mut())
+    }
+
+    fn warn(&self, warning: CalcWarning) {
+        let mut warnings = self.warnings.borrow_mut();
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
+    }
+
+    /// Evaluate the formula of `cell_id`, running the registered hooks.
+    pub fn evaluate_cell<E>(&self, cell_id: CellId, formula: &Formula<T>) -> Result<Value<T>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        let hooks = match self.hooks {
+            Some(hooks) if !hooks.is_empty() => hooks,
+            _ => return self.evaluate_root(formula),
+        };
+
+        let mut context = EvalContext::new(cell_id);
+        for hook in &hooks.pre {
+            hook(&cell_id, formula, &mut context);
+            if context.vetoed {
+                break;
+            }
+        }
+        if context.result.is_none() {
+            context.result = Some(self.evaluate_root(formula)?);
+        }
+        for hook in &hooks.post {
+            hook(&cell_id, formula, &mut context);
+        }
+        Ok(context.result.unwrap_or(Value::Error(CellError::Value)))
+    }
+
+    /// Evaluate the formula of a cell, running its program if it has one.
+    fn evaluate_root<E>(&self, formula: &Formula<T>) -> Result<Value<T>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        if let (Some(program), None) = (self.program, self.recording) {
+            if let Some(value) = self.execute(program)? {
+                return Ok(value);
+            }
+        }
+        self.evaluate(formula)
+    }
+
+    /// Run a compiled formula, or get None if a function it compiled is
+    /// shadowed by a registered one, so the tree has to be evaluated.
+    pub fn execute<E>(&self, program: &Program<T>) -> Result<Option<Value<T>>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        if let Some(functions) = self.kernel.functions() {
+            if program.inlined().iter().any(|kind| functions.shadowing(*kind).is_some()) {
+                return Ok(None);
+            }
+        }
+        let mut registers = program.registers();
+        let result = self.run(program.ops(), &mut registers);
+        let value = result.map(|()| std::mem::take(&mut registers[program.result() as usize]).into_value());
+        program.restore(registers);
+        value.map(Some)
+    }
+
+    /// Run instructions on registers.
+    fn run<E>(&self, ops: &[Op<T>], registers: &mut [Slot<T>]) -> Result<(), E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        let take = |registers: &mut [Slot<T>], reg: Reg| std::mem::take(&mut registers[reg as usize]);
+        let value = Slot::into_value;
+        let mut pc = 0;
+        while let Some(op) = ops.get(pc) {
+            pc += 1;
+            let (dst, slot) = match op {
+                Op::Const{dst, slot} => (dst, slot.clone()),
+                Op::Load{dst, sheet, cell_id} => (dst, Slot::Value(match sheet {
+                    Some(sheet) => self.kernel.evaluate_sheet_cell(sheet, *cell_id)?,
+                    None => self.kernel.evaluate_cell(*cell_id)?,
+                })),
+                Op::LoadNumber{dst, sheet, cell_id} => {
+                    let value = match sheet {
+                        Some(sheet) => self.kernel.evaluate_sheet_cell(sheet, *cell_id)?,
+                        None => self.kernel.evaluate_cell(*cell_id)?,
+                    };
+                    (dst, match to_number(&value) {
+                        Ok(number) => Slot::Number(number),
+                        Err(e) => Slot::Error(e),
+                    })
+                },
+                Op::Tree{dst, formula} => (dst, Slot::Value(self.evaluate(formula)?)),
+                Op::Number{dst, src} => (dst, match to_number(&value(take(registers, *src))) {
+                    Ok(number) => Slot::Number(number),
+                    Err(e) => Slot::Error(e),
+                }),
+                Op::Comparable{dst, src} => (dst, match Comparable::of(value(take(registers, *src))) {
+                    Ok(comparable) => Slot::Comparable(comparable),
+                    Err(e) => Slot::Error(e),
+                }),
+                Op::ComparableCell{dst, cell_id} => (dst, match self.cell_text::<E>(*cell_id) {
+                    Some(text) => Slot::Comparable(Comparable::Text(text)),
+                    None => match Comparable::of(self.kernel.evaluate_cell(*cell_id)?) {
+                        Ok(comparable) => Slot::Comparable(comparable),
+                        Err(e) => Slot::Error(e),
+                    },
+                }),
+                Op::Text{dst, src} => (dst, match self.text_of(value(take(registers, *src))) {
+                    Ok(text) => Slot::Text(text),
+                    Err(e) => Slot::Error(e),
+                }),
+                Op::TextCell{dst, cell_id} => (dst, match self.cell_text::<E>(*cell_id) {
+                    Some(text) => Slot::Text(text),
+                    None => match self.text_of(self.kernel.evaluate_cell(*cell_id)?) {
+                        Ok(text) => Slot::Text(text),
+                        Err(e) => Slot::Error(e),
+                    },
+                }),
+                Op::Bail{src, dst, target} => {
+                    let Slot::Error(e) = registers[*src as usize] else { continue };
+                    pc = *target;
+                    (dst, Slot::Error(e))
+                },
+                Op::Arith{dst, op, lhs, rhs} => (dst, match (take(registers, *lhs), take(registers, *rhs)) {
+                    (Slot::Number(a), Slot::Number(b)) => match op.apply(a, b) {
+                        Ok(number) => Slot::Number(number),
+                        Err(e) => Slot::Error(e),
+                    },
+                    (_, Slot::Error(e)) => Slot::Error(e),
+                    _ => unreachable!("arithmetic reads numbers"),
+                }),
+                Op::Unary{dst, op, src} => (dst, match take(registers, *src) {
+                    Slot::Number(number) => Slot::Number(op.apply(number)),
+                    slot => slot,
+                }),
+                Op::Concat{dst, lhs, rhs} => (dst, match (take(registers, *lhs), take(registers, *rhs)) {
+                    (Slot::Text(lhs), Slot::Text(rhs)) => Slot::Value(Value::Primitive(Primitive::Text(lhs + &rhs))),
+                    (Slot::Error(e), _) | (_, Slot::Error(e)) => Slot::Error(e),
+                    _ => unreachable!("concatenation reads texts"),
+                }),
+                Op::Compare{dst, op, lhs, rhs} => (dst, match (take(registers, *lhs), take(registers, *rhs)) {
+                    (Slot::Comparable(a), Slot::Comparable(b)) => Slot::Value(Value::Primitive(Primitive::Bool(a.compare(&b, &self.settings).is_some_and(op)))),
+                    (_, Slot::Error(e)) => Slot::Error(e),
+                    _ => unreachable!("comparisons read comparables"),
+                }),
+                Op::JumpUnless{cond, target} => {
+                    if matches!(&registers[*cond as usize], Slot::Number(number) if number.value() == Floating::from_f64(0.0)) {
+                        pc = *target;
+                    }
+                    continue;
+                },
+                Op::Jump{target} => {
+                    pc = *target;
+                    continue;
+                },
+                Op::Move{dst, src} => (dst, take(registers, *src)),
+            };
+            registers[*dst as usize] = slot;
+        }
+        Ok(())
+    }
+
+    /// Evaluate a value, computing it if it is a formula.
+    pub fn evaluate_value<E>(&self, value: &Value<T>) -> Result<Value<T>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        match value {
+            Value::Formula(formula) => self.evaluate(formula),
+            Value::FormulaParseError(_) => Ok(Value::Error(CellError::Name)),
+            value => Ok(value.clone()),
+        }
+    }
+
+    /// Evaluate a formula to a single value.
+    pub fn evaluate<E>(&self, formula: &Formula<T>) -> Result<Value<T>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        let Some(recording) = self.recording else { return self.compute(formula) };
+        let depth = self.depth.get();
+        self.depth.set(depth + 1);
+        let result = self.compute(formula);
+        self.depth.set(depth);
+        if let Ok(value) = &result {
+            recording.borrow_mut().push(Event::Value{depth, formula: formula.clone(), value: value.clone()});
+        }
+        result
+    }
+
+    fn compute<E>(&self, formula: &Formula<T>) -> Result<Value<T>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        match formula {
+            Formula::NumberLit(number) => Ok(Value::Primitive(Primitive::Number(number.clone()))),
+            Formula::TextLit(text) => Ok(Value::Primitive(Primitive::Text(text.clone()))),
+            Formula::BoolLit(b) => Ok(Value::Primitive(Primitive::Bool(*b))),
+            Formula::CellRef(cell_id) => self.kernel.evaluate_cell(*cell_id),
+            // A range cannot be a single value; implicit intersection is not supported.
+            Formula::CellRange(..) => Ok(Value::Error(CellError::Value)),
+            Formula::Name(name) => match self.kernel.resolve_name(None, name) {
+                Some(formula) => self.evaluate(&formula),
+                None => Ok(Value::Error(CellError::Name)),
+            },
+            Formula::SheetRef(sheet, target) => match **target {
+                Formula::CellRef(cell_id) => self.kernel.evaluate_sheet_cell(sheet, cell_id),
+                Formula::Name(ref name) => match self.kernel.resolve_name(Some(sheet), name) {
+                    Some(formula) => self.evaluate(&formula),
+                    None => Ok(Value::Error(CellError::Name)),
+                },
+                _ => Ok(Value::Error(CellError::Value)),
+            },
+            Formula::Function{kind, arguments} => match self.kernel.functions().and_then(|functions| functions.shadowing(*kind)) {
+                Some(function) => self.custom(function, arguments),
+                None => self.function(*kind, arguments),
+            },
+            Formula::Custom{name, arguments} => match self.kernel.functions().and_then(|functions| functions.resolve(name)) {
+                Some(function) => self.custom(function, arguments),
+                None => Ok(Value::Error(CellError::Name)),
+            },
+            Formula::Add(lhs, rhs) => self.arithmetic(lhs, rhs, Arith::Add),
+            Formula::Sub(lhs, rhs) => self.arithmetic(lhs, rhs, Arith::Sub),
+            Formula::Mul(lhs, rhs) => self.arithmetic(lhs, rhs, Arith::Mul),
+            Formula::Div(lhs, rhs) => self.arithmetic(lhs, rhs, Arith::Div),
+            Formula::Pow(lhs, rhs) => self.arithmetic(lhs, rhs, Arith::Pow),
+            Formula::Neg(operand) => Ok(match self.number(operand)? {
+                Ok(number) => Value::Primitive(Primitive::Number(Unary::Neg.apply(number))),
+                Err(e) => Value::Error(e),
+            }),
+            Formula::Percent(operand) => Ok(match self.number(operand)? {
+                Ok(number) => Value::Primitive(Primitive::Number(Unary::Percent.apply(number))),
+                Err(e) => Value::Error(e),
+            }),
+            Formula::Concat(lhs, rhs) => Ok(match (self.display_text(lhs)?, self.display_text(rhs)?) {
+                (Ok(lhs), Ok(rhs)) => Value::Primitive(Primitive::Text(lhs + &rhs)),
+                (Err(e), _) | (_, Err(e)) => Value::Error(e),
+            }),
+            Formula::Cmp(lhs, rhs) => self.comparison(lhs, rhs, Ordering::is_eq),
+            Formula::Lt(lhs, rhs) => self.comparison(lhs, rhs, Ordering::is_lt),
+            Formula::Gr(lhs, rhs) => self.comparison(lhs, rhs, Ordering::is_gt),
+            Formula::Le(lhs, rhs) => self.comparison(lhs, rhs, Ordering::is_le),
+            Formula::Ge(lhs, rhs) => self.comparison(lhs, rhs, Ordering::is_ge),
+            Formula::Ne(lhs, rhs) => self.comparison(lhs, rhs, Ordering::is_ne),
+        }
+    }
+
+    fn arithmetic<E>(&self, lhs: &Value<T>, rhs: &Value<T>, op: Arith) -> Result<Value<T>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        let lhs = match self.number(lhs)? {
+            Ok(number) => number,
+            Err(e) => return Ok(Value::Error(e)),
+        };
+        let rhs = match self.number(rhs)? {
+            Ok(number) => number,
+            Err(e) => return Ok(Value::Error(e)),
+        };
+        Ok(match op.apply(lhs, rhs) {
+            Ok(number) => Value::Primitive(Primitive::Number(number)),
+            Err(e) => Value::Error(e),
+        })
+    }
+
+    fn comparison<E>(&self, lhs: &Value<T>, rhs: &Value<T>, op: fn(Ordering) -> bool) -> Result<Value<T>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        let lhs = match self.comparable(lhs)? {
+            Ok(comparable) => comparable,
+            Err(e) => return Ok(Value::Error(e)),
+        };
+        let rhs = match self.comparable(rhs)? {
+            Ok(comparable) => comparable,
+            Err(e) => return Ok(Value::Error(e)),
+        };
+        let ordering = lhs.compare(&rhs, &self.settings);
+        Ok(Value::Primitive(Primitive::Bool(ordering.is_some_and(op))))
+    }
+
+    /// Evaluate an operand of a comparison.
+    fn comparable<E>(&self, value: &Value<T>) -> Result<Result<Comparable<T>, CellError>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        if let Value::Formula(Formula::CellRef(cell_id)) = value {
+            if let Some(text) = self.cell_text::<E>(*cell_id) {
+                return Ok(Ok(Comparable::Text(text)));
+            }
+        }
+        Ok(Comparable::of(self.evaluate_value(value)?))
+    }
+
+    /// Get the text of a text cell, which operators take as is rather than
+    /// as the cell evaluates.
+    fn cell_text<E>(&self, cell_id: CellId) -> Option<String>
+    where K: Kernel<E, T>, E: std::error::Error {
+        let cell = self.kernel.get_cell(cell_id)?;
+        matches!(cell.value(), Value::Raw).then(|| cell.text().to_string())
+    }
+
+    /// Evaluate a value and coerce it to a number.
+    fn number<E>(&self, value: &Value<T>) -> Result<Result<Numeric<T>, CellError>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        Ok(to_number(&self.evaluate_value(value)?))
+    }
+
+    /// Evaluate function arguments to a flat list of values, expanding ranges.
+    fn flatten<E>(&self, arguments: &[Value<T>]) -> Result<Vec<Value<T>>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        let mut values = Vec::new();
+        for argument in arguments {
+            let expanded = match argument {
+                Value::Formula(formula) => self.expand(None, formula)?,
+                _ => None,
+            };
+            match expanded {
+                Some(range) => values.extend(range.into_values()),
+                None => match self.evaluate_value(argument)? {
+                    Value::Array(array) => values.extend(array.into_values()),
+                    value => values.push(value),
+                },
+            }
+        }
+        Ok(values)
+    }
+
+    /// Evaluate every cell of a formula referring to a range, or None if the
+    /// formula is not a range.
+    fn expand<E>(&self, sheet: Option<&str>, formula: &Formula<T>) -> Result<Option<Array2D<Value<T>>>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        match formula {
+            Formula::CellRange(start, end) => {
+                let values = evaluate_rectangle(*start, *end, |cell_id| self.cell_value(sheet, cell_id))?;
+                self.record(|depth| Event::Range{
+                    depth,
+                    sheet: sheet.map(str::to_string),
+                    start: *start,
+                    end: *end,
+                    values: values.clone(),
+                });
+                Ok(Some(values))
+            },
+            Formula::SheetRef(sheet, target) => self.expand(Some(sheet), target),
+            Formula::Name(name) => match self.kernel.resolve_name(sheet, name) {
+                Some(formula) => self.expand(None, &formula),
+                None => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /// Evaluate a cell, optionally of another sheet, with the text of text
+    /// cells as text values.
+    fn cell_value<E>(&self, sheet: Option<&str>, cell_id: CellId) -> Result<Value<T>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        let cell = match sheet {
+            Some(sheet) => self.kernel.get_sheet_cell(sheet, cell_id),
+            None => self.kernel.get_cell(cell_id),
+        };
+        match cell {
+            Some(cell) if matches!(cell.value(), Value::Raw) => Ok(Value::Primitive(Primitive::Text(cell.text().to_string()))),
+            _ => match sheet {
+                Some(sheet) => self.kernel.evaluate_sheet_cell(sheet, cell_id),
+                None => self.kernel.evaluate_cell(cell_id),
+            },
+        }
+    }
+
+    /// Evaluate function arguments to numbers, skipping values in ranges
+    /// which are not numbers as aggregate functions do.
+    fn numbers<E>(&self, arguments: &[Value<T>]) -> Result<Result<Vec<T>, CellError>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        let mut numbers = Vec::new();
+        for value in self.flatten(arguments)? {
+            match value {
+                Value::Primitive(Primitive::Number(number)) => numbers.push(number.value()),
+                Value::Error(e) => return Ok(Err(e)),
+                _ => {},
+            }
+        }
+        Ok(Ok(numbers))
+    }
+
+    /// Resolve an argument to text. Text lives in raw cells, so references
+    /// to those cells yield their raw contents.
+    fn text<E>(&self, argument: &Value<T>) -> Result<Result<String, CellError>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        if let Value::Formula(Formula::CellRef(cell_id)) = argument {
+            if let Some(cell) = self.kernel.get_cell(*cell_id) {
+                if let Value::Raw = cell.value() {
+                    return Ok(Ok(cell.text().trim().to_string()));
+                }
+            }
+        }
+        Ok(match self.evaluate_value(argument)? {
+            Value::Primitive(Primitive::Text(text)) => Ok(text),
+            Value::Error(e) => Err(e),
+            _ => Err(CellError::Value),
+        })
+    }
+
+    /// Evaluate a value and coerce it to text the way concatenation does:
+    /// text as is, blanks as empty text and other values as displayed in
+    /// the `General` format.
+    fn display_text<E>(&self, value: &Value<T>) -> Result<Result<String, CellError>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        if let Value::Formula(Formula::CellRef(cell_id)) = value {
+            if let Some(text) = self.cell_text::<E>(*cell_id) {
+                return Ok(Ok(text));
+            }
+        }
+        Ok(self.text_of(self.evaluate_value(value)?))
+    }
+
+    /// Convert a value to the text concatenation joins.
+    fn text_of(&self, value: Value<T>) -> Result<String, CellError> {
+        match value {
+            Value::Primitive(Primitive::Text(text)) => Ok(text),
+            Value::Error(e) => Err(e),
+            Value::FormulaParseError(_) => Err(CellError::Name),
+            value => {
+                let text = format_value(&value, "General", &Locale::default());
+                if let Value::Primitive(Primitive::Number(number)) = &value {
+                    let plain = number.attr().is_none();
+                    let number = number.value().to_f64();
+                    if plain && number.is_finite() && text.parse::<f64>() != Ok(number) {
+                        self.warn(CalcWarning::NumberToText{number, text: text.clone()});
+                    }
+                }
+                Ok(text)
+            },
+        }
+    }
+
+    fn texts<E>(&self, arguments: &[Value<T>]) -> Result<Result<Vec<String>, CellError>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        let mut texts = Vec::new();
+        for argument in arguments {
+            match self.text(argument)? {
+                Ok(text) => texts.push(text),
+                Err(e) => return Ok(Err(e)),
+            }
+        }
+        Ok(Ok(texts))
+    }
+
+    /// Call a registered function with its arguments evaluated, ranges as
+    /// arrays.
+    fn custom<E>(&self, function: &CustomFunction<T>, arguments: &[Value<T>]) -> Result<Value<T>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        let mut values = Vec::with_capacity(arguments.len());
+        for argument in arguments {
+            values.push(match argument {
+                Value::Formula(Formula::CellRef(cell_id)) => self.cell_value(None, *cell_id)?,
+                Value::Formula(formula) => match self.expand(None, formula)? {
+                    Some(range) => Value::Array(range),
+                    None => self.evaluate_value(argument)?,
+                },
+                argument => self.evaluate_value(argument)?,
+            });
+        }
+        Ok(function(&values))
+    }
+
+    fn function<E>(&self, kind: FunctionKind, arguments: &[Value<T>]) -> Result<Value<T>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        if let Some(replacement) = kind.replacement() {
+            self.warn(CalcWarning::Deprecated{function: kind, replacement});
+        }
+        if let Some(result) = self.indexed(kind, arguments)? {
+            return Ok(result.unwrap_or_else(Value::Error));
+        }
+        if let Some(result) = self.vectorized(kind, arguments)? {
+            return Ok(result.unwrap_or_else(Value::Error));
+        }
+        let number = |number: T| Value::Primitive(Primitive::Number(Numeric::new(number, None)));
+        let zero: T = Floating::from_f64(0.0);
+        let result = match kind {
+            FunctionKind::Sum => self.numbers(arguments)?
+                .map(|numbers| number(numbers.into_iter().fold(zero, |sum, x| sum + x))),
+            FunctionKind::SumProduct => self.sum_product(arguments)?,
+            FunctionKind::Average => self.numbers(arguments)?.and_then(|numbers| {
+                if numbers.is_empty() {
+                    return Err(CellError::Div0);
+                }
+                let count: T = Floating::from_f64(numbers.len() as f64);
+                Ok(number(numbers.into_iter().fold(zero, |sum, x| sum + x) / count))
+            }),
+            // Unlike other aggregates, COUNT skips errors.
+            FunctionKind::Count => {
+                let values = self.flatten(arguments)?;
+                let count = values.iter().filter(|value| matches!(value, Value::Primitive(Primitive::Number(_)))).count();
+                Ok(number(Floating::from_f64(count as f64)))
+            },
+            FunctionKind::Prod => self.numbers(arguments)?
+                .map(|numbers| number(numbers.into_iter().fold(Floating::from_f64(1.0), |product, x| product * x))),
+            FunctionKind::Sdev => self.numbers(arguments)?.and_then(|numbers| {
+                if numbers.len() < 2 {
+                    return Err(CellError::Div0);
+                }
+                let count: T = Floating::from_f64(numbers.len() as f64);
+                let mean = numbers.iter().fold(zero, |sum, x| sum + *x) / count;
+                let squares = numbers.iter().fold(zero, |sum, x| sum + (*x - mean) * (*x - mean));
+                Ok(number((squares / (count - Floating::from_f64(1.0))).sqrt()))
+            }),
+            FunctionKind::Sqrt => match arguments {
+                [argument] => self.number(argument)?.and_then(|x| {
+                    if x.value() < zero {
+                        Err(CellError::Num)
+                    } else {
+                        Ok(number(x.value().sqrt()))
+                    }
+                }),
+                _ => Err(CellError::Value),
+            },
+            FunctionKind::If => match arguments {
+                [condition, rest @ ..] if rest.len() <= 2 => match self.number(condition)? {
+                    Ok(condition) => {
+                        let taken = condition.value() != zero;
+                        self.record(|depth| Event::Branch{depth, taken});
+                        match rest.get(if taken { 0 } else { 1 }) {
+                            Some(value) => return self.evaluate_value(value),
+                            None => Ok(Value::Primitive(Primitive::Bool(taken))),
+                        }
+                    },
+                    Err(e) => Err(e),
+                },
+                _ => Err(CellError::Value),
+            },
+            FunctionKind::Offset => match arguments {
+                [Value::Formula(Formula::CellRef(origin)), rows, cols] => {
+                    match (self.number(rows)?, self.number(cols)?) {
+                        (Ok(rows), Ok(cols)) => match offset(*origin, rows.value(), cols.value()) {
+                            Some(cell_id) => return self.kernel.evaluate_cell(cell_id),
+                            None => Err(CellError::Ref),
+                        },
+                        (Err(e), _) | (_, Err(e)) => Err(e),
+                    }
+                },
+                _ => Err(CellError::Value),
+            },
+            FunctionKind::GetPivotData => match arguments {
+                [measure, source, members @ ..] => {
+                    match (self.text(measure)?, self.text(source)?, self.texts(members)?) {
+                        (Ok(measure), Ok(source), Ok(members)) => {
+                            Member::pairs(&members)
+                                .ok_or(CellError::Value)
+                                .and_then(|members| self.query(&source, &measure, &members))
+                        },
+                        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => Err(e),
+                    }
+                },
+                _ => Err(CellError::Value),
+            },
+            FunctionKind::CubeValue => match arguments {
+                [connection, measure, members @ ..] => {
+                    match (self.text(connection)?, self.text(measure)?, self.texts(members)?) {
+                        (Ok(connection), Ok(measure), Ok(members)) => {
+                            members.iter()
+                                .map(|member| Member::parse(member))
+                                .collect::<Result<Vec<_>, _>>()
+                                .map_err(|_| CellError::Value)
+                                .and_then(|members| self.query(&connection, &measure, &members))
+                        },
+                        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => Err(e),
+                    }
+                },
+                _ => Err(CellError::Value),
+            },
+            FunctionKind::Text | FunctionKind::TextBefore | FunctionKind::TextAfter | FunctionKind::Proper
+                | FunctionKind::Exact | FunctionKind::Rept | FunctionKind::Char | FunctionKind::Code
+                | FunctionKind::Unichar | FunctionKind::Unicode | FunctionKind::Search | FunctionKind::Find
+                | FunctionKind::Replace => self.text_function(kind, arguments)?,
+            FunctionKind::Choose | FunctionKind::ChooseCols | FunctionKind::ChooseRows | FunctionKind::Take
+                | FunctionKind::Drop | FunctionKind::HStack | FunctionKind::VStack => self.array_function(kind, arguments)?,
+            FunctionKind::Aggregate => self.aggregate(arguments)?,
+            FunctionKind::Match | FunctionKind::VLookup | FunctionKind::CountIf => self.lookup_function(kind, arguments)?,
+            // With the `units` feature a number with a unit converts to
+            // another unit given alone.
+            FunctionKind::Convert => match arguments {
+                [value, from @ .., to] if from.len() <= 1 => {
+                    let from = match from.first() {
+                        Some(from) => match self.display_text(from)? {
+                            Ok(from) => Some(from),
+                            Err(e) => return Ok(Value::Error(e)),
+                        },
+                        None => None,
+                    };
+                    match (self.number(value)?, self.display_text(to)?) {
+                        (Ok(x), Ok(to)) => convert_numeric(&x, from.as_deref(), &to).map(|x| Value::Primitive(Primitive::Number(x))),
+                        (Err(e), _) | (_, Err(e)) => Err(e),
+                    }
+                },
+                _ => Err(CellError::Value),
+            },
+            FunctionKind::ChangedSince => self.changed_since(arguments)?,
+        };
+        Ok(result.unwrap_or_else(Value::Error))
+    }
+
+    /// Compare the cells of a range with the values they had in a data
+    /// snapshot, giving an array which is TRUE for every cell which
+    /// changed, or `#N/A` if the kernel has no snapshot with the ID.
+    fn changed_since<E>(&self, arguments: &[Value<T>]) -> Result<Result<Value<T>, CellError>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        let [range, snapshot] = arguments else { return Ok(Err(CellError::Value)) };
+        let (sheet, first, second) = match range {
+            Value::Formula(Formula::CellRef(cell_id)) => (None, *cell_id, *cell_id),
+            Value::Formula(Formula::CellRange(first, second)) => (None, *first, *second),
+            Value::Formula(Formula::SheetRef(sheet, target)) => match &**target {
+                Formula::CellRef(cell_id) => (Some(sheet.as_str()), *cell_id, *cell_id),
+                Formula::CellRange(first, second) => (Some(sheet.as_str()), *first, *second),
+                _ => return Ok(Err(CellError::Value)),
+            },
+            _ => return Ok(Err(CellError::Value)),
+        };
+        let snapshot = match self.text(snapshot)? {
+            Ok(snapshot) => snapshot,
+            Err(e) => return Ok(Err(e)),
+        };
+        let (top, bottom) = (first.row().min(second.row()), first.row().max(second.row()));
+        let (left, right) = (first.col().min(second.col()), first.col().max(second.col()));
+        let mut changed = Vec::new();
+        for row in top..=bottom {
+            for col in left..=right {
+                let cell_id = CellId::new(row, col);
+                let Some(before) = self.kernel.snapshot_value(&snapshot, sheet, cell_id) else { return Ok(Err(CellError::NA)) };
+                let now = self.cell_value(sheet, cell_id)?;
+                changed.push(Value::Primitive(Primitive::Bool(!unchanged(&before, &now, &self.settings))));
+            }
+        }
+        let (rows, cols) = ((bottom - top + 1) as usize, (right - left + 1) as usize);
+        Ok(Ok(Value::Array(Array2D::from_vec(rows, cols, changed).expect("a value for every cell"))))
+    }
+
+    /// Multiply the corresponding values of arrays of the same size, counting
+    /// values which are not numbers as zero, and add the products.
+    fn sum_product<E>(&self, arguments: &[Value<T>]) -> Result<Result<Value<T>, CellError>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        let mut arrays = Vec::with_capacity(arguments.len());
+        for argument in arguments {
+            match self.array(argument)? {
+                Ok(array) => arrays.push(array),
+                Err(e) => return Ok(Err(e)),
+            }
+        }
+        let Some(first) = arrays.first() else { return Ok(Err(CellError::Value)) };
+        if arrays.iter().any(|array| array.rows() != first.rows() || array.cols() != first.cols()) {
+            return Ok(Err(CellError::Value));
+        }
+        let arrays = arrays.iter().map(|array| array.values()).collect::<Vec<_>>();
+        Ok(sum_of_products(&arrays, arrays[0].len(), |array, index| &array[index])
+            .map(|sum| Value::Primitive(Primitive::Number(Numeric::new(sum, None)))))
+    }
+
+    /// Get the columns of a range argument from the kernel, or None if the
+    /// argument is not a range or the kernel does not store it densely.
+    fn dense<E>(&self, argument: &Value<T>) -> Option<Vec<Cow<'a, [Value<T>]>>>
+    where K: Kernel<E, T>, E: std::error::Error {
+        let (sheet, start, end) = range_argument(argument)?;
+        let (top, bottom) = (start.row().min(end.row()), start.row().max(end.row()));
+        (start.col().min(end.col())..=start.col().max(end.col()))
+            .map(|col| self.kernel.column_slice(sheet, col, top, bottom))
+            .collect()
+    }
+
+    /// Look a key up with `MATCH` or `VLOOKUP` through the index the kernel
+    /// keeps of the key column of the range, or get None to compare the key
+    /// with the cells one by one, as when the kernel keeps no index, the
+    /// index cannot answer the lookup or an argument is an error. Recorded
+    /// evaluations always compare cell by cell.
+    fn indexed<E>(&self, kind: FunctionKind, arguments: &[Value<T>]) -> Result<Option<Result<Value<T>, CellError>>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        if !matches!(kind, FunctionKind::Match | FunctionKind::VLookup) || self.recording.is_some() {
+            return Ok(None);
+        }
+        let (lookup, range, rest) = match (kind, arguments) {
+            (FunctionKind::Match, [lookup, range, rest @ ..]) if rest.len() <= 1 => (lookup, range, rest),
+            (FunctionKind::VLookup, [lookup, range, _, rest @ ..]) if rest.len() <= 1 => (lookup, range, rest),
+            _ => return Ok(None),
+        };
+        let Some((sheet, start, end)) = range_argument(range) else { return Ok(None) };
+        let (top, bottom, left) = (start.row().min(end.row()), start.row().max(end.row()), start.col().min(end.col()));
+        if kind == FunctionKind::Match && start.col() != end.col() {
+            return Ok(None);
+        }
+        let Some(index) = self.kernel.lookup_index(sheet, left, top, bottom) else { return Ok(None) };
+        let match_type = match (kind, rest.first()) {
+            (_, None) => Ok(1),
+            (FunctionKind::Match, Some(match_type)) => self.integer(match_type)?,
+            (_, Some(approximate)) => self.number(approximate)?.map(|approximate| (approximate.value() != Floating::from_f64(0.0)) as i64),
+        };
+        let (Ok(lookup), Ok(match_type)) = (self.comparable(lookup)?, match_type) else { return Ok(None) };
+        let Some(position) = index.position(&lookup, match_type, &self.settings) else { return Ok(None) };
+        if kind == FunctionKind::Match {
+            let number = |number: usize| Value::Primitive(Primitive::Number(Numeric::new(Floating::from_f64(number as f64), None)));
+            return Ok(Some(position.map(|row| number(row + 1)).ok_or(CellError::NA)));
+        }
+        let Ok(col) = self.integer(&arguments[2])? else { return Ok(None) };
+        if col < 1 {
+            return Ok(Some(Err(CellError::Value)));
+        }
+        match u32::try_from(col - 1) {
+            Ok(col) if col <= start.col().abs_diff(end.col()) => match position {
+                Some(row) => Ok(Some(Ok(self.cell_value(sheet, CellId::new(top + row as u32, left + col))?))),
+                None => Ok(Some(Err(CellError::NA))),
+            },
+            _ => Ok(Some(Err(CellError::Ref))),
+        }
+    }
+
+    /// Compute a function over whole column slices of its range arguments
+    /// instead of cell by cell, or get None to fall back to evaluating its
+    /// cells one by one, as when the kernel stores a range sparsely.
+    /// Recorded evaluations always take the cell by cell path, whose range
+    /// events the recording holds.
+    fn vectorized<E>(&self, kind: FunctionKind, arguments: &[Value<T>]) -> Result<Option<Result<Value<T>, CellError>>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        if !kind.is_vectorized() || self.recording.is_some() {
+            return Ok(None);
+        }
+        let number = |number: T| Value::Primitive(Primitive::Number(Numeric::new(number, None)));
+        let zero: T = Floating::from_f64(0.0);
+        let ranges = |arguments: &[Value<T>]| arguments.iter().map(|argument| self.dense::<E>(argument)).collect::<Option<Vec<_>>>();
+        Ok(match (kind, arguments) {
+            (FunctionKind::Sum, arguments) => {
+                let Some(ranges) = ranges(arguments) else { return Ok(None) };
+                #[cfg(feature = "gpu")]
+                if let Some(sum) = self.offload.and_then(|offload| offload.sum(&ranges)) {
+                    return Ok(Some(sum.map(number)));
+                }
+                let mut sum = zero;
+                for columns in &ranges {
+                    for row in 0..columns[0].len() {
+                        for column in columns {
+                            match &column[row] {
+                                Value::Primitive(Primitive::Number(x)) => sum += x.value(),
+                                Value::Error(e) => return Ok(Some(Err(*e))),
+                                _ => {},
+                            }
+                        }
+                    }
+                }
+                Some(Ok(number(sum)))
+            },
+            (FunctionKind::SumProduct, arguments) if !arguments.is_empty() => {
+                let Some(ranges) = ranges(arguments) else { return Ok(None) };
+                let (rows, cols) = (ranges[0][0].len(), ranges[0].len());
+                if ranges.iter().any(|columns| columns.len() != cols || columns[0].len() != rows) {
+                    return Ok(None);
+                }
+                #[cfg(feature = "gpu")]
+                if let Some(sum) = self.offload.and_then(|offload| offload.sum_of_products(&ranges, rows, cols)) {
+                    return Ok(Some(sum.map(number)));
+                }
+                // Index the values row by row, as the cell by cell path does.
+                Some(sum_of_products(&ranges, rows * cols, |columns, index| &columns[index % cols][index / cols]).map(number))
+            },
+            (FunctionKind::CountIf, [range, criterion]) => {
+                let Some(columns) = self.dense::<E>(range) else { return Ok(None) };
+                match self.comparable(criterion)? {
+                    Ok(criterion) => {
+                        let criterion = Criterion::parse(criterion);
+                        let count = (0..columns[0].len())
+                            .flat_map(|row| columns.iter().map(move |column| &column[row]))
+                            .filter_map(|value| Comparable::of(value.clone()).ok())
+                            .filter(|value| criterion.matches(value, &self.settings))
+                            .count();
+                        Some(Ok(number(Floating::from_f64(count as f64))))
+                    },
+                    Err(e) => Some(Err(e)),
+                }
+            },
+            // Only exact lookups; approximate ones take the cell by cell path.
+            (FunctionKind::VLookup, [lookup, table, col, approximate]) => {
+                let Some(columns) = self.dense::<E>(table) else { return Ok(None) };
+                let approximate = self.number(approximate)?.map(|approximate| approximate.value() != zero);
+                if approximate != Ok(false) {
+                    return Ok(None);
+                }
+                match (self.comparable(lookup)?, self.integer(col)?) {
+                    (Ok(_), Ok(col)) if col < 1 => Some(Err(CellError::Value)),
+                    (Ok(lookup), Ok(col)) => match usize::try_from(col - 1) {
+                        Ok(col) if col < columns.len() => Some(self.position(&lookup, &columns[0], 0)
+                            .map(|row| columns[col][row].clone())
+                            .ok_or(CellError::NA)),
+                        _ => Some(Err(CellError::Ref)),
+                    },
+                    (Err(e), _) | (_, Err(e)) => Some(Err(e)),
+                }
+            },
+            _ => None,
+        })
+    }
+
+    /// Evaluate an argument to an array: ranges to their cells, arrays as
+    /// they are and single values to one by one arrays.
+    fn array<E>(&self, argument: &Value<T>) -> Result<Result<Array2D<Value<T>>, CellError>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        if let Value::Formula(formula) = argument {
+            if let Some(range) = self.expand(None, formula)? {
+                return Ok(Ok(range));
+            }
+        }
+        let value = match argument {
+            Value::Formula(Formula::CellRef(cell_id)) => self.cell_value(None, *cell_id)?,
+            argument => self.evaluate_value(argument)?,
+        };
+        Ok(match value {
+            Value::Array(array) => Ok(array),
+            Value::Error(e) => Err(e),
+            value => Ok(Array2D::from_fn(1, 1, |_, _| value.clone())),
+        })
+    }
+
+    fn integers<E>(&self, arguments: &[Value<T>]) -> Result<Result<Vec<i64>, CellError>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        let mut integers = Vec::new();
+        for argument in arguments {
+            match self.integer(argument)? {
+                Ok(integer) => integers.push(integer),
+                Err(e) => return Ok(Err(e)),
+            }
+        }
+        Ok(Ok(integers))
+    }
+
+    fn array_function<E>(&self, kind: FunctionKind, arguments: &[Value<T>]) -> Result<Result<Value<T>, CellError>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        Ok(match (kind, arguments) {
+            (FunctionKind::Choose, [index, choices @ ..]) if !choices.is_empty() => match self.integer(index)? {
+                Ok(index) => match usize::try_from(index).ok().and_then(|index| choices.get(index.wrapping_sub(1))) {
+                    Some(choice) => self.array(choice)?.map(spill),
+                    None => Err(CellError::Value),
+                },
+                Err(e) => Err(e),
+            },
+            (FunctionKind::ChooseCols | FunctionKind::ChooseRows, [array, indexes @ ..]) if !indexes.is_empty() => {
+                match (self.array(array)?, self.integers(indexes)?) {
+                    (Ok(array), Ok(indexes)) => match kind {
+                        FunctionKind::ChooseRows => choose_rows(&array, &indexes),
+                        _ => choose_rows(&transpose(array), &indexes).map(transpose),
+                    }.map(spill),
+                    (Err(e), _) | (_, Err(e)) => Err(e),
+                }
+            },
+            (FunctionKind::Take | FunctionKind::Drop, [array, counts @ ..]) if (1..=2).contains(&counts.len()) => {
+                match (self.array(array)?, self.integers(counts)?) {
+                    (Ok(array), Ok(counts)) => {
+                        let rows = keep(array.rows(), counts[0], kind == FunctionKind::Take);
+                        let cols = match counts.get(1) {
+                            Some(count) => keep(array.cols(), *count, kind == FunctionKind::Take),
+                            None => Some(0..array.cols()),
+                        };
+                        match (rows, cols) {
+                            (Some(rows), Some(cols)) if !rows.is_empty() && !cols.is_empty() => {
+                                Ok(spill(Array2D::from_fn(rows.len(), cols.len(), |row, col| {
+                                    array.get(rows.start + row, cols.start + col).cloned().expect("kept part is inside the array")
+                                })))
+                            },
+                            _ => Err(CellError::Calc),
+                        }
+                    },
+                    (Err(e), _) | (_, Err(e)) => Err(e),
+                }
+            },
+            // Stacking side by side is stacking the transposed arrays.
+            (FunctionKind::VStack | FunctionKind::HStack, arguments) if !arguments.is_empty() => {
+                let orient = |array| match kind {
+                    FunctionKind::HStack => transpose(array),
+                    _ => array,
+                };
+                let mut arrays = Vec::new();
+                for argument in arguments {
+                    match self.array(argument)? {
+                        Ok(array) => arrays.push(orient(array)),
+                        Err(e) => return Ok(Err(e)),
+                    }
+                }
+                Ok(spill(orient(vstack(arrays))))
+            },
+            _ => Err(CellError::Value),
+        })
+    }
+
+    fn lookup_function<E>(&self, kind: FunctionKind, arguments: &[Value<T>]) -> Result<Result<Value<T>, CellError>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        let number = |number: usize| Value::Primitive(Primitive::Number(Numeric::new(Floating::from_f64(number as f64), None)));
+        Ok(match (kind, arguments) {
+            (FunctionKind::Match, [lookup, array, rest @ ..]) if rest.len() <= 1 => {
+                let match_type = match rest.first() {
+                    Some(match_type) => self.integer(match_type)?,
+                    None => Ok(1),
+                };
+                match (self.comparable(lookup)?, self.array(array)?, match_type) {
+                    (Ok(lookup), Ok(array), Ok(match_type)) if array.rows() == 1 || array.cols() == 1 => {
+                        self.position(&lookup, array.values(), match_type).map(|index| number(index + 1)).ok_or(CellError::NA)
+                    },
+                    (Ok(_), Ok(_), Ok(_)) => Err(CellError::NA),
+                    (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => Err(e),
+                }
+            },
+            (FunctionKind::VLookup, [lookup, table, col, rest @ ..]) if rest.len() <= 1 => {
+                let approximate = match rest.first() {
+                    Some(approximate) => self.number(approximate)?.map(|approximate| approximate.value() != Floating::from_f64(0.0)),
+                    None => Ok(true),
+                };
+                match (self.comparable(lookup)?, self.array(table)?, self.integer(col)?, approximate) {
+                    (Ok(_), Ok(_), Ok(col), Ok(_)) if col < 1 => Err(CellError::Value),
+                    (Ok(lookup), Ok(table), Ok(col), Ok(approximate)) => match usize::try_from(col - 1) {
+                        Ok(col) if col < table.cols() => {
+                            let keys = table.iter_rows().map(|row| row[0].clone()).collect::<Vec<_>>();
+                            self.position(&lookup, &keys, if approximate { 1 } else { 0 })
+                                .and_then(|row| table.get(row, col).cloned())
+                                .ok_or(CellError::NA)
+                        },
+                        _ => Err(CellError::Ref),
+                    },
+                    (Err(e), _, _, _) | (_, Err(e), _, _) | (_, _, Err(e), _) | (_, _, _, Err(e)) => Err(e),
+                }
+            },
+            (FunctionKind::CountIf, [range, criterion]) => match (self.array(range)?, self.comparable(criterion)?) {
+                (Ok(range), Ok(criterion)) => {
+                    let criterion = Criterion::parse(criterion);
+                    let count = range.into_values().into_iter()
+                        .filter_map(|value| Comparable::of(value).ok())
+                        .filter(|value| criterion.matches(value, &self.settings))
+                        .count();
+                    Ok(number(count))
+                },
+                (Err(e), _) | (_, Err(e)) => Err(e),
+            },
+            _ => Err(CellError::Value),
+        })
+    }
+
+    /// Find the position of a value in a row or column the way `MATCH` does.
+    /// Match type 0 finds an equal value, matching text with wildcards. Type
+    /// 1 finds the largest value not above it in ascending values, and -1
+    /// the smallest not below it in descending values.
+    fn position(&self, lookup: &Comparable<T>, values: &[Value<T>], match_type: i64) -> Option<usize> {
+        let pattern = match lookup {
+            Comparable::Text(text) if match_type == 0 => Some(Pattern::new(text, MatchOptions::default())),
+            _ => None,
+        };
+        let mut found = None;
+        for (index, value) in values.iter().enumerate() {
+            let value = match Comparable::of(value.clone()) {
+                Ok(Comparable::Empty) | Err(_) => continue,
+                Ok(value) if value.rank() != lookup.rank() => continue,
+                Ok(value) => value,
+            };
+            let ordering = match (&pattern, &value) {
+                (Some(pattern), Comparable::Text(text)) => if pattern.is_match(text) { Some(Ordering::Equal) } else { None },
+                _ => value.compare(lookup, &self.settings),
+            };
+            match (match_type.signum(), ordering) {
+                (0, Some(Ordering::Equal)) => return Some(index),
+                (0, _) => {},
+                (1, Some(Ordering::Less | Ordering::Equal)) | (-1, Some(Ordering::Greater | Ordering::Equal)) => found = Some(index),
+                (_, Some(_)) => break,
+                (_, None) => {},
+            }
+        }
+        found
+    }
+
+    fn aggregate<E>(&self, arguments: &[Value<T>]) -> Result<Result<Value<T>, CellError>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        let (function, options, references) = match arguments {
+            [function, options, references @ ..] if !references.is_empty() => (function, options, references),
+            _ => return Ok(Err(CellError::Value)),
+        };
+        let (function, options) = match (self.integer(function)?, self.integer(options)?) {
+            (Ok(function), Ok(options)) => (AggregateFunction::from_number(function), AggregateOptions::from_number(options)),
+            (Err(e), _) | (_, Err(e)) => return Ok(Err(e)),
+        };
+        let (function, options) = match (function, options) {
+            (Some(function), Some(options)) => (function, options),
+            _ => return Ok(Err(CellError::Value)),
+        };
+        let (references, k) = match (function.takes_k(), references) {
+            (true, [array, k]) => match self.number(k)? {
+                Ok(k) => (std::slice::from_ref(array), Some(k.value())),
+                Err(e) => return Ok(Err(e)),
+            },
+            (true, _) => return Ok(Err(CellError::Value)),
+            (false, references) => (references, None),
+        };
+        let mut values = Vec::new();
+        for reference in references {
+            let added = match reference {
+                Value::Formula(formula) => self.aggregated(None, formula, &options, &mut values)?,
+                value => keep_aggregated(value.clone(), &options, &mut values),
+            };
+            if let Err(e) = added {
+                return Ok(Err(e));
+            }
+        }
+        Ok(function.apply(&values, k).map(|result| Value::Primitive(Primitive::Number(Numeric::new(result, None)))))
+    }
+
+    /// Collect the values of a reference `AGGREGATE` does not skip.
+    fn aggregated<E>(&self, sheet: Option<&str>, formula: &Formula<T>, options: &AggregateOptions, values: &mut Vec<Value<T>>) -> Result<Result<(), CellError>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        let cells = match formula {
+            Formula::CellRef(cell_id) => CellId::range(*cell_id, *cell_id),
+            Formula::CellRange(start, end) => CellId::range(*start, *end),
+            Formula::SheetRef(sheet, target) => return self.aggregated(Some(sheet), target, options, values),
+            Formula::Name(name) => match self.kernel.resolve_name(sheet, name) {
+                Some(formula) => return self.aggregated(None, &formula, options, values),
+                None => return Ok(keep_aggregated(Value::Error(CellError::Name), options, values)),
+            },
+            formula => {
+                let added = match self.evaluate(formula)? {
+                    Value::Array(array) => array.into_values().into_iter().try_for_each(|value| keep_aggregated(value, options, values)),
+                    value => keep_aggregated(value, options, values),
+                };
+                return Ok(added);
+            },
+        };
+        for cell_id in cells {
+            if options.ignore_hidden && self.kernel.is_row_hidden(sheet, cell_id.row()) {
+                continue;
+            }
+            if options.ignore_nested {
+                let cell = match sheet {
+                    Some(sheet) => self.kernel.get_sheet_cell(sheet, cell_id),
+                    None => self.kernel.get_cell(cell_id),
+                };
+                if matches!(cell.as_ref().and_then(|cell| cell.formula()), Some(Formula::Function{kind: FunctionKind::Aggregate, ..})) {
+                    continue;
+                }
+            }
+            let value = match sheet {
+                Some(sheet) => self.kernel.evaluate_sheet_cell(sheet, cell_id)?,
+                None => self.kernel.evaluate_cell(cell_id)?,
+            };
+            if let Err(e) = keep_aggregated(value, options, values) {
+                return Ok(Err(e));
+            }
+        }
+        Ok(Ok(()))
+    }
+
+    /// Evaluate an argument to a whole number, truncating any fraction.
+    fn integer<E>(&self, argument: &Value<T>) -> Result<Result<i64, CellError>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        Ok(self.number(argument)?.map(|number| {
+            let number = number.value().to_f64();
+            if number.fract() != 0.0 {
+                self.warn(CalcWarning::Truncated{number});
+            }
+            number.trunc() as i64
+        }))
+    }
+
+    fn text_function<E>(&self, kind: FunctionKind, arguments: &[Value<T>]) -> Result<Result<Value<T>, CellError>, E>
+    where K: Kernel<E, T>, E: std::error::Error {
+        let text = |text: String| Value::Primitive(Primitive::Text(text));
+        Ok(match (kind, arguments) {
+            (FunctionKind::Text, [value, code]) => match (self.evaluate_value(value)?, self.display_text(code)?) {
+                (_, Err(e)) | (Value::Error(e), _) => Err(e),
+                (value @ Value::Primitive(Primitive::Number(_) | Primitive::Bool(_)), Ok(code)) => {
+                    if let Value::Primitive(Primitive::Number(serial)) = &value {
+                        if is_date_code(&code) && !holds_milliseconds(serial.value()) {
+                            self.warn(CalcWarning::DateSerialPrecision{serial: serial.value().to_f64()});
+                        }
+                    }
+                    Ok(text(format_value(&value, &code, &Locale::default())))
+                },
+                _ => self.display_text(value)?.map(text),
+            },
+            (FunctionKind::TextBefore | FunctionKind::TextAfter, [value, delimiter, rest @ ..]) if rest.len() <= 4 => {
+                // The instance, match mode and match end, in that order.
+                let mut options = [1, 0, 0];
+                for (option, argument) in options.iter_mut().zip(rest) {
+                    match self.integer(argument)? {
+                        Ok(integer) => *option = integer,
+                        Err(e) => return Ok(Err(e)),
+                    }
+                }
+                let search = TextSearch{instance: options[0], ignore_case: options[1] == 1, match_end: options[2] == 1};
+                match (self.display_text(value)?, self.display_text(delimiter)?) {
+                    (Ok(value), Ok(delimiter)) => {
+                        let split = match kind {
+                            FunctionKind::TextBefore => text_before(&value, &delimiter, &search),
+                            _ => text_after(&value, &delimiter, &search),
+                        };
+                        match (split, rest.get(3)) {
+                            (Ok(split), _) => Ok(text(split)),
+                            (Err(SplitError::NotFound), Some(if_not_found)) => return self.evaluate_value(if_not_found).map(Ok),
+                            (Err(SplitError::NotFound), None) => Err(CellError::NA),
+                            (Err(SplitError::InvalidInstance), _) => Err(CellError::Value),
+                        }
+                    },
+                    (Err(e), _) | (_, Err(e)) => Err(e),
+                }
+            },
+            // Positions count characters from 1.
+            (FunctionKind::Search | FunctionKind::Find, [needle, haystack, rest @ ..]) if rest.len() <= 1 => {
+                let start = match rest.first() {
+                    Some(start) => self.integer(start)?,
+                    None => Ok(1),
+                };
+                match (self.display_text(needle)?, self.display_text(haystack)?, start) {
+                    (Ok(needle), Ok(haystack), Ok(start)) => {
+                        let options = match kind {
+                            FunctionKind::Search => MatchOptions::default(),
+                            _ => MatchOptions::literal(),
+                        };
+                        match usize::try_from(start) {
+                            Ok(start) if start >= 1 && start <= haystack.chars().count() + 1 => {
+                                Pattern::new(&needle, options).find(&haystack, start - 1)
+                                    .map(|found| Value::Primitive(Primitive::Number(Numeric::new(Floating::from_f64((found.start + 1) as f64), None))))
+                                    .ok_or(CellError::Value)
+                            },
+                            _ => Err(CellError::Value),
+                        }
+                    },
+                    (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => Err(e),
+                }
+            },
+            (FunctionKind::Replace, [old, start, count, new]) => {
+                match (self.display_text(old)?, self.integer(start)?, self.integer(count)?, self.display_text(new)?) {
+                    (Ok(old), Ok(start), Ok(count), Ok(new)) if start >= 1 && count >= 0 => {
+                        let chars = old.chars().collect::<Vec<_>>();
+                        let start = (start as usize - 1).min(chars.len());
+                        let end = start.saturating_add(count as usize).min(chars.len());
+                        let replaced = chars[..start].iter().collect::<String>() + &new + &chars[end..].iter().collect::<String>();
+                        match replaced.chars().count() <= MAX_TEXT_LENGTH {
+                            true => Ok(text(replaced)),
+                            false => Err(CellError::Value),
+                        }
+                    },
+                    (Ok(_), Ok(_), Ok(_), Ok(_)) => Err(CellError::Value),
+                    (Err(e), _, _, _) | (_, Err(e), _, _) | (_, _, Err(e), _) | (_, _, _, Err(e)) => Err(e),
+                }
+            },
+            (FunctionKind::Proper, [value]) => self.display_text(value)?.map(|value| text(proper(&value))),
+            (FunctionKind::Exact, [first, second]) => match (self.display_text(first)?, self.display_text(second)?) {
+                (Ok(first), Ok(second)) => Ok(Value::Primitive(Primitive::Bool(first == second))),
+                (Err(e), _) | (_, Err(e)) => Err(e),
+            },
+            (FunctionKind::Rept, [value, times]) => match (self.display_text(value)?, self.integer(times)?) {
+                (Ok(value), Ok(times)) => match usize::try_from(times) {
+                    Ok(times) if value.chars().count().saturating_mul(times) <= MAX_TEXT_LENGTH => Ok(text(value.repeat(times))),
+                    _ => Err(CellError::Value),
+                },
+                (Err(e), _) | (_, Err(e)) => Err(e),
+            },
+            (FunctionKind::Char, [code]) => self.integer(code)?.and_then(|code| {
+                u8::try_from(code).ok()
+                    .and_then(windows_1252_char)
+                    .map(|c| text(c.to_string()))
+                    .ok_or(CellError::Value)
+            }),
+            (FunctionKind::Unichar, [code]) => self.integer(code)?.and_then(|code| match u32::try_from(code) {
+                Ok(0) | Err(_) => Err(CellError::Value),
+                Ok(0xD800..=0xDFFF) => Err(CellError::NA),
+                Ok(code) => char::from_u32(code).map(|c| text(c.to_string())).ok_or(CellError::Value),
+            }),
+            (FunctionKind::Code | FunctionKind::Unicode, [value]) => self.display_text(value)?.and_then(|value| {
+                let c = value.chars().next().ok_or(CellError::Value)?;
+                let code = match kind {
+                    FunctionKind::Code => windows_1252_code(c) as u32,
+                    _ => c as u32,
+                };
+                Ok(Value::Primitive(Primitive::Number(Numeric::new(Floating::from_f64(code as f64), None))))
+            }),
+            _ => Err(CellError::Value),
+        })
+    }
+
+    fn query(&self, source: &str, measure: &str, members: &[Member]) -> Result<Value<T>, CellError> {
+        self.sources
+            .ok_or(CellError::Ref)?
+            .query(source, measure, members)
+            .map(Value::Primitive)
+            .map_err(|_| CellError::Ref)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CriterionOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Criterion is the condition of `COUNTIF`, such as `5`, `">=5"` or
+/// `"app*"`. Text is compared with wildcards and without regard to case.
+struct Criterion<T: Arithmetic> {
+    op: CriterionOp,
+    operand: Comparable<T>,
+    pattern: Option<Pattern>,
+}
+
+impl<T: Arithmetic> Criterion<T> {
+    fn parse(criterion: Comparable<T>) -> Self {
+        let text = match criterion {
+            Comparable::Text(text) => text,
+            Comparable::Empty => String::new(),
+            operand => return Self{op: CriterionOp::Eq, operand, pattern: None},
+        };
+        let operators = [("<>", CriterionOp::Ne), (">=", CriterionOp::Ge), ("<=", CriterionOp::Le), ("=", CriterionOp::Eq), (">", CriterionOp::Gt), ("<", CriterionOp::Lt)];
+        let (op, operand) = operators.iter()
+            .find_map(|(prefix, op)| text.strip_prefix(prefix).map(|operand| (*op, operand)))
+            .unwrap_or((CriterionOp::Eq, text.as_str()));
+        let operand = match Primitive::try_from(operand).map(|primitive| Comparable::of(Value::Primitive(primitive))) {
+            Ok(Ok(operand @ (Comparable::Number(_) | Comparable::Bool(_)))) => operand,
+            _ => Comparable::Text(operand.to_string()),
+        };
+        let pattern = match (&operand, op) {
+            (Comparable::Text(text), CriterionOp::Eq | CriterionOp::Ne) => Some(Pattern::new(text, MatchOptions::default())),
+            _ => None,
+        };
+        Self{op, operand, pattern}
+    }
+
+    fn matches(&self, value: &Comparable<T>, settings: &CalcSettings) -> bool {
+        let equal = match (&self.pattern, value) {
+            // An empty criterion stands for blanks.
+            (Some(_), Comparable::Empty) => matches!(&self.operand, Comparable::Text(text) if text.is_empty()),
+            (Some(pattern), Comparable::Text(text)) => pattern.is_match(text),
+            (Some(_), _) => false,
+            (None, Comparable::Empty) => false,
+            (None, value) => value.rank() == self.operand.rank() && value.compare(&self.operand, settings) == Some(Ordering::Equal),
+        };
+        let ordering = match value {
+            Comparable::Empty => None,
+            value if value.rank() == self.operand.rank() => value.compare(&self.operand, settings),
+            _ => None,
+        };
+        match self.op {
+            CriterionOp::Eq => equal,
+            CriterionOp::Ne => !equal,
+            CriterionOp::Lt => ordering == Some(Ordering::Less),
+            CriterionOp::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+            CriterionOp::Gt => ordering == Some(Ordering::Greater),
+            CriterionOp::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+        }
+    }
+}
+
+/// Comparable is an evaluated operand of a comparison.
+#[derive(Debug, Clone)]
+pub(crate) enum Comparable<T: Arithmetic> {
+    Empty,
+    Number(T),
+    Text(String),
+    Bool(bool),
+}
+
+impl<T: Arithmetic> Comparable<T> {
+    pub(crate) fn of(value: Value<T>) -> Result<Self, CellError> {
+        match value {
+            Value::Empty => Ok(Self::Empty),
+            Value::Primitive(Primitive::Bool(b)) => Ok(Self::Bool(b)),
+            Value::Primitive(Primitive::Text(text)) => Ok(Self::Text(text)),
+            value @ Value::Primitive(Primitive::IPAddress(_)) => Ok(Self::Text(format_value(&value, "General", &Locale::default()))),
+            value => to_number(&value).map(|number| Self::Number(number.value())),
+        }
+    }
+
+    /// Rank the type of an operand: spreadsheets order every number before
+    /// any text, and all text before the booleans.
+    pub(crate) fn rank(&self) -> u8 {
+        match self {
+            Self::Empty | Self::Number(_) => 0,
+            Self::Text(_) => 1,
+            Self::Bool(_) => 2,
+        }
+    }
+
+    /// Order two operands. A blank takes the type of the other operand,
+    /// comparing like zero, empty text or `FALSE`; text compares without
+    /// regard to case.
+    pub(crate) fn compare(&self, other: &Self, settings: &CalcSettings) -> Option<Ordering> {
+        let zero = || Floating::from_f64(0.0);
+        match (self, other) {
+            (Self::Empty, Self::Empty) => Some(Ordering::Equal),
+            (Self::Number(a), Self::Number(b)) => settings.compare_numbers(*a, *b),
+            (Self::Number(a), Self::Empty) => settings.compare_numbers(*a, zero()),
+            (Self::Empty, Self::Number(b)) => settings.compare_numbers(zero(), *b),
+            (Self::Text(a), Self::Text(b)) => Some(a.to_lowercase().cmp(&b.to_lowercase())),
+            (Self::Text(a), Self::Empty) => Some(a.as_str().cmp("")),
+            (Self::Empty, Self::Text(b)) => Some("".cmp(b.as_str())),
+            (Self::Bool(a), Self::Bool(b)) => Some(a.cmp(b)),
+            (Self::Bool(a), Self::Empty) => Some(a.cmp(&false)),
+            (Self::Empty, Self::Bool(b)) => Some(false.cmp(b)),
+            (a, b) => Some(a.rank().cmp(&b.rank())),
+        }
+    }
+}
+
+impl FunctionKind {
+    /// Whether the function has a path computing over whole column slices
+    /// of its range arguments.
+    pub fn is_vectorized(&self) -> bool {
+        matches!(self, Self::Sum | Self::SumProduct | Self::CountIf | Self::VLookup)
+    }
+}
+
+/// Get the sheet and corners of an argument which is a range.
+fn range_argument<T: Arithmetic>(argument: &Value<T>) -> Option<(Option<&str>, CellId, CellId)> {
+    match argument {
+        Value::Formula(Formula::CellRange(start, end)) => Some((None, *start, *end)),
+        Value::Formula(Formula::SheetRef(sheet, target)) => match &**target {
+            Formula::CellRange(start, end) => Some((Some(sheet.as_str()), *start, *end)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Multiply the values at each index of arrays of `len` values, counting
+/// values which are not numbers as zero, and add the products. Fails with
+/// the first error found.
+pub(crate) fn sum_of_products<A, T: Arithmetic>(arrays: &[A], len: usize, at: impl Fn(&A, usize) -> &Value<T>) -> Result<T, CellError> {
+    let mut sum: T = Floating::from_f64(0.0);
+    for index in 0..len {
+        let mut product: T = Floating::from_f64(1.0);
+        for array in arrays {
+            match at(array, index) {
+                Value::Primitive(Primitive::Number(x)) => product *= x.value(),
+                Value::Error(e) => return Err(*e),
+                _ => product = Floating::from_f64(0.0),
+            }
+        }
+        sum += product;
+    }
+    Ok(sum)
+}
+
+/// Whether adding a millisecond to a date serial changes it.
+fn holds_milliseconds<T: Arithmetic>(serial: T) -> bool {
+    let millisecond: T = Floating::from_f64(1.0 / 86_400_000.0);
+    serial + millisecond != serial
+}
+
+/// Add a value to those `AGGREGATE` works on, or fail with an error
+/// it does not skip.
+fn keep_aggregated<T: Arithmetic>(value: Value<T>, options: &AggregateOptions, values: &mut Vec<Value<T>>) -> Result<(), CellError> {
+    match value {
+        Value::Error(_) | Value::FormulaParseError(_) if options.ignore_errors => Ok(()),
+        Value::Error(e) => Err(e),
+        Value::FormulaParseError(_) => Err(CellError::Name),
+        value => {
+            values.push(value);
+            Ok(())
+        },
+    }
+}
+
+/// Get a single value as is and bigger arrays as dynamic arrays.
+fn spill<T: Arithmetic>(array: Array2D<Value<T>>) -> Value<T> {
+    match (array.rows(), array.cols()) {
+        (1, 1) => array.into_values().pop().expect("one value"),
+        _ => Value::Array(array),
+    }
+}
+
+fn transpose<T: Clone>(array: Array2D<T>) -> Array2D<T> {
+    Array2D::from_fn(array.cols(), array.rows(), |row, col| array.get(col, row).cloned().expect("inside the array"))
+}
+
+/// Pick rows of an array by their number, counting from the end if
+/// negative.
+fn choose_rows<T: Arithmetic>(array: &Array2D<Value<T>>, indexes: &[i64]) -> Result<Array2D<Value<T>>, CellError> {
+    let rows = indexes.iter().map(|index| {
+        let row = match *index {
+            index if index > 0 => index as usize - 1,
+            index => array.rows().checked_sub(index.unsigned_abs() as usize).ok_or(CellError::Value)?,
+        };
+        array.row(row).ok_or(CellError::Value)
+    }).collect::<Result<Vec<_>, _>>()?;
+    Ok(Array2D::from_fn(rows.len(), array.cols(), |row, col| rows[row][col].clone()))
+}
+
+/// Get the part of `len` rows or columns `TAKE` keeps, or `DROP` keeps if
+/// not `take`. Positive counts are from the start and negative from the end.
+fn keep(len: usize, count: i64, take: bool) -> Option<std::ops::Range<usize>> {
+    let n = (count.unsigned_abs() as usize).min(len);
+    let range = match (take, count >= 0) {
+        (true, true) => 0..n,
+        (true, false) => len - n..len,
+        (false, true) => n..len,
+        (false, false) => 0..len - n,
+    };
+    (count != 0 || !take).then_some(range)
+}
+
+/// Stack arrays on top of each other, padding narrower arrays with `#N/A`.
+fn vstack<T: Arithmetic>(arrays: Vec<Array2D<Value<T>>>) -> Array2D<Value<T>> {
+    let cols = arrays.iter().map(|array| array.cols()).max().unwrap_or(0);
+    let mut values = Vec::new();
+    for array in &arrays {
+        for row in array.iter_rows() {
+            values.extend(row.iter().cloned());
+            values.extend((row.len()..cols).map(|_| Value::Error(CellError::NA)));
+        }
+    }
+    let rows = arrays.iter().map(|array| array.rows()).sum();
+    Array2D::from_vec(rows, cols, values).expect("every row is padded")
+}
+
+/// Coerce an evaluated value to a number.
+pub fn to_number<T: Arithmetic>(value: &Value<T>) -> Result<Numeric<T>, CellError> {
+    match value {
+        Value::Primitive(Primitive::Number(number)) => Ok(number.clone()),
+        Value::Primitive(Primitive::Bool(b)) => Ok(Numeric::new(Floating::from_f64(if *b { 1.0 } else { 0.0 }), None)),
+        Value::Empty => Ok(Numeric::new(Floating::from_f64(0.0), None)),
+        Value::Error(e) => Err(*e),
+        Value::FormulaParseError(_) => Err(CellError::Name),
+        _ => Err(CellError::Value),
+    }
+}
+
+/// Arith is a binary arithmetic operator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Arith {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+}
+
+impl Arith {
+    pub(crate) fn apply<T: Arithmetic>(self, a: Numeric<T>, b: Numeric<T>) -> Result<Numeric<T>, CellError> {
+        #[cfg(feature = "units")]
+        if let Some(result) = units::apply(self, &a, &b) {
+            return result;
+        }
+        match self {
+            Self::Add => a.try_add(b).ok_or(CellError::Value),
+            Self::Sub => Ok(Numeric::new(a.value() - b.value(), None)),
+            Self::Mul => Ok(Numeric::new(a.value() * b.value(), None)),
+            Self::Div if b.value() == Floating::from_f64(0.0) => Err(CellError::Div0),
+            Self::Div => Ok(Numeric::new(a.value() / b.value(), None)),
+            Self::Pow => power(a.value(), b.value()),
+        }
+    }
+}
+
+/// Unary is a unary arithmetic operator: minus or percent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Unary {
+    Neg,
+    Percent,
+}
+
+impl Unary {
+    pub(crate) fn apply<T: Arithmetic>(self, number: Numeric<T>) -> Numeric<T> {
+        match self {
+            Self::Neg => {
+                let zero: T = Floating::from_f64(0.0);
+                Numeric::new(zero - number.number(), number.attr().cloned())
+            },
+            Self::Percent => Numeric::new(number.value(), Some(NumericAttribute::Percent)),
+        }
+    }
+}
+
+/// Raise `base` to `exponent`, with the errors spreadsheets give for results
+/// which are not real numbers.
+fn power<T: Arithmetic>(base: T, exponent: T) -> Result<Numeric<T>, CellError> {
+    let zero: T = Floating::from_f64(0.0);
+    if base == zero && exponent < zero {
+        return Err(CellError::Div0);
+    }
+    if base == zero && exponent == zero {
+        return Err(CellError::Num);
+    }
+    let result = base.pow(exponent);
+    if result.to_f64().is_finite() {
+        Ok(Numeric::new(result, None))
+    } else {
+        Err(CellError::Num)
+    }
+}
+
+fn offset<T: Arithmetic>(origin: CellId, rows: T, cols: T) -> Option<CellId> {
+    let shift = |start: u32, by: T| u32::try_from(start as i64 + by.to_f64().trunc() as i64).ok();
+    Some(CellId::new(shift(origin.row(), rows)?, shift(origin.col(), cols)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::serialize::value_to_raw;
+    use crate::kernel::worksheet::Worksheet;
+    use crate::kernel::parser::{parse, ParseOptions};
+    use crate::kernel::datasource::PivotCache;
+
+    fn sheet(cells: &[(u32, u32, &str)]) -> Worksheet {
+        let mut sheet = Worksheet::default();
+        for (row, col, data) in cells {
+            sheet.set_cell(CellId::new(*row, *col), data.to_string());
+        }
+        sheet
+    }
+
+    fn evaluate(sheet: &mut Worksheet, formula: &str) -> String {
+        let cell_id = CellId::new(1000, 0);
+        sheet.set_cell(cell_id, formula.to_string());
+        value_to_raw(&sheet.evaluate_cell(cell_id).unwrap())
+    }
+
+    #[test]
+    fn comparisons_order_numbers_before_text_before_booleans() {
+        let mut sheet = sheet(&[(0, 0, "b"), (0, 1, "A"), (0, 2, "TRUE"), (0, 3, "5")]);
+        for (formula, expected) in [
+            ("=A1>B1", "TRUE"),
+            ("=A1<>B1", "TRUE"),
+            ("=D1<A1", "TRUE"),
+            ("=A1<C1", "TRUE"),
+            ("=D1>=C1", "FALSE"),
+            ("=D1<=5", "TRUE"),
+            ("=D1<>5", "FALSE"),
+        ] {
+            assert_eq!(evaluate(&mut sheet, formula), expected, "{}", formula);
+        }
+    }
+
+    #[test]
+    fn blanks_compare_like_the_other_operand() {
+        let mut sheet = sheet(&[(0, 0, "b"), (0, 2, "FALSE"), (0, 3, "-1")]);
+        for (formula, expected) in [
+            ("=E1>=0", "TRUE"),
+            ("=E1<=0", "TRUE"),
+            ("=D1<E1", "TRUE"),
+            ("=E1<A1", "TRUE"),
+            ("=E1>=C1", "TRUE"),
+            ("=E1<>C1", "FALSE"),
+        ] {
+            assert_eq!(evaluate(&mut sheet, formula), expected, "{}", formula);
+        }
+    }
+
+    #[test]
+    fn literals_evaluate_to_themselves() {
+        let mut sheet = sheet(&[]);
+        for (formula, expected) in [
+            ("=5", "5"),
+            ("=TRUE", "TRUE"),
+            ("=\"yes\"", "yes"),
+            ("=\"a\"=\"A\"", "TRUE"),
+            ("=\"x\"&2", "x2"),
+            ("=IF(FALSE,1,\"no\")", "no"),
+        ] {
+            assert_eq!(evaluate(&mut sheet, formula), expected, "{}", formula);
+        }
+    }
+
+    #[test]
+    fn literals_display_as_written() {
+        for formula in ["\"say \"\"hi\"\"\"&TRUE", "50%*2", "FALSE", "\"\""] {
+            let parsed = parse::<f64>(formula, &ParseOptions::default()).unwrap();
+            assert_eq!(parsed.to_string(), formula);
+        }
+    }
+
+    #[test]
+    fn text_positions_count_characters_from_one() {
+        let mut sheet = sheet(&[(0, 0, "Ünïcode Übung")]);
+        for (formula, expected) in [
+            ("=SEARCH(\"übung\",A1)", "9"),
+            ("=SEARCH(\"c?de\",A1)", "4"),
+            ("=FIND(\"Ü\",A1,2)", "9"),
+            ("=FIND(\"c?de\",A1)", "#VALUE!"),
+            ("=FIND(\"ü\",A1)", "#VALUE!"),
+            ("=SEARCH(\"\",A1,14)", "14"),
+            ("=SEARCH(\"\",A1,15)", "#VALUE!"),
+            ("=REPLACE(A1,1,7,\"Neue\")", "Neue Übung"),
+            ("=REPLACE(A1,20,0,\"!\")", "Ünïcode Übung!"),
+            ("=REPLACE(A1,0,1,\"x\")", "#VALUE!"),
+        ] {
+            assert_eq!(evaluate(&mut sheet, formula), expected, "{}", formula);
+        }
+    }
+
+    /// Columns of numbers, text, booleans and gaps, as found in sheets.
+    fn mixed(rows: u32) -> Vec<(u32, u32, String)> {
+        let mut cells = Vec::new();
+        for row in 0..rows {
+            let a = match row % 6 {
+                0 => String::new(),
+                1 => "text".to_string(),
+                2 => "TRUE".to_string(),
+                _ => format!("{}", (row * 7) % 23),
+            };
+            cells.push((row, 0, a));
+            cells.push((row, 1, format!("{}", (row * 3) % 11)));
+            cells.push((row, 2, format!("key{}", row)));
+        }
+        cells
+    }
+
+    #[test]
+    fn functions_compute_their_values() {
+        let mut sheet = sheet(&[(0, 0, "1"), (1, 0, "2"), (2, 0, "3"), (0, 1, "x"), (1, 1, "4"), (2, 1, "TRUE")]);
+        for (formula, expected) in [
+            ("=1+2*3", "7"),
+            ("=(1+2)*3", "9"),
+            ("=2^10", "1024"),
+            ("=SUM(A1:B3)", "10"),
+            ("=SUM(A1:A3,10)", "16"),
+            ("=AVERAGE(A1:A3)", "2"),
+            ("=COUNT(A1:B3)", "4"),
+            ("=PRODUCT(A1:A3)", "6"),
+            ("=SUMPRODUCT(A1:A3,A1:A3)", "14"),
+            ("=SQRT(16)", "4"),
+            ("=COUNTIF(A1:A3,\">1\")", "2"),
+            ("=\"a\"&\"b\"", "ab"),
+            ("=PROPER(\"hello world\")", "Hello World"),
+            ("=REPT(\"ab\",3)", "ababab"),
+            ("=A1<A2", "TRUE"),
+        ] {
+            assert_eq!(evaluate(&mut sheet, formula), expected, "{}", formula);
+        }
+    }
+
+    #[test]
+    fn errors_propagate_and_if_short_circuits() {
+        let mut sheet = sheet(&[(0, 0, "=1/0"), (1, 0, "5")]);
+        for (formula, expected) in [
+            ("=1/0", CellError::Div0),
+            ("=SQRT(-1)", CellError::Num),
+            ("=\"a\"+1", CellError::Value),
+            ("=A1+1", CellError::Div0),
+            ("=SUM(A1:A2)", CellError::Div0),
+            ("=NOSUCHFUNCTION(1)", CellError::Name),
+            ("=MATCH(9,A2:A2,0)", CellError::NA),
+            ("=IF(A1,1,2)", CellError::Div0),
+        ] {
+            sheet.set_cell(CellId::new(1000, 0), formula.to_string());
+            let value = sheet.evaluate_cell(CellId::new(1000, 0)).unwrap();
+            assert!(matches!(value, Value::Error(e) if e == expected), "{} gave {:?}", formula, value);
+        }
+        assert_eq!(evaluate(&mut sheet, "=IF(TRUE,1,1/0)"), "1");
+        assert_eq!(evaluate(&mut sheet, "=IF(FALSE,A1,A2)"), "5");
+        assert_eq!(evaluate(&mut sheet, "=IF(A2>1,\"big\",\"small\")"), "big");
+    }
+
+    #[test]
+    fn lookups_find_exact_and_approximate_matches() {
+        let mut sheet = sheet(&[(0, 0, "10"), (1, 0, "20"), (2, 0, "30"), (0, 1, "ten"), (1, 1, "twenty"), (2, 1, "thirty")]);
+        for (formula, expected) in [
+            ("=VLOOKUP(20,A1:B3,2,FALSE)", "twenty"),
+            ("=VLOOKUP(25,A1:B3,2,TRUE)", "twenty"),
+            ("=VLOOKUP(25,A1:B3,2)", "twenty"),
+            ("=MATCH(30,A1:A3,0)", "3"),
+            ("=MATCH(35,A1:A3,1)", "3"),
+            ("=MATCH(\"TWENTY\",B1:B3,0)", "2"),
+        ] {
+            assert_eq!(evaluate(&mut sheet, formula), expected, "{}", formula);
+        }
+        sheet.set_cell(CellId::new(1000, 0), "=VLOOKUP(25,A1:B3,2,FALSE)".to_string());
+        assert!(matches!(sheet.evaluate_cell(CellId::new(1000, 0)).unwrap(), Value::Error(CellError::NA)));
+        sheet.set_cell(CellId::new(1000, 0), "=VLOOKUP(20,A1:B3,3,FALSE)".to_string());
+        assert!(matches!(sheet.evaluate_cell(CellId::new(1000, 0)).unwrap(), Value::Error(CellError::Ref)));
+    }
+
+    #[test]
+    fn vectorized_functions_match_cell_by_cell() {
+        let cells = mixed(60);
+        let mut sheet = sheet(&cells.iter().map(|(row, col, data)| (*row, *col, data.as_str())).collect::<Vec<_>>());
+        assert!(sheet.dense_column(0, 0, 59).is_some());
+        for formula in [
+            "=SUM(A1:B60)",
+            "=SUM(A1:A60,B1:B60)",
+            "=SUMPRODUCT(A1:A60,B1:B60)",
+            "=SUMPRODUCT(A1:B60,A1:B60)",
+            "=COUNTIF(A1:B60,\">5\")",
+            "=COUNTIF(A1:A60,\"text\")",
+            "=COUNTIF(C1:C60,\"key1*\")",
+            "=VLOOKUP(\"key17\",C1:C60,1,FALSE)",
+            "=VLOOKUP(14,A1:B60,2,FALSE)",
+            "=VLOOKUP(99,A1:B60,2,FALSE)",
+        ] {
+            let cell_id = CellId::new(1000, 0);
+            sheet.set_cell(cell_id, formula.to_string());
+            let vectorized = value_to_raw(&sheet.evaluate_cell(cell_id).unwrap());
+            let (cell_by_cell, _) = sheet.record(|| sheet.evaluate_cell(cell_id).unwrap());
+            assert_eq!(vectorized, value_to_raw(&cell_by_cell), "{}", formula);
+        }
+        sheet.set_cell(CellId::new(5, 1), "=1/0".to_string());
+        assert!(sheet.dense_column(1, 0, 59).is_none());
+        sheet.set_cell(CellId::new(5, 1), "=1+".to_string());
+        assert!(sheet.dense_column(1, 0, 59).is_some());
+        let cell_id = CellId::new(1000, 0);
+        sheet.set_cell(cell_id, "=SUM(A1:B60)".to_string());
+        let vectorized = value_to_raw(&sheet.evaluate_cell(cell_id).unwrap());
+        let (cell_by_cell, _) = sheet.record(|| sheet.evaluate_cell(cell_id).unwrap());
+        assert_eq!(vectorized, value_to_raw(&cell_by_cell));
+    }
+
+    #[test]
+    fn indexed_lookups_match_scans() {
+        let mut cells = Vec::new();
+        for row in 0..80u32 {
+            cells.push((row, 0, format!("{}", row * 5)));
+            cells.push((row, 1, format!("name{}", row % 13)));
+            cells.push((row, 2, format!("{}", row)));
+        }
+        let mut sheet = sheet(&cells.iter().map(|(row, col, data)| (*row, *col, data.as_str())).collect::<Vec<_>>());
+        let formulas = [
+            "=MATCH(125,A1:A80,0)",
+            "=MATCH(127,A1:A80,1)",
+            "=MATCH(-1,A1:A80,1)",
+            "=MATCH(1000,A1:A80,0)",
+            "=MATCH(\"NAME4\",B1:B80,0)",
+            "=VLOOKUP(200,A1:C80,3,FALSE)",
+            "=VLOOKUP(201,A1:C80,3,TRUE)",
+            "=VLOOKUP(\"name7\",B1:C80,2,FALSE)",
+            "=VLOOKUP(\"name7\",B1:C80,5,FALSE)",
+        ];
+        let scanned = formulas.iter().map(|formula| evaluate(&mut sheet, formula)).collect::<Vec<_>>();
+        sheet.set_index_lookups(true);
+        let indexed = formulas.iter().map(|formula| evaluate(&mut sheet, formula)).collect::<Vec<_>>();
+        assert!(sheet.lookup_index_count() > 0);
+        assert_eq!(scanned, indexed);
+
+        // Changing a key drops the index of its range.
+        sheet.set_cell(CellId::new(25, 0), "126".to_string());
+        assert_eq!(evaluate(&mut sheet, "=MATCH(126,A1:A80,0)"), "26");
+        sheet.set_index_lookups(false);
+        assert_eq!(evaluate(&mut sheet, "=MATCH(126,A1:A80,0)"), "26");
+    }
+
+    #[test]
+    fn data_source_functions_query_registered_sources() {
+        let mut cache = PivotCache::new(vec!["Region".to_string(), "Year".to_string()], vec!["Sales".to_string()]);
+        for (region, year, sales) in [("East", "2023", 10.0), ("East", "2024", 20.0), ("West", "2024", 5.0)] {
+            assert!(cache.push(vec![region.to_string(), year.to_string()], vec![sales]));
+        }
+        assert!(!cache.push(vec!["East".to_string()], vec![1.0]));
+        let mut sheet = sheet(&[]);
+        sheet.sources_mut().register("Pivot", Box::new(cache));
+        for (formula, expected) in [
+            ("=GETPIVOTDATA(\"Sales\",\"pivot\")", "35"),
+            ("=GETPIVOTDATA(\"Sales\",\"Pivot\",\"Region\",\"East\")", "30"),
+            ("=GETPIVOTDATA(\"Sales\",\"Pivot\",\"Region\",\"east\",\"Year\",\"2024\")", "20"),
+            ("=CUBEVALUE(\"Pivot\",\"Sales\",\"[Year].[2024]\")", "25"),
+        ] {
+            assert_eq!(evaluate(&mut sheet, formula), expected, "{}", formula);
+        }
+        for formula in [
+            "=GETPIVOTDATA(\"Sales\",\"Pivot\",\"Region\")",
+            "=GETPIVOTDATA(\"Sales\",\"Other\")",
+            "=CUBEVALUE(\"Pivot\",\"Sales\",\"Year 2024\")",
+        ] {
+            sheet.set_cell(CellId::new(1000, 0), formula.to_string());
+            assert!(sheet.evaluate_cell(CellId::new(1000, 0)).unwrap().is_error(), "{}", formula);
+        }
+    }
+}
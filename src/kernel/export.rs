@@ -0,0 +1,156 @@
+use super::arithmetic::Arithmetic;
+use super::autosave::save_workbook;
+use super::csv::write_grid;
+use super::kernel::CellId;
+use super::workbook::{Workbook, WorkbookError};
+use thiserror::Error;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error(transparent)]
+    Workbook(#[from] WorkbookError),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// ExportFormat is the writer of an export.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// A directory in the format of `save_workbook`, with every sheet and
+    /// defined name, which `load_autosave` loads.
+    Directory,
+    /// A file of delimited text holding one sheet.
+    Csv{sheet: String, delimiter: char},
+}
+
+/// ExportOptions configures `Workbook::export_values`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    /// Whether to write the text every cell displays with its number
+    /// format, such as `$1,234.50`, instead of its value. Formatted cells
+    /// hold text, so this suits reports read by people.
+    pub formatted: bool,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self{format: ExportFormat::Directory, formatted: false}
+    }
+}
+
+impl<T: Arithmetic> Workbook<T> {
+    /// Copy this workbook with every formula replaced by its value, and the
+    /// cells dynamic arrays spill into by the values they display, like
+    /// pasting values over a whole workbook. Number formats and defined
+    /// names are kept. Registered functions are not, as the copy has no
+    /// formulas left to call them. Numbers are written in the precision of
+    /// the settings of the workbook.
+    pub fn values_copy(&self) -> Result<Workbook<T>, WorkbookError> {
+        let mut copy = self.convert::<T>()?;
+        let precision = self.settings().precision;
+        for (name, _) in self.sheets() {
+            let values = self.formula_values(name)?;
+            let sheet = copy.sheet_mut(name).expect("copies have every sheet");
+            for (cell_id, value, _) in values {
+                sheet.set_value_with(cell_id, value, precision);
+            }
+        }
+        Ok(copy)
+    }
+
+    /// Write a copy of this workbook holding only values, see `values_copy`,
+    /// for distributing reports without the formulas behind them.
+    pub fn export_values(&self, path: impl AsRef<Path>, options: &ExportOptions) -> Result<(), ExportError> {
+        let mut copy = self.values_copy()?;
+        match &options.format {
+            ExportFormat::Directory => {
+                if options.formatted {
+                    let names = copy.sheet_names().map(str::to_string).collect::<Vec<_>>();
+                    for name in names {
+                        let texts = formatted(&copy, &name)?;
+                        let sheet = copy.sheet_mut(&name).expect("sheets exist");
+                        for (cell_id, text) in texts {
+                            sheet.set_text(cell_id, &text);
+                        }
+                    }
+                }
+                save_workbook(&copy, path, 0)?;
+            },
+            ExportFormat::Csv{sheet: name, delimiter} => {
+                let sheet = copy.sheet(name).ok_or_else(|| WorkbookError::UnknownSheet(name.clone()))?;
+                let texts = match options.formatted {
+                    true => formatted(&copy, name)?,
+                    false => sheet.cells().map(|(cell_id, cell)| (cell_id, cell.raw().to_string())).collect(),
+                };
+                let mut writer = BufWriter::new(File::create(path)?);
+                write_grid(&sheet.cell_ids(), |cell_id| texts.get(&cell_id).map(String::as_str), &mut writer, *delimiter)?;
+                writer.flush()?;
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Get the text every cell of a sheet displays.
+fn formatted<T: Arithmetic>(workbook: &Workbook<T>, sheet: &str) -> Result<HashMap<CellId, String>, WorkbookError> {
+    let ids = workbook.sheet(sheet).ok_or_else(|| WorkbookError::UnknownSheet(sheet.to_string()))?.cell_ids();
+    ids.into_iter().map(|cell_id| Ok((cell_id, workbook.formatted(sheet, cell_id)?))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::autosave::load_autosave;
+
+    fn raw(workbook: &Workbook, sheet: &str, row: u32, col: u32) -> String {
+        workbook.sheet(sheet).unwrap().cell(CellId::new(row, col)).map(|cell| cell.raw().to_string()).unwrap_or_default()
+    }
+
+    fn workbook() -> Workbook {
+        let mut workbook: Workbook = Workbook::new();
+        workbook.add_sheet("Sales").unwrap();
+        workbook.set_cell("Sales", CellId::new(0, 0), "1200".to_string()).unwrap();
+        workbook.set_cell("Sales", CellId::new(1, 0), "=A1*2".to_string()).unwrap();
+        workbook.set_cell("Sales", CellId::new(0, 1), "=TAKE(A1:A2,2)".to_string()).unwrap();
+        workbook.sheet_mut("Sales").unwrap().set_number_format(CellId::new(1, 0), "$#,##0.00");
+        workbook
+    }
+
+    #[test]
+    fn values_copy_replaces_formulas() {
+        let copy = workbook().values_copy().unwrap();
+        assert_eq!(raw(&copy, "Sales", 1, 0), "2400");
+        assert_eq!(raw(&copy, "Sales", 0, 1), "1200");
+        assert_eq!(raw(&copy, "Sales", 1, 1), "2400");
+        assert!(copy.sheet("Sales").unwrap().cells().all(|(_, cell)| cell.formula().is_none()));
+        assert_eq!(copy.sheet("Sales").unwrap().number_format(CellId::new(1, 0)), "$#,##0.00");
+    }
+
+    #[test]
+    fn export_values_writes_directories_and_csv() {
+        let dir = std::env::temp_dir().join(format!("xlnt-export-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let workbook = workbook();
+
+        workbook.export_values(dir.join("values"), &ExportOptions::default()).unwrap();
+        let (loaded, _) = load_autosave::<f64>(dir.join("values")).unwrap();
+        assert_eq!(raw(&loaded, "Sales", 1, 0), "2400");
+        let formatted = ExportOptions{formatted: true, ..ExportOptions::default()};
+        workbook.export_values(dir.join("formatted"), &formatted).unwrap();
+        let (loaded, _) = load_autosave::<f64>(dir.join("formatted")).unwrap();
+        assert_eq!(loaded.formatted("Sales", CellId::new(1, 0)).unwrap(), "$2,400.00");
+
+        let csv = ExportOptions{format: ExportFormat::Csv{sheet: "Sales".to_string(), delimiter: ';'}, formatted: true};
+        workbook.export_values(dir.join("sales.csv"), &csv).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("sales.csv")).unwrap(), "1200;1200\n$2,400.00;2400\n");
+        let missing = ExportOptions{format: ExportFormat::Csv{sheet: "Missing".to_string(), delimiter: ','}, formatted: false};
+        assert!(matches!(workbook.export_values(dir.join("missing.csv"), &missing), Err(ExportError::Workbook(WorkbookError::UnknownSheet(_)))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
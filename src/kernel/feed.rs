@@ -0,0 +1,247 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{CellId, Kernel, Value};
+use super::worksheet::{SheetError, Worksheet};
+use std::collections::HashMap;
+
+/// CoalesceOptions tunes how a `FeedBuffer` merges the cells it marks dirty
+/// into rectangular regions. Larger gaps and fewer regions make recalcs
+/// cheaper to plan, at the cost of recalculating formulas which only refer
+/// to clean cells swept into a region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoalesceOptions {
+    /// The most rows or columns of clean cells between a dirty cell and a
+    /// region it is merged into.
+    pub max_gap: u32,
+    /// The most regions kept. Past it the two regions whose union holds the
+    /// fewest clean cells are merged.
+    pub max_regions: usize,
+    /// The number of pending writes past which the buffer is full and should
+    /// be recalculated early.
+    pub max_pending: usize,
+}
+
+impl Default for CoalesceOptions {
+    fn default() -> Self {
+        Self{max_gap: 1, max_regions: 64, max_pending: 100000}
+    }
+}
+
+/// FeedStats counts what a `FeedBuffer` did, to tune its options by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeedStats {
+    /// The number of `set_cell` calls.
+    pub writes: u64,
+    /// The writes to cells which already had a pending write, of which only
+    /// the last is applied.
+    pub overwritten: u64,
+    /// The writes whose cell was inside or next to an existing region.
+    pub coalesced: u64,
+    pub regions_created: u64,
+    /// The number of times two regions were merged into one.
+    pub merges: u64,
+    pub recalcs: u64,
+    /// The number of formulas evaluated by recalcs.
+    pub cells_recalculated: u64,
+}
+
+/// A rectangle as its top left and bottom right corners.
+type Region = (CellId, CellId);
+
+fn area((first, second): Region) -> u64 {
+    (second.row() - first.row() + 1) as u64 * (second.col() - first.col() + 1) as u64
+}
+
+fn union((a, b): Region, (c, d): Region) -> Region {
+    (CellId::new(a.row().min(c.row()), a.col().min(c.col())), CellId::new(b.row().max(d.row()), b.col().max(d.col())))
+}
+
+/// Whether two regions are within `gap` rows and columns of each other.
+fn near((a, b): Region, (c, d): Region, gap: u32) -> bool {
+    a.row() <= d.row().saturating_add(gap) && c.row() <= b.row().saturating_add(gap)
+        && a.col() <= d.col().saturating_add(gap) && c.col() <= b.col().saturating_add(gap)
+}
+
+/// Recalc is the outcome of applying the writes of a `FeedBuffer`.
+#[derive(Debug, Clone)]
+pub struct Recalc<T: Arithmetic=f64> {
+    /// The dirty regions the writes were coalesced into.
+    pub regions: Vec<(CellId, CellId)>,
+    /// The new values of the formulas written or depending on the regions,
+    /// row by row.
+    pub values: Vec<(CellId, Value<T>)>,
+}
+
+/// FeedBuffer collects the writes of a streaming feed to a sheet and
+/// applies them at the next scheduled recalc. Repeated writes to a cell are
+/// collapsed to the last, and dirty cells are coalesced into rectangular
+/// regions, so the dependency graph is built and searched once per recalc
+/// rather than once per write.
+#[derive(Debug, Clone, Default)]
+pub struct FeedBuffer {
+    options: CoalesceOptions,
+    pending: HashMap<CellId, String>,
+    regions: Vec<Region>,
+    stats: FeedStats,
+}
+
+impl FeedBuffer {
+    pub fn new(options: CoalesceOptions) -> Self {
+        Self{options, ..Self::default()}
+    }
+
+    pub fn options(&self) -> &CoalesceOptions {
+        &self.options
+    }
+
+    pub fn options_mut(&mut self) -> &mut CoalesceOptions {
+        &mut self.options
+    }
+
+    pub fn stats(&self) -> &FeedStats {
+        &self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = FeedStats::default();
+    }
+
+    /// Get the dirty regions, as top left and bottom right corners.
+    pub fn regions(&self) -> &[(CellId, CellId)] {
+        &self.regions
+    }
+
+    /// Get the number of cells with a pending write.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Whether the pending writes exceed `max_pending`, so the sheet should
+    /// be recalculated without waiting for the schedule.
+    pub fn is_full(&self) -> bool {
+        self.pending.len() > self.options.max_pending
+    }
+
+    /// Queue a write of the raw contents of a cell, and get whether the
+    /// buffer is full.
+    pub fn set_cell(&mut self, cell_id: CellId, data: String) -> bool {
+        self.stats.writes += 1;
+        if self.pending.insert(cell_id, data).is_some() {
+            self.stats.overwritten += 1;
+        }
+        self.mark(cell_id);
+        self.is_full()
+    }
+
+    /// Add a cell to the dirty regions.
+    fn mark(&mut self, cell_id: CellId) {
+        let cell = (cell_id, cell_id);
+        if self.regions.iter().any(|region| near(*region, cell, 0)) {
+            self.stats.coalesced += 1;
+            return;
+        }
+        let nearest = self.regions.iter()
+            .enumerate()
+            .filter(|(_, region)| near(**region, cell, self.options.max_gap))
+            .min_by_key(|(_, region)| area(union(**region, cell)) - area(**region))
+            .map(|(index, _)| index);
+        let Some(mut index) = nearest else {
+            self.regions.push(cell);
+            self.stats.regions_created += 1;
+            if self.regions.len() > self.options.max_regions.max(1) {
+                self.merge_cheapest();
+            }
+            return;
+        };
+        self.stats.coalesced += 1;
+        self.regions[index] = union(self.regions[index], cell);
+        // The grown region may now reach others.
+        while let Some(other) = (0..self.regions.len()).find(|other| *other != index && near(self.regions[*other], self.regions[index], self.options.max_gap)) {
+            let merged = union(self.regions[index], self.regions[other]);
+            self.regions.swap_remove(other);
+            if index == self.regions.len() {
+                index = other;
+            }
+            self.regions[index] = merged;
+            self.stats.merges += 1;
+        }
+    }
+
+    /// Merge the two regions whose union holds the fewest cells outside them.
+    fn merge_cheapest(&mut self) {
+        let mut cheapest = None;
+        for a in 0..self.regions.len() {
+            for b in a + 1..self.regions.len() {
+                let (first, second) = (self.regions[a], self.regions[b]);
+                let waste = area(union(first, second)).saturating_sub(area(first) + area(second));
+                if cheapest.is_none_or(|(_, _, least)| waste < least) {
+                    cheapest = Some((a, b, waste));
+                }
+            }
+        }
+        let Some((a, b, _)) = cheapest else { return };
+        let merged = union(self.regions[a], self.regions[b]);
+        self.regions.swap_remove(b);
+        self.regions[a] = merged;
+        self.stats.merges += 1;
+    }
+
+    /// Apply the pending writes to a sheet and evaluate the formulas they
+    /// affect: those written and those depending on the dirty regions.
+    pub fn recalc<T: Arithmetic>(&mut self, sheet: &mut Worksheet<T>) -> Result<Recalc<T>, SheetError> {
+        let mut written = Vec::with_capacity(self.pending.len());
+        for (cell_id, data) in self.pending.drain() {
+            sheet.set_cell(cell_id, data);
+            written.push(cell_id);
+        }
+        let regions = std::mem::take(&mut self.regions);
+        let mut affected = sheet.dependency_graph().dependents_of_regions(&regions);
+        affected.extend(written.into_iter().filter(|cell_id| sheet.cell(*cell_id).is_some_and(|cell| cell.formula().is_some())));
+        affected.sort_by_key(|cell_id| (cell_id.row(), cell_id.col()));
+        affected.dedup();
+        let values = affected.into_iter()
+            .map(|cell_id| Ok((cell_id, sheet.evaluate_cell(cell_id)?)))
+            .collect::<Result<Vec<_>, SheetError>>()?;
+        self.stats.recalcs += 1;
+        self.stats.cells_recalculated += values.len() as u64;
+        Ok(Recalc{regions, values})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::Primitive;
+
+    #[test]
+    fn writes_coalesce_into_regions_until_the_recalc() {
+        let mut sheet: Worksheet = Worksheet::new();
+        for (cell_id, data) in [("C5", "=SUM(A1:A2)"), ("E5", "=D1*2"), ("F5", "=E5+1"), ("F6", "=Z9")] {
+            sheet.set_cell(CellId::parse(cell_id).unwrap(), data.to_string());
+        }
+        let mut feed = FeedBuffer::new(CoalesceOptions{max_gap: 1, max_regions: 2, max_pending: 3});
+        let mut write = |cell_id: &str, data: &str| feed.set_cell(CellId::parse(cell_id).unwrap(), data.to_string());
+        assert!(!write("A1", "4"));
+        assert!(!write("A1", "5"));
+        assert!(!write("A3", "1"));
+        assert!(!write("D1", "10"));
+        assert!(write("B2", "=A1"));
+        assert_eq!(feed.pending(), 4);
+        assert!(feed.is_full());
+        let region = |first: &str, second: &str| (CellId::parse(first).unwrap(), CellId::parse(second).unwrap());
+        assert_eq!(feed.regions(), [region("A1", "B3"), region("D1", "D1")]);
+
+        let recalc = feed.recalc(&mut sheet).unwrap();
+        assert_eq!(recalc.regions.len(), 2);
+        let values = recalc.values.iter()
+            .map(|(cell_id, value)| match value {
+                Value::Primitive(Primitive::Number(number)) => (cell_id.to_string(), number.value()),
+                _ => panic!("{} is not a number", cell_id),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(values, [("B2".to_string(), 5.0), ("C5".to_string(), 5.0), ("E5".to_string(), 20.0), ("F5".to_string(), 21.0)]);
+        assert_eq!(*feed.stats(), FeedStats{writes: 5, overwritten: 1, coalesced: 2, regions_created: 3, merges: 1, recalcs: 1, cells_recalculated: 4});
+        assert_eq!((feed.pending(), feed.regions().len()), (0, 0));
+        feed.reset_stats();
+        assert_eq!(*feed.stats(), FeedStats::default());
+    }
+}
@@ -0,0 +1,770 @@
+use super::arithmetic::Arithmetic;
+use super::currency::Currency;
+use super::kernel::{NumericAttribute, Primitive, Value};
+use chrono::{Datelike, Timelike};
+
+/// Locale holds the conventions numbers and dates are displayed with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Locale {
+    pub decimal_separator: char,
+    pub thousands_separator: char,
+    pub currency_symbol: String,
+    /// Whether the currency symbol goes before the amount.
+    pub currency_before: bool,
+    /// The format code of dates displayed with the `General` format.
+    pub date_format: String,
+}
+
+impl Default for Locale {
+    fn default() -> Self {
+        Self::en_us()
+    }
+}
+
+impl Locale {
+    pub fn en_us() -> Self {
+        Self{
+            decimal_separator: '.',
+            thousands_separator: ',',
+            currency_symbol: "$".to_string(),
+            currency_before: true,
+            date_format: "m/d/yyyy".to_string(),
+        }
+    }
+
+    pub fn en_gb() -> Self {
+        Self{currency_symbol: "£".to_string(), date_format: "dd/mm/yyyy".to_string(), ..Self::en_us()}
+    }
+
+    pub fn de_de() -> Self {
+        Self{
+            decimal_separator: ',',
+            thousands_separator: '.',
+            currency_symbol: "€".to_string(),
+            currency_before: false,
+            date_format: "dd.mm.yyyy".to_string(),
+        }
+    }
+
+    pub fn fr_fr() -> Self {
+        Self{
+            decimal_separator: ',',
+            thousands_separator: '\u{202F}',
+            currency_symbol: "€".to_string(),
+            currency_before: false,
+            date_format: "dd/mm/yyyy".to_string(),
+        }
+    }
+
+    pub fn ja_jp() -> Self {
+        Self{currency_symbol: "¥".to_string(), date_format: "yyyy/m/d".to_string(), ..Self::en_us()}
+    }
+
+    /// Get the format code of amounts in the currency of this locale, with
+    /// as many decimals as its minor unit has.
+    pub fn currency_format(&self) -> String {
+        let symbol = format!("\"{}\"", self.currency_symbol);
+        let amount = placeholders(minor_units(&self.currency_symbol), true);
+        if self.currency_before {
+            format!("{}{};-{}{}", symbol, amount, symbol, amount)
+        } else {
+            format!("{} {};-{} {}", amount, symbol, amount, symbol)
+        }
+    }
+}
+
+const MONTHS: [&str; 12] = [
+    "January", "February", "March", "April", "May", "June",
+    "July", "August", "September", "October", "November", "December",
+];
+
+const WEEKDAYS: [&str; 7] = ["Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday", "Sunday"];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// A digit placeholder: `0` pads with zeros, `#` shows only significant
+    /// digits and `?` pads with spaces.
+    Digit(char),
+    Point,
+    Comma,
+    Percent,
+    /// Scientific notation, with whether the sign of positive exponents is
+    /// shown.
+    Exponent(bool),
+    Literal(String),
+    /// The text of the value, `@`.
+    Text,
+    Year(usize),
+    Month(usize),
+    Day(usize),
+    Hour(usize),
+    Minute(usize),
+    Second(usize),
+    /// Elapsed hours, `[h]`.
+    ElapsedHours,
+    AmPm(bool),
+}
+
+fn tokenize(section: &str) -> Vec<Token> {
+    let chars = section.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    let run = |i: usize, c: char| chars[i..].iter().take_while(|d| d.eq_ignore_ascii_case(&c)).count();
+    while i < chars.len() {
+        let c = chars[i];
+        let upper = chars[i..].iter().collect::<String>().to_ascii_uppercase();
+        match c {
+            '0' | '#' | '?' => tokens.push(Token::Digit(c)),
+            '.' => tokens.push(Token::Point),
+            ',' => tokens.push(Token::Comma),
+            '%' => tokens.push(Token::Percent),
+            '@' => tokens.push(Token::Text),
+            'E' | 'e' if matches!(chars.get(i + 1), Some('+') | Some('-')) => {
+                tokens.push(Token::Exponent(chars[i + 1] == '+'));
+                i += 1;
+            },
+            '"' => {
+                let literal = chars[i + 1..].iter().take_while(|c| **c != '"').collect::<String>();
+                i += literal.chars().count() + 1;
+                tokens.push(Token::Literal(literal));
+            },
+            '\\' => {
+                if let Some(c) = chars.get(i + 1) {
+                    tokens.push(Token::Literal(c.to_string()));
+                }
+                i += 1;
+            },
+            // A space as wide as the next character.
+            '_' => {
+                tokens.push(Token::Literal(" ".to_string()));
+                i += 1;
+            },
+            // Repeat the next character to fill the cell, which has no
+            // width here.
+            '*' => i += 1,
+            '[' => {
+                let tag = chars[i + 1..].iter().take_while(|c| **c != ']').collect::<String>();
+                i += tag.chars().count() + 1;
+                if tag.eq_ignore_ascii_case("h") || tag.eq_ignore_ascii_case("hh") {
+                    tokens.push(Token::ElapsedHours);
+                } else if let Some(currency) = tag.strip_prefix('$') {
+                    let symbol = currency.split('-').next().unwrap_or("");
+                    if !symbol.is_empty() {
+                        tokens.push(Token::Literal(symbol.to_string()));
+                    }
+                }
+                // Colors and conditions are not rendered.
+            },
+            _ if upper.starts_with("AM/PM") => {
+                tokens.push(Token::AmPm(true));
+                i += 4;
+            },
+            _ if upper.starts_with("A/P") => {
+                tokens.push(Token::AmPm(false));
+                i += 2;
+            },
+            'y' | 'Y' => {
+                let n = run(i, 'y');
+                tokens.push(Token::Year(n));
+                i += n - 1;
+            },
+            'm' | 'M' => {
+                let n = run(i, 'm');
+                tokens.push(Token::Month(n));
+                i += n - 1;
+            },
+            'd' | 'D' => {
+                let n = run(i, 'd');
+                tokens.push(Token::Day(n));
+                i += n - 1;
+            },
+            'h' | 'H' => {
+                let n = run(i, 'h');
+                tokens.push(Token::Hour(n));
+                i += n - 1;
+            },
+            's' | 'S' => {
+                let n = run(i, 's');
+                tokens.push(Token::Second(n));
+                i += n - 1;
+            },
+            c => tokens.push(Token::Literal(c.to_string())),
+        }
+        i += 1;
+    }
+    resolve_minutes(&mut tokens);
+    tokens
+}
+
+/// `m` means minutes right after hours or right before seconds, and months
+/// everywhere else.
+fn resolve_minutes(tokens: &mut [Token]) {
+    let parts = tokens.iter().enumerate()
+        .filter(|(_, token)| matches!(token, Token::Hour(_) | Token::ElapsedHours | Token::Minute(_) | Token::Month(_) | Token::Second(_)))
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+    for (position, index) in parts.iter().enumerate() {
+        let Token::Month(n) = tokens[*index] else { continue };
+        if n > 2 {
+            continue;
+        }
+        let after_hour = position > 0 && matches!(tokens[parts[position - 1]], Token::Hour(_) | Token::ElapsedHours);
+        let before_second = parts.get(position + 1).is_some_and(|next| matches!(tokens[*next], Token::Second(_)));
+        if after_hour || before_second {
+            tokens[*index] = Token::Minute(n);
+        }
+    }
+}
+
+/// Split a format code into its sections at semicolons outside quotes.
+fn sections(code: &str) -> Vec<&str> {
+    let mut sections = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (index, c) in code.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                sections.push(&code[start..index]);
+                start = index + 1;
+            },
+            _ => {},
+        }
+    }
+    sections.push(&code[start..]);
+    sections
+}
+
+fn is_date_format(tokens: &[Token]) -> bool {
+    tokens.iter().any(|token| matches!(token,
+        Token::Year(_) | Token::Month(_) | Token::Day(_) | Token::Hour(_)
+            | Token::Minute(_) | Token::Second(_) | Token::ElapsedHours | Token::AmPm(_)))
+}
+
+/// Whether a format code shows numbers as dates or times in any section.
+pub fn is_date_code(code: &str) -> bool {
+    sections(code).iter().any(|section| is_date_format(&tokenize(section)))
+}
+
+/// Whether a section formats fractions like `# ?/?`, which are shown in the
+/// `General` format instead.
+fn is_fraction_format(tokens: &[Token]) -> bool {
+    tokens.windows(2).any(|pair| matches!(pair, [Token::Digit(_), Token::Literal(slash)] if slash == "/"))
+}
+
+/// Group the digits of an integer with a separator every three digits.
+fn group(digits: &str, separator: char) -> String {
+    let mut grouped = String::new();
+    for (index, c) in digits.chars().enumerate() {
+        if index > 0 && (digits.len() - index).is_multiple_of(3) {
+            grouped.push(separator);
+        }
+        grouped.push(c);
+    }
+    grouped
+}
+
+/// Format a number like the `General` format: integers in full, other
+/// numbers with up to ten significant digits, and very large or small
+/// numbers in scientific notation.
+fn general(number: f64, locale: &Locale) -> String {
+    if !number.is_finite() {
+        return "#NUM!".to_string();
+    }
+    let magnitude = number.abs();
+    let text = if magnitude != 0.0 && !(1e-9..1e11).contains(&magnitude) {
+        let formatted = format!("{:.5E}", number);
+        let (mantissa, exponent) = formatted.split_once('E').unwrap_or((&formatted, "0"));
+        let mantissa = mantissa.trim_end_matches('0').trim_end_matches('.');
+        let exponent = exponent.parse::<i32>().unwrap_or(0);
+        format!("{}E{}{:02}", mantissa, if exponent < 0 { '-' } else { '+' }, exponent.abs())
+    } else {
+        let integer_digits = if magnitude < 1.0 { 1 } else { magnitude.log10().floor() as usize + 1 };
+        let decimals = 10usize.saturating_sub(integer_digits);
+        let formatted = format!("{:.*}", decimals, number);
+        if formatted.contains('.') {
+            formatted.trim_end_matches('0').trim_end_matches('.').to_string()
+        } else {
+            formatted
+        }
+    };
+    let text = if text == "-0" { "0".to_string() } else { text };
+    text.replace('.', &locale.decimal_separator.to_string())
+}
+
+/// Format the magnitude of a number with a number of decimals the way
+/// spreadsheets round it: to 15 significant digits, then half away from
+/// zero, so 2.675 shows as 2.68 although it is stored as 2.67499….
+fn fixed(number: f64, decimals: usize) -> String {
+    let number = number.abs();
+    if !number.is_finite() || number == 0.0 {
+        return format!("{:.*}", decimals, number);
+    }
+    let scientific = format!("{:.14e}", number);
+    let (mantissa, exponent) = scientific.split_once('e').expect("scientific notation has an exponent");
+    let mut exponent = exponent.parse::<i32>().expect("the exponent is an integer");
+    let significant = mantissa.bytes().filter(u8::is_ascii_digit).map(|b| b - b'0').collect::<Vec<_>>();
+    // The first digit is in the place of 10^exponent.
+    let keep = exponent + 1 + decimals as i32;
+    let mut digits = significant[..keep.clamp(0, 15) as usize].to_vec();
+    if (0..15).contains(&keep) && significant[keep as usize] >= 5 {
+        match digits.iter().rposition(|digit| *digit < 9) {
+            Some(index) => {
+                digits[index] += 1;
+                digits[index + 1..].fill(0);
+            },
+            None => {
+                digits.fill(0);
+                digits.insert(0, 1);
+                exponent += 1;
+            },
+        }
+    }
+    let digit = |place: i32| match exponent - place {
+        index if index >= 0 && (index as usize) < digits.len() => (b'0' + digits[index as usize]) as char,
+        _ => '0',
+    };
+    let mut text = (0..=exponent.max(0)).rev().map(digit).collect::<String>();
+    if decimals > 0 {
+        text.push('.');
+        text.extend((1..=decimals as i32).map(|place| digit(-place)));
+    }
+    text
+}
+
+/// Render the magnitude of a number with the tokens of a numeric section.
+fn render_number(number: f64, tokens: &[Token], locale: &Locale) -> String {
+    let mut number = number;
+    let percents = tokens.iter().filter(|token| **token == Token::Percent).count();
+    number *= 100f64.powi(percents as i32);
+
+    let exponent_at = tokens.iter().position(|token| matches!(token, Token::Exponent(_)));
+    let mantissa_tokens = &tokens[..exponent_at.unwrap_or(tokens.len())];
+    let point = mantissa_tokens.iter().position(|token| *token == Token::Point);
+    let (integer_tokens, decimal_tokens) = match point {
+        Some(point) => (&mantissa_tokens[..point], &mantissa_tokens[point + 1..]),
+        None => (mantissa_tokens, &[][..]),
+    };
+
+    // Commas right after the last integer placeholder scale by thousands,
+    // commas between placeholders turn on grouping.
+    let last_digit = integer_tokens.iter().rposition(|token| matches!(token, Token::Digit(_)));
+    let first_digit = integer_tokens.iter().position(|token| matches!(token, Token::Digit(_)));
+    let mut grouping = false;
+    if let (Some(first), Some(last)) = (first_digit, last_digit) {
+        grouping = integer_tokens[first..last].contains(&Token::Comma);
+        let scaling = integer_tokens[last + 1..].iter().take_while(|token| **token == Token::Comma).count();
+        number /= 1000f64.powi(scaling as i32);
+    }
+
+    let decimal_places = decimal_tokens.iter().filter(|token| matches!(token, Token::Digit(_))).count();
+    let mut exponent = 0;
+    if exponent_at.is_some() && number != 0.0 {
+        let integer_places = integer_tokens.iter().filter(|token| matches!(token, Token::Digit(_))).count().max(1) as i32;
+        exponent = number.abs().log10().floor() as i32 - (integer_places - 1);
+        number /= 10f64.powi(exponent);
+        // Rounding may carry into another digit.
+        if fixed(number, decimal_places).len() > fixed(9.0 * 10f64.powi(integer_places - 1), decimal_places).len() {
+            exponent += 1;
+            number /= 10.0;
+        }
+    }
+
+    let formatted = fixed(number, decimal_places);
+    let (integer, fraction) = formatted.split_once('.').unwrap_or((&formatted, ""));
+    let integer = integer.trim_start_matches('0');
+
+    // Fill integer placeholders right to left, the first one taking any
+    // digits left over.
+    let digits = integer.chars().collect::<Vec<_>>();
+    let placeholders = integer_tokens.iter().filter(|token| matches!(token, Token::Digit(_))).count();
+    let mut integer_text = String::new();
+    let mut placeholder = 0;
+    for token in integer_tokens {
+        match token {
+            Token::Digit(kind) => {
+                let from_right = placeholders - placeholder;
+                let shown = if placeholder == 0 {
+                    digits[..digits.len().saturating_sub(from_right - 1)].iter().collect::<String>()
+                } else if digits.len() >= from_right {
+                    digits[digits.len() - from_right].to_string()
+                } else {
+                    String::new()
+                };
+                integer_text.push_str(if shown.is_empty() { pad(*kind) } else { &shown });
+                placeholder += 1;
+            },
+            Token::Comma => {},
+            token => integer_text.push_str(&literal(token)),
+        }
+    }
+    let mut output = if grouping {
+        group_in_place(&integer_text, locale.thousands_separator)
+    } else {
+        integer_text
+    };
+
+    if point.is_some() {
+        let mut fraction_digits = fraction.chars().collect::<Vec<_>>();
+        // Drop trailing zeros shown by optional placeholders.
+        let kinds = decimal_tokens.iter().filter_map(|token| match token {
+            Token::Digit(kind) => Some(*kind),
+            _ => None,
+        }).collect::<Vec<_>>();
+        for (index, kind) in kinds.iter().enumerate().rev() {
+            if fraction_digits[index] != '0' || *kind == '0' {
+                break;
+            }
+            fraction_digits[index] = if *kind == '?' { ' ' } else { '\0' };
+        }
+        output.push(locale.decimal_separator);
+        let mut next = 0;
+        for token in decimal_tokens {
+            match token {
+                Token::Digit(_) => {
+                    if fraction_digits[next] != '\0' {
+                        output.push(fraction_digits[next]);
+                    }
+                    next += 1;
+                },
+                Token::Comma => {},
+                token => output.push_str(&literal(token)),
+            }
+        }
+    }
+
+    if let Some(at) = exponent_at {
+        let Token::Exponent(plus) = tokens[at] else { unreachable!() };
+        let exponent_tokens = &tokens[at + 1..];
+        let width = exponent_tokens.iter().filter(|token| matches!(token, Token::Digit(_))).count();
+        output.push('E');
+        if exponent < 0 {
+            output.push('-');
+        } else if plus {
+            output.push('+');
+        }
+        output.push_str(&format!("{:0width$}", exponent.abs(), width = width));
+        for token in exponent_tokens.iter().filter(|token| !matches!(token, Token::Digit(_))) {
+            output.push_str(&literal(token));
+        }
+    }
+    output
+}
+
+/// Get what an empty digit placeholder shows.
+fn pad(kind: char) -> &'static str {
+    match kind {
+        '0' => "0",
+        '?' => " ",
+        _ => "",
+    }
+}
+
+fn literal(token: &Token) -> String {
+    match token {
+        Token::Literal(text) => text.clone(),
+        Token::Percent => "%".to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Insert thousands separators between the digits of rendered integer text,
+/// leaving literals in place.
+fn group_in_place(text: &str, separator: char) -> String {
+    let start = text.find(|c: char| c.is_ascii_digit());
+    let end = text.rfind(|c: char| c.is_ascii_digit()).map(|end| end + 1);
+    match (start, end) {
+        (Some(start), Some(end)) if text[start..end].chars().all(|c| c.is_ascii_digit()) => {
+            format!("{}{}{}", &text[..start], group(&text[start..end], separator), &text[end..])
+        },
+        _ => text.to_string(),
+    }
+}
+
+/// Convert a spreadsheet serial number, the days since 1899-12-30, to a
+/// date and time. Dates are the days since 1899-12-31 before serial 61, see
+/// `to_serial`, which this does not correct for.
+pub(crate) fn from_serial(serial: f64) -> Option<chrono::NaiveDateTime> {
+    let epoch = chrono::NaiveDate::from_ymd_opt(1899, 12, 30)?.and_hms_opt(0, 0, 0)?;
+    let millis = (serial * 86_400_000.0).round();
+    if !millis.is_finite() || millis.abs() > 1e15 {
+        return None;
+    }
+    epoch.checked_add_signed(chrono::TimeDelta::milliseconds(millis as i64))
+}
+
+/// Get the serial number of a date. Spreadsheets count 1900 as a leap
+/// year, as Lotus 1-2-3 did, so serial 60 is 1900-02-29 and earlier dates
+/// are a day later than the days since 1899-12-30.
+pub(crate) fn to_serial(date: chrono::NaiveDate) -> f64 {
+    let epoch = chrono::NaiveDate::from_ymd_opt(1899, 12, 30).expect("the epoch is a date");
+    let days = (date - epoch).num_days();
+    match days {
+        1..=60 => days as f64 - 1.0,
+        days => days as f64,
+    }
+}
+
+fn render_date(serial: f64, tokens: &[Token]) -> String {
+    let Some(datetime) = from_serial(serial) else { return "#NUM!".to_string() };
+    // Weekdays follow the serials, so only the dates before serial 61 move.
+    let (year, month, day) = match serial {
+        serial if (1.0..60.0).contains(&serial) => match from_serial(serial + 1.0) {
+            Some(shifted) => (shifted.year(), shifted.month(), shifted.day()),
+            None => return "#NUM!".to_string(),
+        },
+        serial if (60.0..61.0).contains(&serial) => (1900, 2, 29),
+        _ => (datetime.year(), datetime.month(), datetime.day()),
+    };
+    let twelve_hour = tokens.iter().any(|token| matches!(token, Token::AmPm(_)));
+    let mut output = String::new();
+    for token in tokens {
+        match token {
+            Token::Year(n) if *n <= 2 => output.push_str(&format!("{:02}", year % 100)),
+            Token::Year(_) => output.push_str(&format!("{:04}", year)),
+            Token::Month(1) => output.push_str(&month.to_string()),
+            Token::Month(2) => output.push_str(&format!("{:02}", month)),
+            Token::Month(3) => output.push_str(&MONTHS[month as usize - 1][..3]),
+            Token::Month(5) => output.push_str(&MONTHS[month as usize - 1][..1]),
+            Token::Month(_) => output.push_str(MONTHS[month as usize - 1]),
+            Token::Day(1) => output.push_str(&day.to_string()),
+            Token::Day(2) => output.push_str(&format!("{:02}", day)),
+            Token::Day(3) => output.push_str(&WEEKDAYS[datetime.weekday().num_days_from_monday() as usize][..3]),
+            Token::Day(_) => output.push_str(WEEKDAYS[datetime.weekday().num_days_from_monday() as usize]),
+            Token::Hour(n) => {
+                let hour = match twelve_hour {
+                    true if datetime.hour() % 12 == 0 => 12,
+                    true => datetime.hour() % 12,
+                    false => datetime.hour(),
+                };
+                output.push_str(&if *n >= 2 { format!("{:02}", hour) } else { hour.to_string() });
+            },
+            Token::ElapsedHours => output.push_str(&((serial * 24.0).floor() as i64).to_string()),
+            Token::Minute(n) if *n >= 2 => output.push_str(&format!("{:02}", datetime.minute())),
+            Token::Minute(_) => output.push_str(&datetime.minute().to_string()),
+            Token::Second(n) if *n >= 2 => output.push_str(&format!("{:02}", datetime.second())),
+            Token::Second(_) => output.push_str(&datetime.second().to_string()),
+            Token::AmPm(full) => {
+                let pm = datetime.hour() >= 12;
+                output.push_str(match (full, pm) {
+                    (true, false) => "AM",
+                    (true, true) => "PM",
+                    (false, false) => "A",
+                    (false, true) => "P",
+                });
+            },
+            Token::Point => output.push('.'),
+            Token::Comma => output.push(','),
+            Token::Digit(c) => output.push(*c),
+            token => output.push_str(&literal(token)),
+        }
+    }
+    output
+}
+
+fn render_text(text: &str, tokens: &[Token]) -> String {
+    if !tokens.contains(&Token::Text) {
+        return tokens.iter().map(literal).collect();
+    }
+    tokens.iter()
+        .map(|token| match token {
+            Token::Text => text.to_string(),
+            token => literal(token),
+        })
+        .collect()
+}
+
+/// Format a number with a format code.
+fn format_number(number: f64, code: &str, locale: &Locale) -> String {
+    let sections = sections(code);
+    let numeric = sections.iter().take(3).copied().collect::<Vec<_>>();
+    let (section, signed) = match numeric.len() {
+        1 => (numeric[0], true),
+        2 if number < 0.0 => (numeric[1], false),
+        2 => (numeric[0], true),
+        _ if number < 0.0 => (numeric[1], false),
+        _ if number == 0.0 => (numeric[2], true),
+        _ => (numeric[0], true),
+    };
+    if section.eq_ignore_ascii_case("General") || section.is_empty() && sections.len() == 1 {
+        return general(number, locale);
+    }
+    let tokens = tokenize(section);
+    if is_date_format(&tokens) {
+        return render_date(number, &tokens);
+    }
+    if !tokens.iter().any(|token| matches!(token, Token::Digit(_))) {
+        let text = render_text(&general(number.abs(), locale), &tokens);
+        return if signed && number < 0.0 { format!("-{}", text) } else { text };
+    }
+    if is_fraction_format(&tokens) {
+        return general(number, locale);
+    }
+    let rendered = render_number(number, &tokens, locale);
+    // A negative number rounded to zero shows no sign.
+    let nonzero = rendered.chars().any(|c| c.is_ascii_digit() && c != '0');
+    if signed && number < 0.0 && nonzero {
+        format!("-{}", rendered)
+    } else {
+        rendered
+    }
+}
+
+/// Get the decimals of a currency, 2 for unknown ones.
+fn minor_units(currency: &str) -> usize {
+    Currency::lookup(currency).map_or(2, |currency| currency.minor_units as usize)
+}
+
+/// Get the digit placeholders of a number with `decimals` decimals,
+/// optionally grouped.
+fn placeholders(decimals: usize, grouped: bool) -> String {
+    let whole = if grouped { "#,##0" } else { "0" };
+    match decimals {
+        0 => whole.to_string(),
+        decimals => format!("{}.{}", whole, "0".repeat(decimals)),
+    }
+}
+
+/// Infer the number format of a value entered as `raw`, so the value keeps
+/// looking the way it was typed once exported with formats: `12.5%` gets
+/// `0.0%`, `$1,200` gets `"$"#,##0` and `2024-03-01` gets `yyyy-mm-dd`.
+/// Values which `General` shows as typed infer no format.
+pub fn infer_number_format<T: Arithmetic>(raw: &str, value: &Value<T>) -> Option<String> {
+    let raw = raw.trim();
+    let unsigned = raw.trim_start_matches('-');
+    let decimals = unsigned.split_once('.')
+        .map_or(0, |(_, fraction)| fraction.chars().take_while(char::is_ascii_digit).count());
+    let Value::Primitive(primitive) = value else { return None };
+    match primitive {
+        Primitive::Number(number) => match number.attr() {
+            Some(NumericAttribute::Percent) => Some(format!("{}%", placeholders(decimals, false))),
+            Some(NumericAttribute::Currency(symbol)) => {
+                let amount = placeholders(decimals, true);
+                match unsigned.starts_with(symbol.as_str()) {
+                    true => Some(format!("\"{}\"{}", symbol, amount)),
+                    false => Some(format!("{} \"{}\"", amount, symbol)),
+                }
+            },
+            #[cfg(feature = "units")]
+            Some(NumericAttribute::Unit(_)) => None,
+            None if raw.contains(',') => Some(placeholders(decimals, true)),
+            None => None,
+        },
+        Primitive::Date(_) if raw.contains('-') => Some("yyyy-mm-dd".to_string()),
+        Primitive::Date(_) => Some("m/d/yyyy".to_string()),
+        Primitive::Time(_) if raw.matches(':').count() == 2 => Some("h:mm:ss".to_string()),
+        Primitive::Time(_) => Some("h:mm".to_string()),
+        _ => None,
+    }
+}
+
+/// Format a value the way a cell with the format code `code` displays it,
+/// using the separators and currency conventions of `locale`. Format codes
+/// follow spreadsheet number formats: up to four sections for positive,
+/// negative, zero and text values; digit placeholders `0`, `#` and `?`;
+/// grouping and scaling commas; percent; scientific notation; quoted and
+/// escaped literals; `[$€-407]` currency tags; and date and time codes.
+/// Colors and conditions are accepted but not rendered, fractions are shown
+/// in `General`, and month and day names are English.
+pub fn format_value<T: Arithmetic>(value: &Value<T>, code: &str, locale: &Locale) -> String {
+    let is_general = code.trim().is_empty() || code.eq_ignore_ascii_case("General");
+    match value {
+        Value::Empty | Value::Raw | Value::Formula(_) => String::new(),
+        Value::FormulaParseError(_) => "#NAME?".to_string(),
+        Value::Error(e) => e.to_string(),
+        // An array shows its top left value where it is not spilled.
+        Value::Array(array) => array.get(0, 0).map(|value| format_value(value, code, locale)).unwrap_or_default(),
+        Value::Primitive(primitive) => match primitive {
+            Primitive::Bool(b) => if *b { "TRUE" } else { "FALSE" }.to_string(),
+            Primitive::IPAddress([a, b, c, d]) => format!("{}.{}.{}.{}", a, b, c, d),
+            Primitive::Text(text) => text.clone(),
+            Primitive::Number(number) if is_general => match number.attr() {
+                Some(NumericAttribute::Percent) => format!("{}%", general(number.number().to_f64(), locale)),
+                Some(NumericAttribute::Currency(symbol)) => {
+                    let amount = placeholders(minor_units(symbol), true);
+                    // Codes like `USD` go after the amount, as in `5.00 USD`.
+                    let before = locale.currency_before && !symbol.chars().all(char::is_alphabetic);
+                    let symbol = format!("\"{}\"", symbol);
+                    let code = match before {
+                        true => format!("{}{}", symbol, amount),
+                        false => format!("{} {}", amount, symbol),
+                    };
+                    format_number(number.value().to_f64(), &code, locale)
+                },
+                #[cfg(feature = "units")]
+                Some(NumericAttribute::Unit(unit)) => format!("{} {}", general(number.number().to_f64(), locale), unit),
+                None => general(number.value().to_f64(), locale),
+            },
+            Primitive::Number(number) => format_number(number.value().to_f64(), code, locale),
+            Primitive::Date(date) => {
+                let serial = to_serial(*date);
+                format_number(serial, if is_general { &locale.date_format } else { code }, locale)
+            },
+            Primitive::Time(time) => {
+                let serial = time.num_milliseconds() as f64 / 86_400_000.0;
+                format_number(serial, if is_general { "h:mm:ss" } else { code }, locale)
+            },
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(number: f64, code: &str) -> String {
+        format_value::<f64>(&Value::from(number.to_string().as_str()), code, &Locale::default())
+    }
+
+    #[test]
+    fn rounds_half_away_from_zero() {
+        assert_eq!(format(0.5, "0"), "1");
+        assert_eq!(format(1.5, "0"), "2");
+        assert_eq!(format(2.5, "0"), "3");
+        assert_eq!(format(-2.5, "0"), "-3");
+        assert_eq!(format(2.675, "0.00"), "2.68");
+        assert_eq!(format(1.005, "0.00"), "1.01");
+        assert_eq!(format(0.125, "0.00"), "0.13");
+        assert_eq!(format(9.995, "0.00"), "10.00");
+        assert_eq!(format(0.0049, "0.00"), "0.00");
+        assert_eq!(format(1234.5, "#,##0"), "1,235");
+        assert_eq!(format(0.125, "0.0%"), "12.5%");
+        assert_eq!(format(99950.0, "0.0E+00"), "1.0E+05");
+    }
+
+    #[test]
+    fn counts_1900_as_a_leap_year() {
+        assert_eq!(format(1.0, "yyyy-mm-dd"), "1900-01-01");
+        assert_eq!(format(59.0, "yyyy-mm-dd"), "1900-02-28");
+        assert_eq!(format(60.0, "yyyy-mm-dd ddd"), "1900-02-29 Wed");
+        assert_eq!(format(61.0, "yyyy-mm-dd"), "1900-03-01");
+        assert_eq!(format(45000.0, "yyyy-mm-dd"), "2023-03-15");
+        let date = |year, month, day| chrono::NaiveDate::from_ymd_opt(year, month, day).unwrap();
+        assert_eq!(to_serial(date(1900, 1, 1)), 1.0);
+        assert_eq!(to_serial(date(1900, 2, 28)), 59.0);
+        assert_eq!(to_serial(date(1900, 3, 1)), 61.0);
+        assert_eq!(format_value::<f64>(&Value::from("1900-01-01"), "d mmm yyyy", &Locale::default()), "1 Jan 1900");
+    }
+
+    fn inferred(raw: &str) -> Option<String> {
+        infer_number_format::<f64>(raw, &Value::from(raw))
+    }
+
+    #[test]
+    fn formats_are_inferred_from_how_values_were_typed() {
+        assert_eq!(inferred("12.5%").as_deref(), Some("0.0%"));
+        assert_eq!(inferred("-50%").as_deref(), Some("0%"));
+        assert_eq!(inferred("$1,200").as_deref(), Some("\"$\"#,##0"));
+        assert_eq!(inferred("$1,200.50").as_deref(), Some("\"$\"#,##0.00"));
+        assert_eq!(inferred("1,200.5").as_deref(), Some("#,##0.0"));
+        assert_eq!(inferred("2024-03-01").as_deref(), Some("yyyy-mm-dd"));
+        assert_eq!(inferred("12:30").as_deref(), Some("h:mm"));
+        assert_eq!(inferred("12:30:15").as_deref(), Some("h:mm:ss"));
+        assert_eq!(inferred("1200.5"), None);
+        assert_eq!(inferred("TRUE"), None);
+        assert_eq!(inferred("text"), None);
+    }
+}
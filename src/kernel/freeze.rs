@@ -0,0 +1,197 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{CellError, CellId, Kernel, Value};
+use super::settings::Precision;
+use super::workbook::{Workbook, WorkbookError};
+use super::worksheet::{SheetError, Worksheet};
+
+/// FrozenFormula is where the value of a frozen cell came from, kept so
+/// archived models still tell how their numbers were computed, and so
+/// freezing can be undone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrozenFormula {
+    /// The raw contents of the cell before it was frozen, like
+    /// `=SUM(A1:A3)`.
+    pub formula: String,
+    /// The cells the dynamic array of the formula spilled into, which were
+    /// frozen with it.
+    pub spilled: Vec<CellId>,
+}
+
+/// Frozen is a formula cell of a range to freeze, with its value and the
+/// values its dynamic array spills.
+struct Frozen<T: Arithmetic> {
+    cell_id: CellId,
+    value: Value<T>,
+    spilled: Vec<(CellId, Value<T>)>,
+}
+
+/// Get the corners of the rectangle between two cells.
+fn corners(first: CellId, second: CellId) -> (CellId, CellId) {
+    (
+        CellId::new(first.row().min(second.row()), first.col().min(second.col())),
+        CellId::new(first.row().max(second.row()), first.col().max(second.col())),
+    )
+}
+
+/// Evaluate the formulas of a sheet between two corners, with `value` for
+/// the value of a cell and `array` for its whole dynamic array.
+fn evaluate<T, E>(
+    sheet: &Worksheet<T>,
+    first: CellId,
+    second: CellId,
+    value: impl Fn(CellId) -> Result<Value<T>, E>,
+    array: impl Fn(CellId) -> Result<Value<T>, E>,
+) -> Result<Vec<Frozen<T>>, E>
+where T: Arithmetic {
+    let (start, end) = corners(first, second);
+    let inside = |cell_id: CellId| (start.row()..=end.row()).contains(&cell_id.row()) && (start.col()..=end.col()).contains(&cell_id.col());
+    let mut formulas = sheet.cells()
+        .filter(|(cell_id, cell)| inside(*cell_id) && cell.formula().is_some())
+        .map(|(cell_id, _)| cell_id)
+        .collect::<Vec<_>>();
+    formulas.sort_by_key(|cell_id| (cell_id.row(), cell_id.col()));
+    // Every cell is evaluated before any is frozen, as they may refer to
+    // each other.
+    let mut frozen = Vec::new();
+    for cell_id in formulas {
+        let value = value(cell_id)?;
+        let mut spilled = Vec::new();
+        if let (Value::Array(array), false) = (array(cell_id)?, matches!(value, Value::Error(CellError::Spill))) {
+            for row in 0..array.rows() {
+                for col in 0..array.cols() {
+                    let target = CellId::new(cell_id.row() + row as u32, cell_id.col() + col as u32);
+                    if target != cell_id {
+                        spilled.push((target, array.get(row, col).cloned().unwrap_or(Value::Empty)));
+                    }
+                }
+            }
+        }
+        frozen.push(Frozen{cell_id, value, spilled});
+    }
+    Ok(frozen)
+}
+
+impl<T: Arithmetic> Worksheet<T> {
+    /// Convert the formulas between two corners into the values they have
+    /// now, like pasting values over them, so they are no longer
+    /// recalculated, such as when archiving a model. The cells dynamic
+    /// arrays spill into get the values they display. Every frozen cell
+    /// remembers its formula, see `frozen_formula`, and
+    /// `unfreeze_formulas` puts them back. Get the number of formulas
+    /// frozen.
+    pub fn freeze_formulas(&mut self, first: CellId, second: CellId) -> Result<usize, SheetError> {
+        let frozen = evaluate(self, first, second, |cell_id| self.evaluate_cell(cell_id), |cell_id| self.evaluate_formula(self, cell_id))?;
+        let precision = self.settings().precision;
+        Ok(self.freeze(frozen, precision))
+    }
+
+    fn freeze(&mut self, frozen: Vec<Frozen<T>>, precision: Precision) -> usize {
+        let count = frozen.len();
+        for Frozen{cell_id, value, spilled} in frozen {
+            let formula = self.cell(cell_id).map(|cell| cell.raw().to_string()).unwrap_or_default();
+            let spilled = spilled.into_iter().map(|(target, value)| {
+                self.set_value_with(target, value, precision);
+                target
+            }).collect();
+            self.set_value_with(cell_id, value, precision);
+            self.frozen_mut().insert(cell_id, FrozenFormula{formula, spilled});
+        }
+        count
+    }
+
+    /// Put back the formulas of the frozen cells between two corners, and
+    /// empty the cells their dynamic arrays spilled into so they spill
+    /// again. Cells changed since they were frozen are left as they are.
+    /// Get the number of formulas put back.
+    pub fn unfreeze_formulas(&mut self, first: CellId, second: CellId) -> usize {
+        let (start, end) = corners(first, second);
+        let inside = |cell_id: CellId| (start.row()..=end.row()).contains(&cell_id.row()) && (start.col()..=end.col()).contains(&cell_id.col());
+        let mut frozen = self.frozen_formulas().filter(|(cell_id, _)| inside(*cell_id)).map(|(cell_id, frozen)| (cell_id, frozen.clone())).collect::<Vec<_>>();
+        frozen.sort_by_key(|(cell_id, _)| (cell_id.row(), cell_id.col()));
+        for (cell_id, FrozenFormula{formula, spilled}) in &frozen {
+            for target in spilled {
+                self.clear_cell(*target);
+            }
+            self.set_cell(*cell_id, formula.clone());
+        }
+        frozen.len()
+    }
+}
+
+impl<T: Arithmetic> Workbook<T> {
+    /// Convert the formulas of a sheet between two corners into their
+    /// values, see `Worksheet::freeze_formulas`, evaluating references to
+    /// other sheets. Numbers are written in the precision of the settings
+    /// of the workbook.
+    pub fn freeze_formulas(&mut self, sheet: &str, first: CellId, second: CellId) -> Result<usize, WorkbookError> {
+        let own = self.sheet(sheet).ok_or_else(|| WorkbookError::UnknownSheet(sheet.to_string()))?;
+        let frozen = evaluate(own, first, second, |cell_id| self.evaluate_cell(sheet, cell_id), |cell_id| self.evaluate_array(sheet, cell_id))?;
+        let precision = self.settings().precision;
+        Ok(self.sheet_mut(sheet).expect("sheet exists").freeze(frozen, precision))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(sheet: &Worksheet, cell_id: &str) -> String {
+        sheet.cell(CellId::parse(cell_id).unwrap()).map(|cell| cell.raw().to_string()).unwrap_or_default()
+    }
+
+    fn at(cell_id: &str) -> CellId {
+        CellId::parse(cell_id).unwrap()
+    }
+
+    fn sheet() -> Worksheet {
+        let mut sheet: Worksheet = Worksheet::new();
+        for (cell_id, data) in [("A1", "1"), ("A2", "2"), ("B1", "=A1*10"), ("B2", "=B1+A2"), ("C1", "=TAKE(A1:A2,2)"), ("D1", "=B2")] {
+            sheet.set_cell(at(cell_id), data.to_string());
+        }
+        sheet
+    }
+
+    #[test]
+    fn frozen_formulas_remember_where_they_came_from() {
+        let mut sheet = sheet();
+        assert_eq!(sheet.freeze_formulas(at("C2"), at("B1")).unwrap(), 3);
+        assert_eq!((raw(&sheet, "B1"), raw(&sheet, "B2"), raw(&sheet, "C1"), raw(&sheet, "C2")), ("10".into(), "12".into(), "1".into(), "2".into()));
+        assert_eq!(raw(&sheet, "D1"), "=B2");
+        assert_eq!(sheet.frozen_formula(at("B2")), Some(&FrozenFormula{formula: "=B1+A2".to_string(), spilled: Vec::new()}));
+        assert_eq!(sheet.frozen_formula(at("C1")).unwrap().spilled, [at("C2")]);
+        // The frozen values no longer follow their precedents.
+        sheet.set_cell(at("A1"), "5".to_string());
+        assert_eq!(raw(&sheet, "B1"), "10");
+
+        // Entering anything forgets the formula.
+        sheet.set_cell(at("B1"), "7".to_string());
+        assert!(sheet.frozen_formula(at("B1")).is_none());
+        assert_eq!(sheet.unfreeze_formulas(at("A1"), at("C2")), 2);
+        assert_eq!((raw(&sheet, "B1"), raw(&sheet, "B2"), raw(&sheet, "C1"), raw(&sheet, "C2")), ("7".into(), "=B1+A2".into(), "=TAKE(A1:A2,2)".into(), "".into()));
+        assert_eq!(sheet.frozen_formulas().count(), 0);
+        assert!(matches!(sheet.evaluate_cell(at("C2")), Ok(Value::Primitive(_))));
+    }
+
+    #[test]
+    fn workbooks_freeze_references_to_other_sheets() {
+        let mut workbook: Workbook = Workbook::new();
+        workbook.add_sheet("Inputs").unwrap();
+        workbook.add_sheet("Model").unwrap();
+        workbook.set_cell("Inputs", at("A1"), "0.1".to_string()).unwrap();
+        workbook.set_cell("Model", at("A1"), "=Inputs!A1+0.2".to_string()).unwrap();
+        assert_eq!(workbook.freeze_formulas("Model", at("A1"), at("A1")).unwrap(), 1);
+        assert_eq!(raw(workbook.sheet("Model").unwrap(), "A1"), "0.3");
+        assert!(matches!(workbook.freeze_formulas("Missing", at("A1"), at("A1")), Err(WorkbookError::UnknownSheet(_))));
+    }
+
+    #[test]
+    fn frozen_formulas_move_with_their_rows() {
+        let mut sheet = sheet();
+        sheet.freeze_formulas(at("B1"), at("C2")).unwrap();
+        sheet.insert_rows(0, 1);
+        assert_eq!(sheet.frozen_formula(at("C2")).unwrap().spilled, [at("C3")]);
+        assert!(sheet.frozen_formula(at("C1")).is_none());
+        assert_eq!(sheet.unfreeze_formulas(at("A1"), at("D3")), 3);
+        assert_eq!(raw(&sheet, "C3"), "");
+    }
+}
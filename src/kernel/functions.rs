@@ -0,0 +1,427 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{FunctionKind, Value};
+use super::parser::{builtins, function_kind};
+use thiserror::Error;
+use std::collections::HashMap;
+
+/// Category groups functions the way function references and pickers of
+/// spreadsheets do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Category {
+    Math,
+    Statistical,
+    Logical,
+    Lookup,
+    Text,
+    Cube,
+    /// Functions of plugin packs which fit none of the others.
+    Other,
+}
+
+/// Argument describes one parameter of a function.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Argument {
+    pub name: String,
+    pub optional: bool,
+    /// Whether the argument may be repeated, like the numbers of `SUM`.
+    pub repeating: bool,
+}
+
+/// FunctionInfo is the documentation of a function: its signature, what it
+/// does and its category, for signature help and function references.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FunctionInfo {
+    pub name: String,
+    pub category: Category,
+    pub description: String,
+    pub arguments: Vec<Argument>,
+}
+
+impl FunctionInfo {
+    /// Describe a function with its arguments written like in a function
+    /// reference: optional arguments between brackets and a trailing `...`
+    /// repeating the argument before it, as in `number1, [number2], ...`.
+    /// Brackets may group arguments which go together, like `[field, item]`.
+    pub fn new(name: &str, category: Category, description: &str, arguments: &str) -> Self {
+        let mut split = Vec::new();
+        let (mut depth, mut start) = (0, 0);
+        for (index, c) in arguments.char_indices() {
+            match c {
+                '[' => depth += 1,
+                ']' => depth -= 1,
+                ',' if depth == 0 => {
+                    split.push(&arguments[start..index]);
+                    start = index + 1;
+                },
+                _ => {},
+            }
+        }
+        split.push(&arguments[start..]);
+        let mut parsed: Vec<Argument> = Vec::new();
+        for argument in split.into_iter().map(str::trim).filter(|argument| !argument.is_empty()) {
+            if argument == "..." {
+                if let Some(last) = parsed.last_mut() {
+                    last.repeating = true;
+                }
+                continue;
+            }
+            let (name, optional) = match argument.strip_prefix('[').and_then(|argument| argument.strip_suffix(']')) {
+                Some(name) => (name, true),
+                None => (argument, false),
+            };
+            parsed.push(Argument{name: name.to_string(), optional, repeating: false});
+        }
+        Self{name: name.to_string(), category, description: description.to_string(), arguments: parsed}
+    }
+
+    /// Get the signature as a function reference writes it, such as
+    /// `VLOOKUP(lookup_value, table_array, col_index_num, [range_lookup])`.
+    pub fn signature(&self) -> String {
+        let arguments = self.arguments.iter()
+            .map(|argument| {
+                let name = match argument.optional {
+                    true => format!("[{}]", argument.name),
+                    false => argument.name.clone(),
+                };
+                match argument.repeating {
+                    true => format!("{}, ...", name),
+                    false => name,
+                }
+            })
+            .collect::<Vec<_>>();
+        format!("{}({})", self.name, arguments.join(", "))
+    }
+
+    /// Get the argument a call is at after `commas` separators, for
+    /// highlighting it in signature help.
+    pub fn argument_at(&self, commas: usize) -> Option<&Argument> {
+        self.arguments.get(commas).or_else(|| self.arguments.last().filter(|argument| argument.repeating))
+    }
+}
+
+impl FunctionKind {
+    /// Get the documentation of a built-in function.
+    pub fn info(&self) -> FunctionInfo {
+        let (category, description, arguments) = match self {
+            Self::Sum => (Category::Math, "Adds its arguments.", "number1, [number2], ..."),
+            Self::SumProduct => (Category::Math, "Multiplies the corresponding values of arrays and adds the products.", "array1, [array2], ..."),
+            Self::Average => (Category::Statistical, "Returns the arithmetic mean of its arguments.", "number1, [number2], ..."),
+            Self::Count => (Category::Statistical, "Counts the numbers among its arguments.", "value1, [value2], ..."),
+            Self::Prod => (Category::Math, "Multiplies its arguments.", "number1, [number2], ..."),
+            Self::If => (Category::Logical, "Returns one value if a condition is true and another if it is false.", "logical_test, [value_if_true], [value_if_false]"),
+            Self::Sqrt => (Category::Math, "Returns the square root of a number.", "number"),
+            Self::Sdev => (Category::Statistical, "Estimates the standard deviation of a sample.", "number1, [number2], ..."),
+            Self::Offset => (Category::Lookup, "Returns a reference offset from a given reference.", "reference, rows, cols, [height], [width]"),
+            Self::GetPivotData => (Category::Lookup, "Returns a measure of a data source at the given field items.", "data_field, source, [field, item], ..."),
+            Self::CubeValue => (Category::Cube, "Returns a measure of a cube at the given members.", "connection, measure, [member_expression], ..."),
+            Self::Text => (Category::Text, "Formats a number as text with a number format.", "value, format_text"),
+            Self::TextBefore => (Category::Text, "Returns the text before a delimiter.", "text, delimiter, [instance_num], [match_mode], [match_end], [if_not_found]"),
+            Self::TextAfter => (Category::Text, "Returns the text after a delimiter.", "text, delimiter, [instance_num], [match_mode], [match_end], [if_not_found]"),
+            Self::Proper => (Category::Text, "Capitalizes the first letter of every word.", "text"),
+            Self::Exact => (Category::Text, "Checks whether two texts are the same, case sensitively.", "text1, text2"),
+            Self::Rept => (Category::Text, "Repeats text a number of times.", "text, number_times"),
+            Self::Char => (Category::Text, "Returns the character of a Windows-1252 code.", "number"),
+            Self::Code => (Category::Text, "Returns the Windows-1252 code of the first character of a text.", "text"),
+            Self::Unichar => (Category::Text, "Returns the Unicode character of a code point.", "number"),
+            Self::Unicode => (Category::Text, "Returns the code point of the first character of a text.", "text"),
+            Self::Choose => (Category::Lookup, "Chooses a value from a list by its index.", "index_num, value1, [value2], ..."),
+            Self::ChooseCols => (Category::Lookup, "Returns the given columns of an array.", "array, col_num1, [col_num2], ..."),
+            Self::ChooseRows => (Category::Lookup, "Returns the given rows of an array.", "array, row_num1, [row_num2], ..."),
+            Self::Take => (Category::Lookup, "Returns rows or columns from the start or end of an array.", "array, rows, [columns]"),
+            Self::Drop => (Category::Lookup, "Excludes rows or columns from the start or end of an array.", "array, rows, [columns]"),
+            Self::HStack => (Category::Lookup, "Appends arrays side by side.", "array1, [array2], ..."),
+            Self::VStack => (Category::Lookup, "Appends arrays one below the other.", "array1, [array2], ..."),
+            Self::Aggregate => (Category::Math, "Aggregates a list or range, optionally ignoring hidden rows and errors.", "function_num, options, ref1, [ref2], ..."),
+            Self::Match => (Category::Lookup, "Returns the position of a value in a list.", "lookup_value, lookup_array, [match_type]"),
+            Self::VLookup => (Category::Lookup, "Looks a value up in the first column of a table and returns a value of the same row.", "lookup_value, table_array, col_index_num, [range_lookup]"),
+            Self::CountIf => (Category::Statistical, "Counts the cells of a range which meet a criterion.", "range, criteria"),
+            Self::Search => (Category::Text, "Finds text within text, case insensitively and with wildcards.", "find_text, within_text, [start_num]"),
+            Self::Find => (Category::Text, "Finds text within text, case sensitively.", "find_text, within_text, [start_num]"),
+            Self::Replace => (Category::Text, "Replaces characters of a text by position.", "old_text, start_num, num_chars, new_text"),
+            Self::Convert => (Category::Math, "Converts a number from one unit of measurement to another.", "number, from_unit, to_unit"),
+            Self::ChangedSince => (Category::Lookup, "Checks which cells of a range changed since a snapshot of their values.", "range, snapshot_id"),
+        };
+        FunctionInfo::new(self.name(), category, description, arguments)
+    }
+}
+
+/// Get the documentation of a built-in function by name.
+pub fn describe(name: &str) -> Option<FunctionInfo> {
+    function_kind(name).map(|kind| kind.info())
+}
+
+/// Get the documentation of every built-in function, ordered by name, as
+/// for generating a function reference.
+pub fn builtin_infos() -> Vec<FunctionInfo> {
+    let mut infos = builtins().map(|kind| kind.info()).collect::<Vec<_>>();
+    infos.sort_by(|a, b| a.name.cmp(&b.name));
+    infos
+}
+
+/// CustomFunction is a function registered by an embedder. It is called with
+/// its evaluated arguments, ranges as arrays, and errors are passed to it
+/// like any other value.
+pub type CustomFunction<T> = Box<dyn Fn(&[Value<T>]) -> Value<T>>;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum FunctionError {
+    #[error("{0} is not a valid function name")]
+    InvalidName(String),
+
+    #[error("a function named {0} is already registered")]
+    Duplicate(String),
+
+    #[error("{0} is a built-in function")]
+    ShadowsBuiltin(String),
+}
+
+/// Precedence is which function a call resolves to when a function
+/// registered without a namespace has the name of a built-in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Precedence {
+    /// Built-ins cannot be shadowed, and registering one of their names
+    /// fails.
+    #[default]
+    Builtin,
+    /// Registered functions shadow built-ins of the same name.
+    Custom,
+}
+
+/// FunctionRegistry holds the functions registered for a workbook. Names
+/// are case insensitive and may be qualified with a namespace, like
+/// `MYORG.RISK`, so plugin packs can register functions without
+/// colliding with each other or with built-ins.
+///
+/// A call of a name without a namespace resolves to: the built-in, unless a
+/// function shadows it; then a function registered without a namespace;
+/// then the one function of that name in any namespace. Names registered in
+/// several namespaces must be qualified.
+pub struct FunctionRegistry<T: Arithmetic=f64> {
+    functions: HashMap<String, CustomFunction<T>>,
+    infos: HashMap<String, FunctionInfo>,
+    precedence: Precedence,
+}
+
+impl<T: Arithmetic> Default for FunctionRegistry<T> {
+    fn default() -> Self {
+        Self{functions: HashMap::new(), infos: HashMap::new(), precedence: Precedence::default()}
+    }
+}
+
+/// Whether a name is made of identifiers separated by single dots.
+fn is_function_name(name: &str) -> bool {
+    name.split('.').all(|part| {
+        let mut chars = part.chars();
+        chars.next().is_some_and(|c| c.is_alphabetic() || c == '_') && chars.all(|c| c.is_alphanumeric() || c == '_')
+    })
+}
+
+impl<T: Arithmetic> FunctionRegistry<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_precedence(precedence: Precedence) -> Self {
+        Self{precedence, ..Self::default()}
+    }
+
+    pub fn precedence(&self) -> Precedence {
+        self.precedence
+    }
+
+    /// Register a function under a name which may be qualified with a
+    /// namespace.
+    pub fn register(&mut self, name: &str, function: CustomFunction<T>) -> Result<(), FunctionError> {
+        if !is_function_name(name) {
+            return Err(FunctionError::InvalidName(name.to_string()));
+        }
+        if self.precedence == Precedence::Builtin && function_kind(name).is_some() {
+            return Err(FunctionError::ShadowsBuiltin(name.to_string()));
+        }
+        let key = name.to_uppercase();
+        if self.functions.contains_key(&key) {
+            return Err(FunctionError::Duplicate(name.to_string()));
+        }
+        self.functions.insert(key, function);
+        Ok(())
+    }
+
+    /// Register a function along with its documentation. The name of the
+    /// function is the one it is registered under.
+    pub fn register_with_info(&mut self, name: &str, function: CustomFunction<T>, info: FunctionInfo) -> Result<(), FunctionError> {
+        self.register(name, function)?;
+        self.infos.insert(name.to_uppercase(), FunctionInfo{name: name.to_uppercase(), ..info});
+        Ok(())
+    }
+
+    /// Register a function in a namespace, as `NAMESPACE.NAME`.
+    pub fn register_in(&mut self, namespace: &str, name: &str, function: CustomFunction<T>) -> Result<(), FunctionError> {
+        self.register(&format!("{}.{}", namespace, name), function)
+    }
+
+    pub fn unregister(&mut self, name: &str) -> Option<CustomFunction<T>> {
+        self.infos.remove(&name.to_uppercase());
+        self.functions.remove(&name.to_uppercase())
+    }
+
+    /// Get the registered names, in upper case, in no particular order.
+    pub fn names(&self) -> impl Iterator<Item=&str> {
+        self.functions.keys().map(String::as_str)
+    }
+
+    /// Get the function a call of a name resolves to, or None if it is
+    /// unknown or ambiguous.
+    pub fn resolve(&self, name: &str) -> Option<&CustomFunction<T>> {
+        self.resolve_name(name).map(|name| &self.functions[name])
+    }
+
+    /// Get the name a call of a name resolves to, as registered.
+    fn resolve_name(&self, name: &str) -> Option<&str> {
+        let key = name.to_uppercase();
+        if let Some((name, _)) = self.functions.get_key_value(&key) {
+            return Some(name);
+        }
+        if key.contains('.') {
+            return None;
+        }
+        let suffix = format!(".{}", key);
+        let mut found = self.functions.keys().filter(|name| name.ends_with(&suffix));
+        match (found.next(), found.next()) {
+            (Some(name), None) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Get the documentation of the function a call of a name resolves to,
+    /// built-in or registered. Registered functions without documentation
+    /// are described by their name alone.
+    pub fn describe(&self, name: &str) -> Option<FunctionInfo> {
+        let key = name.to_uppercase();
+        if let Some(kind) = function_kind(&key).filter(|kind| self.shadowing(*kind).is_none()) {
+            return Some(kind.info());
+        }
+        let name = self.resolve_name(&key)?;
+        Some(self.infos.get(name).cloned().unwrap_or_else(|| FunctionInfo::new(name, Category::Other, "", "")))
+    }
+
+    /// Get the documentation of the built-in and registered functions whose
+    /// name starts with `prefix`, ordered by name, for autocomplete.
+    /// Registered functions also complete by their name without namespace.
+    pub fn complete(&self, prefix: &str) -> Vec<FunctionInfo> {
+        let prefix = prefix.to_uppercase();
+        let mut infos = builtin_infos().into_iter()
+            .filter(|info| info.name.starts_with(&prefix))
+            .filter(|info| !self.functions.contains_key(&info.name) || self.precedence == Precedence::Builtin)
+            .collect::<Vec<_>>();
+        infos.extend(self.functions.keys()
+            .filter(|name| name.starts_with(&prefix) || name.rsplit('.').next().is_some_and(|local| local.starts_with(&prefix)))
+            .filter_map(|name| self.describe(name)));
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+        infos
+    }
+
+    /// Get the function shadowing a built-in, if built-ins may be shadowed.
+    pub(crate) fn shadowing(&self, kind: FunctionKind) -> Option<&CustomFunction<T>> {
+        match self.precedence {
+            Precedence::Builtin => None,
+            Precedence::Custom => self.functions.get(kind.name()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::{CellError, CellId, Numeric, Primitive};
+    use crate::kernel::workbook::Workbook;
+
+    fn constant(number: f64) -> CustomFunction<f64> {
+        Box::new(move |_| Value::Primitive(Primitive::Number(Numeric::new(number, None))))
+    }
+
+    fn evaluate(workbook: &mut Workbook, formula: &str) -> Value {
+        workbook.set_cell("Sheet1", CellId::new(0, 1), formula.to_string()).unwrap();
+        workbook.evaluate_cell("Sheet1", CellId::new(0, 1)).unwrap()
+    }
+
+    fn is_number(value: &Value, expected: f64) -> bool {
+        matches!(value, Value::Primitive(Primitive::Number(number)) if number.value() == expected)
+    }
+
+    #[test]
+    fn names_are_checked_when_registering() {
+        let mut functions: FunctionRegistry = FunctionRegistry::new();
+        assert_eq!(functions.register("1RISK", constant(1.0)), Err(FunctionError::InvalidName("1RISK".to_string())));
+        assert_eq!(functions.register("MYORG..RISK", constant(1.0)), Err(FunctionError::InvalidName("MYORG..RISK".to_string())));
+        assert_eq!(functions.register("sum", constant(1.0)), Err(FunctionError::ShadowsBuiltin("sum".to_string())));
+        functions.register_in("MyOrg", "Risk", constant(1.0)).unwrap();
+        assert_eq!(functions.register("MYORG.RISK", constant(2.0)), Err(FunctionError::Duplicate("MYORG.RISK".to_string())));
+        assert_eq!(functions.names().collect::<Vec<_>>(), ["MYORG.RISK"]);
+        assert!(functions.unregister("myorg.risk").is_some());
+        assert!(functions.resolve("RISK").is_none());
+    }
+
+    #[test]
+    fn calls_resolve_through_namespaces() {
+        let mut workbook: Workbook = Workbook::new();
+        workbook.add_sheet("Sheet1").unwrap();
+        workbook.set_cell("Sheet1", CellId::new(0, 0), "3".to_string()).unwrap();
+        let count: CustomFunction<f64> = Box::new(|arguments| match arguments {
+            [Value::Array(range)] => Value::Primitive(Primitive::Number(Numeric::new(range.values().len() as f64, None))),
+            arguments => Value::Primitive(Primitive::Number(Numeric::new(arguments.len() as f64, None))),
+        });
+        workbook.functions_mut().register_in("MyOrg", "Count2", count).unwrap();
+        workbook.functions_mut().register_in("MyOrg", "Risk", constant(1.0)).unwrap();
+        workbook.functions_mut().register_in("Other", "Risk", constant(2.0)).unwrap();
+        assert!(is_number(&evaluate(&mut workbook, "=COUNT2(A1:A4)"), 4.0));
+        assert!(is_number(&evaluate(&mut workbook, "=myorg.count2(A1,1,\"x\")"), 3.0));
+        assert!(matches!(evaluate(&mut workbook, "=RISK()"), Value::Error(CellError::Name)));
+        assert!(is_number(&evaluate(&mut workbook, "=OTHER.RISK()"), 2.0));
+        assert!(matches!(evaluate(&mut workbook, "=MISSING(1)"), Value::Error(CellError::Name)));
+    }
+
+    #[test]
+    fn custom_precedence_shadows_builtins() {
+        let mut workbook: Workbook = Workbook::new();
+        workbook.add_sheet("Sheet1").unwrap();
+        assert!(is_number(&evaluate(&mut workbook, "=SQRT(16)"), 4.0));
+        *workbook.functions_mut() = FunctionRegistry::with_precedence(Precedence::Custom);
+        workbook.functions_mut().register("sqrt", constant(7.0)).unwrap();
+        assert_eq!(workbook.functions().precedence(), Precedence::Custom);
+        assert!(is_number(&evaluate(&mut workbook, "=SQRT(16)"), 7.0));
+    }
+
+    #[test]
+    fn signatures_are_written_like_function_references() {
+        let vlookup = describe("vlookup").unwrap();
+        assert_eq!(vlookup.signature(), "VLOOKUP(lookup_value, table_array, col_index_num, [range_lookup])");
+        assert_eq!(vlookup.category, Category::Lookup);
+        let sum = FunctionKind::Sum.info();
+        assert_eq!(sum.signature(), "SUM(number1, [number2], ...)");
+        assert_eq!(sum.argument_at(0).unwrap().name, "number1");
+        assert_eq!(sum.argument_at(5).unwrap().name, "number2");
+        assert!(vlookup.argument_at(4).is_none());
+        let pivot = describe("GETPIVOTDATA").unwrap();
+        assert_eq!(pivot.arguments.iter().map(|argument| argument.name.as_str()).collect::<Vec<_>>(), ["data_field", "source", "field, item"]);
+        assert_eq!(pivot.signature(), "GETPIVOTDATA(data_field, source, [field, item], ...)");
+        assert!(describe("MISSING").is_none());
+        let infos = builtin_infos();
+        assert!(infos.windows(2).all(|pair| pair[0].name < pair[1].name));
+    }
+
+    #[test]
+    fn registered_functions_are_described_and_completed() {
+        let mut functions: FunctionRegistry = FunctionRegistry::new();
+        let info = FunctionInfo::new("ignored", Category::Other, "Weaves text.", "text, [times]");
+        functions.register_with_info("MyOrg.Textile", constant(1.0), info).unwrap();
+        functions.register_in("MyOrg", "Risk", constant(1.0)).unwrap();
+        assert_eq!(functions.describe("textile").unwrap().signature(), "MYORG.TEXTILE(text, [times])");
+        assert_eq!(functions.describe("RISK").unwrap().signature(), "MYORG.RISK()");
+        assert_eq!(functions.describe("Sqrt").unwrap().description, "Returns the square root of a number.");
+        let names = |prefix: &str| functions.complete(prefix).into_iter().map(|info| info.name).collect::<Vec<_>>();
+        assert_eq!(names("text"), ["MYORG.TEXTILE", "TEXT", "TEXTAFTER", "TEXTBEFORE"]);
+        assert_eq!(names("myorg."), ["MYORG.RISK", "MYORG.TEXTILE"]);
+        functions.unregister("MYORG.TEXTILE");
+        assert!(functions.describe("TEXTILE").is_none());
+    }
+}
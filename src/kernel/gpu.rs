@@ -0,0 +1,303 @@
+use super::arithmetic::{Arithmetic, Floating};
+use super::eval::sum_of_products;
+use super::kernel::{CellError, Primitive, Value};
+use std::borrow::Cow;
+use std::cell::Cell;
+
+/// ComputeBackend runs bulk numeric operations on a device. Implement it on
+/// top of compute shaders, such as with wgpu, to offload large `SUM`s and
+/// `SUMPRODUCT`s of dense numeric ranges. Numbers are sent as `f64`, so
+/// sheets with a wider number type lose precision when offloading.
+pub trait ComputeBackend {
+    /// Sum numbers, or get None to leave it to the CPU, as when the device
+    /// is lost or the buffer too big for it.
+    fn sum(&self, numbers: &[f64]) -> Option<f64>;
+
+    /// Sum the products of the rows of columns of the same length, or get
+    /// None to leave it to the CPU.
+    fn sum_of_products(&self, columns: &[Vec<f64>]) -> Option<f64>;
+}
+
+/// The numbers a workgroup of `CpuBackend` reduces.
+const WORKGROUP_SIZE: usize = 256;
+
+/// CpuBackend reduces numbers in workgroups and then sums the partial
+/// results, the way a compute shader does, to exercise offloading without a
+/// device.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuBackend;
+
+impl ComputeBackend for CpuBackend {
+    fn sum(&self, numbers: &[f64]) -> Option<f64> {
+        Some(numbers.chunks(WORKGROUP_SIZE).map(|chunk| chunk.iter().sum::<f64>()).sum())
+    }
+
+    fn sum_of_products(&self, columns: &[Vec<f64>]) -> Option<f64> {
+        let len = columns.first()?.len();
+        let products = (0..len).map(|row| columns.iter().map(|column| column[row]).product()).collect::<Vec<f64>>();
+        self.sum(&products)
+    }
+}
+
+/// OffloadOptions tunes when a sheet offloads work to a backend.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OffloadOptions {
+    /// The fewest cells a range must have to be offloaded. Smaller ranges
+    /// cost more to send to the device than to add up.
+    pub min_cells: usize,
+    /// Whether to calculate every offloaded result on the CPU as well, and
+    /// take the CPU result when they differ by more than `tolerance`.
+    pub verify: bool,
+    /// How far apart the results may be, relative to the larger one, for
+    /// them to agree. Backends add numbers in another order than the CPU,
+    /// so results differ in their last digits.
+    pub tolerance: f64,
+}
+
+impl Default for OffloadOptions {
+    fn default() -> Self {
+        Self{min_cells: 1 << 16, verify: false, tolerance: 1e-9}
+    }
+}
+
+/// OffloadStats counts the work an `Offload` sent to its backend.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OffloadStats {
+    pub offloaded: u64,
+    /// The operations the backend declined, which the CPU then calculated.
+    pub fallbacks: u64,
+    /// The verified results which disagreed with the CPU.
+    pub mismatches: u64,
+}
+
+/// Offload sends large aggregations of a sheet to a compute backend, and
+/// falls back to the CPU when the backend declines them. This is
+/// experimental.
+pub struct Offload {
+    backend: Box<dyn ComputeBackend>,
+    options: OffloadOptions,
+    stats: Cell<OffloadStats>,
+}
+
+impl Offload {
+    pub fn new(backend: impl ComputeBackend + 'static) -> Self {
+        Self{backend: Box::new(backend), options: OffloadOptions::default(), stats: Cell::new(OffloadStats::default())}
+    }
+
+    pub fn with_options(mut self, options: OffloadOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn options(&self) -> &OffloadOptions {
+        &self.options
+    }
+
+    pub fn stats(&self) -> OffloadStats {
+        self.stats.get()
+    }
+
+    fn count(&self, f: impl FnOnce(&mut OffloadStats)) {
+        let mut stats = self.stats.get();
+        f(&mut stats);
+        self.stats.set(stats);
+    }
+
+    /// Take the result of the backend unless it disagrees with `scalar`,
+    /// when verifying.
+    fn checked<T: Arithmetic>(&self, result: Option<f64>, scalar: impl FnOnce() -> T) -> Option<T> {
+        let Some(result) = result else {
+            self.count(|stats| stats.fallbacks += 1);
+            return None;
+        };
+        self.count(|stats| stats.offloaded += 1);
+        if !self.options.verify {
+            return Some(Floating::from_f64(result));
+        }
+        let scalar = scalar();
+        let expected = scalar.to_f64();
+        let scale = result.abs().max(expected.abs()).max(f64::MIN_POSITIVE);
+        if (result - expected).abs() / scale > self.options.tolerance {
+            self.count(|stats| stats.mismatches += 1);
+            return Some(scalar);
+        }
+        Some(Floating::from_f64(result))
+    }
+
+    /// Sum the numbers of dense ranges, each a list of columns, on the
+    /// backend, or get None to sum them on the CPU. Errors are found on the
+    /// CPU, in the order the scalar path meets them.
+    pub(crate) fn sum<T: Arithmetic>(&self, ranges: &[Vec<Cow<'_, [Value<T>]>>]) -> Option<Result<T, CellError>> {
+        let cells = ranges.iter().map(|columns| columns.len() * columns[0].len()).sum::<usize>();
+        if cells < self.options.min_cells {
+            return None;
+        }
+        let mut numbers = Vec::with_capacity(cells);
+        for columns in ranges {
+            for row in 0..columns[0].len() {
+                for column in columns {
+                    match &column[row] {
+                        Value::Primitive(Primitive::Number(x)) => numbers.push(x.value().to_f64()),
+                        Value::Error(e) => return Some(Err(*e)),
+                        _ => {},
+                    }
+                }
+            }
+        }
+        let scalar = || {
+            let mut sum: T = Floating::from_f64(0.0);
+            for columns in ranges {
+                for row in 0..columns[0].len() {
+                    for column in columns {
+                        if let Value::Primitive(Primitive::Number(x)) = &column[row] {
+                            sum += x.value();
+                        }
+                    }
+                }
+            }
+            sum
+        };
+        self.checked(self.backend.sum(&numbers), scalar).map(Ok)
+    }
+
+    /// Sum the products of same-shaped dense ranges on the backend, or get
+    /// None to calculate it on the CPU. Cells which are not numbers count as
+    /// zero, as on the scalar path.
+    pub(crate) fn sum_of_products<T: Arithmetic>(&self, ranges: &[Vec<Cow<'_, [Value<T>]>>], rows: usize, cols: usize) -> Option<Result<T, CellError>> {
+        if rows * cols * ranges.len() < self.options.min_cells {
+            return None;
+        }
+        let mut columns = vec![Vec::with_capacity(rows * cols); ranges.len()];
+        for index in 0..rows * cols {
+            for (range, column) in ranges.iter().zip(&mut columns) {
+                column.push(match &range[index % cols][index / cols] {
+                    Value::Primitive(Primitive::Number(x)) => x.value().to_f64(),
+                    Value::Error(e) => return Some(Err(*e)),
+                    _ => 0.0,
+                });
+            }
+        }
+        // Errors were returned above, so the scalar path finds none.
+        let scalar = || sum_of_products(ranges, rows * cols, |columns, index| &columns[index % cols][index / cols]).unwrap_or_else(|_| Floating::from_f64(0.0));
+        self.checked(self.backend.sum_of_products(&columns), scalar).map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::{CellId, Kernel};
+    use crate::kernel::worksheet::Worksheet;
+
+    /// A backend which declines everything.
+    struct Declining;
+
+    impl ComputeBackend for Declining {
+        fn sum(&self, _: &[f64]) -> Option<f64> {
+            None
+        }
+
+        fn sum_of_products(&self, _: &[Vec<f64>]) -> Option<f64> {
+            None
+        }
+    }
+
+    /// A backend which gets every result wrong.
+    struct Faulty;
+
+    impl ComputeBackend for Faulty {
+        fn sum(&self, numbers: &[f64]) -> Option<f64> {
+            Some(numbers.iter().sum::<f64>() + 1.0)
+        }
+
+        fn sum_of_products(&self, _: &[Vec<f64>]) -> Option<f64> {
+            Some(-1.0)
+        }
+    }
+
+    const FORMULAS: [&str; 4] = ["=SUM(A1:B600)", "=SUMPRODUCT(A1:A600,B1:B600)", "=SUM(A1:A600,B1:B300)", "=SUMPRODUCT(A1:B300,A301:B600)"];
+
+    /// Fill two columns with numbers of mixed magnitudes, text and blanks,
+    /// and the formulas to aggregate them in column D.
+    fn sheet() -> Worksheet {
+        let mut sheet = Worksheet::new();
+        for row in 0..600u32 {
+            let number = (row as f64 * 0.37).sin() * 10f64.powi((row % 7) as i32 - 3);
+            sheet.set_cell(CellId::new(row, 0), format!("{}", number));
+            let other = match row % 50 {
+                7 => "text".to_string(),
+                13 => String::new(),
+                _ => format!("{}", row as f64 / 8.0 - 20.0),
+            };
+            sheet.set_cell(CellId::new(row, 1), other);
+        }
+        for (row, formula) in FORMULAS.iter().enumerate() {
+            sheet.set_cell(CellId::new(row as u32, 3), formula.to_string());
+        }
+        sheet
+    }
+
+    fn results(sheet: &Worksheet) -> Vec<Value<f64>> {
+        (0..FORMULAS.len()).map(|row| sheet.evaluate_cell(CellId::new(row as u32, 3)).unwrap()).collect()
+    }
+
+    fn number(value: &Value<f64>) -> f64 {
+        match value {
+            Value::Primitive(Primitive::Number(number)) => number.value(),
+            value => panic!("{:?} is not a number", value),
+        }
+    }
+
+    fn offloaded(backend: impl ComputeBackend + 'static, verify: bool) -> Worksheet {
+        let mut sheet = sheet();
+        let options = OffloadOptions{min_cells: 16, verify, ..OffloadOptions::default()};
+        sheet.set_offload(Some(Offload::new(backend).with_options(options)));
+        sheet
+    }
+
+    #[test]
+    fn offloaded_aggregations_match_the_scalar_path() {
+        let expected = results(&sheet());
+        let sheet = offloaded(CpuBackend, true);
+        for (formula, (actual, expected)) in FORMULAS.iter().zip(results(&sheet).iter().zip(&expected)) {
+            let (actual, expected) = (number(actual), number(expected));
+            assert!((actual - expected).abs() <= 1e-9 * expected.abs().max(1.0), "{}: {} != {}", formula, actual, expected);
+        }
+        let stats = sheet.offload().unwrap().stats();
+        assert_eq!((stats.offloaded, stats.fallbacks, stats.mismatches), (FORMULAS.len() as u64, 0, 0));
+    }
+
+    #[test]
+    fn small_ranges_stay_on_the_cpu() {
+        let mut sheet = sheet();
+        sheet.set_offload(Some(Offload::new(Faulty)));
+        let expected = results(&self::sheet()).iter().map(number).collect::<Vec<_>>();
+        assert_eq!(results(&sheet).iter().map(number).collect::<Vec<_>>(), expected);
+        assert_eq!(sheet.offload().unwrap().stats(), OffloadStats::default());
+    }
+
+    #[test]
+    fn declined_and_wrong_results_fall_back_to_the_cpu() {
+        let expected = results(&sheet()).iter().map(number).collect::<Vec<_>>();
+        let declining = offloaded(Declining, false);
+        assert_eq!(results(&declining).iter().map(number).collect::<Vec<_>>(), expected);
+        assert_eq!(declining.offload().unwrap().stats().fallbacks, FORMULAS.len() as u64);
+
+        let faulty = offloaded(Faulty, true);
+        assert_eq!(results(&faulty).iter().map(number).collect::<Vec<_>>(), expected);
+        assert_eq!(faulty.offload().unwrap().stats().mismatches, FORMULAS.len() as u64);
+    }
+
+    #[test]
+    fn errors_are_found_in_scalar_order() {
+        let mut sheet = offloaded(CpuBackend, false);
+        sheet.set_cell(CellId::new(200, 1), "=1/0".to_string());
+        sheet.set_cell(CellId::new(400, 0), "#N/A".to_string());
+        let mut scalar = self::sheet();
+        scalar.set_cell(CellId::new(200, 1), "=1/0".to_string());
+        scalar.set_cell(CellId::new(400, 0), "#N/A".to_string());
+        for (actual, expected) in results(&sheet).iter().zip(results(&scalar)) {
+            assert_eq!(format!("{:?}", actual), format!("{:?}", expected));
+        }
+    }
+}
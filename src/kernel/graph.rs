@@ -0,0 +1,361 @@
+use super::arithmetic::Arithmetic;
+use super::bytecode::Program;
+use super::eval::Evaluator;
+use super::kernel::{Cell, CellError, CellId, Formula, Kernel, Value};
+use super::schedule::ScheduleError;
+use super::settings::CalcSettings;
+use super::worksheet::{SheetError, Worksheet};
+use thiserror::Error;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum GraphError {
+    /// The formula of a cell refers to cells which are only known once it is
+    /// evaluated, through a defined name, another sheet, a volatile function
+    /// or a dynamic array.
+    #[error("the formula of {0} has references which are not static")]
+    Dynamic(CellId),
+
+    #[error("{0} is not an input of the graph")]
+    NotAnInput(CellId),
+
+    #[error(transparent)]
+    Schedule(#[from] ScheduleError),
+
+    #[error(transparent)]
+    Sheet(#[from] SheetError),
+}
+
+/// Whether everything a formula refers to is known without evaluating it.
+fn is_static<T: Arithmetic>(formula: &Formula<T>) -> bool {
+    match formula {
+        Formula::Name(_) | Formula::SheetRef(..) => false,
+        Formula::Function{kind, ..} if kind.is_volatile() || kind.returns_array() => false,
+        _ => formula.operands().into_iter().all(|operand| match operand {
+            Value::Formula(operand) => is_static(operand),
+            _ => true,
+        }),
+    }
+}
+
+/// Step is a formula of a graph, calculated in place.
+struct Step<'a, T: Arithmetic> {
+    cell_id: CellId,
+    formula: &'a Formula<T>,
+    program: Option<Program<T>>,
+}
+
+/// Input is a cell without a formula which formulas of a graph refer to.
+struct Input<T: Arithmetic> {
+    cell: Option<Cell<T>>,
+    value: Value<T>,
+    /// The first step referring to the input. Steps before it are not
+    /// affected by a change to the input.
+    first: usize,
+}
+
+/// CalcGraph is the dependency subgraph of some cells of a sheet compiled
+/// into straight-line code: the formulas they depend on in calculation
+/// order, each compiled to bytecode where it can be, reading the results of
+/// the formulas before it from slots instead of through the sheet. It suits
+/// models whose formulas stay the same while their inputs change all the
+/// time, as inputs are set on the graph and a calculation runs only the
+/// formulas after the first one depending on a changed input, without cycle
+/// detection or caching. This is experimental.
+pub struct CalcGraph<'a, T: Arithmetic=f64> {
+    sheet: &'a Worksheet<T>,
+    settings: CalcSettings,
+    outputs: Vec<CellId>,
+    steps: Vec<Step<'a, T>>,
+    slots: HashMap<CellId, usize>,
+    inputs: HashMap<CellId, Input<T>>,
+    values: Vec<Value<T>>,
+    /// The first step to calculate, None when every value is up to date.
+    dirty: Option<usize>,
+}
+
+impl<'a, T: Arithmetic> CalcGraph<'a, T> {
+    fn new(sheet: &'a Worksheet<T>, outputs: &[CellId]) -> Result<Self, GraphError> {
+        let mut formulas = HashSet::new();
+        let mut inputs = HashSet::new();
+        let mut stack = outputs.to_vec();
+        while let Some(cell_id) = stack.pop() {
+            let Some(formula) = sheet.cell(cell_id).and_then(|cell| cell.formula()) else {
+                inputs.insert(cell_id);
+                continue;
+            };
+            if !is_static(formula) {
+                return Err(GraphError::Dynamic(cell_id));
+            }
+            if formulas.insert(cell_id) {
+                stack.extend(formula.precedents());
+            }
+        }
+
+        let steps = sheet.calculation_order()?.into_iter()
+            .filter(|cell_id| formulas.contains(cell_id))
+            .map(|cell_id| {
+                let formula = sheet.cell(cell_id).and_then(|cell| cell.formula()).expect("steps are formulas");
+                Step{cell_id, formula, program: Program::compile(formula)}
+            })
+            .collect::<Vec<_>>();
+        let slots = steps.iter().enumerate().map(|(index, step)| (step.cell_id, index)).collect::<HashMap<_, _>>();
+        let mut firsts = HashMap::new();
+        for (index, step) in steps.iter().enumerate().rev() {
+            for precedent in step.formula.precedents() {
+                firsts.insert(precedent, index);
+            }
+        }
+        let inputs = inputs.into_iter()
+            .map(|cell_id| Ok((cell_id, Input{
+                cell: sheet.cell(cell_id).cloned(),
+                value: sheet.evaluate_cell(cell_id)?,
+                first: firsts.get(&cell_id).copied().unwrap_or(steps.len()),
+            })))
+            .collect::<Result<HashMap<_, _>, SheetError>>()?;
+        Ok(Self{
+            sheet,
+            settings: sheet.settings().clone(),
+            outputs: outputs.to_vec(),
+            values: vec![Value::Empty; steps.len()],
+            dirty: (!steps.is_empty()).then_some(0),
+            steps,
+            slots,
+            inputs,
+        })
+    }
+
+    /// Get the number of formulas the graph calculates.
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Get the cells the graph was compiled for.
+    pub fn outputs(&self) -> &[CellId] {
+        &self.outputs
+    }
+
+    /// Get the cells without formulas the graph reads, row by row.
+    pub fn inputs(&self) -> Vec<CellId> {
+        let mut inputs = self.inputs.keys().copied().collect::<Vec<_>>();
+        inputs.sort_by_key(|cell_id| (cell_id.row(), cell_id.col()));
+        inputs
+    }
+
+    /// Set an input of the graph to a value, in place of the cell of the
+    /// sheet, which is left alone.
+    pub fn set_input(&mut self, cell_id: CellId, value: Value<T>) -> Result<(), GraphError> {
+        let input = self.inputs.get_mut(&cell_id).ok_or(GraphError::NotAnInput(cell_id))?;
+        input.cell = match value {
+            Value::Empty => None,
+            value => Some(Cell::from_value(value)),
+        };
+        input.value = match &input.cell {
+            None => Value::Empty,
+            Some(cell) => Evaluator::new(self.sheet).with_settings(self.settings.clone()).evaluate_value(cell.value())?,
+        };
+        if input.first < self.steps.len() {
+            self.dirty = Some(self.dirty.map_or(input.first, |dirty| dirty.min(input.first)));
+        }
+        Ok(())
+    }
+
+    /// Calculate the formulas depending on the inputs set since the last
+    /// calculation.
+    pub fn calculate(&mut self) -> Result<(), GraphError> {
+        let Some(dirty) = self.dirty else { return Ok(()) };
+        let mut values = std::mem::take(&mut self.values);
+        let result = (dirty..self.steps.len()).try_for_each(|index| {
+            values[index] = self.step(index, &values)?;
+            Ok(())
+        });
+        self.values = values;
+        if result.is_ok() {
+            self.dirty = None;
+        }
+        result
+    }
+
+    /// Calculate a step from the values of the steps before it.
+    fn step(&self, index: usize, values: &[Value<T>]) -> Result<Value<T>, GraphError> {
+        let step = &self.steps[index];
+        let view = GraphView{graph: self, values};
+        let mut evaluator = Evaluator::new(&view)
+            .with_settings(self.settings.clone())
+            .with_hooks(self.sheet.hooks())
+            .with_sources(self.sheet.sources());
+        #[cfg(feature = "gpu")]
+        if let Some(offload) = self.sheet.offload() {
+            evaluator = evaluator.with_offload(offload);
+        }
+        if let Some(program) = &step.program {
+            evaluator = evaluator.with_program(program);
+        }
+        Ok(match evaluator.evaluate_cell(step.cell_id, step.formula)? {
+            Value::Array(array) => {
+                let last = CellId::new(
+                    step.cell_id.row().saturating_add(array.rows() as u32 - 1),
+                    step.cell_id.col().saturating_add(array.cols() as u32 - 1),
+                );
+                match CellId::range(step.cell_id, last).any(|cell_id| cell_id != step.cell_id && view.get_cell(cell_id).is_some()) {
+                    true => Value::Error(CellError::Spill),
+                    false => array.get(0, 0).cloned().unwrap_or(Value::Empty),
+                }
+            },
+            value => value,
+        })
+    }
+
+    /// Get the value of a formula or input of the graph as of the last
+    /// calculation.
+    pub fn value(&self, cell_id: CellId) -> Option<&Value<T>> {
+        match self.slots.get(&cell_id) {
+            Some(index) => Some(&self.values[*index]),
+            None => self.inputs.get(&cell_id).map(|input| &input.value),
+        }
+    }
+
+    /// Get the values of the outputs as of the last calculation, in the
+    /// order they were given.
+    pub fn output_values(&self) -> Vec<(CellId, Value<T>)> {
+        self.outputs.iter()
+            .map(|cell_id| (*cell_id, self.value(*cell_id).cloned().unwrap_or(Value::Empty)))
+            .collect()
+    }
+}
+
+/// GraphView evaluates a step of a graph, reading the other cells from the
+/// slots of the graph. Views are only handed to the evaluator, which never
+/// changes cells.
+struct GraphView<'g, 'a, T: Arithmetic> {
+    graph: &'g CalcGraph<'a, T>,
+    values: &'g [Value<T>],
+}
+
+impl<T: Arithmetic> Kernel<SheetError, T> for GraphView<'_, '_, T> {
+    fn get_cell(&self, cell_id: CellId) -> Option<Cell<T>> {
+        match self.graph.inputs.get(&cell_id) {
+            Some(input) => input.cell.clone(),
+            None => self.graph.sheet.get_cell(cell_id),
+        }
+    }
+
+    fn evaluate_cell(&self, cell_id: CellId) -> Result<Value<T>, SheetError> {
+        if let Some(index) = self.graph.slots.get(&cell_id) {
+            return Ok(self.values[*index].clone());
+        }
+        match self.graph.inputs.get(&cell_id) {
+            Some(input) => Ok(input.value.clone()),
+            None => self.graph.sheet.evaluate_cell(cell_id),
+        }
+    }
+
+    fn set_cell(&mut self, _cell_id: CellId, _data: String) {
+        unreachable!("graph views are read only")
+    }
+
+    fn calc_settings(&self) -> CalcSettings {
+        self.graph.settings.clone()
+    }
+
+    fn is_row_hidden(&self, sheet: Option<&str>, row: u32) -> bool {
+        self.graph.sheet.is_row_hidden(sheet, row)
+    }
+}
+
+impl<T: Arithmetic> Worksheet<T> {
+    /// Compile the cells of this sheet `outputs` depend on into a graph to
+    /// calculate them from inputs set on the graph. The order of the
+    /// formulas respects calculation chains, so the whole sheet must have a
+    /// calculation order.
+    pub fn compile_graph(&self, outputs: &[CellId]) -> Result<CalcGraph<'_, T>, GraphError> {
+        CalcGraph::new(self, outputs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::{Numeric, Primitive};
+
+    fn number(value: Option<&Value<f64>>) -> Option<f64> {
+        match value {
+            Some(Value::Primitive(Primitive::Number(number))) => Some(number.value()),
+            _ => None,
+        }
+    }
+
+    fn input(value: f64) -> Value<f64> {
+        Value::Primitive(Primitive::Number(Numeric::new(value, None)))
+    }
+
+    /// A model with a rate in A1 and an amount in A2, and a total in B3 the
+    /// net in B2 depends on. C1 depends on nothing the outputs need.
+    fn sheet() -> Worksheet {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), "0.5".to_string());
+        sheet.set_cell(CellId::new(1, 0), "100".to_string());
+        sheet.set_cell(CellId::new(0, 1), "=A2*A1".to_string());
+        sheet.set_cell(CellId::new(1, 1), "=A2-B1".to_string());
+        sheet.set_cell(CellId::new(2, 1), "=SUM(B1:B2)+1".to_string());
+        sheet.set_cell(CellId::new(0, 2), "=A1*1000".to_string());
+        sheet
+    }
+
+    #[test]
+    fn graph_calculates_outputs_from_inputs() {
+        let sheet = sheet();
+        let mut graph = sheet.compile_graph(&[CellId::new(2, 1)]).unwrap();
+        assert_eq!(graph.len(), 3);
+        assert_eq!(graph.inputs(), vec![CellId::new(0, 0), CellId::new(1, 0)]);
+        graph.calculate().unwrap();
+        assert_eq!(number(graph.value(CellId::new(2, 1))), Some(101.0));
+        assert_eq!(number(graph.value(CellId::new(1, 1))), Some(50.0));
+        assert!(graph.value(CellId::new(0, 2)).is_none());
+
+        graph.set_input(CellId::new(1, 0), input(40.0)).unwrap();
+        graph.calculate().unwrap();
+        let outputs = graph.output_values();
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(number(Some(&outputs[0].1)), Some(41.0));
+        // The sheet is left alone.
+        assert_eq!(number(Some(&sheet.evaluate_cell(CellId::new(2, 1)).unwrap())), Some(101.0));
+
+        graph.set_input(CellId::new(0, 0), Value::Empty).unwrap();
+        graph.calculate().unwrap();
+        assert_eq!(number(graph.value(CellId::new(2, 1))), Some(41.0));
+        assert_eq!(number(graph.value(CellId::new(0, 1))), Some(0.0));
+    }
+
+    #[test]
+    fn graph_rejects_unknown_inputs_and_dynamic_formulas() {
+        let mut sheet = sheet();
+        {
+            let mut graph = sheet.compile_graph(&[CellId::new(2, 1)]).unwrap();
+            assert_eq!(graph.set_input(CellId::new(0, 1), input(1.0)), Err(GraphError::NotAnInput(CellId::new(0, 1))));
+            assert_eq!(graph.set_input(CellId::new(5, 5), input(1.0)), Err(GraphError::NotAnInput(CellId::new(5, 5))));
+        }
+
+        sheet.set_cell(CellId::new(3, 1), "=B3+OFFSET(A1,1,0)".to_string());
+        sheet.set_cell(CellId::new(4, 1), "=Other!A1".to_string());
+        sheet.set_cell(CellId::new(5, 1), "=TAKE(A1:A2,1)".to_string());
+        for row in 3..6 {
+            let cell_id = CellId::new(row, 1);
+            assert!(matches!(sheet.compile_graph(&[cell_id]), Err(GraphError::Dynamic(dynamic)) if dynamic == cell_id));
+        }
+    }
+
+    #[test]
+    fn empty_graph_reads_its_inputs() {
+        let sheet = sheet();
+        let mut graph = sheet.compile_graph(&[CellId::new(1, 0)]).unwrap();
+        assert!(graph.is_empty());
+        assert_eq!(graph.outputs(), &[CellId::new(1, 0)]);
+        graph.calculate().unwrap();
+        assert_eq!(number(graph.value(CellId::new(1, 0))), Some(100.0));
+    }
+}
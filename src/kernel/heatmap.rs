@@ -0,0 +1,249 @@
+use super::arithmetic::Arithmetic;
+use super::array::Array2D;
+use super::bench::{affected, Edit, Script};
+use super::compare::escape_html;
+use super::conditional::{Color, ConditionalFormat, Visual};
+use super::kernel::{CellId, Numeric, Primitive, Value};
+use super::range::Range;
+use super::workbook::{Workbook, WorkbookError};
+use super::worksheet::Worksheet;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// HeatMetric is the measure of a cell a heat map shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HeatMetric {
+    /// The number of formulas depending on the cell, directly or through
+    /// other cells.
+    Dependents,
+    /// The number of times the cell was recalculated.
+    Recalculations,
+    /// The time spent evaluating the cell, in seconds.
+    EvaluationTime,
+}
+
+impl HeatMetric {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Dependents => "Dependents",
+            Self::Recalculations => "Recalculations",
+            Self::EvaluationTime => "Evaluation Time",
+        }
+    }
+}
+
+/// CellHeat is what a heat map measured of one cell.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CellHeat {
+    pub dependents: usize,
+    /// The number of times the formula of the cell was evaluated: once for
+    /// the first calculation and once for every edit of the script it
+    /// depends on. Zero for cells without a formula.
+    pub recalculations: usize,
+    /// The total time of those evaluations. A formula is timed with the
+    /// evaluation of its precedents, so the time accumulates along chains
+    /// of formulas towards the cells summing them, where it is spent.
+    pub time: Duration,
+}
+
+impl CellHeat {
+    pub fn metric(&self, metric: HeatMetric) -> f64 {
+        match metric {
+            HeatMetric::Dependents => self.dependents as f64,
+            HeatMetric::Recalculations => self.recalculations as f64,
+            HeatMetric::EvaluationTime => self.time.as_secs_f64(),
+        }
+    }
+}
+
+/// HeatMap holds per cell metrics of the structure of a sheet, so owners of
+/// a model see its hot spots at a glance: the inputs most of it depends on,
+/// and the formulas recalculated most often or taking the longest. It can
+/// be rendered as a grid of numbers, a worksheet, colors or HTML.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeatMap {
+    pub sheet: String,
+    pub cells: HashMap<CellId, CellHeat>,
+}
+
+impl HeatMap {
+    pub fn get(&self, cell_id: CellId) -> Option<&CellHeat> {
+        self.cells.get(&cell_id)
+    }
+
+    /// Get the corners of the cells measured, or None if there are none.
+    pub fn bounds(&self) -> Option<(CellId, CellId)> {
+        let rows = self.cells.keys().map(|cell_id| cell_id.row());
+        let cols = self.cells.keys().map(|cell_id| cell_id.col());
+        let (top, bottom) = (rows.clone().min()?, rows.max()?);
+        let (left, right) = (cols.clone().min()?, cols.max()?);
+        Some((CellId::new(top, left), CellId::new(bottom, right)))
+    }
+
+    /// Get the cells with the highest values of a metric, highest first,
+    /// up to `count` of them. Cells where it is zero are left out.
+    pub fn hottest(&self, metric: HeatMetric, count: usize) -> Vec<(CellId, CellHeat)> {
+        let mut cells = self.cells.iter().filter(|(_, heat)| heat.metric(metric) > 0.0).map(|(cell_id, heat)| (*cell_id, *heat)).collect::<Vec<_>>();
+        cells.sort_by(|(a, x), (b, y)| y.metric(metric).total_cmp(&x.metric(metric)).then((a.row(), a.col()).cmp(&(b.row(), b.col()))));
+        cells.truncate(count);
+        cells
+    }
+
+    /// Get a metric over the bounds of the map, None for cells not
+    /// measured. The grid is empty if no cell was.
+    pub fn grid(&self, metric: HeatMetric) -> Array2D<Option<f64>> {
+        let Some((first, last)) = self.bounds() else { return Array2D::from_fn(0, 0, |_, _| None) };
+        let rows = (last.row() - first.row() + 1) as usize;
+        let cols = (last.col() - first.col() + 1) as usize;
+        Array2D::from_fn(rows, cols, |row, col| {
+            self.get(CellId::new(first.row() + row as u32, first.col() + col as u32)).map(|heat| heat.metric(metric))
+        })
+    }
+
+    /// Render a metric as a worksheet holding it in the cells of the sheet
+    /// measured, so it lines up with the model.
+    pub fn to_worksheet<T: Arithmetic>(&self, metric: HeatMetric) -> Worksheet<T> {
+        let mut sheet = Worksheet::new();
+        for (cell_id, heat) in &self.cells {
+            sheet.set_value(*cell_id, Value::Primitive(Primitive::Number(Numeric::new(T::from_f64(heat.metric(metric)), None))));
+        }
+        sheet
+    }
+
+    /// Get the color of every cell on a color scale of a metric from the
+    /// lowest to the highest value, over the bounds of the map.
+    pub fn colors(&self, metric: HeatMetric, low: Color, high: Color) -> Array2D<Option<Color>> {
+        let Some((first, last)) = self.bounds() else { return Array2D::from_fn(0, 0, |_, _| None) };
+        let sheet = self.to_worksheet::<f64>(metric);
+        let visuals = ConditionalFormat::color_scale(low, high).compute(&Range::new(&sheet, first, last)).expect("heat values are numbers");
+        visuals.map(|visual| match visual {
+            Some(Visual::Color(color)) => Some(color),
+            _ => None,
+        })
+    }
+
+    /// Render a metric as a standalone HTML document of a table shaped like
+    /// the sheet, with the cells filled by a color scale from white to red.
+    pub fn to_html(&self, metric: HeatMetric) -> String {
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Heat Map</title></head>\n<body>\n<h1>{}: {}</h1>\n<table>\n",
+            escape_html(&self.sheet),
+            metric.label(),
+        );
+        if let Some((first, _)) = self.bounds() {
+            let values = self.grid(metric);
+            let colors = self.colors(metric, Color::rgb(255, 255, 255), Color::rgb(248, 105, 107));
+            for (row, cells) in values.iter_rows().enumerate() {
+                html.push_str("<tr>");
+                for (col, value) in cells.iter().enumerate() {
+                    let cell_id = CellId::new(first.row() + row as u32, first.col() + col as u32);
+                    match (value, colors.get(row, col).copied().flatten()) {
+                        (Some(value), Some(Color{r, g, b})) => html.push_str(&format!(
+                            "<td title=\"{}\" style=\"background-color:#{:02x}{:02x}{:02x}\">{}</td>",
+                            cell_id, r, g, b, value,
+                        )),
+                        _ => html.push_str("<td></td>"),
+                    }
+                }
+                html.push_str("</tr>\n");
+            }
+        }
+        html.push_str("</table>\n</body>\n</html>\n");
+        html
+    }
+}
+
+impl<T: Arithmetic> Workbook<T> {
+    /// Measure the cells of a sheet for a heat map. Every formula of the
+    /// sheet is evaluated once, then the script is replayed against the
+    /// workbook as by `bench::run`, recalculating the formulas of the sheet
+    /// each edit affects, so the recalculations and times reflect how the
+    /// model is used. An empty script measures the first calculation only.
+    pub fn heat_map(&mut self, sheet: &str, script: &Script) -> Result<HeatMap, WorkbookError> {
+        let own = self.sheet(sheet).ok_or_else(|| WorkbookError::UnknownSheet(sheet.to_string()))?;
+        let graph = own.dependency_graph();
+        let mut cells = own.cell_ids().into_iter().map(|cell_id| {
+            (cell_id, CellHeat{dependents: graph.dependents(cell_id).len(), ..CellHeat::default()})
+        }).collect::<HashMap<_, _>>();
+        let formulas = own.cells().filter(|(_, cell)| cell.formula().is_some()).map(|(cell_id, _)| cell_id).collect::<Vec<_>>();
+        for cell_id in formulas {
+            self.time_cell(sheet, cell_id, &mut cells)?;
+        }
+
+        for Edit{sheet: edited, cell_id, data} in script.edits() {
+            self.set_cell(edited, *cell_id, data.clone())?;
+            let graphs = self.sheets().map(|(name, sheet)| (name, sheet.dependency_graph())).collect::<Vec<_>>();
+            let recalculated = affected(self, &graphs, edited, *cell_id)
+                .into_iter()
+                .filter(|(name, cell_id)| name.eq_ignore_ascii_case(sheet) && self.sheet(name).and_then(|own| own.cell(*cell_id)).is_some_and(|cell| cell.formula().is_some()))
+                .collect::<Vec<_>>();
+            for (_, cell_id) in recalculated {
+                self.time_cell(sheet, cell_id, &mut cells)?;
+            }
+        }
+        Ok(HeatMap{sheet: sheet.to_string(), cells})
+    }
+
+    fn time_cell(&self, sheet: &str, cell_id: CellId, cells: &mut HashMap<CellId, CellHeat>) -> Result<(), WorkbookError> {
+        let start = Instant::now();
+        self.evaluate_cell(sheet, cell_id)?;
+        let heat = cells.entry(cell_id).or_default();
+        heat.recalculations += 1;
+        heat.time += start.elapsed();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workbook() -> Workbook {
+        let mut workbook: Workbook = Workbook::new();
+        workbook.add_sheet("Inputs").unwrap();
+        workbook.add_sheet("Model").unwrap();
+        for (sheet, cell_id, data) in [
+            ("Inputs", "A1", "1"), ("Inputs", "B1", "=A1*2"), ("Inputs", "C1", "=B1+1"),
+            ("Inputs", "D1", "10"), ("Inputs", "E1", "=D1"), ("Model", "A1", "=Inputs!C1"),
+        ] {
+            workbook.set_cell(sheet, CellId::parse(cell_id).unwrap(), data.to_string()).unwrap();
+        }
+        workbook
+    }
+
+    fn at(cell_id: &str) -> CellId {
+        CellId::parse(cell_id).unwrap()
+    }
+
+    #[test]
+    fn heat_maps_count_dependents_and_recalculations() {
+        let script = Script::new().set("Inputs", at("A1"), "2");
+        let map = workbook().heat_map("Inputs", &script).unwrap();
+        let counts = |cell_id| map.get(at(cell_id)).map(|heat| (heat.dependents, heat.recalculations));
+        assert_eq!(counts("A1"), Some((2, 0)));
+        assert_eq!(counts("B1"), Some((1, 2)));
+        assert_eq!(counts("C1"), Some((0, 2)));
+        assert_eq!(counts("E1"), Some((0, 1)));
+        assert_eq!(map.bounds(), Some((at("A1"), at("E1"))));
+        let hottest = map.hottest(HeatMetric::Dependents, 2).into_iter().map(|(cell_id, _)| cell_id).collect::<Vec<_>>();
+        assert_eq!(hottest, [at("A1"), at("B1")]);
+        assert_eq!(map.hottest(HeatMetric::Recalculations, 10).len(), 3);
+        assert!(matches!(workbook().heat_map("Missing", &Script::new()), Err(WorkbookError::UnknownSheet(_))));
+    }
+
+    #[test]
+    fn heat_maps_render() {
+        let map = workbook().heat_map("Inputs", &Script::new()).unwrap();
+        assert_eq!(map.grid(HeatMetric::Dependents).values(), [Some(2.0), Some(1.0), Some(0.0), Some(1.0), Some(0.0)]);
+        let sheet = map.to_worksheet::<f64>(HeatMetric::Recalculations);
+        assert_eq!(sheet.cell(at("B1")).unwrap().raw(), "1");
+        let colors = map.colors(HeatMetric::Dependents, Color::rgb(0, 0, 0), Color::rgb(200, 0, 0));
+        assert_eq!(colors.get(0, 0).copied().flatten(), Some(Color::rgb(200, 0, 0)));
+        assert_eq!(colors.get(0, 2).copied().flatten(), Some(Color::rgb(0, 0, 0)));
+        let html = map.to_html(HeatMetric::Dependents);
+        assert!(html.contains("<h1>Inputs: Dependents</h1>"));
+        assert!(html.contains("<td title=\"A1\" style=\"background-color:#f8696b\">2</td>"));
+        assert_eq!(html.matches("<tr>").count(), 1);
+        assert!(HeatMap::default().to_html(HeatMetric::EvaluationTime).contains("<table>\n</table>"));
+    }
+}
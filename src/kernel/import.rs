@@ -0,0 +1,356 @@
+use super::arithmetic::Arithmetic;
+use super::fixed_width::FieldType;
+use super::kernel::{CellId, FormulaParseError, Kernel, Primitive, Value};
+use super::worksheet::{SheetError, Worksheet};
+use thiserror::Error;
+
+/// ImportErrorKind is why an imported cell did not read as intended.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ImportErrorKind {
+    #[error(transparent)]
+    Formula(#[from] FormulaParseError),
+
+    /// The text looks like a number, date or time but is none, such as
+    /// `1.2.3` or `2024-13-01`, and was stored as text.
+    #[error("not a valid number, date or time")]
+    Value,
+}
+
+/// ImportIssue is a cell of an import which did not read as intended.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportIssue {
+    pub cell_id: CellId,
+    pub raw: String,
+    pub error: ImportErrorKind,
+}
+
+/// ImportReport collects the issues of an import instead of leaving them in
+/// the sheet as text and `#NAME?` formulas for someone to stumble upon.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportReport {
+    /// The number of non-empty cells imported.
+    pub cells: usize,
+    pub issues: Vec<ImportIssue>,
+}
+
+impl ImportReport {
+    /// Get the fraction of the imported cells with issues.
+    pub fn error_rate(&self) -> f64 {
+        match self.cells {
+            0 => 0.0,
+            cells => self.issues.len() as f64 / cells as f64,
+        }
+    }
+
+    /// Check the cell just imported and record its issue, if any.
+    pub(crate) fn check<T: Arithmetic>(&mut self, cell_id: CellId, raw: &str, value: &Value<T>) {
+        self.cells += 1;
+        let error = match value {
+            Value::FormulaParseError(e) => ImportErrorKind::Formula(e.clone()),
+            Value::Raw if looks_like_value(raw) => ImportErrorKind::Value,
+            _ => return,
+        };
+        self.issues.push(ImportIssue{cell_id, raw: raw.to_string(), error});
+    }
+}
+
+/// Whether text which did not parse as a value was probably meant as one:
+/// it starts like a number and only holds what numbers, dates and times
+/// are written with.
+fn looks_like_value(raw: &str) -> bool {
+    let text = raw.trim();
+    let digits = text.trim_start_matches(['-', '+', '.', '(']);
+    digits.starts_with(|c: char| c.is_ascii_digit())
+        && text.chars().all(|c| c.is_ascii_digit() || ".,:/-+%() ".contains(c))
+}
+
+/// ImportLimits abort an import with too many issues, such as a file of the
+/// wrong format or with the wrong delimiter.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ImportLimits {
+    /// The most issues allowed.
+    pub max_errors: Option<usize>,
+    /// The highest fraction of cells with issues allowed, checked when the
+    /// import is done.
+    pub max_error_rate: Option<f64>,
+}
+
+impl ImportLimits {
+    pub(crate) fn too_many_errors(&self, report: &ImportReport) -> bool {
+        self.max_errors.is_some_and(|max| report.issues.len() > max)
+    }
+
+    pub(crate) fn error_rate_too_high(&self, report: &ImportReport) -> bool {
+        self.max_error_rate.is_some_and(|max| report.error_rate() > max)
+    }
+}
+
+/// Constraint is a rule the values of a schema column must follow.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constraint {
+    /// Numbers must not be below this.
+    Min(f64),
+    /// Numbers must not be above this.
+    Max(f64),
+    /// The text of the cell must be one of these.
+    OneOf(Vec<String>),
+    /// Text must not be longer than this many characters.
+    MaxLength(usize),
+}
+
+impl Constraint {
+    fn allows<T: Arithmetic>(&self, primitive: &Primitive<T>, text: &str) -> bool {
+        match (self, primitive) {
+            (Self::Min(min), Primitive::Number(number)) => number.value().to_f64() >= *min,
+            (Self::Max(max), Primitive::Number(number)) => number.value().to_f64() <= *max,
+            (Self::OneOf(allowed), _) => allowed.iter().any(|allowed| allowed == text),
+            (Self::MaxLength(max), Primitive::Text(text)) => text.chars().count() <= *max,
+            _ => true,
+        }
+    }
+}
+
+/// SchemaColumn is a column an import expects, found by its header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaColumn {
+    pub name: String,
+    /// Whether the column must be present and every cell of it filled.
+    pub required: bool,
+    pub field_type: FieldType,
+    pub constraints: Vec<Constraint>,
+}
+
+impl SchemaColumn {
+    pub fn new(name: &str, field_type: FieldType) -> Self {
+        Self{name: name.to_string(), required: false, field_type, constraints: Vec::new()}
+    }
+
+    pub fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+
+    pub fn constraint(mut self, constraint: Constraint) -> Self {
+        self.constraints.push(constraint);
+        self
+    }
+}
+
+/// ImportSchema is the columns an imported table must have, with the header
+/// row first and one record per row after it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportSchema {
+    pub columns: Vec<SchemaColumn>,
+    /// Whether headers the schema does not know are allowed.
+    pub allow_extra_columns: bool,
+}
+
+/// SchemaViolation is where an imported table breaks its schema.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum SchemaViolation {
+    #[error("missing required column {0}")]
+    MissingColumn(String),
+
+    #[error("unexpected column {0}")]
+    UnexpectedColumn(String),
+
+    #[error("{cell_id}: missing value of required column {column}")]
+    MissingValue{cell_id: CellId, column: String},
+
+    #[error("{cell_id}: {raw:?} is not a valid {field_type:?}")]
+    Type{cell_id: CellId, raw: String, field_type: FieldType},
+
+    #[error("{cell_id}: {raw:?} breaks {constraint:?}")]
+    Constraint{cell_id: CellId, raw: String, constraint: Constraint},
+}
+
+/// Record is a row which follows its schema, with a value per schema column
+/// in schema order, None for empty cells and absent optional columns.
+#[derive(Debug, Clone)]
+pub struct Record<T: Arithmetic=f64> {
+    pub row: u32,
+    pub values: Vec<Option<Primitive<T>>>,
+}
+
+/// SchemaReport holds the records of the rows which follow the schema and
+/// the violations of those which don't.
+#[derive(Debug, Clone)]
+pub struct SchemaReport<T: Arithmetic=f64> {
+    pub records: Vec<Record<T>>,
+    pub violations: Vec<SchemaViolation>,
+}
+
+impl<T: Arithmetic> SchemaReport<T> {
+    pub fn is_valid(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl ImportSchema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn column(mut self, column: SchemaColumn) -> Self {
+        self.columns.push(column);
+        self
+    }
+
+    pub fn allow_extra_columns(mut self) -> Self {
+        self.allow_extra_columns = true;
+        self
+    }
+
+    /// Validate a table starting at `A1` of a sheet row by row. Headers are
+    /// matched ignoring case and surrounding whitespace, in any order.
+    /// Formulas are checked by their evaluated values, and numbers written
+    /// in text columns are kept as text.
+    pub fn validate<T: Arithmetic>(&self, sheet: &Worksheet<T>) -> Result<SchemaReport<T>, SheetError> {
+        let ids = sheet.cell_ids();
+        let rows = ids.iter().map(|cell_id| cell_id.row() + 1).max().unwrap_or(0);
+        let cols = ids.iter().map(|cell_id| cell_id.col() + 1).max().unwrap_or(0);
+        let headers = (0..cols)
+            .map(|col| sheet.cell(CellId::new(0, col)).map(|cell| cell.text().trim().to_string()).unwrap_or_default())
+            .collect::<Vec<_>>();
+        let mut violations = Vec::new();
+        let positions = self.columns.iter()
+            .map(|column| {
+                let position = headers.iter().position(|header| header.eq_ignore_ascii_case(column.name.trim()));
+                if position.is_none() && column.required {
+                    violations.push(SchemaViolation::MissingColumn(column.name.clone()));
+                }
+                position.map(|col| col as u32)
+            })
+            .collect::<Vec<_>>();
+        if !self.allow_extra_columns {
+            for (col, header) in headers.iter().enumerate() {
+                if !header.is_empty() && !positions.contains(&Some(col as u32)) {
+                    violations.push(SchemaViolation::UnexpectedColumn(header.clone()));
+                }
+            }
+        }
+        let mut records = Vec::new();
+        for row in 1..rows {
+            let before = violations.len();
+            let mut values = Vec::with_capacity(self.columns.len());
+            for (column, position) in self.columns.iter().zip(&positions) {
+                let Some(col) = *position else {
+                    values.push(None);
+                    continue;
+                };
+                let cell_id = CellId::new(row, col);
+                values.push(check(column, sheet, cell_id, &mut violations)?);
+            }
+            if violations.len() == before {
+                records.push(Record{row, values});
+            }
+        }
+        Ok(SchemaReport{records, violations})
+    }
+}
+
+/// Check one cell against its column, and get its typed value.
+fn check<T: Arithmetic>(
+    column: &SchemaColumn,
+    sheet: &Worksheet<T>,
+    cell_id: CellId,
+    violations: &mut Vec<SchemaViolation>,
+) -> Result<Option<Primitive<T>>, SheetError> {
+    let text = sheet.cell(cell_id).map(|cell| cell.text().to_string()).unwrap_or_default();
+    let primitive = match sheet.evaluate_cell(cell_id)? {
+        Value::Empty => None,
+        Value::Raw => Some(Primitive::Text(text.trim().to_string())),
+        Value::Primitive(_) if column.field_type == FieldType::Text && !text.starts_with('=') => {
+            Some(Primitive::Text(text.trim().to_string()))
+        },
+        Value::Primitive(primitive) => Some(primitive),
+        _ => {
+            violations.push(SchemaViolation::Type{cell_id, raw: text, field_type: column.field_type});
+            return Ok(None);
+        },
+    };
+    let Some(primitive) = primitive else {
+        if column.required {
+            violations.push(SchemaViolation::MissingValue{cell_id, column: column.name.clone()});
+        }
+        return Ok(None);
+    };
+    let accepted = match primitive {
+        Primitive::Text(_) => matches!(column.field_type, FieldType::Text | FieldType::Any),
+        ref primitive => column.field_type.accepts(primitive),
+    };
+    if !accepted {
+        violations.push(SchemaViolation::Type{cell_id, raw: text, field_type: column.field_type});
+        return Ok(None);
+    }
+    for constraint in &column.constraints {
+        if !constraint.allows(&primitive, text.trim()) {
+            violations.push(SchemaViolation::Constraint{cell_id, raw: text.clone(), constraint: constraint.clone()});
+        }
+    }
+    Ok(Some(primitive))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::csv::{parse_csv_with_report, CsvError, CsvOptions};
+    use crate::kernel::budget::Budget;
+    use crate::kernel::worksheet::Worksheet;
+    use crate::kernel::csv::read_csv_with_schema;
+
+    const TEXT: &str = "Version,Released,Total\n1.2.3,2024-13-01,=SUM(\nv2,2024-03-01,=1+1\n";
+
+    #[test]
+    fn imports_report_cells_which_did_not_read_as_intended() {
+        let (sheet, report): (Worksheet, _) = parse_csv_with_report(TEXT, &CsvOptions::default(), &Budget::default()).unwrap();
+        assert_eq!(report.cells, 9);
+        assert_eq!(report.issues.iter().map(|issue| (issue.cell_id, issue.raw.as_str())).collect::<Vec<_>>(), [
+            (CellId::new(1, 0), "1.2.3"),
+            (CellId::new(1, 1), "2024-13-01"),
+            (CellId::new(1, 2), "=SUM("),
+        ]);
+        assert_eq!(report.issues[0].error, ImportErrorKind::Value);
+        assert!(matches!(report.issues[2].error, ImportErrorKind::Formula(_)));
+        assert_eq!(report.error_rate(), 3.0 / 9.0);
+        assert!(sheet.cell(CellId::new(1, 0)).is_some());
+        assert!(!looks_like_value("v2"));
+        assert!(looks_like_value("(1,200.50)"));
+    }
+
+    #[test]
+    fn imports_fail_past_their_limits() {
+        let parse = |limits| parse_csv_with_report::<f64>(TEXT, &CsvOptions{limits, ..CsvOptions::default()}, &Budget::default());
+        assert!(matches!(parse(ImportLimits{max_errors: Some(1), ..ImportLimits::default()}), Err(CsvError::TooManyErrors(report)) if report.issues.len() == 2));
+        assert!(matches!(parse(ImportLimits{max_error_rate: Some(0.25), ..ImportLimits::default()}), Err(CsvError::TooManyErrors(report)) if report.cells == 9));
+        assert!(parse(ImportLimits{max_errors: Some(3), max_error_rate: Some(0.5)}).is_ok());
+    }
+
+    #[test]
+    fn tables_are_validated_against_their_schema() {
+        let text = "ID, qty ,Status,Note\n007,5,open,x\n8,-1,done,\n9,abc,open,\n,3,closed,\n";
+        let schema = ImportSchema::new()
+            .column(SchemaColumn::new("ID", FieldType::Text).required())
+            .column(SchemaColumn::new("Qty", FieldType::Number).required().constraint(Constraint::Min(0.0)))
+            .column(SchemaColumn::new("Status", FieldType::Text).constraint(Constraint::OneOf(vec!["open".to_string(), "done".to_string()])))
+            .column(SchemaColumn::new("Owner", FieldType::Text).required());
+        let (_, report): (Worksheet, _) = read_csv_with_schema(text.as_bytes(), &CsvOptions::default(), &Budget::default(), &schema).unwrap();
+        assert!(!report.is_valid());
+        assert_eq!(report.violations, [
+            SchemaViolation::MissingColumn("Owner".to_string()),
+            SchemaViolation::UnexpectedColumn("Note".to_string()),
+            SchemaViolation::Constraint{cell_id: CellId::new(2, 1), raw: "-1".to_string(), constraint: Constraint::Min(0.0)},
+            SchemaViolation::Type{cell_id: CellId::new(3, 1), raw: "abc".to_string(), field_type: FieldType::Number},
+            SchemaViolation::MissingValue{cell_id: CellId::new(4, 0), column: "ID".to_string()},
+            SchemaViolation::Constraint{cell_id: CellId::new(4, 2), raw: "closed".to_string(), constraint: Constraint::OneOf(vec!["open".to_string(), "done".to_string()])},
+        ]);
+        assert_eq!(report.records.len(), 1);
+        let record = &report.records[0];
+        assert_eq!(record.row, 1);
+        assert!(matches!(&record.values[..], [Some(Primitive::Text(id)), Some(Primitive::Number(_)), Some(Primitive::Text(_)), None] if id == "007"));
+
+        let relaxed = ImportSchema{columns: schema.columns[..2].to_vec(), allow_extra_columns: true};
+        let (_, report): (Worksheet, _) = read_csv_with_schema("ID,Qty,Note\n1,2,x\n".as_bytes(), &CsvOptions::default(), &Budget::default(), &relaxed).unwrap();
+        assert!(report.is_valid());
+    }
+}
@@ -0,0 +1,536 @@
+use super::arithmetic::Arithmetic;
+use super::budget::Budget;
+use super::csv::{parse_csv_with_report, quote_field, records, CsvError, CsvOptions};
+use super::encoding::{decode_text, Encoding};
+use super::fixed_width::{import_fixed_width, FieldType, FixedWidthColumn, FixedWidthSpec, RecordError};
+use super::format::Locale;
+use super::import::{Constraint, ImportLimits, ImportReport, ImportSchema, SchemaColumn, SchemaViolation};
+use super::journal::{escape, unescape};
+use super::kernel::{CellId, Kernel};
+use super::workbook::{Workbook, WorkbookError};
+use super::worksheet::{SheetError, Worksheet};
+use thiserror::Error;
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+#[derive(Error, Debug)]
+pub enum ProfileError {
+    #[error("line {0} of the profile is corrupt")]
+    Corrupt(usize),
+
+    #[error("no import hook is registered as {0}")]
+    UnknownHook(String),
+
+    #[error("the import breaks its schema: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("; "))]
+    Schema(Vec<SchemaViolation>),
+
+    #[error(transparent)]
+    Csv(#[from] CsvError),
+
+    #[error(transparent)]
+    Workbook(#[from] WorkbookError),
+
+    #[error(transparent)]
+    Sheet(#[from] SheetError),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// ImportSource is the kind of file a profile imports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImportSource {
+    Delimited{delimiter: char},
+    FixedWidth{columns: Vec<FixedWidthColumn>},
+}
+
+/// ImportProfile is a saved import configuration, so a file delivered
+/// again and again, such as a monthly extract, is imported the same way
+/// every time by `Workbook::import_with_profile`. Profiles are written as
+/// text by `write` to keep alongside the workbook.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportProfile {
+    pub name: String,
+    pub source: ImportSource,
+    /// The encoding of the file, detected if `None`.
+    pub encoding: Option<Encoding>,
+    /// The locale numbers and dates of delimited files are written in, such
+    /// as `1.234,5` and `31.01.2024` for German files. They are read as
+    /// written in the kernel's own format if `None`.
+    pub locale: Option<Locale>,
+    /// The columns the table must have, with the header row first. A table
+    /// breaking its schema is not imported.
+    pub schema: Option<ImportSchema>,
+    /// The sheet the table is imported into, added if missing.
+    pub sheet: String,
+    /// The cell the first field of the first record goes to.
+    pub anchor: CellId,
+    /// Whether fixed-width imports start with a header row of the column
+    /// names. Delimited files hold their own header, if any.
+    pub header: bool,
+    /// The names of the import hooks run in turn over the imported range,
+    /// see `register_import_hook`.
+    pub hooks: Vec<String>,
+    pub limits: ImportLimits,
+}
+
+impl ImportProfile {
+    /// Create a profile importing delimited text into `A1` of a sheet.
+    pub fn delimited(name: &str, delimiter: char, sheet: &str) -> Self {
+        Self{
+            name: name.to_string(),
+            source: ImportSource::Delimited{delimiter},
+            encoding: None,
+            locale: None,
+            schema: None,
+            sheet: sheet.to_string(),
+            anchor: CellId::new(0, 0),
+            header: false,
+            hooks: Vec::new(),
+            limits: ImportLimits::default(),
+        }
+    }
+
+    /// Create a profile importing fixed-width records into `A1` of a sheet.
+    pub fn fixed_width(name: &str, columns: Vec<FixedWidthColumn>, sheet: &str) -> Self {
+        Self{source: ImportSource::FixedWidth{columns}, ..Self::delimited(name, ',', sheet)}
+    }
+
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = Some(encoding);
+        self
+    }
+
+    pub fn with_locale(mut self, locale: Locale) -> Self {
+        self.locale = Some(locale);
+        self
+    }
+
+    pub fn with_schema(mut self, schema: ImportSchema) -> Self {
+        self.schema = Some(schema);
+        self
+    }
+
+    pub fn with_anchor(mut self, anchor: CellId) -> Self {
+        self.anchor = anchor;
+        self
+    }
+
+    pub fn with_header(mut self, header: bool) -> Self {
+        self.header = header;
+        self
+    }
+
+    pub fn hook(mut self, name: &str) -> Self {
+        self.hooks.push(name.to_string());
+        self
+    }
+
+    /// Write the profile as lines of tab separated fields.
+    pub fn write(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writeln!(writer, "name\t{}", escape(&self.name))?;
+        match &self.source {
+            ImportSource::Delimited{delimiter} => writeln!(writer, "delimited\t{}", escape(&delimiter.to_string()))?,
+            ImportSource::FixedWidth{columns} => {
+                writeln!(writer, "fixed_width")?;
+                for column in columns {
+                    let field_type = field_type_name(column.field_type);
+                    writeln!(writer, "field\t{}\t{}\t{}\t{}", escape(&column.name), column.bytes.start, column.bytes.end, field_type)?;
+                }
+            },
+        }
+        if let Some(encoding) = self.encoding {
+            writeln!(writer, "encoding\t{}", encoding_name(encoding))?;
+        }
+        if let Some(locale) = &self.locale {
+            writeln!(
+                writer,
+                "locale\t{}\t{}\t{}\t{}\t{}",
+                escape(&locale.decimal_separator.to_string()),
+                escape(&locale.thousands_separator.to_string()),
+                escape(&locale.currency_symbol),
+                locale.currency_before,
+                escape(&locale.date_format),
+            )?;
+        }
+        if let Some(schema) = &self.schema {
+            writeln!(writer, "schema\t{}", schema.allow_extra_columns)?;
+            for column in &schema.columns {
+                writeln!(writer, "column\t{}\t{}\t{}", escape(&column.name), field_type_name(column.field_type), column.required)?;
+                for constraint in &column.constraints {
+                    match constraint {
+                        Constraint::Min(min) => writeln!(writer, "constraint\tmin\t{}", min)?,
+                        Constraint::Max(max) => writeln!(writer, "constraint\tmax\t{}", max)?,
+                        Constraint::MaxLength(max) => writeln!(writer, "constraint\tmax_length\t{}", max)?,
+                        Constraint::OneOf(allowed) => {
+                            let allowed = allowed.iter().map(|allowed| escape(allowed)).collect::<Vec<_>>();
+                            writeln!(writer, "constraint\tone_of\t{}", allowed.join("\t"))?;
+                        },
+                    }
+                }
+            }
+        }
+        writeln!(writer, "target\t{}\t{}\t{}", escape(&self.sheet), self.anchor, self.header)?;
+        for hook in &self.hooks {
+            writeln!(writer, "hook\t{}", escape(hook))?;
+        }
+        if let Some(max) = self.limits.max_errors {
+            writeln!(writer, "max_errors\t{}", max)?;
+        }
+        if let Some(max) = self.limits.max_error_rate {
+            writeln!(writer, "max_error_rate\t{}", max)?;
+        }
+        Ok(())
+    }
+
+    /// Read a profile written by `write`.
+    pub fn read(reader: impl BufRead) -> Result<Self, ProfileError> {
+        let mut profile = Self::delimited("", ',', "");
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            let corrupt = || ProfileError::Corrupt(index + 1);
+            if line.is_empty() {
+                continue;
+            }
+            let fields = line.split('\t').map(unescape).collect::<Option<Vec<_>>>().ok_or_else(corrupt)?;
+            let chars = |text: &str| {
+                let mut chars = text.chars();
+                chars.next().filter(|_| chars.next().is_none())
+            };
+            match fields.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+                ["name", name] => profile.name = name.to_string(),
+                ["delimited", delimiter] => profile.source = ImportSource::Delimited{delimiter: chars(delimiter).ok_or_else(corrupt)?},
+                ["fixed_width"] => profile.source = ImportSource::FixedWidth{columns: Vec::new()},
+                ["field", name, start, end, field_type] => {
+                    let ImportSource::FixedWidth{columns} = &mut profile.source else { return Err(corrupt()) };
+                    let (start, end) = start.parse().ok().zip(end.parse().ok()).ok_or_else(corrupt)?;
+                    let field_type = parse_field_type(field_type).ok_or_else(corrupt)?;
+                    columns.push(FixedWidthColumn{name: name.to_string(), bytes: start..end, field_type});
+                },
+                ["encoding", encoding] => profile.encoding = Some(parse_encoding(encoding).ok_or_else(corrupt)?),
+                ["locale", decimal, thousands, symbol, before, date_format] => {
+                    profile.locale = Some(Locale{
+                        decimal_separator: chars(decimal).ok_or_else(corrupt)?,
+                        thousands_separator: chars(thousands).ok_or_else(corrupt)?,
+                        currency_symbol: symbol.to_string(),
+                        currency_before: before.parse().map_err(|_| corrupt())?,
+                        date_format: date_format.to_string(),
+                    });
+                },
+                ["schema", allow_extra_columns] => {
+                    let allow_extra_columns = allow_extra_columns.parse().map_err(|_| corrupt())?;
+                    profile.schema = Some(ImportSchema{columns: Vec::new(), allow_extra_columns});
+                },
+                ["column", name, field_type, required] => {
+                    let schema = profile.schema.as_mut().ok_or_else(corrupt)?;
+                    let mut column = SchemaColumn::new(name, parse_field_type(field_type).ok_or_else(corrupt)?);
+                    column.required = required.parse().map_err(|_| corrupt())?;
+                    schema.columns.push(column);
+                },
+                ["constraint", kind, arguments @ ..] => {
+                    let column = profile.schema.as_mut().and_then(|schema| schema.columns.last_mut()).ok_or_else(corrupt)?;
+                    let constraint = match (*kind, arguments) {
+                        ("min", [min]) => Constraint::Min(min.parse().map_err(|_| corrupt())?),
+                        ("max", [max]) => Constraint::Max(max.parse().map_err(|_| corrupt())?),
+                        ("max_length", [max]) => Constraint::MaxLength(max.parse().map_err(|_| corrupt())?),
+                        ("one_of", allowed) => Constraint::OneOf(allowed.iter().map(|allowed| allowed.to_string()).collect()),
+                        _ => return Err(corrupt()),
+                    };
+                    column.constraints.push(constraint);
+                },
+                ["target", sheet, anchor, header] => {
+                    profile.sheet = sheet.to_string();
+                    profile.anchor = CellId::parse(anchor).map_err(|_| corrupt())?;
+                    profile.header = header.parse().map_err(|_| corrupt())?;
+                },
+                ["hook", hook] => profile.hooks.push(hook.to_string()),
+                ["max_errors", max] => profile.limits.max_errors = Some(max.parse().map_err(|_| corrupt())?),
+                ["max_error_rate", max] => profile.limits.max_error_rate = Some(max.parse().map_err(|_| corrupt())?),
+                _ => return Err(corrupt()),
+            }
+        }
+        Ok(profile)
+    }
+}
+
+fn field_type_name(field_type: FieldType) -> &'static str {
+    match field_type {
+        FieldType::Text => "text",
+        FieldType::Number => "number",
+        FieldType::Bool => "bool",
+        FieldType::Date => "date",
+        FieldType::Time => "time",
+        FieldType::Any => "any",
+    }
+}
+
+fn parse_field_type(name: &str) -> Option<FieldType> {
+    [FieldType::Text, FieldType::Number, FieldType::Bool, FieldType::Date, FieldType::Time, FieldType::Any]
+        .into_iter()
+        .find(|field_type| field_type_name(*field_type) == name)
+}
+
+fn encoding_name(encoding: Encoding) -> &'static str {
+    match encoding {
+        Encoding::Utf8 => "utf-8",
+        Encoding::Utf16Le => "utf-16le",
+        Encoding::Utf16Be => "utf-16be",
+        Encoding::Windows1252 => "windows-1252",
+        Encoding::ShiftJis => "shift_jis",
+    }
+}
+
+fn parse_encoding(name: &str) -> Option<Encoding> {
+    [Encoding::Utf8, Encoding::Utf16Le, Encoding::Utf16Be, Encoding::Windows1252, Encoding::ShiftJis]
+        .into_iter()
+        .find(|encoding| encoding_name(*encoding) == name)
+}
+
+/// ImportHook is a step of an import pipeline run over the range a profile
+/// imported, such as dropping rows or adding computed columns.
+pub trait ImportHook<T: Arithmetic=f64> {
+    fn apply(&self, sheet: &mut Worksheet<T>, first: CellId, last: CellId) -> Result<(), SheetError>;
+}
+
+impl<T: Arithmetic, F> ImportHook<T> for F
+where F: Fn(&mut Worksheet<T>, CellId, CellId) -> Result<(), SheetError> {
+    fn apply(&self, sheet: &mut Worksheet<T>, first: CellId, last: CellId) -> Result<(), SheetError> {
+        self(sheet, first, last)
+    }
+}
+
+type Hooks<T> = HashMap<String, Rc<dyn ImportHook<T>>>;
+
+thread_local! {
+    /// The hooks of each number type. Profiles name their hooks, as code
+    /// cannot be saved with them.
+    static HOOKS: RefCell<HashMap<TypeId, Box<dyn Any>>> = RefCell::new(HashMap::new());
+}
+
+/// Register a hook on the current thread under a case insensitive name,
+/// replacing any hook of that name.
+pub fn register_import_hook<T: Arithmetic>(name: &str, hook: impl ImportHook<T> + 'static) {
+    HOOKS.with_borrow_mut(|hooks| {
+        hooks.entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(Hooks::<T>::new()))
+            .downcast_mut::<Hooks<T>>()
+            .expect("hooks are keyed by their number type")
+            .insert(name.to_lowercase(), Rc::new(hook));
+    });
+}
+
+fn import_hook<T: Arithmetic>(name: &str) -> Option<Rc<dyn ImportHook<T>>> {
+    HOOKS.with_borrow(|hooks| {
+        hooks.get(&TypeId::of::<T>())
+            .and_then(|hooks| hooks.downcast_ref::<Hooks<T>>())
+            .and_then(|hooks| hooks.get(&name.to_lowercase()))
+            .cloned()
+    })
+}
+
+/// Translate the format code of dates, like `dd.mm.yyyy`, into the pattern
+/// chrono parses them with.
+fn date_pattern(code: &str) -> String {
+    let mut pattern = String::new();
+    let mut chars = code.chars().peekable();
+    while let Some(c) = chars.next() {
+        let mut run = 1;
+        while chars.peek() == Some(&c) {
+            chars.next();
+            run += 1;
+        }
+        match c.to_ascii_lowercase() {
+            'y' if run > 2 => pattern.push_str("%Y"),
+            'y' => pattern.push_str("%y"),
+            'm' => pattern.push_str("%m"),
+            'd' => pattern.push_str("%d"),
+            '%' => pattern.push_str(&"%%".repeat(run)),
+            c => pattern.extend(std::iter::repeat_n(c, run)),
+        }
+    }
+    pattern
+}
+
+/// Rewrite a field holding a number or date written in `locale` the way
+/// the kernel reads them, keeping signs, percent signs and currency
+/// symbols around the number. Other fields are kept as they are.
+fn delocalize(field: &str, locale: &Locale) -> String {
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(field.trim(), &date_pattern(&locale.date_format)) {
+        return date.format("%Y-%m-%d").to_string();
+    }
+
+    let Some(start) = field.find(|c: char| c.is_ascii_digit()) else { return field.to_string() };
+    let end = field.rfind(|c: char| c.is_ascii_digit()).expect("a digit was found") + 1;
+    let (before, number, after) = (&field[..start], &field[start..end], &field[end..]);
+    let surrounds = |text: &str| text.replace(&locale.currency_symbol, "").chars().all(|c| c.is_whitespace() || "+-()%".contains(c));
+    let valid = number.chars().all(|c| c.is_ascii_digit() || c == locale.decimal_separator || c == locale.thousands_separator)
+        && number.matches(locale.decimal_separator).count() <= 1;
+    if !valid || !surrounds(before) || !surrounds(after) {
+        return field.to_string();
+    }
+    let number = number.replace(locale.thousands_separator, "").replace(locale.decimal_separator, ".");
+    format!("{}{}{}", before, number, after)
+}
+
+/// ProfileImport is what `Workbook::import_with_profile` imported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfileImport {
+    /// The corners of the imported range, or None if the file held nothing.
+    pub range: Option<(CellId, CellId)>,
+    /// The cells of delimited files which did not read as intended.
+    pub report: ImportReport,
+    /// The fields of fixed-width files which did not convert to their type.
+    pub field_errors: Vec<RecordError>,
+}
+
+impl<T: Arithmetic> Workbook<T> {
+    /// Import a file as a profile describes: decode it, read its records
+    /// into a table, validate it against the schema, write it at the anchor
+    /// of the target sheet and run the hooks over it. Nothing is written if
+    /// the table breaks its schema or exceeds the limits of the profile.
+    /// Formulas of delimited files are entered as written, without
+    /// adjusting their references to the anchor.
+    pub fn import_with_profile(&mut self, path: impl AsRef<Path>, profile: &ImportProfile) -> Result<ProfileImport, ProfileError> {
+        let hooks = profile.hooks.iter()
+            .map(|name| import_hook::<T>(name).ok_or_else(|| ProfileError::UnknownHook(name.clone())))
+            .collect::<Result<Vec<_>, _>>()?;
+        let bytes = std::fs::read(path)?;
+        let (table, report, field_errors) = match &profile.source {
+            ImportSource::Delimited{delimiter} => {
+                let (mut text, _) = decode_text(&bytes, profile.encoding);
+                if let Some(locale) = &profile.locale {
+                    let records = records(&text, *delimiter).map_err(CsvError::UnterminatedQuote)?;
+                    text = records.iter()
+                        .map(|record| record.iter().map(|field| quote_field(&delocalize(field, locale), *delimiter)).collect::<Vec<_>>().join(&delimiter.to_string()))
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                }
+                let options = CsvOptions{delimiter: *delimiter, limits: profile.limits, ..CsvOptions::default()};
+                let (table, report) = parse_csv_with_report::<T>(&text, &options, &Budget::default())?;
+                (table, report, Vec::new())
+            },
+            ImportSource::FixedWidth{columns} => {
+                let spec = FixedWidthSpec{columns: columns.clone(), encoding: profile.encoding, header: profile.header};
+                let mut table = Worksheet::new();
+                let report = import_fixed_width(&mut table, CellId::new(0, 0), bytes.as_slice(), &spec)?;
+                (table, ImportReport::default(), report.errors)
+            },
+        };
+        if let Some(schema) = &profile.schema {
+            let validated = schema.validate(&table)?;
+            if !validated.is_valid() {
+                return Err(ProfileError::Schema(validated.violations));
+            }
+        }
+
+        if self.sheet(&profile.sheet).is_none() {
+            self.add_sheet(&profile.sheet)?;
+        }
+        let sheet = self.sheet_mut(&profile.sheet).expect("the sheet was added");
+        let anchor = profile.anchor;
+        let ids = table.cell_ids();
+        for cell_id in &ids {
+            let target = CellId::new(anchor.row() + cell_id.row(), anchor.col() + cell_id.col());
+            let raw = table.cell(*cell_id).map(|cell| cell.raw().to_string()).unwrap_or_default();
+            sheet.set_cell(target, raw);
+            sheet.set_number_format(target, table.number_format(*cell_id));
+        }
+        let range = ids.iter().map(|cell_id| cell_id.row()).max().zip(ids.iter().map(|cell_id| cell_id.col()).max())
+            .map(|(row, col)| (anchor, CellId::new(anchor.row() + row, anchor.col() + col)));
+        if let Some((first, last)) = range {
+            for hook in hooks {
+                hook.apply(sheet, first, last)?;
+            }
+        }
+        Ok(ProfileImport{range, report, field_errors})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(workbook: &Workbook, sheet: &str, cell_id: &str) -> String {
+        workbook.sheet(sheet).unwrap().cell(CellId::parse(cell_id).unwrap()).map(|cell| cell.raw().to_string()).unwrap_or_default()
+    }
+
+    fn schema() -> ImportSchema {
+        let mut amount = SchemaColumn::new("amount", FieldType::Number);
+        amount.required = true;
+        amount.constraints = vec![Constraint::Min(0.0), Constraint::OneOf(vec!["1234.5".to_string(), "7".to_string()])];
+        let mut name = SchemaColumn::new("name", FieldType::Text);
+        name.constraints = vec![Constraint::MaxLength(8)];
+        ImportSchema{columns: vec![name, amount], allow_extra_columns: true}
+    }
+
+    fn file(name: &str, contents: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("xlnt-profile-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn profiles_round_trip() {
+        let profiles = [
+            ImportProfile::delimited("monthly\textract", ';', "Data")
+                .with_encoding(Encoding::Windows1252)
+                .with_locale(Locale::de_de())
+                .with_schema(schema())
+                .with_anchor(CellId::new(2, 1))
+                .with_header(true)
+                .hook("drop totals"),
+            ImportProfile::fixed_width("bank", vec![
+                FixedWidthColumn{name: "date".to_string(), bytes: 0..8, field_type: FieldType::Date},
+                FixedWidthColumn{name: "amount".to_string(), bytes: 8..16, field_type: FieldType::Number},
+            ], "Bank"),
+        ];
+        for mut profile in profiles {
+            profile.limits.max_errors = Some(3);
+            let mut written = Vec::new();
+            profile.write(&mut written).unwrap();
+            assert_eq!(ImportProfile::read(written.as_slice()).unwrap(), profile);
+        }
+        assert!(matches!(ImportProfile::read("name\ta\nfield\tx\t0\t1\ttext\n".as_bytes()), Err(ProfileError::Corrupt(2))));
+        assert!(matches!(ImportProfile::read("encoding\tebcdic\n".as_bytes()), Err(ProfileError::Corrupt(1))));
+    }
+
+    #[test]
+    fn imports_follow_their_profile() {
+        let path = file("de.csv", "name;amount;when\nalpha;1.234,5;31.12.2024\nbeta;7;\n".as_bytes());
+        register_import_hook::<f64>("Total", |sheet: &mut Worksheet, first: CellId, last: CellId| {
+            sheet.set_cell(CellId::new(last.row() + 1, first.col() + 1), format!("=SUM({}:{})", CellId::new(first.row() + 1, first.col() + 1), CellId::new(last.row(), first.col() + 1)));
+            Ok(())
+        });
+        let profile = ImportProfile::delimited("de", ';', "Data")
+            .with_locale(Locale::de_de())
+            .with_schema(schema())
+            .with_anchor(CellId::parse("B2").unwrap())
+            .hook("total");
+        let mut workbook: Workbook = Workbook::new();
+        let imported = workbook.import_with_profile(&path, &profile).unwrap();
+        assert_eq!(imported.range, Some((CellId::parse("B2").unwrap(), CellId::parse("D4").unwrap())));
+        assert_eq!(raw(&workbook, "Data", "C3"), "1234.5");
+        assert_eq!(raw(&workbook, "Data", "D3"), "2024-12-31");
+        assert_eq!(raw(&workbook, "Data", "C5"), "=SUM(C3:C4)");
+        assert_eq!(workbook.formatted("Data", CellId::parse("C5").unwrap()).unwrap(), "1241.5");
+
+        let unknown = ImportProfile::delimited("de", ';', "Data").hook("missing");
+        assert!(matches!(workbook.import_with_profile(&path, &unknown), Err(ProfileError::UnknownHook(_))));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn imports_breaking_their_schema_write_nothing() {
+        let path = file("bad.csv", b"name,amount\nfar too long,5\n");
+        let profile = ImportProfile::delimited("bad", ',', "Data").with_schema(schema());
+        let mut workbook: Workbook = Workbook::new();
+        let Err(ProfileError::Schema(violations)) = workbook.import_with_profile(&path, &profile) else { panic!("the schema holds") };
+        assert_eq!(violations.len(), 2);
+        assert!(workbook.sheet("Data").is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
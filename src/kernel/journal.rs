@@ -0,0 +1,450 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{CellId, Formula};
+use super::workbook::{Workbook, WorkbookError};
+use thiserror::Error;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// The first line of every journal, naming its format.
+const HEADER: &str = "xlnt-journal 1";
+
+#[derive(Error, Debug)]
+pub enum JournalError {
+    #[error("line {0}: not a journal entry")]
+    Corrupt(usize),
+
+    #[error("line {line}: {error}")]
+    Replay{line: usize, error: WorkbookError},
+
+    #[error(transparent)]
+    Workbook(#[from] WorkbookError),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Mutation is one change to a workbook, as recorded in a journal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mutation {
+    SetCell{sheet: String, cell_id: CellId, data: String},
+    AddSheet(String),
+    RemoveSheet(String),
+    RenameSheet{old: String, new: String},
+    /// Define a name as the text of a formula, without the leading `=`.
+    DefineName{name: String, formula: String},
+    RemoveName(String),
+}
+
+impl Mutation {
+    /// Get the sheet whose contents this mutation changes, if any.
+    pub fn sheet(&self) -> Option<&str> {
+        match self {
+            Self::SetCell{sheet, ..} | Self::AddSheet(sheet) => Some(sheet),
+            Self::RenameSheet{new, ..} => Some(new),
+            Self::RemoveSheet(_) | Self::DefineName{..} | Self::RemoveName(_) => None,
+        }
+    }
+
+    fn encode(&self) -> String {
+        let fields = match self {
+            Self::SetCell{sheet, cell_id, data} => vec![escape("set"), escape(sheet), cell_id.to_string(), escape(data)],
+            Self::AddSheet(sheet) => vec![escape("add"), escape(sheet)],
+            Self::RemoveSheet(sheet) => vec![escape("remove"), escape(sheet)],
+            Self::RenameSheet{old, new} => vec![escape("rename"), escape(old), escape(new)],
+            Self::DefineName{name, formula} => vec![escape("name"), escape(name), escape(formula)],
+            Self::RemoveName(name) => vec![escape("unname"), escape(name)],
+        };
+        fields.join("\t")
+    }
+
+    fn decode(line: &str) -> Option<Self> {
+        let fields = line.split('\t').map(unescape).collect::<Option<Vec<_>>>()?;
+        let mutation = match fields.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+            ["set", sheet, cell_id, data] => {
+                Self::SetCell{sheet: sheet.to_string(), cell_id: CellId::parse(cell_id).ok()?, data: data.to_string()}
+            },
+            ["add", sheet] => Self::AddSheet(sheet.to_string()),
+            ["remove", sheet] => Self::RemoveSheet(sheet.to_string()),
+            ["rename", old, new] => Self::RenameSheet{old: old.to_string(), new: new.to_string()},
+            ["name", name, formula] => Self::DefineName{name: name.to_string(), formula: formula.to_string()},
+            ["unname", name] => Self::RemoveName(name.to_string()),
+            _ => return None,
+        };
+        Some(mutation)
+    }
+}
+
+pub(crate) fn escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub(crate) fn unescape(field: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '\\' => unescaped.push('\\'),
+            't' => unescaped.push('\t'),
+            'n' => unescaped.push('\n'),
+            'r' => unescaped.push('\r'),
+            _ => return None,
+        }
+    }
+    Some(unescaped)
+}
+
+/// Journal is an append-only file of the mutations of a workbook since it
+/// was last saved, so a crashed session can be recovered by replaying them
+/// over the saved file. Every entry is flushed to the operating system when
+/// recorded, which survives the process crashing; syncing as well survives
+/// the machine losing power, at the cost of a disk write per entry.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    sync: bool,
+}
+
+impl Journal {
+    /// Open a journal, creating it if it does not exist, to append to it.
+    /// An unfinished last entry, as left by a crash while writing it, is
+    /// cut off so the next entry starts on a line of its own.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(&path)?;
+        truncate_torn_entry(&mut file)?;
+        if file.metadata()?.len() == 0 {
+            writeln!(file, "{}", HEADER)?;
+        }
+        Ok(Self{path, file, sync: false})
+    }
+
+    /// Sync every entry to disk as it is recorded.
+    pub fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append a mutation.
+    pub fn record(&mut self, mutation: &Mutation) -> io::Result<()> {
+        writeln!(self.file, "{}", mutation.encode())?;
+        self.file.flush()?;
+        if self.sync {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Cut the journal back to a length it had, such as to take back an
+    /// entry whose mutation failed to apply.
+    fn truncate(&mut self, length: u64) -> io::Result<()> {
+        self.file.set_len(length)?;
+        if self.sync {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Drop the oldest `count` entries, such as those a save made durable.
+    /// The rest are written to a new file which then replaces the journal.
+    pub fn discard(&mut self, count: usize) -> Result<(), JournalError> {
+        let mutations = read_journal(&self.path)?;
+        let temporary = self.path.with_extension("tmp");
+        let mut file = File::create(&temporary)?;
+        writeln!(file, "{}", HEADER)?;
+        for mutation in mutations.iter().skip(count) {
+            writeln!(file, "{}", mutation.encode())?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&temporary, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    /// Empty the journal, such as once the workbook has been saved.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        writeln!(self.file, "{}", HEADER)?;
+        self.file.flush()?;
+        if self.sync {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// Cut everything after the last newline off a file, unless it ends with
+/// one.
+fn truncate_torn_entry(file: &mut File) -> io::Result<()> {
+    let length = file.metadata()?.len();
+    if length == 0 {
+        return Ok(());
+    }
+    let mut last = [0; 1];
+    file.seek(SeekFrom::Start(length - 1))?;
+    file.read_exact(&mut last)?;
+    if last[0] == b'\n' {
+        return Ok(());
+    }
+    let mut contents = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut contents)?;
+    let end = contents.iter().rposition(|&byte| byte == b'\n').map_or(0, |index| index + 1);
+    file.set_len(end as u64)
+}
+
+/// Read the mutations of a journal. An unfinished last entry, as left by a
+/// crash while writing it, is ignored.
+pub fn read_journal(path: impl AsRef<Path>) -> Result<Vec<Mutation>, JournalError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut mutations = Vec::new();
+    let mut line = String::new();
+    let mut number = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+            break;
+        }
+        number += 1;
+        let entry = line.trim_end_matches(['\n', '\r']);
+        if number == 1 {
+            if entry != HEADER {
+                return Err(JournalError::Corrupt(number));
+            }
+            continue;
+        }
+        mutations.push(Mutation::decode(entry).ok_or(JournalError::Corrupt(number))?);
+    }
+    Ok(mutations)
+}
+
+impl<T: Arithmetic> Workbook<T> {
+    /// Apply a mutation, as recorded in a journal.
+    pub fn apply(&mut self, mutation: &Mutation) -> Result<(), WorkbookError> {
+        match mutation {
+            Mutation::SetCell{sheet, cell_id, data} => self.set_cell(sheet, *cell_id, data.clone())?,
+            Mutation::AddSheet(sheet) => {
+                self.add_sheet(sheet)?;
+            },
+            Mutation::RemoveSheet(sheet) => {
+                self.remove_sheet(sheet)?;
+            },
+            Mutation::RenameSheet{old, new} => {
+                self.rename_sheet(old, new)?;
+            },
+            Mutation::DefineName{name, formula} => {
+                let formula = Formula::try_from(formula.as_str()).map_err(|_| WorkbookError::InvalidName(name.clone()))?;
+                self.define_name(name, formula)?;
+            },
+            Mutation::RemoveName(name) => {
+                self.remove_name(name)?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Replay a journal over this workbook, loaded from the file saved last
+    /// before a crash, and get the number of mutations replayed.
+    pub fn recover(&mut self, journal: impl AsRef<Path>) -> Result<usize, JournalError> {
+        let mutations = read_journal(journal)?;
+        for (index, mutation) in mutations.iter().enumerate() {
+            // The header is line 1.
+            self.apply(mutation).map_err(|error| JournalError::Replay{line: index + 2, error})?;
+        }
+        Ok(mutations.len())
+    }
+}
+
+/// Journaled is a workbook whose mutations are recorded in a journal. A
+/// mutation is recorded before it is applied, so it is durable once it has
+/// been applied.
+pub struct Journaled<T: Arithmetic=f64> {
+    workbook: Workbook<T>,
+    journal: Journal,
+    revision: u64,
+    /// The revision the first entry of the journal follows.
+    base: u64,
+    dirty: HashSet<String>,
+}
+
+impl<T: Arithmetic> Journaled<T> {
+    pub fn new(workbook: Workbook<T>, journal: Journal) -> Self {
+        Self{workbook, journal, revision: 0, base: 0, dirty: HashSet::new()}
+    }
+
+    pub fn workbook(&self) -> &Workbook<T> {
+        &self.workbook
+    }
+
+    pub fn journal(&self) -> &Journal {
+        &self.journal
+    }
+
+    /// Record a mutation and apply it. Mutations which fail to be recorded
+    /// are not applied, and those which fail to apply are cut off the
+    /// journal again.
+    pub fn apply(&mut self, mutation: Mutation) -> Result<(), JournalError> {
+        let length = self.journal.file.metadata()?.len();
+        self.journal.record(&mutation)?;
+        if let Err(error) = self.workbook.apply(&mutation) {
+            self.journal.truncate(length)?;
+            return Err(error.into());
+        }
+        self.revision += 1;
+        match &mutation {
+            Mutation::RenameSheet{old, new} => {
+                self.dirty.remove(&old.to_lowercase());
+                self.dirty.insert(new.to_lowercase());
+            },
+            Mutation::RemoveSheet(sheet) => {
+                self.dirty.remove(&sheet.to_lowercase());
+            },
+            mutation => {
+                self.dirty.extend(mutation.sheet().map(str::to_lowercase));
+            },
+        }
+        Ok(())
+    }
+
+    pub fn set_cell(&mut self, sheet: &str, cell_id: CellId, data: String) -> Result<(), JournalError> {
+        self.apply(Mutation::SetCell{sheet: sheet.to_string(), cell_id, data})
+    }
+
+    /// Get the number of mutations applied so far.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Whether a sheet changed since the last checkpoint.
+    pub fn is_dirty(&self, sheet: &str) -> bool {
+        self.dirty.contains(&sheet.to_lowercase())
+    }
+
+    /// Get the names of the sheets changed since the last checkpoint, in
+    /// workbook order.
+    pub fn dirty_sheets(&self) -> Vec<String> {
+        self.workbook.sheet_names().filter(|name| self.is_dirty(name)).map(str::to_string).collect()
+    }
+
+    /// Mark the workbook as saved: empty the journal and forget the dirty
+    /// sheets.
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        self.journal.clear()?;
+        self.base = self.revision;
+        self.dirty.clear();
+        Ok(())
+    }
+
+    /// Drop the entries of the journal up to a revision a save made
+    /// durable, keeping those applied since.
+    pub fn checkpoint_through(&mut self, revision: u64) -> Result<(), JournalError> {
+        let revision = revision.min(self.revision);
+        if revision > self.base {
+            self.journal.discard((revision - self.base) as usize)?;
+            self.base = revision;
+        }
+        Ok(())
+    }
+
+    /// Take the sheets changed since they were last taken, lowercased.
+    pub(crate) fn take_dirty(&mut self) -> HashSet<String> {
+        std::mem::take(&mut self.dirty)
+    }
+
+    pub fn into_inner(self) -> Workbook<T> {
+        self.workbook
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reopening_cuts_torn_entries() {
+        let path = std::env::temp_dir().join(format!("xlnt-journal-{}.log", std::process::id()));
+        let mut journal = Journal::open(&path).unwrap();
+        let first = Mutation::SetCell{sheet: "S".to_string(), cell_id: CellId::new(0, 0), data: "1".to_string()};
+        journal.record(&first).unwrap();
+        drop(journal);
+        // A crash while writing the second entry.
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"set\tS\tA2\t12").unwrap();
+        assert_eq!(read_journal(&path).unwrap(), vec![first.clone()]);
+        let mut journal = Journal::open(&path).unwrap();
+        let second = Mutation::SetCell{sheet: "S".to_string(), cell_id: CellId::new(1, 0), data: "2".to_string()};
+        journal.record(&second).unwrap();
+        assert_eq!(read_journal(&path).unwrap(), vec![first, second]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reopening_a_torn_header_starts_over() {
+        let path = std::env::temp_dir().join(format!("xlnt-journal-header-{}.log", std::process::id()));
+        std::fs::write(&path, &HEADER[..5]).unwrap();
+        let mut journal = Journal::open(&path).unwrap();
+        journal.record(&Mutation::AddSheet("S".to_string())).unwrap();
+        assert_eq!(read_journal(&path).unwrap(), vec![Mutation::AddSheet("S".to_string())]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn journaled_mutations_are_recovered() {
+        let path = std::env::temp_dir().join(format!("xlnt-journal-recover-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut journaled = Journaled::<f64>::new(Workbook::new(), Journal::open(&path).unwrap());
+        journaled.apply(Mutation::AddSheet("S".to_string())).unwrap();
+        journaled.set_cell("S", CellId::new(0, 0), "2".to_string()).unwrap();
+        assert_eq!(journaled.revision(), 2);
+        assert!(journaled.is_dirty("s"));
+        let mut recovered = Workbook::<f64>::new();
+        assert_eq!(recovered.recover(&path).unwrap(), 2);
+        assert!(recovered.sheet("S").is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn mutations_which_fail_to_apply_are_cut_off_the_journal() {
+        let path = std::env::temp_dir().join(format!("xlnt-journal-failed-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut journaled = Journaled::<f64>::new(Workbook::new(), Journal::open(&path).unwrap());
+        journaled.apply(Mutation::AddSheet("S".to_string())).unwrap();
+        assert!(journaled.apply(Mutation::RemoveSheet("T".to_string())).is_err());
+        assert_eq!(journaled.revision(), 1);
+        assert_eq!(read_journal(&path).unwrap(), vec![Mutation::AddSheet("S".to_string())]);
+        journaled.apply(Mutation::AddSheet("T".to_string())).unwrap();
+        assert_eq!(read_journal(&path).unwrap().len(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn mutations_which_fail_to_be_recorded_are_not_applied() {
+        let path = std::env::temp_dir().join(format!("xlnt-journal-unwritable-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut journal = Journal::open(&path).unwrap();
+        journal.file = File::open(&path).unwrap();
+        let mut journaled = Journaled::<f64>::new(Workbook::new(), journal);
+        assert!(journaled.apply(Mutation::AddSheet("S".to_string())).is_err());
+        assert!(journaled.workbook().sheet("S").is_none());
+        assert_eq!(journaled.revision(), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
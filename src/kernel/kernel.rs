@@ -1,6 +1,20 @@
 use super::arithmetic::{Arithmetic, Floating};
+use super::array::Array2D;
+use super::audit;
+use super::currency::{same_currency, split_amount};
+use super::functions::FunctionRegistry;
+use super::lookup::LookupIndex;
+use super::parser::{self, ParseOptions};
+use super::settings::CalcSettings;
+use super::strings::CellText;
+#[cfg(feature = "units")]
+use super::units::{split_quantity, with_unit, Unit};
+use super::value_parser;
 use thiserror::Error;
+use std::any::Any;
+use std::borrow::Cow;
 use std::iter::Iterator;
+use std::rc::Rc;
 
 /// NumericAttribute represents some extra parsed attribute found on a number.
 /// For instance, a cell may represent a percentage value in which case we 
@@ -9,7 +23,23 @@ use std::iter::Iterator;
 #[derive(Clone, Debug, PartialEq)]
 pub enum NumericAttribute {
     Percent,
+    /// A currency as written, which may be a symbol like `$` or an ISO 4217
+    /// code like `USD`.
     Currency(String),
+    /// A physical unit, like `m/s`, with the number in that unit.
+    #[cfg(feature = "units")]
+    Unit(Unit),
+}
+
+impl NumericAttribute {
+    /// Whether numbers with the two attributes may be added, which is when
+    /// they are alike or are the same currency written differently.
+    pub fn is_compatible(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Currency(a), Self::Currency(b)) => same_currency(a, b),
+            (a, b) => a == b,
+        }
+    }
 }
 
 /// Numeric is a number with attributes attached, like percentage or currency.
@@ -37,30 +67,58 @@ where T: Arithmetic {
         }
     }
 
+    /// Create a number with an optional attribute.
+    pub fn new(number: T, attr: Option<NumericAttribute>) -> Self {
+        Self{number, attr}
+    }
+
+    /// Get the number as written, before its attribute is applied. For
+    /// `50%` this is 50 while the value is 0.5.
+    pub fn number(&self) -> T {
+        self.number
+    }
+
+    /// Get the attribute attached to this number, if any.
+    pub fn attr(&self) -> Option<&NumericAttribute> {
+        self.attr.as_ref()
+    }
+
+    /// Convert the number to another number type. Numbers are copied as they
+    /// are to the same type, and otherwise go through their exact text, so
+    /// no digits are lost between types wider than `f64`. Only types
+    /// without such text go through `f64`.
+    pub fn cast<U: Arithmetic>(&self) -> Numeric<U> {
+        let number = if let Some(number) = (&self.number as &dyn Any).downcast_ref::<U>() {
+            *number
+        } else {
+            self.number.to_text()
+                .and_then(|text| text.parse().ok())
+                .unwrap_or_else(|| Floating::from_f64(self.number.to_f64()))
+        };
+        Numeric{number, attr: self.attr.clone()}
+    }
+
     /// Tries to add another number. This may fail if we add two numerics with
-    /// different attributes.
+    /// different attributes. Percentages only stay percentages when added to
+    /// percentages, `1+50%` is 1.5.
     pub fn try_add(self, other: Self) -> Option<Self> {
-        match self.attr {
-            None => {
-                Some(Self{number: self.value() + other.value(), attr: other.attr.clone()})
+        match (&self.attr, &other.attr) {
+            (Some(NumericAttribute::Percent), Some(NumericAttribute::Percent)) => {
+                Some(Self{number: self.number + other.number, attr: self.attr})
             },
-            Some(ref attr) => {
-                match other.attr {
-                    None => Some(Self{number: self.value() + other.value(), attr: self.attr.clone()}),
-                    Some(ref other_attr) => {
-                        if attr == other_attr {
-                            Some(Self{number: self.value() + other.value(), attr: self.attr.clone()})
-                        } else {
-                            None
-                        }
-                    }
-                }
-            }
+            (Some(NumericAttribute::Percent), None) | (None, Some(NumericAttribute::Percent)) => {
+                Some(Self::new(self.value() + other.value(), None))
+            },
+            (None, _) => Some(Self{number: self.value() + other.value(), attr: other.attr}),
+            (Some(_), None) => Some(Self{number: self.value() + other.value(), attr: self.attr}),
+            (Some(attr), Some(other_attr)) => attr.is_compatible(other_attr)
+                .then(|| Self{number: self.value() + other.value(), attr: self.attr.clone()}),
         }
     }
 }
 
 /// A primitive type which a cell may represent.
+#[derive(Clone, Debug)]
 pub enum Primitive<T=f64> 
 where T: Arithmetic {
     Number(Numeric<T>),
@@ -68,18 +126,99 @@ where T: Arithmetic {
     Date(chrono::NaiveDate),
     Time(chrono::TimeDelta),
     IPAddress([u8; 4]),
+    /// Text computed by a formula or read from a text cell. Parsing cell
+    /// contents never yields text, such cells are raw.
+    Text(String),
+}
+
+/// Parse a plain number, allowing a sign and thousands separators.
+fn parse_number<T: Arithmetic>(value: &str) -> Option<T> {
+    let digits = value.strip_prefix(['-', '+']).unwrap_or(value);
+    if !digits.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+    if value.contains(',') {
+        let (integer, _) = digits.split_once('.').unwrap_or((digits, ""));
+        let mut groups = integer.split(',');
+        let first = groups.next()?;
+        if first.is_empty() || first.len() > 3 || groups.any(|group| group.len() != 3) {
+            return None;
+        }
+        return value.replace(',', "").parse().ok();
+    }
+    value.parse().ok()
+}
+
+fn parse_numeric<T: Arithmetic>(value: &str) -> Option<Numeric<T>> {
+    if let Some(percent) = value.strip_suffix('%') {
+        return parse_number(percent.trim_end()).map(|number| Numeric::new(number, Some(NumericAttribute::Percent)));
+    }
+    if let Some(number) = parse_number(value) {
+        return Some(Numeric::new(number, None));
+    }
+    let (sign, unsigned) = match value.strip_prefix('-') {
+        Some(unsigned) => ("-", unsigned),
+        None => ("", value),
+    };
+    if let Some((currency, amount)) = split_amount(unsigned) {
+        let number = parse_number(&format!("{}{}", sign, amount))?;
+        return Some(Numeric::new(number, Some(NumericAttribute::Currency(currency.to_string()))));
+    }
+    #[cfg(feature = "units")]
+    if let Some((number, unit)) = split_quantity(value) {
+        return Some(with_unit(parse_number(number)?, unit));
+    }
+    None
+}
+
+fn parse_time(value: &str) -> Option<chrono::TimeDelta> {
+    let time = chrono::NaiveTime::parse_from_str(value, "%H:%M:%S")
+        .or_else(|_| chrono::NaiveTime::parse_from_str(value, "%H:%M"))
+        .ok()?;
+    Some(time.signed_duration_since(chrono::NaiveTime::MIN))
+}
+
+fn parse_ip_address(value: &str) -> Option<[u8; 4]> {
+    let mut octets = [0u8; 4];
+    let mut parts = value.split('.');
+    for octet in octets.iter_mut() {
+        let part = parts.next()?;
+        if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        *octet = part.parse().ok()?;
+    }
+    parts.next().is_none().then_some(octets)
 }
 
 impl<T: Arithmetic> TryFrom<&str> for Primitive<T> {
     type Error = ();
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        unimplemented!()
+        let value = value.trim();
+        if value.eq_ignore_ascii_case("TRUE") {
+            return Ok(Self::Bool(true));
+        }
+        if value.eq_ignore_ascii_case("FALSE") {
+            return Ok(Self::Bool(false));
+        }
+        if let Some(numeric) = parse_numeric(value) {
+            return Ok(Self::Number(numeric));
+        }
+        if let Some(octets) = parse_ip_address(value) {
+            return Ok(Self::IPAddress(octets));
+        }
+        for format in ["%Y-%m-%d", "%m/%d/%Y"] {
+            if let Ok(date) = chrono::NaiveDate::parse_from_str(value, format) {
+                return Ok(Self::Date(date));
+            }
+        }
+        parse_time(value).map(Self::Time).ok_or(())
     }
 }
 
-#[derive(Error, Debug)]
-enum ColumnParseError {
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnParseError {
     #[error("Encountered unexpected char {0}")]
     UnexpectedChar(char),
 
@@ -88,55 +227,86 @@ enum ColumnParseError {
 
     #[error("Did not contain numerical characters")]
     DidntContainNumber,
-}
 
-const fn ipow(x: u64, pow: u64) -> u64 {
-    match pow {
-        0 => 1,
-        1 => x,
-        _ => x * ipow(x, pow-1)
-    }
+    #[error("Row or column out of range")]
+    OutOfRange,
 }
 
+/// Convert column letters to a zero based column index, so `A` is 0, `Z` is
+/// 25 and `AA` is 26.
 const fn column_to_u64(column: &str) -> Result<u64, ColumnParseError> {
     let bytes = column.as_bytes();
-    match bytes.len() {
-        0 => Ok(0),
-        _ => {
-            let c = bytes[0];
-            let (_, s2) = bytes.split_at(1);
-            match c as char {
-                'A'..'Z' => {
-                    let res = ((c - b'A') as u64 * ipow(26, s2.len() as u64));
-                    unsafe {
-                        match column_to_u64(std::str::from_utf8_unchecked(s2)) {
-                            Ok(v) => Ok(v + res),
-                            Err(e) => Err(e),
-                        }
-                    }
-                },
-                'a'..'z' => {
-                    let res = ((c - b'a') as u64 * ipow(26, s2.len() as u64));
-                    unsafe {
-                        match column_to_u64(std::str::from_utf8_unchecked(s2)) {
-                            Ok(v) => Ok(v + res),
-                            Err(e) => Err(e),
-                        }
-                    }
-                },
-                _ => Err(ColumnParseError::UnexpectedChar(c as char)),
-            }
-        },
+    if bytes.is_empty() {
+        return Err(ColumnParseError::DidntStartAlpha);
+    }
+    let mut index = 0;
+    let mut column = 0u64;
+    while index < bytes.len() {
+        let c = bytes[index];
+        let digit = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a',
+            _ => return Err(ColumnParseError::UnexpectedChar(c as char)),
+        };
+        column = match column.checked_mul(26) {
+            Some(v) => v + digit as u64 + 1,
+            None => return Err(ColumnParseError::OutOfRange),
+        };
+        index += 1;
     }
+    Ok(column - 1)
 }
 
+/// Split a cell id like `AB12` into its column letters and row digits.
 const fn split_id(s: &str) -> Result<(&str, &str), ColumnParseError> {
-    Err(ColumnParseError::DidntStartAlpha)
+    let bytes = s.as_bytes();
+    let mut split = 0;
+    while split < bytes.len() && bytes[split].is_ascii_alphabetic() {
+        split += 1;
+    }
+    if split == 0 {
+        return match bytes.first() {
+            Some(c) if c.is_ascii_digit() => Err(ColumnParseError::DidntStartAlpha),
+            Some(c) => Err(ColumnParseError::UnexpectedChar(*c as char)),
+            None => Err(ColumnParseError::DidntStartAlpha),
+        };
+    }
+    if split == bytes.len() {
+        return Err(ColumnParseError::DidntContainNumber);
+    }
+    let mut index = split;
+    while index < bytes.len() {
+        if !bytes[index].is_ascii_digit() {
+            return Err(ColumnParseError::UnexpectedChar(bytes[index] as char));
+        }
+        index += 1;
+    }
+    Ok(s.split_at(split))
+}
+
+const fn row_to_u64(row: &str) -> Result<u64, ColumnParseError> {
+    let bytes = row.as_bytes();
+    let mut index = 0;
+    let mut value = 0u64;
+    while index < bytes.len() {
+        value = match value.checked_mul(10) {
+            Some(v) => v + (bytes[index] - b'0') as u64,
+            None => return Err(ColumnParseError::OutOfRange),
+        };
+        index += 1;
+    }
+    Ok(value)
 }
 
+/// Build a `CellId` from an A1 style id at compile time, e.g. `xl!("B3")`.
+#[macro_export]
 macro_rules! xl {
     ($s:expr) => {{
-        const parts = split_id.unwrap();
+        const ID: $crate::kernel::kernel::CellId = match $crate::kernel::kernel::CellId::parse($s) {
+            Ok(id) => id,
+            Err(_) => panic!("invalid cell id"),
+        };
+        ID
     }}
 }
 
@@ -147,68 +317,363 @@ pub struct CellId {
     col: u32,
 }
 
+impl std::fmt::Display for CellId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut letters = Vec::new();
+        let mut col = self.col as u64 + 1;
+        while col > 0 {
+            letters.push((b'A' + ((col - 1) % 26) as u8) as char);
+            col = (col - 1) / 26;
+        }
+        letters.iter().rev().try_for_each(|c| write!(f, "{}", c))?;
+        write!(f, "{}", self.row as u64 + 1)
+    }
+}
+
 impl CellId {
-    fn new(row: u32, col: u32) -> Self {
+    pub const fn new(row: u32, col: u32) -> Self {
         Self{row, col}
     }
+
+    /// Parse an A1 style id like `B3` (row 2, column 1). Rows start at 1 in
+    /// A1 notation and at 0 in a `CellId`.
+    pub const fn parse(id: &str) -> Result<Self, ColumnParseError> {
+        let (column, row) = match split_id(id) {
+            Ok(parts) => parts,
+            Err(e) => return Err(e),
+        };
+        let col = match column_to_u64(column) {
+            Ok(col) if col <= u32::MAX as u64 => col as u32,
+            Ok(_) => return Err(ColumnParseError::OutOfRange),
+            Err(e) => return Err(e),
+        };
+        let row = match row_to_u64(row) {
+            Ok(row) if row >= 1 && row <= u32::MAX as u64 => (row - 1) as u32,
+            Ok(_) => return Err(ColumnParseError::OutOfRange),
+            Err(e) => return Err(e),
+        };
+        Ok(Self{row, col})
+    }
+
+    pub fn row(&self) -> u32 {
+        self.row
+    }
+
+    pub fn col(&self) -> u32 {
+        self.col
+    }
+
+    /// Iterate the cells of the rectangle spanned by two corners, row by row.
+    pub fn range(start: CellId, end: CellId) -> impl Iterator<Item=CellId> {
+        let (top, bottom) = (start.row.min(end.row), start.row.max(end.row));
+        let (left, right) = (start.col.min(end.col), start.col.max(end.col));
+        (top..=bottom).flat_map(move |row| (left..=right).map(move |col| CellId::new(row, col)))
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum FunctionKind {
     Sum,
+    SumProduct,
+    Average,
+    Count,
     Prod,
     If,
     Sqrt,
     Sdev,
     Offset,
+    GetPivotData,
+    CubeValue,
+    Text,
+    TextBefore,
+    TextAfter,
+    Proper,
+    Exact,
+    Rept,
+    Char,
+    Code,
+    Unichar,
+    Unicode,
+    Choose,
+    ChooseCols,
+    ChooseRows,
+    Take,
+    Drop,
+    HStack,
+    VStack,
+    Aggregate,
+    Match,
+    VLookup,
+    CountIf,
+    Search,
+    Find,
+    Replace,
+    Convert,
+    ChangedSince,
 }
 
+#[derive(Clone, Debug)]
 pub enum Formula<T: Arithmetic> {
+    /// A number written in the formula, like `2` or `50%`.
+    NumberLit(Numeric<T>),
+    /// Text written in the formula between double quotes, like `"yes"`.
+    TextLit(String),
+    /// `TRUE` or `FALSE` written in the formula.
+    BoolLit(bool),
     CellRef(CellId),
     CellRange(CellId, CellId),
+    /// A defined name.
+    Name(String),
+    /// A cell reference, range or name qualified with the sheet it lives on.
+    SheetRef(String, Box<Formula<T>>),
     Function{
         kind: FunctionKind,
         arguments: Vec<Value<T>>,
     },
+    /// A call of a function which is not built in, such as one registered
+    /// for a workbook as `MYORG.RISK`. Unknown functions evaluate to
+    /// `#NAME?`.
+    Custom{
+        name: String,
+        arguments: Vec<Value<T>>,
+    },
     Add(Box<Value<T>>, Box<Value<T>>),
     Mul(Box<Value<T>>, Box<Value<T>>),
     Sub(Box<Value<T>>, Box<Value<T>>),
     Div(Box<Value<T>>, Box<Value<T>>),
+    Pow(Box<Value<T>>, Box<Value<T>>),
+    /// Unary minus, `-A1`.
+    Neg(Box<Value<T>>),
+    /// The postfix percent operator, `A1%`, dividing by 100.
+    Percent(Box<Value<T>>),
+    /// Text concatenation, `A1&B1`.
+    Concat(Box<Value<T>>, Box<Value<T>>),
     Cmp(Box<Value<T>>, Box<Value<T>>),
     Lt(Box<Value<T>>, Box<Value<T>>),
     Gr(Box<Value<T>>, Box<Value<T>>),
+    Le(Box<Value<T>>, Box<Value<T>>),
+    Ge(Box<Value<T>>, Box<Value<T>>),
+    Ne(Box<Value<T>>, Box<Value<T>>),
+}
+
+impl<T: Arithmetic> Formula<T> {
+    /// Parse a formula, without the leading `=`, using the given options.
+    pub fn parse(formula: &str, options: &ParseOptions) -> Result<Self, FormulaParseError> {
+        parser::parse(formula, options)
+    }
+
+    /// Get the cells on the same sheet this formula directly refers to, with
+    /// ranges expanded. Defined names and references qualified with a sheet
+    /// are not included.
+    pub fn precedents(&self) -> Vec<CellId> {
+        let mut cells = Vec::new();
+        self.collect_precedents(&mut cells);
+        cells
+    }
+
+    fn collect_precedents(&self, cells: &mut Vec<CellId>) {
+        match self {
+            Self::CellRef(cell_id) => cells.push(*cell_id),
+            Self::CellRange(start, end) => cells.extend(CellId::range(*start, *end)),
+            Self::Name(_) | Self::SheetRef(..) => {},
+            _ => {
+                for operand in self.operands() {
+                    operand.collect_precedents(cells);
+                }
+            },
+        }
+    }
+
+    /// Get the name of the function this formula calls, as written in
+    /// formulas.
+    pub fn function_name(&self) -> Option<&str> {
+        match self {
+            Self::Function{kind, ..} => Some(kind.name()),
+            Self::Custom{name, ..} => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Get the operands of an operator or the arguments of a function.
+    pub fn operands(&self) -> Vec<&Value<T>> {
+        match self {
+            Self::NumberLit(_) | Self::TextLit(_) | Self::BoolLit(_) => Vec::new(),
+            Self::CellRef(_) | Self::CellRange(..) | Self::Name(_) | Self::SheetRef(..) => Vec::new(),
+            Self::Function{arguments, ..} | Self::Custom{arguments, ..} => arguments.iter().collect(),
+            Self::Neg(operand) | Self::Percent(operand) => vec![operand],
+            Self::Add(lhs, rhs) |
+            Self::Mul(lhs, rhs) |
+            Self::Sub(lhs, rhs) |
+            Self::Div(lhs, rhs) |
+            Self::Pow(lhs, rhs) |
+            Self::Concat(lhs, rhs) |
+            Self::Cmp(lhs, rhs) |
+            Self::Lt(lhs, rhs) |
+            Self::Gr(lhs, rhs) |
+            Self::Le(lhs, rhs) |
+            Self::Ge(lhs, rhs) |
+            Self::Ne(lhs, rhs) => vec![lhs, rhs],
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Value<T>> {
+        match self {
+            Self::NumberLit(_) | Self::TextLit(_) | Self::BoolLit(_) => Vec::new(),
+            Self::CellRef(_) | Self::CellRange(..) | Self::Name(_) | Self::SheetRef(..) => Vec::new(),
+            Self::Function{arguments, ..} | Self::Custom{arguments, ..} => arguments.iter_mut().collect(),
+            Self::Neg(operand) | Self::Percent(operand) => vec![operand],
+            Self::Add(lhs, rhs) |
+            Self::Mul(lhs, rhs) |
+            Self::Sub(lhs, rhs) |
+            Self::Div(lhs, rhs) |
+            Self::Pow(lhs, rhs) |
+            Self::Concat(lhs, rhs) |
+            Self::Cmp(lhs, rhs) |
+            Self::Lt(lhs, rhs) |
+            Self::Gr(lhs, rhs) |
+            Self::Le(lhs, rhs) |
+            Self::Ge(lhs, rhs) |
+            Self::Ne(lhs, rhs) => vec![lhs, rhs],
+        }
+    }
+
+    /// Call `f` on every reference in this formula: cell references, ranges
+    /// and names, along with the sheet they are qualified with. `f` may
+    /// replace the reference.
+    pub fn rewrite_references(&mut self, f: &mut dyn FnMut(Option<&str>, &mut Formula<T>)) {
+        match self {
+            Self::CellRef(_) | Self::CellRange(..) | Self::Name(_) => f(None, self),
+            Self::SheetRef(sheet, target) => f(Some(sheet.as_str()), target),
+            _ => {
+                for operand in self.operands_mut() {
+                    if let Value::Formula(formula) = operand {
+                        formula.rewrite_references(f);
+                    }
+                }
+            },
+        }
+    }
 }
 
 impl<T: Arithmetic> TryFrom<&str> for Formula<T> {
     type Error=FormulaParseError;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        unimplemented!()
+        Self::parse(value, &ParseOptions::default())
     }
 }
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone, PartialEq)]
 pub enum FormulaParseError {
     #[error("unknown function")]
     UnknownFunction(String),
+
+    #[error("unexpected end of formula")]
+    UnexpectedEnd,
+
+    #[error("unexpected token {0}")]
+    UnexpectedToken(String),
+
+    #[error("invalid number {0}")]
+    InvalidNumber(String),
+
+    #[error("{0} is not supported")]
+    Unsupported(String),
+
+    #[error("implicit intersection is not allowed in strict mode")]
+    ImplicitIntersection,
+
+    #[error("union of references in function arguments is not allowed in strict mode")]
+    UnionInArguments,
+
+    #[error("name {0} reads like a reference, which is not allowed in strict mode")]
+    AmbiguousName(String),
+
+    #[error("formula of {length} characters exceeds {max} characters")]
+    TooLong{length: usize, max: usize},
+
+    #[error("formula nesting exceeds {max} levels")]
+    TooDeep{max: usize},
+}
+
+/// CellError is an error value produced by evaluating a formula. Like in
+/// spreadsheets, errors are values which propagate through dependent cells.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CellError {
+    #[error("#NULL!")]
+    Null,
+
+    #[error("#DIV/0!")]
+    Div0,
+
+    #[error("#VALUE!")]
+    Value,
+
+    #[error("#REF!")]
+    Ref,
+
+    #[error("#NAME?")]
+    Name,
+
+    #[error("#NUM!")]
+    Num,
+
+    #[error("#N/A")]
+    NA,
+
+    /// A dynamic array cannot spill because cells it would cover are not
+    /// empty.
+    #[error("#SPILL!")]
+    Spill,
+
+    /// A calculation would produce an empty array.
+    #[error("#CALC!")]
+    Calc,
+
+    /// Numbers with units which do not measure the same were added, or a
+    /// number with a unit was raised to a power which is not an integer.
+    #[cfg(feature = "units")]
+    #[error("#UNIT!")]
+    Unit,
 }
 
+#[derive(Clone, Debug)]
 pub enum Value<T=f64>
 where T: Arithmetic {
+    /// A blank cell.
+    Empty,
     Raw,
     Primitive(Primitive<T>),
     Formula(Formula<T>),
     FormulaParseError(FormulaParseError),
+    Error(CellError),
+    /// A dynamic array, which spills into the cells right of and below the
+    /// formula producing it.
+    Array(Array2D<Value<T>>),
+}
+
+impl<T: Arithmetic> Value<T> {
+    /// Whether this value is an error, either from evaluation or from parsing.
+    pub fn is_error(&self) -> bool {
+        matches!(self, Self::Error(_) | Self::FormulaParseError(_))
+    }
+
+    fn collect_precedents(&self, cells: &mut Vec<CellId>) {
+        if let Self::Formula(formula) = self {
+            formula.collect_precedents(cells);
+        }
+    }
 }
 
 impl<T: Arithmetic> From<&str> for Value<T> {
     fn from(value: &str) -> Self {
         let value = value.trim();
-        if !value.starts_with('=') {
-            if let Ok(primitive) = Primitive::try_from(value) {
-                Self::Primitive(primitive)
-            } else {
-                Self::Raw
-            }
+        if value.is_empty() {
+            Self::Empty
+        } else if value.starts_with('\'') {
+            Self::Raw
+        } else if !value.starts_with('=') {
+            value_parser::parse_value(value)
         } else {
             let (_, remainder) = value.split_at(1);
             match Formula::try_from(remainder) {
@@ -219,31 +684,336 @@ impl<T: Arithmetic> From<&str> for Value<T> {
     }
 }
 
+/// Quote text so that a cell created from it holds exactly that text, the
+/// way a leading apostrophe does when typing into a spreadsheet.
+pub fn escape_text(text: &str) -> String {
+    match Value::<f64>::from(text) {
+        Value::Raw if !text.starts_with('\'') => text.to_string(),
+        _ if text.is_empty() => String::new(),
+        _ => format!("'{}", text),
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Cell<T: Arithmetic> {
-    raw: String,
+    raw: CellText,
     value: Value<T>,
 }
 
+impl<T: Arithmetic> Cell<T> {
+    /// Get the text this cell was created from.
+    pub fn raw(&self) -> &str {
+        self.raw.as_str()
+    }
+
+    /// Get the parsed value of this cell.
+    pub fn value(&self) -> &Value<T> {
+        &self.value
+    }
+
+    /// Get the text of this cell, without the leading apostrophe which marks
+    /// text that would otherwise be parsed as a value or formula.
+    pub fn text(&self) -> &str {
+        let raw = self.raw();
+        raw.strip_prefix('\'').unwrap_or(raw)
+    }
+
+    /// Get the formula in this cell, if it holds one.
+    pub fn formula(&self) -> Option<&Formula<T>> {
+        match self.value {
+            Value::Formula(ref formula) => Some(formula),
+            _ => None,
+        }
+    }
+
+    /// Replace the value of this cell, keeping its raw contents until they
+    /// are refreshed.
+    pub fn set_value(&mut self, value: Value<T>) {
+        self.value = value;
+    }
+
+    pub(crate) fn set_raw(&mut self, raw: String) {
+        self.raw = raw.into();
+    }
+
+    pub(crate) fn raw_text(&self) -> &CellText {
+        &self.raw
+    }
+
+    pub(crate) fn raw_text_mut(&mut self) -> &mut CellText {
+        &mut self.raw
+    }
+}
+
 impl<T: Arithmetic> From<String> for Cell<T> {
     fn from(s: String) -> Self {
         let value = s.as_str().into();
-        Self{raw: s, value}
+        Self{raw: s.into(), value}
     }
 }
 
+/// Evaluate every cell of the rectangle between two corners with `evaluate`.
+pub(crate) fn evaluate_rectangle<T, E>(
+    first: CellId,
+    second: CellId,
+    mut evaluate: impl FnMut(CellId) -> Result<Value<T>, E>,
+) -> Result<Array2D<Value<T>>, E>
+where T: Arithmetic {
+    let start = CellId::new(first.row.min(second.row), first.col.min(second.col));
+    let rows = first.row.abs_diff(second.row) as usize + 1;
+    let cols = first.col.abs_diff(second.col) as usize + 1;
+    Array2D::try_from_fn(rows, cols, |row, col| evaluate(CellId::new(start.row + row as u32, start.col + col as u32)))
+}
+
 pub trait Kernel<E: std::error::Error, T: Arithmetic=f64> {
     fn get_cell(&self, cell_id: CellId) -> Option<Cell<T>>;
-    fn evaluate_cell(&self, cell_id: CellId) -> Result<Value, E>;
+    fn evaluate_cell(&self, cell_id: CellId) -> Result<Value<T>, E>;
     fn set_cell(&mut self, cell_id: CellId, data: String);
+
+    /// Resolve a defined name, optionally scoped to a sheet, to the formula it
+    /// stands for. Kernels without defined names resolve nothing.
+    fn resolve_name(&self, sheet: Option<&str>, name: &str) -> Option<Formula<T>> {
+        let _ = (sheet, name);
+        None
+    }
+
+    /// Get the functions registered by the embedder. Kernels without a
+    /// registry only know the built-ins.
+    fn functions(&self) -> Option<&FunctionRegistry<T>> {
+        None
+    }
+
+    /// Get the value a cell, optionally of another sheet, had in a data
+    /// snapshot, or None if there is no snapshot with the ID. Kernels
+    /// without snapshots have none.
+    fn snapshot_value(&self, snapshot: &str, sheet: Option<&str>, cell_id: CellId) -> Option<Value<T>> {
+        let _ = (snapshot, sheet, cell_id);
+        None
+    }
+
+    /// Get a cell on another sheet. Kernels without sheets have none.
+    fn get_sheet_cell(&self, sheet: &str, cell_id: CellId) -> Option<Cell<T>> {
+        let _ = (sheet, cell_id);
+        None
+    }
+
+    /// Whether a row, optionally of another sheet, is hidden by a collapsed
+    /// outline group. Kernels without outlines hide nothing.
+    fn is_row_hidden(&self, sheet: Option<&str>, row: u32) -> bool {
+        let _ = (sheet, row);
+        false
+    }
+
+    /// Evaluate a cell on another sheet. Kernels without sheets treat such
+    /// references as invalid.
+    fn evaluate_sheet_cell(&self, sheet: &str, cell_id: CellId) -> Result<Value<T>, E> {
+        let _ = (sheet, cell_id);
+        Ok(Value::Error(CellError::Ref))
+    }
+
+    /// Evaluate the rectangle of cells between two corners, in any order,
+    /// into a buffer with a row for every row of the rectangle.
+    fn evaluate_range(&self, first: CellId, second: CellId) -> Result<Array2D<Value<T>>, E> {
+        evaluate_rectangle(first, second, |cell_id| self.evaluate_cell(cell_id))
+    }
+
+    /// Get the values of a column of cells between two rows, optionally of
+    /// another sheet, for functions with a vectorized path over ranges.
+    /// Kernels give None, as by default, when the column is not stored
+    /// densely or holds formulas, and functions then evaluate the cells one
+    /// by one.
+    fn column_slice(&self, sheet: Option<&str>, col: u32, first_row: u32, last_row: u32) -> Option<Cow<'_, [Value<T>]>> {
+        let _ = (sheet, col, first_row, last_row);
+        None
+    }
+
+    /// Get an index of the keys of a column between two rows, optionally of
+    /// another sheet, for `MATCH` and `VLOOKUP` to find keys in. Kernels give
+    /// None, as by default, when they keep no index of the column, and
+    /// lookups compare the key with the cells one by one.
+    fn lookup_index(&self, sheet: Option<&str>, col: u32, first_row: u32, last_row: u32) -> Option<Rc<LookupIndex<T>>> {
+        let _ = (sheet, col, first_row, last_row);
+        None
+    }
+
+    /// Get the settings formulas are calculated with.
+    fn calc_settings(&self) -> CalcSettings {
+        CalcSettings::default()
+    }
+
+    /// Get the chain of cells through which an error value propagated into
+    /// `cell_id`, starting with `cell_id` and ending at the cell where the
+    /// error originated. Empty if the cell does not evaluate to an error.
+    fn trace_error(&self, cell_id: CellId) -> Result<Vec<CellId>, E>
+    where Self: Sized {
+        audit::trace_error(self, cell_id)
+    }
+
+    /// Get every dependency path leading from `from` to its (possibly
+    /// indirect) precedent `to`. Each path starts with `from` and ends with
+    /// `to`; no paths are returned if `from` does not depend on `to`. At
+    /// most `audit::MAX_PATHS` paths are returned.
+    fn path_between(&self, from: CellId, to: CellId) -> Vec<Vec<CellId>>
+    where Self: Sized {
+        audit::path_between(self, from, to)
+    }
 }
 
-//#[cfg(test)]
-//mod tests {
-//    use super::*;
-//
-//    #[test]
-//    fn it_works() {
-//        let result = add(2, 2);
-//        assert_eq!(result, 4);
-//    }
-//}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::{CellError, Formula};
+    use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Sub, SubAssign};
+    use crate::kernel::worksheet::Worksheet;
+
+    fn reference(row: u32, col: u32) -> Box<Value<f64>> {
+        Box::new(Value::Formula(Formula::CellRef(CellId::new(row, col))))
+    }
+
+    #[test]
+    fn precedents_expand_ranges() {
+        let range = Value::Formula(Formula::CellRange(CellId::new(0, 1), CellId::new(1, 2)));
+        let sum = Formula::Function{kind: FunctionKind::Sum, arguments: vec![range, Value::Formula(Formula::Add(reference(0, 0), reference(3, 0)))]};
+        let expected = [(0, 1), (0, 2), (1, 1), (1, 2), (0, 0), (3, 0)].map(|(row, col)| CellId::new(row, col));
+        assert_eq!(sum.precedents(), expected);
+    }
+
+    #[test]
+    fn errors_display_like_spreadsheets() {
+        assert_eq!(CellError::Div0.to_string(), "#DIV/0!");
+        assert_eq!(CellError::NA.to_string(), "#N/A");
+        assert!(Value::<f64>::Error(CellError::Ref).is_error());
+        assert!(!Value::<f64>::Raw.is_error());
+    }
+
+    fn percent(number: f64) -> Numeric {
+        Numeric::new(number, Some(NumericAttribute::Percent))
+    }
+
+    #[test]
+    fn adding_percentages_scales_once() {
+        assert_eq!(Numeric::new(1.0, None).try_add(percent(50.0)).map(|sum| sum.value()), Some(1.5));
+        assert_eq!(percent(50.0).try_add(Numeric::new(10.0, None)).map(|sum| sum.value()), Some(10.5));
+        let both = percent(50.0).try_add(percent(50.0)).unwrap();
+        assert_eq!((both.value(), both.attr()), (1.0, Some(&NumericAttribute::Percent)));
+        assert!(percent(50.0).try_add(Numeric::new(1.0, Some(NumericAttribute::Currency("$".to_string())))).is_none());
+    }
+
+    /// Wide is a fixed-point number with six decimals and more digits than
+    /// `f64`, tagged so two distinct types of it can be cast between.
+    #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+    struct Wide<const TAG: u8>(i128);
+
+    const SCALE: i128 = 1_000_000;
+
+    impl<const TAG: u8> std::str::FromStr for Wide<TAG> {
+        type Err = std::num::ParseIntError;
+
+        fn from_str(text: &str) -> Result<Self, Self::Err> {
+            let (sign, digits) = text.strip_prefix('-').map_or((1, text), |digits| (-1, digits));
+            let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+            let fraction = format!("{:0<6}", fraction);
+            Ok(Self(sign * (whole.parse::<i128>()? * SCALE + fraction.parse::<i128>()?)))
+        }
+    }
+
+    macro_rules! impl_wide_op {
+        ($op:ident, $method:ident, $assign:ident, $assign_method:ident, $apply:expr) => {
+            impl<const TAG: u8> $op for Wide<TAG> {
+                type Output = Self;
+
+                fn $method(self, other: Self) -> Self {
+                    Self($apply(self.0, other.0))
+                }
+            }
+
+            impl<const TAG: u8> $assign for Wide<TAG> {
+                fn $assign_method(&mut self, other: Self) {
+                    *self = $op::$method(*self, other);
+                }
+            }
+        };
+    }
+
+    impl_wide_op!(Add, add, AddAssign, add_assign, |a, b| a + b);
+    impl_wide_op!(Sub, sub, SubAssign, sub_assign, |a, b| a - b);
+    impl_wide_op!(Mul, mul, MulAssign, mul_assign, |a, b| a * b / SCALE);
+    impl_wide_op!(Div, div, DivAssign, div_assign, |a, b| a * SCALE / b);
+
+    impl<const TAG: u8> Floating for Wide<TAG> {
+        fn sqrt(self) -> Self {
+            Self::from_f64(self.to_f64().sqrt())
+        }
+
+        fn sin(self) -> Self {
+            Self::from_f64(self.to_f64().sin())
+        }
+
+        fn cos(self) -> Self {
+            Self::from_f64(self.to_f64().cos())
+        }
+
+        fn tan(self) -> Self {
+            Self::from_f64(self.to_f64().tan())
+        }
+
+        fn ln(self) -> Self {
+            Self::from_f64(self.to_f64().ln())
+        }
+
+        fn log(self, base: Self) -> Self {
+            Self::from_f64(self.to_f64().log(base.to_f64()))
+        }
+
+        fn pow(self, exp: Self) -> Self {
+            Self::from_f64(self.to_f64().powf(exp.to_f64()))
+        }
+
+        fn from_f64(number: f64) -> Self {
+            Self((number * SCALE as f64) as i128)
+        }
+
+        fn to_f64(self) -> f64 {
+            self.0 as f64 / SCALE as f64
+        }
+
+        fn to_text(self) -> Option<String> {
+            let sign = if self.0 < 0 { "-" } else { "" };
+            Some(format!("{}{}.{:06}", sign, (self.0 / SCALE).abs(), (self.0 % SCALE).abs()))
+        }
+    }
+
+    impl<const TAG: u8> Arithmetic for Wide<TAG> {}
+
+    #[test]
+    fn casts_keep_digits_f64_cannot_hold() {
+        let number: Numeric<Wide<0>> = Numeric::new("9007199254740993.5".parse().unwrap(), Some(NumericAttribute::Percent));
+        let cast = number.cast::<Wide<1>>();
+        assert_eq!(cast.number(), Wide(9_007_199_254_740_993_500_000));
+        assert_eq!(cast.attr(), Some(&NumericAttribute::Percent));
+        assert_eq!(cast.cast::<Wide<0>>().number(), number.number());
+        assert_eq!(number.cast::<Wide<0>>().number(), number.number());
+        assert_ne!(number.cast::<f64>().cast::<Wide<0>>().number(), number.number());
+        let negative: Numeric<Wide<0>> = Numeric::new("-1.5".parse().unwrap(), None);
+        assert_eq!(negative.cast::<Wide<1>>().number(), Wide(-1_500_000));
+        assert_eq!(Numeric::<f64>::new(0.1, None).cast::<f32>().number(), 0.1f32);
+    }
+
+    #[test]
+    fn percent_sums_in_tree_and_bytecode() {
+        for compile_after in [None, Some(1)] {
+            let mut sheet: Worksheet = Worksheet::default();
+            sheet.set_compile_after(compile_after);
+            let formulas = [("=1+50%", 1.5), ("=50%+50%", 1.0), ("=10+50%", 10.5), ("=50%+10", 10.5), ("=50%+50%+1", 2.0)];
+            for (row, (formula, _)) in formulas.iter().enumerate() {
+                sheet.set_cell(CellId::new(row as u32, 0), formula.to_string());
+            }
+            for _ in 0..2 {
+                for (row, (formula, expected)) in formulas.iter().enumerate() {
+                    let value = sheet.evaluate_cell(CellId::new(row as u32, 0)).unwrap();
+                    assert!(matches!(value, Value::Primitive(Primitive::Number(ref number)) if number.value() == *expected), "{} gave {:?}", formula, value);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use super::arithmetic::Arithmetic;
+use super::eval::Comparable;
+use super::kernel::Value;
+use super::matcher::{MatchOptions, Pattern};
+use super::memory::map_bytes;
+use super::settings::CalcSettings;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::mem::size_of;
+
+/// Bucket groups the keys which may equal each other: numbers by their
+/// nearest `f64`, text by its lowercase form and booleans by value.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Bucket {
+    Number(u64),
+    Text(String),
+    Bool(bool),
+}
+
+impl Bucket {
+    fn of<T: Arithmetic>(key: &Comparable<T>) -> Option<Self> {
+        match key {
+            Comparable::Empty => None,
+            // Adding zero turns -0 into 0, so both land in one bucket.
+            Comparable::Number(number) => Some(Self::Number((number.to_f64() + 0.0).to_bits())),
+            Comparable::Text(text) => Some(Self::Text(text.to_lowercase())),
+            Comparable::Bool(b) => Some(Self::Bool(*b)),
+        }
+    }
+}
+
+/// LookupIndex indexes the keys of a column of values, so `MATCH` and
+/// `VLOOKUP` into the column find a key with a hash lookup, or with a
+/// binary search for approximate matches into sorted keys, instead of
+/// comparing it with every key in turn.
+#[derive(Debug, Clone)]
+pub struct LookupIndex<T: Arithmetic=f64> {
+    /// The keys by row, None for blanks and errors, which lookups skip.
+    keys: Vec<Option<Comparable<T>>>,
+    /// The rows of the keys in each bucket, in order.
+    buckets: HashMap<Bucket, Vec<usize>>,
+    /// The rows of the numbers, texts and booleans, in order.
+    ranked: [Vec<usize>; 3],
+    /// Whether the keys of each type ascend, and whether they descend.
+    ascending: [bool; 3],
+    descending: [bool; 3],
+}
+
+impl<T: Arithmetic> LookupIndex<T> {
+    /// Index the values of a column, from the top row down.
+    pub fn new(values: &[Value<T>]) -> Self {
+        let keys = values.iter()
+            .map(|value| match Comparable::of(value.clone()) {
+                Ok(Comparable::Empty) | Err(_) => None,
+                Ok(key) => Some(key),
+            })
+            .collect::<Vec<_>>();
+        let settings = CalcSettings::default();
+        let mut buckets = HashMap::<Bucket, Vec<usize>>::new();
+        let mut ranked: [Vec<usize>; 3] = Default::default();
+        let (mut ascending, mut descending) = ([true; 3], [true; 3]);
+        for (row, key) in keys.iter().enumerate() {
+            let Some(key) = key else { continue };
+            let rank = key.rank() as usize;
+            if let Some(previous) = ranked[rank].last().and_then(|previous| keys[*previous].as_ref()) {
+                let ordering = previous.compare(key, &settings);
+                ascending[rank] &= ordering != Some(Ordering::Greater);
+                descending[rank] &= ordering != Some(Ordering::Less);
+            }
+            ranked[rank].push(row);
+            if let Some(bucket) = Bucket::of(key) {
+                buckets.entry(bucket).or_default().push(row);
+            }
+        }
+        Self{keys, buckets, ranked, ascending, descending}
+    }
+
+    /// Get the number of rows indexed.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Get the bytes the index takes on the heap.
+    pub(crate) fn heap_bytes(&self) -> usize {
+        let keys = self.keys.capacity() * size_of::<Option<Comparable<T>>>() + self.keys.iter().map(|key| match key {
+            Some(Comparable::Text(text)) => text.capacity(),
+            _ => 0,
+        }).sum::<usize>();
+        let rows = self.buckets.values().chain(&self.ranked).map(|rows| rows.capacity() * size_of::<usize>()).sum::<usize>();
+        keys + map_bytes(&self.buckets) + rows
+    }
+
+    /// Find the row of a key the way `MATCH` does with a match type, or get
+    /// None when the index cannot tell and the column has to be scanned: for
+    /// blank keys, text with wildcards, numbers compared with a tolerance
+    /// and approximate matches into keys out of order.
+    pub(crate) fn position(&self, lookup: &Comparable<T>, match_type: i64, settings: &CalcSettings) -> Option<Option<usize>> {
+        if matches!(lookup, Comparable::Number(_)) && settings.comparison_tolerance.is_some() {
+            return None;
+        }
+        let bucket = Bucket::of(lookup)?;
+        let key = |row: usize| self.keys[row].as_ref().expect("indexed rows have keys");
+        let rank = lookup.rank() as usize;
+        match match_type.signum() {
+            0 => {
+                let pattern = match lookup {
+                    Comparable::Text(text) if text.contains(['*', '?', '~']) => return None,
+                    Comparable::Text(text) => Some(Pattern::new(text, MatchOptions::default())),
+                    _ => None,
+                };
+                let Some(rows) = self.buckets.get(&bucket) else { return Some(None) };
+                Some(rows.iter().copied().find(|row| match (&pattern, key(*row)) {
+                    (Some(pattern), Comparable::Text(text)) => pattern.is_match(text),
+                    (_, value) => value.compare(lookup, settings) == Some(Ordering::Equal),
+                }))
+            },
+            // The scan stops at the first key past the lookup and takes the
+            // one before, which in sorted keys a binary search finds.
+            1 if self.ascending[rank] => {
+                let rows = &self.ranked[rank];
+                let end = rows.partition_point(|row| key(*row).compare(lookup, settings) != Some(Ordering::Greater));
+                Some(end.checked_sub(1).map(|index| rows[index]))
+            },
+            -1 if self.descending[rank] => {
+                let rows = &self.ranked[rank];
+                let end = rows.partition_point(|row| key(*row).compare(lookup, settings) != Some(Ordering::Less));
+                Some(end.checked_sub(1).map(|index| rows[index]))
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::{CellError, Numeric, Primitive};
+
+    fn number(x: f64) -> Value<f64> {
+        Value::Primitive(Primitive::Number(Numeric::new(x, None)))
+    }
+
+    fn text(text: &str) -> Value<f64> {
+        Value::Primitive(Primitive::Text(text.to_string()))
+    }
+
+    #[test]
+    fn exact_matches_find_the_first_row() {
+        let index = LookupIndex::new(&[text("b"), Value::Empty, number(0.0), text("B"), number(-0.0), Value::Error(CellError::NA), Value::Primitive(Primitive::Bool(true))]);
+        let settings = CalcSettings::default();
+        assert_eq!(index.len(), 7);
+        assert_eq!(index.position(&Comparable::Text("B".to_string()), 0, &settings), Some(Some(0)));
+        assert_eq!(index.position(&Comparable::Number(-0.0), 0, &settings), Some(Some(2)));
+        assert_eq!(index.position(&Comparable::Bool(true), 0, &settings), Some(Some(6)));
+        assert_eq!(index.position(&Comparable::Number(1.0), 0, &settings), Some(None));
+        // Wildcards, blanks and tolerant comparisons are left to the scan.
+        assert_eq!(index.position(&Comparable::Text("b*".to_string()), 0, &settings), None);
+        assert_eq!(index.position(&Comparable::Empty, 0, &settings), None);
+        let tolerant = CalcSettings::default().with_comparison_tolerance(1e-9);
+        assert_eq!(index.position(&Comparable::Number(0.0), 0, &tolerant), None);
+    }
+
+    #[test]
+    fn approximate_matches_need_sorted_keys() {
+        let ascending = LookupIndex::new(&[number(10.0), number(20.0), text("x"), number(30.0)]);
+        let settings = CalcSettings::default();
+        assert_eq!(ascending.position(&Comparable::Number(25.0), 1, &settings), Some(Some(1)));
+        assert_eq!(ascending.position(&Comparable::Number(30.0), 1, &settings), Some(Some(3)));
+        assert_eq!(ascending.position(&Comparable::Number(5.0), 1, &settings), Some(None));
+        assert_eq!(ascending.position(&Comparable::Number(25.0), -1, &settings), None);
+
+        let descending = LookupIndex::new(&[number(30.0), number(20.0), number(10.0)]);
+        assert_eq!(descending.position(&Comparable::Number(15.0), -1, &settings), Some(Some(1)));
+        assert_eq!(descending.position(&Comparable::Number(40.0), -1, &settings), Some(None));
+
+        let unsorted = LookupIndex::new(&[number(20.0), number(10.0), number(30.0)]);
+        assert_eq!(unsorted.position(&Comparable::Number(15.0), 1, &settings), None);
+        assert_eq!(unsorted.position(&Comparable::Number(10.0), 0, &settings), Some(Some(1)));
+    }
+}
@@ -0,0 +1,244 @@
+use std::ops::Range;
+
+/// MatchOptions configures how a pattern matches text. The default is how
+/// lookups match: without regard to case, with wildcards and exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MatchOptions {
+    pub ignore_case: bool,
+    /// Whether `*` matches any run of characters, `?` any character and `~`
+    /// escapes the character after it.
+    pub wildcards: bool,
+    /// How many single character edits a text may be from the pattern and
+    /// still match it. Fuzzy patterns only match whole texts, and take
+    /// wildcard characters literally.
+    pub max_distance: usize,
+}
+
+impl Default for MatchOptions {
+    fn default() -> Self {
+        Self{ignore_case: true, wildcards: true, max_distance: 0}
+    }
+}
+
+impl MatchOptions {
+    /// Match exactly and case sensitively, like `FIND`.
+    pub fn literal() -> Self {
+        Self{ignore_case: false, wildcards: false, max_distance: 0}
+    }
+
+    /// Match texts within `max_distance` edits of the pattern.
+    pub fn fuzzy(max_distance: usize) -> Self {
+        Self{max_distance, ..Self::default()}
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Token {
+    Char(char),
+    /// `?`, matching any one character.
+    Any,
+    /// `*`, matching any run of characters.
+    Run,
+}
+
+fn chars_equal(a: char, b: char, ignore_case: bool) -> bool {
+    a == b || (ignore_case && a.to_lowercase().eq(b.to_lowercase()))
+}
+
+/// Pattern is text to look for, as in the criteria of `MATCH`, `VLOOKUP`
+/// and `COUNTIF` or the search of `SEARCH`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Pattern {
+    tokens: Vec<Token>,
+    /// The pattern as written, which fuzzy matching compares against.
+    chars: Vec<char>,
+    options: MatchOptions,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str, options: MatchOptions) -> Self {
+        let chars = pattern.chars().collect::<Vec<_>>();
+        let mut tokens = Vec::with_capacity(chars.len());
+        let mut rest = chars.iter();
+        while let Some(c) = rest.next() {
+            tokens.push(match c {
+                '*' if options.wildcards => Token::Run,
+                '?' if options.wildcards => Token::Any,
+                '~' if options.wildcards => match rest.clone().next() {
+                    Some(next @ ('*' | '?' | '~')) => {
+                        rest.next();
+                        Token::Char(*next)
+                    },
+                    _ => Token::Char('~'),
+                },
+                c => Token::Char(*c),
+            });
+        }
+        Self{tokens, chars, options}
+    }
+
+    pub fn options(&self) -> &MatchOptions {
+        &self.options
+    }
+
+    /// Whether the pattern has wildcards, which tells apart patterns that
+    /// only match themselves.
+    pub fn has_wildcards(&self) -> bool {
+        self.tokens.iter().any(|token| !matches!(token, Token::Char(_)))
+    }
+
+    /// Whether the whole of `text` matches the pattern.
+    pub fn is_match(&self, text: &str) -> bool {
+        let text = text.chars().collect::<Vec<_>>();
+        if self.options.max_distance > 0 {
+            return distance(&self.chars, &text, self.options.ignore_case) <= self.options.max_distance;
+        }
+        self.ends(&text, 0).last() == Some(&text.len())
+    }
+
+    /// Find the first match in `text` starting at or after the char index
+    /// `from`, as a char range. At the same start the longest match wins.
+    pub fn find(&self, text: &str, from: usize) -> Option<Range<usize>> {
+        let text = text.chars().collect::<Vec<_>>();
+        self.find_chars(&text, from)
+    }
+
+    fn find_chars(&self, text: &[char], from: usize) -> Option<Range<usize>> {
+        (from..=text.len()).find_map(|start| self.ends(text, start).last().map(|end| start..*end))
+    }
+
+    /// Get the char indexes, in increasing order, where a match of the
+    /// pattern starting at `start` can end.
+    fn ends(&self, text: &[char], start: usize) -> Vec<usize> {
+        // states[i] is whether the first i tokens match the text so far.
+        let mut states = vec![false; self.tokens.len() + 1];
+        states[0] = true;
+        self.close(&mut states);
+        let mut ends = Vec::new();
+        for (index, c) in text.iter().enumerate().skip(start) {
+            if states[self.tokens.len()] {
+                ends.push(index);
+            }
+            let mut next = vec![false; states.len()];
+            for (state, token) in self.tokens.iter().enumerate() {
+                if !states[state] {
+                    continue;
+                }
+                match token {
+                    Token::Char(expected) if chars_equal(*expected, *c, self.options.ignore_case) => next[state + 1] = true,
+                    Token::Char(_) => {},
+                    Token::Any => next[state + 1] = true,
+                    Token::Run => next[state] = true,
+                }
+            }
+            self.close(&mut next);
+            if !next.contains(&true) {
+                return ends;
+            }
+            states = next;
+        }
+        if states[self.tokens.len()] {
+            ends.push(text.len().max(start));
+        }
+        ends
+    }
+
+    /// Let runs match nothing, so the state after a run is reached with it.
+    fn close(&self, states: &mut [bool]) {
+        for (state, token) in self.tokens.iter().enumerate() {
+            if states[state] && *token == Token::Run {
+                states[state + 1] = true;
+            }
+        }
+    }
+}
+
+/// Get the number of single character insertions, deletions and
+/// substitutions turning one text into another.
+pub fn levenshtein(a: &str, b: &str, ignore_case: bool) -> usize {
+    distance(&a.chars().collect::<Vec<_>>(), &b.chars().collect::<Vec<_>>(), ignore_case)
+}
+
+fn distance(a: &[char], b: &[char], ignore_case: bool) -> usize {
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, x) in a.iter().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, y) in b.iter().enumerate() {
+            let substitution = previous[j] + !chars_equal(*x, *y, ignore_case) as usize;
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Replace every match of a pattern in a text, and count the replacements.
+/// Matches of nothing are not replaced.
+pub fn replace_all(text: &str, pattern: &Pattern, replacement: &str) -> (String, usize) {
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut replaced = String::with_capacity(text.len());
+    let mut count = 0;
+    let mut position = 0;
+    while let Some(found) = pattern.find_chars(&chars, position) {
+        if found.is_empty() {
+            match chars.get(found.start) {
+                Some(c) => {
+                    replaced.extend(&chars[position..found.start]);
+                    replaced.push(*c);
+                    position = found.start + 1;
+                    continue;
+                },
+                None => break,
+            }
+        }
+        replaced.extend(&chars[position..found.start]);
+        replaced.push_str(replacement);
+        count += 1;
+        position = found.end;
+    }
+    replaced.extend(&chars[position.min(chars.len())..]);
+    (replaced, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards_match_whole_texts() {
+        let pattern = Pattern::new("a*c?", MatchOptions::default());
+        assert!(pattern.has_wildcards());
+        for (text, expected) in [("abcd", true), ("ACX", true), ("acxy", false), ("a-b-cd", true), ("a-b-c-d", false), ("abc", false)] {
+            assert_eq!(pattern.is_match(text), expected, "{}", text);
+        }
+        let escaped = Pattern::new("100~%~*~?", MatchOptions::default());
+        assert!(escaped.is_match("100~%*?") && !escaped.is_match("100~%xy"));
+        let literal = Pattern::new("a*", MatchOptions::literal());
+        assert!(!literal.has_wildcards());
+        assert!(literal.is_match("a*") && !literal.is_match("A*") && !literal.is_match("ab"));
+    }
+
+    #[test]
+    fn find_takes_the_first_and_longest_match() {
+        let pattern = Pattern::new("b*d", MatchOptions::default());
+        assert_eq!(pattern.find("abcdbd", 0), Some(1..6));
+        assert_eq!(Pattern::new("B", MatchOptions::default()).find("abcb", 2), Some(3..4));
+        assert_eq!(Pattern::new("x", MatchOptions::default()).find("abc", 0), None);
+    }
+
+    #[test]
+    fn fuzzy_matches_count_edits() {
+        assert_eq!(levenshtein("kitten", "sitting", false), 3);
+        assert_eq!(levenshtein("ABC", "abc", true), 0);
+        let pattern = Pattern::new("colour", MatchOptions::fuzzy(1));
+        assert!(pattern.is_match("color") && pattern.is_match("COLOUR") && !pattern.is_match("colors"));
+    }
+
+    #[test]
+    fn replace_all_counts_replacements() {
+        let pattern = Pattern::new("a?", MatchOptions::default());
+        assert_eq!(replace_all("abAcad", &pattern, "-"), ("---".to_string(), 3));
+        assert_eq!(replace_all("xyz", &Pattern::new("*", MatchOptions::default()), "-"), ("-".to_string(), 1));
+        assert_eq!(replace_all("xyz", &Pattern::new("q", MatchOptions::default()), "-"), ("xyz".to_string(), 0));
+    }
+}
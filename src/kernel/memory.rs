@@ -0,0 +1,216 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{Cell, Formula, Primitive, Value};
+use super::theme::{FontRef, Theme};
+use super::workbook::Workbook;
+use super::worksheet::Worksheet;
+use std::collections::{HashMap, HashSet};
+use std::mem::{size_of, size_of_val};
+
+/// Get the bytes a hash map takes on the heap, for its whole capacity,
+/// leaving out what its keys and values own.
+pub(crate) fn map_bytes<K, V>(map: &HashMap<K, V>) -> usize {
+    map.capacity() * (size_of::<(K, V)>() + 1)
+}
+
+pub(crate) fn set_bytes<K>(set: &HashSet<K>) -> usize {
+    set.capacity() * (size_of::<K>() + 1)
+}
+
+/// Get the bytes a value owns on the heap, on top of its own size.
+pub(crate) fn value_bytes<T: Arithmetic>(value: &Value<T>) -> usize {
+    match value {
+        Value::Primitive(Primitive::Text(text)) => text.capacity(),
+        Value::Formula(formula) => formula_bytes(formula),
+        Value::Array(array) => array.values().iter().map(|value| size_of::<Value<T>>() + value_bytes(value)).sum(),
+        _ => 0,
+    }
+}
+
+/// Get the bytes the tree of a formula owns on the heap, on top of the size
+/// of its root.
+pub(crate) fn formula_bytes<T: Arithmetic>(formula: &Formula<T>) -> usize {
+    let own = match formula {
+        Formula::TextLit(text) | Formula::Name(text) => text.capacity(),
+        Formula::SheetRef(sheet, target) => sheet.capacity() + size_of::<Formula<T>>() + formula_bytes(target),
+        Formula::Custom{name, ..} => name.capacity(),
+        _ => 0,
+    };
+    own + formula.operands().into_iter().map(|operand| size_of::<Value<T>>() + value_bytes(operand)).sum::<usize>()
+}
+
+/// SheetMemory is an estimate of the bytes a sheet takes in memory, by what
+/// takes them, so models can be trimmed where it matters. Estimates count
+/// what the data structures hold and the capacity they reserve, not what
+/// the allocator adds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SheetMemory {
+    pub name: String,
+    pub cells: usize,
+    /// The hash table finding the contents of cells by their IDs.
+    pub cell_index: usize,
+    /// The raw contents of cells, inline or on the heap. Contents shared
+    /// between cells are counted once per sheet.
+    pub raw_strings: usize,
+    /// The parsed formulas of formula cells.
+    pub formulas: usize,
+    /// The parsed values of the other cells, kept next to their raw
+    /// contents, and the lookup indexes and compiled formulas kept to
+    /// evaluate faster.
+    pub cached_values: usize,
+    /// The number formats and styles of cells.
+    pub styles: usize,
+    /// The dependency graph of the formulas. Graphs are built when they are
+    /// needed, so this is what building one takes.
+    pub dependency_graph: usize,
+}
+
+impl SheetMemory {
+    pub fn total(&self) -> usize {
+        self.cell_index + self.raw_strings + self.formulas + self.cached_values + self.styles + self.dependency_graph
+    }
+}
+
+/// MemoryReport is an estimate of the bytes a workbook takes in memory,
+/// sheet by sheet and for what its sheets share.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryReport {
+    pub sheets: Vec<SheetMemory>,
+    /// The formulas of the defined names.
+    pub names: usize,
+    /// The theme and the styles the sheets share.
+    pub styles: usize,
+    /// The values kept by data snapshots.
+    pub snapshots: usize,
+}
+
+impl MemoryReport {
+    pub fn total(&self) -> usize {
+        self.sheets.iter().map(SheetMemory::total).sum::<usize>() + self.names + self.styles + self.snapshots
+    }
+
+    /// Render the report as plain text, a line per part with its share of
+    /// the total.
+    pub fn to_text(&self) -> String {
+        let total = self.total().max(1);
+        let share = |bytes: usize| format!("{} bytes ({:.1}%)", bytes, bytes as f64 * 100.0 / total as f64);
+        let mut text = String::new();
+        for sheet in &self.sheets {
+            text.push_str(&format!("{}: {} cells, {}\n", sheet.name, sheet.cells, share(sheet.total())));
+            text.push_str(&format!("  cell index: {}\n", share(sheet.cell_index)));
+            text.push_str(&format!("  raw strings: {}\n", share(sheet.raw_strings)));
+            text.push_str(&format!("  formulas: {}\n", share(sheet.formulas)));
+            text.push_str(&format!("  cached values: {}\n", share(sheet.cached_values)));
+            text.push_str(&format!("  styles: {}\n", share(sheet.styles)));
+            text.push_str(&format!("  dependency graph: {}\n", share(sheet.dependency_graph)));
+        }
+        text.push_str(&format!("names: {}\n", share(self.names)));
+        text.push_str(&format!("shared styles: {}\n", share(self.styles)));
+        text.push_str(&format!("snapshots: {}\n", share(self.snapshots)));
+        text.push_str(&format!("total: {} bytes\n", self.total()));
+        text
+    }
+}
+
+impl<T: Arithmetic> Worksheet<T> {
+    /// Estimate the bytes this sheet takes in memory, reported under a
+    /// name.
+    pub fn memory_report(&self, name: &str) -> SheetMemory {
+        let cells = self.cell_map();
+        // The raw contents of a cell take what its value leaves of it.
+        let raw_size = size_of::<Cell<T>>() - size_of::<Value<T>>();
+        let (mut formulas, mut values) = (0, 0);
+        for cell in cells.values() {
+            let bytes = size_of::<Value<T>>() + value_bytes(cell.value());
+            match cell.formula() {
+                Some(_) => formulas += bytes,
+                None => values += bytes,
+            }
+        }
+        let formats = map_bytes(self.format_map()) + self.format_map().values().map(String::capacity).sum::<usize>();
+        SheetMemory{
+            name: name.to_string(),
+            cells: cells.len(),
+            cell_index: map_bytes(cells) - cells.len() * size_of::<Cell<T>>(),
+            raw_strings: cells.len() * raw_size + self.string_stats().heap_bytes,
+            formulas,
+            cached_values: values + self.cache_bytes(),
+            styles: formats + map_bytes(self.style_map()),
+            dependency_graph: self.dependency_graph().heap_bytes(),
+        }
+    }
+}
+
+impl<T: Arithmetic> Workbook<T> {
+    /// Estimate the bytes the workbook takes in memory, broken down by
+    /// sheet into raw strings, parsed formulas, cached values, styles and
+    /// dependency graphs, to tell where the memory of a large model goes.
+    pub fn memory_report(&self) -> MemoryReport {
+        let sheets = self.sheets().map(|(name, sheet)| sheet.memory_report(name)).collect();
+        let names = self.names()
+            .map(|(name, formula)| size_of::<(String, Formula<T>)>() + name.len() + formula_bytes(formula))
+            .sum();
+        let theme = self.theme();
+        let fonts = self.styles().iter().map(|style| match &style.font {
+            Some(FontRef::Named(font)) => font.capacity(),
+            _ => 0,
+        });
+        let styles = size_of::<Theme>() + theme.name.capacity() + theme.major_font.capacity() + theme.minor_font.capacity()
+            + theme.format_scheme.as_ref().map_or(0, String::capacity)
+            + size_of_val(self.styles()) + fonts.sum::<usize>();
+        let snapshots = self.snapshots().iter().map(|snapshot| snapshot.heap_bytes()).sum();
+        MemoryReport{sheets, names, styles, snapshots}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::CellId;
+
+    fn at(cell_id: &str) -> CellId {
+        CellId::parse(cell_id).unwrap()
+    }
+
+    #[test]
+    fn reports_grow_with_what_is_kept() {
+        let mut workbook: Workbook = Workbook::new();
+        workbook.add_sheet("Model").unwrap();
+        let empty = workbook.memory_report();
+        assert_eq!(empty.sheets.len(), 1);
+        assert_eq!((empty.sheets[0].name.as_str(), empty.sheets[0].cells, empty.sheets[0].formulas), ("Model", 0, 0));
+        assert_eq!((empty.names, empty.snapshots), (0, 0));
+
+        for row in 0..50 {
+            workbook.set_cell("Model", CellId::new(row, 0), format!("a label long enough for the heap {}", row)).unwrap();
+            workbook.set_cell("Model", CellId::new(row, 1), format!("=LEN(A{})*2", row + 1)).unwrap();
+        }
+        workbook.define_name("Labels", Formula::SheetRef("Model".to_string(), Box::new(Formula::CellRange(at("A1"), at("A50"))))).unwrap();
+        workbook.take_snapshot("before").unwrap();
+        let report = workbook.memory_report();
+        let sheet = &report.sheets[0];
+        assert_eq!(sheet.cells, 100);
+        assert!(sheet.cell_index > empty.sheets[0].cell_index);
+        assert!(sheet.raw_strings >= 50 * "a label long enough for the heap".len());
+        assert!(sheet.formulas >= 50 * size_of::<Value<f64>>());
+        assert!(sheet.dependency_graph > 0);
+        assert!(report.names > "Labels".len() && report.snapshots > 0);
+        assert_eq!(report.total(), sheet.total() + report.names + report.styles + report.snapshots);
+
+        let text = report.to_text();
+        assert!(text.starts_with(&format!("Model: 100 cells, {} bytes (", sheet.total())));
+        assert!(text.contains(&format!("  formulas: {} bytes (", sheet.formulas)));
+        assert!(text.ends_with(&format!("total: {} bytes\n", report.total())));
+    }
+
+    #[test]
+    fn heap_bytes_of_values_and_formulas() {
+        assert_eq!(value_bytes::<f64>(&Value::Empty), 0);
+        assert_eq!(value_bytes::<f64>(&Value::Primitive(Primitive::Text(String::with_capacity(40)))), 40);
+        let formula = Formula::<f64>::try_from("CONCAT(\"text\",Other!A1)").unwrap();
+        assert!(formula_bytes(&formula) >= "text".len() + "Other".len() + size_of::<Formula<f64>>());
+        let mut map = HashMap::<u32, u64>::with_capacity(16);
+        map.insert(1, 1);
+        assert_eq!(map_bytes(&map), map.capacity() * 17);
+        assert_eq!(set_bytes(&HashSet::<u8>::new()), 0);
+    }
+}
@@ -0,0 +1,179 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{CellId, Formula, FunctionKind, Value};
+use super::worksheet::Worksheet;
+use std::collections::{BTreeSet, HashSet};
+
+/// FormulaMetrics describes the complexity of a single formula.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FormulaMetrics {
+    /// The depth of the syntax tree, where a lone reference or literal is 1.
+    pub depth: usize,
+    /// The number of nodes in the syntax tree, literals included.
+    pub node_count: usize,
+    /// The number of distinct cells referenced, with ranges expanded.
+    pub referenced_cells: usize,
+    /// The number of calls to volatile functions.
+    pub volatile_functions: usize,
+    /// The sheets referenced by sheet-qualified references.
+    pub sheets: BTreeSet<String>,
+}
+
+impl FunctionKind {
+    /// Whether the function must be recalculated on every evaluation because
+    /// its result does not only depend on its arguments.
+    pub fn is_volatile(&self) -> bool {
+        matches!(self, Self::Offset)
+    }
+}
+
+#[derive(Default)]
+struct Walk {
+    node_count: usize,
+    volatile_functions: usize,
+    cells: HashSet<(Option<String>, CellId)>,
+    sheets: BTreeSet<String>,
+}
+
+impl Walk {
+    fn value<T: Arithmetic>(&mut self, value: &Value<T>) -> usize {
+        match value {
+            Value::Formula(formula) => self.formula(None, formula),
+            _ => {
+                self.node_count += 1;
+                1
+            },
+        }
+    }
+
+    fn formula<T: Arithmetic>(&mut self, sheet: Option<&str>, formula: &Formula<T>) -> usize {
+        self.node_count += 1;
+        let sheet_key = sheet.map(|sheet| sheet.to_string());
+        match formula {
+            Formula::CellRef(cell_id) => {
+                self.cells.insert((sheet_key, *cell_id));
+                1
+            },
+            Formula::CellRange(start, end) => {
+                self.cells.extend(CellId::range(*start, *end).map(|cell_id| (sheet_key.clone(), cell_id)));
+                1
+            },
+            Formula::Name(_) => 1,
+            Formula::SheetRef(sheet, target) => {
+                self.sheets.insert(sheet.clone());
+                // The qualifier and its target count as a single node.
+                self.node_count -= 1;
+                self.formula(Some(sheet), target)
+            },
+            Formula::Function{kind, ..} => {
+                if kind.is_volatile() {
+                    self.volatile_functions += 1;
+                }
+                1 + formula.operands().into_iter().map(|operand| self.value(operand)).max().unwrap_or(0)
+            },
+            _ => 1 + formula.operands().into_iter().map(|operand| self.value(operand)).max().unwrap_or(0),
+        }
+    }
+}
+
+impl<T: Arithmetic> Formula<T> {
+    /// Compute complexity metrics for this formula.
+    pub fn metrics(&self) -> FormulaMetrics {
+        let mut walk = Walk::default();
+        let depth = walk.formula(None, self);
+        FormulaMetrics{
+            depth,
+            node_count: walk.node_count,
+            referenced_cells: walk.cells.len(),
+            volatile_functions: walk.volatile_functions,
+            sheets: walk.sheets,
+        }
+    }
+}
+
+/// SheetMetrics aggregates the metrics of every formula on a sheet.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SheetMetrics {
+    pub formula_count: usize,
+    pub max_depth: usize,
+    pub total_nodes: usize,
+    pub max_nodes: usize,
+    /// The sum of the referenced cell counts of every formula.
+    pub referenced_cells: usize,
+    pub volatile_functions: usize,
+    /// The number of formulas using at least one volatile function.
+    pub volatile_formulas: usize,
+    pub sheets: BTreeSet<String>,
+}
+
+impl SheetMetrics {
+    /// Add the metrics of one formula.
+    pub fn add(&mut self, metrics: &FormulaMetrics) {
+        self.formula_count += 1;
+        self.max_depth = self.max_depth.max(metrics.depth);
+        self.total_nodes += metrics.node_count;
+        self.max_nodes = self.max_nodes.max(metrics.node_count);
+        self.referenced_cells += metrics.referenced_cells;
+        self.volatile_functions += metrics.volatile_functions;
+        if metrics.volatile_functions > 0 {
+            self.volatile_formulas += 1;
+        }
+        self.sheets.extend(metrics.sheets.iter().cloned());
+    }
+
+    /// Combine with the metrics of another sheet.
+    pub fn merge(&mut self, other: &SheetMetrics) {
+        self.formula_count += other.formula_count;
+        self.max_depth = self.max_depth.max(other.max_depth);
+        self.total_nodes += other.total_nodes;
+        self.max_nodes = self.max_nodes.max(other.max_nodes);
+        self.referenced_cells += other.referenced_cells;
+        self.volatile_functions += other.volatile_functions;
+        self.volatile_formulas += other.volatile_formulas;
+        self.sheets.extend(other.sheets.iter().cloned());
+    }
+}
+
+impl<T: Arithmetic> Worksheet<T> {
+    /// Compute metrics aggregated over every formula on this sheet.
+    pub fn metrics(&self) -> SheetMetrics {
+        let mut metrics = SheetMetrics::default();
+        for (_, cell) in self.cells() {
+            if let Some(formula) = cell.formula() {
+                metrics.add(&formula.metrics());
+            }
+        }
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::Kernel;
+
+    #[test]
+    fn formula_metrics_count_nodes_cells_and_volatile_calls() {
+        let formula = Formula::<f64>::try_from("SUM(A1:B2,Data!C3)*OFFSET(A1,1,1)").unwrap();
+        let metrics = formula.metrics();
+        assert_eq!(metrics.depth, 3);
+        assert_eq!(metrics.node_count, 8);
+        // A1 is referenced twice but counted once.
+        assert_eq!(metrics.referenced_cells, 5);
+        assert_eq!(metrics.volatile_functions, 1);
+        assert_eq!(metrics.sheets, BTreeSet::from(["Data".to_string()]));
+    }
+
+    #[test]
+    fn sheet_metrics_aggregate_formulas() {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), "1".to_string());
+        sheet.set_cell(CellId::new(0, 1), "=A1+1".to_string());
+        sheet.set_cell(CellId::new(0, 2), "=OFFSET(A1,0,1)*2".to_string());
+        let metrics = sheet.metrics();
+        assert_eq!(metrics.formula_count, 2);
+        assert_eq!(metrics.max_depth, 3);
+        assert_eq!(metrics.total_nodes, 9);
+        assert_eq!(metrics.volatile_formulas, 1);
+        assert!(metrics.sheets.is_empty());
+    }
+}
@@ -0,0 +1,235 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{CellId, Formula};
+use super::refactor::{as_range, same_sheet, visit_references};
+use super::serialize::quote_sheet;
+use super::structure::blocks;
+use super::workbook::{Workbook, WorkbookError};
+use std::fmt;
+
+/// NameCase is how the words of a header are joined into a name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NameCase {
+    /// Capitalized words run together, like `UnitPrice`.
+    #[default]
+    Pascal,
+    /// Lowercase words joined by underscores, like `unit_price`.
+    Snake,
+    /// Uppercase words joined by underscores, like `UNIT_PRICE`.
+    Upper,
+}
+
+impl NameCase {
+    fn join(&self, words: &[String]) -> String {
+        match self {
+            Self::Pascal => words.iter().map(|word| {
+                let mut chars = word.chars();
+                match chars.next() {
+                    Some(first) => first.to_uppercase().chain(chars.flat_map(char::to_lowercase)).collect(),
+                    None => String::new(),
+                }
+            }).collect(),
+            Self::Snake => words.iter().map(|word| word.to_lowercase()).collect::<Vec<_>>().join("_"),
+            Self::Upper => words.iter().map(|word| word.to_uppercase()).collect::<Vec<_>>().join("_"),
+        }
+    }
+}
+
+/// NamingScheme is how `Workbook::auto_name` names the columns of tables.
+/// The parts of a name, its prefix, sheet and header, are joined by
+/// underscores.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamingScheme {
+    pub case: NameCase,
+    /// Text every name starts with, such as `rng`, or nothing if empty.
+    pub prefix: String,
+    /// Whether names include the name of their sheet, like
+    /// `Sales_UnitPrice`, so columns with the same header on different
+    /// sheets get different names.
+    pub qualify: bool,
+}
+
+impl Default for NamingScheme {
+    fn default() -> Self {
+        Self{case: NameCase::default(), prefix: String::new(), qualify: true}
+    }
+}
+
+impl NamingScheme {
+    /// Get the name of a column of a table on a sheet, before it is made
+    /// unique, or None if its header has no letters or digits.
+    fn name(&self, sheet: &str, header: &str) -> Option<String> {
+        let words = |text: &str| text.split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        let mut parts = Vec::new();
+        if !self.prefix.is_empty() {
+            parts.push(self.prefix.clone());
+        }
+        if self.qualify {
+            parts.push(self.case.join(&words(sheet)));
+        }
+        let header = words(header);
+        if header.is_empty() {
+            return None;
+        }
+        parts.push(self.case.join(&header));
+        parts.retain(|part| !part.is_empty());
+        Some(parts.join("_"))
+    }
+}
+
+/// NamingWarning is a formula using a range by its address where a defined
+/// name stands for the same range.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamingWarning {
+    pub sheet: String,
+    pub cell_id: CellId,
+    /// The reference as written in the formula, like `B2:B13`.
+    pub reference: String,
+    /// The defined name to use instead.
+    pub name: String,
+}
+
+impl fmt::Display for NamingWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}!{} uses {} where the name {} is defined", quote_sheet(&self.sheet), self.cell_id, self.reference, self.name)
+    }
+}
+
+impl<T: Arithmetic> Workbook<T> {
+    /// Get the name standing for a range of a sheet, if any. Only names
+    /// referring to a sheet stand for its ranges.
+    fn name_for(&self, sheet: &str, start: CellId, end: CellId) -> Option<&str> {
+        self.names()
+            .find(|(_, formula)| match as_range(formula) {
+                Some((Some(target), first, last)) => same_sheet(Some(target), Some(sheet)) && (first, last) == (start, end),
+                _ => false,
+            })
+            .map(|(name, _)| name)
+    }
+
+    /// Define names for the columns of the tables of every sheet, found as
+    /// `outline` finds them, after their headers and following a naming
+    /// scheme. A name stands for the cells of its column below the header.
+    /// Tables without a header, columns already named and headers without
+    /// letters or digits are skipped, and a name already defined gets a
+    /// number, like `Sales_Date_2`. Get the names defined, sheet by sheet
+    /// and table by table.
+    pub fn auto_name(&mut self, scheme: &NamingScheme) -> Result<Vec<String>, WorkbookError> {
+        let mut columns = Vec::new();
+        for (name, sheet) in self.sheets() {
+            for (first, last) in blocks(sheet) {
+                let Some(headers) = sheet.headers(first, last) else { continue };
+                for (col, header) in (first.col()..).zip(headers) {
+                    let (start, end) = (CellId::new(first.row() + 1, col), CellId::new(last.row(), col));
+                    if self.name_for(name, start, end).is_none() {
+                        columns.push((name.to_string(), header, start, end));
+                    }
+                }
+            }
+        }
+        let mut defined = Vec::new();
+        for (sheet, header, start, end) in columns {
+            let Some(mut base) = scheme.name(&sheet, &header) else { continue };
+            // Names may not start with a digit or read as cell references.
+            if !matches!(Formula::<T>::try_from(base.as_str()), Ok(Formula::Name(ref parsed)) if *parsed == base) {
+                base = format!("_{}", base);
+            }
+            let mut name = base.clone();
+            let mut number = 1;
+            while self.name(&name).is_some() {
+                number += 1;
+                name = format!("{}_{}", base, number);
+            }
+            let target = match start == end {
+                true => Formula::CellRef(start),
+                false => Formula::CellRange(start, end),
+            };
+            self.define_name(&name, Formula::SheetRef(sheet, Box::new(target)))?;
+            defined.push(name);
+        }
+        Ok(defined)
+    }
+
+    /// Check the formulas of every sheet for ranges written by their
+    /// address where a defined name stands for the same range, such as
+    /// `SUM(Sales!B2:B13)` where `Sales_Amount` refers to `Sales!B2:B13`,
+    /// to hold a workbook to its naming conventions. Warnings are listed
+    /// sheet by sheet, then cell row by row.
+    pub fn naming_warnings(&self) -> Vec<NamingWarning> {
+        let mut warnings = Vec::new();
+        for (name, sheet) in self.sheets() {
+            let mut cells = sheet.cells()
+                .filter_map(|(cell_id, cell)| cell.formula().map(|formula| (cell_id, formula.clone())))
+                .collect::<Vec<_>>();
+            cells.sort_by_key(|(cell_id, _)| (cell_id.row(), cell_id.col()));
+            for (cell_id, mut formula) in cells {
+                visit_references(&mut formula, &mut |reference| {
+                    if let Some((target, start, end)) = as_range(reference) {
+                        if let Some(defined) = self.name_for(target.unwrap_or(name), start, end) {
+                            warnings.push(NamingWarning{sheet: name.to_string(), cell_id, reference: reference.to_string(), name: defined.to_string()});
+                        }
+                    }
+                    false
+                });
+            }
+        }
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(cell_id: &str) -> CellId {
+        CellId::parse(cell_id).unwrap()
+    }
+
+    /// Sales has a table of unit prices and dates with a total below it, and
+    /// Cost data has a date column of its own.
+    fn workbook() -> Workbook {
+        let mut workbook: Workbook = Workbook::new();
+        workbook.add_sheet("Sales").unwrap();
+        workbook.add_sheet("Cost data").unwrap();
+        for (cell_id, data) in [("A1", "Unit price"), ("B1", "Date"), ("A2", "3"), ("B2", "45000"), ("A3", "4"), ("B3", "45001"), ("A5", "=SUM(A2:A3)")] {
+            workbook.set_cell("Sales", at(cell_id), data.to_string()).unwrap();
+        }
+        for (cell_id, data) in [("A1", "date"), ("B1", "A1"), ("C1", "-"), ("A2", "45000"), ("B2", "1"), ("C2", "2")] {
+            workbook.set_cell("Cost data", at(cell_id), data.to_string()).unwrap();
+        }
+        workbook
+    }
+
+    #[test]
+    fn auto_name_follows_the_scheme() {
+        let mut workbook = workbook();
+        assert_eq!(workbook.auto_name(&NamingScheme::default()).unwrap(), ["Sales_UnitPrice", "Sales_Date", "CostData_Date", "CostData_A1"]);
+        assert!(matches!(workbook.name("Sales_UnitPrice"), Some(Formula::SheetRef(sheet, target)) if sheet == "Sales" && matches!(**target, Formula::CellRange(start, end) if (start, end) == (at("A2"), at("A3")))));
+        assert!(matches!(workbook.name("CostData_Date"), Some(Formula::SheetRef(_, target)) if matches!(**target, Formula::CellRef(cell_id) if cell_id == at("A2"))));
+        // Columns already named are skipped.
+        assert!(workbook.auto_name(&NamingScheme::default()).unwrap().is_empty());
+
+        let mut workbook = self::workbook();
+        let scheme = NamingScheme{case: NameCase::Snake, prefix: String::new(), qualify: false};
+        assert_eq!(workbook.auto_name(&scheme).unwrap(), ["unit_price", "date", "date_2", "_a1"]);
+        let mut workbook = self::workbook();
+        let scheme = NamingScheme{case: NameCase::Upper, prefix: "rng".to_string(), qualify: false};
+        assert_eq!(workbook.auto_name(&scheme).unwrap(), ["rng_UNIT_PRICE", "rng_DATE", "rng_DATE_2", "rng_A1"]);
+    }
+
+    #[test]
+    fn raw_ranges_with_a_name_are_warned_about() {
+        let mut workbook = workbook();
+        assert!(workbook.naming_warnings().is_empty());
+        workbook.auto_name(&NamingScheme::default()).unwrap();
+        workbook.set_cell("Cost data", at("E1"), "=MAX(sales!B2:B3)+A2".to_string()).unwrap();
+        workbook.set_cell("Cost data", at("E2"), "=Sales_UnitPrice".to_string()).unwrap();
+        let warnings = workbook.naming_warnings();
+        assert_eq!(warnings.len(), 3);
+        assert_eq!(warnings[0], NamingWarning{sheet: "Sales".to_string(), cell_id: at("A5"), reference: "A2:A3".to_string(), name: "Sales_UnitPrice".to_string()});
+        assert_eq!(warnings[1].to_string(), "'Cost data'!E1 uses sales!B2:B3 where the name Sales_Date is defined");
+        assert_eq!((warnings[2].cell_id, warnings[2].name.as_str()), (at("E1"), "CostData_Date"));
+    }
+}
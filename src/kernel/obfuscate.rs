@@ -0,0 +1,308 @@
+use super::arithmetic::Arithmetic;
+use super::journal::{escape, unescape};
+use super::kernel::{CellId, Formula, Kernel, Value};
+use super::refactor::visit_references;
+use super::workbook::{Workbook, WorkbookError};
+use thiserror::Error;
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+#[derive(Error, Debug)]
+pub enum ObfuscationError {
+    #[error("line {0} of the mapping is corrupt")]
+    Corrupt(usize),
+
+    #[error(transparent)]
+    Workbook(#[from] WorkbookError),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// ObfuscateOptions chooses what `Workbook::obfuscate` hides.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObfuscateOptions {
+    /// Rename every defined name to an opaque one, like `N_1`.
+    pub rename_names: bool,
+    /// Drop comments written into formulas as `+N("...")`.
+    pub strip_comments: bool,
+    /// The sheets whose formulas are replaced with the values they have,
+    /// such as helper sheets holding the logic of a model.
+    pub freeze_sheets: Vec<String>,
+}
+
+impl Default for ObfuscateOptions {
+    fn default() -> Self {
+        Self{rename_names: true, strip_comments: true, freeze_sheets: Vec::new()}
+    }
+}
+
+/// ObfuscationMap is what an obfuscation changed: the original of every
+/// renamed name and the contents of every frozen cell. Authors keep it
+/// privately to restore the workbook they shipped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ObfuscationMap {
+    /// The original and new names, in the order the names are defined.
+    names: Vec<(String, String)>,
+    /// The sheet, cell and raw contents of the frozen formulas.
+    frozen: Vec<(String, CellId, String)>,
+}
+
+/// Whether a value is a comment: `N` of text, which is zero.
+fn is_comment<T: Arithmetic>(value: &Value<T>) -> bool {
+    match value {
+        Value::Formula(Formula::Custom{name, arguments}) => {
+            name.eq_ignore_ascii_case("N") && matches!(arguments.as_slice(), [Value::Formula(Formula::TextLit(_))])
+        },
+        _ => false,
+    }
+}
+
+/// Drop the comments added to the operands of a value.
+fn strip_comments<T: Arithmetic>(value: &mut Value<T>) {
+    let Value::Formula(formula) = value else { return };
+    for operand in formula.operands_mut() {
+        strip_comments(operand);
+    }
+    let kept = match formula {
+        Formula::Add(lhs, rhs) if is_comment(rhs) => std::mem::replace(&mut **lhs, Value::Empty),
+        Formula::Add(lhs, rhs) if is_comment(lhs) => std::mem::replace(&mut **rhs, Value::Empty),
+        _ => return,
+    };
+    *value = kept;
+}
+
+/// Rename the names a formula uses.
+fn rename<T: Arithmetic>(formula: &mut Formula<T>, names: &HashMap<String, String>) {
+    visit_references(formula, &mut |reference| {
+        let target = match reference {
+            Formula::SheetRef(_, target) => &mut **target,
+            target => target,
+        };
+        match target {
+            Formula::Name(name) => match names.get(&name.to_lowercase()) {
+                Some(new) => {
+                    *name = new.clone();
+                    true
+                },
+                None => false,
+            },
+            _ => false,
+        }
+    });
+}
+
+impl ObfuscationMap {
+    /// Get the name a name was renamed to.
+    pub fn obfuscated_name(&self, original: &str) -> Option<&str> {
+        self.names.iter().find(|(name, _)| name.eq_ignore_ascii_case(original)).map(|(_, new)| new.as_str())
+    }
+
+    /// Get the name a renamed name had.
+    pub fn original_name(&self, obfuscated: &str) -> Option<&str> {
+        self.names.iter().find(|(_, new)| new.eq_ignore_ascii_case(obfuscated)).map(|(name, _)| name.as_str())
+    }
+
+    /// Get the number of formulas frozen into values.
+    pub fn frozen_count(&self) -> usize {
+        self.frozen.len()
+    }
+
+    /// Undo an obfuscation: name the names as they were and put the frozen
+    /// formulas back. Comments and whitespace stay stripped. Returns the
+    /// number of formulas rewritten.
+    pub fn restore<T: Arithmetic>(&self, workbook: &mut Workbook<T>) -> Result<usize, WorkbookError> {
+        let mut count = 0;
+        for (original, new) in &self.names {
+            if workbook.name(new).is_some() {
+                count += workbook.rename_defined_name(new, original)?;
+            }
+        }
+        for (sheet, cell_id, raw) in &self.frozen {
+            workbook.set_cell(sheet, *cell_id, raw.clone())?;
+        }
+        Ok(count + self.frozen.len())
+    }
+
+    /// Write the mapping as lines of tab separated fields.
+    pub fn write(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        for (original, new) in &self.names {
+            writeln!(writer, "name\t{}\t{}", escape(original), escape(new))?;
+        }
+        for (sheet, cell_id, raw) in &self.frozen {
+            writeln!(writer, "cell\t{}\t{}\t{}", escape(sheet), cell_id, escape(raw))?;
+        }
+        Ok(())
+    }
+
+    /// Read a mapping written by `write`.
+    pub fn read(reader: impl BufRead) -> Result<Self, ObfuscationError> {
+        let mut map = Self::default();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            let fields = line.split('\t').map(unescape).collect::<Option<Vec<_>>>().ok_or(ObfuscationError::Corrupt(index + 1))?;
+            match fields.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+                ["name", original, new] => map.names.push((original.to_string(), new.to_string())),
+                ["cell", sheet, cell_id, raw] => {
+                    let cell_id = CellId::parse(cell_id).map_err(|_| ObfuscationError::Corrupt(index + 1))?;
+                    map.frozen.push((sheet.to_string(), cell_id, raw.to_string()));
+                },
+                _ => return Err(ObfuscationError::Corrupt(index + 1)),
+            }
+        }
+        Ok(map)
+    }
+}
+
+impl<T: Arithmetic> Workbook<T> {
+    /// Hide the logic of a workbook before shipping it: freeze the formulas
+    /// of helper sheets into their values, rename the defined names, strip
+    /// comments and write every formula without whitespace. Get the mapping
+    /// to undo the renames and freezes with.
+    pub fn obfuscate(&mut self, options: &ObfuscateOptions) -> Result<ObfuscationMap, WorkbookError> {
+        let mut map = ObfuscationMap::default();
+        // Evaluate every frozen cell before changing any, as they may refer
+        // to each other.
+        let mut values = Vec::new();
+        for sheet in &options.freeze_sheets {
+            for (cell_id, value, spilled) in self.formula_values(sheet)? {
+                // The cells a dynamic array spills into are emptied again on
+                // restoring.
+                let raw = match spilled {
+                    true => String::new(),
+                    false => self.sheet(sheet).and_then(|sheet| sheet.cell(cell_id)).map(|cell| cell.raw().to_string()).unwrap_or_default(),
+                };
+                values.push((sheet.clone(), cell_id, value));
+                map.frozen.push((sheet.clone(), cell_id, raw));
+            }
+        }
+        for (sheet, cell_id, value) in values {
+            self.sheet_mut(&sheet).expect("frozen sheets exist").set_value(cell_id, value);
+        }
+
+        let mut renames = HashMap::new();
+        if options.rename_names {
+            let mut next = 0;
+            let names = self.names().map(|(name, _)| name.to_string()).collect::<Vec<_>>();
+            for name in names {
+                let new = loop {
+                    next += 1;
+                    let new = format!("N_{}", next);
+                    if self.name(&new).is_none() {
+                        break new;
+                    }
+                };
+                renames.insert(name.to_lowercase(), new.clone());
+                map.names.push((name, new));
+            }
+            for (name, formula) in self.names_mut().iter_mut() {
+                *name = renames[&name.to_lowercase()].clone();
+                rename(formula, &renames);
+            }
+        }
+
+        for (_, sheet) in self.sheets_mut() {
+            let rewritten = sheet.cells()
+                .filter_map(|(cell_id, cell)| {
+                    let mut value = Value::Formula(cell.formula()?.clone());
+                    if options.strip_comments {
+                        strip_comments(&mut value);
+                    }
+                    if let Value::Formula(formula) = &mut value {
+                        rename(formula, &renames);
+                    }
+                    let raw = match value {
+                        Value::Formula(formula) => format!("={}", formula),
+                        _ => return None,
+                    };
+                    (raw != cell.raw()).then_some((cell_id, raw))
+                })
+                .collect::<Vec<_>>();
+            for (cell_id, raw) in rewritten {
+                sheet.set_cell(cell_id, raw);
+            }
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::Primitive;
+
+    fn raw(workbook: &Workbook, sheet: &str, row: u32, col: u32) -> String {
+        workbook.sheet(sheet).unwrap().cell(CellId::new(row, col)).map(|cell| cell.raw().to_string()).unwrap_or_default()
+    }
+
+    fn number(workbook: &Workbook, sheet: &str, row: u32) -> Option<f64> {
+        match workbook.evaluate_cell(sheet, CellId::new(row, 0)).unwrap() {
+            Value::Primitive(Primitive::Number(number)) => Some(number.value()),
+            _ => None,
+        }
+    }
+
+    /// A model whose helper sheet holds the logic: a margin, a formula with
+    /// a comment and a dynamic array.
+    fn workbook() -> Workbook {
+        let mut workbook: Workbook = Workbook::new();
+        workbook.add_sheet("Report").unwrap();
+        workbook.add_sheet("Logic").unwrap();
+        workbook.set_cell("Logic", CellId::new(0, 0), "100".to_string()).unwrap();
+        workbook.set_cell("Logic", CellId::new(1, 0), "=A1 * Margin".to_string()).unwrap();
+        workbook.set_cell("Logic", CellId::new(0, 1), "=TAKE(A1:A2,2)".to_string()).unwrap();
+        workbook.set_cell("Report", CellId::new(0, 0), "=Logic!A2 + N(\"the net\")".to_string()).unwrap();
+        workbook.set_cell("Report", CellId::new(1, 0), "=Margin*2".to_string()).unwrap();
+        workbook.define_name("Margin", Formula::try_from("0.25").unwrap()).unwrap();
+        workbook
+    }
+
+    #[test]
+    fn obfuscate_hides_names_comments_and_helper_sheets() {
+        let mut workbook = workbook();
+        let options = ObfuscateOptions{freeze_sheets: vec!["Logic".to_string()], ..ObfuscateOptions::default()};
+        let map = workbook.obfuscate(&options).unwrap();
+        assert_eq!(map.obfuscated_name("MARGIN"), Some("N_1"));
+        assert_eq!(map.original_name("n_1"), Some("Margin"));
+        assert_eq!(workbook.names().map(|(name, _)| name).collect::<Vec<_>>(), ["N_1"]);
+        assert_eq!(raw(&workbook, "Report", 0, 0), "=Logic!A2");
+        assert_eq!(raw(&workbook, "Report", 1, 0), "=N_1*2");
+        assert_eq!(raw(&workbook, "Logic", 1, 0), "25");
+        // The array froze into the cell it spilled into too.
+        assert_eq!(map.frozen_count(), 3);
+        assert_eq!(raw(&workbook, "Logic", 1, 1), "25");
+        assert_eq!(number(&workbook, "Report", 0), Some(25.0));
+        assert_eq!(number(&workbook, "Report", 1), Some(0.5));
+
+        assert_eq!(map.restore(&mut workbook).unwrap(), 4);
+        assert_eq!(workbook.names().map(|(name, _)| name).collect::<Vec<_>>(), ["Margin"]);
+        assert_eq!(raw(&workbook, "Report", 1, 0), "=Margin*2");
+        assert_eq!(raw(&workbook, "Logic", 1, 0), "=A1 * Margin");
+        assert_eq!(raw(&workbook, "Logic", 1, 1), "");
+        assert_eq!(raw(&workbook, "Report", 0, 0), "=Logic!A2");
+    }
+
+    #[test]
+    fn options_leave_names_and_comments() {
+        let mut workbook = workbook();
+        let options = ObfuscateOptions{rename_names: false, strip_comments: false, freeze_sheets: Vec::new()};
+        let map = workbook.obfuscate(&options).unwrap();
+        assert_eq!(map, ObfuscationMap::default());
+        assert_eq!(raw(&workbook, "Report", 0, 0), "=Logic!A2+N(\"the net\")");
+        assert_eq!(raw(&workbook, "Logic", 1, 0), "=A1*Margin");
+        let unknown = ObfuscateOptions{freeze_sheets: vec!["Missing".to_string()], ..ObfuscateOptions::default()};
+        assert!(matches!(workbook.obfuscate(&unknown), Err(WorkbookError::UnknownSheet(_))));
+    }
+
+    #[test]
+    fn mappings_round_trip() {
+        let mut workbook = workbook();
+        let options = ObfuscateOptions{freeze_sheets: vec!["Logic".to_string()], ..ObfuscateOptions::default()};
+        let map = workbook.obfuscate(&options).unwrap();
+        let mut written = Vec::new();
+        map.write(&mut written).unwrap();
+        assert_eq!(ObfuscationMap::read(written.as_slice()).unwrap(), map);
+        assert!(matches!(ObfuscationMap::read("name\tonly one\n".as_bytes()), Err(ObfuscationError::Corrupt(1))));
+        assert!(matches!(ObfuscationMap::read("name\ta\tb\ncell\tLogic\tnope\tx\n".as_bytes()), Err(ObfuscationError::Corrupt(2))));
+    }
+}
@@ -0,0 +1,240 @@
+use thiserror::Error;
+
+/// The deepest nesting of groups spreadsheets support.
+pub const MAX_OUTLINE_LEVEL: usize = 7;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum OutlineError {
+    #[error("{first}..={last} is not a valid group")]
+    Empty{first: u32, last: u32},
+
+    #[error("{first}..={last} is already grouped")]
+    AlreadyGrouped{first: u32, last: u32},
+
+    #[error("{first}..={last} partially overlaps another group")]
+    Overlap{first: u32, last: u32},
+
+    #[error("groups cannot be nested deeper than {MAX_OUTLINE_LEVEL} levels")]
+    TooDeep,
+
+    #[error("{first}..={last} is not a group")]
+    NotGrouped{first: u32, last: u32},
+}
+
+/// OutlineGroup is a group of consecutive rows or columns, from `first` to
+/// `last` inclusive. The detail of a collapsed group is hidden.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OutlineGroup {
+    pub first: u32,
+    pub last: u32,
+    pub collapsed: bool,
+}
+
+impl OutlineGroup {
+    fn contains(&self, index: u32) -> bool {
+        (self.first..=self.last).contains(&index)
+    }
+
+    fn encloses(&self, other: &Self) -> bool {
+        self.first <= other.first && other.last <= self.last
+    }
+}
+
+/// Groups holds the properly nested groups of one axis of a sheet.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Groups {
+    groups: Vec<OutlineGroup>,
+}
+
+impl Groups {
+    /// Group the rows or columns from `first` to `last`. The group must nest
+    /// within or around the existing groups.
+    pub fn group(&mut self, first: u32, last: u32) -> Result<(), OutlineError> {
+        if first > last {
+            return Err(OutlineError::Empty{first, last});
+        }
+        let group = OutlineGroup{first, last, collapsed: false};
+        for existing in &self.groups {
+            if existing.first == first && existing.last == last {
+                return Err(OutlineError::AlreadyGrouped{first, last});
+            }
+            let disjoint = existing.last < first || last < existing.first;
+            if !disjoint && !existing.encloses(&group) && !group.encloses(existing) {
+                return Err(OutlineError::Overlap{first, last});
+            }
+        }
+        self.groups.push(group);
+        if (first..=last).any(|index| self.level(index) > MAX_OUTLINE_LEVEL) {
+            self.groups.pop();
+            return Err(OutlineError::TooDeep);
+        }
+        self.groups.sort_by_key(|group| (group.first, std::cmp::Reverse(group.last)));
+        Ok(())
+    }
+
+    /// Remove the group from `first` to `last`, keeping the groups nested
+    /// in it.
+    pub fn ungroup(&mut self, first: u32, last: u32) -> Result<(), OutlineError> {
+        let index = self.groups.iter()
+            .position(|group| group.first == first && group.last == last)
+            .ok_or(OutlineError::NotGrouped{first, last})?;
+        self.groups.remove(index);
+        Ok(())
+    }
+
+    /// Get the outline level of a row or column, the number of groups it
+    /// belongs to.
+    pub fn level(&self, index: u32) -> usize {
+        self.groups.iter().filter(|group| group.contains(index)).count()
+    }
+
+    /// Get the level of a group, counting the group itself.
+    fn group_level(&self, group: &OutlineGroup) -> usize {
+        self.groups.iter().filter(|other| other.encloses(group)).count()
+    }
+
+    /// Show the outline down to `level`, the way the outline level buttons
+    /// of spreadsheets do: groups at `level` and deeper are collapsed, the
+    /// others expanded. Level 1 shows only what is outside of all groups.
+    pub fn collapse(&mut self, level: usize) {
+        let levels = self.groups.iter().map(|group| self.group_level(group)).collect::<Vec<_>>();
+        for (group, group_level) in self.groups.iter_mut().zip(levels) {
+            group.collapsed = group_level >= level;
+        }
+    }
+
+    /// Collapse or expand a single group.
+    pub fn set_collapsed(&mut self, first: u32, last: u32, collapsed: bool) -> Result<(), OutlineError> {
+        let group = self.groups.iter_mut()
+            .find(|group| group.first == first && group.last == last)
+            .ok_or(OutlineError::NotGrouped{first, last})?;
+        group.collapsed = collapsed;
+        Ok(())
+    }
+
+    pub fn expand_all(&mut self) {
+        for group in &mut self.groups {
+            group.collapsed = false;
+        }
+    }
+
+    /// Whether a row or column is hidden by a collapsed group.
+    pub fn is_hidden(&self, index: u32) -> bool {
+        self.groups.iter().any(|group| group.collapsed && group.contains(index))
+    }
+
+    /// Iterate the groups, outer groups before the groups nested in them.
+    pub fn iter(&self) -> impl Iterator<Item=&OutlineGroup> {
+        self.groups.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+}
+
+/// Outline holds the row and column groups of a sheet.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Outline {
+    pub rows: Groups,
+    pub cols: Groups,
+}
+
+impl Outline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn group_rows(&mut self, first: u32, last: u32) -> Result<(), OutlineError> {
+        self.rows.group(first, last)
+    }
+
+    pub fn ungroup_rows(&mut self, first: u32, last: u32) -> Result<(), OutlineError> {
+        self.rows.ungroup(first, last)
+    }
+
+    pub fn group_cols(&mut self, first: u32, last: u32) -> Result<(), OutlineError> {
+        self.cols.group(first, last)
+    }
+
+    pub fn ungroup_cols(&mut self, first: u32, last: u32) -> Result<(), OutlineError> {
+        self.cols.ungroup(first, last)
+    }
+
+    /// Show rows and columns down to outline `level`.
+    pub fn collapse(&mut self, level: usize) {
+        self.rows.collapse(level);
+        self.cols.collapse(level);
+    }
+
+    pub fn expand_all(&mut self) {
+        self.rows.expand_all();
+        self.cols.expand_all();
+    }
+
+    pub fn is_row_hidden(&self, row: u32) -> bool {
+        self.rows.is_hidden(row)
+    }
+
+    pub fn is_col_hidden(&self, col: u32) -> bool {
+        self.cols.is_hidden(col)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::{CellId, Kernel};
+    use crate::kernel::worksheet::Worksheet;
+
+    #[test]
+    fn groups_nest_within_each_other() {
+        let mut outline = Outline::new();
+        outline.group_rows(1, 10).unwrap();
+        outline.group_rows(2, 4).unwrap();
+        outline.group_rows(6, 8).unwrap();
+        assert_eq!(outline.group_rows(2, 4), Err(OutlineError::AlreadyGrouped{first: 2, last: 4}));
+        assert_eq!(outline.group_rows(4, 6), Err(OutlineError::Overlap{first: 4, last: 6}));
+        assert_eq!(outline.group_rows(5, 4), Err(OutlineError::Empty{first: 5, last: 4}));
+        assert_eq!((outline.rows.level(0), outline.rows.level(1), outline.rows.level(3)), (0, 1, 2));
+        assert_eq!(outline.rows.iter().map(|group| (group.first, group.last)).collect::<Vec<_>>(), [(1, 10), (2, 4), (6, 8)]);
+        outline.ungroup_rows(1, 10).unwrap();
+        assert_eq!(outline.rows.level(3), 1);
+        assert_eq!(outline.ungroup_rows(1, 10), Err(OutlineError::NotGrouped{first: 1, last: 10}));
+        assert!(outline.cols.is_empty());
+    }
+
+    #[test]
+    fn groups_nest_at_most_seven_deep() {
+        let mut groups = Groups::default();
+        for level in 0..MAX_OUTLINE_LEVEL as u32 {
+            groups.group(level, 20 - level).unwrap();
+        }
+        assert_eq!(groups.group(10, 10), Err(OutlineError::TooDeep));
+        assert_eq!(groups.iter().count(), MAX_OUTLINE_LEVEL);
+    }
+
+    #[test]
+    fn collapsed_groups_hide_their_detail() {
+        let mut outline = Outline::new();
+        outline.group_cols(1, 5).unwrap();
+        outline.group_cols(2, 3).unwrap();
+        outline.collapse(2);
+        assert!(!outline.is_col_hidden(1));
+        assert!(outline.is_col_hidden(2));
+        outline.collapse(1);
+        assert!(outline.is_col_hidden(1));
+        outline.expand_all();
+        outline.cols.set_collapsed(2, 3, true).unwrap();
+        assert!(outline.is_col_hidden(3) && !outline.is_col_hidden(4));
+
+        let mut sheet: Worksheet = Worksheet::new();
+        for row in 0..3 {
+            sheet.set_cell(CellId::new(row, 0), row.to_string());
+        }
+        sheet.outline_mut().group_rows(1, 1).unwrap();
+        sheet.outline_mut().collapse(1);
+        let copied = sheet.range(CellId::new(0, 0), CellId::new(2, 0)).to_clipboard();
+        assert_eq!(copied.text, "0\r\n2\r\n");
+    }
+}
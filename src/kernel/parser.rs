@@ -0,0 +1,742 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{CellId, Formula, FormulaParseError, FunctionKind, Numeric, NumericAttribute, Value};
+use std::marker::PhantomData;
+
+/// The number of columns addressable in a formula, `A` through `XFD`.
+pub const MAX_COLUMNS: u32 = 16384;
+
+/// The number of rows addressable in a formula.
+pub const MAX_ROWS: u32 = 1048576;
+
+/// The number of characters, `=` excluded, spreadsheets allow in a formula,
+/// which formulas are held to by default.
+pub const MAX_FORMULA_LENGTH: usize = 8192;
+
+/// The nesting formulas are held to by default, of parentheses and function
+/// calls as well as of operators: `1+2+3` nests two deep. It is twice the
+/// nesting of function calls spreadsheets allow, to leave room for sums of
+/// many cells.
+pub const MAX_NESTING: usize = 128;
+
+/// Strictness controls which formula constructs the parser accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strictness {
+    /// Accept everything spreadsheets accept, including legacy constructs.
+    #[default]
+    Lenient,
+    /// Reject implicit intersection, unions of references in function
+    /// arguments and names which read like references, such as `XFE1` past
+    /// the last column or `R2C3` in R1C1 style.
+    Strict,
+}
+
+/// ParseOptions configures the formula parser.
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    pub strictness: Strictness,
+    /// The maximum number of characters of a formula, `MAX_FORMULA_LENGTH`
+    /// by default.
+    pub max_length: Option<usize>,
+    /// The maximum nesting of parentheses, function calls and operators,
+    /// `MAX_NESTING` by default. None lifts the limit.
+    pub max_nesting: Option<usize>,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self{strictness: Strictness::default(), max_length: Some(MAX_FORMULA_LENGTH), max_nesting: Some(MAX_NESTING)}
+    }
+}
+
+impl ParseOptions {
+    pub fn strict() -> Self {
+        Self{strictness: Strictness::Strict, ..Self::default()}
+    }
+
+    fn is_strict(&self) -> bool {
+        self.strictness == Strictness::Strict
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(String),
+    Ident(String),
+    Sheet(String),
+    Text(String),
+    Op(char),
+    LParen,
+    RParen,
+    Comma,
+    Colon,
+    Bang,
+    At,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Self::Number(s) | Self::Ident(s) => s.clone(),
+            Self::Sheet(s) => format!("'{}'", s),
+            Self::Text(s) => format!("\"{}\"", s),
+            Self::Op(c) => c.to_string(),
+            Self::LParen => "(".to_string(),
+            Self::RParen => ")".to_string(),
+            Self::Comma => ",".to_string(),
+            Self::Colon => ":".to_string(),
+            Self::Bang => "!".to_string(),
+            Self::At => "@".to_string(),
+        }
+    }
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_' || c == '$' || c == '\\'
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$' || c == '.'
+}
+
+/// Read a quoted string whose quote character is escaped by doubling it.
+fn quoted(chars: &mut std::iter::Peekable<std::str::Chars>, quote: char) -> Result<String, FormulaParseError> {
+    let mut s = String::new();
+    loop {
+        match chars.next() {
+            None => return Err(FormulaParseError::UnexpectedEnd),
+            Some(c) if c == quote => {
+                if chars.peek() == Some(&quote) {
+                    chars.next();
+                    s.push(quote);
+                } else {
+                    return Ok(s);
+                }
+            },
+            Some(c) => s.push(c),
+        }
+    }
+}
+
+fn tokenize(formula: &str) -> Result<Vec<Token>, FormulaParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = formula.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek() {
+                let exponent_sign = (c == '+' || c == '-') && number.ends_with(['e', 'E']);
+                if c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || exponent_sign {
+                    number.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            tokens.push(Token::Number(number));
+        } else if is_ident_start(c) {
+            let mut ident = String::new();
+            while let Some(&c) = chars.peek() {
+                if is_ident_char(c) || (ident.is_empty() && is_ident_start(c)) {
+                    ident.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            tokens.push(Token::Ident(ident));
+        } else {
+            chars.next();
+            tokens.push(match c {
+                '\'' => Token::Sheet(quoted(&mut chars, '\'')?),
+                '"' => Token::Text(quoted(&mut chars, '"')?),
+                '+' | '-' | '*' | '/' | '=' | '<' | '>' | '&' | '^' | '%' => Token::Op(c),
+                '(' => Token::LParen,
+                ')' => Token::RParen,
+                ',' => Token::Comma,
+                ':' => Token::Colon,
+                '!' => Token::Bang,
+                '@' => Token::At,
+                _ => return Err(FormulaParseError::UnexpectedToken(c.to_string())),
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+/// The spreadsheet names of the built-in functions.
+const FUNCTIONS: [(&str, FunctionKind); 37] = [
+    ("SUM", FunctionKind::Sum),
+    ("SUMPRODUCT", FunctionKind::SumProduct),
+    ("AVERAGE", FunctionKind::Average),
+    ("COUNT", FunctionKind::Count),
+    ("PRODUCT", FunctionKind::Prod),
+    ("IF", FunctionKind::If),
+    ("SQRT", FunctionKind::Sqrt),
+    ("STDEV", FunctionKind::Sdev),
+    ("OFFSET", FunctionKind::Offset),
+    ("GETPIVOTDATA", FunctionKind::GetPivotData),
+    ("CUBEVALUE", FunctionKind::CubeValue),
+    ("TEXT", FunctionKind::Text),
+    ("TEXTBEFORE", FunctionKind::TextBefore),
+    ("TEXTAFTER", FunctionKind::TextAfter),
+    ("PROPER", FunctionKind::Proper),
+    ("EXACT", FunctionKind::Exact),
+    ("REPT", FunctionKind::Rept),
+    ("CHAR", FunctionKind::Char),
+    ("CODE", FunctionKind::Code),
+    ("UNICHAR", FunctionKind::Unichar),
+    ("UNICODE", FunctionKind::Unicode),
+    ("CHOOSE", FunctionKind::Choose),
+    ("CHOOSECOLS", FunctionKind::ChooseCols),
+    ("CHOOSEROWS", FunctionKind::ChooseRows),
+    ("TAKE", FunctionKind::Take),
+    ("DROP", FunctionKind::Drop),
+    ("HSTACK", FunctionKind::HStack),
+    ("VSTACK", FunctionKind::VStack),
+    ("AGGREGATE", FunctionKind::Aggregate),
+    ("MATCH", FunctionKind::Match),
+    ("VLOOKUP", FunctionKind::VLookup),
+    ("COUNTIF", FunctionKind::CountIf),
+    ("SEARCH", FunctionKind::Search),
+    ("FIND", FunctionKind::Find),
+    ("REPLACE", FunctionKind::Replace),
+    ("CONVERT", FunctionKind::Convert),
+    ("CHANGEDSINCE", FunctionKind::ChangedSince),
+];
+
+/// Iterate the built-in functions.
+pub(crate) fn builtins() -> impl Iterator<Item=FunctionKind> {
+    FUNCTIONS.iter().map(|(_, kind)| *kind)
+}
+
+pub(crate) fn function_kind(name: &str) -> Option<FunctionKind> {
+    FUNCTIONS.iter()
+        .find(|(candidate, _)| candidate.eq_ignore_ascii_case(name))
+        .map(|(_, kind)| *kind)
+}
+
+impl FunctionKind {
+    /// Get the name of the function as written in formulas.
+    pub fn name(&self) -> &'static str {
+        FUNCTIONS.iter()
+            .find(|(_, kind)| kind == self)
+            .map(|(name, _)| *name)
+            .expect("every function has a name")
+    }
+
+    /// Whether the function may produce a dynamic array.
+    pub fn returns_array(&self) -> bool {
+        matches!(self, Self::Choose | Self::ChooseCols | Self::ChooseRows | Self::Take | Self::Drop | Self::HStack | Self::VStack | Self::ChangedSince)
+    }
+}
+
+/// Whether a function takes exactly one argument.
+fn is_single_argument(kind: FunctionKind) -> bool {
+    matches!(kind, FunctionKind::Sqrt | FunctionKind::Proper | FunctionKind::Char | FunctionKind::Code | FunctionKind::Unichar | FunctionKind::Unicode)
+}
+
+/// Parse an A1 style reference, ignoring `$` markers for absolute rows and
+/// columns. Ids outside the grid are not references.
+fn cell_id(ident: &str) -> Option<CellId> {
+    let id = ident.replace('$', "");
+    CellId::parse(&id).ok().filter(|id| id.row() < MAX_ROWS && id.col() < MAX_COLUMNS)
+}
+
+/// Whether a name reads like a reference spreadsheets would take it for:
+/// an A1 style reference outside the grid, or an R1C1 style one like `R`,
+/// `RC` or `R2C3`.
+fn is_ambiguous_name(name: &str) -> bool {
+    let id = name.replace('$', "");
+    if CellId::parse(&id).is_ok() {
+        return true;
+    }
+    let upper = id.to_ascii_uppercase();
+    let rest = upper.strip_prefix('R').unwrap_or(&upper);
+    let rest = rest.trim_start_matches(|c: char| c.is_ascii_digit());
+    let rest = match rest.strip_prefix('C') {
+        Some(rest) => rest.trim_start_matches(|c: char| c.is_ascii_digit()),
+        None if rest.len() < upper.len() => rest,
+        None => return false,
+    };
+    rest.is_empty()
+}
+
+/// Whether a value is a reference to more than one cell.
+fn is_range<T: Arithmetic>(value: &Value<T>) -> bool {
+    match value {
+        Value::Formula(Formula::CellRange(..)) => true,
+        Value::Formula(Formula::SheetRef(_, target)) => matches!(**target, Formula::CellRange(..)),
+        _ => false,
+    }
+}
+
+struct Parser<'a, T: Arithmetic> {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// The nesting of parentheses and function calls being parsed.
+    depth: usize,
+    /// The nesting of operators and function calls of the value parsed
+    /// last, zero for a literal or reference.
+    height: usize,
+    options: &'a ParseOptions,
+    _number: PhantomData<T>,
+}
+
+impl<'a, T: Arithmetic> Parser<'a, T> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, FormulaParseError> {
+        let token = self.tokens.get(self.pos).cloned().ok_or(FormulaParseError::UnexpectedEnd)?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), FormulaParseError> {
+        let token = self.next()?;
+        if token == expected {
+            Ok(())
+        } else {
+            Err(FormulaParseError::UnexpectedToken(token.describe()))
+        }
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Check an operand of a scalar operator. Ranges there are implicitly
+    /// intersected with the current row or column.
+    fn scalar(&self, value: Value<T>) -> Result<Box<Value<T>>, FormulaParseError> {
+        if self.options.is_strict() && is_range(&value) {
+            return Err(FormulaParseError::ImplicitIntersection);
+        }
+        Ok(Box::new(value))
+    }
+
+    /// Get the nesting of an operator or function call over operands nested
+    /// `below` deep, checked against the limit.
+    fn nest(&self, below: usize) -> Result<usize, FormulaParseError> {
+        let height = below + 1;
+        match self.options.max_nesting {
+            Some(max) if height > max => Err(FormulaParseError::TooDeep{max}),
+            _ => Ok(height),
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Value<T>, FormulaParseError> {
+        let mut lhs = self.concatenation()?;
+        let mut height = self.height;
+        while let Some(Token::Op(first @ ('=' | '<' | '>'))) = self.peek().cloned() {
+            self.pos += 1;
+            let op = match (first, self.peek()) {
+                ('<', Some(Token::Op('='))) => "<=",
+                ('<', Some(Token::Op('>'))) => "<>",
+                ('>', Some(Token::Op('='))) => ">=",
+                ('=', _) => "=",
+                ('<', _) => "<",
+                _ => ">",
+            };
+            if op.len() == 2 {
+                self.pos += 1;
+            }
+            let rhs = self.concatenation()?;
+            height = self.nest(height.max(self.height))?;
+            let (lhs_box, rhs_box) = (self.scalar(lhs)?, self.scalar(rhs)?);
+            lhs = Value::Formula(match op {
+                "=" => Formula::Cmp(lhs_box, rhs_box),
+                "<" => Formula::Lt(lhs_box, rhs_box),
+                ">" => Formula::Gr(lhs_box, rhs_box),
+                "<=" => Formula::Le(lhs_box, rhs_box),
+                ">=" => Formula::Ge(lhs_box, rhs_box),
+                _ => Formula::Ne(lhs_box, rhs_box),
+            });
+        }
+        self.height = height;
+        Ok(lhs)
+    }
+
+    fn concatenation(&mut self) -> Result<Value<T>, FormulaParseError> {
+        let mut lhs = self.additive()?;
+        let mut height = self.height;
+        while self.eat(&Token::Op('&')) {
+            let rhs = self.additive()?;
+            height = self.nest(height.max(self.height))?;
+            lhs = Value::Formula(Formula::Concat(self.scalar(lhs)?, self.scalar(rhs)?));
+        }
+        self.height = height;
+        Ok(lhs)
+    }
+
+    fn additive(&mut self) -> Result<Value<T>, FormulaParseError> {
+        let mut lhs = self.term()?;
+        let mut height = self.height;
+        while let Some(Token::Op(op @ ('+' | '-'))) = self.peek().cloned() {
+            self.pos += 1;
+            let rhs = self.term()?;
+            height = self.nest(height.max(self.height))?;
+            let (lhs_box, rhs_box) = (self.scalar(lhs)?, self.scalar(rhs)?);
+            lhs = Value::Formula(match op {
+                '+' => Formula::Add(lhs_box, rhs_box),
+                _ => Formula::Sub(lhs_box, rhs_box),
+            });
+        }
+        self.height = height;
+        Ok(lhs)
+    }
+
+    fn term(&mut self) -> Result<Value<T>, FormulaParseError> {
+        let mut lhs = self.power()?;
+        let mut height = self.height;
+        while let Some(Token::Op(op @ ('*' | '/'))) = self.peek().cloned() {
+            self.pos += 1;
+            let rhs = self.power()?;
+            height = self.nest(height.max(self.height))?;
+            let (lhs_box, rhs_box) = (self.scalar(lhs)?, self.scalar(rhs)?);
+            lhs = Value::Formula(match op {
+                '*' => Formula::Mul(lhs_box, rhs_box),
+                _ => Formula::Div(lhs_box, rhs_box),
+            });
+        }
+        self.height = height;
+        Ok(lhs)
+    }
+
+    /// Parse exponentiation, which is left associative like in spreadsheets,
+    /// so `2^3^2` is 64.
+    fn power(&mut self) -> Result<Value<T>, FormulaParseError> {
+        let mut lhs = self.percent()?;
+        let mut height = self.height;
+        while self.eat(&Token::Op('^')) {
+            let rhs = self.percent()?;
+            height = self.nest(height.max(self.height))?;
+            lhs = Value::Formula(Formula::Pow(self.scalar(lhs)?, self.scalar(rhs)?));
+        }
+        self.height = height;
+        Ok(lhs)
+    }
+
+    /// Parse postfix percent operators. A percent directly after a number
+    /// makes a percentage literal such as `50%`.
+    fn percent(&mut self) -> Result<Value<T>, FormulaParseError> {
+        let mut value = self.unary()?;
+        let mut height = self.height;
+        while self.eat(&Token::Op('%')) {
+            value = match value {
+                Value::Formula(Formula::NumberLit(number)) if number.attr().is_none() => {
+                    Value::Formula(Formula::NumberLit(Numeric::new(number.number(), Some(NumericAttribute::Percent))))
+                },
+                value => {
+                    height = self.nest(height)?;
+                    Value::Formula(Formula::Percent(self.scalar(value)?))
+                },
+            };
+        }
+        self.height = height;
+        Ok(value)
+    }
+
+    /// Parse unary signs. Like in spreadsheets they bind tighter than any
+    /// binary operator, so `-2^2` is 4. Unary plus has no effect.
+    fn unary(&mut self) -> Result<Value<T>, FormulaParseError> {
+        let mut negations = 0;
+        while let Some(Token::Op(op @ ('+' | '-'))) = self.peek().cloned() {
+            self.pos += 1;
+            if op == '-' {
+                negations += 1;
+            }
+        }
+        let mut value = self.primary()?;
+        let mut height = self.height;
+        for _ in 0..negations {
+            height = self.nest(height)?;
+            value = Value::Formula(Formula::Neg(self.scalar(value)?));
+        }
+        self.height = height;
+        Ok(value)
+    }
+
+    fn primary(&mut self) -> Result<Value<T>, FormulaParseError> {
+        self.depth += 1;
+        if let Some(max) = self.options.max_nesting.filter(|max| self.depth > *max) {
+            return Err(FormulaParseError::TooDeep{max});
+        }
+        self.height = 0;
+        let value = self.operand();
+        self.depth -= 1;
+        value
+    }
+
+    fn operand(&mut self) -> Result<Value<T>, FormulaParseError> {
+        match self.next()? {
+            Token::Number(number) => {
+                let parsed = number.parse::<T>()
+                    .map_err(|_| FormulaParseError::InvalidNumber(number.clone()))?;
+                Ok(Value::Formula(Formula::NumberLit(Numeric::new(parsed, None))))
+            },
+            Token::LParen => {
+                let value = self.comparison()?;
+                self.expect(Token::RParen)?;
+                Ok(value)
+            },
+            Token::At => {
+                if self.options.is_strict() {
+                    return Err(FormulaParseError::ImplicitIntersection);
+                }
+                self.primary()
+            },
+            Token::Text(text) => Ok(Value::Formula(Formula::TextLit(text))),
+            Token::Sheet(sheet) => {
+                self.expect(Token::Bang)?;
+                self.reference(Some(sheet))
+            },
+            Token::Ident(ident) => match self.peek() {
+                Some(Token::Bang) => {
+                    self.pos += 1;
+                    self.reference(Some(ident))
+                },
+                Some(Token::LParen) => {
+                    self.pos += 1;
+                    self.function(&ident)
+                },
+                _ => {
+                    self.pos -= 1;
+                    self.reference(None)
+                },
+            },
+            token => Err(FormulaParseError::UnexpectedToken(token.describe())),
+        }
+    }
+
+    fn reference(&mut self, sheet: Option<String>) -> Result<Value<T>, FormulaParseError> {
+        let ident = match self.next()? {
+            Token::Ident(ident) => ident,
+            token => return Err(FormulaParseError::UnexpectedToken(token.describe())),
+        };
+
+        let target = if let Some(start) = cell_id(&ident) {
+            if self.eat(&Token::Colon) {
+                match self.next()? {
+                    Token::Ident(end) => match cell_id(&end) {
+                        Some(end) => Formula::CellRange(start, end),
+                        None => return Err(FormulaParseError::UnexpectedToken(end)),
+                    },
+                    token => return Err(FormulaParseError::UnexpectedToken(token.describe())),
+                }
+            } else {
+                Formula::CellRef(start)
+            }
+        } else if sheet.is_none() && ident.eq_ignore_ascii_case("TRUE") {
+            return Ok(Value::Formula(Formula::BoolLit(true)));
+        } else if sheet.is_none() && ident.eq_ignore_ascii_case("FALSE") {
+            return Ok(Value::Formula(Formula::BoolLit(false)));
+        } else if self.options.is_strict() && is_ambiguous_name(&ident) {
+            return Err(FormulaParseError::AmbiguousName(ident));
+        } else {
+            Formula::Name(ident)
+        };
+
+        Ok(Value::Formula(match sheet {
+            Some(sheet) => Formula::SheetRef(sheet, Box::new(target)),
+            None => target,
+        }))
+    }
+
+    fn function(&mut self, name: &str) -> Result<Value<T>, FormulaParseError> {
+        let kind = function_kind(name);
+        // Functions which are not built in are resolved when evaluating, so in
+        // strict mode they must be qualified with a namespace to rule out typos.
+        if kind.is_none() && self.options.is_strict() && !name.contains('.') {
+            return Err(FormulaParseError::UnknownFunction(name.to_string()));
+        }
+        let mut arguments = Vec::new();
+        let mut below = 0;
+        if !self.eat(&Token::RParen) {
+            loop {
+                self.argument(&mut arguments)?;
+                below = below.max(self.height);
+                match self.next()? {
+                    Token::Comma => continue,
+                    Token::RParen => break,
+                    token => return Err(FormulaParseError::UnexpectedToken(token.describe())),
+                }
+            }
+        }
+        self.height = self.nest(below)?;
+        let Some(kind) = kind else { return Ok(Value::Formula(Formula::Custom{name: name.to_string(), arguments})) };
+        if self.options.is_strict() && is_single_argument(kind) && arguments.len() > 1 {
+            return Err(FormulaParseError::UnionInArguments);
+        }
+        Ok(Value::Formula(Formula::Function{kind, arguments}))
+    }
+
+    /// Parse one function argument. A parenthesised list of references like
+    /// `(A1,B2:B4)` is a union, which is flattened into the arguments. The
+    /// height left is that of the deepest argument.
+    fn argument(&mut self, arguments: &mut Vec<Value<T>>) -> Result<(), FormulaParseError> {
+        if self.peek() == Some(&Token::LParen) {
+            let start = self.pos;
+            self.pos += 1;
+            let first = self.comparison()?;
+            if self.peek() == Some(&Token::Comma) {
+                if self.options.is_strict() {
+                    return Err(FormulaParseError::UnionInArguments);
+                }
+                arguments.push(first);
+                let mut height = self.height;
+                while self.eat(&Token::Comma) {
+                    arguments.push(self.comparison()?);
+                    height = height.max(self.height);
+                }
+                self.height = height;
+                return self.expect(Token::RParen);
+            }
+            self.pos = start;
+        }
+        arguments.push(self.comparison()?);
+        Ok(())
+    }
+}
+
+/// Parse a formula, without the leading `=`.
+pub fn parse<T: Arithmetic>(formula: &str, options: &ParseOptions) -> Result<Formula<T>, FormulaParseError> {
+    if let Some(max) = options.max_length {
+        let length = formula.chars().count();
+        if length > max {
+            return Err(FormulaParseError::TooLong{length, max});
+        }
+    }
+    let mut parser = Parser{tokens: tokenize(formula)?, pos: 0, depth: 0, height: 0, options, _number: PhantomData::<T>};
+    let value = parser.comparison()?;
+    if let Some(token) = parser.peek() {
+        return Err(FormulaParseError::UnexpectedToken(token.describe()));
+    }
+    match value {
+        Value::Formula(formula) => Ok(formula),
+        _ => unreachable!("the parser only produces formulas"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::Kernel;
+    use crate::kernel::worksheet::Worksheet;
+
+    #[test]
+    fn strict_mode_rejects_ambiguous_constructs() {
+        let strict = ParseOptions::strict();
+        assert!(parse::<f64>("Rate*2", &strict).is_ok());
+        assert!(parse::<f64>("Inputs!Rate*2", &strict).is_ok());
+        for ambiguous in ["XFE1", "R2C3", "rc", "C", "R", "Inputs!R1C1"] {
+            assert!(matches!(parse::<f64>(ambiguous, &strict), Err(FormulaParseError::AmbiguousName(_))), "{}", ambiguous);
+        }
+        for name in ["Rate", "Cost", "Rc2d", "Total"] {
+            assert!(parse::<f64>(name, &strict).is_ok(), "{}", name);
+        }
+        assert!(parse::<f64>("IF(TRUE,A1,B1:B2)", &strict).is_ok());
+        assert!(matches!(parse::<f64>("@A1:A3", &strict), Err(FormulaParseError::ImplicitIntersection)));
+        assert!(matches!(parse::<f64>("SQRT((A1,A2))", &strict), Err(FormulaParseError::UnionInArguments)));
+        for lenient in ["R2C3*2", "@A1:A3"] {
+            assert!(parse::<f64>(lenient, &ParseOptions::default()).is_ok(), "{}", lenient);
+        }
+    }
+
+    #[test]
+    fn products_bind_tighter_than_sums() {
+        let parse = |formula: &str| parse::<f64>(formula, &ParseOptions::default()).unwrap();
+        assert!(matches!(parse("1+2*3"), Formula::Add(..)));
+        assert!(matches!(parse("1*2+3"), Formula::Add(..)));
+        assert!(matches!(parse("1+2=3"), Formula::Cmp(..)));
+        assert!(matches!(parse("(1+2)*3"), Formula::Mul(..)));
+        assert!(matches!(parse("Data!A1:B2"), Formula::SheetRef(sheet, _) if sheet == "Data"));
+    }
+
+    #[test]
+    fn malformed_formulas_fail_to_parse() {
+        for formula in ["1+", "SUM(1,", "(1", "1)", "\"open", "A1:", "1 2"] {
+            assert!(parse::<f64>(formula, &ParseOptions::default()).is_err(), "{}", formula);
+        }
+    }
+
+    #[test]
+    fn default_options_limit_nesting() {
+        let nested = |depth: usize| format!("{}1+1{}", "(".repeat(depth), ")".repeat(depth));
+        // The innermost numbers count as a level of their own.
+        assert!(parse::<f64>(&nested(MAX_NESTING - 1), &ParseOptions::default()).is_ok());
+        assert!(matches!(parse::<f64>(&nested(MAX_NESTING), &ParseOptions::default()), Err(FormulaParseError::TooDeep{max: MAX_NESTING})));
+        let unlimited = ParseOptions{max_nesting: None, ..ParseOptions::default()};
+        assert!(parse::<f64>(&nested(MAX_NESTING), &unlimited).is_ok());
+    }
+
+    #[test]
+    fn default_options_limit_operator_nesting() {
+        let chain = |length: usize| format!("1{}", "+1".repeat(length));
+        assert!(parse::<f64>(&chain(MAX_NESTING), &ParseOptions::default()).is_ok());
+        assert!(matches!(parse::<f64>(&chain(MAX_NESTING + 1), &ParseOptions::default()), Err(FormulaParseError::TooDeep{max: MAX_NESTING})));
+        let calls = |depth: usize| format!("{}1{}", "SUM(1*".repeat(depth), ")".repeat(depth));
+        assert!(parse::<f64>(&calls(MAX_NESTING / 2), &ParseOptions::default()).is_ok());
+        assert!(parse::<f64>(&calls(MAX_NESTING / 2 + 1), &ParseOptions::default()).is_err());
+        // Sums of many cells nest no deeper than their longest chain.
+        assert!(parse::<f64>(&format!("SUM({})", vec!["A1*2"; 1000].join(",")), &ParseOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn default_options_limit_length() {
+        let chain = format!("1{}", "+1".repeat(MAX_FORMULA_LENGTH));
+        assert!(matches!(parse::<f64>(&chain, &ParseOptions::default()), Err(FormulaParseError::TooLong{max: MAX_FORMULA_LENGTH, ..})));
+    }
+
+    #[test]
+    fn pathological_cells_do_not_overflow_the_stack() {
+        let mut sheet: Worksheet = Worksheet::default();
+        let pathological = [
+            format!("={}1{}", "(".repeat(200_000), ")".repeat(200_000)),
+            format!("=1{}", "+1".repeat(4000)),
+            format!("={}1", "-".repeat(8000)),
+            format!("=1{}", "&1".repeat(4000)),
+            format!("=1{}", "^1".repeat(4000)),
+            format!("=A1{}", "%".repeat(8000)),
+        ];
+        for (row, formula) in pathological.into_iter().enumerate() {
+            let cell_id = CellId::new(row as u32, 0);
+            sheet.set_cell(cell_id, formula);
+            assert!(matches!(sheet.cell(cell_id).map(|cell| cell.value()), Some(Value::FormulaParseError(_))));
+            assert!(sheet.evaluate_cell(cell_id).is_ok_and(|value| value.is_error()));
+        }
+        // The deepest formulas parsed evaluate and display.
+        let deepest = [
+            format!("={}1+1{}", "(".repeat(MAX_NESTING - 1), ")".repeat(MAX_NESTING - 1)),
+            format!("=1{}", "+1".repeat(MAX_NESTING)),
+            format!("={}1", "-".repeat(MAX_NESTING)),
+            format!("=1{}", "&1".repeat(MAX_NESTING)),
+            format!("=1{}", "%".repeat(MAX_NESTING)),
+        ];
+        for (row, formula) in deepest.into_iter().enumerate() {
+            let cell_id = CellId::new(row as u32, 1);
+            sheet.set_cell(cell_id, formula);
+            assert!(sheet.evaluate_cell(cell_id).is_ok_and(|value| !value.is_error()));
+            assert!(!sheet.cell(cell_id).unwrap().formula().unwrap().to_string().is_empty());
+        }
+    }
+
+    #[test]
+    fn operators_bind_by_precedence() {
+        let parse = |formula: &str| parse::<f64>(formula, &ParseOptions::default()).unwrap();
+        assert!(matches!(parse("1+2*3"), Formula::Add(..)));
+        assert!(matches!(parse("1*2+3"), Formula::Add(..)));
+        assert!(matches!(parse("1&2=3"), Formula::Cmp(..)));
+        assert!(matches!(parse("-2^2"), Formula::Pow(..)));
+        assert!(matches!(parse("(1+2)*3"), Formula::Mul(..)));
+        assert!(matches!(parse("Data!A1:B2"), Formula::SheetRef(sheet, _) if sheet == "Data"));
+        assert!(matches!(parse("'My ''Sheet'''!A1"), Formula::SheetRef(sheet, _) if sheet == "My 'Sheet'"));
+    }
+}
@@ -0,0 +1,322 @@
+use super::arithmetic::Arithmetic;
+use super::autosave::{load_autosave, save_workbook};
+use super::functions::{FunctionError, FunctionRegistry};
+use super::journal::JournalError;
+use super::kernel::Cell;
+use super::workbook::Workbook;
+use thiserror::Error;
+use std::collections::HashMap;
+use std::io;
+use std::mem::size_of;
+use std::path::PathBuf;
+
+#[derive(Error, Debug)]
+pub enum PoolError {
+    #[error("no tenant {0}")]
+    UnknownTenant(String),
+
+    #[error("tenant {tenant} has no workbook {id}")]
+    NotFound{tenant: String, id: String},
+
+    #[error(transparent)]
+    Function(#[from] FunctionError),
+
+    #[error(transparent)]
+    Journal(#[from] JournalError),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// WorkbookStore keeps the workbooks a pool evicts, so they can be loaded
+/// again when next used.
+pub trait WorkbookStore<T: Arithmetic> {
+    /// Load a workbook, or get None if it was never stored.
+    fn load(&mut self, tenant: &str, id: &str) -> Result<Option<Workbook<T>>, PoolError>;
+    fn save(&mut self, tenant: &str, id: &str, workbook: &Workbook<T>) -> Result<(), PoolError>;
+}
+
+/// DirectoryStore stores workbooks in the format of `AutoSave`, in a
+/// directory per workbook under a directory per tenant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryStore {
+    root: PathBuf,
+}
+
+impl DirectoryStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self{root: root.into()}
+    }
+
+    /// Get the directory of a workbook, named after the hex digits of the
+    /// tenant and id so neither can escape the root.
+    fn dir(&self, tenant: &str, id: &str) -> PathBuf {
+        let hex = |text: &str| text.bytes().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        self.root.join(hex(tenant)).join(hex(id))
+    }
+}
+
+impl<T: Arithmetic> WorkbookStore<T> for DirectoryStore {
+    fn load(&mut self, tenant: &str, id: &str) -> Result<Option<Workbook<T>>, PoolError> {
+        let dir = self.dir(tenant, id);
+        if !dir.exists() {
+            return Ok(None);
+        }
+        Ok(Some(load_autosave(dir)?.0))
+    }
+
+    fn save(&mut self, tenant: &str, id: &str, workbook: &Workbook<T>) -> Result<(), PoolError> {
+        Ok(save_workbook(workbook, self.dir(tenant, id), 0)?)
+    }
+}
+
+/// TenantSetup registers the functions of a tenant in the registry of each
+/// of its workbooks as it is opened.
+pub type TenantSetup<T> = Box<dyn Fn(&mut FunctionRegistry<T>) -> Result<(), FunctionError>>;
+
+/// Estimate the memory a workbook takes: its cells and the raw contents
+/// they keep on the heap, with formulas counted at twice their text for
+/// their parsed form.
+pub(crate) fn estimated_size<T: Arithmetic>(workbook: &Workbook<T>) -> usize {
+    workbook.sheets()
+        .flat_map(|(_, sheet)| sheet.cells())
+        .map(|(_, cell)| {
+            let parsed = if cell.formula().is_some() { 2 * cell.raw().len() } else { 0 };
+            size_of::<Cell<T>>() + cell.raw_text().heap_size() + parsed
+        })
+        .sum()
+}
+
+struct Entry<T: Arithmetic> {
+    workbook: Workbook<T>,
+    /// The estimated size, None after the workbook was borrowed mutably.
+    size: Option<usize>,
+    last_used: u64,
+    /// Whether the workbook changed since it was last stored.
+    dirty: bool,
+}
+
+/// WorkbookPool keeps many workbooks of many tenants open within a memory
+/// budget. When the estimated size of the open workbooks exceeds the
+/// budget, the least recently used are stored and closed, and they are
+/// loaded again the next time they are used. Each workbook has functions of
+/// its own: those of its tenant, registered by the setup of the tenant
+/// whenever the workbook is opened, so tenants never see each other's
+/// functions or workbooks.
+pub struct WorkbookPool<T: Arithmetic=f64, S: WorkbookStore<T>=DirectoryStore> {
+    store: S,
+    budget: usize,
+    tenants: HashMap<String, TenantSetup<T>>,
+    open: HashMap<(String, String), Entry<T>>,
+    clock: u64,
+}
+
+impl<T: Arithmetic, S: WorkbookStore<T>> WorkbookPool<T, S> {
+    /// Create a pool keeping the estimated size of its open workbooks within
+    /// `budget` bytes.
+    pub fn new(store: S, budget: usize) -> Self {
+        Self{store, budget, tenants: HashMap::new(), open: HashMap::new(), clock: 0}
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Add a tenant, or replace its setup. Replacing it only affects the
+    /// workbooks opened afterwards.
+    pub fn add_tenant(&mut self, tenant: &str, setup: TenantSetup<T>) {
+        self.tenants.insert(tenant.to_string(), setup);
+    }
+
+    /// Add a tenant without functions of its own.
+    pub fn add_plain_tenant(&mut self, tenant: &str) {
+        self.add_tenant(tenant, Box::new(|_| Ok(())));
+    }
+
+    /// Whether a workbook is open, as opposed to stored or unknown.
+    pub fn is_open(&self, tenant: &str, id: &str) -> bool {
+        self.open.contains_key(&(tenant.to_string(), id.to_string()))
+    }
+
+    /// Get the number of open workbooks.
+    pub fn len(&self) -> usize {
+        self.open.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.open.is_empty()
+    }
+
+    /// Get the estimated size of the workbooks open.
+    pub fn used(&mut self) -> usize {
+        self.open.values_mut().map(|entry| *entry.size.get_or_insert_with(|| estimated_size(&entry.workbook))).sum()
+    }
+
+    /// Get the estimated size of the workbooks open other than `key`.
+    fn used_by_others(&mut self, key: &(String, String)) -> usize {
+        self.open.iter_mut()
+            .filter(|(other, _)| *other != key)
+            .map(|(_, entry)| *entry.size.get_or_insert_with(|| estimated_size(&entry.workbook)))
+            .sum()
+    }
+
+    /// Add a workbook for a tenant, replacing any of the same id. The
+    /// functions of the tenant are registered in it.
+    pub fn insert(&mut self, tenant: &str, id: &str, mut workbook: Workbook<T>) -> Result<&mut Workbook<T>, PoolError> {
+        let setup = self.tenants.get(tenant).ok_or_else(|| PoolError::UnknownTenant(tenant.to_string()))?;
+        setup(workbook.functions_mut())?;
+        let key = (tenant.to_string(), id.to_string());
+        let size = estimated_size(&workbook);
+        self.evict(&key, size)?;
+        self.clock += 1;
+        let entry = Entry{size: Some(size), last_used: self.clock, dirty: true, workbook};
+        Ok(&mut self.open.entry(key).insert_entry(entry).into_mut().workbook)
+    }
+
+    /// Get a workbook, loading it if it was evicted.
+    pub fn get(&mut self, tenant: &str, id: &str) -> Result<&Workbook<T>, PoolError> {
+        let key = self.open_entry(tenant, id)?;
+        Ok(&self.open[&key].workbook)
+    }
+
+    /// Get a workbook to change, loading it if it was evicted. It is stored
+    /// again when evicted.
+    pub fn get_mut(&mut self, tenant: &str, id: &str) -> Result<&mut Workbook<T>, PoolError> {
+        let key = self.open_entry(tenant, id)?;
+        let entry = self.open.get_mut(&key).expect("the workbook was just opened");
+        entry.size = None;
+        entry.dirty = true;
+        Ok(&mut entry.workbook)
+    }
+
+    /// Store a workbook if it changed and close it.
+    pub fn close(&mut self, tenant: &str, id: &str) -> Result<(), PoolError> {
+        let key = (tenant.to_string(), id.to_string());
+        if let Some(entry) = self.open.get(&key) {
+            if entry.dirty {
+                self.store.save(tenant, id, &entry.workbook)?;
+            }
+            self.open.remove(&key);
+        }
+        Ok(())
+    }
+
+    /// Store every open workbook which changed, keeping them open.
+    pub fn flush(&mut self) -> Result<(), PoolError> {
+        for ((tenant, id), entry) in self.open.iter_mut() {
+            if entry.dirty {
+                self.store.save(tenant, id, &entry.workbook)?;
+                entry.dirty = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark a workbook as used, loading it first if it is not open.
+    fn open_entry(&mut self, tenant: &str, id: &str) -> Result<(String, String), PoolError> {
+        let key = (tenant.to_string(), id.to_string());
+        self.clock += 1;
+        if let Some(entry) = self.open.get_mut(&key) {
+            entry.last_used = self.clock;
+            return Ok(key);
+        }
+        let setup = self.tenants.get(tenant).ok_or_else(|| PoolError::UnknownTenant(tenant.to_string()))?;
+        let not_found = || PoolError::NotFound{tenant: tenant.to_string(), id: id.to_string()};
+        let mut workbook = self.store.load(tenant, id)?.ok_or_else(not_found)?;
+        setup(workbook.functions_mut())?;
+        let size = estimated_size(&workbook);
+        self.evict(&key, size)?;
+        self.open.insert(key.clone(), Entry{size: Some(size), last_used: self.clock, dirty: false, workbook});
+        Ok(key)
+    }
+
+    /// Close the least recently used workbooks other than `keep` until the
+    /// open ones fit the budget along with `size` bytes more for `keep`,
+    /// which is about to be opened or replaced. This happens before `keep`
+    /// is opened, so a failure to store a workbook leaves the pool as it
+    /// was.
+    fn evict(&mut self, keep: &(String, String), size: usize) -> Result<(), PoolError> {
+        while self.used_by_others(keep) + size > self.budget {
+            let oldest = self.open.iter()
+                .filter(|(key, _)| *key != keep)
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            let Some((tenant, id)) = oldest else { break };
+            self.close(&tenant, &id)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::CellId;
+
+    #[derive(Default)]
+    struct MemoryStore {
+        saved: HashMap<(String, String), Workbook>,
+        failing: bool,
+    }
+
+    impl WorkbookStore<f64> for MemoryStore {
+        fn load(&mut self, tenant: &str, id: &str) -> Result<Option<Workbook>, PoolError> {
+            Ok(self.saved.get(&(tenant.to_string(), id.to_string())).map(|workbook| workbook.convert().unwrap()))
+        }
+
+        fn save(&mut self, tenant: &str, id: &str, workbook: &Workbook) -> Result<(), PoolError> {
+            if self.failing {
+                return Err(io::Error::other("store is down").into());
+            }
+            self.saved.insert((tenant.to_string(), id.to_string()), workbook.convert().unwrap());
+            Ok(())
+        }
+    }
+
+    fn workbook(value: &str) -> Workbook {
+        let mut workbook = Workbook::new();
+        workbook.add_sheet("Sheet1").unwrap();
+        workbook.set_cell("Sheet1", CellId::new(0, 0), value.to_string()).unwrap();
+        workbook
+    }
+
+    #[test]
+    fn least_recently_used_workbooks_are_evicted_and_reloaded() {
+        let size = estimated_size(&workbook("1"));
+        let mut pool = WorkbookPool::new(MemoryStore::default(), 2 * size);
+        pool.add_plain_tenant("a");
+        pool.insert("a", "one", workbook("1")).unwrap();
+        pool.insert("a", "two", workbook("2")).unwrap();
+        pool.get("a", "one").unwrap();
+        pool.insert("a", "three", workbook("3")).unwrap();
+        assert!(pool.is_open("a", "one") && pool.is_open("a", "three"));
+        assert!(!pool.is_open("a", "two"));
+        assert!(pool.used() <= pool.budget());
+        let reloaded = pool.get("a", "two").unwrap();
+        assert_eq!(reloaded.sheet("Sheet1").unwrap().cell(CellId::new(0, 0)).unwrap().raw(), "2");
+        assert!(matches!(pool.get("b", "one"), Err(PoolError::UnknownTenant(_))));
+        assert!(matches!(pool.get("a", "four"), Err(PoolError::NotFound{..})));
+    }
+
+    #[test]
+    fn failing_evictions_leave_the_pool_as_it_was() {
+        let size = estimated_size(&workbook("1"));
+        let mut pool = WorkbookPool::new(MemoryStore::default(), size);
+        pool.add_plain_tenant("a");
+        pool.insert("a", "one", workbook("1")).unwrap();
+        pool.store.failing = true;
+        assert!(pool.insert("a", "two", workbook("2")).is_err());
+        assert!(pool.is_open("a", "one"));
+        assert!(!pool.is_open("a", "two"));
+        assert_eq!(pool.len(), 1);
+        assert!(pool.used() <= pool.budget());
+        pool.store.failing = false;
+        pool.insert("a", "two", workbook("2")).unwrap();
+        assert_eq!(pool.len(), 1);
+        assert!(pool.store().saved.contains_key(&("a".to_string(), "one".to_string())));
+    }
+}
@@ -0,0 +1,185 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{CellError, CellId, Primitive, Value};
+use super::worksheet::{SheetError, Worksheet};
+use std::collections::{BTreeMap, HashSet};
+
+/// The number of bins of the histogram of a column.
+pub const HISTOGRAM_BINS: usize = 10;
+
+/// ValueKind is the type of an evaluated value, as counted by a profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ValueKind {
+    Number,
+    Text,
+    Bool,
+    Date,
+    Time,
+    IPAddress,
+    Error,
+}
+
+/// Bin is one bar of a histogram: the numbers from `start` up to `end`, and
+/// up to and including `end` for the last bin.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bin {
+    pub start: f64,
+    pub end: f64,
+    pub count: usize,
+}
+
+/// ColumnProfile summarizes the evaluated values of one column of a range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnProfile {
+    /// The column of the sheet.
+    pub col: u32,
+    /// The number of empty cells.
+    pub nulls: usize,
+    /// The number of values of each type.
+    pub types: BTreeMap<ValueKind, usize>,
+    /// The number of different values, with text compared exactly.
+    pub distinct: usize,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub mean: Option<f64>,
+    /// The distribution of the numbers between `min` and `max`, empty
+    /// without numbers.
+    pub histogram: Vec<Bin>,
+}
+
+impl ColumnProfile {
+    /// Get the number of values which are not empty.
+    pub fn count(&self) -> usize {
+        self.types.values().sum()
+    }
+
+    /// Get the type most values have, if any.
+    pub fn dominant_type(&self) -> Option<ValueKind> {
+        self.types.iter().max_by_key(|(_, count)| **count).map(|(kind, _)| *kind)
+    }
+}
+
+/// The identity of a value for counting distinct values.
+#[derive(PartialEq, Eq, Hash)]
+pub(crate) enum Key {
+    Empty,
+    Number(u64),
+    Text(String),
+    Bool(bool),
+    Date(chrono::NaiveDate),
+    Time(chrono::TimeDelta),
+    IPAddress([u8; 4]),
+    Error(CellError),
+}
+
+fn histogram(numbers: &[f64], min: f64, max: f64) -> Vec<Bin> {
+    if numbers.is_empty() {
+        return Vec::new();
+    }
+    if min == max {
+        return vec![Bin{start: min, end: max, count: numbers.len()}];
+    }
+    let width = (max - min) / HISTOGRAM_BINS as f64;
+    let mut bins = (0..HISTOGRAM_BINS)
+        .map(|index| Bin{start: min + width * index as f64, end: min + width * (index + 1) as f64, count: 0})
+        .collect::<Vec<_>>();
+    bins[HISTOGRAM_BINS - 1].end = max;
+    for number in numbers {
+        let index = (((number - min) / width) as usize).min(HISTOGRAM_BINS - 1);
+        bins[index].count += 1;
+    }
+    bins
+}
+
+impl<T: Arithmetic> Worksheet<T> {
+    /// Profile the columns of the range between two corners over their
+    /// evaluated values, such as to check the quality of imported data.
+    /// Text cells count as text values.
+    pub fn profile(&self, first: CellId, second: CellId) -> Result<Vec<ColumnProfile>, SheetError> {
+        let range = self.range(first, second);
+        let values = range.evaluate()?;
+        let mut profiles = Vec::with_capacity(values.cols());
+        for col in 0..values.cols() {
+            let mut profile = ColumnProfile{
+                col: range.start().col() + col as u32,
+                nulls: 0,
+                types: BTreeMap::new(),
+                distinct: 0,
+                min: None,
+                max: None,
+                mean: None,
+                histogram: Vec::new(),
+            };
+            let mut keys = HashSet::new();
+            let mut numbers = Vec::new();
+            for row in 0..values.rows() {
+                let (kind, key) = match &values.get(row, col).expect("position is in the range") {
+                    Value::Empty => {
+                        profile.nulls += 1;
+                        continue;
+                    },
+                    Value::Raw => {
+                        let text = range.cell(row as u32, col as u32).map(|cell| cell.text().to_string()).unwrap_or_default();
+                        (ValueKind::Text, Key::Text(text))
+                    },
+                    Value::Primitive(primitive) => match primitive {
+                        Primitive::Number(number) => {
+                            let number = number.value().to_f64();
+                            numbers.push(number);
+                            (ValueKind::Number, Key::Number(number.to_bits()))
+                        },
+                        Primitive::Text(text) => (ValueKind::Text, Key::Text(text.clone())),
+                        Primitive::Bool(b) => (ValueKind::Bool, Key::Bool(*b)),
+                        Primitive::Date(date) => (ValueKind::Date, Key::Date(*date)),
+                        Primitive::Time(time) => (ValueKind::Time, Key::Time(*time)),
+                        Primitive::IPAddress(octets) => (ValueKind::IPAddress, Key::IPAddress(*octets)),
+                    },
+                    Value::Error(e) => (ValueKind::Error, Key::Error(*e)),
+                    Value::FormulaParseError(_) => (ValueKind::Error, Key::Error(CellError::Name)),
+                    Value::Formula(_) | Value::Array(_) => unreachable!("evaluated values are not formulas or arrays"),
+                };
+                *profile.types.entry(kind).or_default() += 1;
+                keys.insert(key);
+            }
+            profile.distinct = keys.len();
+            profile.min = numbers.iter().copied().reduce(f64::min);
+            profile.max = numbers.iter().copied().reduce(f64::max);
+            if let (Some(min), Some(max)) = (profile.min, profile.max) {
+                profile.mean = Some(numbers.iter().sum::<f64>() / numbers.len() as f64);
+                profile.histogram = histogram(&numbers, min, max);
+            }
+            profiles.push(profile);
+        }
+        Ok(profiles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::Kernel;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn columns_are_profiled_over_their_values() {
+        let mut sheet: Worksheet = Worksheet::new();
+        for (row, (number, text)) in [("0", "a"), ("10", "b"), ("5", "a"), ("", "TRUE"), ("=1/0", "=B3*2"), ("10", "")].iter().enumerate() {
+            sheet.set_cell(CellId::new(row as u32 + 1, 1), number.to_string());
+            sheet.set_cell(CellId::new(row as u32 + 1, 2), text.to_string());
+        }
+        let profiles = sheet.profile(CellId::new(1, 1), CellId::new(6, 2)).unwrap();
+        let numbers = &profiles[0];
+        assert_eq!((numbers.col, numbers.nulls, numbers.count(), numbers.distinct), (1, 1, 5, 4));
+        assert_eq!(numbers.types, BTreeMap::from([(ValueKind::Number, 4), (ValueKind::Error, 1)]));
+        assert_eq!((numbers.min, numbers.max, numbers.mean), (Some(0.0), Some(10.0), Some(6.25)));
+        assert_eq!(numbers.histogram.len(), HISTOGRAM_BINS);
+        assert_eq!((numbers.histogram[0].count, numbers.histogram[5].count, numbers.histogram[9].count), (1, 1, 2));
+        assert_eq!(numbers.histogram[9].end, 10.0);
+        assert_eq!(numbers.dominant_type(), Some(ValueKind::Number));
+
+        let text = &profiles[1];
+        assert_eq!((text.col, text.nulls, text.distinct), (2, 1, 4));
+        assert_eq!(text.types, BTreeMap::from([(ValueKind::Text, 3), (ValueKind::Bool, 1), (ValueKind::Number, 1)]));
+        assert_eq!(text.histogram, [Bin{start: 20.0, end: 20.0, count: 1}]);
+        assert_eq!(text.dominant_type(), Some(ValueKind::Text));
+    }
+}
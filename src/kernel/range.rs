@@ -0,0 +1,185 @@
+use super::arithmetic::Arithmetic;
+use super::array::Array2D;
+use super::kernel::{Cell, CellId, Kernel, Numeric, Primitive, Value};
+use super::worksheet::{SheetError, Worksheet};
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum MatrixError {
+    #[error("{0} is not a number")]
+    NotNumeric(CellId),
+
+    #[error("writing at {anchor} would extend past the sheet")]
+    OutOfBounds{anchor: CellId},
+
+    #[error(transparent)]
+    Sheet(#[from] SheetError),
+}
+
+/// MatrixWrite summarizes writing a matrix into a sheet.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MatrixWrite {
+    pub written: usize,
+    /// The formula cells left in place.
+    pub skipped: Vec<CellId>,
+}
+
+/// Range is a rectangular block of cells of a sheet.
+pub struct Range<'a, T: Arithmetic=f64> {
+    sheet: &'a Worksheet<T>,
+    start: CellId,
+    end: CellId,
+}
+
+impl<'a, T: Arithmetic> Range<'a, T> {
+    /// Create a range between two corners, in any order.
+    pub fn new(sheet: &'a Worksheet<T>, first: CellId, second: CellId) -> Self {
+        let start = CellId::new(first.row().min(second.row()), first.col().min(second.col()));
+        let end = CellId::new(first.row().max(second.row()), first.col().max(second.col()));
+        Self{sheet, start, end}
+    }
+
+    pub fn sheet(&self) -> &'a Worksheet<T> {
+        self.sheet
+    }
+
+    /// Get the top left corner.
+    pub fn start(&self) -> CellId {
+        self.start
+    }
+
+    /// Get the bottom right corner.
+    pub fn end(&self) -> CellId {
+        self.end
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.end.row() - self.start.row() + 1
+    }
+
+    pub fn cols(&self) -> u32 {
+        self.end.col() - self.start.col() + 1
+    }
+
+    pub fn contains(&self, cell_id: CellId) -> bool {
+        (self.start.row()..=self.end.row()).contains(&cell_id.row())
+            && (self.start.col()..=self.end.col()).contains(&cell_id.col())
+    }
+
+    /// Iterate the ids of the cells, row by row.
+    pub fn cell_ids(&self) -> impl Iterator<Item=CellId> {
+        CellId::range(self.start, self.end)
+    }
+
+    /// Evaluate every cell of the range.
+    pub fn evaluate(&self) -> Result<Array2D<Value<T>>, SheetError> {
+        self.sheet.evaluate_range(self.start, self.end)
+    }
+
+    /// Evaluate the range into a matrix of numbers under another number
+    /// type, for handing over to numeric code. Numbers convert like
+    /// `Numeric::cast`. Every cell must evaluate to a number.
+    pub fn as_matrix<U: Arithmetic>(&self) -> Result<Array2D<U>, MatrixError> {
+        let values = self.evaluate()?;
+        Array2D::try_from_fn(values.rows(), values.cols(), |row, col| match values.get(row, col) {
+            Some(Value::Primitive(Primitive::Number(number))) => Ok(number.cast::<U>().value()),
+            _ => Err(MatrixError::NotNumeric(CellId::new(self.start.row() + row as u32, self.start.col() + col as u32))),
+        })
+    }
+
+    /// Evaluate the range into a matrix of `f64`, see `as_matrix`.
+    pub fn as_f64_matrix(&self) -> Result<Array2D<f64>, MatrixError> {
+        self.as_matrix()
+    }
+
+    /// Get a cell by its position relative to the top left corner.
+    pub fn cell(&self, row: u32, col: u32) -> Option<&'a Cell<T>> {
+        if row >= self.rows() || col >= self.cols() {
+            return None;
+        }
+        self.sheet.cell(CellId::new(self.start.row() + row, self.start.col() + col))
+    }
+}
+
+impl<T: Arithmetic> Worksheet<T> {
+    /// Get the range of cells between two corners.
+    pub fn range(&self, first: CellId, second: CellId) -> Range<'_, T> {
+        Range::new(self, first, second)
+    }
+
+    /// Write a matrix of numbers into the sheet with its first number at
+    /// `anchor`, such as the result of a simulation step, keeping the number
+    /// formats of the cells. Formula cells are left in place unless
+    /// `overwrite_formulas`. Formulas evaluate on demand, so those depending
+    /// on the cells see the new numbers the next time they are evaluated.
+    pub fn write_matrix(&mut self, anchor: CellId, matrix: &Array2D<T>, overwrite_formulas: bool) -> Result<MatrixWrite, MatrixError> {
+        let fits = |start: u32, len: usize| len == 0 || u32::try_from(len - 1).ok().and_then(|len| start.checked_add(len)).is_some();
+        if !fits(anchor.row(), matrix.rows()) || !fits(anchor.col(), matrix.cols()) {
+            return Err(MatrixError::OutOfBounds{anchor});
+        }
+        let mut report = MatrixWrite::default();
+        for (row, values) in matrix.iter_rows().enumerate() {
+            for (col, number) in values.iter().enumerate() {
+                let cell_id = CellId::new(anchor.row() + row as u32, anchor.col() + col as u32);
+                if !overwrite_formulas && self.cell(cell_id).is_some_and(|cell| cell.formula().is_some()) {
+                    report.skipped.push(cell_id);
+                    continue;
+                }
+                self.set_value(cell_id, Value::Primitive(Primitive::Number(Numeric::new(*number, None))));
+                report.written += 1;
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::Kernel;
+
+    #[test]
+    fn ranges_normalize_their_corners() {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::parse("B2").unwrap(), "x".to_string());
+        let range = sheet.range(CellId::parse("C3").unwrap(), CellId::parse("A2").unwrap());
+        assert_eq!((range.start(), range.end()), (CellId::parse("A2").unwrap(), CellId::parse("C3").unwrap()));
+        assert_eq!((range.rows(), range.cols()), (2, 3));
+        assert!(range.contains(CellId::parse("B3").unwrap()));
+        assert!(!range.contains(CellId::parse("D2").unwrap()));
+        assert_eq!(range.cell_ids().count(), 6);
+        assert_eq!(range.cell(0, 1).map(|cell| cell.raw()), Some("x"));
+        assert!(range.cell(2, 0).is_none());
+    }
+
+    #[test]
+    fn ranges_read_as_numeric_matrices() {
+        let mut sheet: Worksheet = Worksheet::new();
+        for (cell_id, data) in [("B2", "1"), ("C2", "0.1"), ("B3", "=B2*3"), ("C3", "50%")] {
+            sheet.set_cell(CellId::parse(cell_id).unwrap(), data.to_string());
+        }
+        let matrix = sheet.range(CellId::parse("B2").unwrap(), CellId::parse("C3").unwrap()).as_f64_matrix().unwrap();
+        assert_eq!((matrix.rows(), matrix.cols()), (2, 2));
+        assert_eq!(matrix.values(), [1.0, 0.1, 3.0, 0.5]);
+        let single = sheet.range(CellId::parse("C2").unwrap(), CellId::parse("C2").unwrap()).as_matrix::<f32>().unwrap();
+        assert_eq!(single.values(), [0.1f32]);
+        let gap = sheet.range(CellId::parse("B2").unwrap(), CellId::parse("D3").unwrap()).as_f64_matrix();
+        assert_eq!(gap, Err(MatrixError::NotNumeric(CellId::parse("D2").unwrap())));
+    }
+
+    #[test]
+    fn matrices_write_numbers_around_formulas() {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 1), "=A1*2".to_string());
+        sheet.set_cell(CellId::new(2, 0), "=SUM(A1:B2)".to_string());
+        sheet.set_number_format(CellId::new(1, 0), "0.00");
+        let matrix = Array2D::from_vec(2, 2, vec![1.0, 9.0, 2.5, 4.0]).unwrap();
+        let report = sheet.write_matrix(CellId::new(0, 0), &matrix, false).unwrap();
+        assert_eq!(report, MatrixWrite{written: 3, skipped: vec![CellId::new(0, 1)]});
+        assert_eq!(sheet.number_format(CellId::new(1, 0)), "0.00");
+        assert!(matches!(sheet.evaluate_cell(CellId::new(2, 0)).unwrap(), Value::Primitive(Primitive::Number(number)) if number.value() == 9.5));
+        assert_eq!(sheet.write_matrix(CellId::new(0, 0), &matrix, true).unwrap().written, 4);
+        assert!(sheet.cell(CellId::new(0, 1)).unwrap().formula().is_none());
+        assert_eq!(sheet.write_matrix(CellId::new(u32::MAX, 0), &matrix, true), Err(MatrixError::OutOfBounds{anchor: CellId::new(u32::MAX, 0)}));
+    }
+}
@@ -0,0 +1,170 @@
+use super::arithmetic::Arithmetic;
+use super::array::Array2D;
+use super::kernel::{CellId, Formula, Value};
+use std::collections::HashMap;
+
+/// Event is one step of the evaluation of a formula. Depth 0 is the formula
+/// of the cell itself, and every argument or operand is one deeper than the
+/// formula it belongs to.
+#[derive(Debug, Clone)]
+pub enum Event<T: Arithmetic=f64> {
+    /// A formula or part of one evaluated to a value.
+    Value{depth: usize, formula: Formula<T>, value: Value<T>},
+
+    /// `IF` took its first branch, or its second if not `taken`.
+    Branch{depth: usize, taken: bool},
+
+    /// A range was read, optionally from another sheet.
+    Range{depth: usize, sheet: Option<String>, start: CellId, end: CellId, values: Array2D<Value<T>>},
+}
+
+impl<T: Arithmetic> Event<T> {
+    pub fn depth(&self) -> usize {
+        match self {
+            Self::Value{depth, ..} | Self::Branch{depth, ..} | Self::Range{depth, ..} => *depth,
+        }
+    }
+}
+
+/// Recording holds the events of every formula evaluated while recording,
+/// such as during one recalculation. Events of a formula are in evaluation
+/// order, so the operands of a formula come before its value like in the
+/// formula evaluation dialog of spreadsheets.
+#[derive(Debug, Clone)]
+pub struct Recording<T: Arithmetic=f64> {
+    events: HashMap<CellId, Vec<Event<T>>>,
+    order: Vec<CellId>,
+}
+
+impl<T: Arithmetic> Default for Recording<T> {
+    fn default() -> Self {
+        Self{events: HashMap::new(), order: Vec::new()}
+    }
+}
+
+impl<T: Arithmetic> Recording<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn insert(&mut self, cell_id: CellId, events: Vec<Event<T>>) {
+        if self.events.insert(cell_id, events).is_none() {
+            self.order.push(cell_id);
+        }
+    }
+
+    /// Get the cells evaluated, in the order their evaluation finished.
+    pub fn cells(&self) -> &[CellId] {
+        &self.order
+    }
+
+    pub fn events(&self, cell_id: CellId) -> &[Event<T>] {
+        self.events.get(&cell_id).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Step through the evaluation of a cell.
+    pub fn inspect(&self, cell_id: CellId) -> Inspector<'_, T> {
+        Inspector{events: self.events(cell_id), position: 0}
+    }
+}
+
+/// Inspector steps forward and back through the events of one formula.
+pub struct Inspector<'a, T: Arithmetic=f64> {
+    events: &'a [Event<T>],
+    position: usize,
+}
+
+impl<'a, T: Arithmetic> Inspector<'a, T> {
+    /// Get the event of the latest step, or None before the first.
+    pub fn current(&self) -> Option<&'a Event<T>> {
+        self.position.checked_sub(1).and_then(|index| self.events.get(index))
+    }
+
+    /// Take a step forward and get its event, or None at the end.
+    pub fn step(&mut self) -> Option<&'a Event<T>> {
+        let event = self.events.get(self.position)?;
+        self.position += 1;
+        Some(event)
+    }
+
+    /// Take a step back and get the event now current.
+    pub fn step_back(&mut self) -> Option<&'a Event<T>> {
+        self.position = self.position.saturating_sub(1);
+        self.current()
+    }
+
+    /// Step forward past the rest of the current formula, to the event
+    /// which is its value, and get that event.
+    pub fn step_out(&mut self) -> Option<&'a Event<T>> {
+        let depth = self.current()?.depth().checked_sub(1)?;
+        while let Some(event) = self.step() {
+            if event.depth() == depth {
+                return Some(event);
+            }
+        }
+        None
+    }
+
+    pub fn reset(&mut self) {
+        self.position = 0;
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.position == self.events.len()
+    }
+
+    /// Get the events of the operands of the current event: the steps one
+    /// deeper since the previous step at its depth, such as the argument
+    /// values of a function.
+    pub fn operands(&self) -> Vec<&'a Event<T>> {
+        let Some(current) = self.current() else { return Vec::new() };
+        let before = &self.events[..self.position - 1];
+        let start = before.iter()
+            .rposition(|event| event.depth() <= current.depth())
+            .map_or(0, |index| index + 1);
+        before[start..].iter().filter(|event| event.depth() == current.depth() + 1).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::{Kernel, Primitive};
+    use crate::kernel::worksheet::Worksheet;
+
+    fn number(event: Option<&Event>) -> Option<f64> {
+        match event {
+            Some(Event::Value{value: Value::Primitive(Primitive::Number(number)), ..}) => Some(number.value()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn inspector_steps_through_a_recorded_formula() {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), "2".to_string());
+        sheet.set_cell(CellId::new(0, 1), "=A1*3".to_string());
+        sheet.set_cell(CellId::new(0, 2), "=IF(B1>1,SUM(A1:B1),0)".to_string());
+        let (result, recording) = sheet.record(|| sheet.evaluate_cell(CellId::new(0, 2)));
+        assert!(result.is_ok());
+        assert_eq!(recording.cells(), [CellId::new(0, 1), CellId::new(0, 2)]);
+
+        let mut inspector = recording.inspect(CellId::new(0, 2));
+        assert!(inspector.current().is_none());
+        let first = inspector.step().unwrap();
+        assert_eq!(first.depth(), 2);
+        assert_eq!(inspector.step_out().map(Event::depth), Some(1));
+        while inspector.step().is_some() {}
+        assert!(inspector.is_done());
+        assert_eq!(inspector.current().map(Event::depth), Some(0));
+        assert_eq!(number(inspector.current()), Some(8.0));
+        let operands = inspector.operands();
+        assert!(operands.iter().any(|event| matches!(event, Event::Branch{taken: true, ..})));
+        assert!(operands.iter().any(|event| number(Some(event)) == Some(8.0)));
+        assert!(recording.events(CellId::new(0, 2)).iter().any(|event| matches!(event, Event::Range{sheet: None, values, ..} if values.values().len() == 2)));
+        inspector.step_back();
+        assert!(!inspector.is_done());
+        inspector.reset();
+        assert!(inspector.current().is_none());
+    }
+}
@@ -0,0 +1,254 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{CellId, Formula, Kernel, Value};
+use super::workbook::{Workbook, WorkbookError};
+use super::worksheet::Worksheet;
+
+/// Call `f` on every reference node of a formula: cell references, ranges,
+/// names and whole sheet-qualified references, so `f` can change the sheet
+/// as well as the reference. `f` tells whether it changed the node.
+pub(crate) fn visit_references<T: Arithmetic>(formula: &mut Formula<T>, f: &mut dyn FnMut(&mut Formula<T>) -> bool) -> bool {
+    match formula {
+        Formula::CellRef(_) | Formula::CellRange(..) | Formula::Name(_) | Formula::SheetRef(..) => f(formula),
+        _ => {
+            let mut changed = false;
+            for operand in formula.operands_mut() {
+                if let Value::Formula(operand) = operand {
+                    changed |= visit_references(operand, f);
+                }
+            }
+            changed
+        },
+    }
+}
+
+/// Rewrite the formulas of every cell and defined name of a workbook, and
+/// count those which changed. `f` is handed the sheet a formula lives on,
+/// or None for defined names.
+fn rewrite_formulas<T: Arithmetic>(workbook: &mut Workbook<T>, f: &mut dyn FnMut(Option<&str>, &mut Formula<T>) -> bool) -> usize {
+    let mut count = 0;
+    for (name, sheet) in workbook.sheets_mut() {
+        count += rewrite_sheet(sheet, &mut |reference| f(Some(name), reference));
+    }
+    for (_, formula) in workbook.names_mut() {
+        if visit_references(formula, &mut |reference| f(None, reference)) {
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Rewrite the formulas of one sheet, and count those which changed.
+pub(crate) fn rewrite_sheet<T: Arithmetic>(sheet: &mut Worksheet<T>, f: &mut dyn FnMut(&mut Formula<T>) -> bool) -> usize {
+    let rewritten = sheet.cells()
+        .filter_map(|(cell_id, cell)| {
+            let mut formula = cell.formula()?.clone();
+            visit_references(&mut formula, f).then_some((cell_id, formula))
+        })
+        .collect::<Vec<_>>();
+    for (cell_id, formula) in &rewritten {
+        sheet.set_cell(*cell_id, format!("={}", formula));
+    }
+    rewritten.len()
+}
+
+/// Get the names a formula uses, with or without a sheet.
+pub(crate) fn names_used<T: Arithmetic>(formula: &Formula<T>) -> Vec<String> {
+    let mut names = Vec::new();
+    visit_references(&mut formula.clone(), &mut |reference| {
+        let target = match reference {
+            Formula::SheetRef(_, target) => &**target,
+            target => target,
+        };
+        if let Formula::Name(name) = target {
+            names.push(name.clone());
+        }
+        false
+    });
+    names
+}
+
+/// Get the sheet and normalized corners of a cell reference or range.
+pub(crate) fn as_range<T: Arithmetic>(formula: &Formula<T>) -> Option<(Option<&str>, CellId, CellId)> {
+    let (sheet, target) = match formula {
+        Formula::SheetRef(sheet, target) => (Some(sheet.as_str()), &**target),
+        target => (None, target),
+    };
+    let (first, second) = match target {
+        Formula::CellRef(cell_id) => (*cell_id, *cell_id),
+        Formula::CellRange(first, second) => (*first, *second),
+        _ => return None,
+    };
+    let start = CellId::new(first.row().min(second.row()), first.col().min(second.col()));
+    let end = CellId::new(first.row().max(second.row()), first.col().max(second.col()));
+    Some((sheet, start, end))
+}
+
+pub(crate) fn same_sheet(a: Option<&str>, b: Option<&str>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+        (a, b) => a.is_none() && b.is_none(),
+    }
+}
+
+impl<T: Arithmetic> Workbook<T> {
+    /// Rename a sheet and rewrite every formula and defined name referring
+    /// to it, as well as the external data regions landing on it. Returns
+    /// the number of formulas rewritten.
+    pub fn rename_sheet(&mut self, old: &str, new: &str) -> Result<usize, WorkbookError> {
+        let index = self.sheet_index(old).ok_or_else(|| WorkbookError::UnknownSheet(old.to_string()))?;
+        if self.sheet_index(new).is_some_and(|existing| existing != index) {
+            return Err(WorkbookError::DuplicateSheet(new.to_string()));
+        }
+        self.rename_entry(index, new);
+        for query in self.queries_mut().iter_mut().filter(|query| query.sheet.eq_ignore_ascii_case(old)) {
+            query.sheet = new.to_string();
+        }
+        Ok(rewrite_formulas(self, &mut |_, reference| match reference {
+            Formula::SheetRef(sheet, _) if sheet.eq_ignore_ascii_case(old) => {
+                *sheet = new.to_string();
+                true
+            },
+            _ => false,
+        }))
+    }
+
+    /// Rename a defined name and rewrite every formula and defined name
+    /// using it. Returns the number of formulas rewritten.
+    pub fn rename_defined_name(&mut self, old: &str, new: &str) -> Result<usize, WorkbookError> {
+        let formula = self.name(old).cloned().ok_or_else(|| WorkbookError::UnknownName(old.to_string()))?;
+        let renamed = !old.eq_ignore_ascii_case(new);
+        if renamed && self.name(new).is_some() {
+            return Err(WorkbookError::DuplicateName(new.to_string()));
+        }
+        self.define_name(new, formula)?;
+        if renamed {
+            self.remove_name(old)?;
+        }
+        Ok(rewrite_formulas(self, &mut |_, reference| {
+            let target = match reference {
+                Formula::SheetRef(_, target) => &mut **target,
+                target => target,
+            };
+            match target {
+                Formula::Name(name) if name.eq_ignore_ascii_case(old) => {
+                    *name = new.to_string();
+                    true
+                },
+                _ => false,
+            }
+        }))
+    }
+
+    /// Replace every reference to one cell or range with a reference to
+    /// another, such as `Inputs!B2:B13` with `Inputs!C2:C13` after moving a
+    /// model's inputs. A reference without a sheet is to the sheet of the
+    /// formula using it, so `A1:A3` is replaced on every sheet; a new
+    /// reference without a sheet stays on the sheet of the old one. Returns
+    /// the number of formulas rewritten.
+    pub fn replace_range_reference(&mut self, old: &str, new: &str) -> Result<usize, WorkbookError> {
+        let parse = |text: &str| Formula::<T>::try_from(text.trim_start_matches('='))
+            .ok()
+            .and_then(|formula| as_range(&formula).map(|(sheet, start, end)| (sheet.map(str::to_string), start, end)))
+            .ok_or_else(|| WorkbookError::InvalidReference(text.to_string()));
+        let (old_sheet, old_start, old_end) = parse(old)?;
+        let (new_sheet, new_start, new_end) = parse(new)?;
+        for sheet in old_sheet.iter().chain(&new_sheet) {
+            if self.sheet_index(sheet).is_none() {
+                return Err(WorkbookError::UnknownSheet(sheet.clone()));
+            }
+        }
+        let replacement = match new_start == new_end {
+            true => Formula::CellRef(new_start),
+            false => Formula::CellRange(new_start, new_end),
+        };
+        Ok(rewrite_formulas(self, &mut |own, reference| {
+            let Some((sheet, start, end)) = as_range(reference) else { return false };
+            let qualified = sheet.is_some();
+            // Resolve both references against the sheet of the formula.
+            let target = sheet.or(own).map(str::to_string);
+            let matches = match &old_sheet {
+                Some(old_sheet) => same_sheet(target.as_deref(), Some(old_sheet)),
+                None => !qualified,
+            };
+            if !matches || (start, end) != (old_start, old_end) {
+                return false;
+            }
+            let moved_to = new_sheet.clone().or(old_sheet.clone()).or(target);
+            *reference = match moved_to {
+                Some(_) if !qualified && same_sheet(moved_to.as_deref(), own) => replacement.clone(),
+                Some(moved_to) => Formula::SheetRef(moved_to, Box::new(replacement.clone())),
+                None => replacement.clone(),
+            };
+            true
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::{CellError, Primitive, Value};
+
+    fn raw(workbook: &Workbook, sheet: &str, row: u32) -> String {
+        workbook.sheet(sheet).unwrap().cell(CellId::new(row, 0)).unwrap().raw().to_string()
+    }
+
+    fn workbook() -> Workbook {
+        let mut workbook: Workbook = Workbook::new();
+        workbook.add_sheet("Inputs").unwrap();
+        workbook.add_sheet("Model").unwrap();
+        workbook.set_cell("Inputs", CellId::new(0, 0), "0.2".to_string()).unwrap();
+        workbook.set_cell("Inputs", CellId::new(1, 0), "100".to_string()).unwrap();
+        workbook.set_cell("Model", CellId::new(0, 0), "=Inputs!A2*(1+Rate)".to_string()).unwrap();
+        workbook.set_cell("Model", CellId::new(1, 0), "=SUM(inputs!A1:A2)+A1".to_string()).unwrap();
+        workbook.set_cell("Model", CellId::new(2, 0), "=A1:A2".to_string()).unwrap();
+        workbook.define_name("Rate", Formula::try_from("Inputs!A1").unwrap()).unwrap();
+        workbook
+    }
+
+    #[test]
+    fn names_are_defined_for_the_workbook() {
+        let mut workbook = workbook();
+        assert!(matches!(workbook.evaluate_cell("Model", CellId::new(0, 0)).unwrap(), Value::Primitive(Primitive::Number(number)) if number.value() == 120.0));
+        for invalid in ["A1", "XFD100", "1Rate", "Two words", "SUM(A1)"] {
+            assert!(matches!(workbook.define_name(invalid, Formula::try_from("1").unwrap()), Err(WorkbookError::InvalidName(_))), "{}", invalid);
+        }
+        assert_eq!(workbook.names().map(|(name, _)| name).collect::<Vec<_>>(), ["Rate"]);
+        workbook.remove_name("RATE").unwrap();
+        assert!(matches!(workbook.remove_name("Rate"), Err(WorkbookError::UnknownName(_))));
+        assert!(matches!(workbook.evaluate_cell("Model", CellId::new(0, 0)).unwrap(), Value::Error(CellError::Name)));
+    }
+
+    #[test]
+    fn renaming_a_sheet_rewrites_references() {
+        let mut workbook = workbook();
+        assert_eq!(workbook.rename_sheet("inputs", "Assumptions").unwrap(), 3);
+        assert_eq!(raw(&workbook, "Model", 0), "=Assumptions!A2*(1+Rate)");
+        assert_eq!(raw(&workbook, "Model", 1), "=SUM(Assumptions!A1:A2)+A1");
+        assert_eq!(workbook.name("Rate").unwrap().to_string(), "Assumptions!A1");
+        assert!(matches!(workbook.rename_sheet("Model", "assumptions"), Err(WorkbookError::DuplicateSheet(_))));
+        assert!(matches!(workbook.rename_sheet("Inputs", "Other"), Err(WorkbookError::UnknownSheet(_))));
+    }
+
+    #[test]
+    fn renaming_a_name_rewrites_its_uses() {
+        let mut workbook = workbook();
+        workbook.define_name("Growth", Formula::try_from("Rate*2").unwrap()).unwrap();
+        assert!(matches!(workbook.rename_defined_name("Rate", "Growth"), Err(WorkbookError::DuplicateName(_))));
+        assert_eq!(workbook.rename_defined_name("Rate", "TaxRate").unwrap(), 2);
+        assert_eq!(raw(&workbook, "Model", 0), "=Inputs!A2*(1+TaxRate)");
+        assert_eq!(workbook.name("Growth").unwrap().to_string(), "TaxRate*2");
+        assert!(workbook.name("Rate").is_none());
+    }
+
+    #[test]
+    fn replacing_a_range_follows_the_sheet_of_each_formula() {
+        let mut workbook = workbook();
+        assert_eq!(workbook.replace_range_reference("Inputs!A1:A2", "Inputs!B1:B2").unwrap(), 1);
+        assert_eq!(raw(&workbook, "Model", 1), "=SUM(Inputs!B1:B2)+A1");
+        assert_eq!(workbook.replace_range_reference("A1:A2", "C1:C2").unwrap(), 1);
+        assert_eq!(raw(&workbook, "Model", 2), "=C1:C2");
+        assert!(matches!(workbook.replace_range_reference("SUM(A1)", "A1"), Err(WorkbookError::InvalidReference(_))));
+        assert!(matches!(workbook.replace_range_reference("Other!A1", "A2"), Err(WorkbookError::UnknownSheet(_))));
+    }
+}
@@ -0,0 +1,217 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::CellId;
+use super::workbook::Workbook;
+#[cfg(feature = "connectors")]
+use super::connectors::{reland, ConnectorError, Driver, LandedRange};
+#[cfg(feature = "connectors")]
+use super::workbook::WorkbookError;
+#[cfg(feature = "connectors")]
+use thiserror::Error;
+
+/// QueryDefinition describes an external data region of a workbook, like a
+/// query table: where the data comes from and where it is landed. It is
+/// stored with the workbook so every region can be refreshed later.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryDefinition {
+    /// Unique name of the definition.
+    pub name: String,
+    /// Connection string handed to the driver factory on refresh.
+    pub connection: String,
+    pub sql: String,
+    pub sheet: String,
+    pub anchor: CellId,
+    pub header: bool,
+    /// How often the region should be refreshed, if it is scheduled.
+    pub interval: Option<chrono::TimeDelta>,
+    /// When the region was last refreshed.
+    pub last_refreshed: Option<chrono::NaiveDateTime>,
+    /// The corners of the range written by the last refresh.
+    pub landed: Option<(CellId, CellId)>,
+}
+
+impl QueryDefinition {
+    pub fn new(name: &str, connection: &str, sql: &str, sheet: &str, anchor: CellId) -> Self {
+        Self{
+            name: name.to_string(),
+            connection: connection.to_string(),
+            sql: sql.to_string(),
+            sheet: sheet.to_string(),
+            anchor,
+            header: true,
+            interval: None,
+            last_refreshed: None,
+            landed: None,
+        }
+    }
+
+    pub fn every(mut self, interval: chrono::TimeDelta) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// Whether a scheduled refresh is due at `now`. Definitions which have
+    /// never been refreshed are always due.
+    pub fn is_due(&self, now: chrono::NaiveDateTime) -> bool {
+        match (self.last_refreshed, self.interval) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(last), Some(interval)) => now - last >= interval,
+        }
+    }
+}
+
+impl<T: Arithmetic> Workbook<T> {
+    /// Add a query definition, replacing any definition with the same name.
+    pub fn add_query(&mut self, query: QueryDefinition) {
+        self.queries_mut().retain(|existing| existing.name != query.name);
+        self.queries_mut().push(query);
+    }
+
+    pub fn remove_query(&mut self, name: &str) -> Option<QueryDefinition> {
+        let index = self.queries().iter().position(|query| query.name == name)?;
+        Some(self.queries_mut().remove(index))
+    }
+
+    pub fn query(&self, name: &str) -> Option<&QueryDefinition> {
+        self.queries().iter().find(|query| query.name == name)
+    }
+}
+
+#[cfg(feature = "connectors")]
+#[derive(Error, Debug)]
+pub enum RefreshError {
+    #[error("query {name}: {source}")]
+    Connector{name: String, source: ConnectorError},
+
+    #[error("query {name}: {source}")]
+    Workbook{name: String, source: WorkbookError},
+}
+
+/// Connect creates a driver for a connection string.
+#[cfg(feature = "connectors")]
+pub type Connect<'a> = dyn FnMut(&str) -> Result<Box<dyn Driver>, ConnectorError> + 'a;
+
+#[cfg(feature = "connectors")]
+impl<T: Arithmetic> Workbook<T> {
+    /// Re-import every external data region. `connect` creates a driver for
+    /// the connection string of each definition. Formulas depending on the
+    /// regions see the new data the next time they are evaluated.
+    pub fn refresh_all(
+        &mut self,
+        now: chrono::NaiveDateTime,
+        connect: &mut Connect<'_>,
+    ) -> Vec<Result<String, RefreshError>> {
+        self.refresh_where(now, connect, |_| true)
+    }
+
+    /// Re-import the external data regions whose schedule is due at `now`.
+    pub fn refresh_due(
+        &mut self,
+        now: chrono::NaiveDateTime,
+        connect: &mut Connect<'_>,
+    ) -> Vec<Result<String, RefreshError>> {
+        self.refresh_where(now, connect, |query| query.is_due(now))
+    }
+
+    fn refresh_where(
+        &mut self,
+        now: chrono::NaiveDateTime,
+        connect: &mut Connect<'_>,
+        filter: impl Fn(&QueryDefinition) -> bool,
+    ) -> Vec<Result<String, RefreshError>> {
+        let mut results = Vec::new();
+        for index in 0..self.queries().len() {
+            let query = self.queries()[index].clone();
+            if !filter(&query) {
+                continue;
+            }
+            let name = query.name.clone();
+            results.push(self.refresh_one(&query, connect).map(|landed| {
+                let stored = &mut self.queries_mut()[index];
+                stored.last_refreshed = Some(now);
+                stored.landed = Some((landed.start, landed.end));
+                name
+            }));
+        }
+        results
+    }
+
+    fn refresh_one(
+        &mut self,
+        query: &QueryDefinition,
+        connect: &mut Connect<'_>,
+    ) -> Result<LandedRange, RefreshError> {
+        let connector = |source| RefreshError::Connector{name: query.name.clone(), source};
+        let mut driver = connect(&query.connection).map_err(connector)?;
+        let result = driver.query(&query.sql).map_err(connector)?;
+        let sheet = self.sheet_mut(&query.sheet).ok_or_else(|| RefreshError::Workbook{
+            name: query.name.clone(),
+            source: WorkbookError::UnknownSheet(query.sheet.clone()),
+        })?;
+        let previous = query.landed.map(|(start, end)| LandedRange{start, end, rows: 0});
+        reland(sheet, previous, query.anchor, &result, query.header).map_err(connector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "connectors")]
+    use crate::kernel::connectors::{ResultSet, SqlValue};
+
+    fn at(hour: u32) -> chrono::NaiveDateTime {
+        chrono::NaiveDate::from_ymd_opt(2024, 1, 1).unwrap().and_hms_opt(hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn definitions_are_kept_by_name() {
+        let mut workbook = Workbook::<f64>::new();
+        workbook.add_query(QueryDefinition::new("sales", "db", "SELECT 1", "Data", CellId::new(0, 0)));
+        workbook.add_query(QueryDefinition::new("sales", "db", "SELECT 2", "Data", CellId::new(0, 0)));
+        assert_eq!(workbook.queries().len(), 1);
+        assert_eq!(workbook.query("sales").unwrap().sql, "SELECT 2");
+        assert!(workbook.remove_query("sales").is_some());
+        assert!(workbook.query("sales").is_none());
+    }
+
+    #[test]
+    fn schedules_fall_due_after_their_interval() {
+        let mut query = QueryDefinition::new("sales", "db", "SELECT 1", "Data", CellId::new(0, 0));
+        assert!(query.is_due(at(0)));
+        query.last_refreshed = Some(at(1));
+        assert!(!query.is_due(at(5)));
+        let query = query.every(chrono::TimeDelta::hours(2));
+        assert!(!query.is_due(at(2)));
+        assert!(query.is_due(at(3)));
+    }
+
+    #[cfg(feature = "connectors")]
+    struct Rows(i64);
+
+    #[cfg(feature = "connectors")]
+    impl Driver for Rows {
+        fn query(&mut self, _sql: &str) -> Result<ResultSet, ConnectorError> {
+            Ok(ResultSet{columns: vec!["n".to_string()], rows: (0..self.0).map(|n| vec![SqlValue::Integer(n)]).collect()})
+        }
+    }
+
+    #[cfg(feature = "connectors")]
+    #[test]
+    fn refreshing_lands_due_queries_and_records_when() {
+        let mut workbook = Workbook::<f64>::new();
+        workbook.add_sheet("Data").unwrap();
+        workbook.add_query(QueryDefinition::new("three", "3", "SELECT n", "Data", CellId::new(0, 0)).every(chrono::TimeDelta::hours(1)));
+        workbook.add_query(QueryDefinition::new("missing", "1", "SELECT n", "Nowhere", CellId::new(0, 0)));
+        let mut connect = |connection: &str| Ok(Box::new(Rows(connection.parse().unwrap())) as Box<dyn Driver>);
+        let results = workbook.refresh_all(at(0), &mut connect);
+        assert_eq!(results[0].as_ref().unwrap(), "three");
+        assert!(matches!(&results[1], Err(RefreshError::Workbook{name, ..}) if name == "missing"));
+        assert_eq!(workbook.query("three").unwrap().landed, Some((CellId::new(0, 0), CellId::new(3, 0))));
+        assert_eq!(workbook.query("three").unwrap().last_refreshed, Some(at(0)));
+        assert!(workbook.query("missing").unwrap().last_refreshed.is_none());
+        let results = workbook.refresh_due(at(0), &mut connect);
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_err());
+    }
+}
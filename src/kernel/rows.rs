@@ -0,0 +1,385 @@
+use super::arithmetic::Arithmetic;
+use super::eval::Comparable;
+use super::kernel::{CellError, CellId, Formula, Kernel, Value};
+use super::refactor::{rewrite_sheet, visit_references};
+use super::worksheet::{SheetError, Worksheet};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+/// RowId identifies a row of a sheet for as long as the row exists,
+/// wherever sorts, insertions and deletions move it, so records keep their
+/// identity when they are reordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RowId(pub u64);
+
+impl fmt::Display for RowId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// RowIds holds the IDs of the rows of a sheet. IDs are handed out in the
+/// order rows are first used and are never reused, even after their row is
+/// deleted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RowIds {
+    next: u64,
+    ids: HashMap<u32, RowId>,
+    rows: HashMap<RowId, u32>,
+}
+
+impl RowIds {
+    pub fn get(&self, row: u32) -> Option<RowId> {
+        self.ids.get(&row).copied()
+    }
+
+    /// Get the row an ID is at now, or None if it was deleted.
+    pub fn find(&self, id: RowId) -> Option<u32> {
+        self.rows.get(&id).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Iterate the rows with their IDs, top to bottom.
+    pub fn iter(&self) -> impl Iterator<Item=(u32, RowId)> {
+        let mut rows = self.ids.iter().map(|(row, id)| (*row, *id)).collect::<Vec<_>>();
+        rows.sort();
+        rows.into_iter()
+    }
+
+    /// Give a row an ID if it has none, and get its ID.
+    pub(crate) fn assign(&mut self, row: u32) -> RowId {
+        if let Some(id) = self.get(row) {
+            return id;
+        }
+        let id = RowId(self.next);
+        self.next += 1;
+        self.ids.insert(row, id);
+        self.rows.insert(id, row);
+        id
+    }
+
+    /// Move every ID to the row `to` maps its row to, dropping those it
+    /// maps to None.
+    fn remap(&mut self, to: impl Fn(u32) -> Option<u32>) {
+        let ids = std::mem::take(&mut self.ids);
+        self.rows.clear();
+        for (row, id) in ids {
+            if let Some(row) = to(row) {
+                self.ids.insert(row, id);
+                self.rows.insert(id, row);
+            }
+        }
+    }
+}
+
+impl<T: Arithmetic> Worksheet<T> {
+    /// Turn row IDs on or off. Turning them on gives every row in use an ID,
+    /// top to bottom, and every row used later one on creation; turning
+    /// them off forgets them.
+    pub fn set_row_ids(&mut self, enabled: bool) {
+        match enabled {
+            true if self.row_ids().is_none() => {
+                let mut ids = RowIds::default();
+                for cell_id in self.cell_ids() {
+                    ids.assign(cell_id.row());
+                }
+                *self.row_ids_mut() = Some(ids);
+            },
+            true => {},
+            false => *self.row_ids_mut() = None,
+        }
+    }
+
+    pub(crate) fn assign_row_id(&mut self, row: u32) {
+        if let Some(ids) = self.row_ids_mut() {
+            ids.assign(row);
+        }
+    }
+
+    /// Get the ID of a row, or None if row IDs are off or it was never
+    /// used.
+    pub fn row_id(&self, row: u32) -> Option<RowId> {
+        self.row_ids()?.get(row)
+    }
+
+    /// Find the row an ID is at now, after any sorts, insertions and
+    /// deletions, or None if the row was deleted.
+    pub fn find_row(&self, id: RowId) -> Option<u32> {
+        self.row_ids()?.find(id)
+    }
+
+    /// Move the cells of every row, with their number formats, styles,
+    /// frozen formulas and IDs, to the row `to` maps it to, dropping those
+    /// it maps to None. References are left as they are.
+    fn move_rows(&mut self, to: impl Fn(u32) -> Option<u32>) {
+        // Moved cells are set as they are entered, which must not give the
+        // rows they land in new IDs.
+        let ids = self.row_ids_mut().take();
+        let cells = self.cell_ids().into_iter()
+            .filter(|cell_id| to(cell_id.row()) != Some(cell_id.row()))
+            .map(|cell_id| {
+                let format = self.number_format(cell_id).to_string();
+                self.set_number_format(cell_id, "General");
+                let style = self.style(cell_id);
+                self.set_style(cell_id, None);
+                let frozen = self.frozen_formula(cell_id).cloned();
+                (cell_id, self.clear_cell(cell_id), format, style, frozen)
+            })
+            .collect::<Vec<_>>();
+        let moved = |cell_id: CellId| to(cell_id.row()).map(|row| CellId::new(row, cell_id.col()));
+        for (cell_id, cell, format, style, frozen) in cells {
+            let Some(target) = moved(cell_id) else { continue };
+            if let Some(cell) = cell {
+                self.set_cell(target, cell.raw().to_string());
+            }
+            self.set_number_format(target, &format);
+            self.set_style(target, style);
+            if let Some(mut frozen) = frozen {
+                frozen.spilled = frozen.spilled.iter().filter_map(|spilled| moved(*spilled)).collect();
+                self.frozen_mut().insert(target, frozen);
+            }
+        }
+        *self.row_ids_mut() = ids.map(|mut ids| {
+            ids.remap(&to);
+            ids
+        });
+    }
+
+    /// Insert empty rows at a row, moving it and the rows below down with
+    /// their IDs. References on this sheet follow the cells they refer to,
+    /// and ranges spanning the insertion grow to include the new rows.
+    pub fn insert_rows(&mut self, at: u32, count: u32) {
+        if count == 0 {
+            return;
+        }
+        let shift = |row: u32| match row >= at {
+            true => row.saturating_add(count),
+            false => row,
+        };
+        self.move_rows(|row| Some(shift(row)));
+        let moved = |cell_id: CellId| CellId::new(shift(cell_id.row()), cell_id.col());
+        rewrite_sheet(self, &mut |reference| {
+            let replacement = match *reference {
+                Formula::CellRef(cell_id) if moved(cell_id) != cell_id => Formula::CellRef(moved(cell_id)),
+                Formula::CellRange(a, b) if (moved(a), moved(b)) != (a, b) => Formula::CellRange(moved(a), moved(b)),
+                _ => return false,
+            };
+            *reference = replacement;
+            true
+        });
+    }
+
+    /// Delete rows starting at a row with their IDs, moving the rows below
+    /// up. References on this sheet follow the cells they refer to, ranges
+    /// shrink to the rows left, and formulas referring to a deleted cell or
+    /// to a range of deleted rows only become `#REF!`.
+    pub fn delete_rows(&mut self, at: u32, count: u32) {
+        if count == 0 {
+            return;
+        }
+        let end = at.saturating_add(count);
+        let shift = |row: u32| match row {
+            row if row < at => Some(row),
+            row if row < end => None,
+            row => Some(row - count),
+        };
+        self.move_rows(shift);
+
+        let rewritten = self.cells()
+            .filter_map(|(cell_id, cell)| {
+                let mut formula = cell.formula()?.clone();
+                let mut dropped = false;
+                let changed = visit_references(&mut formula, &mut |reference| {
+                    let replacement = match *reference {
+                        Formula::CellRef(cell_id) => match shift(cell_id.row()) {
+                            None => {
+                                dropped = true;
+                                return false;
+                            },
+                            Some(row) if row == cell_id.row() => return false,
+                            Some(row) => Formula::CellRef(CellId::new(row, cell_id.col())),
+                        },
+                        Formula::CellRange(a, b) => {
+                            let (top, bottom) = (a.row().min(b.row()), a.row().max(b.row()));
+                            let (left, right) = (a.col().min(b.col()), a.col().max(b.col()));
+                            // The corners move to the first and last rows left between them.
+                            let (top, bottom) = match (top < at, bottom >= end) {
+                                (false, false) => {
+                                    dropped = true;
+                                    return false;
+                                },
+                                _ => (shift(top).unwrap_or(at), shift(bottom).unwrap_or(at.saturating_sub(1))),
+                            };
+                            if (top, bottom) == (a.row().min(b.row()), a.row().max(b.row())) {
+                                return false;
+                            }
+                            Formula::CellRange(CellId::new(top, left), CellId::new(bottom, right))
+                        },
+                        _ => return false,
+                    };
+                    *reference = replacement;
+                    true
+                });
+                match dropped {
+                    true => Some((cell_id, None)),
+                    false => changed.then_some((cell_id, Some(formula))),
+                }
+            })
+            .collect::<Vec<_>>();
+        for (cell_id, formula) in rewritten {
+            match formula {
+                Some(formula) => self.set_cell(cell_id, format!("={}", formula)),
+                None => self.set_value(cell_id, Value::Error(CellError::Ref)),
+            }
+        }
+    }
+
+    /// Sort the rows between two rows by the values of a column, moving
+    /// whole rows with their IDs. The sort is stable, and empty cells and
+    /// errors go last in either order, as in spreadsheets. References into
+    /// the rows are left as they are, except those of formulas to their own
+    /// row, which move with it.
+    pub fn sort_rows(&mut self, first_row: u32, last_row: u32, col: u32, ascending: bool) -> Result<(), SheetError> {
+        let (top, bottom) = (first_row.min(last_row), first_row.max(last_row));
+        let mut keys = Vec::new();
+        for row in top..=bottom {
+            let cell_id = CellId::new(row, col);
+            let key = match self.evaluate_cell(cell_id)? {
+                Value::Raw => Some(Comparable::Text(self.cell(cell_id).map(|cell| cell.text().to_string()).unwrap_or_default())),
+                Value::Empty => None,
+                value => Comparable::of(value).ok(),
+            };
+            keys.push((row, key));
+        }
+        let settings = self.settings().clone();
+        keys.sort_by(|(_, a), (_, b)| match (a, b) {
+            (Some(a), Some(b)) => {
+                let ordering = a.compare(b, &settings).unwrap_or(Ordering::Equal);
+                if ascending { ordering } else { ordering.reverse() }
+            },
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        });
+        let to = keys.iter().enumerate().map(|(index, (row, _))| (*row, top + index as u32)).collect::<HashMap<_, _>>();
+        let target = |row: u32| to.get(&row).copied().unwrap_or(row);
+
+        // References of formulas to their own row move with it.
+        let rewritten = self.cells()
+            .filter(|(cell_id, _)| target(cell_id.row()) != cell_id.row())
+            .filter_map(|(cell_id, cell)| {
+                let mut formula = cell.formula()?.clone();
+                let row = cell_id.row();
+                let moved = |reference: CellId| match reference.row() == row {
+                    true => CellId::new(target(row), reference.col()),
+                    false => reference,
+                };
+                let changed = visit_references(&mut formula, &mut |reference| {
+                    let replacement = match *reference {
+                        Formula::CellRef(cell_id) if moved(cell_id) != cell_id => Formula::CellRef(moved(cell_id)),
+                        Formula::CellRange(a, b) if (moved(a), moved(b)) != (a, b) => Formula::CellRange(moved(a), moved(b)),
+                        _ => return false,
+                    };
+                    *reference = replacement;
+                    true
+                });
+                changed.then_some((cell_id, formula))
+            })
+            .collect::<Vec<_>>();
+        for (cell_id, formula) in rewritten {
+            self.set_cell(cell_id, format!("={}", formula));
+        }
+        self.move_rows(|row| Some(target(row)));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(sheet: &Worksheet, cell_id: &str) -> String {
+        sheet.cell(CellId::parse(cell_id).unwrap()).map(|cell| cell.raw().to_string()).unwrap_or_default()
+    }
+
+    /// Records of a name and an amount in rows 2 to 4 under a header, with
+    /// a total below them.
+    fn sheet() -> Worksheet {
+        let mut sheet: Worksheet = Worksheet::new();
+        for (row, (name, amount)) in [("name", "amount"), ("pear", "3"), ("apple", "10"), ("fig", "")].iter().enumerate() {
+            sheet.set_cell(CellId::new(row as u32, 0), name.to_string());
+            sheet.set_cell(CellId::new(row as u32, 1), amount.to_string());
+            if row > 0 {
+                sheet.set_cell(CellId::new(row as u32, 2), format!("=B{}*2", row + 1));
+            }
+        }
+        sheet.set_cell(CellId::new(5, 1), "=SUM(B2:B4)".to_string());
+        sheet.set_number_format(CellId::new(1, 1), "0.00");
+        sheet
+    }
+
+    #[test]
+    fn row_ids_are_handed_out_in_order() {
+        let mut sheet = sheet();
+        assert_eq!(sheet.row_id(0), None);
+        sheet.set_row_ids(true);
+        assert_eq!(sheet.row_ids().unwrap().iter().collect::<Vec<_>>(), [(0, RowId(0)), (1, RowId(1)), (2, RowId(2)), (3, RowId(3)), (5, RowId(4))]);
+        sheet.set_cell(CellId::new(9, 0), "late".to_string());
+        assert_eq!(sheet.row_id(9), Some(RowId(5)));
+        assert_eq!(sheet.find_row(RowId(5)), Some(9));
+        sheet.set_row_ids(false);
+        assert!(sheet.row_ids().is_none());
+    }
+
+    #[test]
+    fn sorts_move_rows_with_their_ids() {
+        let mut sheet = sheet();
+        sheet.set_row_ids(true);
+        let pear = sheet.row_id(1).unwrap();
+        sheet.sort_rows(1, 3, 0, true).unwrap();
+        assert_eq!((raw(&sheet, "A2"), raw(&sheet, "A3"), raw(&sheet, "A4")), ("apple".to_string(), "fig".to_string(), "pear".to_string()));
+        assert_eq!(sheet.find_row(pear), Some(3));
+        // Formulas to their own row move with it, and the format with its cell.
+        assert_eq!(raw(&sheet, "C4"), "=B4*2");
+        assert_eq!(sheet.number_format(CellId::new(3, 1)), "0.00");
+        assert_eq!(raw(&sheet, "B6"), "=SUM(B2:B4)");
+
+        // Empty cells go last in either order.
+        sheet.sort_rows(1, 3, 1, false).unwrap();
+        assert_eq!((raw(&sheet, "A2"), raw(&sheet, "A3"), raw(&sheet, "A4")), ("apple".to_string(), "pear".to_string(), "fig".to_string()));
+    }
+
+    #[test]
+    fn insertions_and_deletions_keep_ids_and_references() {
+        let mut sheet = sheet();
+        sheet.set_row_ids(true);
+        let (apple, fig) = (sheet.row_id(2).unwrap(), sheet.row_id(3).unwrap());
+        sheet.insert_rows(2, 2);
+        assert_eq!(sheet.find_row(apple), Some(4));
+        assert_eq!(raw(&sheet, "A5"), "apple");
+        assert_eq!(raw(&sheet, "C5"), "=B5*2");
+        assert_eq!(raw(&sheet, "B8"), "=SUM(B2:B6)");
+        assert_eq!(sheet.row_id(2), None);
+
+        sheet.delete_rows(4, 1);
+        assert_eq!(sheet.find_row(apple), None);
+        assert_eq!(sheet.find_row(fig), Some(4));
+        assert_eq!(raw(&sheet, "C5"), "=B5*2");
+        assert_eq!(raw(&sheet, "B7"), "=SUM(B2:B5)");
+
+        sheet.set_cell(CellId::new(0, 3), "=B2".to_string());
+        sheet.set_cell(CellId::new(0, 4), "=SUM(B2:B2)".to_string());
+        sheet.delete_rows(1, 1);
+        assert!(matches!(sheet.evaluate_cell(CellId::new(0, 3)), Ok(Value::Error(CellError::Ref))));
+        assert!(matches!(sheet.evaluate_cell(CellId::new(0, 4)), Ok(Value::Error(CellError::Ref))));
+        assert_eq!(raw(&sheet, "B6"), "=SUM(B2:B4)");
+    }
+}
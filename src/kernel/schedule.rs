@@ -0,0 +1,204 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{CellId, Kernel, Value};
+use super::worksheet::{SheetError, Worksheet};
+use thiserror::Error;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ScheduleError {
+    #[error("circular reference through {0}")]
+    CircularReference(CellId),
+
+    /// A calculation chain pins `before` ahead of `after`, but `before`
+    /// depends on `after`.
+    #[error("calculation chain puts {before} before {after}, which it depends on")]
+    Conflict{before: CellId, after: CellId},
+
+    #[error("calculation chains order {0} before itself")]
+    ChainCycle(CellId),
+
+    #[error(transparent)]
+    Sheet(#[from] SheetError),
+}
+
+/// CalcChain pins the order formulas of a sheet are calculated in: every
+/// formula of a range is calculated before the formulas of the ranges after
+/// it, and the formulas of a range row by row. This matters to evaluation
+/// hooks with side effects, such as models where formulas replaced macros
+/// that ran in a fixed order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CalcChain {
+    ranges: Vec<(CellId, CellId)>,
+}
+
+impl CalcChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append the range between two corners to the chain.
+    pub fn then(mut self, first: CellId, second: CellId) -> Self {
+        self.ranges.push((first, second));
+        self
+    }
+
+    pub fn ranges(&self) -> &[(CellId, CellId)] {
+        &self.ranges
+    }
+
+    /// Iterate the cells of the chain in the order they are pinned.
+    fn cell_ids(&self) -> impl Iterator<Item=CellId> + '_ {
+        self.ranges.iter().flat_map(|(first, second)| CellId::range(*first, *second))
+    }
+}
+
+/// Whether `to` is reachable from `from` following `edges`.
+fn reaches(edges: &HashMap<CellId, Vec<CellId>>, from: CellId, to: CellId) -> bool {
+    let mut stack = vec![from];
+    let mut visited = HashSet::from([from]);
+    while let Some(cell_id) = stack.pop() {
+        if cell_id == to {
+            return true;
+        }
+        for next in edges.get(&cell_id).into_iter().flatten() {
+            if visited.insert(*next) {
+                stack.push(*next);
+            }
+        }
+    }
+    false
+}
+
+/// Order `nodes` so every edge points forward, taking cells which are
+/// ready row by row. Returns the nodes left over if the edges have a cycle.
+fn topological(nodes: &HashSet<CellId>, edges: &HashMap<CellId, Vec<CellId>>) -> Result<Vec<CellId>, Vec<CellId>> {
+    let mut incoming = nodes.iter().map(|cell_id| (*cell_id, 0)).collect::<HashMap<_, _>>();
+    for next in edges.values().flatten() {
+        *incoming.get_mut(next).expect("edges connect nodes") += 1;
+    }
+    let mut ready = incoming.iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(cell_id, _)| Reverse((cell_id.row(), cell_id.col())))
+        .collect::<BinaryHeap<_>>();
+    let mut order = Vec::with_capacity(nodes.len());
+    while let Some(Reverse((row, col))) = ready.pop() {
+        let cell_id = CellId::new(row, col);
+        order.push(cell_id);
+        for next in edges.get(&cell_id).into_iter().flatten() {
+            let count = incoming.get_mut(next).expect("edges connect nodes");
+            *count -= 1;
+            if *count == 0 {
+                ready.push(Reverse((next.row(), next.col())));
+            }
+        }
+    }
+    if order.len() == nodes.len() {
+        Ok(order)
+    } else {
+        let ordered = order.into_iter().collect::<HashSet<_>>();
+        let mut left = nodes.difference(&ordered).copied().collect::<Vec<_>>();
+        left.sort_by_key(|cell_id| (cell_id.row(), cell_id.col()));
+        Err(left)
+    }
+}
+
+impl<T: Arithmetic> Worksheet<T> {
+    /// Get the order the formulas of this sheet are calculated in by
+    /// `calculate`. Every formula comes after the formulas it refers to and
+    /// the calculation chains are respected; otherwise formulas are taken
+    /// row by row. Chains which contradict references are conflicts.
+    pub fn calculation_order(&self) -> Result<Vec<CellId>, ScheduleError> {
+        let formulas = self.cells()
+            .filter(|(_, cell)| cell.formula().is_some())
+            .map(|(cell_id, _)| cell_id)
+            .collect::<HashSet<_>>();
+
+        let mut dependents: HashMap<CellId, Vec<CellId>> = HashMap::new();
+        for (cell_id, cell) in self.cells() {
+            for precedent in cell.formula().into_iter().flat_map(|formula| formula.precedents()) {
+                if precedent == cell_id {
+                    return Err(ScheduleError::CircularReference(cell_id));
+                }
+                if formulas.contains(&precedent) {
+                    dependents.entry(precedent).or_default().push(cell_id);
+                }
+            }
+        }
+        if let Err(left) = topological(&formulas, &dependents) {
+            return Err(ScheduleError::CircularReference(left[0]));
+        }
+
+        let mut edges = dependents.clone();
+        for chain in self.calc_chains() {
+            let pinned = chain.cell_ids().filter(|cell_id| formulas.contains(cell_id)).collect::<Vec<_>>();
+            for pair in pinned.windows(2) {
+                let (before, after) = (pair[0], pair[1]);
+                if before == after || reaches(&dependents, before, after) {
+                    continue;
+                }
+                if reaches(&dependents, after, before) {
+                    return Err(ScheduleError::Conflict{before, after});
+                }
+                edges.entry(before).or_default().push(after);
+            }
+        }
+        // Each pinned pair agrees with references, but chains crossing each
+        // other can still contradict.
+        topological(&formulas, &edges).map_err(|left| ScheduleError::ChainCycle(left[0]))
+    }
+
+    /// Calculate every formula of this sheet in calculation order, and get
+    /// the results in that order.
+    pub fn calculate(&self) -> Result<Vec<(CellId, Value<T>)>, ScheduleError> {
+        let order = self.calculation_order()?;
+        self.memoized(|| {
+            order.into_iter()
+                .map(|cell_id| Ok((cell_id, self.evaluate_cell(cell_id)?)))
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sheet(cells: &[(&str, &str)]) -> Worksheet {
+        let mut sheet: Worksheet = Worksheet::new();
+        for (cell_id, data) in cells {
+            sheet.set_cell(CellId::parse(cell_id).unwrap(), data.to_string());
+        }
+        sheet
+    }
+
+    fn order(sheet: &Worksheet) -> Result<Vec<String>, ScheduleError> {
+        sheet.calculation_order().map(|order| order.iter().map(CellId::to_string).collect())
+    }
+
+    #[test]
+    fn formulas_follow_their_precedents_then_rows() {
+        let sheet = sheet(&[("A1", "=B2+1"), ("B1", "=1+1"), ("A2", "5"), ("B2", "=A2*2")]);
+        assert_eq!(order(&sheet).unwrap(), ["B1", "B2", "A1"]);
+        let results = sheet.calculate().unwrap();
+        assert_eq!(results.iter().map(|(cell_id, _)| cell_id.to_string()).collect::<Vec<_>>(), ["B1", "B2", "A1"]);
+        assert!(matches!(order(&self::sheet(&[("A1", "=B1"), ("B1", "=A1")])), Err(ScheduleError::CircularReference(_))));
+    }
+
+    #[test]
+    fn chains_pin_independent_formulas() {
+        let mut sheet = sheet(&[("A1", "=1+1"), ("A2", "=2+2"), ("A3", "=A1*2")]);
+        sheet.calc_chains_mut().push(CalcChain::new().then(CellId::parse("A3").unwrap(), CellId::parse("A3").unwrap()).then(CellId::parse("A2").unwrap(), CellId::parse("A2").unwrap()));
+        assert_eq!(order(&sheet).unwrap(), ["A1", "A3", "A2"]);
+    }
+
+    #[test]
+    fn chains_contradicting_references_are_conflicts() {
+        let mut sheet = sheet(&[("A1", "=1+1"), ("A2", "=A1*2")]);
+        sheet.calc_chains_mut().push(CalcChain::new().then(CellId::parse("A2").unwrap(), CellId::parse("A2").unwrap()).then(CellId::parse("A1").unwrap(), CellId::parse("A1").unwrap()));
+        assert_eq!(order(&sheet), Err(ScheduleError::Conflict{before: CellId::parse("A2").unwrap(), after: CellId::parse("A1").unwrap()}));
+        sheet.set_cell(CellId::parse("A2").unwrap(), "=3+3".to_string());
+        sheet.calc_chains_mut().push(CalcChain::new().then(CellId::parse("A1").unwrap(), CellId::parse("A1").unwrap()).then(CellId::parse("A2").unwrap(), CellId::parse("A2").unwrap()));
+        assert!(matches!(order(&sheet), Err(ScheduleError::ChainCycle(_))));
+    }
+}
@@ -0,0 +1,307 @@
+use super::arithmetic::Arithmetic;
+use super::functions::FunctionError;
+use super::kernel::{CellError, CellId, Numeric, Primitive, Value};
+use super::serialize::value_to_raw;
+use super::workbook::{Workbook, WorkbookError};
+use thiserror::Error;
+use std::path::Path;
+use std::rc::Rc;
+
+#[derive(Error, Debug)]
+pub enum ScriptError {
+    /// The engine failed to compile or run a script, with its message.
+    #[error("script failed: {0}")]
+    Engine(String),
+
+    #[error("{0} is not a cell or range reference")]
+    InvalidReference(String),
+
+    #[error(transparent)]
+    Function(#[from] FunctionError),
+
+    #[error(transparent)]
+    Workbook(#[from] WorkbookError),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// ScriptValue is a value passed between a workbook and a script, in the
+/// types scripting languages have.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptValue {
+    Empty,
+    Bool(bool),
+    Number(f64),
+    /// Text, and dates, times and addresses as they are written in cells.
+    Text(String),
+    Error(CellError),
+    /// The rows of a range or dynamic array.
+    Array(Vec<Vec<ScriptValue>>),
+}
+
+impl ScriptValue {
+    fn from_value<T: Arithmetic>(value: &Value<T>) -> Self {
+        match value {
+            Value::Empty => Self::Empty,
+            Value::Primitive(Primitive::Number(number)) => Self::Number(number.value().to_f64()),
+            Value::Primitive(Primitive::Bool(b)) => Self::Bool(*b),
+            Value::Primitive(Primitive::Text(text)) => Self::Text(text.clone()),
+            Value::Error(e) => Self::Error(*e),
+            Value::FormulaParseError(_) => Self::Error(CellError::Name),
+            Value::Array(array) => Self::Array(array.iter_rows().map(|row| row.iter().map(Self::from_value).collect()).collect()),
+            value => Self::Text(value_to_raw(value)),
+        }
+    }
+
+    fn to_value<T: Arithmetic>(&self) -> Value<T> {
+        match self {
+            Self::Empty => Value::Empty,
+            Self::Bool(b) => Value::Primitive(Primitive::Bool(*b)),
+            Self::Number(number) => Value::Primitive(Primitive::Number(Numeric::new(T::from_f64(*number), None))),
+            Self::Text(text) => Value::Primitive(Primitive::Text(text.clone())),
+            Self::Error(e) => Value::Error(*e),
+            // Functions return one value to their cell.
+            Self::Array(rows) => rows.first().and_then(|row| row.first()).map_or(Value::Empty, Self::to_value),
+        }
+    }
+}
+
+/// ScriptHost is the workbook API a script sees. References are written
+/// like `B2` or `A1:C3`.
+pub trait ScriptHost {
+    fn sheets(&self) -> Vec<String>;
+
+    /// Get the value of a cell, or the rows of values of a range.
+    fn get(&self, sheet: &str, reference: &str) -> Result<ScriptValue, ScriptError>;
+
+    /// Set the contents of a cell as if typed, so `=A1*2` is a formula.
+    fn set(&mut self, sheet: &str, reference: &str, data: &str) -> Result<(), ScriptError>;
+
+    /// Get the cells of a range which are not empty, row by row, with their
+    /// references and values.
+    fn cells(&self, sheet: &str, reference: &str) -> Result<Vec<(String, ScriptValue)>, ScriptError>;
+
+    /// Make a function of the script callable from formulas under a name,
+    /// replacing any function of that name.
+    fn register_function(&mut self, name: &str, function: &str) -> Result<(), ScriptError>;
+}
+
+/// ScriptEngine runs automation scripts against workbooks, as macros do in
+/// spreadsheet applications. Implement it on top of an embedded language,
+/// such as Rhai or Lua, by exposing the methods of the host to scripts
+/// while they run and keeping the functions they define to call later.
+pub trait ScriptEngine {
+    /// Run a script, giving it the host to work on the workbook through.
+    fn run(&self, source: &str, host: &mut dyn ScriptHost) -> Result<(), ScriptError>;
+
+    /// Call a function defined by a script run before, for a formula.
+    fn call(&self, function: &str, arguments: &[ScriptValue]) -> Result<ScriptValue, ScriptError>;
+}
+
+/// Parse a cell or range reference such as `B2` or `A1:C3`.
+fn parse_range(reference: &str) -> Result<(CellId, CellId), ScriptError> {
+    let invalid = || ScriptError::InvalidReference(reference.to_string());
+    let (first, second) = reference.split_once(':').unwrap_or((reference, reference));
+    let first = CellId::parse(&first.trim().to_ascii_uppercase()).map_err(|_| invalid())?;
+    let second = CellId::parse(&second.trim().to_ascii_uppercase()).map_err(|_| invalid())?;
+    let start = CellId::new(first.row().min(second.row()), first.col().min(second.col()));
+    let end = CellId::new(first.row().max(second.row()), first.col().max(second.col()));
+    Ok((start, end))
+}
+
+/// Host is the workbook a script runs against.
+struct Host<'a, T: Arithmetic, E: ScriptEngine> {
+    workbook: &'a mut Workbook<T>,
+    engine: Rc<E>,
+}
+
+impl<T: Arithmetic, E: ScriptEngine> Host<'_, T, E> {
+    fn value(&self, sheet: &str, cell_id: CellId) -> Result<ScriptValue, ScriptError> {
+        let value = self.workbook.evaluate_cell(sheet, cell_id)?;
+        Ok(match value {
+            Value::Raw => {
+                let cell = self.workbook.sheet(sheet).and_then(|sheet| sheet.cell(cell_id));
+                ScriptValue::Text(cell.map(|cell| cell.text().to_string()).unwrap_or_default())
+            },
+            value => ScriptValue::from_value(&value),
+        })
+    }
+}
+
+impl<T: Arithmetic + 'static, E: ScriptEngine + 'static> ScriptHost for Host<'_, T, E> {
+    fn sheets(&self) -> Vec<String> {
+        self.workbook.sheet_names().map(str::to_string).collect()
+    }
+
+    fn get(&self, sheet: &str, reference: &str) -> Result<ScriptValue, ScriptError> {
+        let (start, end) = parse_range(reference)?;
+        if start == end && !reference.contains(':') {
+            return self.value(sheet, start);
+        }
+        let rows = (start.row()..=end.row())
+            .map(|row| (start.col()..=end.col()).map(|col| self.value(sheet, CellId::new(row, col))).collect())
+            .collect::<Result<_, _>>()?;
+        Ok(ScriptValue::Array(rows))
+    }
+
+    fn set(&mut self, sheet: &str, reference: &str, data: &str) -> Result<(), ScriptError> {
+        let (start, end) = parse_range(reference)?;
+        if start != end {
+            return Err(ScriptError::InvalidReference(reference.to_string()));
+        }
+        self.workbook.set_cell(sheet, start, data.to_string())?;
+        Ok(())
+    }
+
+    fn cells(&self, sheet: &str, reference: &str) -> Result<Vec<(String, ScriptValue)>, ScriptError> {
+        let (start, end) = parse_range(reference)?;
+        let own = self.workbook.sheet(sheet).ok_or_else(|| WorkbookError::UnknownSheet(sheet.to_string()))?;
+        let mut ids = own.cell_ids().into_iter()
+            .filter(|cell_id| (start.row()..=end.row()).contains(&cell_id.row()) && (start.col()..=end.col()).contains(&cell_id.col()))
+            .collect::<Vec<_>>();
+        ids.sort_by_key(|cell_id| (cell_id.row(), cell_id.col()));
+        ids.into_iter().map(|cell_id| Ok((cell_id.to_string(), self.value(sheet, cell_id)?))).collect()
+    }
+
+    fn register_function(&mut self, name: &str, function: &str) -> Result<(), ScriptError> {
+        let engine = Rc::clone(&self.engine);
+        let function = function.to_string();
+        let functions = self.workbook.functions_mut();
+        functions.unregister(name);
+        functions.register(name, Box::new(move |arguments: &[Value<T>]| {
+            let arguments = arguments.iter().map(ScriptValue::from_value).collect::<Vec<_>>();
+            match engine.call(&function, &arguments) {
+                Ok(value) => value.to_value(),
+                Err(_) => Value::Error(CellError::Value),
+            }
+        }))?;
+        Ok(())
+    }
+}
+
+impl<T: Arithmetic + 'static> Workbook<T> {
+    /// Run an automation script against this workbook without a spreadsheet
+    /// application, such as to fill in a report or clean up an import. The
+    /// functions it registers stay callable from formulas for as long as
+    /// the workbook lives, through the engine.
+    pub fn run_script<E: ScriptEngine + 'static>(&mut self, engine: Rc<E>, source: &str) -> Result<(), ScriptError> {
+        let mut host = Host{workbook: self, engine: Rc::clone(&engine)};
+        engine.run(source, &mut host)
+    }
+
+    /// Run the script of a file shipped alongside the workbook, see
+    /// `run_script`.
+    pub fn run_script_file<E: ScriptEngine + 'static>(&mut self, engine: Rc<E>, path: impl AsRef<Path>) -> Result<(), ScriptError> {
+        let source = std::fs::read_to_string(path)?;
+        self.run_script(engine, &source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// A toy engine running one command per line: `set SHEET REF DATA`,
+    /// `copy SHEET FROM TO`, which writes the value of a cell as text, `sum
+    /// SHEET RANGE TO`, which adds up the numbers of the cells of a range,
+    /// and `def NAME FUNCTION`. Its only function is `double`.
+    #[derive(Default)]
+    struct LineEngine {
+        calls: RefCell<usize>,
+    }
+
+    impl ScriptEngine for LineEngine {
+        fn run(&self, source: &str, host: &mut dyn ScriptHost) -> Result<(), ScriptError> {
+            for line in source.lines() {
+                match line.splitn(4, ' ').collect::<Vec<_>>().as_slice() {
+                    ["set", sheet, reference, data] => host.set(sheet, reference, data)?,
+                    ["copy", sheet, from, to] => {
+                        let text = match host.get(sheet, from)? {
+                            ScriptValue::Number(number) => number.to_string(),
+                            ScriptValue::Text(text) => text,
+                            value => format!("{:?}", value),
+                        };
+                        host.set(sheet, to, &text)?;
+                    },
+                    ["sum", sheet, range, to] => {
+                        let sum = host.cells(sheet, range)?.into_iter()
+                            .filter_map(|(_, value)| match value {
+                                ScriptValue::Number(number) => Some(number),
+                                _ => None,
+                            })
+                            .sum::<f64>();
+                        host.set(sheet, to, &sum.to_string())?;
+                    },
+                    ["def", name, function] => host.register_function(name, function)?,
+                    _ => return Err(ScriptError::Engine(format!("cannot run {:?}", line))),
+                }
+            }
+            Ok(())
+        }
+
+        fn call(&self, function: &str, arguments: &[ScriptValue]) -> Result<ScriptValue, ScriptError> {
+            *self.calls.borrow_mut() += 1;
+            match (function, arguments) {
+                ("double", [ScriptValue::Number(number)]) => Ok(ScriptValue::Number(number * 2.0)),
+                ("double", [ScriptValue::Array(rows)]) => Ok(ScriptValue::Number(rows.len() as f64)),
+                _ => Err(ScriptError::Engine(format!("{} failed", function))),
+            }
+        }
+    }
+
+    fn workbook() -> Workbook {
+        let mut workbook: Workbook = Workbook::new();
+        workbook.add_sheet("Report").unwrap();
+        workbook.set_cell("Report", CellId::new(0, 0), "4".to_string()).unwrap();
+        workbook.set_cell("Report", CellId::new(1, 0), "abc".to_string()).unwrap();
+        workbook.set_cell("Report", CellId::new(2, 0), "=A1*3".to_string()).unwrap();
+        workbook
+    }
+
+    fn raw(workbook: &Workbook, cell_id: &str) -> String {
+        workbook.sheet("Report").unwrap().cell(CellId::parse(cell_id).unwrap()).map(|cell| cell.raw().to_string()).unwrap_or_default()
+    }
+
+    #[test]
+    fn scripts_work_on_workbooks() {
+        let mut workbook = workbook();
+        let engine = Rc::new(LineEngine::default());
+        workbook.run_script(Rc::clone(&engine), "copy Report A3 B1\ncopy Report a2 B2\nsum Report A1:A3 B3\nset Report C1 =B3+1").unwrap();
+        assert_eq!(raw(&workbook, "B1"), "12");
+        assert_eq!(raw(&workbook, "B2"), "abc");
+        assert_eq!(raw(&workbook, "B3"), "16");
+        assert_eq!(workbook.formatted("Report", CellId::new(0, 2)).unwrap(), "17");
+
+        assert!(matches!(workbook.run_script(Rc::clone(&engine), "set Report A1:A2 1"), Err(ScriptError::InvalidReference(_))));
+        assert!(matches!(workbook.run_script(Rc::clone(&engine), "set Report A0 1"), Err(ScriptError::InvalidReference(_))));
+        assert!(matches!(workbook.run_script(Rc::clone(&engine), "copy Missing A1 B1"), Err(ScriptError::Workbook(WorkbookError::UnknownSheet(_)))));
+        assert!(matches!(workbook.run_script(engine, "jump"), Err(ScriptError::Engine(_))));
+    }
+
+    #[test]
+    fn scripts_register_functions() {
+        let mut workbook = workbook();
+        let engine = Rc::new(LineEngine::default());
+        workbook.run_script(Rc::clone(&engine), "def TWICE double\ndef BROKEN explode").unwrap();
+        workbook.set_cell("Report", CellId::new(0, 1), "=TWICE(A3)".to_string()).unwrap();
+        workbook.set_cell("Report", CellId::new(1, 1), "=TWICE(A1:A3)".to_string()).unwrap();
+        workbook.set_cell("Report", CellId::new(2, 1), "=BROKEN(1)".to_string()).unwrap();
+        assert_eq!(workbook.formatted("Report", CellId::new(0, 1)).unwrap(), "24");
+        assert_eq!(workbook.formatted("Report", CellId::new(1, 1)).unwrap(), "3");
+        assert!(matches!(workbook.evaluate_cell("Report", CellId::new(2, 1)).unwrap(), Value::Error(CellError::Value)));
+        assert_eq!(*engine.calls.borrow(), 3);
+    }
+
+    #[test]
+    fn script_files_are_read() {
+        let path = std::env::temp_dir().join(format!("xlnt-script-{}.txt", std::process::id()));
+        std::fs::write(&path, "set Report D1 done\n").unwrap();
+        let mut workbook = workbook();
+        workbook.run_script_file(Rc::new(LineEngine::default()), &path).unwrap();
+        assert_eq!(raw(&workbook, "D1"), "done");
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(workbook.run_script_file(Rc::new(LineEngine::default()), &path), Err(ScriptError::Io(_))));
+    }
+}
@@ -0,0 +1,319 @@
+use super::arithmetic::Arithmetic;
+use super::format::{format_value, Locale};
+use super::kernel::{escape_text, Cell, Formula, Numeric, NumericAttribute, Primitive, Value};
+use super::settings::Precision;
+use std::fmt;
+
+/// Binding strength of an operator, higher binds tighter.
+fn precedence<T: Arithmetic>(formula: &Formula<T>) -> u8 {
+    match formula {
+        Formula::Cmp(..) | Formula::Lt(..) | Formula::Gr(..) | Formula::Le(..) | Formula::Ge(..) | Formula::Ne(..) => 1,
+        Formula::Concat(..) => 2,
+        Formula::Add(..) | Formula::Sub(..) => 3,
+        Formula::Mul(..) | Formula::Div(..) => 4,
+        Formula::Pow(..) => 5,
+        Formula::Percent(..) => 6,
+        Formula::Neg(..) => 7,
+        _ => u8::MAX,
+    }
+}
+
+/// Whether a sheet name must be quoted in a reference.
+fn needs_quotes(sheet: &str) -> bool {
+    sheet.is_empty()
+        || sheet.starts_with(|c: char| c.is_ascii_digit())
+        || !sheet.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Get a sheet name as it appears before the `!` of a reference, quoted
+/// if necessary.
+pub fn quote_sheet(sheet: &str) -> String {
+    if needs_quotes(sheet) {
+        format!("'{}'", sheet.replace('\'', "''"))
+    } else {
+        sheet.to_string()
+    }
+}
+
+/// Write a number literal with the default precision, as formulas have no
+/// settings of their own.
+fn write_number<T: Arithmetic>(f: &mut fmt::Formatter<'_>, number: T) -> fmt::Result {
+    write!(f, "{}", Precision::default().format(number.to_f64()))
+}
+
+/// Write a number the way it is written in a formula, keeping a percent sign.
+fn write_numeric<T: Arithmetic>(f: &mut fmt::Formatter<'_>, number: &Numeric<T>) -> fmt::Result {
+    match number.attr() {
+        Some(NumericAttribute::Percent) => {
+            write_number(f, number.number())?;
+            write!(f, "%")
+        },
+        _ => write_number(f, number.value()),
+    }
+}
+
+/// Write text as a formula literal, doubling embedded quotes.
+fn write_text(f: &mut fmt::Formatter<'_>, text: &str) -> fmt::Result {
+    write!(f, "\"{}\"", text.replace('"', "\"\""))
+}
+
+/// Write an operand of an operator with precedence `parent`, adding
+/// parentheses where the operand binds looser. Right operands of equal
+/// precedence are parenthesized as well since operators are left associative.
+fn write_operand<T: Arithmetic>(f: &mut fmt::Formatter<'_>, value: &Value<T>, parent: u8, right: bool) -> fmt::Result {
+    match value {
+        Value::Formula(formula) => {
+            let own = precedence(formula);
+            if own < parent || (right && own == parent) {
+                write!(f, "({})", formula)
+            } else {
+                write!(f, "{}", formula)
+            }
+        },
+        value => write!(f, "{}", LiteralDisplay(value)),
+    }
+}
+
+/// LiteralDisplay writes a non-formula value the way it appears inside a
+/// formula.
+struct LiteralDisplay<'a, T: Arithmetic>(&'a Value<T>);
+
+impl<T: Arithmetic> fmt::Display for LiteralDisplay<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Value::Empty | Value::Raw => Ok(()),
+            Value::Primitive(Primitive::Number(number)) => write_numeric(f, number),
+            Value::Primitive(Primitive::Bool(b)) => write!(f, "{}", if *b { "TRUE" } else { "FALSE" }),
+            Value::Primitive(Primitive::Date(date)) => write!(f, "{}", date),
+            Value::Primitive(Primitive::Time(time)) => write!(f, "{}", time),
+            Value::Primitive(Primitive::IPAddress([a, b, c, d])) => write!(f, "{}.{}.{}.{}", a, b, c, d),
+            Value::Primitive(Primitive::Text(text)) => write_text(f, text),
+            Value::Formula(formula) => write!(f, "{}", formula),
+            Value::FormulaParseError(_) => write!(f, "#NAME?"),
+            Value::Error(e) => write!(f, "{}", e),
+            Value::Array(array) => {
+                write!(f, "{{")?;
+                for (i, row) in array.iter_rows().enumerate() {
+                    if i > 0 {
+                        write!(f, ";")?;
+                    }
+                    for (j, value) in row.iter().enumerate() {
+                        if j > 0 {
+                            write!(f, ",")?;
+                        }
+                        write!(f, "{}", LiteralDisplay(value))?;
+                    }
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+/// Get the raw cell contents which parse back to a computed value, as used
+/// when caching the result of a formula in place of the formula. Numbers
+/// are written with the default precision.
+pub fn value_to_raw<T: Arithmetic>(value: &Value<T>) -> String {
+    value_to_raw_with(value, Precision::default())
+}
+
+/// Get the raw cell contents of a computed value, see `value_to_raw`, with
+/// numbers written with a precision.
+pub fn value_to_raw_with<T: Arithmetic>(value: &Value<T>, precision: Precision) -> String {
+    match value {
+        Value::Formula(formula) => format!("={}", formula),
+        Value::Error(e) => escape_text(&e.to_string()),
+        Value::Primitive(Primitive::Text(text)) => escape_text(text),
+        Value::Primitive(Primitive::Number(number)) => number.to_raw_string_with(precision),
+        value => LiteralDisplay(value).to_string(),
+    }
+}
+
+impl<T: Arithmetic> Numeric<T> {
+    /// Get the raw cell contents which parse back to this number with its
+    /// attribute, like `50%` for a percentage or `$5` and `5 USD` for
+    /// amounts in a currency. Numbers are written with the default
+    /// precision, see `Precision`.
+    pub fn to_raw_string(&self) -> String {
+        self.to_raw_string_with(Precision::default())
+    }
+
+    /// Get the raw cell contents of this number, see `to_raw_string`,
+    /// written with a precision.
+    pub fn to_raw_string_with(&self, precision: Precision) -> String {
+        let number = precision.format(self.number().to_f64());
+        match self.attr() {
+            None => number.to_string(),
+            Some(NumericAttribute::Percent) => format!("{}%", number),
+            Some(NumericAttribute::Currency(currency)) if currency.chars().all(char::is_alphabetic) => format!("{} {}", number, currency),
+            Some(NumericAttribute::Currency(currency)) if number.starts_with('-') => format!("-{}{}", currency, &number[1..]),
+            Some(NumericAttribute::Currency(currency)) => format!("{}{}", currency, number),
+            #[cfg(feature = "units")]
+            Some(NumericAttribute::Unit(unit)) => format!("{} {}", number, unit),
+        }
+    }
+
+    /// Get the text a cell holding this number displays with the `General`
+    /// format, such as `50%` or `$5.00`.
+    pub fn display(&self, locale: &Locale) -> String {
+        format_value(&Value::Primitive(Primitive::Number(self.clone())), "General", locale)
+    }
+}
+
+impl<T: Arithmetic> Cell<T> {
+    /// Create a cell holding a computed value, with raw contents which parse
+    /// back to it.
+    pub fn from_value(value: Value<T>) -> Self {
+        Self::from_value_with(value, Precision::default())
+    }
+
+    /// Create a cell holding a computed value, see `from_value`, with
+    /// numbers written with a precision.
+    pub fn from_value_with(value: Value<T>, precision: Precision) -> Self {
+        let mut cell = Self::from(String::new());
+        cell.set_value(value);
+        cell.refresh_raw_with(precision);
+        cell
+    }
+
+    /// Write the raw contents of this cell anew from its value, such as
+    /// after a computed value was written into it.
+    pub fn refresh_raw(&mut self) {
+        self.refresh_raw_with(Precision::default());
+    }
+
+    pub fn refresh_raw_with(&mut self, precision: Precision) {
+        if !matches!(self.value(), Value::Raw) {
+            let raw = value_to_raw_with(self.value(), precision);
+            self.set_raw(raw);
+        }
+    }
+}
+
+/// Formulas display as formula text without the leading `=`, so that
+/// parsing the output yields an equivalent formula.
+impl<T: Arithmetic> fmt::Display for Formula<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operator = match self {
+            Self::NumberLit(number) => return write_numeric(f, number),
+            Self::TextLit(text) => return write_text(f, text),
+            Self::BoolLit(b) => return write!(f, "{}", if *b { "TRUE" } else { "FALSE" }),
+            Self::CellRef(cell_id) => return write!(f, "{}", cell_id),
+            Self::CellRange(start, end) => return write!(f, "{}:{}", start, end),
+            Self::Name(name) => return write!(f, "{}", name),
+            Self::SheetRef(sheet, target) => return write!(f, "{}!{}", quote_sheet(sheet), target),
+            Self::Function{arguments, ..} | Self::Custom{arguments, ..} => {
+                write!(f, "{}(", self.function_name().unwrap_or_default())?;
+                for (index, argument) in arguments.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_operand(f, argument, 0, false)?;
+                }
+                return write!(f, ")");
+            },
+            Self::Add(..) => "+",
+            Self::Sub(..) => "-",
+            Self::Mul(..) => "*",
+            Self::Div(..) => "/",
+            Self::Pow(..) => "^",
+            Self::Concat(..) => "&",
+            Self::Cmp(..) => "=",
+            Self::Lt(..) => "<",
+            Self::Gr(..) => ">",
+            Self::Le(..) => "<=",
+            Self::Ge(..) => ">=",
+            Self::Ne(..) => "<>",
+            Self::Neg(operand) => {
+                write!(f, "-")?;
+                return write_operand(f, operand, precedence(self), false);
+            },
+            Self::Percent(operand) => {
+                write_operand(f, operand, precedence(self), false)?;
+                return write!(f, "%");
+            },
+        };
+        let parent = precedence(self);
+        let operands = self.operands();
+        write_operand(f, operands[0], parent, false)?;
+        write!(f, "{}", operator)?;
+        write_operand(f, operands[1], parent, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::CellError;
+
+    fn display(formula: &str) -> String {
+        Formula::<f64>::try_from(formula).unwrap().to_string()
+    }
+
+    #[test]
+    fn formulas_display_with_the_parentheses_they_need() {
+        for (formula, expected) in [
+            ("1+(2*3)", "1+2*3"),
+            ("(1+2)*3", "(1+2)*3"),
+            ("1-(2-3)", "1-(2-3)"),
+            ("(1-2)-3", "1-2-3"),
+            ("(2^3)^2", "2^3^2"),
+            ("2^(3^2)", "2^(3^2)"),
+            ("-(A1+B2)", "-(A1+B2)"),
+            ("50%*A1", "50%*A1"),
+            ("\"say \"\"hi\"\"\"&A1", "\"say \"\"hi\"\"\"&A1"),
+            ("SUM('My Sheet'!A1:B2,Data!C3)", "SUM('My Sheet'!A1:B2,Data!C3)"),
+            ("IF(A1>=1,TRUE,FALSE)", "IF(A1>=1,TRUE,FALSE)"),
+        ] {
+            assert_eq!(display(formula), expected, "{}", formula);
+            assert_eq!(display(expected), expected, "{}", expected);
+        }
+    }
+
+    #[test]
+    fn sheets_are_quoted_when_needed() {
+        assert_eq!(quote_sheet("Data"), "Data");
+        assert_eq!(quote_sheet("Q1.2024"), "Q1.2024");
+        assert_eq!(quote_sheet("My Sheet"), "'My Sheet'");
+        assert_eq!(quote_sheet("2024"), "'2024'");
+        assert_eq!(quote_sheet("Bob's"), "'Bob''s'");
+        assert_eq!(quote_sheet(""), "''");
+    }
+
+    #[test]
+    fn computed_values_parse_back_from_their_raw_contents() {
+        let values: Vec<Value<f64>> = vec![
+            Value::Primitive(Primitive::Number(Numeric::new(1.5, None))),
+            Value::Primitive(Primitive::Number(Numeric::new(-2.0, None))),
+            Value::Primitive(Primitive::Number(Numeric::new(50.0, Some(NumericAttribute::Percent)))),
+            Value::Primitive(Primitive::Text("=not a formula".to_string())),
+            Value::Primitive(Primitive::Text("12".to_string())),
+            Value::Primitive(Primitive::Bool(true)),
+        ];
+        for value in values {
+            let cell = Cell::from_value(value.clone());
+            let parsed = Cell::<f64>::from(cell.raw().to_string());
+            let parsed = match parsed.value() {
+                Value::Raw => Value::Primitive(Primitive::Text(parsed.text().to_string())),
+                value => value.clone(),
+            };
+            assert_eq!(format!("{:?}", parsed), format!("{:?}", value), "{}", cell.raw());
+        }
+        let percent = Numeric::new(50.0, Some(NumericAttribute::Percent));
+        assert_eq!(percent.to_raw_string(), "50%");
+        // Cells cannot hold errors, so errors are kept as their text.
+        assert_eq!(value_to_raw(&Value::<f64>::Error(CellError::Div0)), "#DIV/0!");
+    }
+
+    #[test]
+    fn numbers_are_written_with_a_precision() {
+        let number = |x: f64, attr| Value::<f64>::Primitive(Primitive::Number(Numeric::new(x, attr)));
+        assert_eq!(value_to_raw(&number(0.1 + 0.2, None)), "0.3");
+        assert_eq!(value_to_raw_with(&number(0.1 + 0.2, None), Precision::RoundTrip), "0.30000000000000004");
+        assert_eq!(value_to_raw_with(&number(1.0 / 3.0, None), Precision::Significant(4)), "0.3333");
+        let dollars = Some(NumericAttribute::Currency("$".to_string()));
+        assert_eq!(value_to_raw_with(&number(-1234.5678, dollars), Precision::Significant(5)), "-$1234.6");
+        // Literals in formulas are written with the default precision.
+        assert_eq!(Formula::<f64>::try_from("0.30000000000000004*2").unwrap().to_string(), "0.3*2");
+    }
+}
@@ -0,0 +1,406 @@
+use super::arithmetic::Arithmetic;
+use super::budget::Budget;
+use super::csv::{parse_csv, quote_field, records, CsvError, CsvOptions};
+use super::kernel::{CellId, Kernel};
+use super::workbook::{Workbook, WorkbookError};
+use thiserror::Error;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+
+/// How long a read from or a write to a client may block.
+pub const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug)]
+pub enum ServiceError {
+    #[error("no workbook {0}")]
+    UnknownWorkbook(String),
+
+    #[error("{0} is not a cell or range reference")]
+    InvalidReference(String),
+
+    #[error("malformed request: {0}")]
+    BadRequest(String),
+
+    #[error(transparent)]
+    Csv(#[from] CsvError),
+
+    #[error(transparent)]
+    Workbook(#[from] WorkbookError),
+}
+
+/// Request is one call to a calculation service, independent of the
+/// transport which carried it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    /// Load a sheet from delimited text, creating the workbook if needed
+    /// and replacing a sheet of the same name.
+    LoadSheet{workbook: String, sheet: String, csv: String},
+    /// Enter the raw contents of cells, as if typed.
+    SetCells{workbook: String, sheet: String, cells: Vec<(CellId, String)>},
+    /// Get the text the cells of a range display.
+    Evaluate{workbook: String, sheet: String, first: CellId, second: CellId},
+    Unload(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// The number of non-empty cells loaded.
+    Loaded(usize),
+    /// The number of cells set.
+    Updated(usize),
+    /// The displayed text of a range, row by row.
+    Values(Vec<Vec<String>>),
+    Unloaded,
+}
+
+/// ChangeEvent tells subscribers which cells were entered, so they can
+/// evaluate whatever depends on them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+    pub workbook: String,
+    pub sheet: String,
+    pub cells: Vec<CellId>,
+}
+
+/// Service is a calculation service holding workbooks by id. It handles
+/// requests but does no I/O, so it can be put behind any transport: `serve`
+/// is a small HTTP one, and a gRPC server only has to map its messages to
+/// requests. Uploads are checked against the budget, which is untrusted by
+/// default.
+pub struct Service<T: Arithmetic=f64> {
+    workbooks: HashMap<String, Workbook<T>>,
+    options: CsvOptions,
+    budget: Budget,
+    subscribers: Vec<Sender<ChangeEvent>>,
+}
+
+impl<T: Arithmetic> Default for Service<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Arithmetic> Service<T> {
+    pub fn new() -> Self {
+        Self{workbooks: HashMap::new(), options: CsvOptions::default(), budget: Budget::untrusted(), subscribers: Vec::new()}
+    }
+
+    pub fn with_budget(mut self, budget: Budget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    pub fn with_options(mut self, options: CsvOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn workbook(&self, id: &str) -> Option<&Workbook<T>> {
+        self.workbooks.get(id)
+    }
+
+    pub fn workbook_mut(&mut self, id: &str) -> Option<&mut Workbook<T>> {
+        self.workbooks.get_mut(id)
+    }
+
+    /// Get the change events of all workbooks from now on.
+    pub fn subscribe(&mut self) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    pub fn handle(&mut self, request: Request) -> Result<Response, ServiceError> {
+        match request {
+            Request::LoadSheet{workbook, sheet, csv} => {
+                let loaded = parse_csv::<T>(&csv, &self.options, &self.budget)?;
+                let cells = loaded.cell_ids();
+                let book = self.workbooks.entry(workbook.clone()).or_default();
+                match book.sheet_mut(&sheet) {
+                    Some(existing) => *existing = loaded,
+                    None => {
+                        book.insert_sheet(&sheet, loaded)?;
+                    },
+                }
+                let count = cells.len();
+                self.publish(ChangeEvent{workbook, sheet, cells});
+                Ok(Response::Loaded(count))
+            },
+            Request::SetCells{workbook, sheet, cells} => {
+                let book = self.workbooks.get_mut(&workbook).ok_or_else(|| ServiceError::UnknownWorkbook(workbook.clone()))?;
+                let target = book.sheet_mut(&sheet).ok_or_else(|| WorkbookError::UnknownSheet(sheet.clone()))?;
+                // Check the whole batch first, so a batch over budget
+                // changes no cells at all.
+                for (_, data) in &cells {
+                    self.budget.check_string(data).map_err(CsvError::from)?;
+                    self.budget.check_formula::<T>(data).map_err(CsvError::from)?;
+                }
+                let mut ids = Vec::with_capacity(cells.len());
+                for (cell_id, data) in cells {
+                    target.set_cell(cell_id, data);
+                    ids.push(cell_id);
+                }
+                let count = ids.len();
+                self.publish(ChangeEvent{workbook, sheet, cells: ids});
+                Ok(Response::Updated(count))
+            },
+            Request::Evaluate{workbook, sheet, first, second} => {
+                let book = self.workbooks.get(&workbook).ok_or_else(|| ServiceError::UnknownWorkbook(workbook.clone()))?;
+                let (top, bottom) = (first.row().min(second.row()), first.row().max(second.row()));
+                let (left, right) = (first.col().min(second.col()), first.col().max(second.col()));
+                let cells = (bottom - top + 1) as usize * (right - left + 1) as usize;
+                self.budget.check_cells(cells).map_err(CsvError::from)?;
+                let mut rows = Vec::with_capacity((bottom - top + 1) as usize);
+                for row in top..=bottom {
+                    let values = (left..=right).map(|col| book.formatted(&sheet, CellId::new(row, col))).collect::<Result<_, _>>()?;
+                    rows.push(values);
+                }
+                Ok(Response::Values(rows))
+            },
+            Request::Unload(workbook) => {
+                self.workbooks.remove(&workbook).ok_or(ServiceError::UnknownWorkbook(workbook))?;
+                Ok(Response::Unloaded)
+            },
+        }
+    }
+
+    /// Send an event to every subscriber, forgetting those who hung up.
+    fn publish(&mut self, event: ChangeEvent) {
+        self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+/// Parse a cell or range reference such as `B2` or `A1:C3`.
+fn parse_range(reference: &str) -> Result<(CellId, CellId), ServiceError> {
+    let invalid = || ServiceError::InvalidReference(reference.to_string());
+    let (first, second) = reference.split_once(':').unwrap_or((reference, reference));
+    let first = CellId::parse(&first.to_ascii_uppercase()).map_err(|_| invalid())?;
+    let second = CellId::parse(&second.to_ascii_uppercase()).map_err(|_| invalid())?;
+    Ok((first, second))
+}
+
+/// Decode the `%XX` escapes of a segment of a path.
+fn percent_decode(segment: &str) -> Result<String, ServiceError> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'%' => {
+                let hex = segment.get(index + 1..index + 3).ok_or_else(|| ServiceError::BadRequest(segment.to_string()))?;
+                decoded.push(u8::from_str_radix(hex, 16).map_err(|_| ServiceError::BadRequest(segment.to_string()))?);
+                index += 3;
+            },
+            byte => {
+                decoded.push(byte);
+                index += 1;
+            },
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| ServiceError::BadRequest(segment.to_string()))
+}
+
+/// Turn an HTTP call into a request:
+///
+/// - `PUT /workbooks/{id}/sheets/{sheet}` loads a sheet from the CSV body.
+/// - `POST /workbooks/{id}/sheets/{sheet}/cells` sets cells from a CSV body
+///   of records of a cell id and its contents.
+/// - `GET /workbooks/{id}/sheets/{sheet}/cells/{range}` evaluates a range.
+/// - `DELETE /workbooks/{id}` unloads a workbook.
+fn route(method: &str, path: &str, body: String) -> Result<Request, ServiceError> {
+    let segments = path.trim_matches('/').split('/').map(percent_decode).collect::<Result<Vec<_>, _>>()?;
+    let segments = segments.iter().map(String::as_str).collect::<Vec<_>>();
+    let request = match (method, segments.as_slice()) {
+        ("PUT", ["workbooks", workbook, "sheets", sheet]) => {
+            Request::LoadSheet{workbook: workbook.to_string(), sheet: sheet.to_string(), csv: body}
+        },
+        ("POST", ["workbooks", workbook, "sheets", sheet, "cells"]) => {
+            let mut cells = Vec::new();
+            for record in records(&body, ',').map_err(CsvError::UnterminatedQuote)? {
+                let [reference, data] = <[String; 2]>::try_from(record).map_err(|_| ServiceError::BadRequest(body.clone()))?;
+                let cell_id = CellId::parse(&reference.to_ascii_uppercase()).map_err(|_| ServiceError::InvalidReference(reference))?;
+                cells.push((cell_id, data));
+            }
+            Request::SetCells{workbook: workbook.to_string(), sheet: sheet.to_string(), cells}
+        },
+        ("GET", ["workbooks", workbook, "sheets", sheet, "cells", reference]) => {
+            let (first, second) = parse_range(reference)?;
+            Request::Evaluate{workbook: workbook.to_string(), sheet: sheet.to_string(), first, second}
+        },
+        ("DELETE", ["workbooks", workbook]) => Request::Unload(workbook.to_string()),
+        _ => return Err(ServiceError::BadRequest(format!("{} {}", method, path))),
+    };
+    Ok(request)
+}
+
+/// Get the status code of an error.
+fn status(error: &ServiceError) -> &'static str {
+    match error {
+        ServiceError::UnknownWorkbook(_) | ServiceError::Workbook(WorkbookError::UnknownSheet(_)) => "404 Not Found",
+        ServiceError::Csv(CsvError::Budget(_)) => "413 Payload Too Large",
+        _ => "400 Bad Request",
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body,
+    )?;
+    stream.flush()
+}
+
+/// Read an HTTP request, and get its method, path and body.
+fn read_request(stream: &TcpStream, budget: &Budget) -> io::Result<(String, String, String)> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed request line"));
+    };
+    let (method, path) = (method.to_string(), path.split('?').next().unwrap_or_default().to_string());
+    let mut length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed content length"))?;
+            }
+        }
+    }
+    budget.check_input(length).map_err(io::Error::other)?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    let body = String::from_utf8(body).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "body is not UTF-8"))?;
+    Ok((method, path, body))
+}
+
+/// Write a change event in the server-sent events format: the workbook,
+/// the sheet and the changed cells, separated by tabs.
+fn write_event(stream: &mut TcpStream, event: &ChangeEvent) -> io::Result<()> {
+    let cells = event.cells.iter().map(CellId::to_string).collect::<Vec<_>>().join(" ");
+    write!(stream, "event: change\ndata: {}\t{}\t{}\n\n", event.workbook, event.sheet, cells)?;
+    stream.flush()
+}
+
+/// Serve a calculation service over HTTP/1.1 until accepting a connection
+/// fails. Requests are handled one at a time on the calling thread, and
+/// bodies are plain delimited text. `GET /events` subscribes to the change
+/// events of all workbooks as server-sent events, for as long as the
+/// connection stays open and keeps up. Ranges evaluate to delimited text of
+/// the displayed values. Reads and writes time out after `IO_TIMEOUT`, so a
+/// client which stalls cannot hold up the others.
+pub fn serve<T: Arithmetic>(listener: TcpListener, service: &mut Service<T>) -> io::Result<()> {
+    let events = service.subscribe();
+    let mut subscribers = Vec::<TcpStream>::new();
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+        if stream.set_read_timeout(Some(IO_TIMEOUT)).and_then(|_| stream.set_write_timeout(Some(IO_TIMEOUT))).is_err() {
+            continue;
+        }
+        let (method, path, body) = match read_request(&stream, &service.budget) {
+            Ok(request) => request,
+            Err(e) => {
+                let _ = respond(&mut stream, "400 Bad Request", &e.to_string());
+                continue;
+            },
+        };
+        if method == "GET" && path.trim_end_matches('/') == "/events" {
+            let opened = write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n");
+            if opened.and_then(|_| stream.flush()).is_ok() {
+                subscribers.push(stream);
+            }
+            continue;
+        }
+        let result = route(&method, &path, body).and_then(|request| service.handle(request));
+        let _ = match result {
+            Ok(Response::Values(rows)) => {
+                let delimiter = service.options.delimiter;
+                let lines = rows.iter()
+                    .map(|row| row.iter().map(|value| quote_field(value, delimiter)).collect::<Vec<_>>().join(&delimiter.to_string()))
+                    .collect::<Vec<_>>();
+                respond(&mut stream, "200 OK", &(lines.join("\n") + "\n"))
+            },
+            Ok(Response::Loaded(count) | Response::Updated(count)) => respond(&mut stream, "200 OK", &format!("{}\n", count)),
+            Ok(Response::Unloaded) => respond(&mut stream, "200 OK", ""),
+            Err(e) => respond(&mut stream, status(&e), &format!("{}\n", e)),
+        };
+        for event in events.try_iter() {
+            subscribers.retain_mut(|subscriber| write_event(subscriber, &event).is_ok());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::budget::BudgetError;
+    use crate::kernel::parser::MAX_NESTING;
+
+    #[test]
+    fn set_cells_rejects_formulas_over_budget() {
+        let mut service: Service = Service::new();
+        service.handle(Request::LoadSheet{workbook: "book".to_string(), sheet: "Sheet1".to_string(), csv: "1\n".to_string()}).unwrap();
+        let nested = format!("={}1{}", "(".repeat(1000), ")".repeat(1000));
+        let request = Request::SetCells{workbook: "book".to_string(), sheet: "Sheet1".to_string(), cells: vec![(CellId::new(1, 0), nested)]};
+        let error = service.handle(request).unwrap_err();
+        assert!(matches!(error, ServiceError::Csv(CsvError::Budget(BudgetError::NestingTooDeep{max: MAX_NESTING}))));
+        assert_eq!(status(&error), "413 Payload Too Large");
+        assert!(service.workbook("book").unwrap().sheet("Sheet1").unwrap().cell(CellId::new(1, 0)).is_none());
+
+        let request = Request::SetCells{workbook: "book".to_string(), sheet: "Sheet1".to_string(), cells: vec![(CellId::new(1, 0), "=A1*2".to_string())]};
+        assert_eq!(service.handle(request).unwrap(), Response::Updated(1));
+    }
+
+    #[test]
+    fn set_cells_changes_nothing_when_any_cell_is_over_budget() {
+        let mut service: Service = Service::new();
+        service.handle(Request::LoadSheet{workbook: "book".to_string(), sheet: "Sheet1".to_string(), csv: "1\n".to_string()}).unwrap();
+        let events = service.subscribe();
+        let nested = format!("={}1{}", "(".repeat(1000), ")".repeat(1000));
+        let cells = vec![(CellId::new(1, 0), "2".to_string()), (CellId::new(2, 0), "=A1+1".to_string()), (CellId::new(3, 0), nested)];
+        let request = Request::SetCells{workbook: "book".to_string(), sheet: "Sheet1".to_string(), cells};
+        assert!(service.handle(request).is_err());
+        let sheet = service.workbook("book").unwrap().sheet("Sheet1").unwrap();
+        assert!(sheet.cell(CellId::new(1, 0)).is_none());
+        assert!(sheet.cell(CellId::new(2, 0)).is_none());
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn requests_are_routed() {
+        let request = route("POST", "/workbooks/book/sheets/Sheet1/cells", "a1,2\nB2,=A1\n".to_string()).unwrap();
+        let cells = vec![(CellId::new(0, 0), "2".to_string()), (CellId::new(1, 1), "=A1".to_string())];
+        assert_eq!(request, Request::SetCells{workbook: "book".to_string(), sheet: "Sheet1".to_string(), cells});
+        assert!(matches!(route("PATCH", "/workbooks/book", String::new()), Err(ServiceError::BadRequest(_))));
+    }
+
+    #[test]
+    fn stalled_clients_time_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut service: Service = Service::new();
+            serve(listener, &mut service)
+        });
+        // A client which connects and never sends its request.
+        let _stalled = TcpStream::connect(address).unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        write!(client, "PUT /workbooks/book/sheets/Sheet1 HTTP/1.1\r\nContent-Length: 2\r\n\r\n1\n").unwrap();
+        client.set_read_timeout(Some(IO_TIMEOUT * 3)).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    }
+}
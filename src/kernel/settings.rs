@@ -0,0 +1,142 @@
+use super::arithmetic::Arithmetic;
+use std::cmp::Ordering;
+
+/// Precision is how many significant digits computed numbers are written
+/// with when they are turned back into text, such as the raw contents of
+/// cells holding values and the fields of CSV exports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precision {
+    /// As many digits as it takes to read the exact number back, so
+    /// `0.1+0.2` is written `0.30000000000000004`.
+    RoundTrip,
+    /// The shortest text reading back to the number rounded to this many
+    /// significant digits, from 1 to 17.
+    Significant(u8),
+}
+
+impl Default for Precision {
+    /// Fifteen digits like spreadsheets, so `0.1+0.2` is written `0.3`.
+    fn default() -> Self {
+        Self::Significant(15)
+    }
+}
+
+impl Precision {
+    pub fn format(&self, number: f64) -> String {
+        match *self {
+            Self::Significant(digits) if number.is_finite() && number != 0.0 => {
+                let digits = digits.clamp(1, 17) as usize;
+                let rounded = format!("{:.*e}", digits - 1, number).parse::<f64>().expect("formatted floats parse");
+                rounded.to_string()
+            },
+            _ => number.to_string(),
+        }
+    }
+}
+
+/// CalcSettings configures how formulas are calculated, and how the
+/// numbers they compute are written as text.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CalcSettings {
+    /// The relative tolerance within which numbers compare as equal, so that
+    /// `0.1+0.2=0.3` holds. Off by default, comparing numbers exactly.
+    pub comparison_tolerance: Option<f64>,
+    pub precision: Precision,
+}
+
+impl CalcSettings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_comparison_tolerance(mut self, tolerance: f64) -> Self {
+        self.comparison_tolerance = Some(tolerance);
+        self
+    }
+
+    pub fn with_precision(mut self, precision: Precision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    /// Whether two numbers are equal. With a tolerance they are equal when
+    /// they differ by at most the tolerance times the larger magnitude.
+    /// Comparison operators and exact lookups share this definition.
+    pub fn numbers_equal<T: Arithmetic>(&self, a: T, b: T) -> bool {
+        match self.comparison_tolerance {
+            None => a == b,
+            Some(tolerance) => {
+                let (a, b) = (a.to_f64(), b.to_f64());
+                a == b || (a - b).abs() <= tolerance * a.abs().max(b.abs())
+            },
+        }
+    }
+
+    /// Order two numbers, treating numbers within the tolerance as equal.
+    pub fn compare_numbers<T: Arithmetic>(&self, a: T, b: T) -> Option<Ordering> {
+        if self.numbers_equal(a, b) {
+            Some(Ordering::Equal)
+        } else {
+            a.partial_cmp(&b)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::{CellId, Kernel, Primitive, Value};
+    use crate::kernel::workbook::Workbook;
+    use crate::kernel::worksheet::Worksheet;
+    use crate::kernel::kernel::Numeric;
+
+    #[test]
+    fn numbers_within_the_tolerance_are_equal() {
+        let exact = CalcSettings::new();
+        assert!(!exact.numbers_equal(0.1 + 0.2, 0.3));
+        assert_eq!(exact.compare_numbers(0.1 + 0.2, 0.3), Some(Ordering::Greater));
+        let tolerant = CalcSettings::new().with_comparison_tolerance(1e-12);
+        assert!(tolerant.numbers_equal(0.1 + 0.2, 0.3));
+        assert_eq!(tolerant.compare_numbers(0.1 + 0.2, 0.3), Some(Ordering::Equal));
+        assert_eq!(tolerant.compare_numbers(1.0, 1.001), Some(Ordering::Less));
+        assert!(!tolerant.numbers_equal(0.0, 1e-300));
+    }
+
+    #[test]
+    fn comparisons_use_the_tolerance_of_their_sheet_or_workbook() {
+        let formula = "=0.1+0.2=0.3".to_string();
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), formula.clone());
+        assert!(matches!(sheet.evaluate_cell(CellId::new(0, 0)).unwrap(), Value::Primitive(Primitive::Bool(false))));
+        *sheet.settings_mut() = CalcSettings::new().with_comparison_tolerance(1e-12);
+        assert!(matches!(sheet.evaluate_cell(CellId::new(0, 0)).unwrap(), Value::Primitive(Primitive::Bool(true))));
+
+        let mut workbook: Workbook = Workbook::new();
+        workbook.add_sheet("Sheet1").unwrap();
+        workbook.set_cell("Sheet1", CellId::new(0, 0), formula).unwrap();
+        workbook.settings_mut().comparison_tolerance = Some(1e-12);
+        assert!(matches!(workbook.evaluate_cell("Sheet1", CellId::new(0, 0)).unwrap(), Value::Primitive(Primitive::Bool(true))));
+    }
+
+    #[test]
+    fn precision_rounds_to_significant_digits() {
+        assert_eq!(Precision::default().format(0.1 + 0.2), "0.3");
+        assert_eq!(Precision::RoundTrip.format(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(Precision::Significant(3).format(1234.5678), "1230");
+        assert_eq!(Precision::Significant(3).format(-0.00012345), "-0.000123");
+        assert_eq!(Precision::Significant(0).format(2.6), "3");
+        assert_eq!(Precision::Significant(40).format(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(Precision::Significant(2).format(0.0), "0");
+        assert_eq!(Precision::Significant(2).format(f64::INFINITY), "inf");
+    }
+
+    #[test]
+    fn values_are_written_in_the_precision_of_their_sheet() {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_value(CellId::new(0, 0), Value::Primitive(Primitive::Number(Numeric::new(0.1 + 0.2, None))));
+        assert_eq!(sheet.cell(CellId::new(0, 0)).unwrap().raw(), "0.3");
+        *sheet.settings_mut() = CalcSettings::default().with_precision(Precision::Significant(2));
+        sheet.set_value(CellId::new(0, 0), Value::Primitive(Primitive::Number(Numeric::new(2.0 / 3.0, None))));
+        assert_eq!(sheet.cell(CellId::new(0, 0)).unwrap().raw(), "0.67");
+    }
+}
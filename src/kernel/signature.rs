@@ -0,0 +1,875 @@
+use super::xml::{tags, unescape_xml, Tag};
+use chrono::NaiveDateTime;
+use thiserror::Error;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path};
+
+/// The signature method of RSA signatures over SHA-256 digests, which
+/// Excel writes and checks.
+pub const RSA_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256";
+
+/// The signature method of HMACs over SHA-256 digests, which `SharedKey`
+/// signs with.
+pub const HMAC_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#hmac-sha256";
+
+const SHA256: &str = "http://www.w3.org/2001/04/xmlenc#sha256";
+const C14N: &str = "http://www.w3.org/TR/2001/REC-xml-c14n-20010315";
+const DSIG_NAMESPACE: &str = "http://www.w3.org/2000/09/xmldsig#";
+const XADES_NAMESPACE: &str = "http://uri.etsi.org/01903/v1.3.2#";
+const OBJECT_TYPE: &str = "http://www.w3.org/2000/09/xmldsig#Object";
+const SIGNED_PROPERTIES_TYPE: &str = "http://uri.etsi.org/01903#SignedProperties";
+
+const RELATIONSHIPS_NAMESPACE: &str = "http://schemas.openxmlformats.org/package/2006/relationships";
+const ORIGIN_RELATIONSHIP: &str = "http://schemas.openxmlformats.org/package/2006/relationships/digital-signature/origin";
+const SIGNATURE_RELATIONSHIP: &str = "http://schemas.openxmlformats.org/package/2006/relationships/digital-signature/signature";
+const CONTENT_TYPES_NAMESPACE: &str = "http://schemas.openxmlformats.org/package/2006/content-types";
+const ORIGIN_CONTENT_TYPE: &str = "application/vnd.openxmlformats-package.digital-signature-origin";
+const SIGNATURE_CONTENT_TYPE: &str = "application/vnd.openxmlformats-package.digital-signature-xmlsignature+xml";
+
+/// The part listing the content types of the other parts, which package
+/// signatures cannot cover.
+const CONTENT_TYPES: &str = "[Content_Types].xml";
+
+/// The folder holding the signatures of a package and their relationships.
+const SIGNATURES: &str = "_xmlsignatures/";
+
+/// The part the signatures of a package hang off.
+const ORIGIN: &str = "_xmlsignatures/origin.sigs";
+const ORIGIN_RELATIONSHIPS: &str = "_xmlsignatures/_rels/origin.sigs.rels";
+
+#[derive(Error, Debug)]
+pub enum SignatureError {
+    #[error("signing failed: {0}")]
+    Signer(String),
+
+    #[error("invalid part name {0}")]
+    InvalidPartName(String),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// SignerIdentity is who a signature names as its signer: the subject and
+/// issuer of their certificate, its serial number and the certificate
+/// itself, DER encoded. Signatures with shared keys have no certificate.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SignerIdentity {
+    pub subject: String,
+    pub issuer: String,
+    pub serial_number: String,
+    pub certificate: Vec<u8>,
+}
+
+/// Signer signs packages with a private key. Implement it on top of an RSA
+/// library or a hardware token to write signatures Excel checks; the key
+/// never has to leave the signer.
+pub trait Signer {
+    fn identity(&self) -> SignerIdentity;
+
+    /// The signature method, as its XML-DSig algorithm URI.
+    fn algorithm(&self) -> &str {
+        RSA_SHA256
+    }
+
+    /// Sign the canonical `SignedInfo` of a signature, which for
+    /// `RSA_SHA256` is an RSASSA-PKCS1-v1_5 signature of its SHA-256
+    /// digest.
+    fn sign(&self, signed_info: &[u8]) -> Result<Vec<u8>, SignatureError>;
+}
+
+/// Verifier checks signature values against the certificates of their
+/// signers. It decides which signers to trust, such as by checking their
+/// certificates chain up to a trusted root and name the subject they
+/// claim, as the subject is not covered by the signature.
+pub trait Verifier {
+    /// Whether `signature` is a valid signature of the canonical
+    /// `SignedInfo` with the signature method and the key of the signer.
+    /// Methods the verifier does not know fail.
+    fn verify(&self, signer: &SignerIdentity, algorithm: &str, signed_info: &[u8], signature: &[u8]) -> bool;
+}
+
+/// SharedKey signs and verifies with an HMAC under a key both sides hold,
+/// to sign reports distributed within one organization without
+/// certificates. Excel does not check HMAC signatures.
+#[derive(Debug, Clone)]
+pub struct SharedKey {
+    key: Vec<u8>,
+    identity: SignerIdentity,
+}
+
+impl SharedKey {
+    pub fn new(key: impl Into<Vec<u8>>, identity: SignerIdentity) -> Self {
+        Self{key: key.into(), identity}
+    }
+}
+
+impl Signer for SharedKey {
+    fn identity(&self) -> SignerIdentity {
+        self.identity.clone()
+    }
+
+    fn algorithm(&self) -> &str {
+        HMAC_SHA256
+    }
+
+    fn sign(&self, signed_info: &[u8]) -> Result<Vec<u8>, SignatureError> {
+        Ok(hmac_sha256(&self.key, signed_info).to_vec())
+    }
+}
+
+impl Verifier for SharedKey {
+    fn verify(&self, _signer: &SignerIdentity, algorithm: &str, signed_info: &[u8], signature: &[u8]) -> bool {
+        let expected = hmac_sha256(&self.key, signed_info);
+        // Compare every byte, so the time taken tells nothing of the key.
+        algorithm == HMAC_SHA256 && signature.len() == expected.len()
+            && signature.iter().zip(expected).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+    }
+}
+
+/// SignatureStatus is what verifying a signature found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureStatus {
+    Valid,
+    /// Parts were changed, added or removed since signing, by name.
+    Modified(Vec<String>),
+    /// The signature does not match what it signs or the certificate of its
+    /// signer, or the verifier rejected it.
+    Invalid(String),
+    /// The signature uses what verification does not support, such as
+    /// another canonicalization or digest method.
+    Unsupported(String),
+}
+
+/// PackageSignature is a signature of a package and what verifying it found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageSignature {
+    /// The part holding the signature, like `_xmlsignatures/sig1.xml`.
+    pub part: String,
+    pub signer: SignerIdentity,
+    pub algorithm: String,
+    /// The time the signer claims to have signed at, in UTC.
+    pub signing_time: Option<NaiveDateTime>,
+    pub status: SignatureStatus,
+}
+
+impl PackageSignature {
+    pub fn is_valid(&self) -> bool {
+        self.status == SignatureStatus::Valid
+    }
+}
+
+/// Signatures is the signatures of a package, in the order of their parts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Signatures {
+    signatures: Vec<PackageSignature>,
+}
+
+impl Signatures {
+    pub fn iter(&self) -> impl Iterator<Item=&PackageSignature> {
+        self.signatures.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.signatures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.signatures.is_empty()
+    }
+
+    /// Whether the package is signed and every signature is valid.
+    pub fn all_valid(&self) -> bool {
+        !self.is_empty() && self.iter().all(PackageSignature::is_valid)
+    }
+}
+
+/// Package is the parts of an OOXML package, such as an XLSX file, by their
+/// names in the package, like `xl/workbook.xml`. Zipping and unzipping the
+/// parts is left to the caller.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Package {
+    parts: BTreeMap<String, Vec<u8>>,
+}
+
+impl Package {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a part, or replace it, and get the data it had. A leading `/`
+    /// of the name is dropped.
+    pub fn insert(&mut self, name: &str, data: impl Into<Vec<u8>>) -> Option<Vec<u8>> {
+        self.parts.insert(name.trim_start_matches('/').to_string(), data.into())
+    }
+
+    pub fn part(&self, name: &str) -> Option<&[u8]> {
+        self.parts.get(name.trim_start_matches('/')).map(Vec::as_slice)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Vec<u8>> {
+        self.parts.remove(name.trim_start_matches('/'))
+    }
+
+    /// Iterate the names of the parts in order.
+    pub fn part_names(&self) -> impl Iterator<Item=&str> {
+        self.parts.keys().map(String::as_str)
+    }
+
+    /// Read the files of an unzipped package, every file under `dir` being
+    /// a part.
+    pub fn read_dir(dir: impl AsRef<Path>) -> Result<Self, SignatureError> {
+        let mut package = Self::new();
+        let mut pending = vec![(dir.as_ref().to_path_buf(), String::new())];
+        while let Some((path, prefix)) = pending.pop() {
+            for entry in fs::read_dir(&path)? {
+                let entry = entry?;
+                let name = entry.file_name().into_string()
+                    .map_err(|name| SignatureError::InvalidPartName(name.to_string_lossy().into_owned()))?;
+                let name = format!("{}{}", prefix, name);
+                match entry.file_type()?.is_dir() {
+                    true => pending.push((entry.path(), format!("{}/", name))),
+                    false => {
+                        package.parts.insert(name, fs::read(entry.path())?);
+                    },
+                }
+            }
+        }
+        Ok(package)
+    }
+
+    /// Write the parts as files under `dir`, unzipped. Parts whose names
+    /// would leave `dir` are refused.
+    pub fn write_dir(&self, dir: impl AsRef<Path>) -> Result<(), SignatureError> {
+        for (name, data) in &self.parts {
+            let relative = Path::new(name);
+            if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+                return Err(SignatureError::InvalidPartName(name.clone()));
+            }
+            let path = dir.as_ref().join(relative);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, data)?;
+        }
+        Ok(())
+    }
+
+    /// The parts a package signature covers: all but the signatures and the
+    /// content types.
+    fn signed_parts(&self) -> impl Iterator<Item=(&str, &[u8])> {
+        self.parts.iter()
+            .filter(|(name, _)| name.as_str() != CONTENT_TYPES && !name.starts_with(SIGNATURES))
+            .map(|(name, data)| (name.as_str(), data.as_slice()))
+    }
+
+    /// Sign the package at a time, in UTC, adding a signature part like
+    /// `_xmlsignatures/sig1.xml` with the relationships and content types
+    /// it needs, and get the name of the part. Signatures are XML-DSig with
+    /// XAdES signed properties naming the certificate of the signer. A
+    /// signature covers every part but the content types and the other
+    /// signatures, so a package may be signed several times.
+    pub fn sign(&mut self, signer: &dyn Signer, signing_time: NaiveDateTime) -> Result<String, SignatureError> {
+        if !self.parts.contains_key(ORIGIN) {
+            self.parts.insert(ORIGIN.to_string(), Vec::new());
+        }
+        self.add_relationship("_rels/.rels", ORIGIN_RELATIONSHIP, ORIGIN);
+        self.add_content_type("Default", "Extension=\"sigs\"", ORIGIN_CONTENT_TYPE);
+
+        let number = (1..).find(|number| !self.parts.contains_key(&format!("{}sig{}.xml", SIGNATURES, number))).expect("a free number");
+        let part = format!("{}sig{}.xml", SIGNATURES, number);
+        let identity = signer.identity();
+
+        let mut manifest = String::new();
+        for (name, data) in self.signed_parts() {
+            manifest.push_str(&format!("<Reference URI=\"/{}\">{}</Reference>", escape_attribute(name), digest_of(data)));
+        }
+        let object = format!("<Object xmlns=\"{}\" Id=\"idPackageObject\"><Manifest>{}</Manifest></Object>", DSIG_NAMESPACE, manifest);
+        let signed_properties = format!(
+            concat!(
+                "<xd:SignedProperties xmlns=\"{}\" xmlns:xd=\"{}\" Id=\"idSignedProperties\"><xd:SignedSignatureProperties>",
+                "<xd:SigningTime>{}</xd:SigningTime><xd:SigningCertificate><xd:Cert><xd:CertDigest>{}</xd:CertDigest>",
+                "<xd:IssuerSerial><X509IssuerName>{}</X509IssuerName><X509SerialNumber>{}</X509SerialNumber></xd:IssuerSerial>",
+                "</xd:Cert></xd:SigningCertificate></xd:SignedSignatureProperties></xd:SignedProperties>",
+            ),
+            DSIG_NAMESPACE, XADES_NAMESPACE, signing_time.format("%Y-%m-%dT%H:%M:%SZ"), digest_of(&identity.certificate),
+            escape_text(&identity.issuer), escape_text(&identity.serial_number),
+        );
+        let signed_info = format!(
+            concat!(
+                "<SignedInfo xmlns=\"{}\"><CanonicalizationMethod Algorithm=\"{}\"></CanonicalizationMethod>",
+                "<SignatureMethod Algorithm=\"{}\"></SignatureMethod>",
+                "<Reference Type=\"{}\" URI=\"#idPackageObject\">{}</Reference>",
+                "<Reference Type=\"{}\" URI=\"#idSignedProperties\"><Transforms><Transform Algorithm=\"{}\"></Transform></Transforms>{}</Reference>",
+                "</SignedInfo>",
+            ),
+            DSIG_NAMESPACE, C14N, escape_attribute(signer.algorithm()),
+            OBJECT_TYPE, digest_of(object.as_bytes()),
+            SIGNED_PROPERTIES_TYPE, C14N, digest_of(signed_properties.as_bytes()),
+        );
+        let value = signer.sign(signed_info.as_bytes())?;
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n");
+        xml.push_str(&format!("<Signature xmlns=\"{}\" Id=\"idPackageSignature\">", DSIG_NAMESPACE));
+        xml.push_str(&signed_info);
+        xml.push_str(&format!("<SignatureValue>{}</SignatureValue>", base64(&value)));
+        xml.push_str(&format!(
+            "<KeyInfo><X509Data><X509SubjectName>{}</X509SubjectName><X509Certificate>{}</X509Certificate></X509Data></KeyInfo>",
+            escape_text(&identity.subject),
+            base64(&identity.certificate),
+        ));
+        xml.push_str(&object);
+        xml.push_str(&format!("<Object><xd:QualifyingProperties xmlns:xd=\"{}\" Target=\"#idPackageSignature\">", XADES_NAMESPACE));
+        xml.push_str(&signed_properties);
+        xml.push_str("</xd:QualifyingProperties></Object></Signature>\n");
+
+        self.parts.insert(part.clone(), xml.into_bytes());
+        self.add_relationship(ORIGIN_RELATIONSHIPS, SIGNATURE_RELATIONSHIP, &format!("sig{}.xml", number));
+        self.add_content_type("Override", &format!("PartName=\"/{}\"", part), SIGNATURE_CONTENT_TYPE);
+        Ok(part)
+    }
+
+    /// Add a relationship to a relationships part unless it has one to the
+    /// target, creating the part if there is none.
+    fn add_relationship(&mut self, rels: &str, kind: &str, target: &str) {
+        let mut xml = match self.parts.get(rels).map(|data| String::from_utf8_lossy(data).into_owned()) {
+            Some(xml) if xml.contains("</Relationships>") => xml,
+            _ => format!("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n<Relationships xmlns=\"{}\"></Relationships>", RELATIONSHIPS_NAMESPACE),
+        };
+        if xml.contains(&format!("Target=\"{}\"", target)) {
+            return;
+        }
+        let id = (1..).map(|number| format!("rId{}", number)).find(|id| !xml.contains(&format!("Id=\"{}\"", id))).expect("a free ID");
+        let relationship = format!("<Relationship Id=\"{}\" Type=\"{}\" Target=\"{}\"/>", id, kind, target);
+        let end = xml.rfind("</Relationships>").expect("relationships were checked");
+        xml.insert_str(end, &relationship);
+        self.parts.insert(rels.to_string(), xml.into_bytes());
+    }
+
+    /// Add a `Default` or `Override` content type unless there is one for
+    /// its extension or part name.
+    fn add_content_type(&mut self, element: &str, key: &str, content_type: &str) {
+        let mut xml = match self.parts.get(CONTENT_TYPES).map(|data| String::from_utf8_lossy(data).into_owned()) {
+            Some(xml) if xml.contains("</Types>") => xml,
+            _ => format!("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n<Types xmlns=\"{}\"></Types>", CONTENT_TYPES_NAMESPACE),
+        };
+        if xml.contains(key) {
+            return;
+        }
+        let end = xml.rfind("</Types>").expect("content types were checked");
+        xml.insert_str(end, &format!("<{} {} ContentType=\"{}\"/>", element, key, content_type));
+        self.parts.insert(CONTENT_TYPES.to_string(), xml.into_bytes());
+    }
+
+    /// Verify the signatures of the package with a verifier. Digests are
+    /// checked here and signature values by the verifier. Verification
+    /// canonicalizes the elements a signature references by adding the
+    /// namespaces they inherit and ordering the attributes of their root,
+    /// which suffices for signatures written in canonical form, like those
+    /// `sign` writes; signatures with other transforms, self-closing tags or
+    /// comments in what they sign are reported as unsupported rather than
+    /// invalid.
+    pub fn signatures(&self, verifier: &dyn Verifier) -> Signatures {
+        let signatures = self.parts.iter()
+            .filter(|(name, _)| name.starts_with(SIGNATURES) && name.ends_with(".xml") && !name.contains("/_rels/"))
+            .map(|(name, data)| self.verify(name, data, verifier))
+            .collect();
+        Signatures{signatures}
+    }
+
+    fn verify(&self, part: &str, data: &[u8], verifier: &dyn Verifier) -> PackageSignature {
+        let mut signature = PackageSignature{
+            part: part.to_string(),
+            signer: SignerIdentity::default(),
+            algorithm: String::new(),
+            signing_time: None,
+            status: SignatureStatus::Valid,
+        };
+        let Ok(xml) = std::str::from_utf8(data) else {
+            signature.status = SignatureStatus::Unsupported("the signature is not UTF-8".to_string());
+            return signature;
+        };
+        let tags = tags(xml);
+        let text = |name: &str| element(&tags, name, None).map(|element| unescape_xml(element.inner(xml)));
+        signature.signer = SignerIdentity{
+            subject: text("X509SubjectName").unwrap_or_default(),
+            certificate: text("X509Certificate").and_then(|certificate| from_base64(&certificate)).unwrap_or_default(),
+            ..SignerIdentity::default()
+        };
+        signature.status = match self.check(xml, &tags, &mut signature, verifier) {
+            Ok(status) | Err(status) => status,
+        };
+        signature
+    }
+
+    /// Check a signature, stopping at the first thing wrong with it.
+    fn check(&self, xml: &str, tags: &[Tag], signature: &mut PackageSignature, verifier: &dyn Verifier) -> Result<SignatureStatus, SignatureStatus> {
+        let unsupported = |reason: &str| SignatureStatus::Unsupported(reason.to_string());
+        let invalid = |reason: &str| SignatureStatus::Invalid(reason.to_string());
+        let signed_info = element(tags, "SignedInfo", None).ok_or_else(|| unsupported("the signature has no SignedInfo"))?;
+        let method = |element: &Element, name: &str| within(tags, element, name).first().and_then(|tag| tag.attribute("Algorithm"));
+        if method(&signed_info, "CanonicalizationMethod").as_deref() != Some(C14N) {
+            return Err(unsupported("the signature is not canonicalized with C14N"));
+        }
+        signature.algorithm = method(&signed_info, "SignatureMethod").unwrap_or_default();
+
+        let mut manifests = Vec::new();
+        let mut properties = None;
+        for reference in within(tags, &signed_info, "Reference") {
+            let reference = element_at(tags, reference).ok_or_else(|| unsupported("a reference is not closed"))?;
+            if within(tags, &reference, "Transform").iter().any(|tag| tag.attribute("Algorithm").as_deref() != Some(C14N)) {
+                return Err(unsupported("a reference is transformed other than with C14N"));
+            }
+            let uri = tags.iter().find(|tag| tag.start == reference.start).and_then(|tag| tag.attribute("URI")).unwrap_or_default();
+            let Some(id) = uri.strip_prefix('#') else { return Err(unsupported("a reference is not to an element of the signature")) };
+            let target = element(tags, "", Some(id)).ok_or_else(|| invalid("a referenced element is missing"))?;
+            let canonical = canonical(xml, tags, &target).ok_or_else(|| unsupported("a referenced element is not in canonical form"))?;
+            if digest_differs(xml, tags, &reference, canonical.as_bytes())? {
+                return Err(invalid("the signature was changed since signing"));
+            }
+            manifests.extend(within(tags, &target, "Manifest").into_iter().filter_map(|tag| element_at(tags, tag)));
+            if tags.iter().any(|tag| tag.start == target.start && tag.name == "SignedProperties") {
+                properties = Some(target);
+            }
+        }
+        if manifests.is_empty() {
+            return Err(unsupported("the signature covers no parts of the package"));
+        }
+
+        // Only what the signature covers names the signer and the time.
+        if let Some(properties) = properties {
+            let text = |name: &str| within(tags, &properties, name).first()
+                .and_then(|tag| element_at(tags, tag))
+                .map(|element| unescape_xml(element.inner(xml)));
+            signature.signer.issuer = text("X509IssuerName").unwrap_or_default();
+            signature.signer.serial_number = text("X509SerialNumber").unwrap_or_default();
+            signature.signing_time = text("SigningTime")
+                .and_then(|time| NaiveDateTime::parse_from_str(time.trim_end_matches('Z'), "%Y-%m-%dT%H:%M:%S").ok());
+            let certificate = within(tags, &properties, "CertDigest").first().and_then(|tag| element_at(tags, tag));
+            if let Some(certificate) = certificate {
+                if digest_differs(xml, tags, &certificate, &signature.signer.certificate)? {
+                    return Err(invalid("the certificate is not the one signed for"));
+                }
+            }
+        }
+        let value = element(tags, "SignatureValue", None)
+            .and_then(|value| from_base64(&unescape_xml(value.inner(xml))))
+            .ok_or_else(|| invalid("the signature has no value"))?;
+        let signed_info = canonical(xml, tags, &signed_info).ok_or_else(|| unsupported("the SignedInfo is not in canonical form"))?;
+        if !verifier.verify(&signature.signer, &signature.algorithm, signed_info.as_bytes(), &value) {
+            return Err(invalid("the signature value does not verify"));
+        }
+
+        let mut covered = Vec::new();
+        let mut modified = Vec::new();
+        for manifest in &manifests {
+            for reference in within(tags, manifest, "Reference") {
+                let reference = element_at(tags, reference).ok_or_else(|| unsupported("a reference is not closed"))?;
+                let uri = tags.iter().find(|tag| tag.start == reference.start).and_then(|tag| tag.attribute("URI")).unwrap_or_default();
+                // Excel adds the content type of a part to its URI.
+                let name = uri.split('?').next().unwrap_or_default().trim_start_matches('/').to_string();
+                match self.parts.get(&name) {
+                    Some(data) if !digest_differs(xml, tags, &reference, data)? => {},
+                    _ => modified.push(name.clone()),
+                }
+                covered.push(name);
+            }
+        }
+        modified.extend(self.signed_parts().map(|(name, _)| name).filter(|name| !covered.iter().any(|covered| covered == name)).map(str::to_string));
+        if !modified.is_empty() {
+            modified.sort();
+            return Ok(SignatureStatus::Modified(modified));
+        }
+        Ok(SignatureStatus::Valid)
+    }
+}
+
+/// Element is an element of an XML document, from the start of its start
+/// tag to the end of its end tag.
+struct Element {
+    start: usize,
+    /// The end of the start tag and the start of the end tag.
+    inner_start: usize,
+    inner_end: usize,
+    end: usize,
+}
+
+impl Element {
+    fn inner<'a>(&self, xml: &'a str) -> &'a str {
+        &xml[self.inner_start..self.inner_end]
+    }
+}
+
+/// Find the first element with a name without its namespace prefix, or
+/// any name if empty, and an `Id`, if one is given.
+fn element(tags: &[Tag], name: &str, id: Option<&str>) -> Option<Element> {
+    let tag = tags.iter().find(|tag| {
+        !tag.closing && (name.is_empty() || tag.name == name) && id.is_none_or(|id| tag.attribute("Id").as_deref() == Some(id))
+    })?;
+    element_at(tags, tag)
+}
+
+/// Get the start tags of the elements with a name within an element.
+fn within<'a>(tags: &'a [Tag<'a>], element: &Element, name: &str) -> Vec<&'a Tag<'a>> {
+    tags.iter().filter(|tag| tag.name == name && !tag.closing && tag.start > element.start && tag.end <= element.end).collect()
+}
+
+/// Find the element a start tag opens.
+fn element_at(tags: &[Tag], start: &Tag) -> Option<Element> {
+    if start.self_closing {
+        return Some(Element{start: start.start, inner_start: start.end, inner_end: start.end, end: start.end});
+    }
+    let mut depth = 0;
+    for tag in tags.iter().filter(|tag| tag.start >= start.start && tag.name == start.name && !tag.self_closing) {
+        depth += if tag.closing { -1 } else { 1 };
+        if depth == 0 {
+            return Some(Element{start: start.start, inner_start: start.end, inner_end: tag.start, end: tag.end});
+        }
+    }
+    None
+}
+
+/// Check the `DigestMethod` and `DigestValue` of a reference against data,
+/// and get whether the data has another digest.
+fn digest_differs(xml: &str, tags: &[Tag], reference: &Element, data: &[u8]) -> Result<bool, SignatureStatus> {
+    let inside = |name: &str| tags.iter().find(|tag| tag.name == name && !tag.closing && tag.start > reference.start && tag.end <= reference.end);
+    if inside("DigestMethod").and_then(|tag| tag.attribute("Algorithm")).as_deref() != Some(SHA256) {
+        return Err(SignatureStatus::Unsupported("a digest is not SHA-256".to_string()));
+    }
+    let value = inside("DigestValue").and_then(|tag| element_at(tags, tag)).map(|value| unescape_xml(value.inner(xml)));
+    Ok(value.and_then(|value| from_base64(&value)).as_deref() != Some(sha256(data).as_slice()))
+}
+
+/// Get an element in canonical form, with the namespaces it inherits
+/// declared on its start tag and the attributes of its start tag ordered,
+/// or None if what it contains is not already canonical.
+fn canonical(xml: &str, tags: &[Tag], element: &Element) -> Option<String> {
+    let inner = element.inner(xml);
+    if inner.contains("/>") || inner.contains("<!--") || inner.contains("<?") || inner.contains("<![CDATA[") || inner.contains('\r') {
+        return None;
+    }
+    // The start tags enclosing the element, outermost first.
+    let mut open = Vec::new();
+    for tag in tags.iter().take_while(|tag| tag.start < element.start) {
+        match (tag.closing, tag.self_closing) {
+            (true, _) => {
+                open.pop();
+            },
+            (false, false) => open.push(tag),
+            (false, true) => {},
+        }
+    }
+    let start = &xml[element.start + 1..element.inner_start - 1];
+    let (qualified, own) = start.split_once(char::is_whitespace).unwrap_or((start, ""));
+    let is_namespace = |key: &str| key == "xmlns" || key.starts_with("xmlns:");
+    // Declarations nearer the element win, and the default namespace sorts
+    // before prefixed ones.
+    let namespaces = open.iter().flat_map(|tag| attributes_of(tag.attributes)).chain(attributes_of(own))
+        .filter(|(key, _)| is_namespace(key))
+        .collect::<BTreeMap<_, _>>();
+    let mut attributes = attributes_of(own).into_iter().filter(|(key, _)| !is_namespace(key)).collect::<Vec<_>>();
+    attributes.sort_by(|(a, _), (b, _)| (a.contains(':'), a).cmp(&(b.contains(':'), b)));
+    let mut canonical = format!("<{}", qualified);
+    for (key, value) in namespaces.iter().chain(attributes.iter().map(|(key, value)| (key, value))) {
+        canonical.push_str(&format!(" {}=\"{}\"", key, escape_attribute(value)));
+    }
+    canonical.push('>');
+    canonical.push_str(inner);
+    canonical.push_str(&format!("</{}>", qualified));
+    Some(canonical)
+}
+
+/// Split the attributes of a start tag into names and unescaped values.
+fn attributes_of(attributes: &str) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let mut rest = attributes;
+    while let Some(equals) = rest.find('=') {
+        let key = rest[..equals].trim().to_string();
+        let value = rest[equals + 1..].trim_start();
+        let Some(quote) = value.chars().next() else { break };
+        let value = &value[1..];
+        let Some(close) = value.find(quote) else { break };
+        pairs.push((key, unescape_xml(&value[..close])));
+        rest = &value[close + 1..];
+    }
+    pairs
+}
+
+/// Escape text the way canonical XML does.
+fn escape_text(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('\r', "&#xD;")
+}
+
+/// Escape an attribute value the way canonical XML does.
+fn escape_attribute(value: &str) -> String {
+    value.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('"', "&quot;")
+        .replace('\t', "&#x9;")
+        .replace('\n', "&#xA;")
+        .replace('\r', "&#xD;")
+}
+
+/// Get the `DigestMethod` and `DigestValue` of data.
+fn digest_of(data: &[u8]) -> String {
+    format!("<DigestMethod Algorithm=\"{}\"></DigestMethod><DigestValue>{}</DigestValue>", SHA256, base64(&sha256(data)))
+}
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Hash data with SHA-256.
+pub(crate) fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut state: [u32; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for block in padded.chunks_exact(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().expect("words have 4 bytes"));
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(choice).wrapping_add(ROUND_CONSTANTS[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(majority);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (word, add) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+    let mut digest = [0; 32];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+/// Authenticate data with HMAC-SHA-256 under a key.
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    match key.len() > block.len() {
+        true => block[..32].copy_from_slice(&sha256(key)),
+        false => block[..key.len()].copy_from_slice(key),
+    }
+    let mut inner = block.iter().map(|byte| byte ^ 0x36).collect::<Vec<_>>();
+    inner.extend_from_slice(data);
+    let mut outer = block.iter().map(|byte| byte ^ 0x5c).collect::<Vec<_>>();
+    outer.extend_from_slice(&sha256(&inner));
+    sha256(&outer)
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub(crate) fn base64(data: &[u8]) -> String {
+    let mut text = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => text.push(BASE64[(bits >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => text.push('='),
+            }
+        }
+    }
+    text
+}
+
+/// Decode base64, skipping whitespace, as certificates in XML are often
+/// wrapped. Get None if the text is not base64.
+pub(crate) fn from_base64(text: &str) -> Option<Vec<u8>> {
+    let digits = text.bytes().filter(|byte| !byte.is_ascii_whitespace()).collect::<Vec<_>>();
+    if digits.len() % 4 != 0 {
+        return None;
+    }
+    let mut data = Vec::with_capacity(digits.len() / 4 * 3);
+    for (index, chunk) in digits.chunks(4).enumerate() {
+        let last = index + 1 == digits.len() / 4;
+        let padding = chunk.iter().rev().take_while(|byte| **byte == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut bits = 0u32;
+        for byte in &chunk[..4 - padding] {
+            let value = BASE64.iter().position(|digit| digit == byte)? as u32;
+            bits = bits << 6 | value;
+        }
+        bits <<= 6 * padding as u32;
+        data.extend_from_slice(&bits.to_be_bytes()[1..4 - padding]);
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn package() -> Package {
+        let mut package = Package::new();
+        package.insert(CONTENT_TYPES, "<?xml version=\"1.0\"?><Types xmlns=\"http://schemas.openxmlformats.org/package/2006/content-types\"></Types>");
+        package.insert("_rels/.rels", "<?xml version=\"1.0\"?><Relationships xmlns=\"http://schemas.openxmlformats.org/package/2006/relationships\"><Relationship Id=\"rId1\" Type=\"officeDocument\" Target=\"xl/workbook.xml\"/></Relationships>");
+        package.insert("/xl/workbook.xml", "<workbook/>");
+        package.insert("xl/worksheets/sheet1.xml", "<worksheet><c r=\"A1\"><v>42</v></c></worksheet>");
+        package
+    }
+
+    fn key() -> SharedKey {
+        let identity = SignerIdentity{subject: "CN=Finance".to_string(), issuer: "CN=Example CA & Co".to_string(), serial_number: "1234".to_string(), certificate: vec![1, 2, 3]};
+        SharedKey::new(b"secret".to_vec(), identity)
+    }
+
+    fn noon() -> NaiveDateTime {
+        NaiveDateTime::parse_from_str("2024-03-31 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn status(package: &Package, verifier: &dyn Verifier) -> Vec<SignatureStatus> {
+        package.signatures(verifier).iter().map(|signature| signature.status.clone()).collect()
+    }
+
+    #[test]
+    fn digests_match_reference_vectors() {
+        assert_eq!(hex(&sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")), "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+        assert_eq!(hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        assert_eq!(hex(&hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First")), "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+    }
+
+    #[test]
+    fn base64_round_trips() {
+        for (data, text) in [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foob", "Zm9vYg=="), ("fooba", "Zm9vYmE="), ("foobar", "Zm9vYmFy")] {
+            assert_eq!(base64(data.as_bytes()), text);
+            assert_eq!(from_base64(text).as_deref(), Some(data.as_bytes()));
+        }
+        assert_eq!(from_base64("Zm9v\n YmFy").as_deref(), Some(b"foobar".as_slice()));
+        for invalid in ["Zm9", "Z===", "Zg==Zm9v", "Zm9*"] {
+            assert!(from_base64(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn signed_packages_verify() {
+        let mut package = package();
+        let part = package.sign(&key(), noon()).unwrap();
+        assert_eq!(part, "_xmlsignatures/sig1.xml");
+        let signatures = package.signatures(&key());
+        assert!(signatures.all_valid(), "{:?}", signatures);
+        let signature = signatures.iter().next().unwrap();
+        assert_eq!(signature.signer, key().identity());
+        assert_eq!(signature.algorithm, HMAC_SHA256);
+        assert_eq!(signature.signing_time, Some(noon()));
+
+        let rels = String::from_utf8(package.part("_rels/.rels").unwrap().to_vec()).unwrap();
+        assert!(rels.contains("Id=\"rId2\"") && rels.contains(ORIGIN_RELATIONSHIP));
+        let types = String::from_utf8(package.part(CONTENT_TYPES).unwrap().to_vec()).unwrap();
+        assert!(types.contains("Extension=\"sigs\"") && types.contains("PartName=\"/_xmlsignatures/sig1.xml\""));
+        assert!(Package::new().signatures(&key()).is_empty() && !Package::new().signatures(&key()).all_valid());
+    }
+
+    #[test]
+    fn changed_parts_are_reported() {
+        let mut package = package();
+        package.sign(&key(), noon()).unwrap();
+        let mut changed = package.clone();
+        changed.insert("xl/worksheets/sheet1.xml", "<worksheet><c r=\"A1\"><v>43</v></c></worksheet>");
+        changed.insert("xl/extra.xml", "<extra/>");
+        changed.remove("xl/workbook.xml");
+        let expected = ["xl/extra.xml", "xl/workbook.xml", "xl/worksheets/sheet1.xml"].map(str::to_string).to_vec();
+        assert_eq!(status(&changed, &key()), vec![SignatureStatus::Modified(expected)]);
+
+        // Content types are not signed, so changing them keeps signatures valid.
+        let mut types = package.clone();
+        types.insert(CONTENT_TYPES, "<Types></Types>");
+        assert!(types.signatures(&key()).all_valid());
+    }
+
+    #[test]
+    fn forged_signatures_are_invalid() {
+        let mut package = package();
+        package.sign(&key(), noon()).unwrap();
+        let other = SharedKey::new(b"guess".to_vec(), key().identity());
+        assert!(matches!(&status(&package, &other)[..], [SignatureStatus::Invalid(_)]));
+
+        let xml = String::from_utf8(package.part("_xmlsignatures/sig1.xml").unwrap().to_vec()).unwrap();
+        let mut forged = package.clone();
+        forged.insert("_xmlsignatures/sig1.xml", xml.replace("2024-03-31T12:00:00Z", "2024-01-01T12:00:00Z"));
+        assert!(matches!(&status(&forged, &key())[..], [SignatureStatus::Invalid(_)]));
+        forged.insert("_xmlsignatures/sig1.xml", xml.replace(&base64(&[1, 2, 3]), &base64(&[4, 5, 6])));
+        assert!(matches!(&status(&forged, &key())[..], [SignatureStatus::Invalid(_)]));
+        forged.insert("_xmlsignatures/sig1.xml", xml.replace(C14N, "http://www.w3.org/2001/10/xml-exc-c14n#"));
+        assert!(matches!(&status(&forged, &key())[..], [SignatureStatus::Unsupported(_)]));
+        forged.insert("_xmlsignatures/sig1.xml", xml.replace("></DigestMethod>", "/>"));
+        assert!(matches!(&status(&forged, &key())[..], [SignatureStatus::Unsupported(_)]));
+    }
+
+    #[test]
+    fn packages_are_signed_several_times() {
+        let mut package = package();
+        package.sign(&key(), noon()).unwrap();
+        let second = SharedKey::new(b"auditor".to_vec(), SignerIdentity{subject: "CN=Audit".to_string(), ..SignerIdentity::default()});
+        assert_eq!(package.sign(&second, noon()).unwrap(), "_xmlsignatures/sig2.xml");
+        let statuses = status(&package, &key());
+        assert!(statuses[0] == SignatureStatus::Valid && matches!(statuses[1], SignatureStatus::Invalid(_)));
+        assert_eq!(status(&package, &second)[1], SignatureStatus::Valid);
+        let rels = String::from_utf8(package.part(ORIGIN_RELATIONSHIPS).unwrap().to_vec()).unwrap();
+        assert!(rels.contains("Target=\"sig1.xml\"") && rels.contains("Target=\"sig2.xml\""));
+    }
+
+    #[test]
+    fn canonical_form_declares_inherited_namespaces() {
+        let xml = "<a:root xmlns:a=\"urn:a\" xmlns=\"urn:d\"><a:child z=\"1\" Id=\"x\" xmlns:b=\"urn:b\" a=\"&quot;\"><v>1</v></a:child></a:root>";
+        let tags = tags(xml);
+        let child = element(&tags, "", Some("x")).unwrap();
+        assert_eq!(canonical(xml, &tags, &child).unwrap(), "<a:child xmlns=\"urn:d\" xmlns:a=\"urn:a\" xmlns:b=\"urn:b\" Id=\"x\" a=\"&quot;\" z=\"1\"><v>1</v></a:child>");
+    }
+
+    #[test]
+    fn signed_packages_survive_saving() {
+        let dir = std::env::temp_dir().join(format!("xlnt-signature-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut package = package();
+        package.sign(&key(), noon()).unwrap();
+        package.write_dir(&dir).unwrap();
+        let loaded = Package::read_dir(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(loaded, package);
+        assert!(loaded.signatures(&key()).all_valid());
+
+        let mut escaping = Package::new();
+        escaping.insert("../outside.xml", "<x/>");
+        assert!(matches!(escaping.write_dir(std::env::temp_dir()), Err(SignatureError::InvalidPartName(_))));
+    }
+}
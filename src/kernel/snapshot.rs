@@ -0,0 +1,169 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{CellError, CellId, Primitive, Value};
+use super::serialize::value_to_raw_with;
+use super::settings::{CalcSettings, Precision};
+use super::workbook::{Workbook, WorkbookError};
+use super::memory::{map_bytes, value_bytes};
+use std::collections::HashMap;
+
+/// DataSnapshot is the values the cells of a workbook had at one moment,
+/// such as the last month-end close, kept under an ID so `CHANGEDSINCE`
+/// can tell which cells moved since. Cells dynamic arrays spilled into are
+/// kept with the values they displayed.
+#[derive(Debug, Clone)]
+pub struct DataSnapshot<T: Arithmetic=f64> {
+    id: String,
+    values: HashMap<(String, CellId), Value<T>>,
+}
+
+impl<T: Arithmetic> DataSnapshot<T> {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Get the value a cell of a sheet had, which is empty if the cell was.
+    pub fn value(&self, sheet: &str, cell_id: CellId) -> Value<T> {
+        self.values.get(&(sheet.to_lowercase(), cell_id)).cloned().unwrap_or(Value::Empty)
+    }
+
+    /// Get the number of cells which were not empty.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Get the bytes the values kept take on the heap.
+    pub(crate) fn heap_bytes(&self) -> usize {
+        map_bytes(&self.values) + self.id.capacity() + self.values.iter().map(|((sheet, _), value)| sheet.capacity() + value_bytes(value)).sum::<usize>()
+    }
+}
+
+/// Whether a value is the same as the one a snapshot kept. Numbers are
+/// compared with the tolerance of the settings, text case sensitively, and
+/// other values by their raw contents.
+pub(crate) fn unchanged<T: Arithmetic>(before: &Value<T>, now: &Value<T>, settings: &CalcSettings) -> bool {
+    match (before, now) {
+        (Value::Primitive(Primitive::Number(a)), Value::Primitive(Primitive::Number(b))) => settings.numbers_equal(a.value(), b.value()),
+        (Value::Error(a), Value::Error(b)) => a == b,
+        (Value::Error(_), _) | (_, Value::Error(_)) => false,
+        (before, now) => value_to_raw_with(before, Precision::RoundTrip) == value_to_raw_with(now, Precision::RoundTrip),
+    }
+}
+
+impl<T: Arithmetic> Workbook<T> {
+    /// Keep the values every cell of every sheet has now under an ID, such
+    /// as `close-2024-03`, replacing any snapshot with the same ID. IDs are
+    /// case insensitive. Get the number of cells kept.
+    pub fn take_snapshot(&mut self, id: &str) -> Result<usize, WorkbookError> {
+        let mut values = HashMap::new();
+        for (name, sheet) in self.sheets() {
+            let key = name.to_lowercase();
+            let mut cells = sheet.cells().map(|(cell_id, cell)| (cell_id, cell.formula().is_some(), cell.text().to_string())).collect::<Vec<_>>();
+            cells.sort_by_key(|(cell_id, _, _)| (cell_id.row(), cell_id.col()));
+            for (cell_id, formula, text) in cells {
+                let value = match self.evaluate_cell(name, cell_id)? {
+                    Value::Raw => Value::Primitive(Primitive::Text(text)),
+                    value => value,
+                };
+                if formula && !matches!(value, Value::Error(CellError::Spill)) {
+                    if let Value::Array(array) = self.evaluate_array(name, cell_id)? {
+                        for row in 0..array.rows() {
+                            for col in 0..array.cols() {
+                                let target = CellId::new(cell_id.row() + row as u32, cell_id.col() + col as u32);
+                                let spilled = array.get(row, col).filter(|value| target != cell_id && !matches!(value, Value::Empty));
+                                if let Some(spilled) = spilled {
+                                    values.insert((key.clone(), target), spilled.clone());
+                                }
+                            }
+                        }
+                    }
+                }
+                if !matches!(value, Value::Empty) {
+                    values.insert((key.clone(), cell_id), value);
+                }
+            }
+        }
+        let count = values.len();
+        self.remove_snapshot(id);
+        self.snapshots_mut().push(DataSnapshot{id: id.to_string(), values});
+        Ok(count)
+    }
+
+    pub fn snapshot(&self, id: &str) -> Option<&DataSnapshot<T>> {
+        self.snapshots().iter().find(|snapshot| snapshot.id.eq_ignore_ascii_case(id))
+    }
+
+    /// Drop a snapshot, and get whether there was one with the ID.
+    pub fn remove_snapshot(&mut self, id: &str) -> bool {
+        let before = self.snapshots().len();
+        self.snapshots_mut().retain(|snapshot| !snapshot.id.eq_ignore_ascii_case(id));
+        before != self.snapshots().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(cell_id: &str) -> CellId {
+        CellId::parse(cell_id).unwrap()
+    }
+
+    fn flag(workbook: &Workbook, sheet: &str, cell_id: &str) -> Option<bool> {
+        match workbook.evaluate_cell(sheet, at(cell_id)).unwrap() {
+            Value::Primitive(Primitive::Bool(b)) => Some(b),
+            _ => None,
+        }
+    }
+
+    fn workbook() -> Workbook {
+        let mut workbook: Workbook = Workbook::new();
+        workbook.add_sheet("Ledger").unwrap();
+        workbook.add_sheet("Review").unwrap();
+        for (cell_id, data) in [("A1", "100"), ("A2", "rent"), ("A3", "=A1*2"), ("C1", "=TAKE(A1:A2,2)")] {
+            workbook.set_cell("Ledger", at(cell_id), data.to_string()).unwrap();
+        }
+        workbook
+    }
+
+    #[test]
+    fn snapshots_keep_values() {
+        let mut workbook = workbook();
+        assert_eq!(workbook.take_snapshot("Close-March").unwrap(), 5);
+        let snapshot = workbook.snapshot("close-march").unwrap();
+        assert_eq!(snapshot.id(), "Close-March");
+        assert!(matches!(snapshot.value("LEDGER", at("A3")), Value::Primitive(Primitive::Number(number)) if number.value() == 200.0));
+        assert!(matches!(snapshot.value("Ledger", at("C2")), Value::Primitive(Primitive::Text(text)) if text == "rent"));
+        assert!(matches!(snapshot.value("Ledger", at("A9")), Value::Empty));
+        // Taking a snapshot again replaces it.
+        workbook.set_cell("Ledger", at("A9"), "1".to_string()).unwrap();
+        assert_eq!(workbook.take_snapshot("CLOSE-MARCH").unwrap(), 6);
+        assert_eq!(workbook.snapshots().len(), 1);
+        assert!(workbook.remove_snapshot("close-march"));
+        assert!(!workbook.remove_snapshot("close-march"));
+    }
+
+    #[test]
+    fn changed_since_compares_with_a_snapshot() {
+        let mut workbook = workbook();
+        workbook.take_snapshot("close").unwrap();
+        // Numbers within the comparison tolerance have not changed.
+        workbook.settings_mut().comparison_tolerance = Some(1e-12);
+        workbook.set_cell("Ledger", at("A1"), "100.0000000000001".to_string()).unwrap();
+        workbook.set_cell("Ledger", at("A2"), "Rent".to_string()).unwrap();
+        workbook.set_cell("Ledger", at("A4"), "5".to_string()).unwrap();
+        workbook.set_cell("Review", at("A1"), "=CHANGEDSINCE(Ledger!A1:A4,\"close\")".to_string()).unwrap();
+        assert_eq!(flag(&workbook, "Review", "A1"), Some(false));
+        assert_eq!(flag(&workbook, "Review", "A2"), Some(true));
+        assert_eq!(flag(&workbook, "Review", "A3"), Some(false));
+        assert_eq!(flag(&workbook, "Review", "A4"), Some(true));
+
+        workbook.set_cell("Ledger", at("B1"), "=CHANGEDSINCE(A1,\"missing\")".to_string()).unwrap();
+        assert!(matches!(workbook.evaluate_cell("Ledger", at("B1")).unwrap(), Value::Error(CellError::NA)));
+        workbook.set_cell("Ledger", at("B2"), "=CHANGEDSINCE(1,\"close\")".to_string()).unwrap();
+        assert!(matches!(workbook.evaluate_cell("Ledger", at("B2")).unwrap(), Value::Error(CellError::Value)));
+    }
+}
@@ -0,0 +1,175 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{CellId, Primitive, Value};
+use super::worksheet::{SheetError, Worksheet};
+
+/// SparklineKind is how a sparkline draws its data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SparklineKind {
+    Line,
+    Column,
+    /// Bars of one height up for positive numbers and down for negative.
+    WinLoss,
+}
+
+/// AxisBound is where the vertical axis of the sparklines of a group ends.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AxisBound {
+    /// The smallest or largest number of each sparkline.
+    #[default]
+    Individual,
+    /// The smallest or largest number of all sparklines of the group.
+    Group,
+    Custom(f64),
+}
+
+/// Sparkline is a small chart of the data of a range of one row or column,
+/// drawn in a host cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Sparkline {
+    pub cell_id: CellId,
+    pub first: CellId,
+    pub second: CellId,
+}
+
+/// SparklineGroup is a set of sparklines sharing a kind and axis, like the
+/// sparkline groups of spreadsheets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SparklineGroup {
+    pub kind: SparklineKind,
+    pub sparklines: Vec<Sparkline>,
+    pub min: AxisBound,
+    pub max: AxisBound,
+    /// Whether empty cells are drawn as zero instead of gaps.
+    pub empty_as_zero: bool,
+}
+
+impl SparklineGroup {
+    pub fn new(kind: SparklineKind) -> Self {
+        Self{kind, sparklines: Vec::new(), min: AxisBound::Individual, max: AxisBound::Individual, empty_as_zero: false}
+    }
+
+    /// Add a sparkline of the range between two corners drawn in a cell.
+    pub fn sparkline(mut self, cell_id: CellId, first: CellId, second: CellId) -> Self {
+        self.sparklines.push(Sparkline{cell_id, first, second});
+        self
+    }
+
+    pub fn with_bounds(mut self, min: AxisBound, max: AxisBound) -> Self {
+        self.min = min;
+        self.max = max;
+        self
+    }
+}
+
+/// SparklineSeries is the data of a sparkline ready to draw.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SparklineSeries {
+    pub kind: SparklineKind,
+    /// The numbers of the data range, None for gaps.
+    pub values: Vec<Option<f64>>,
+    /// The heights of the points between the bounds of the axis, from 0 at
+    /// `min` to 1 at `max` and clamped to those, or 0.5 if the bounds are
+    /// equal. Win/loss sparklines get 1 for wins, -1 for losses and 0
+    /// otherwise.
+    pub points: Vec<Option<f64>>,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl<T: Arithmetic> Worksheet<T> {
+    /// Get the numbers of a range for a sparkline, with None for cells
+    /// which are not numbers.
+    fn sparkline_values(&self, sparkline: &Sparkline, empty_as_zero: bool) -> Result<Vec<Option<f64>>, SheetError> {
+        let values = self.range(sparkline.first, sparkline.second).evaluate()?;
+        Ok(values.values()
+            .iter()
+            .map(|value| match value {
+                Value::Primitive(Primitive::Number(number)) => Some(number.value().to_f64()),
+                Value::Empty if empty_as_zero => Some(0.0),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Get the series of the sparkline drawn in a cell, if any.
+    pub fn sparkline_series(&self, cell_id: CellId) -> Result<Option<SparklineSeries>, SheetError> {
+        let found = self.sparklines().iter().find_map(|group| {
+            group.sparklines.iter().find(|sparkline| sparkline.cell_id == cell_id).map(|sparkline| (group, sparkline))
+        });
+        let Some((group, sparkline)) = found else { return Ok(None) };
+        let values = self.sparkline_values(sparkline, group.empty_as_zero)?;
+        let numbers = |values: &[Option<f64>]| values.iter().flatten().copied().collect::<Vec<_>>();
+        let mut group_numbers = Vec::new();
+        if matches!(group.min, AxisBound::Group) || matches!(group.max, AxisBound::Group) {
+            for other in &group.sparklines {
+                group_numbers.extend(numbers(&self.sparkline_values(other, group.empty_as_zero)?));
+            }
+        }
+        let own = numbers(&values);
+        let bound = |axis: AxisBound, pick: fn(f64, f64) -> f64| match axis {
+            AxisBound::Individual => own.iter().copied().reduce(pick).unwrap_or(0.0),
+            AxisBound::Group => group_numbers.iter().copied().reduce(pick).unwrap_or(0.0),
+            AxisBound::Custom(bound) => bound,
+        };
+        let (min, max) = (bound(group.min, f64::min), bound(group.max, f64::max));
+        let points = values.iter()
+            .map(|value| value.map(|number| match group.kind {
+                SparklineKind::WinLoss if number > 0.0 => 1.0,
+                SparklineKind::WinLoss if number < 0.0 => -1.0,
+                SparklineKind::WinLoss => 0.0,
+                _ if max > min => ((number - min) / (max - min)).clamp(0.0, 1.0),
+                _ => 0.5,
+            }))
+            .collect();
+        Ok(Some(SparklineSeries{kind: group.kind, values, points, min, max}))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::Kernel;
+
+    fn sheet() -> Worksheet {
+        let mut sheet: Worksheet = Worksheet::new();
+        for (row, cells) in [["2", "4", "", "6"], ["-1", "10", "0", "x"]].iter().enumerate() {
+            for (col, data) in cells.iter().enumerate() {
+                sheet.set_cell(CellId::new(row as u32, col as u32), data.to_string());
+            }
+        }
+        sheet
+    }
+
+    fn group(kind: SparklineKind) -> SparklineGroup {
+        SparklineGroup::new(kind)
+            .sparkline(CellId::new(0, 4), CellId::new(0, 0), CellId::new(0, 3))
+            .sparkline(CellId::new(1, 4), CellId::new(1, 0), CellId::new(1, 3))
+    }
+
+    #[test]
+    fn series_scale_points_to_their_axis() {
+        let mut sheet = sheet();
+        sheet.sparklines_mut().push(group(SparklineKind::Line));
+        let series = sheet.sparkline_series(CellId::new(0, 4)).unwrap().unwrap();
+        assert_eq!(series.values, [Some(2.0), Some(4.0), None, Some(6.0)]);
+        assert_eq!(series.points, [Some(0.0), Some(0.5), None, Some(1.0)]);
+        assert_eq!((series.min, series.max), (2.0, 6.0));
+        assert!(sheet.sparkline_series(CellId::new(2, 4)).unwrap().is_none());
+
+        sheet.sparklines_mut()[0] = group(SparklineKind::Column).with_bounds(AxisBound::Custom(0.0), AxisBound::Group);
+        sheet.sparklines_mut()[0].empty_as_zero = true;
+        let series = sheet.sparkline_series(CellId::new(0, 4)).unwrap().unwrap();
+        assert_eq!((series.min, series.max), (0.0, 10.0));
+        assert_eq!(series.points, [Some(0.2), Some(0.4), Some(0.0), Some(0.6)]);
+    }
+
+    #[test]
+    fn win_loss_series_only_keep_the_sign() {
+        let mut sheet = sheet();
+        sheet.sparklines_mut().push(group(SparklineKind::WinLoss));
+        let series = sheet.sparkline_series(CellId::new(1, 4)).unwrap().unwrap();
+        assert_eq!(series.kind, SparklineKind::WinLoss);
+        assert_eq!(series.points, [Some(-1.0), Some(1.0), Some(0.0), None]);
+        assert_eq!(sheet.duplicate().sparklines(), sheet.sparklines());
+    }
+}
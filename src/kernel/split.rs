@@ -0,0 +1,201 @@
+use super::arithmetic::Arithmetic;
+use super::csv::records;
+use super::fixed_width::{convert, FieldError, FieldType};
+use super::kernel::{CellId, Formula, Kernel, Value};
+use super::refactor::rewrite_sheet;
+use super::worksheet::{SheetError, Worksheet};
+
+/// Split is how the text of a cell is cut into fields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Split {
+    /// Fields separated by a character, with fields optionally quoted with
+    /// `"` like in CSV.
+    Delimited(char),
+    /// Fields of the given widths in characters. Text after the last width
+    /// is one more field.
+    Widths(Vec<usize>),
+}
+
+impl Split {
+    fn fields(&self, text: &str) -> Vec<String> {
+        match self {
+            Self::Delimited(delimiter) => match records(text, *delimiter) {
+                Ok(mut records) if records.len() == 1 => records.remove(0),
+                _ => text.split(*delimiter).map(str::to_string).collect(),
+            },
+            Self::Widths(widths) => {
+                let mut rest = text;
+                let mut fields = Vec::with_capacity(widths.len() + 1);
+                for width in widths {
+                    let end = rest.char_indices().nth(*width).map_or(rest.len(), |(index, _)| index);
+                    fields.push(rest[..end].to_string());
+                    rest = &rest[end..];
+                }
+                if !rest.is_empty() {
+                    fields.push(rest.to_string());
+                }
+                fields
+            },
+        }
+    }
+}
+
+/// Placement is what happens to the cells right of the split column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Placement {
+    /// The fields overwrite the cells to the right.
+    Overwrite,
+    /// The cells to the right move right, in the rows of the range, to
+    /// make room for the widest split, and references to them follow.
+    Shift,
+}
+
+/// SplitFieldError is a field which did not convert to its type. Its cell
+/// is left empty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitFieldError {
+    pub cell_id: CellId,
+    pub error: FieldError,
+}
+
+/// SplitReport summarizes a text to columns operation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SplitReport {
+    /// The number of cells split.
+    pub cells: usize,
+    /// The number of columns of the widest split.
+    pub columns: usize,
+    pub errors: Vec<SplitFieldError>,
+}
+
+impl<T: Arithmetic> Worksheet<T> {
+    /// Split the text cells of a range of one column into fields written to
+    /// the cell and the columns right of it, like the Text to Columns
+    /// command of spreadsheets. Fields are trimmed and converted to the
+    /// type at their position in `types`, or to whatever they look like
+    /// past its end. Cells holding numbers or formulas are not split.
+    pub fn text_to_columns(
+        &mut self,
+        first: CellId,
+        second: CellId,
+        split: &Split,
+        types: &[FieldType],
+        placement: Placement,
+    ) -> Result<SplitReport, SheetError> {
+        if first.col() != second.col() {
+            return Err(SheetError::MultipleColumns);
+        }
+        let col = first.col();
+        let (top, bottom) = (first.row().min(second.row()), first.row().max(second.row()));
+        let splits = (top..=bottom)
+            .filter_map(|row| {
+                let cell = self.cell(CellId::new(row, col))?;
+                matches!(cell.value(), Value::Raw).then(|| (row, split.fields(cell.text())))
+            })
+            .collect::<Vec<_>>();
+        let mut report = SplitReport{
+            cells: splits.len(),
+            columns: splits.iter().map(|(_, fields)| fields.len()).max().unwrap_or(0),
+            errors: Vec::new(),
+        };
+        if placement == Placement::Shift && report.columns > 1 {
+            self.shift_right(top, bottom, col, report.columns as u32 - 1);
+        }
+        for (row, fields) in splits {
+            for (offset, field) in fields.iter().enumerate() {
+                let cell_id = CellId::new(row, col + offset as u32);
+                let field_type = types.get(offset).copied().unwrap_or(FieldType::Any);
+                match field.trim() {
+                    "" => self.set_cell(cell_id, String::new()),
+                    field => match convert::<T>(field, field_type) {
+                        Ok(raw) => self.set_cell(cell_id, raw),
+                        Err(error) => {
+                            self.set_cell(cell_id, String::new());
+                            report.errors.push(SplitFieldError{cell_id, error});
+                        },
+                    },
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Move the cells right of `col` in rows `top` to `bottom` right by
+    /// `by` columns, with their number formats, and rewrite references on
+    /// this sheet to follow them.
+    fn shift_right(&mut self, top: u32, bottom: u32, col: u32, by: u32) {
+        let mut moved = self.cell_ids()
+            .into_iter()
+            .filter(|cell_id| (top..=bottom).contains(&cell_id.row()) && cell_id.col() > col)
+            .collect::<Vec<_>>();
+        moved.sort_by_key(|cell_id| std::cmp::Reverse(cell_id.col()));
+        for cell_id in moved {
+            let format = self.number_format(cell_id).to_string();
+            self.set_number_format(cell_id, "General");
+            let target = CellId::new(cell_id.row(), cell_id.col() + by);
+            if let Some(cell) = self.clear_cell(cell_id) {
+                self.set_cell(target, cell.raw().to_string());
+            }
+            self.set_number_format(target, &format);
+        }
+        let shifted = |cell_id: CellId| match (top..=bottom).contains(&cell_id.row()) && cell_id.col() > col {
+            true => CellId::new(cell_id.row(), cell_id.col() + by),
+            false => cell_id,
+        };
+        rewrite_sheet(self, &mut |reference| {
+            let replacement = match *reference {
+                Formula::CellRef(cell_id) if shifted(cell_id) != cell_id => Formula::CellRef(shifted(cell_id)),
+                // Ranges within the rows shifted grow or move with the cells.
+                Formula::CellRange(a, b) if a.row().min(b.row()) >= top && a.row().max(b.row()) <= bottom => {
+                    if (shifted(a), shifted(b)) == (a, b) {
+                        return false;
+                    }
+                    Formula::CellRange(shifted(a), shifted(b))
+                },
+                _ => return false,
+            };
+            *reference = replacement;
+            true
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::fixed_width::FieldType;
+
+    fn text(sheet: &Worksheet, row: u32, col: u32) -> Option<String> {
+        sheet.cell(CellId::new(row, col)).map(|cell| cell.text().to_string())
+    }
+
+    #[test]
+    fn delimited_splits_shift_the_cells_to_the_right() {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), "Smith,\"J, Jr\",42".to_string());
+        sheet.set_cell(CellId::new(1, 0), "Lee, x ,abc".to_string());
+        sheet.set_cell(CellId::new(2, 0), "7".to_string());
+        sheet.set_cell(CellId::new(0, 1), "keep".to_string());
+        sheet.set_cell(CellId::new(0, 2), "=B1".to_string());
+        let types = [FieldType::Text, FieldType::Text, FieldType::Number];
+        let report = sheet.text_to_columns(CellId::new(0, 0), CellId::new(2, 0), &Split::Delimited(','), &types, Placement::Shift).unwrap();
+        assert_eq!((report.cells, report.columns), (2, 3));
+        assert_eq!(report.errors, [SplitFieldError{cell_id: CellId::new(1, 2), error: FieldError::Mismatch("abc".to_string(), FieldType::Number)}]);
+        assert_eq!((0..5).map(|col| text(&sheet, 0, col)).collect::<Vec<_>>(), ["Smith", "J, Jr", "42", "keep", "=D1"].map(|text| Some(text.to_string())));
+        assert_eq!(text(&sheet, 1, 1).as_deref(), Some("x"));
+        assert_eq!(text(&sheet, 1, 2), None);
+        assert_eq!(text(&sheet, 2, 0).as_deref(), Some("7"));
+    }
+
+    #[test]
+    fn width_splits_overwrite_the_cells_to_the_right() {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), "ÄB123xyz".to_string());
+        sheet.set_cell(CellId::new(0, 2), "gone".to_string());
+        let report = sheet.text_to_columns(CellId::new(0, 0), CellId::new(0, 0), &Split::Widths(vec![2, 3]), &[], Placement::Overwrite).unwrap();
+        assert_eq!(report.columns, 3);
+        assert_eq!((0..3).map(|col| text(&sheet, 0, col)).collect::<Vec<_>>(), ["ÄB", "123", "xyz"].map(|text| Some(text.to_string())));
+        assert!(sheet.cell(CellId::new(0, 1)).unwrap().formula().is_none());
+        assert!(matches!(sheet.text_to_columns(CellId::new(0, 0), CellId::new(0, 1), &Split::Delimited(','), &[], Placement::Shift), Err(SheetError::MultipleColumns)));
+    }
+}
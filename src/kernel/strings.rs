@@ -0,0 +1,208 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+/// The most bytes of raw contents a cell stores inline, without allocating.
+pub const INLINE_CAPACITY: usize = 22;
+
+/// CellText is the raw contents of a cell: inline when short, otherwise on
+/// the heap, where cells holding the same text may share one copy. It
+/// takes no more room than a `String`.
+#[derive(Clone)]
+pub(crate) enum CellText {
+    Inline{len: u8, bytes: [u8; INLINE_CAPACITY]},
+    Owned(Box<str>),
+    Shared(Arc<str>),
+}
+
+impl CellText {
+    pub(crate) fn as_str(&self) -> &str {
+        match self {
+            Self::Inline{len, bytes} => std::str::from_utf8(&bytes[..*len as usize]).expect("inline text is whole characters"),
+            Self::Owned(text) => text,
+            Self::Shared(text) => text,
+        }
+    }
+
+    /// Get the bytes this text takes on the heap, the share of the cells
+    /// holding it for shared text.
+    pub(crate) fn heap_size(&self) -> usize {
+        match self {
+            Self::Inline{..} => 0,
+            Self::Owned(text) => text.len(),
+            Self::Shared(text) => text.len() / Arc::strong_count(text),
+        }
+    }
+}
+
+impl From<String> for CellText {
+    fn from(text: String) -> Self {
+        if text.len() > INLINE_CAPACITY {
+            return Self::Owned(text.into_boxed_str());
+        }
+        let mut bytes = [0; INLINE_CAPACITY];
+        bytes[..text.len()].copy_from_slice(text.as_bytes());
+        Self::Inline{len: text.len() as u8, bytes}
+    }
+}
+
+impl fmt::Debug for CellText {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// StringPool holds one copy of each long raw text deduplicated into it, to
+/// share among the cells holding that text on any sheet.
+#[derive(Debug, Clone, Default)]
+pub struct StringPool {
+    strings: HashSet<Arc<str>>,
+}
+
+impl StringPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the number of distinct texts in the pool.
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+
+    /// Share a text with the pool, and get the bytes this freed: those of
+    /// the copy replaced by the one in the pool, if any. Inline text is left
+    /// alone.
+    pub(crate) fn share(&mut self, text: &mut CellText) -> usize {
+        if matches!(text, CellText::Inline{..}) {
+            return 0;
+        }
+        let Some(pooled) = self.strings.get(text.as_str()) else {
+            let pooled = match text {
+                CellText::Shared(shared) => shared.clone(),
+                _ => Arc::<str>::from(text.as_str()),
+            };
+            self.strings.insert(pooled.clone());
+            *text = CellText::Shared(pooled);
+            return 0;
+        };
+        let freed = match text {
+            CellText::Shared(shared) if Arc::ptr_eq(shared, pooled) => return 0,
+            CellText::Shared(shared) if Arc::strong_count(shared) > 1 => 0,
+            _ => text.as_str().len(),
+        };
+        *text = CellText::Shared(pooled.clone());
+        freed
+    }
+}
+
+/// StringStats describes how the raw contents of cells are stored, and the
+/// memory inline storage and sharing save over a heap copy per cell.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StringStats {
+    /// The number of cells whose raw contents are stored inline.
+    pub inline: usize,
+    /// The number of cells with a heap copy of their own.
+    pub owned: usize,
+    /// The number of cells sharing a heap copy with other cells.
+    pub shared: usize,
+    /// The bytes of raw contents on the heap, counting shared copies once.
+    pub heap_bytes: usize,
+    /// The bytes a heap copy per cell would take on top of `heap_bytes`.
+    pub bytes_saved: usize,
+}
+
+impl StringStats {
+    /// Count the raw contents of some cells.
+    pub(crate) fn of<'a>(texts: impl IntoIterator<Item=&'a CellText>) -> Self {
+        let mut stats = Self::default();
+        let mut copies = HashMap::<*const u8, (usize, usize)>::new();
+        for text in texts {
+            match text {
+                CellText::Inline{len, ..} => {
+                    stats.inline += 1;
+                    stats.bytes_saved += *len as usize;
+                },
+                CellText::Owned(text) => {
+                    stats.owned += 1;
+                    stats.heap_bytes += text.len();
+                },
+                CellText::Shared(text) => {
+                    stats.shared += 1;
+                    copies.entry(Arc::as_ptr(text) as *const u8).or_insert((text.len(), 0)).1 += 1;
+                },
+            }
+        }
+        for (len, cells) in copies.into_values() {
+            stats.heap_bytes += len;
+            stats.bytes_saved += len * (cells - 1);
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_text_is_inline() {
+        let short = CellText::from("x".repeat(INLINE_CAPACITY));
+        assert!(matches!(short, CellText::Inline{..}));
+        assert_eq!(short.as_str(), "x".repeat(INLINE_CAPACITY));
+        assert_eq!(short.heap_size(), 0);
+        let long = CellText::from("x".repeat(INLINE_CAPACITY + 1));
+        assert!(matches!(long, CellText::Owned(_)));
+        assert_eq!(long.heap_size(), INLINE_CAPACITY + 1);
+        assert!(size_of::<CellText>() <= size_of::<String>());
+    }
+
+    #[test]
+    fn inline_text_keeps_whole_characters() {
+        let text = CellText::from("Ünïcode".to_string());
+        assert!(matches!(text, CellText::Inline{..}));
+        assert_eq!(text.as_str(), "Ünïcode");
+    }
+
+    #[test]
+    fn pool_shares_long_text() {
+        let label = "a label repeated across many cells".to_string();
+        let mut texts = [CellText::from(label.clone()), CellText::from(label.clone()), CellText::from("short".to_string())];
+        let mut pool = StringPool::new();
+        let freed: usize = texts.iter_mut().map(|text| pool.share(text)).sum();
+        assert_eq!(freed, label.len());
+        assert_eq!(pool.len(), 1);
+        assert!(matches!(texts[2], CellText::Inline{..}));
+        // Sharing again frees nothing more.
+        assert_eq!(texts.iter_mut().map(|text| pool.share(text)).sum::<usize>(), 0);
+        // The pool holds a reference of its own.
+        assert_eq!(texts[0].heap_size(), label.len() / 3);
+        assert_eq!(texts[1].as_str(), label);
+    }
+
+    #[test]
+    fn stats_count_shared_copies_once() {
+        let label = "a label repeated across many cells".to_string();
+        let mut texts = [
+            CellText::from(label.clone()),
+            CellText::from(label.clone()),
+            CellText::from(label.clone()),
+            CellText::from("another long text kept apart".to_string()),
+            CellText::from("short".to_string()),
+        ];
+        let mut pool = StringPool::new();
+        for text in &mut texts[..3] {
+            pool.share(text);
+        }
+        assert_eq!(StringStats::of(&texts), StringStats{
+            inline: 1,
+            owned: 1,
+            shared: 3,
+            heap_bytes: label.len() + "another long text kept apart".len(),
+            bytes_saved: "short".len() + 2 * label.len(),
+        });
+    }
+}
@@ -0,0 +1,343 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{CellId, Value};
+use super::sparkline::SparklineKind;
+use super::workbook::Workbook;
+use super::worksheet::Worksheet;
+use std::collections::{HashSet, VecDeque};
+
+/// TableOutline is a block of cells of a sheet bordered by empty cells, as
+/// a table of data reads to people.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableOutline {
+    /// The query or rolling window landing in the table, or `Table 1` and
+    /// so on, numbered on each sheet.
+    pub name: String,
+    pub first: CellId,
+    pub last: CellId,
+    /// The titles of the columns, if the first row of the table is a
+    /// header: text in every column over rows which are not all text.
+    pub headers: Option<Vec<String>>,
+    /// The number of rows below the header, or of all rows without one.
+    pub rows: u32,
+}
+
+/// ChartOutline is a chart drawn on a sheet. Sparklines are the charts the
+/// kernel holds; they have no titles, so they are described by their kind
+/// and cells.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChartOutline {
+    pub kind: SparklineKind,
+    pub cells: Vec<CellId>,
+}
+
+/// SheetOutline summarizes the structure of one sheet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SheetOutline {
+    pub name: String,
+    /// The corners of the cells used, or None for empty sheets.
+    pub used_range: Option<(CellId, CellId)>,
+    pub cells: usize,
+    pub formulas: usize,
+    pub tables: Vec<TableOutline>,
+    pub charts: Vec<ChartOutline>,
+}
+
+/// WorkbookOutline is the structure of a workbook without its data: its
+/// sheets with their tables and charts, and its defined names. It is meant
+/// for indexing workbooks for search and for summaries read out by screen
+/// readers, and is rendered as JSON or as plain text.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WorkbookOutline {
+    pub sheets: Vec<SheetOutline>,
+    /// The defined names with the formulas they stand for, like `=Sheet1!B2`.
+    pub names: Vec<(String, String)>,
+}
+
+fn kind_label(kind: SparklineKind) -> &'static str {
+    match kind {
+        SparklineKind::Line => "line",
+        SparklineKind::Column => "column",
+        SparklineKind::WinLoss => "win_loss",
+    }
+}
+
+fn range_label(first: CellId, last: CellId) -> String {
+    match first == last {
+        true => first.to_string(),
+        false => format!("{}:{}", first, last),
+    }
+}
+
+/// Quote text as a JSON string.
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn json_list(items: impl IntoIterator<Item=String>) -> String {
+    format!("[{}]", items.into_iter().collect::<Vec<_>>().join(","))
+}
+
+impl TableOutline {
+    fn to_json(&self) -> String {
+        let headers = match &self.headers {
+            Some(headers) => json_list(headers.iter().map(|header| json_string(header))),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"name\":{},\"range\":{},\"headers\":{},\"rows\":{}}}",
+            json_string(&self.name),
+            json_string(&range_label(self.first, self.last)),
+            headers,
+            self.rows,
+        )
+    }
+}
+
+impl SheetOutline {
+    fn to_json(&self) -> String {
+        let used_range = match self.used_range {
+            Some((first, last)) => json_string(&range_label(first, last)),
+            None => "null".to_string(),
+        };
+        let charts = self.charts.iter().map(|chart| {
+            let cells = json_list(chart.cells.iter().map(|cell_id| json_string(&cell_id.to_string())));
+            format!("{{\"kind\":{},\"cells\":{}}}", json_string(kind_label(chart.kind)), cells)
+        });
+        format!(
+            "{{\"name\":{},\"used_range\":{},\"cells\":{},\"formulas\":{},\"tables\":{},\"charts\":{}}}",
+            json_string(&self.name),
+            used_range,
+            self.cells,
+            self.formulas,
+            json_list(self.tables.iter().map(TableOutline::to_json)),
+            json_list(charts),
+        )
+    }
+}
+
+impl WorkbookOutline {
+    /// Render the outline as a JSON document.
+    pub fn to_json(&self) -> String {
+        let names = self.names.iter().map(|(name, refers_to)| {
+            format!("{{\"name\":{},\"refers_to\":{}}}", json_string(name), json_string(refers_to))
+        });
+        format!(
+            "{{\"sheets\":{},\"names\":{}}}\n",
+            json_list(self.sheets.iter().map(SheetOutline::to_json)),
+            json_list(names),
+        )
+    }
+
+    /// Render the outline as sentences for screen readers, one line per
+    /// sheet, table, chart and name.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        let plural = |count: usize, noun: &str| format!("{} {}{}", count, noun, if count == 1 { "" } else { "s" });
+        for sheet in &self.sheets {
+            match sheet.used_range {
+                Some((first, last)) => text.push_str(&format!(
+                    "Sheet {}: {} with {} in {}, {}, {}.\n",
+                    sheet.name,
+                    plural(sheet.cells, "cell"),
+                    plural(sheet.formulas, "formula"),
+                    range_label(first, last),
+                    plural(sheet.tables.len(), "table"),
+                    plural(sheet.charts.len(), "chart"),
+                )),
+                None => text.push_str(&format!("Sheet {}: empty.\n", sheet.name)),
+            }
+            for table in &sheet.tables {
+                let columns = match &table.headers {
+                    Some(headers) => format!(" with columns {}", headers.join(", ")),
+                    None => String::new(),
+                };
+                let range = range_label(table.first, table.last);
+                text.push_str(&format!("{} in {}: {}{}.\n", table.name, range, plural(table.rows as usize, "row"), columns));
+            }
+            for chart in &sheet.charts {
+                let kind = match chart.kind {
+                    SparklineKind::Line => "Line",
+                    SparklineKind::Column => "Column",
+                    SparklineKind::WinLoss => "Win/loss",
+                };
+                let cells = chart.cells.iter().map(ToString::to_string).collect::<Vec<_>>();
+                text.push_str(&format!("{} sparklines in {}.\n", kind, cells.join(", ")));
+            }
+        }
+        for (name, refers_to) in &self.names {
+            text.push_str(&format!("Name {} refers to {}.\n", name, refers_to.trim_start_matches('=')));
+        }
+        text
+    }
+}
+
+/// Find the blocks of cells connected through their edges, in the order of
+/// their top left cells.
+pub(crate) fn blocks<T: Arithmetic>(sheet: &Worksheet<T>) -> Vec<(CellId, CellId)> {
+    let mut ids = sheet.cell_ids();
+    ids.sort_by_key(|cell_id| (cell_id.row(), cell_id.col()));
+    let used = ids.iter().copied().collect::<HashSet<_>>();
+    let mut seen = HashSet::new();
+    let mut blocks = Vec::new();
+    for start in ids {
+        if !seen.insert(start) {
+            continue;
+        }
+        let (mut first, mut last) = (start, start);
+        let mut queue = VecDeque::from([start]);
+        while let Some(cell_id) = queue.pop_front() {
+            first = CellId::new(first.row().min(cell_id.row()), first.col().min(cell_id.col()));
+            last = CellId::new(last.row().max(cell_id.row()), last.col().max(cell_id.col()));
+            let (row, col) = (cell_id.row(), cell_id.col());
+            let neighbours = [
+                row.checked_sub(1).map(|row| CellId::new(row, col)),
+                row.checked_add(1).map(|row| CellId::new(row, col)),
+                col.checked_sub(1).map(|col| CellId::new(row, col)),
+                col.checked_add(1).map(|col| CellId::new(row, col)),
+            ];
+            for neighbour in neighbours.into_iter().flatten() {
+                if used.contains(&neighbour) && seen.insert(neighbour) {
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+        blocks.push((first, last));
+    }
+    blocks
+}
+
+impl<T: Arithmetic> Worksheet<T> {
+    /// Get the titles of the columns of a block if its first row is a
+    /// header.
+    pub(crate) fn headers(&self, first: CellId, last: CellId) -> Option<Vec<String>> {
+        let is_text = |cell_id: CellId| self.cell(cell_id).is_some_and(|cell| matches!(cell.value(), Value::Raw));
+        if first.row() == last.row() {
+            return None;
+        }
+        let titles = (first.col()..=last.col())
+            .map(|col| CellId::new(first.row(), col))
+            .map(|cell_id| is_text(cell_id).then(|| self.cell(cell_id).expect("text cells exist").text().trim().to_string()))
+            .collect::<Option<Vec<_>>>()?;
+        let below = (first.row() + 1..=last.row()).flat_map(|row| (first.col()..=last.col()).map(move |col| CellId::new(row, col)));
+        let mut data = below.filter(|cell_id| self.cell(*cell_id).is_some());
+        data.any(|cell_id| !is_text(cell_id)).then_some(titles)
+    }
+}
+
+impl<T: Arithmetic> Workbook<T> {
+    /// Outline the structure of the workbook. Tables are the blocks of
+    /// cells bordered by empty cells, and are named after the queries and
+    /// rolling windows landing in them.
+    pub fn outline(&self) -> WorkbookOutline {
+        let sheets = self.sheets().map(|(name, sheet)| {
+            let blocks = blocks(sheet);
+            let used_range = blocks.iter().copied().reduce(|(a, b), (c, d)| {
+                (CellId::new(a.row().min(c.row()), a.col().min(c.col())), CellId::new(b.row().max(d.row()), b.col().max(d.col())))
+            });
+            let mut unnamed = 0;
+            let tables = blocks.into_iter().map(|(first, last)| {
+                let inside = |cell_id: CellId| (first.row()..=last.row()).contains(&cell_id.row()) && (first.col()..=last.col()).contains(&cell_id.col());
+                let query = self.queries().iter().find(|query| query.sheet.eq_ignore_ascii_case(name) && inside(query.anchor)).map(|query| query.name.clone());
+                let window = sheet.windows().iter().find(|window| inside(CellId::new(window.top, window.first_col))).map(|window| window.name.clone());
+                let name = query.or(window).unwrap_or_else(|| {
+                    unnamed += 1;
+                    format!("Table {}", unnamed)
+                });
+                let headers = sheet.headers(first, last);
+                let rows = last.row() - first.row() + 1 - headers.is_some() as u32;
+                TableOutline{name, first, last, headers, rows}
+            }).collect();
+            let charts = sheet.sparklines().iter().map(|group| {
+                ChartOutline{kind: group.kind, cells: group.sparklines.iter().map(|sparkline| sparkline.cell_id).collect()}
+            }).collect();
+            SheetOutline{
+                name: name.to_string(),
+                used_range,
+                cells: sheet.cells().count(),
+                formulas: sheet.cells().filter(|(_, cell)| cell.formula().is_some()).count(),
+                tables,
+                charts,
+            }
+        }).collect();
+        let names = self.names().map(|(name, formula)| (name.to_string(), format!("={}", formula))).collect();
+        WorkbookOutline{sheets, names}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::Formula;
+    use crate::kernel::sparkline::SparklineGroup;
+
+    fn at(cell_id: &str) -> CellId {
+        CellId::parse(cell_id).unwrap()
+    }
+
+    /// A sheet of sales by region with a total and a sparkline beside it,
+    /// and an empty sheet.
+    fn workbook() -> Workbook {
+        let mut workbook: Workbook = Workbook::new();
+        workbook.add_sheet("Sales").unwrap();
+        workbook.add_sheet("Notes").unwrap();
+        for (cell_id, data) in [
+            ("A1", "Region"), ("B1", "Q1"), ("C1", "Q2"),
+            ("A2", "North"), ("B2", "10"), ("C2", "12"),
+            ("A3", "South \"S\""), ("B3", "7"), ("C3", "=B3*2"),
+            ("E1", "=SUM(B2:C3)"),
+        ] {
+            workbook.set_cell("Sales", at(cell_id), data.to_string()).unwrap();
+        }
+        let sparklines = SparklineGroup::new(SparklineKind::Line).sparkline(at("D2"), at("B2"), at("C2")).sparkline(at("D3"), at("B3"), at("C3"));
+        workbook.sheet_mut("Sales").unwrap().sparklines_mut().push(sparklines);
+        workbook.define_name("Total", Formula::try_from("Sales!E1").unwrap()).unwrap();
+        workbook
+    }
+
+    #[test]
+    fn outlines_find_tables_and_charts() {
+        let outline = workbook().outline();
+        let sales = &outline.sheets[0];
+        assert_eq!(sales.used_range, Some((at("A1"), at("E3"))));
+        assert_eq!((sales.cells, sales.formulas), (10, 2));
+        assert_eq!(sales.tables, [
+            TableOutline{name: "Table 1".to_string(), first: at("A1"), last: at("C3"), headers: Some(vec!["Region".to_string(), "Q1".to_string(), "Q2".to_string()]), rows: 2},
+            TableOutline{name: "Table 2".to_string(), first: at("E1"), last: at("E1"), headers: None, rows: 1},
+        ]);
+        assert_eq!(sales.charts, [ChartOutline{kind: SparklineKind::Line, cells: vec![at("D2"), at("D3")]}]);
+        assert_eq!(outline.sheets[1].used_range, None);
+        assert_eq!(outline.names, [("Total".to_string(), "=Sales!E1".to_string())]);
+    }
+
+    #[test]
+    fn outlines_render_as_json_and_text() {
+        let outline = workbook().outline();
+        let json = outline.to_json();
+        assert!(json.starts_with("{\"sheets\":[{\"name\":\"Sales\",\"used_range\":\"A1:E3\",\"cells\":10,\"formulas\":2,\"tables\":[{\"name\":\"Table 1\",\"range\":\"A1:C3\",\"headers\":[\"Region\",\"Q1\",\"Q2\"],\"rows\":2}"));
+        assert!(json.contains("\"charts\":[{\"kind\":\"line\",\"cells\":[\"D2\",\"D3\"]}]"));
+        assert!(json.ends_with("\"names\":[{\"name\":\"Total\",\"refers_to\":\"=Sales!E1\"}]}\n"));
+        assert_eq!(json_string("a \"b\"\\\n\u{1}"), "\"a \\\"b\\\"\\\\\\n\\u0001\"");
+        assert_eq!(outline.to_text(), "\
+Sheet Sales: 10 cells with 2 formulas in A1:E3, 2 tables, 1 chart.
+Table 1 in A1:C3: 2 rows with columns Region, Q1, Q2.
+Table 2 in E1: 1 row.
+Line sparklines in D2, D3.
+Sheet Notes: empty.
+Name Total refers to Sales!E1.
+");
+    }
+}
@@ -0,0 +1,264 @@
+use super::arithmetic::Arithmetic;
+use super::csv::{records, CsvError};
+use super::feed::{CoalesceOptions, FeedBuffer, Recalc};
+use super::kernel::{escape_text, CellId};
+use super::worksheet::{SheetError, Worksheet};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// TailOptions configures how a `CsvTailer` reads a file into a sheet.
+#[derive(Debug, Clone)]
+pub struct TailOptions {
+    pub delimiter: char,
+    /// The top left cell of the region rows are appended to.
+    pub origin: CellId,
+    /// Whether the first record of the file is a header to skip. It is
+    /// skipped again when the file is truncated or rotated.
+    pub skip_header: bool,
+    /// Whether fields starting with `=` are entered as formulas. Logs are
+    /// written by others, so by default they are entered as text.
+    pub formulas: bool,
+    /// The least time between two recalcs. Rows arriving in between are
+    /// queued, unless the queue is full.
+    pub min_interval: Duration,
+    pub coalesce: CoalesceOptions,
+}
+
+impl Default for TailOptions {
+    fn default() -> Self {
+        Self{
+            delimiter: ',',
+            origin: CellId::new(0, 0),
+            skip_header: false,
+            formulas: false,
+            min_interval: Duration::from_millis(500),
+            coalesce: CoalesceOptions::default(),
+        }
+    }
+}
+
+/// TailStats counts what a `CsvTailer` read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TailStats {
+    pub bytes: u64,
+    pub rows: u64,
+    /// The number of times the file was found shorter than what was read,
+    /// and read again from its start.
+    pub restarts: u64,
+}
+
+/// CsvTailer follows a growing file of delimited text, such as a log, and
+/// appends the records written to it as rows of a region of a sheet. Call
+/// `poll` on a timer: it reads what was written since the last call and
+/// recalculates the formulas depending on the new rows, at most once every
+/// `min_interval`, so a sheet of formulas over the region is a live report
+/// of the log. A record is read once its line ends, so a half written line
+/// waits for the next poll.
+#[derive(Debug)]
+pub struct CsvTailer {
+    path: PathBuf,
+    options: TailOptions,
+    /// The bytes of the file read so far.
+    offset: u64,
+    /// The bytes read after the last complete record.
+    partial: Vec<u8>,
+    /// The row of the region the next record goes to.
+    next_row: u32,
+    header_skipped: bool,
+    feed: FeedBuffer,
+    last_recalc: Option<Instant>,
+    stats: TailStats,
+}
+
+impl CsvTailer {
+    /// Tail a file from its start. The file need not exist yet.
+    pub fn new(path: impl AsRef<Path>, options: TailOptions) -> Self {
+        Self{
+            path: path.as_ref().to_path_buf(),
+            feed: FeedBuffer::new(options.coalesce),
+            options,
+            offset: 0,
+            partial: Vec::new(),
+            next_row: 0,
+            header_skipped: false,
+            last_recalc: None,
+            stats: TailStats::default(),
+        }
+    }
+
+    /// Tail a file from its current end, skipping what it already holds.
+    pub fn from_end(path: impl AsRef<Path>, options: TailOptions) -> Result<Self, CsvError> {
+        let mut tailer = Self::new(path, options);
+        tailer.offset = std::fs::metadata(&tailer.path)?.len();
+        tailer.header_skipped = true;
+        Ok(tailer)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn options(&self) -> &TailOptions {
+        &self.options
+    }
+
+    pub fn stats(&self) -> &TailStats {
+        &self.stats
+    }
+
+    /// Get the buffer queueing the rows until the next recalc.
+    pub fn feed(&self) -> &FeedBuffer {
+        &self.feed
+    }
+
+    /// Get the number of rows appended to the region, which is where the
+    /// next one goes.
+    pub fn rows(&self) -> u32 {
+        self.next_row
+    }
+
+    /// Read the records written to the file since the last poll, and
+    /// recalculate the sheet if `min_interval` passed or the queue is full.
+    /// Get the recalc, or None if none was due.
+    pub fn poll<T: Arithmetic>(&mut self, sheet: &mut Worksheet<T>) -> Result<Option<Recalc<T>>, CsvError> {
+        self.read()?;
+        let due = self.last_recalc.is_none_or(|last| last.elapsed() >= self.options.min_interval);
+        if self.feed.pending() == 0 || !(due || self.feed.is_full()) {
+            return Ok(None);
+        }
+        Ok(Some(self.recalc(sheet)?))
+    }
+
+    /// Recalculate the sheet with the queued rows now, whatever the time
+    /// since the last recalc. Get None if no rows are queued.
+    pub fn flush<T: Arithmetic>(&mut self, sheet: &mut Worksheet<T>) -> Result<Option<Recalc<T>>, SheetError> {
+        if self.feed.pending() == 0 {
+            return Ok(None);
+        }
+        self.recalc(sheet).map(Some)
+    }
+
+    fn recalc<T: Arithmetic>(&mut self, sheet: &mut Worksheet<T>) -> Result<Recalc<T>, SheetError> {
+        self.last_recalc = Some(Instant::now());
+        self.feed.recalc(sheet)
+    }
+
+    /// Read the new bytes of the file and queue the records they complete.
+    fn read(&mut self) -> Result<(), CsvError> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let len = file.metadata()?.len();
+        if len < self.offset {
+            // The file was truncated or replaced by a new one, as when logs
+            // rotate. Its rows are appended after the ones already read.
+            self.offset = 0;
+            self.partial.clear();
+            self.header_skipped = false;
+            self.stats.restarts += 1;
+        }
+        if len == self.offset {
+            return Ok(());
+        }
+        file.seek(SeekFrom::Start(self.offset))?;
+        let read = file.take(len - self.offset).read_to_end(&mut self.partial)?;
+        self.offset += read as u64;
+        self.stats.bytes += read as u64;
+
+        // Records end at a line break outside quotes. A quoted field still
+        // open at the last line break waits for more of the file.
+        let Some(end) = self.partial.iter().rposition(|byte| *byte == b'\n') else { return Ok(()) };
+        let text = String::from_utf8_lossy(&self.partial[..=end]).into_owned();
+        let Ok(records) = records(&text, self.options.delimiter) else { return Ok(()) };
+        self.partial.drain(..=end);
+        for record in records {
+            if self.options.skip_header && !self.header_skipped {
+                self.header_skipped = true;
+                continue;
+            }
+            let row = self.options.origin.row().saturating_add(self.next_row);
+            for (col, field) in record.into_iter().enumerate() {
+                if field.is_empty() {
+                    continue;
+                }
+                let data = match self.options.formulas || !field.starts_with('=') {
+                    true => field,
+                    false => escape_text(&field),
+                };
+                self.feed.set_cell(CellId::new(row, self.options.origin.col().saturating_add(col as u32)), data);
+            }
+            self.next_row = self.next_row.saturating_add(1);
+            self.stats.rows += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::{Kernel, Primitive, Value};
+    use std::io::Write;
+
+    fn raw(sheet: &Worksheet, row: u32, col: u32) -> Option<String> {
+        sheet.cell(CellId::new(row, col)).map(|cell| cell.raw().to_string())
+    }
+
+    fn append(path: &Path, text: &str) {
+        std::fs::OpenOptions::new().create(true).append(true).open(path).unwrap().write_all(text.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn tailer_appends_complete_records() {
+        let path = std::env::temp_dir().join(format!("xlnt-tail-{}.csv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 3), "=SUM(B2:B10)".to_string());
+        let options = TailOptions{origin: CellId::new(1, 0), skip_header: true, min_interval: Duration::ZERO, ..TailOptions::default()};
+        let mut tailer = CsvTailer::new(&path, options);
+        assert!(tailer.poll(&mut sheet).unwrap().is_none());
+
+        append(&path, "name,amount\nalpha,5\n=HACK(),7\nhalf,");
+        let recalc = tailer.poll(&mut sheet).unwrap().unwrap();
+        assert_eq!(tailer.rows(), 2);
+        assert_eq!(raw(&sheet, 1, 0).as_deref(), Some("alpha"));
+        assert_eq!(raw(&sheet, 2, 0).as_deref(), Some("'=HACK()"));
+        assert!(matches!(&recalc.values[..], [(cell_id, Value::Primitive(Primitive::Number(number)))] if *cell_id == CellId::new(0, 3) && number.value() == 12.0));
+
+        // The half written line is read once it ends.
+        append(&path, "9\n");
+        tailer.poll(&mut sheet).unwrap().unwrap();
+        assert_eq!(raw(&sheet, 3, 1).as_deref(), Some("9"));
+        assert!(matches!(sheet.evaluate_cell(CellId::new(0, 3)), Ok(Value::Primitive(Primitive::Number(number))) if number.value() == 21.0));
+
+        // A rotated file is read from its start, header and all.
+        std::fs::write(&path, "name,amount\nbeta,1\n").unwrap();
+        tailer.poll(&mut sheet).unwrap().unwrap();
+        assert_eq!(raw(&sheet, 4, 0).as_deref(), Some("beta"));
+        assert_eq!(*tailer.stats(), TailStats{bytes: 56, rows: 4, restarts: 1});
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recalcs_are_throttled() {
+        let path = std::env::temp_dir().join(format!("xlnt-tail-throttled-{}.csv", std::process::id()));
+        std::fs::write(&path, "old\n").unwrap();
+        let mut sheet: Worksheet = Worksheet::new();
+        let options = TailOptions{min_interval: Duration::from_secs(3600), ..TailOptions::default()};
+        let mut tailer = CsvTailer::from_end(&path, options).unwrap();
+        append(&path, "1\n");
+        assert!(tailer.poll(&mut sheet).unwrap().is_some());
+        append(&path, "2\n");
+        assert!(tailer.poll(&mut sheet).unwrap().is_none());
+        assert_eq!(tailer.feed().pending(), 1);
+        assert!(tailer.flush(&mut sheet).unwrap().is_some());
+        assert!(tailer.flush(&mut sheet).unwrap().is_none());
+        assert_eq!(raw(&sheet, 0, 0).as_deref(), Some("1"));
+        assert_eq!(raw(&sheet, 1, 0).as_deref(), Some("2"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
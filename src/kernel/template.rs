@@ -0,0 +1,336 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{CellId, Formula, Kernel};
+use super::worksheet::Worksheet;
+use thiserror::Error;
+use std::collections::BTreeMap;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum TemplateError {
+    #[error("no value for placeholder {0}")]
+    MissingValue(String),
+
+    #[error("{0} is not a list")]
+    NotAList(String),
+
+    #[error("unterminated placeholder in {0}")]
+    UnterminatedPlaceholder(CellId),
+
+    #[error("repeat marker in row {0} is not closed")]
+    UnclosedRepeat(u32),
+
+    #[error("closing repeat marker in row {0} has no opening marker")]
+    UnopenedRepeat(u32),
+
+    #[error("repeat markers in row {0} open and close a region of no rows")]
+    EmptyRepeat(u32),
+}
+
+/// TemplateData is the data a template is rendered with: text values,
+/// records of named fields and lists of records for repeated regions.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateData {
+    Value(String),
+    Record(BTreeMap<String, TemplateData>),
+    List(Vec<TemplateData>),
+}
+
+impl TemplateData {
+    /// Create an empty record.
+    pub fn record() -> Self {
+        Self::Record(BTreeMap::new())
+    }
+
+    /// Add a field to a record. Does nothing for other kinds of data.
+    pub fn with(mut self, field: &str, value: impl Into<TemplateData>) -> Self {
+        if let Self::Record(ref mut fields) = self {
+            fields.insert(field.to_string(), value.into());
+        }
+        self
+    }
+
+    /// Look up a dotted path like `customer.name`.
+    pub fn get(&self, path: &str) -> Option<&TemplateData> {
+        path.split('.').try_fold(self, |data, field| match data {
+            Self::Record(fields) => fields.get(field.trim()),
+            _ => None,
+        })
+    }
+}
+
+impl From<&str> for TemplateData {
+    fn from(value: &str) -> Self {
+        Self::Value(value.to_string())
+    }
+}
+
+impl From<String> for TemplateData {
+    fn from(value: String) -> Self {
+        Self::Value(value)
+    }
+}
+
+impl From<f64> for TemplateData {
+    fn from(value: f64) -> Self {
+        Self::Value(value.to_string())
+    }
+}
+
+impl From<Vec<TemplateData>> for TemplateData {
+    fn from(values: Vec<TemplateData>) -> Self {
+        Self::List(values)
+    }
+}
+
+/// A region of rows repeated once per record of a list. The marker rows
+/// holding `{{#each path}}` and `{{/each}}` are not part of the output.
+#[derive(Debug, Clone)]
+struct Repeat {
+    path: String,
+    open: u32,
+    close: u32,
+}
+
+impl Repeat {
+    fn body_len(&self) -> u32 {
+        self.close - self.open - 1
+    }
+
+    fn contains(&self, row: u32) -> bool {
+        row > self.open && row < self.close
+    }
+}
+
+/// Where the rows of a template end up in a rendered sheet.
+struct Layout {
+    repeats: Vec<(Repeat, u32, u32)>,
+}
+
+impl Layout {
+    fn new(repeats: &[Repeat], counts: &[u32]) -> Self {
+        let mut shift = 0i64;
+        let mut placed = Vec::new();
+        for (repeat, count) in repeats.iter().zip(counts) {
+            let start = (repeat.open as i64 + shift) as u32;
+            placed.push((repeat.clone(), start, *count));
+            shift += (*count * repeat.body_len()) as i64 - (repeat.body_len() + 2) as i64;
+        }
+        Self{repeats: placed}
+    }
+
+    /// Get the output row of a template row outside repeated regions, or of
+    /// a row in clone `clone` of the region containing it.
+    fn row(&self, row: u32, clone: u32) -> u32 {
+        let mut shift = 0i64;
+        for (repeat, start, count) in &self.repeats {
+            if repeat.contains(row) {
+                return start + clone * repeat.body_len() + (row - repeat.open - 1);
+            }
+            if row > repeat.close {
+                shift += (*count * repeat.body_len()) as i64 - (repeat.body_len() + 2) as i64;
+            }
+        }
+        (row as i64 + shift).max(0) as u32
+    }
+
+    /// Get the index and clone count of the region containing a row.
+    fn region(&self, row: u32) -> Option<(usize, u32)> {
+        self.repeats.iter()
+            .position(|(repeat, _, _)| repeat.contains(row))
+            .map(|index| (index, self.repeats[index].2))
+    }
+}
+
+/// Template is a worksheet containing `{{path}}` placeholders and repeated
+/// regions delimited by rows holding `{{#each path}}` and `{{/each}}`.
+///
+/// Rendering substitutes placeholders, clones repeated regions once per
+/// record and fixes up formulas: references into a clone point at the same
+/// clone, references to a region from outside span every clone, and
+/// references below a region follow the rows they refer to.
+pub struct Template<T: Arithmetic=f64> {
+    sheet: Worksheet<T>,
+    repeats: Vec<Repeat>,
+}
+
+fn marker(raw: &str) -> Option<Option<String>> {
+    let inner = raw.trim().strip_prefix("{{")?.strip_suffix("}}")?.trim();
+    if let Some(path) = inner.strip_prefix("#each ") {
+        Some(Some(path.trim().to_string()))
+    } else if inner == "/each" {
+        Some(None)
+    } else {
+        None
+    }
+}
+
+/// Replace the placeholders in `raw`, resolving paths in `scopes` from the
+/// innermost outwards.
+fn substitute(raw: &str, cell_id: CellId, scopes: &[&TemplateData]) -> Result<String, TemplateError> {
+    let mut output = String::new();
+    let mut rest = raw;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let end = rest[start..].find("}}").ok_or(TemplateError::UnterminatedPlaceholder(cell_id))?;
+        let path = rest[start + 2..start + end].trim();
+        let value = scopes.iter().rev()
+            .find_map(|scope| scope.get(path))
+            .ok_or_else(|| TemplateError::MissingValue(path.to_string()))?;
+        match value {
+            TemplateData::Value(text) => output.push_str(text),
+            _ => return Err(TemplateError::MissingValue(path.to_string())),
+        }
+        rest = &rest[start + end + 2..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+impl<T: Arithmetic> Template<T> {
+    pub fn new(sheet: Worksheet<T>) -> Result<Self, TemplateError> {
+        let mut markers = sheet.cells()
+            .filter_map(|(cell_id, cell)| marker(cell.raw()).map(|marker| (cell_id, marker)))
+            .collect::<Vec<_>>();
+        markers.sort_by_key(|(cell_id, _)| (cell_id.row(), cell_id.col()));
+
+        let mut repeats = Vec::new();
+        let mut open: Option<(u32, String)> = None;
+        for (cell_id, marker) in markers {
+            let row = cell_id.row();
+            match (marker, open.take()) {
+                (Some(path), None) => open = Some((row, path)),
+                (Some(_), Some((row, _))) => return Err(TemplateError::UnclosedRepeat(row)),
+                (None, Some((open, _))) if open == row => return Err(TemplateError::EmptyRepeat(row)),
+                (None, Some((open, path))) => repeats.push(Repeat{path, open, close: row}),
+                (None, None) => return Err(TemplateError::UnopenedRepeat(row)),
+            }
+        }
+        if let Some((row, _)) = open {
+            return Err(TemplateError::UnclosedRepeat(row));
+        }
+        Ok(Self{sheet, repeats})
+    }
+
+    pub fn sheet(&self) -> &Worksheet<T> {
+        &self.sheet
+    }
+
+    /// Fill in the template with `data`.
+    pub fn render(&self, data: &TemplateData) -> Result<Worksheet<T>, TemplateError> {
+        let mut lists = Vec::new();
+        for repeat in &self.repeats {
+            match data.get(&repeat.path) {
+                Some(TemplateData::List(records)) => lists.push(records.as_slice()),
+                _ => return Err(TemplateError::NotAList(repeat.path.clone())),
+            }
+        }
+        let counts = lists.iter().map(|records| records.len() as u32).collect::<Vec<_>>();
+        let layout = Layout::new(&self.repeats, &counts);
+
+        let mut output = Worksheet::new();
+        for cell_id in self.sheet.cell_ids() {
+            let row = cell_id.row();
+            if self.repeats.iter().any(|repeat| repeat.open == row || repeat.close == row) {
+                continue;
+            }
+            let raw = self.sheet.cell(cell_id).map(|cell| cell.raw()).unwrap_or_default();
+            match layout.region(row) {
+                None => {
+                    let text = substitute(raw, cell_id, &[data])?;
+                    let target = CellId::new(layout.row(row, 0), cell_id.col());
+                    output.set_cell(target, self.fix_up(&text, &layout, None));
+                },
+                Some((index, count)) => {
+                    for (clone, record) in lists[index].iter().enumerate().take(count as usize) {
+                        let text = substitute(raw, cell_id, &[data, record])?;
+                        let target = CellId::new(layout.row(row, clone as u32), cell_id.col());
+                        output.set_cell(target, self.fix_up(&text, &layout, Some((index, clone as u32))));
+                    }
+                },
+            }
+        }
+        Ok(output)
+    }
+
+    /// Move the references of a formula to where the rows they refer to were
+    /// rendered. Text which is not a formula is returned unchanged.
+    fn fix_up(&self, text: &str, layout: &Layout, clone: Option<(usize, u32)>) -> String {
+        let source = match text.trim_start().strip_prefix('=') {
+            Some(source) => source,
+            None => return text.to_string(),
+        };
+        let mut formula = match Formula::<T>::try_from(source) {
+            Ok(formula) => formula,
+            Err(_) => return text.to_string(),
+        };
+        let map = |cell_id: CellId, last: bool| {
+            let row = cell_id.row();
+            let clone = match (layout.region(row), clone) {
+                (Some((index, _)), Some((own, clone))) if index == own => clone,
+                (Some((_, count)), _) if last => count.saturating_sub(1),
+                _ => 0,
+            };
+            CellId::new(layout.row(row, clone), cell_id.col())
+        };
+        formula.rewrite_references(&mut |sheet, reference| {
+            if sheet.is_some() {
+                return;
+            }
+            match reference {
+                Formula::CellRef(cell_id) => *cell_id = map(*cell_id, false),
+                Formula::CellRange(start, end) => {
+                    let (top, bottom) = if start.row() <= end.row() { (start, end) } else { (end, start) };
+                    *top = map(*top, false);
+                    *bottom = map(*bottom, true);
+                },
+                _ => {},
+            }
+        });
+        format!("={}", formula)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sheet(cells: &[(u32, u32, &str)]) -> Worksheet {
+        let mut sheet = Worksheet::new();
+        for (row, col, raw) in cells {
+            sheet.set_cell(CellId::new(*row, *col), raw.to_string());
+        }
+        sheet
+    }
+
+    #[test]
+    fn rejects_markers_in_one_row() {
+        let template = Template::new(sheet(&[(0, 0, "{{#each items}}"), (0, 1, "{{/each}}")]));
+        assert_eq!(template.err(), Some(TemplateError::EmptyRepeat(0)));
+        let template = Template::new(sheet(&[(0, 1, "{{#each items}}"), (0, 0, "{{/each}}")]));
+        assert_eq!(template.err(), Some(TemplateError::UnopenedRepeat(0)));
+    }
+
+    #[test]
+    fn renders_repeated_regions() {
+        let template = Template::new(sheet(&[
+            (0, 0, "{{title}}"),
+            (1, 0, "{{#each items}}"),
+            (2, 0, "{{name}}"),
+            (2, 1, "{{price}}"),
+            (3, 0, "{{/each}}"),
+            (4, 1, "=SUM(B3:B3)"),
+        ])).unwrap();
+        let items = vec![
+            TemplateData::record().with("name", "Tea").with("price", 3.0),
+            TemplateData::record().with("name", "Cake").with("price", 4.5),
+        ];
+        let data = TemplateData::record().with("title", "Order").with("items", items);
+        let output = template.render(&data).unwrap();
+        let raw = |row, col| output.cell(CellId::new(row, col)).map(|cell| cell.raw().to_string());
+        assert_eq!(raw(0, 0).as_deref(), Some("Order"));
+        assert_eq!(raw(1, 0).as_deref(), Some("Tea"));
+        assert_eq!(raw(2, 0).as_deref(), Some("Cake"));
+        assert_eq!(raw(2, 1).as_deref(), Some("4.5"));
+        assert_eq!(raw(3, 1).as_deref(), Some("=SUM(B2:B3)"));
+        assert_eq!(template.render(&TemplateData::record().with("title", "Order")).err(), Some(TemplateError::NotAList("items".to_string())));
+    }
+}
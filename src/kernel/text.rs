@@ -0,0 +1,169 @@
+use super::encoding::Encoding;
+use thiserror::Error;
+
+/// The longest text a cell may hold.
+pub const MAX_TEXT_LENGTH: usize = 32767;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitError {
+    #[error("instance is zero or longer than the text")]
+    InvalidInstance,
+
+    #[error("delimiter not found")]
+    NotFound,
+}
+
+/// TextSearch configures `text_before` and `text_after`, following the
+/// optional arguments of `TEXTBEFORE` and `TEXTAFTER`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextSearch {
+    /// Which occurrence of the delimiter to split at, counting from the end
+    /// of the text if negative.
+    pub instance: i64,
+    pub ignore_case: bool,
+    /// Whether the end of the text counts as an occurrence of the delimiter.
+    pub match_end: bool,
+}
+
+impl Default for TextSearch {
+    fn default() -> Self {
+        Self{instance: 1, ignore_case: false, match_end: false}
+    }
+}
+
+fn chars_equal(a: char, b: char, ignore_case: bool) -> bool {
+    a == b || (ignore_case && a.to_lowercase().eq(b.to_lowercase()))
+}
+
+/// Find the char ranges where `delimiter` occurs in `text`, without overlaps.
+fn occurrences(text: &[char], delimiter: &[char], ignore_case: bool) -> Vec<(usize, usize)> {
+    let mut found = Vec::new();
+    let mut start = 0;
+    while start + delimiter.len() <= text.len() {
+        let matches = text[start..start + delimiter.len()].iter()
+            .zip(delimiter)
+            .all(|(a, b)| chars_equal(*a, *b, ignore_case));
+        if matches && !delimiter.is_empty() {
+            found.push((start, start + delimiter.len()));
+            start += delimiter.len();
+        } else {
+            start += 1;
+        }
+    }
+    found
+}
+
+/// Find the char range of the occurrence of `delimiter` `search` selects.
+fn split_at(text: &[char], delimiter: &str, search: &TextSearch) -> Result<(usize, usize), SplitError> {
+    if search.instance == 0 || search.instance.unsigned_abs() as usize > text.len().max(1) {
+        return Err(SplitError::InvalidInstance);
+    }
+    let delimiter = delimiter.chars().collect::<Vec<_>>();
+    if delimiter.is_empty() {
+        // An empty delimiter occurs at the start, or at the end when
+        // counting from the end.
+        return Ok(if search.instance > 0 { (0, 0) } else { (text.len(), text.len()) });
+    }
+    let mut found = occurrences(text, &delimiter, search.ignore_case);
+    if search.instance > 0 {
+        if search.match_end {
+            found.push((text.len(), text.len()));
+        }
+        found.get(search.instance as usize - 1).copied().ok_or(SplitError::NotFound)
+    } else {
+        if search.match_end {
+            found.insert(0, (0, 0));
+        }
+        let from_end = search.instance.unsigned_abs() as usize;
+        found.len().checked_sub(from_end).map(|index| found[index]).ok_or(SplitError::NotFound)
+    }
+}
+
+/// Get the text before an occurrence of `delimiter`, as `TEXTBEFORE` does.
+pub fn text_before(text: &str, delimiter: &str, search: &TextSearch) -> Result<String, SplitError> {
+    let chars = text.chars().collect::<Vec<_>>();
+    split_at(&chars, delimiter, search).map(|(start, _)| chars[..start].iter().collect())
+}
+
+/// Get the text after an occurrence of `delimiter`, as `TEXTAFTER` does.
+pub fn text_after(text: &str, delimiter: &str, search: &TextSearch) -> Result<String, SplitError> {
+    let chars = text.chars().collect::<Vec<_>>();
+    split_at(&chars, delimiter, search).map(|(_, end)| chars[end..].iter().collect())
+}
+
+/// Whether a character is a combining diacritical mark, which belongs to
+/// the letter before it.
+fn is_combining(c: char) -> bool {
+    matches!(c, '\u{300}'..='\u{36F}' | '\u{1AB0}'..='\u{1AFF}' | '\u{1DC0}'..='\u{1DFF}' | '\u{20D0}'..='\u{20FF}' | '\u{FE20}'..='\u{FE2F}')
+}
+
+/// Capitalize every word of a text and lowercase the other letters, as
+/// `PROPER` does. A word starts at every letter which does not follow a
+/// letter, so `o'neil` becomes `O'Neil` and `2-way` becomes `2-Way`.
+/// Combining marks do not end a word.
+pub fn proper(text: &str) -> String {
+    let mut proper = String::with_capacity(text.len());
+    let mut in_word = false;
+    for c in text.chars() {
+        if is_combining(c) {
+            proper.push(c);
+        } else if c.is_alphabetic() {
+            match in_word {
+                true => proper.extend(c.to_lowercase()),
+                false => proper.extend(c.to_uppercase()),
+            }
+            in_word = true;
+        } else {
+            proper.push(c);
+            in_word = false;
+        }
+    }
+    proper
+}
+
+/// Get the character `CHAR` returns for a code from 1 to 255, which is the
+/// character of that byte in Windows-1252.
+pub fn windows_1252_char(code: u8) -> Option<char> {
+    (code != 0).then(|| Encoding::Windows1252.decode(&[code]).chars().next()).flatten()
+}
+
+/// Get the code `CODE` returns for a character, its byte in Windows-1252,
+/// or 63 for `?` if it has none like in spreadsheets.
+pub fn windows_1252_code(c: char) -> u8 {
+    (1..=255).find(|code| windows_1252_char(*code) == Some(c)).unwrap_or(b'?')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_splits_at_occurrences() {
+        let search = |instance: i64| TextSearch{instance, ..TextSearch::default()};
+        assert_eq!(text_before("a-b-c", "-", &search(1)), Ok("a".to_string()));
+        assert_eq!(text_before("a-b-c", "-", &search(-1)), Ok("a-b".to_string()));
+        assert_eq!(text_after("a-b-c", "-", &search(2)), Ok("c".to_string()));
+        assert_eq!(text_after("a-b-c", "-", &search(3)), Err(SplitError::NotFound));
+        assert_eq!(text_after("a-b-c", "-", &search(0)), Err(SplitError::InvalidInstance));
+        assert_eq!(text_after("aXbxc", "x", &TextSearch{ignore_case: true, ..TextSearch::default()}), Ok("bxc".to_string()));
+        assert_eq!(text_before("abc", "-", &TextSearch{match_end: true, ..TextSearch::default()}), Ok("abc".to_string()));
+        assert_eq!(text_after("a–b", "–", &search(1)), Ok("b".to_string()));
+    }
+
+    #[test]
+    fn proper_capitalizes_words() {
+        assert_eq!(proper("o'neil of 2-way STREET"), "O'Neil Of 2-Way Street");
+        assert_eq!(proper("e\u{301}cole"), "E\u{301}cole");
+    }
+
+    #[test]
+    fn windows_1252_codes_round_trip() {
+        assert_eq!(windows_1252_char(65), Some('A'));
+        assert_eq!(windows_1252_char(0x80), Some('€'));
+        for code in 1..=255u8 {
+            if let Some(c) = windows_1252_char(code) {
+                assert_eq!(windows_1252_code(c), code);
+            }
+        }
+    }
+}
@@ -0,0 +1,515 @@
+use super::arithmetic::Arithmetic;
+use super::compare::escape_html;
+use super::conditional::Color;
+use super::kernel::CellId;
+use super::workbook::{Workbook, WorkbookError};
+use super::xml::tags;
+use thiserror::Error;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+#[derive(Error, Debug)]
+pub enum ThemeError {
+    #[error("theme has no {0}")]
+    Missing(&'static str),
+
+    #[error("{0} is not a theme color")]
+    InvalidColor(String),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// ThemeColor is a slot of the color palette of a theme, in the order of
+/// the `clrScheme` of `theme1.xml`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ThemeColor {
+    Dark1,
+    Light1,
+    Dark2,
+    Light2,
+    Accent1,
+    Accent2,
+    Accent3,
+    Accent4,
+    Accent5,
+    Accent6,
+    Hyperlink,
+    FollowedHyperlink,
+}
+
+impl ThemeColor {
+    pub const ALL: [Self; 12] = [
+        Self::Dark1, Self::Light1, Self::Dark2, Self::Light2,
+        Self::Accent1, Self::Accent2, Self::Accent3, Self::Accent4, Self::Accent5, Self::Accent6,
+        Self::Hyperlink, Self::FollowedHyperlink,
+    ];
+
+    /// Get the name of the element of the slot in `theme1.xml`.
+    pub fn xml_name(&self) -> &'static str {
+        match self {
+            Self::Dark1 => "dk1",
+            Self::Light1 => "lt1",
+            Self::Dark2 => "dk2",
+            Self::Light2 => "lt2",
+            Self::Accent1 => "accent1",
+            Self::Accent2 => "accent2",
+            Self::Accent3 => "accent3",
+            Self::Accent4 => "accent4",
+            Self::Accent5 => "accent5",
+            Self::Accent6 => "accent6",
+            Self::Hyperlink => "hlink",
+            Self::FollowedHyperlink => "folHlink",
+        }
+    }
+
+    /// Get the slot of the `theme` attribute of colors in the styles of
+    /// XLSX files, which swaps the dark and light colors: 0 is `lt1` and 1
+    /// is `dk1`.
+    pub fn from_index(index: u32) -> Option<Self> {
+        match index {
+            0 => Some(Self::Light1),
+            1 => Some(Self::Dark1),
+            2 => Some(Self::Light2),
+            3 => Some(Self::Dark2),
+            index => Self::ALL.get(index as usize).copied(),
+        }
+    }
+
+    /// Get the `theme` attribute of the slot, see `from_index`.
+    pub fn index(&self) -> u32 {
+        match self {
+            Self::Light1 => 0,
+            Self::Dark1 => 1,
+            Self::Light2 => 2,
+            Self::Dark2 => 3,
+            slot => Self::ALL.iter().position(|other| other == slot).expect("every slot is listed") as u32,
+        }
+    }
+}
+
+/// Theme is the color palette and fonts styles refer to, so a workbook is
+/// re-skinned by switching its theme instead of editing every style.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Theme {
+    pub name: String,
+    /// The colors of the slots, in the order of `ThemeColor::ALL`.
+    pub colors: [Color; 12],
+    /// The font of headings.
+    pub major_font: String,
+    /// The font of body text, which cells use unless styled otherwise.
+    pub minor_font: String,
+    /// The `fmtScheme` element of the `theme1.xml` a theme was read from,
+    /// written back as it is so fills and lines round-trip. A minimal one
+    /// is written if None.
+    pub format_scheme: Option<String>,
+}
+
+impl Default for Theme {
+    /// The Office theme of current spreadsheet applications.
+    fn default() -> Self {
+        let hex = |rgb: u32| Color::rgb((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8);
+        Self{
+            name: "Office Theme".to_string(),
+            colors: [
+                0x000000, 0xFFFFFF, 0x44546A, 0xE7E6E6,
+                0x4472C4, 0xED7D31, 0xA5A5A5, 0xFFC000, 0x5B9BD5, 0x70AD47,
+                0x0563C1, 0x954F72,
+            ].map(hex),
+            major_font: "Calibri Light".to_string(),
+            minor_font: "Calibri".to_string(),
+            format_scheme: None,
+        }
+    }
+}
+
+const MINIMAL_FORMAT_SCHEME: &str = concat!(
+    "<a:fmtScheme name=\"Office\">",
+    "<a:fillStyleLst>",
+    "<a:solidFill><a:schemeClr val=\"phClr\"/></a:solidFill>",
+    "<a:solidFill><a:schemeClr val=\"phClr\"/></a:solidFill>",
+    "<a:solidFill><a:schemeClr val=\"phClr\"/></a:solidFill>",
+    "</a:fillStyleLst>",
+    "<a:lnStyleLst>",
+    "<a:ln w=\"6350\"><a:solidFill><a:schemeClr val=\"phClr\"/></a:solidFill></a:ln>",
+    "<a:ln w=\"12700\"><a:solidFill><a:schemeClr val=\"phClr\"/></a:solidFill></a:ln>",
+    "<a:ln w=\"19050\"><a:solidFill><a:schemeClr val=\"phClr\"/></a:solidFill></a:ln>",
+    "</a:lnStyleLst>",
+    "<a:effectStyleLst>",
+    "<a:effectStyle><a:effectLst/></a:effectStyle>",
+    "<a:effectStyle><a:effectLst/></a:effectStyle>",
+    "<a:effectStyle><a:effectLst/></a:effectStyle>",
+    "</a:effectStyleLst>",
+    "<a:bgFillStyleLst>",
+    "<a:solidFill><a:schemeClr val=\"phClr\"/></a:solidFill>",
+    "<a:solidFill><a:schemeClr val=\"phClr\"/></a:solidFill>",
+    "<a:solidFill><a:schemeClr val=\"phClr\"/></a:solidFill>",
+    "</a:bgFillStyleLst>",
+    "</a:fmtScheme>",
+);
+
+fn parse_hex(hex: &str) -> Option<Color> {
+    if hex.len() != 6 {
+        return None;
+    }
+    let rgb = u32::from_str_radix(hex, 16).ok()?;
+    Some(Color::rgb((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8))
+}
+
+impl Theme {
+    pub fn color(&self, slot: ThemeColor) -> Color {
+        self.colors[slot as usize]
+    }
+
+    pub fn set_color(&mut self, slot: ThemeColor, color: Color) {
+        self.colors[slot as usize] = color;
+    }
+
+    /// Parse the `xl/theme/theme1.xml` part of an XLSX file. System colors
+    /// take the color they were last saved with.
+    pub fn from_xml(xml: &str) -> Result<Self, ThemeError> {
+        let tags = tags(xml);
+        let mut theme = Self{format_scheme: None, ..Self::default()};
+        theme.name = tags.iter().find(|tag| tag.name == "theme" && !tag.closing)
+            .and_then(|tag| tag.attribute("name"))
+            .unwrap_or_default();
+
+        let scheme = tags.iter().position(|tag| tag.name == "clrScheme" && !tag.closing).ok_or(ThemeError::Missing("color scheme"))?;
+        for slot in ThemeColor::ALL {
+            let start = tags[scheme..].iter().position(|tag| tag.name == slot.xml_name() && !tag.closing)
+                .ok_or(ThemeError::Missing(slot.xml_name()))?;
+            let tag = tags[scheme + start + 1..].iter().find(|tag| matches!(tag.name, "srgbClr" | "sysClr"))
+                .ok_or(ThemeError::Missing(slot.xml_name()))?;
+            let hex = match tag.name {
+                "srgbClr" => tag.attribute("val"),
+                _ => tag.attribute("lastClr"),
+            }.unwrap_or_default();
+            let color = parse_hex(&hex).ok_or_else(|| ThemeError::InvalidColor(hex.clone()))?;
+            theme.set_color(slot, color);
+        }
+
+        for (element, font) in [("majorFont", &mut theme.major_font), ("minorFont", &mut theme.minor_font)] {
+            let start = tags.iter().position(|tag| tag.name == element && !tag.closing).ok_or(ThemeError::Missing("font scheme"))?;
+            *font = tags[start..].iter().find(|tag| tag.name == "latin")
+                .and_then(|tag| tag.attribute("typeface"))
+                .ok_or(ThemeError::Missing("font scheme"))?;
+        }
+
+        let format_start = tags.iter().find(|tag| tag.name == "fmtScheme" && !tag.closing);
+        theme.format_scheme = format_start.and_then(|start| match start.self_closing {
+            true => Some(xml[start.start..start.end].to_string()),
+            false => {
+                let end = tags.iter().find(|tag| tag.name == "fmtScheme" && tag.closing && tag.start > start.start)?;
+                Some(xml[start.start..end.end].to_string())
+            },
+        });
+        Ok(theme)
+    }
+
+    /// Write the theme as the `xl/theme/theme1.xml` part of an XLSX file.
+    pub fn to_xml(&self) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n");
+        xml.push_str(&format!(
+            "<a:theme xmlns:a=\"http://schemas.openxmlformats.org/drawingml/2006/main\" name=\"{}\"><a:themeElements>",
+            escape_html(&self.name),
+        ));
+        xml.push_str(&format!("<a:clrScheme name=\"{}\">", escape_html(&self.name)));
+        for slot in ThemeColor::ALL {
+            let Color{r, g, b} = self.color(slot);
+            xml.push_str(&format!("<a:{0}><a:srgbClr val=\"{1:02X}{2:02X}{3:02X}\"/></a:{0}>", slot.xml_name(), r, g, b));
+        }
+        xml.push_str("</a:clrScheme>");
+        xml.push_str(&format!("<a:fontScheme name=\"{}\">", escape_html(&self.name)));
+        for (element, font) in [("majorFont", &self.major_font), ("minorFont", &self.minor_font)] {
+            xml.push_str(&format!(
+                "<a:{0}><a:latin typeface=\"{1}\"/><a:ea typeface=\"\"/><a:cs typeface=\"\"/></a:{0}>",
+                element,
+                escape_html(font),
+            ));
+        }
+        xml.push_str("</a:fontScheme>");
+        xml.push_str(self.format_scheme.as_deref().unwrap_or(MINIMAL_FORMAT_SCHEME));
+        xml.push_str("</a:themeElements></a:theme>\n");
+        xml
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, ThemeError> {
+        Self::from_xml(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), ThemeError> {
+        std::fs::write(path, self.to_xml())?;
+        Ok(())
+    }
+
+    /// Resolve the colors and font of a style against this theme.
+    pub fn resolve(&self, style: &CellStyle) -> ResolvedStyle {
+        ResolvedStyle{
+            font: style.font.as_ref().map_or(self.minor_font.as_str(), |font| font.resolve(self)).to_string(),
+            font_color: style.font_color.as_ref().map(|color| color.resolve(self)),
+            fill: style.fill.as_ref().map(|color| color.resolve(self)),
+            bold: style.bold,
+            italic: style.italic,
+        }
+    }
+}
+
+/// Get the hue, saturation and lightness of a color, each from 0 to 1.
+fn to_hsl(color: Color) -> (f64, f64, f64) {
+    let [r, g, b] = [color.r, color.g, color.b].map(|channel| channel as f64 / 255.0);
+    let (max, min) = (r.max(g).max(b), r.min(g).min(b));
+    let lightness = (max + min) / 2.0;
+    if max == min {
+        return (0.0, 0.0, lightness);
+    }
+    let delta = max - min;
+    let saturation = match lightness > 0.5 {
+        true => delta / (2.0 - max - min),
+        false => delta / (max + min),
+    };
+    let hue = match max {
+        max if max == r => (g - b) / delta + if g < b { 6.0 } else { 0.0 },
+        max if max == g => (b - r) / delta + 2.0,
+        _ => (r - g) / delta + 4.0,
+    };
+    (hue / 6.0, saturation, lightness)
+}
+
+fn from_hsl(hue: f64, saturation: f64, lightness: f64) -> Color {
+    if saturation == 0.0 {
+        let gray = (lightness * 255.0).round() as u8;
+        return Color::rgb(gray, gray, gray);
+    }
+    let q = match lightness < 0.5 {
+        true => lightness * (1.0 + saturation),
+        false => lightness + saturation - lightness * saturation,
+    };
+    let p = 2.0 * lightness - q;
+    let channel = |t: f64| {
+        let t = t.rem_euclid(1.0);
+        let value = match t {
+            t if t < 1.0 / 6.0 => p + (q - p) * 6.0 * t,
+            t if t < 0.5 => q,
+            t if t < 2.0 / 3.0 => p + (q - p) * (2.0 / 3.0 - t) * 6.0,
+            _ => p,
+        };
+        (value * 255.0).round() as u8
+    };
+    Color::rgb(channel(hue + 1.0 / 3.0), channel(hue), channel(hue - 1.0 / 3.0))
+}
+
+/// Lighten a color towards white by a positive tint, or darken it towards
+/// black by a negative one, as spreadsheets apply tints to theme colors:
+/// through its lightness, keeping its hue.
+pub fn apply_tint(color: Color, tint: f64) -> Color {
+    let tint = tint.clamp(-1.0, 1.0);
+    if tint == 0.0 {
+        return color;
+    }
+    let (hue, saturation, lightness) = to_hsl(color);
+    let lightness = match tint < 0.0 {
+        true => lightness * (1.0 + tint),
+        false => lightness * (1.0 - tint) + tint,
+    };
+    from_hsl(hue, saturation, lightness)
+}
+
+/// ColorRef is a color of a style, fixed or taken from the theme.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorRef {
+    Rgb(Color),
+    /// A slot of the theme, lightened by a positive tint or darkened by a
+    /// negative one, from -1 to 1.
+    Theme{color: ThemeColor, tint: f64},
+}
+
+impl ColorRef {
+    pub fn theme(color: ThemeColor) -> Self {
+        Self::Theme{color, tint: 0.0}
+    }
+
+    pub fn resolve(&self, theme: &Theme) -> Color {
+        match *self {
+            Self::Rgb(color) => color,
+            Self::Theme{color, tint} => apply_tint(theme.color(color), tint),
+        }
+    }
+}
+
+/// FontRef is the font of a style, fixed or taken from the theme.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FontRef {
+    /// The heading font of the theme.
+    Major,
+    /// The body font of the theme.
+    Minor,
+    Named(String),
+}
+
+impl FontRef {
+    pub fn resolve<'a>(&'a self, theme: &'a Theme) -> &'a str {
+        match self {
+            Self::Major => &theme.major_font,
+            Self::Minor => &theme.minor_font,
+            Self::Named(name) => name,
+        }
+    }
+}
+
+/// StyleId is the position of a style in the styles of a workbook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StyleId(pub u32);
+
+/// CellStyle is how a cell is drawn: its font, text color and fill. Unset
+/// parts are drawn as spreadsheets do by default, in the body font of the
+/// theme with automatic colors.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CellStyle {
+    pub font: Option<FontRef>,
+    pub font_color: Option<ColorRef>,
+    pub fill: Option<ColorRef>,
+    pub bold: bool,
+    pub italic: bool,
+}
+
+impl CellStyle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn font(mut self, font: FontRef) -> Self {
+        self.font = Some(font);
+        self
+    }
+
+    pub fn font_color(mut self, color: ColorRef) -> Self {
+        self.font_color = Some(color);
+        self
+    }
+
+    pub fn fill(mut self, color: ColorRef) -> Self {
+        self.fill = Some(color);
+        self
+    }
+
+    pub fn bold(mut self, bold: bool) -> Self {
+        self.bold = bold;
+        self
+    }
+
+    pub fn italic(mut self, italic: bool) -> Self {
+        self.italic = italic;
+        self
+    }
+}
+
+/// ResolvedStyle is a style with its theme colors and fonts looked up, as
+/// it is drawn. Colors are None where they are automatic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedStyle {
+    pub font: String,
+    pub font_color: Option<Color>,
+    pub fill: Option<Color>,
+    pub bold: bool,
+    pub italic: bool,
+}
+
+impl<T: Arithmetic> Workbook<T> {
+    /// Add a style for the cells of any sheet to share, and get its ID. A
+    /// style equal to one already added gets the ID of that one.
+    pub fn add_style(&mut self, style: CellStyle) -> StyleId {
+        let styles = self.styles_mut();
+        match styles.iter().position(|other| *other == style) {
+            Some(index) => StyleId(index as u32),
+            None => {
+                styles.push(style);
+                StyleId(styles.len() as u32 - 1)
+            },
+        }
+    }
+
+    pub fn style(&self, style: StyleId) -> Option<&CellStyle> {
+        self.styles().get(style.0 as usize)
+    }
+
+    /// Get the style of a cell with its theme colors and fonts looked up,
+    /// or None if it has no style.
+    pub fn cell_style(&self, sheet: &str, cell_id: CellId) -> Result<Option<ResolvedStyle>, WorkbookError> {
+        let own = self.sheet(sheet).ok_or_else(|| WorkbookError::UnknownSheet(sheet.to_string()))?;
+        Ok(own.style(cell_id).and_then(|style| self.style(style)).map(|style| self.theme().resolve(style)))
+    }
+
+    /// Switch to another theme, so every color and font referring to the
+    /// theme takes the new one. Get the theme switched from.
+    pub fn set_theme(&mut self, theme: Theme) -> Theme {
+        std::mem::replace(self.theme_mut(), theme)
+    }
+
+    /// Apply a theme to styles made with fixed colors, such as generated
+    /// reports: fixed colors equal to a color of the current theme are
+    /// turned into references to its slot, then the theme is switched so
+    /// they take the colors of the new one. Get the number of styles
+    /// changed.
+    pub fn apply_theme(&mut self, theme: Theme) -> usize {
+        let current = self.theme().clone();
+        let slot = |color: Color| ThemeColor::ALL.into_iter().find(|slot| current.color(*slot) == color);
+        let rebind = |color: &mut Option<ColorRef>| match *color {
+            Some(ColorRef::Rgb(rgb)) => match slot(rgb) {
+                Some(slot) => {
+                    *color = Some(ColorRef::theme(slot));
+                    true
+                },
+                None => false,
+            },
+            _ => false,
+        };
+        let mut changed = 0;
+        for style in self.styles_mut() {
+            let font = match &style.font {
+                Some(FontRef::Named(name)) if *name == current.major_font => Some(FontRef::Major),
+                Some(FontRef::Named(name)) if *name == current.minor_font => Some(FontRef::Minor),
+                _ => None,
+            };
+            let rebound = font.is_some();
+            if let Some(font) = font {
+                style.font = Some(font);
+            }
+            // Both colors are rebound, so no short circuit.
+            if rebind(&mut style.font_color) | rebind(&mut style.fill) | rebound {
+                changed += 1;
+            }
+        }
+        self.set_theme(theme);
+        changed
+    }
+
+    /// Merge the styles which became equal, such as after `apply_theme`,
+    /// and drop those no cell of any sheet uses, renumbering the styles of
+    /// every sheet. Get the number of styles removed.
+    pub fn consolidate_styles(&mut self) -> usize {
+        let used = self.sheets().flat_map(|(_, sheet)| sheet.styled_cells().map(|(_, style)| style)).collect::<HashSet<_>>();
+        let mut kept: Vec<CellStyle> = Vec::new();
+        let mut renumbered = HashMap::new();
+        for (index, style) in self.styles().iter().enumerate() {
+            if !used.contains(&StyleId(index as u32)) {
+                continue;
+            }
+            let position = match kept.iter().position(|other| other == style) {
+                Some(position) => position,
+                None => {
+                    kept.push(style.clone());
+                    kept.len() - 1
+                },
+            };
+            renumbered.insert(StyleId(index as u32), StyleId(position as u32));
+        }
+        let removed = self.styles().len() - kept.len();
+        *self.styles_mut() = kept;
+        for (_, sheet) in self.sheets_mut() {
+            for style in sheet.styles_mut().values_mut() {
+                *style = renumbered[style];
+            }
+        }
+        removed
+    }
+}
@@ -0,0 +1,253 @@
+use super::arithmetic::Arithmetic;
+use super::fixed_width::{convert, FieldType};
+use super::kernel::{escape_text, CellId, Kernel};
+use super::matcher::Pattern;
+use super::worksheet::Worksheet;
+use std::rc::Rc;
+
+/// Transform is a step cleaning the text of a field before it is entered.
+#[derive(Clone)]
+pub enum Transform {
+    /// Remove whitespace around the text.
+    Trim,
+    Lowercase,
+    Uppercase,
+    /// Keep the first part of the text matching a pattern, such as
+    /// `INV-??????` to pull invoice numbers out of descriptions, or nothing
+    /// if no part does. Patterns have the wildcards of `SEARCH`, as the
+    /// kernel has no regular expressions.
+    Extract(Pattern),
+    /// Keep only the number of text like `12.5 kg` or `approx. 300ms`,
+    /// with its sign and separators. Text without digits is kept.
+    StripUnit,
+    Custom(Rc<dyn Fn(&str) -> String>),
+}
+
+impl std::fmt::Debug for Transform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Trim => write!(f, "Trim"),
+            Self::Lowercase => write!(f, "Lowercase"),
+            Self::Uppercase => write!(f, "Uppercase"),
+            Self::Extract(pattern) => f.debug_tuple("Extract").field(pattern).finish(),
+            Self::StripUnit => write!(f, "StripUnit"),
+            Self::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
+
+impl Transform {
+    pub fn custom(function: impl Fn(&str) -> String + 'static) -> Self {
+        Self::Custom(Rc::new(function))
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        match self {
+            Self::Trim => text.trim().to_string(),
+            Self::Lowercase => text.to_lowercase(),
+            Self::Uppercase => text.to_uppercase(),
+            Self::Extract(pattern) => match pattern.find(text, 0) {
+                Some(range) => text.chars().skip(range.start).take(range.len()).collect(),
+                None => String::new(),
+            },
+            Self::StripUnit => strip_unit(text),
+            Self::Custom(function) => function(text),
+        }
+    }
+}
+
+/// Keep the number of text, from its sign or first digit through its last
+/// digit.
+fn strip_unit(text: &str) -> String {
+    let Some(first) = text.find(|c: char| c.is_ascii_digit()) else { return text.to_string() };
+    let last = text.rfind(|c: char| c.is_ascii_digit()).expect("a digit was found");
+    // A decimal point and sign right before the first digit belong to the
+    // number.
+    let mut start = first;
+    if text[..start].ends_with('.') {
+        start -= 1;
+    }
+    if text[..start].ends_with(['-', '+']) {
+        start -= 1;
+    }
+    text[start..=last].to_string()
+}
+
+/// ColumnKey is which column of a table a pipeline applies to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ColumnKey {
+    /// The column at an offset from the first column of the table.
+    Index(u32),
+    /// The column whose header, in the first row of the table, is this,
+    /// ignoring case and surrounding whitespace.
+    Header(String),
+}
+
+/// ColumnPipeline is the transforms of one column, and the type their
+/// result is entered as.
+#[derive(Debug, Clone)]
+pub struct ColumnPipeline {
+    pub column: ColumnKey,
+    pub transforms: Vec<Transform>,
+    /// The type the result must convert to. It is entered as text if it
+    /// does not, so it stands out. Results are entered as if typed if None.
+    pub field_type: Option<FieldType>,
+}
+
+/// TransformSpec is the pipelines cleaning the fields of a table as it is
+/// entered, by `Worksheet::set_range` or by importing with the
+/// `transforms` of `CsvOptions`. Header rows are not transformed.
+#[derive(Debug, Clone, Default)]
+pub struct TransformSpec {
+    /// The transforms of every column, run before those of its pipeline.
+    pub every_column: Vec<Transform>,
+    pub columns: Vec<ColumnPipeline>,
+}
+
+impl TransformSpec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn every_column(mut self, transforms: Vec<Transform>) -> Self {
+        self.every_column.extend(transforms);
+        self
+    }
+
+    pub fn column(mut self, col: u32, transforms: Vec<Transform>, field_type: Option<FieldType>) -> Self {
+        self.columns.push(ColumnPipeline{column: ColumnKey::Index(col), transforms, field_type});
+        self
+    }
+
+    pub fn header(mut self, name: &str, transforms: Vec<Transform>, field_type: Option<FieldType>) -> Self {
+        self.columns.push(ColumnPipeline{column: ColumnKey::Header(name.to_string()), transforms, field_type});
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.every_column.is_empty() && self.columns.is_empty()
+    }
+
+    /// Whether a pipeline finds its column by header, so the first row of
+    /// tables is a header.
+    pub fn has_headers(&self) -> bool {
+        self.columns.iter().any(|pipeline| matches!(pipeline.column, ColumnKey::Header(_)))
+    }
+
+    /// Run the pipelines of a column over a field, and get the raw contents
+    /// of its cell.
+    pub(crate) fn apply<T: Arithmetic>(&self, col: u32, header: Option<&str>, field: &str) -> String {
+        let pipelines = self.columns.iter().filter(|pipeline| match &pipeline.column {
+            ColumnKey::Index(index) => *index == col,
+            ColumnKey::Header(name) => header.is_some_and(|header| header.trim().eq_ignore_ascii_case(name.trim())),
+        });
+        let mut text = self.every_column.iter().fold(field.to_string(), |text, transform| transform.apply(&text));
+        let mut field_type = None;
+        for pipeline in pipelines {
+            text = pipeline.transforms.iter().fold(text, |text, transform| transform.apply(&text));
+            field_type = pipeline.field_type.or(field_type);
+        }
+        match field_type {
+            _ if text.trim().is_empty() => String::new(),
+            Some(field_type) => convert::<T>(text.trim(), field_type).unwrap_or_else(|_| escape_text(&text)),
+            None => text,
+        }
+    }
+}
+
+impl<T: Arithmetic> Worksheet<T> {
+    /// Enter rows of fields as if typed, with the first field of the first
+    /// row at `anchor`, cleaning them with the pipelines of a spec. If a
+    /// pipeline finds its column by header, the first row is the header
+    /// and is entered as it is. Get the number of fields the pipelines
+    /// changed.
+    pub fn set_range(&mut self, anchor: CellId, rows: &[Vec<String>], transforms: &TransformSpec) -> usize {
+        let headers = match transforms.has_headers() {
+            true => rows.first().cloned(),
+            false => None,
+        };
+        let mut changed = 0;
+        for (row, fields) in rows.iter().enumerate() {
+            for (col, field) in fields.iter().enumerate() {
+                let cell_id = CellId::new(anchor.row() + row as u32, anchor.col() + col as u32);
+                let raw = match headers.is_some() && row == 0 {
+                    true => field.clone(),
+                    false => {
+                        let header = headers.as_ref().and_then(|headers| headers.get(col)).map(String::as_str);
+                        transforms.apply::<T>(col as u32, header, field)
+                    },
+                };
+                if raw != *field {
+                    changed += 1;
+                }
+                self.set_cell(cell_id, raw);
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::budget::Budget;
+    use crate::kernel::csv::{parse_csv, CsvOptions};
+    use crate::kernel::matcher::MatchOptions;
+
+    fn raw(sheet: &Worksheet, cell_id: &str) -> String {
+        sheet.cell(CellId::parse(cell_id).unwrap()).map(|cell| cell.raw().to_string()).unwrap_or_default()
+    }
+
+    fn rows(rows: &[&[&str]]) -> Vec<Vec<String>> {
+        rows.iter().map(|row| row.iter().map(|field| field.to_string()).collect()).collect()
+    }
+
+    #[test]
+    fn transforms_clean_text() {
+        assert_eq!(Transform::Trim.apply("  a b  "), "a b");
+        assert_eq!(Transform::Uppercase.apply("Straße"), "STRASSE");
+        assert_eq!(Transform::Lowercase.apply("ABC"), "abc");
+        let invoice = Transform::Extract(Pattern::new("INV-??????", MatchOptions::default()));
+        assert_eq!(invoice.apply("paid inv-004217 in full"), "inv-004217");
+        assert_eq!(invoice.apply("no invoice"), "");
+        assert_eq!(Transform::StripUnit.apply("12.5 kg"), "12.5");
+        assert_eq!(Transform::StripUnit.apply("approx. -.5ms"), "-.5");
+        assert_eq!(Transform::StripUnit.apply("1,200 EUR"), "1,200");
+        assert_eq!(Transform::StripUnit.apply("none"), "none");
+        assert_eq!(Transform::custom(|text| text.replace('_', " ")).apply("a_b"), "a b");
+    }
+
+    #[test]
+    fn set_range_runs_pipelines_by_index_and_header() {
+        let spec = TransformSpec::new()
+            .every_column(vec![Transform::Trim])
+            .header("Weight", vec![Transform::StripUnit], Some(FieldType::Number))
+            .column(0, vec![Transform::Uppercase], None);
+        assert!(spec.has_headers());
+        let mut sheet: Worksheet = Worksheet::new();
+        let changed = sheet.set_range(CellId::new(1, 1), &rows(&[
+            &[" code ", "weight "],
+            &[" ab1", "12 kg"],
+            &["cd2 ", "heavy"],
+            &["=x", "  "],
+        ]), &spec);
+        assert_eq!(raw(&sheet, "B2"), " code ");
+        assert_eq!(raw(&sheet, "B3"), "AB1");
+        assert_eq!(raw(&sheet, "C3"), "12");
+        // A result which does not convert stands out as text.
+        assert_eq!(raw(&sheet, "C4"), "heavy");
+        assert_eq!(raw(&sheet, "B5"), "=X");
+        assert_eq!(raw(&sheet, "C5"), "");
+        assert_eq!(changed, 5);
+        assert!(TransformSpec::new().is_empty());
+    }
+
+    #[test]
+    fn csv_imports_run_pipelines() {
+        let options = CsvOptions{transforms: TransformSpec::new().column(1, vec![Transform::StripUnit], Some(FieldType::Number)), ..CsvOptions::default()};
+        let sheet: Worksheet = parse_csv("name,weight\nbox,3 kg\n", &options, &Budget::default()).unwrap();
+        assert_eq!(raw(&sheet, "B1"), "weight");
+        assert_eq!(raw(&sheet, "B2"), "3");
+        assert_eq!(raw(&sheet, "A2"), "box");
+    }
+}
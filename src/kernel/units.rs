@@ -0,0 +1,415 @@
+use super::arithmetic::{Arithmetic, Floating};
+#[cfg(feature = "units")]
+use super::eval::Arith;
+use super::kernel::{CellError, Numeric};
+#[cfg(feature = "units")]
+use super::kernel::NumericAttribute;
+use std::fmt;
+
+/// Dimension is the exponents of the SI base quantities a unit measures:
+/// length, mass, time, electric current, temperature, amount of substance
+/// and luminous intensity, in that order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Dimension(pub [i8; 7]);
+
+impl Dimension {
+    /// The dimension of plain numbers.
+    pub const NONE: Self = Self([0; 7]);
+
+    pub fn is_none(&self) -> bool {
+        *self == Self::NONE
+    }
+
+    /// Multiply by another dimension raised to a power.
+    fn times(self, other: Self, exponent: i32) -> Self {
+        let mut exponents = self.0;
+        for (exponent_of, other) in exponents.iter_mut().zip(other.0) {
+            *exponent_of = exponent_of.saturating_add((other as i32 * exponent).clamp(i8::MIN as i32, i8::MAX as i32) as i8);
+        }
+        Self(exponents)
+    }
+}
+
+const LENGTH: [i8; 7] = [1, 0, 0, 0, 0, 0, 0];
+const MASS: [i8; 7] = [0, 1, 0, 0, 0, 0, 0];
+const TIME: [i8; 7] = [0, 0, 1, 0, 0, 0, 0];
+const AREA: [i8; 7] = [2, 0, 0, 0, 0, 0, 0];
+const VOLUME: [i8; 7] = [3, 0, 0, 0, 0, 0, 0];
+const FORCE: [i8; 7] = [1, 1, -2, 0, 0, 0, 0];
+const ENERGY: [i8; 7] = [2, 1, -2, 0, 0, 0, 0];
+const POWER: [i8; 7] = [2, 1, -3, 0, 0, 0, 0];
+const PRESSURE: [i8; 7] = [-1, 1, -2, 0, 0, 0, 0];
+
+/// The units known by symbol: their scale to SI base units, dimension and
+/// whether they take SI prefixes. Exact symbols are matched before prefixed
+/// ones, so `min` is a minute and `h` an hour.
+const UNITS: [(&str, f64, [i8; 7], bool); 42] = [
+    ("m", 1.0, LENGTH, true),
+    ("g", 1e-3, MASS, true),
+    ("s", 1.0, TIME, true),
+    ("sec", 1.0, TIME, false),
+    ("A", 1.0, [0, 0, 0, 1, 0, 0, 0], true),
+    ("K", 1.0, [0, 0, 0, 0, 1, 0, 0], true),
+    ("mol", 1.0, [0, 0, 0, 0, 0, 1, 0], true),
+    ("cd", 1.0, [0, 0, 0, 0, 0, 0, 1], true),
+    ("min", 60.0, TIME, false),
+    ("mn", 60.0, TIME, false),
+    ("h", 3600.0, TIME, false),
+    ("hr", 3600.0, TIME, false),
+    ("day", 86400.0, TIME, false),
+    ("d", 86400.0, TIME, false),
+    ("yr", 31_557_600.0, TIME, false),
+    ("N", 1.0, FORCE, true),
+    ("J", 1.0, ENERGY, true),
+    ("W", 1.0, POWER, true),
+    ("Pa", 1.0, PRESSURE, true),
+    ("Hz", 1.0, [0, 0, -1, 0, 0, 0, 0], true),
+    ("C", 1.0, [0, 0, 1, 1, 0, 0, 0], true),
+    ("V", 1.0, [2, 1, -3, -1, 0, 0, 0], true),
+    ("ohm", 1.0, [2, 1, -3, -2, 0, 0, 0], true),
+    ("Ω", 1.0, [2, 1, -3, -2, 0, 0, 0], true),
+    ("L", 1e-3, VOLUME, true),
+    ("l", 1e-3, VOLUME, true),
+    ("ha", 1e4, AREA, false),
+    ("bar", 1e5, PRESSURE, true),
+    ("atm", 101_325.0, PRESSURE, false),
+    ("psi", 6_894.757_293_168, PRESSURE, false),
+    ("in", 0.0254, LENGTH, false),
+    ("ft", 0.3048, LENGTH, false),
+    ("yd", 0.9144, LENGTH, false),
+    ("mi", 1_609.344, LENGTH, false),
+    ("lbm", 0.453_592_37, MASS, false),
+    ("lb", 0.453_592_37, MASS, false),
+    ("oz", 0.028_349_523_125, MASS, false),
+    ("t", 1e3, MASS, false),
+    ("eV", 1.602_176_634e-19, ENERGY, true),
+    ("Wh", 3600.0, ENERGY, true),
+    ("cal", 4.184, ENERGY, false),
+    ("lbf", 4.448_221_615_260_5, FORCE, false),
+];
+
+/// The SI prefixes, `u` standing in for `µ`.
+const PREFIXES: [(&str, f64); 14] = [
+    ("T", 1e12), ("G", 1e9), ("M", 1e6), ("k", 1e3), ("h", 1e2), ("da", 1e1), ("d", 1e-1),
+    ("c", 1e-2), ("m", 1e-3), ("u", 1e-6), ("µ", 1e-6), ("n", 1e-9), ("p", 1e-12), ("f", 1e-15),
+];
+
+/// Get the scale to SI base units and the dimension of a unit symbol.
+fn resolve(symbol: &str) -> Option<(f64, Dimension)> {
+    if let Some((_, scale, dimension, _)) = UNITS.iter().find(|(known, ..)| *known == symbol) {
+        return Some((*scale, Dimension(*dimension)));
+    }
+    PREFIXES.iter().find_map(|(prefix, factor)| {
+        let rest = symbol.strip_prefix(prefix)?;
+        let (_, scale, dimension, _) = UNITS.iter().find(|(known, _, _, prefixed)| *prefixed && *known == rest)?;
+        Some((scale * factor, Dimension(*dimension)))
+    })
+}
+
+/// Unit is a product of powers of unit symbols, like `kg*m/s^2`. Units are
+/// kept as written, so `km*h` is not simplified, except that symbols
+/// repeated are merged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unit {
+    terms: Vec<(String, i32)>,
+}
+
+impl Unit {
+    /// Parse a unit of symbols with optional exponents, separated by `*`
+    /// to multiply and `/` to divide, like `m/s^2`, `N*m` or `m2`. Every
+    /// term after a `/` divides. Get None for text with an unknown symbol.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut unit = Self{terms: Vec::new()};
+        for (index, part) in text.split('/').enumerate() {
+            let sign = if index == 0 { 1 } else { -1 };
+            for term in part.split(['*', '·']) {
+                let term = term.trim();
+                if term.is_empty() || (index == 0 && term == "1") {
+                    continue;
+                }
+                let (symbol, exponent) = parse_term(term)?;
+                unit.push(symbol, exponent * sign);
+            }
+        }
+        (!unit.terms.is_empty()).then_some(unit)
+    }
+
+    pub fn terms(&self) -> &[(String, i32)] {
+        &self.terms
+    }
+
+    /// Get the factor converting a number in this unit to SI base units.
+    pub fn scale(&self) -> f64 {
+        self.terms.iter()
+            .map(|(symbol, exponent)| resolve(symbol).map_or(1.0, |(scale, _)| scale).powi(*exponent))
+            .product()
+    }
+
+    pub fn dimension(&self) -> Dimension {
+        self.terms.iter().fold(Dimension::NONE, |dimension, (symbol, exponent)| {
+            resolve(symbol).map_or(dimension, |(_, of)| dimension.times(of, *exponent))
+        })
+    }
+
+    fn push(&mut self, symbol: &str, exponent: i32) {
+        match self.terms.iter().position(|(known, _)| known == symbol) {
+            Some(index) => {
+                self.terms[index].1 += exponent;
+                if self.terms[index].1 == 0 {
+                    self.terms.remove(index);
+                }
+            },
+            None if exponent != 0 => self.terms.push((symbol.to_string(), exponent)),
+            None => {},
+        }
+    }
+
+    /// Get the unit of a product of numbers in this unit and `other`.
+    pub fn times(&self, other: &Self) -> Self {
+        self.times_power(other, 1)
+    }
+
+    /// Get the unit of a quotient of numbers in this unit and `other`.
+    pub fn per(&self, other: &Self) -> Self {
+        self.times_power(other, -1)
+    }
+
+    pub fn powi(&self, exponent: i32) -> Self {
+        Self{terms: self.terms.iter().filter(|_| exponent != 0).map(|(symbol, of)| (symbol.clone(), of * exponent)).collect()}
+    }
+
+    fn times_power(&self, other: &Self, power: i32) -> Self {
+        let mut unit = self.clone();
+        for (symbol, exponent) in &other.terms {
+            unit.push(symbol, exponent * power);
+        }
+        unit
+    }
+}
+
+/// Split a term into its symbol and exponent, written after `^` or, like
+/// `m2`, as digits after the symbol.
+fn parse_term(term: &str) -> Option<(&str, i32)> {
+    if let Some((symbol, exponent)) = term.split_once('^') {
+        let symbol = symbol.trim();
+        resolve(symbol)?;
+        return Some((symbol, exponent.trim().parse().ok()?));
+    }
+    if resolve(term).is_some() {
+        return Some((term, 1));
+    }
+    let digits = term.len() - term.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+    let (symbol, exponent) = term.split_at(term.len() - digits);
+    match digits {
+        0 => None,
+        _ => resolve(symbol).and(exponent.parse().ok()).map(|exponent| (symbol, exponent)),
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let write_term = |f: &mut fmt::Formatter<'_>, symbol: &str, exponent: i32| match exponent {
+            1 => write!(f, "{}", symbol),
+            exponent => write!(f, "{}^{}", symbol, exponent),
+        };
+        let mut numerator = self.terms.iter().filter(|(_, exponent)| *exponent > 0).peekable();
+        if numerator.peek().is_none() {
+            write!(f, "1")?;
+        }
+        for (index, (symbol, exponent)) in numerator.enumerate() {
+            if index > 0 {
+                write!(f, "*")?;
+            }
+            write_term(f, symbol, *exponent)?;
+        }
+        for (symbol, exponent) in self.terms.iter().filter(|(_, exponent)| *exponent < 0) {
+            write!(f, "/")?;
+            write_term(f, symbol, -exponent)?;
+        }
+        Ok(())
+    }
+}
+
+/// Get the scale and offset converting a temperature to kelvin, as
+/// `(t + offset) * scale`. These only convert with `CONVERT`, as
+/// temperatures on a scale with an offset cannot be multiplied.
+fn temperature(name: &str) -> Option<(f64, f64)> {
+    match name {
+        "K" | "kel" => Some((1.0, 0.0)),
+        "degC" | "°C" | "cel" => Some((1.0, 273.15)),
+        "degF" | "°F" | "fah" => Some((5.0 / 9.0, 459.67)),
+        "Rank" | "°R" => Some((5.0 / 9.0, 0.0)),
+        _ => None,
+    }
+}
+
+/// Convert a number between two units of the same dimension, or two
+/// temperature scales, like `CONVERT`. Get None for unknown units or units
+/// of different dimensions.
+pub fn convert(number: f64, from: &str, to: &str) -> Option<f64> {
+    if let (Some((from_scale, from_offset)), Some((to_scale, to_offset))) = (temperature(from), temperature(to)) {
+        return Some((number + from_offset) * from_scale / to_scale - to_offset);
+    }
+    let (from, to) = (Unit::parse(from)?, Unit::parse(to)?);
+    (from.dimension() == to.dimension()).then(|| number * from.scale() / to.scale())
+}
+
+/// Evaluate `CONVERT` of a number from one unit to another, or of a number
+/// with a unit to another unit when `from` is None. Unknown and mismatched
+/// units give `#N/A`, as in Excel, except with the `units` feature for a
+/// number whose unit does not measure the same as `from`.
+pub(crate) fn convert_numeric<T: Arithmetic>(number: &Numeric<T>, from: Option<&str>, to: &str) -> Result<Numeric<T>, CellError> {
+    #[cfg(feature = "units")]
+    if let Some(NumericAttribute::Unit(unit)) = number.attr() {
+        let target = Unit::parse(to).ok_or(CellError::NA)?;
+        if from.is_some_and(|from| Unit::parse(from).is_none_or(|from| from.dimension() != unit.dimension())) {
+            return Err(CellError::Unit);
+        }
+        if target.dimension() != unit.dimension() {
+            return Err(CellError::Unit);
+        }
+        let converted = number.number().to_f64() * unit.scale() / target.scale();
+        return Ok(Numeric::new(Floating::from_f64(converted), Some(NumericAttribute::Unit(target))));
+    }
+    let from = from.ok_or(CellError::Value)?;
+    let converted = Floating::from_f64(convert(number.value().to_f64(), from, to).ok_or(CellError::NA)?);
+    #[cfg(feature = "units")]
+    if temperature(to).is_none() || to == "K" {
+        if let Some(unit) = Unit::parse(to) {
+            return Ok(Numeric::new(converted, Some(NumericAttribute::Unit(unit))));
+        }
+    }
+    Ok(Numeric::new(converted, None))
+}
+
+/// Split a quantity written as a number and a unit, like `9.81 m/s^2`,
+/// into the number and the unit.
+#[cfg(feature = "units")]
+pub(crate) fn split_quantity(text: &str) -> Option<(&str, Unit)> {
+    let (number, unit) = text.split_once(' ')?;
+    Some((number.trim_end(), Unit::parse(unit.trim())?))
+}
+
+/// Get the unit of a number, None for plain numbers, and the number in it.
+#[cfg(feature = "units")]
+fn unit_of<T: Arithmetic>(number: &Numeric<T>) -> (Option<&Unit>, T) {
+    match number.attr() {
+        Some(NumericAttribute::Unit(unit)) => (Some(unit), number.number()),
+        _ => (None, number.value()),
+    }
+}
+
+/// Attach a unit to a number, or make it a plain number scaled to SI base
+/// units if the unit cancelled out, like `m/km`.
+#[cfg(feature = "units")]
+pub(crate) fn with_unit<T: Arithmetic>(number: T, unit: Unit) -> Numeric<T> {
+    match unit.dimension().is_none() {
+        true => Numeric::new(number * Floating::from_f64(unit.scale()), None),
+        false => Numeric::new(number, Some(NumericAttribute::Unit(unit))),
+    }
+}
+
+/// Apply an arithmetic operator to numbers of which at least one has a
+/// unit, checking dimensions: only numbers measuring the same add up, and
+/// only plain integers raise numbers with units. Sums are in the unit of
+/// the left operand, products and quotients in the units combined. Get None
+/// when neither number has a unit.
+#[cfg(feature = "units")]
+pub(crate) fn apply<T: Arithmetic>(op: Arith, a: &Numeric<T>, b: &Numeric<T>) -> Option<Result<Numeric<T>, CellError>> {
+    let ((a_unit, a), (b_unit, b)) = (unit_of(a), unit_of(b));
+    if a_unit.is_none() && b_unit.is_none() {
+        return None;
+    }
+    let none = Unit{terms: Vec::new()};
+    let (a_unit, b_unit) = (a_unit.unwrap_or(&none), b_unit.unwrap_or(&none));
+    let plain = |op: Arith, a: T, b: T| op.apply(Numeric::new(a, None), Numeric::new(b, None)).map(|number| number.value());
+    Some(match op {
+        Arith::Add | Arith::Sub => match a_unit.dimension() == b_unit.dimension() {
+            false => Err(CellError::Unit),
+            true => {
+                let unit = if a_unit.terms.is_empty() { b_unit } else { a_unit };
+                let scale = |of: &Unit| Floating::from_f64(of.scale() / unit.scale());
+                plain(op, a * scale(a_unit), b * scale(b_unit)).map(|number| with_unit(number, unit.clone()))
+            },
+        },
+        Arith::Mul => plain(op, a, b).map(|number| with_unit(number, a_unit.times(b_unit))),
+        Arith::Div => plain(op, a, b).map(|number| with_unit(number, a_unit.per(b_unit))),
+        Arith::Pow => {
+            let exponent = b.to_f64();
+            if !b_unit.dimension().is_none() || (!a_unit.terms.is_empty() && exponent.fract() != 0.0) {
+                return Some(Err(CellError::Unit));
+            }
+            let b = b * Floating::from_f64(b_unit.scale());
+            plain(op, a, b).map(|number| with_unit(number, a_unit.powi(exponent as i32)))
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "units")]
+    use crate::kernel::kernel::{CellId, Kernel, Primitive, Value};
+    #[cfg(feature = "units")]
+    use crate::kernel::worksheet::Worksheet;
+    #[cfg(feature = "units")]
+    use crate::kernel::format::Locale;
+
+    #[test]
+    fn units_parse_and_display() {
+        let unit = Unit::parse("kg*m/s^2").unwrap();
+        assert_eq!(unit.to_string(), "kg*m/s^2");
+        assert_eq!(unit.dimension(), Unit::parse("N").unwrap().dimension());
+        assert_eq!(unit.scale(), 1.0);
+        assert_eq!(Unit::parse("m2").unwrap().terms(), [("m".to_string(), 2)]);
+        assert_eq!(Unit::parse("m*m/m").unwrap().to_string(), "m");
+        assert_eq!(Unit::parse("1/s").unwrap().to_string(), "1/s");
+        assert!(Unit::parse("furlong").is_none());
+        assert!(Unit::parse("").is_none());
+        assert_eq!(Unit::parse("km").unwrap().per(&Unit::parse("h").unwrap()).to_string(), "km/h");
+        assert_eq!(Unit::parse("s").unwrap().powi(-2).to_string(), "1/s^2");
+    }
+
+    #[test]
+    fn convert_checks_dimensions() {
+        let close = |a: Option<f64>, b: f64| a.is_some_and(|a| (a - b).abs() < 1e-9);
+        assert!(close(convert(1.0, "mi", "km"), 1.609344));
+        assert!(close(convert(1.0, "h", "min"), 60.0));
+        assert!(close(convert(100.0, "degC", "degF"), 212.0));
+        assert!(close(convert(0.0, "K", "cel"), -273.15));
+        assert!(close(convert(1.0, "kWh", "J"), 3.6e6));
+        assert_eq!(convert(1.0, "m", "s"), None);
+        assert_eq!(convert(1.0, "m", "nope"), None);
+    }
+
+    #[test]
+    fn convert_numeric_without_a_unit_needs_one_to_convert_from() {
+        let meters = Numeric::new(1500.0, None);
+        assert_eq!(convert_numeric(&meters, Some("m"), "km").unwrap().value(), 1.5);
+        assert!(matches!(convert_numeric(&meters, None, "km"), Err(CellError::Value)));
+        assert!(matches!(convert_numeric(&meters, Some("m"), "kg"), Err(CellError::NA)));
+    }
+
+    #[cfg(feature = "units")]
+    #[test]
+    fn numbers_carry_their_units() {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), "100 km".to_string());
+        sheet.set_cell(CellId::new(1, 0), "2 h".to_string());
+        sheet.set_cell(CellId::new(2, 0), "=A1/A2".to_string());
+        sheet.set_cell(CellId::new(3, 0), "=CONVERT(A3,\"m/s\")".to_string());
+        sheet.set_cell(CellId::new(4, 0), "=A1+A6".to_string());
+        sheet.set_cell(CellId::new(5, 0), "500 m".to_string());
+        sheet.set_cell(CellId::new(6, 0), "=A1+A2".to_string());
+        sheet.set_cell(CellId::new(7, 0), "=A1^0.5".to_string());
+        sheet.set_cell(CellId::new(8, 0), "=A1/A6".to_string());
+        assert_eq!(sheet.formatted(CellId::new(2, 0), &Locale::default()).unwrap(), "50 km/h");
+        assert!(matches!(sheet.evaluate_cell(CellId::new(3, 0)).unwrap(), Value::Primitive(Primitive::Number(number)) if (number.number() - 13.888_888_888_9).abs() < 1e-9));
+        assert_eq!(sheet.formatted(CellId::new(4, 0), &Locale::default()).unwrap(), "100.5 km");
+        assert!(matches!(sheet.evaluate_cell(CellId::new(6, 0)).unwrap(), Value::Error(CellError::Unit)));
+        assert!(matches!(sheet.evaluate_cell(CellId::new(7, 0)).unwrap(), Value::Error(CellError::Unit)));
+        // Units which cancel out leave a plain number.
+        assert!(matches!(sheet.evaluate_cell(CellId::new(8, 0)).unwrap(), Value::Primitive(Primitive::Number(number)) if number.attr().is_none() && number.value() == 200.0));
+    }
+}
@@ -0,0 +1,104 @@
+use super::arithmetic::Arithmetic;
+use super::kernel::{Primitive, Value};
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// ValueParser turns the contents of a cell which is not a formula into a
+/// value, such as a custom literal syntax, a quantity with a domain unit or
+/// a ticket ID to keep as text. Registered parsers are consulted in order
+/// by `Cell::from` before the built-in parser; the first to return a value
+/// wins.
+pub trait ValueParser<T: Arithmetic=f64> {
+    /// Parse trimmed cell contents, or return None to leave them to the
+    /// next parser. Returning `Value::Raw` keeps the contents as text.
+    fn parse(&self, text: &str) -> Option<Value<T>>;
+}
+
+impl<T: Arithmetic, F> ValueParser<T> for F
+where F: Fn(&str) -> Option<Value<T>> {
+    fn parse(&self, text: &str) -> Option<Value<T>> {
+        self(text)
+    }
+}
+
+/// BuiltinParser is the parser every chain ends with: numbers, booleans,
+/// IP addresses, dates and times, and raw text for anything else.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BuiltinParser;
+
+impl<T: Arithmetic> ValueParser<T> for BuiltinParser {
+    fn parse(&self, text: &str) -> Option<Value<T>> {
+        Some(match Primitive::try_from(text) {
+            Ok(primitive) => Value::Primitive(primitive),
+            Err(()) => Value::Raw,
+        })
+    }
+}
+
+type Chain<T> = Vec<Rc<dyn ValueParser<T>>>;
+
+thread_local! {
+    /// The chain of each number type, as cells are created without a sheet
+    /// or workbook to hold it.
+    static CHAINS: RefCell<HashMap<TypeId, Box<dyn Any>>> = RefCell::new(HashMap::new());
+}
+
+/// Register a parser at the end of the chain of the current thread.
+pub fn register_value_parser<T: Arithmetic>(parser: impl ValueParser<T> + 'static) {
+    CHAINS.with_borrow_mut(|chains| {
+        chains.entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(Chain::<T>::new()))
+            .downcast_mut::<Chain<T>>()
+            .expect("chains are keyed by their number type")
+            .push(Rc::new(parser));
+    });
+}
+
+/// Remove every parser registered on the current thread, leaving only the
+/// built-in parser.
+pub fn clear_value_parsers<T: Arithmetic>() {
+    CHAINS.with_borrow_mut(|chains| chains.remove(&TypeId::of::<T>()));
+}
+
+/// Parse cell contents which are not a formula with the registered chain
+/// and then the built-in parser.
+pub(crate) fn parse_value<T: Arithmetic>(text: &str) -> Value<T> {
+    // The chain is cloned so parsers may create cells themselves.
+    let chain = CHAINS.with_borrow(|chains| {
+        chains.get(&TypeId::of::<T>())
+            .and_then(|chain| chain.downcast_ref::<Chain<T>>())
+            .cloned()
+            .unwrap_or_default()
+    });
+    chain.iter()
+        .find_map(|parser| parser.parse(text))
+        .or_else(|| BuiltinParser.parse(text))
+        .unwrap_or(Value::Raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Value {
+        Value::from(text)
+    }
+
+    #[test]
+    fn registered_parsers_run_in_order_before_the_builtin_one() {
+        assert!(matches!(parse("TICKET-12"), Value::Raw));
+        assert!(matches!(parse("1e3"), Value::Primitive(Primitive::Number(_))));
+        register_value_parser::<f64>(|text: &str| text.starts_with("TICKET-").then_some(Value::Raw));
+        register_value_parser::<f64>(|text: &str| text.strip_suffix('k').and_then(|number| number.parse::<f64>().ok()).map(|number| Value::from(format!("{}", number * 1000.0).as_str())));
+        register_value_parser::<f64>(|text: &str| text.starts_with("TICKET-").then_some(Value::Primitive(Primitive::Bool(true))));
+        assert!(matches!(parse("TICKET-12"), Value::Raw));
+        assert!(matches!(parse("2.5k"), Value::Primitive(Primitive::Number(number)) if number.value() == 2500.0));
+        assert!(matches!(parse("'2.5k"), Value::Raw));
+        assert!(matches!(parse("=1+1"), Value::Formula(_)));
+        assert!(matches!(parse("TRUE"), Value::Primitive(Primitive::Bool(true))));
+        clear_value_parsers::<f64>();
+        assert!(matches!(parse("2.5k"), Value::Raw));
+    }
+}
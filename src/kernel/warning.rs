@@ -0,0 +1,75 @@
+use super::kernel::FunctionKind;
+use std::fmt;
+
+/// CalcWarning is a soft issue found while calculating a formula. Unlike a
+/// cell error it does not change the result, but the result may not be what
+/// the author meant.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CalcWarning {
+    /// A number with a fraction was used where a whole number is expected,
+    /// and the fraction was dropped.
+    Truncated{number: f64},
+
+    /// A number was turned into text with fewer digits than it has, as when
+    /// concatenating `1/3`.
+    NumberToText{number: f64, text: String},
+
+    /// A function was used which spreadsheets keep only for compatibility.
+    Deprecated{function: FunctionKind, replacement: &'static str},
+
+    /// A number shown as a date or time cannot hold the time of day to the
+    /// millisecond at the precision calculation uses.
+    DateSerialPrecision{serial: f64},
+}
+
+impl fmt::Display for CalcWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated{number} => write!(f, "{} was truncated to a whole number", number),
+            Self::NumberToText{number, text} => write!(f, "{} was shown as {}", number, text),
+            Self::Deprecated{function, replacement} => write!(f, "{} is deprecated, use {}", function.name(), replacement),
+            Self::DateSerialPrecision{serial} => write!(f, "date serial {} is imprecise", serial),
+        }
+    }
+}
+
+impl FunctionKind {
+    /// Get the function which supersedes a function kept for compatibility.
+    pub fn replacement(&self) -> Option<&'static str> {
+        match self {
+            Self::Sdev => Some("STDEV.S"),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::{CellId, Kernel};
+    use crate::kernel::worksheet::Worksheet;
+
+    #[test]
+    fn formulas_collect_their_warnings_once() {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), "=CHOOSE(1.5,\"a\",\"b\")&CHOOSE(1.5,\"a\",\"b\")".to_string());
+        sheet.set_cell(CellId::new(1, 0), "=\"x\"&1/3".to_string());
+        sheet.set_cell(CellId::new(2, 0), "=STDEV(1,2,3)".to_string());
+        sheet.set_cell(CellId::new(3, 0), "=TEXT(1E+12,\"hh:mm:ss.000\")".to_string());
+        sheet.set_cell(CellId::new(4, 0), "=\"x\"&0.5".to_string());
+        for row in 0..5 {
+            sheet.evaluate_cell(CellId::new(row, 0)).unwrap();
+        }
+        assert_eq!(sheet.cell_warnings(CellId::new(0, 0)), [CalcWarning::Truncated{number: 1.5}]);
+        assert!(matches!(&sheet.cell_warnings(CellId::new(1, 0))[..], [CalcWarning::NumberToText{..}]));
+        assert_eq!(sheet.cell_warnings(CellId::new(2, 0))[0].to_string(), "STDEV is deprecated, use STDEV.S");
+        assert_eq!(sheet.cell_warnings(CellId::new(3, 0)), [CalcWarning::DateSerialPrecision{serial: 1e12}]);
+        assert!(sheet.cell_warnings(CellId::new(4, 0)).is_empty());
+        assert_eq!(sheet.warnings().iter().map(|(cell_id, _)| cell_id.row()).collect::<Vec<_>>(), [0, 1, 2, 3]);
+
+        sheet.set_cell(CellId::new(0, 0), "=CHOOSE(1,\"a\")".to_string());
+        assert!(sheet.cell_warnings(CellId::new(0, 0)).is_empty());
+        sheet.clear_warnings();
+        assert!(sheet.warnings().is_empty());
+    }
+}
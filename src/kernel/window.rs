@@ -0,0 +1,261 @@
+use super::arithmetic::Arithmetic;
+use super::format::from_serial;
+use super::kernel::{CellError, CellId, Formula, Kernel, Primitive, Value};
+use super::refactor::visit_references;
+use super::worksheet::{SheetError, Worksheet};
+
+/// Retention is which rows a rolling window keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retention {
+    /// The last rows, up to a number of them.
+    Rows(u32),
+    /// The rows whose timestamp, a date or text like `2024-05-01 13:45` in
+    /// the given column of the window counted from its first one, is at
+    /// most `max_age` old. Rows are kept
+    /// from the first one with a recent timestamp, or which has no date in
+    /// the column, so rows must be appended in time order.
+    Age{column: u32, max_age: chrono::TimeDelta},
+}
+
+/// RollingWindow is a region of a sheet fed with rows at its bottom, such
+/// as by a `CsvTailer`, which keeps only its recent rows. It spans some
+/// columns from a top row down to the last row used in them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RollingWindow {
+    pub name: String,
+    pub top: u32,
+    pub first_col: u32,
+    pub last_col: u32,
+    pub retention: Retention,
+}
+
+impl RollingWindow {
+    /// Create a window over the columns between two cells, from the upper
+    /// one down.
+    pub fn new(name: &str, first: CellId, second: CellId, retention: Retention) -> Self {
+        Self{
+            name: name.to_string(),
+            top: first.row().min(second.row()),
+            first_col: first.col().min(second.col()),
+            last_col: first.col().max(second.col()),
+            retention,
+        }
+    }
+
+    fn contains(&self, cell_id: CellId) -> bool {
+        cell_id.row() >= self.top && (self.first_col..=self.last_col).contains(&cell_id.col())
+    }
+
+    /// Get the corners of the rows of the window, or None if it is empty.
+    pub fn range<T: Arithmetic>(&self, sheet: &Worksheet<T>) -> Option<(CellId, CellId)> {
+        let bottom = sheet.cells().map(|(cell_id, _)| cell_id).filter(|cell_id| self.contains(*cell_id)).map(|cell_id| cell_id.row()).max()?;
+        Some((CellId::new(self.top, self.first_col), CellId::new(bottom, self.last_col)))
+    }
+
+    /// Count the rows at the top of the window past retention at `now`.
+    fn expired<T: Arithmetic>(&self, sheet: &Worksheet<T>, now: chrono::NaiveDateTime) -> Result<u32, SheetError> {
+        let Some((_, last)) = self.range(sheet) else { return Ok(0) };
+        let rows = last.row() - self.top + 1;
+        match self.retention {
+            Retention::Rows(max) => Ok(rows.saturating_sub(max)),
+            Retention::Age{column, max_age} => {
+                let col = self.first_col.saturating_add(column);
+                for offset in 0..rows {
+                    let timestamp = match sheet.evaluate_cell(CellId::new(self.top + offset, col))? {
+                        Value::Primitive(Primitive::Number(number)) => from_serial(number.number().to_f64()),
+                        Value::Primitive(Primitive::Date(date)) => date.and_hms_opt(0, 0, 0),
+                        Value::Primitive(Primitive::Text(text)) => parse_timestamp(&text),
+                        Value::Raw => sheet.cell(CellId::new(self.top + offset, col)).and_then(|cell| parse_timestamp(cell.text())),
+                        _ => None,
+                    };
+                    if timestamp.is_none_or(|timestamp| now - timestamp <= max_age) {
+                        return Ok(offset);
+                    }
+                }
+                Ok(rows)
+            },
+        }
+    }
+}
+
+/// The formats of the timestamps of logs, tried in turn.
+const TIMESTAMP_FORMATS: [&str; 4] = ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"];
+
+/// Parse a timestamp written as text, like `2024-05-01 13:45:10` or with a
+/// `T` between the date and the time. A time zone or `Z` after it is
+/// ignored, as times are compared in the zone they are written in.
+fn parse_timestamp(text: &str) -> Option<chrono::NaiveDateTime> {
+    let text = text.trim().trim_end_matches('Z');
+    let text = match text.rfind(['+', '-']) {
+        Some(index) if index > 10 => &text[..index],
+        _ => text,
+    };
+    TIMESTAMP_FORMATS.iter().find_map(|format| chrono::NaiveDateTime::parse_from_str(text, format).ok())
+}
+
+/// Eviction is the rows a window dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Eviction {
+    pub window: String,
+    pub rows: u32,
+    /// The number of formulas rewritten to follow the rows which moved up.
+    pub formulas: usize,
+}
+
+impl<T: Arithmetic> Worksheet<T> {
+    /// Drop the rows of every rolling window past its retention at `now`,
+    /// and move the rows left up to the top of the window, with their
+    /// number formats. References on this sheet to ranges starting at or
+    /// above the top of a window are its aggregates and stay as they are,
+    /// so `=AVERAGE(B2:B61)` over a window from row 2 keeping 60 rows
+    /// always averages the rows kept. Other references into a window follow
+    /// the rows they point to, and ranges lose their dropped rows. Formulas
+    /// referring to a dropped cell, or to a range of dropped rows only,
+    /// become `#REF!`. Get what each window which dropped rows dropped.
+    pub fn evict_windows(&mut self, now: chrono::NaiveDateTime) -> Result<Vec<Eviction>, SheetError> {
+        let mut evictions = Vec::new();
+        for window in self.windows().to_vec() {
+            let rows = window.expired(self, now)?;
+            if rows > 0 {
+                let formulas = self.evict(&window, rows);
+                evictions.push(Eviction{window: window.name.clone(), rows, formulas});
+            }
+        }
+        Ok(evictions)
+    }
+
+    /// Drop the first rows of a window, and count the formulas rewritten.
+    fn evict(&mut self, window: &RollingWindow, rows: u32) -> usize {
+        let mut ids = self.cell_ids().into_iter().filter(|cell_id| window.contains(*cell_id)).collect::<Vec<_>>();
+        ids.sort_by_key(|cell_id| cell_id.row());
+        let end = window.top + rows;
+        for cell_id in &ids {
+            let format = self.number_format(*cell_id).to_string();
+            self.set_number_format(*cell_id, "General");
+            let cell = self.clear_cell(*cell_id);
+            if cell_id.row() >= end {
+                let target = CellId::new(cell_id.row() - rows, cell_id.col());
+                if let Some(cell) = cell {
+                    self.set_cell(target, cell.raw().to_string());
+                }
+                self.set_number_format(target, &format);
+            }
+        }
+
+        // Where the cell a reference pointed to is now, None if dropped.
+        let moved = |cell_id: CellId| match window.contains(cell_id) {
+            false => Some(cell_id),
+            true if cell_id.row() < end => None,
+            true => Some(CellId::new(cell_id.row() - rows, cell_id.col())),
+        };
+        let columns = |a: CellId, b: CellId| a.col().min(b.col()) >= window.first_col && a.col().max(b.col()) <= window.last_col;
+        let rewritten = self.cells()
+            .filter_map(|(cell_id, cell)| {
+                let mut formula = cell.formula()?.clone();
+                let mut dropped = false;
+                let changed = visit_references(&mut formula, &mut |reference| {
+                    let replacement = match *reference {
+                        Formula::CellRef(cell_id) => match moved(cell_id) {
+                            None => {
+                                dropped = true;
+                                return false;
+                            },
+                            Some(target) if target == cell_id => return false,
+                            Some(target) => Formula::CellRef(target),
+                        },
+                        Formula::CellRange(a, b) if columns(a, b) && a.row().min(b.row()) > window.top => {
+                            let (top, bottom) = (a.row().min(b.row()), a.row().max(b.row()));
+                            if bottom < end {
+                                dropped = true;
+                                return false;
+                            }
+                            let shift = |row: u32| row.saturating_sub(rows).max(window.top);
+                            Formula::CellRange(CellId::new(shift(top), a.col().min(b.col())), CellId::new(shift(bottom), a.col().max(b.col())))
+                        },
+                        _ => return false,
+                    };
+                    *reference = replacement;
+                    true
+                });
+                match dropped {
+                    true => Some((cell_id, None)),
+                    false => changed.then_some((cell_id, Some(formula))),
+                }
+            })
+            .collect::<Vec<_>>();
+        for (cell_id, formula) in &rewritten {
+            match formula {
+                Some(formula) => self.set_cell(*cell_id, format!("={}", formula)),
+                None => self.set_value(*cell_id, Value::Error(CellError::Ref)),
+            }
+        }
+        rewritten.len()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::serialize::value_to_raw;
+
+    fn raw(sheet: &Worksheet, cell_id: &str) -> String {
+        value_to_raw(&sheet.evaluate_cell(CellId::parse(cell_id).unwrap()).unwrap())
+    }
+
+    fn at(text: &str) -> chrono::NaiveDateTime {
+        chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn windows_keep_their_last_rows_and_follow_them() {
+        let mut sheet: Worksheet = Worksheet::new();
+        for row in 1..=5 {
+            sheet.set_cell(CellId::new(row, 0), row.to_string());
+        }
+        sheet.set_cell(CellId::parse("C1").unwrap(), "=SUM(A2:A6)".to_string());
+        sheet.set_cell(CellId::parse("C2").unwrap(), "=A6".to_string());
+        sheet.set_cell(CellId::parse("C3").unwrap(), "=A2".to_string());
+        sheet.windows_mut().push(RollingWindow::new("log", CellId::parse("A2").unwrap(), CellId::parse("A2").unwrap(), Retention::Rows(3)));
+        let evictions = sheet.evict_windows(at("2024-05-01 00:00")).unwrap();
+        assert_eq!(evictions, vec![Eviction{window: "log".to_string(), rows: 2, formulas: 2}]);
+        assert_eq!(raw(&sheet, "A2"), "3");
+        assert_eq!(raw(&sheet, "A4"), "5");
+        assert!(sheet.cell(CellId::parse("A5").unwrap()).is_none());
+        assert_eq!(raw(&sheet, "C1"), "12");
+        assert_eq!(raw(&sheet, "C2"), "5");
+        assert_eq!(raw(&sheet, "C3"), "#REF!");
+    }
+
+    #[test]
+    fn windows_keep_recent_rows_by_text_timestamps() {
+        let mut sheet: Worksheet = Worksheet::new();
+        let times = ["2024-05-01 10:00", "2024-05-01T11:00:00Z", "2024-05-01 11:30:00+02:00"];
+        for (row, time) in times.iter().enumerate() {
+            sheet.set_cell(CellId::new(row as u32, 0), time.to_string());
+            sheet.set_cell(CellId::new(row as u32, 1), row.to_string());
+        }
+        let retention = Retention::Age{column: 0, max_age: chrono::TimeDelta::hours(1)};
+        sheet.windows_mut().push(RollingWindow::new("log", CellId::new(0, 0), CellId::new(0, 1), retention));
+        let evictions = sheet.evict_windows(at("2024-05-01 12:00")).unwrap();
+        assert_eq!(evictions[0].rows, 1);
+        assert_eq!(raw(&sheet, "B1"), "1");
+        assert_eq!(raw(&sheet, "B2"), "2");
+    }
+
+    #[test]
+    fn windows_keep_recent_rows_by_date_timestamps() {
+        let mut sheet: Worksheet = Worksheet::new();
+        for (row, day) in [1, 2, 3].into_iter().enumerate() {
+            let date = chrono::NaiveDate::from_ymd_opt(2024, 5, day).unwrap();
+            sheet.set_value(CellId::new(row as u32, 0), Value::Primitive(Primitive::Date(date)));
+            sheet.set_cell(CellId::new(row as u32, 1), day.to_string());
+        }
+        let retention = Retention::Age{column: 0, max_age: chrono::TimeDelta::days(1)};
+        sheet.windows_mut().push(RollingWindow::new("days", CellId::new(0, 0), CellId::new(0, 1), retention));
+        let evictions = sheet.evict_windows(at("2024-05-03 12:00")).unwrap();
+        assert_eq!(evictions[0].rows, 2);
+        assert_eq!(raw(&sheet, "B1"), "3");
+        assert!(sheet.cell(CellId::new(1, 1)).is_none());
+    }
+}
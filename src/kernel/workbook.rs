@@ -0,0 +1,562 @@
+use super::arithmetic::Arithmetic;
+use super::array::Array2D;
+use super::contract::Contract;
+use super::kernel::{evaluate_rectangle, Cell, CellError, CellId, Formula, Kernel, Value};
+use super::format::Locale;
+use super::functions::FunctionRegistry;
+use super::lookup::LookupIndex;
+use super::refactor::{names_used, rewrite_sheet};
+use super::refresh::QueryDefinition;
+use super::settings::CalcSettings;
+use super::snapshot::DataSnapshot;
+use super::strings::{StringPool, StringStats};
+use super::theme::{CellStyle, Theme};
+use super::worksheet::{SheetError, Worksheet};
+use thiserror::Error;
+use std::borrow::Cow;
+use std::rc::Rc;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum WorkbookError {
+    #[error("a sheet named {0} already exists")]
+    DuplicateSheet(String),
+
+    #[error("no sheet named {0}")]
+    UnknownSheet(String),
+
+    #[error("a name {0} is already defined")]
+    DuplicateName(String),
+
+    #[error("no name {0} is defined")]
+    UnknownName(String),
+
+    #[error("{0} is not a valid name")]
+    InvalidName(String),
+
+    #[error("{0} is not a cell or range reference")]
+    InvalidReference(String),
+
+    #[error(transparent)]
+    Sheet(#[from] SheetError),
+}
+
+/// Workbook is an ordered collection of named worksheets whose formulas may
+/// refer to each other. Sheet names are case insensitive. A workbook also
+/// keeps its defined names, which are case insensitive too, the
+/// definitions of its external data regions, the contracts its cells are
+/// held to, the snapshots of their values taken, and its theme with the
+/// styles the cells of every sheet share.
+pub struct Workbook<T: Arithmetic=f64> {
+    sheets: Vec<(String, Worksheet<T>)>,
+    names: Vec<(String, Formula<T>)>,
+    functions: FunctionRegistry<T>,
+    queries: Vec<QueryDefinition>,
+    contracts: Vec<Contract>,
+    snapshots: Vec<DataSnapshot<T>>,
+    locale: Locale,
+    settings: CalcSettings,
+    theme: Theme,
+    styles: Vec<CellStyle>,
+}
+
+impl<T: Arithmetic> Default for Workbook<T> {
+    fn default() -> Self {
+        Self{sheets: Vec::new(), names: Vec::new(), functions: FunctionRegistry::new(), queries: Vec::new(), contracts: Vec::new(), snapshots: Vec::new(), locale: Locale::default(), settings: CalcSettings::default(), theme: Theme::default(), styles: Vec::new()}
+    }
+}
+
+impl<T: Arithmetic> Workbook<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn index_of(&self, name: &str) -> Option<usize> {
+        self.sheets.iter().position(|(sheet, _)| sheet.eq_ignore_ascii_case(name))
+    }
+
+    /// Add an empty sheet at the end of the workbook.
+    pub fn add_sheet(&mut self, name: &str) -> Result<&mut Worksheet<T>, WorkbookError> {
+        self.insert_sheet(name, Worksheet::new())
+    }
+
+    /// Add an existing sheet at the end of the workbook.
+    pub fn insert_sheet(&mut self, name: &str, sheet: Worksheet<T>) -> Result<&mut Worksheet<T>, WorkbookError> {
+        if self.index_of(name).is_some() {
+            return Err(WorkbookError::DuplicateSheet(name.to_string()));
+        }
+        self.sheets.push((name.to_string(), sheet));
+        Ok(&mut self.sheets.last_mut().expect("sheet was just pushed").1)
+    }
+
+    pub fn remove_sheet(&mut self, name: &str) -> Result<Worksheet<T>, WorkbookError> {
+        let index = self.index_of(name).ok_or_else(|| WorkbookError::UnknownSheet(name.to_string()))?;
+        Ok(self.sheets.remove(index).1)
+    }
+
+    /// Copy a sheet and place the copy right after it. References the copy
+    /// makes to its own sheet by name are moved to the copy, like when
+    /// copying a sheet in a spreadsheet.
+    pub fn copy_sheet(&mut self, name: &str, new_name: &str) -> Result<&mut Worksheet<T>, WorkbookError> {
+        let index = self.index_of(name).ok_or_else(|| WorkbookError::UnknownSheet(name.to_string()))?;
+        if self.index_of(new_name).is_some() {
+            return Err(WorkbookError::DuplicateSheet(new_name.to_string()));
+        }
+        let mut copy = self.sheets[index].1.duplicate();
+        rewrite_sheet(&mut copy, &mut |reference| match reference {
+            Formula::SheetRef(sheet, _) if sheet.eq_ignore_ascii_case(name) => {
+                *sheet = new_name.to_string();
+                true
+            },
+            _ => false,
+        });
+        self.sheets.insert(index + 1, (new_name.to_string(), copy));
+        Ok(&mut self.sheets[index + 1].1)
+    }
+
+    /// Move a sheet to the end of another workbook, along with the names
+    /// its formulas use which the other workbook does not define. References
+    /// to sheets left behind, from the moved sheet or to it, evaluate to
+    /// `#REF!` while no sheet of that name exists.
+    pub fn move_sheet_to<'a>(&mut self, name: &str, other: &'a mut Workbook<T>) -> Result<&'a mut Worksheet<T>, WorkbookError> {
+        let index = self.index_of(name).ok_or_else(|| WorkbookError::UnknownSheet(name.to_string()))?;
+        let name = self.sheets[index].0.clone();
+        if other.index_of(&name).is_some() {
+            return Err(WorkbookError::DuplicateSheet(name));
+        }
+        let (_, sheet) = self.sheets.remove(index);
+        let mut pending = sheet.cells().filter_map(|(_, cell)| cell.formula()).flat_map(names_used).collect::<Vec<_>>();
+        while let Some(used) = pending.pop() {
+            let Some(index) = self.name_index(&used) else { continue };
+            if other.name_index(&used).is_none() {
+                let (defined, formula) = self.names[index].clone();
+                pending.extend(names_used(&formula));
+                other.names.push((defined, formula));
+            }
+        }
+        other.insert_sheet(&name, sheet)
+    }
+
+    /// Rebuild this workbook under another number type, such as to load a
+    /// model fast in `f64` and re-run it at a higher precision. Cells are
+    /// re-parsed like `Worksheet::convert`, and defined names from their
+    /// formula text. Registered functions and data snapshots work with one
+    /// number type and are not kept.
+    pub fn convert<U: Arithmetic>(&self) -> Result<Workbook<U>, WorkbookError> {
+        let mut converted = Workbook::new();
+        for (name, sheet) in &self.sheets {
+            converted.insert_sheet(name, sheet.convert())?;
+        }
+        for (name, formula) in &self.names {
+            let formula = Formula::try_from(formula.to_string().as_str()).map_err(|_| WorkbookError::InvalidName(name.clone()))?;
+            converted.define_name(name, formula)?;
+        }
+        converted.queries = self.queries.clone();
+        converted.contracts = self.contracts.clone();
+        converted.locale = self.locale.clone();
+        converted.settings = self.settings.clone();
+        converted.theme = self.theme.clone();
+        converted.styles = self.styles.clone();
+        Ok(converted)
+    }
+
+    pub fn sheet(&self, name: &str) -> Option<&Worksheet<T>> {
+        self.index_of(name).map(|index| &self.sheets[index].1)
+    }
+
+    pub fn sheet_mut(&mut self, name: &str) -> Option<&mut Worksheet<T>> {
+        self.index_of(name).map(|index| &mut self.sheets[index].1)
+    }
+
+    /// Get the names of the sheets in order.
+    pub fn sheet_names(&self) -> impl Iterator<Item=&str> {
+        self.sheets.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Iterate the sheets in order.
+    pub fn sheets(&self) -> impl Iterator<Item=(&str, &Worksheet<T>)> {
+        self.sheets.iter().map(|(name, sheet)| (name.as_str(), sheet))
+    }
+
+    pub fn sheets_mut(&mut self) -> impl Iterator<Item=(&str, &mut Worksheet<T>)> {
+        self.sheets.iter_mut().map(|(name, sheet)| (name.as_str(), sheet))
+    }
+
+    /// Share one copy of each long raw text among the cells of every sheet
+    /// holding it, such as labels repeated across sheets, and get the bytes
+    /// this freed.
+    pub fn deduplicate_strings(&mut self) -> usize {
+        let mut pool = StringPool::new();
+        self.sheets.iter_mut().map(|(_, sheet)| sheet.deduplicate_strings(&mut pool)).sum()
+    }
+
+    /// Get how the raw contents of the cells of every sheet are stored,
+    /// counting copies shared across sheets once.
+    pub fn string_stats(&self) -> StringStats {
+        StringStats::of(self.sheets.iter().flat_map(|(_, sheet)| sheet.cells()).map(|(_, cell)| cell.raw_text()))
+    }
+
+    pub(crate) fn rename_entry(&mut self, index: usize, name: &str) {
+        self.sheets[index].0 = name.to_string();
+    }
+
+    pub(crate) fn sheet_index(&self, name: &str) -> Option<usize> {
+        self.index_of(name)
+    }
+
+    fn name_index(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|(defined, _)| defined.eq_ignore_ascii_case(name))
+    }
+
+    /// Define a name for the whole workbook, or redefine it. A name must be
+    /// one a formula reads as a name, such as `TaxRate`, and not a cell
+    /// reference or function.
+    pub fn define_name(&mut self, name: &str, formula: Formula<T>) -> Result<(), WorkbookError> {
+        if !matches!(Formula::<T>::try_from(name), Ok(Formula::Name(ref parsed)) if parsed == name) {
+            return Err(WorkbookError::InvalidName(name.to_string()));
+        }
+        match self.name_index(name) {
+            Some(index) => self.names[index] = (name.to_string(), formula),
+            None => self.names.push((name.to_string(), formula)),
+        }
+        Ok(())
+    }
+
+    pub fn remove_name(&mut self, name: &str) -> Result<Formula<T>, WorkbookError> {
+        let index = self.name_index(name).ok_or_else(|| WorkbookError::UnknownName(name.to_string()))?;
+        Ok(self.names.remove(index).1)
+    }
+
+    /// Get the formula a name stands for.
+    pub fn name(&self, name: &str) -> Option<&Formula<T>> {
+        self.name_index(name).map(|index| &self.names[index].1)
+    }
+
+    /// Iterate the defined names in the order they were defined.
+    pub fn names(&self) -> impl Iterator<Item=(&str, &Formula<T>)> {
+        self.names.iter().map(|(name, formula)| (name.as_str(), formula))
+    }
+
+    pub(crate) fn names_mut(&mut self) -> &mut Vec<(String, Formula<T>)> {
+        &mut self.names
+    }
+
+    /// Get the functions registered for the workbook.
+    pub fn functions(&self) -> &FunctionRegistry<T> {
+        &self.functions
+    }
+
+    pub fn functions_mut(&mut self) -> &mut FunctionRegistry<T> {
+        &mut self.functions
+    }
+
+    /// Get the settings every sheet of the workbook is calculated with.
+    pub fn settings(&self) -> &CalcSettings {
+        &self.settings
+    }
+
+    pub fn settings_mut(&mut self) -> &mut CalcSettings {
+        &mut self.settings
+    }
+
+    /// Get the locale values are displayed in.
+    pub fn locale(&self) -> &Locale {
+        &self.locale
+    }
+
+    pub fn set_locale(&mut self, locale: Locale) {
+        self.locale = locale;
+    }
+
+    /// Get the theme the colors and fonts of styles refer to.
+    pub fn theme(&self) -> &Theme {
+        &self.theme
+    }
+
+    pub(crate) fn theme_mut(&mut self) -> &mut Theme {
+        &mut self.theme
+    }
+
+    /// Get the styles shared by the cells of every sheet, by `StyleId`.
+    pub fn styles(&self) -> &[CellStyle] {
+        &self.styles
+    }
+
+    pub(crate) fn styles_mut(&mut self) -> &mut Vec<CellStyle> {
+        &mut self.styles
+    }
+
+    /// Get the definitions of the external data regions.
+    pub fn queries(&self) -> &[QueryDefinition] {
+        &self.queries
+    }
+
+    pub(crate) fn queries_mut(&mut self) -> &mut Vec<QueryDefinition> {
+        &mut self.queries
+    }
+
+    /// Get the dependency contracts of the cells, see `verify_contracts`.
+    pub fn contracts(&self) -> &[Contract] {
+        &self.contracts
+    }
+
+    pub(crate) fn contracts_mut(&mut self) -> &mut Vec<Contract> {
+        &mut self.contracts
+    }
+
+    /// Get the snapshots of the values of the cells, see `take_snapshot`.
+    pub fn snapshots(&self) -> &[DataSnapshot<T>] {
+        &self.snapshots
+    }
+
+    pub(crate) fn snapshots_mut(&mut self) -> &mut Vec<DataSnapshot<T>> {
+        &mut self.snapshots
+    }
+
+    pub fn len(&self) -> usize {
+        self.sheets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sheets.is_empty()
+    }
+
+    pub fn set_cell(&mut self, sheet: &str, cell_id: CellId, data: String) -> Result<(), WorkbookError> {
+        self.sheet_mut(sheet)
+            .ok_or_else(|| WorkbookError::UnknownSheet(sheet.to_string()))?
+            .set_cell(cell_id, data);
+        Ok(())
+    }
+
+    /// Evaluate a cell, resolving references to other sheets of the workbook.
+    pub fn evaluate_cell(&self, sheet: &str, cell_id: CellId) -> Result<Value<T>, WorkbookError> {
+        let index = self.index_of(sheet).ok_or_else(|| WorkbookError::UnknownSheet(sheet.to_string()))?;
+        Ok(SheetView{workbook: self, index}.evaluate_cell(cell_id)?)
+    }
+
+    /// Evaluate a cell of a sheet, keeping the dynamic array its formula
+    /// produces as is.
+    pub(crate) fn evaluate_array(&self, sheet: &str, cell_id: CellId) -> Result<Value<T>, WorkbookError> {
+        let index = self.index_of(sheet).ok_or_else(|| WorkbookError::UnknownSheet(sheet.to_string()))?;
+        Ok(self.sheets[index].1.evaluate_formula(&SheetView{workbook: self, index}, cell_id)?)
+    }
+
+    /// Evaluate the formulas of a sheet, and get their values along with
+    /// those of the empty cells their dynamic arrays spill into, which come
+    /// with `true`. These are the values to write in place of the formulas.
+    pub(crate) fn formula_values(&self, sheet: &str) -> Result<Vec<(CellId, Value<T>, bool)>, WorkbookError> {
+        let formulas = self.sheet(sheet).ok_or_else(|| WorkbookError::UnknownSheet(sheet.to_string()))?
+            .cells()
+            .filter(|(_, cell)| cell.formula().is_some())
+            .map(|(cell_id, _)| cell_id)
+            .collect::<Vec<_>>();
+        let mut values = Vec::new();
+        for cell_id in formulas {
+            let value = self.evaluate_cell(sheet, cell_id)?;
+            if let (Value::Array(array), false) = (self.evaluate_array(sheet, cell_id)?, matches!(value, Value::Error(CellError::Spill))) {
+                for row in 0..array.rows() {
+                    for col in 0..array.cols() {
+                        let spilled = CellId::new(cell_id.row() + row as u32, cell_id.col() + col as u32);
+                        if spilled != cell_id {
+                            values.push((spilled, array.get(row, col).cloned().unwrap_or(Value::Empty), true));
+                        }
+                    }
+                }
+            }
+            values.push((cell_id, value, false));
+        }
+        values.sort_by_key(|(cell_id, _, _)| (cell_id.row(), cell_id.col()));
+        Ok(values)
+    }
+
+    /// Evaluate the rectangle of cells of a sheet between two corners.
+    pub fn evaluate_range(&self, sheet: &str, first: CellId, second: CellId) -> Result<Array2D<Value<T>>, WorkbookError> {
+        let index = self.index_of(sheet).ok_or_else(|| WorkbookError::UnknownSheet(sheet.to_string()))?;
+        Ok(SheetView{workbook: self, index}.evaluate_range(first, second)?)
+    }
+
+    /// Get the text a cell displays in the locale of the workbook.
+    pub fn formatted(&self, sheet: &str, cell_id: CellId) -> Result<String, WorkbookError> {
+        let index = self.index_of(sheet).ok_or_else(|| WorkbookError::UnknownSheet(sheet.to_string()))?;
+        let view = SheetView{workbook: self, index};
+        Ok(self.sheets[index].1.formatted_through(&view, cell_id, &self.locale)?)
+    }
+}
+
+/// SheetView evaluates one sheet of a workbook, resolving sheet-qualified
+/// references against the other sheets. Views are only handed to the
+/// evaluator, which never changes cells.
+struct SheetView<'a, T: Arithmetic> {
+    workbook: &'a Workbook<T>,
+    index: usize,
+}
+
+impl<T: Arithmetic> SheetView<'_, T> {
+    fn sheet(&self) -> &Worksheet<T> {
+        &self.workbook.sheets[self.index].1
+    }
+}
+
+impl<T: Arithmetic> Kernel<SheetError, T> for SheetView<'_, T> {
+    fn get_cell(&self, cell_id: CellId) -> Option<Cell<T>> {
+        self.sheet().get_cell(cell_id)
+    }
+
+    fn evaluate_cell(&self, cell_id: CellId) -> Result<Value<T>, SheetError> {
+        self.sheet().evaluate_through(self, cell_id)
+    }
+
+    fn set_cell(&mut self, _cell_id: CellId, _data: String) {
+        unreachable!("sheet views are read only")
+    }
+
+    fn calc_settings(&self) -> CalcSettings {
+        self.workbook.settings.clone()
+    }
+
+    fn evaluate_range(&self, first: CellId, second: CellId) -> Result<Array2D<Value<T>>, SheetError> {
+        self.sheet().memoized(|| evaluate_rectangle(first, second, |cell_id| self.evaluate_cell(cell_id)))
+    }
+
+    fn evaluate_sheet_cell(&self, sheet: &str, cell_id: CellId) -> Result<Value<T>, SheetError> {
+        match self.workbook.index_of(sheet) {
+            Some(index) => SheetView{workbook: self.workbook, index}.evaluate_cell(cell_id),
+            None => Ok(Value::Error(CellError::Ref)),
+        }
+    }
+
+    /// Names are defined for the whole workbook, so qualifying one with a
+    /// sheet of the workbook resolves the same name.
+    fn resolve_name(&self, sheet: Option<&str>, name: &str) -> Option<Formula<T>> {
+        if sheet.is_some_and(|sheet| self.workbook.index_of(sheet).is_none()) {
+            return None;
+        }
+        self.workbook.name(name).cloned()
+    }
+
+    fn functions(&self) -> Option<&FunctionRegistry<T>> {
+        Some(&self.workbook.functions)
+    }
+
+    fn snapshot_value(&self, snapshot: &str, sheet: Option<&str>, cell_id: CellId) -> Option<Value<T>> {
+        let snapshot = self.workbook.snapshot(snapshot)?;
+        Some(snapshot.value(sheet.unwrap_or(&self.workbook.sheets[self.index].0), cell_id))
+    }
+
+    fn get_sheet_cell(&self, sheet: &str, cell_id: CellId) -> Option<Cell<T>> {
+        let index = self.workbook.index_of(sheet)?;
+        self.workbook.sheets[index].1.get_cell(cell_id)
+    }
+
+    fn column_slice(&self, sheet: Option<&str>, col: u32, first_row: u32, last_row: u32) -> Option<Cow<'_, [Value<T>]>> {
+        let index = match sheet {
+            Some(sheet) => self.workbook.index_of(sheet)?,
+            None => self.index,
+        };
+        self.workbook.sheets[index].1.dense_column(col, first_row, last_row).map(Cow::Owned)
+    }
+
+    fn lookup_index(&self, sheet: Option<&str>, col: u32, first_row: u32, last_row: u32) -> Option<Rc<LookupIndex<T>>> {
+        let index = match sheet {
+            Some(sheet) => self.workbook.index_of(sheet)?,
+            None => self.index,
+        };
+        self.workbook.sheets[index].1.lookup_index(col, first_row, last_row)
+    }
+
+    fn is_row_hidden(&self, sheet: Option<&str>, row: u32) -> bool {
+        match sheet {
+            Some(sheet) => self.workbook.index_of(sheet).is_some_and(|index| self.workbook.sheets[index].1.outline().is_row_hidden(row)),
+            None => self.sheet().outline().is_row_hidden(row),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::{CellError, Primitive};
+
+    fn number(workbook: &Workbook, sheet: &str, row: u32) -> Option<f64> {
+        match workbook.evaluate_cell(sheet, CellId::new(row, 0)).unwrap() {
+            Value::Primitive(Primitive::Number(number)) => Some(number.value()),
+            _ => None,
+        }
+    }
+
+    fn workbook() -> Workbook {
+        let mut workbook = Workbook::new();
+        workbook.add_sheet("Inputs").unwrap();
+        workbook.add_sheet("Model").unwrap();
+        workbook.set_cell("Inputs", CellId::new(0, 0), "0.2".to_string()).unwrap();
+        workbook.set_cell("Inputs", CellId::new(1, 0), "100".to_string()).unwrap();
+        workbook.set_cell("Model", CellId::new(0, 0), "=Inputs!A2*(1+Inputs!A1)".to_string()).unwrap();
+        workbook.set_cell("Model", CellId::new(1, 0), "=SUM(Inputs!A1:A2)+A1".to_string()).unwrap();
+        workbook
+    }
+
+    #[test]
+    fn formulas_reach_other_sheets() {
+        let mut workbook = workbook();
+        assert_eq!(number(&workbook, "Model", 0), Some(120.0));
+        assert_eq!(number(&workbook, "model", 1), Some(220.2));
+        workbook.set_cell("Inputs", CellId::new(0, 0), "0.5".to_string()).unwrap();
+        assert_eq!(number(&workbook, "Model", 0), Some(150.0));
+    }
+
+    #[test]
+    fn sheets_are_checked() {
+        let mut workbook = workbook();
+        assert!(matches!(workbook.add_sheet("INPUTS"), Err(WorkbookError::DuplicateSheet(_))));
+        assert!(matches!(workbook.set_cell("Other", CellId::new(0, 0), "1".to_string()), Err(WorkbookError::UnknownSheet(_))));
+        assert!(matches!(workbook.evaluate_cell("Other", CellId::new(0, 0)), Err(WorkbookError::UnknownSheet(_))));
+        workbook.remove_sheet("Inputs").unwrap();
+        assert!(matches!(workbook.evaluate_cell("Model", CellId::new(0, 0)).unwrap(), Value::Error(CellError::Ref)));
+    }
+
+    #[test]
+    fn copies_refer_to_themselves() {
+        let mut workbook = workbook();
+        workbook.set_cell("Model", CellId::new(2, 0), "=Model!A1*2".to_string()).unwrap();
+        workbook.copy_sheet("Model", "Copy").unwrap();
+        workbook.add_sheet("Last").unwrap();
+        assert_eq!(workbook.sheets().map(|(name, _)| name).collect::<Vec<_>>(), ["Inputs", "Model", "Copy", "Last"]);
+        assert_eq!(workbook.sheet("Copy").unwrap().cell(CellId::new(2, 0)).unwrap().raw(), "=Copy!A1*2");
+        workbook.set_cell("Copy", CellId::new(0, 0), "7".to_string()).unwrap();
+        assert_eq!(number(&workbook, "Copy", 2), Some(14.0));
+        assert_eq!(number(&workbook, "Model", 2), Some(240.0));
+        assert!(matches!(workbook.copy_sheet("Model", "copy"), Err(WorkbookError::DuplicateSheet(_))));
+    }
+
+    #[test]
+    fn moved_sheets_bring_the_names_they_use() {
+        let mut workbook = workbook();
+        workbook.define_name("Base", Formula::try_from("100").unwrap()).unwrap();
+        workbook.define_name("Rate", Formula::try_from("Base/1000").unwrap()).unwrap();
+        workbook.define_name("Unused", Formula::try_from("1").unwrap()).unwrap();
+        workbook.set_cell("Model", CellId::new(2, 0), "=Rate*10".to_string()).unwrap();
+        let mut other: Workbook = Workbook::new();
+        workbook.move_sheet_to("model", &mut other).unwrap();
+        assert!(workbook.sheet("Model").is_none());
+        assert_eq!(other.names().map(|(name, _)| name).collect::<Vec<_>>(), ["Rate", "Base"]);
+        assert_eq!(number(&other, "Model", 2), Some(1.0));
+        assert!(matches!(other.evaluate_cell("Model", CellId::new(0, 0)).unwrap(), Value::Error(CellError::Ref)));
+        assert!(matches!(workbook.move_sheet_to("Model", &mut other), Err(WorkbookError::UnknownSheet(_))));
+    }
+
+    #[test]
+    fn deduplicate_strings_across_sheets() {
+        let label = "a label repeated on every sheet";
+        let mut workbook: Workbook = Workbook::new();
+        for sheet in ["One", "Two"] {
+            workbook.add_sheet(sheet).unwrap();
+            workbook.set_cell(sheet, CellId::new(0, 0), label.to_string()).unwrap();
+            workbook.set_cell(sheet, CellId::new(1, 0), "short".to_string()).unwrap();
+        }
+        assert_eq!(workbook.string_stats().owned, 2);
+        assert_eq!(workbook.deduplicate_strings(), label.len());
+        let stats = workbook.string_stats();
+        assert_eq!((stats.inline, stats.owned, stats.shared), (2, 0, 2));
+        assert_eq!(stats.heap_bytes, label.len());
+        assert_eq!(workbook.formatted("Two", CellId::new(0, 0)).unwrap(), label);
+    }
+}
@@ -0,0 +1,945 @@
+use super::access::{Permissions, Protection};
+use super::arithmetic::Arithmetic;
+use super::array::Array2D;
+use super::bytecode::Program;
+use super::datasource::DataSources;
+use super::eval::{EvalHooks, Evaluator};
+#[cfg(feature = "gpu")]
+use super::gpu::Offload;
+use super::format::{format_value, infer_number_format, Locale};
+use super::freeze::FrozenFormula;
+use super::kernel::{escape_text, evaluate_rectangle, Cell, CellError, CellId, Formula, Kernel, Primitive, Value};
+use super::lookup::LookupIndex;
+use super::memory::{map_bytes, set_bytes};
+use super::outline::Outline;
+use super::recorder::Recording;
+use super::rows::RowIds;
+use super::schedule::CalcChain;
+use super::settings::{CalcSettings, Precision};
+use super::sparkline::SparklineGroup;
+use super::theme::StyleId;
+use super::strings::{StringPool, StringStats};
+use super::warning::CalcWarning;
+use super::window::RollingWindow;
+use thiserror::Error;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::mem::size_of;
+use std::rc::Rc;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum SheetError {
+    #[error("circular reference through {0}")]
+    CircularReference(CellId),
+
+    #[error("key column {0} is outside the range")]
+    KeyColumnOutsideRange(u32),
+
+    #[error("the range spans more than one column")]
+    MultipleColumns,
+}
+
+/// How many rows per cell of the sheet a column may span and still be read
+/// densely by vectorized functions.
+const DENSE_FACTOR: usize = 8;
+
+/// The indexes of the columns lookups were made into, by column, first and
+/// last row. None for columns which cannot be indexed.
+type LookupIndexes<T> = HashMap<(u32, u32, u32), Option<Rc<LookupIndex<T>>>>;
+
+/// Worksheet is an in-memory grid of cells which evaluates formulas on demand.
+pub struct Worksheet<T: Arithmetic=f64> {
+    cells: HashMap<CellId, Cell<T>>,
+    /// The cells whose formulas may spill a dynamic array, so finding what
+    /// spills into a cell does not scan the sheet.
+    spill_anchors: HashSet<CellId>,
+    hooks: EvalHooks<T>,
+    sources: DataSources<T>,
+    permissions: Permissions,
+    protection: Protection,
+    formats: HashMap<CellId, String>,
+    styles: HashMap<CellId, StyleId>,
+    settings: CalcSettings,
+    calc_chains: Vec<CalcChain>,
+    outline: Outline,
+    sparklines: Vec<SparklineGroup>,
+    windows: Vec<RollingWindow>,
+    row_ids: Option<RowIds>,
+    frozen: HashMap<CellId, FrozenFormula>,
+    evaluating: RefCell<HashSet<CellId>>,
+    /// Results of formulas evaluated so far, kept while evaluating a cell or
+    /// a range so precedents shared by its cells are evaluated once.
+    memo: RefCell<Option<HashMap<CellId, Value<T>>>>,
+    /// Warnings of the latest evaluation of each formula.
+    warnings: RefCell<HashMap<CellId, Vec<CalcWarning>>>,
+    recording: RefCell<Option<Recording<T>>>,
+    index_lookups: bool,
+    lookup_indexes: RefCell<LookupIndexes<T>>,
+    compile_after: Option<u32>,
+    hot: RefCell<HashMap<CellId, Hot<T>>>,
+    #[cfg(feature = "gpu")]
+    offload: Option<Offload>,
+}
+
+/// Hot is how often a formula was evaluated, until it is compiled.
+enum Hot<T: Arithmetic> {
+    Evaluations(u32),
+    Compiled(Rc<Program<T>>),
+    /// The formula has nothing to compile.
+    Interpreted,
+}
+
+impl<T: Arithmetic> Default for Worksheet<T> {
+    fn default() -> Self {
+        Self{
+            cells: HashMap::new(),
+            spill_anchors: HashSet::new(),
+            hooks: EvalHooks::new(),
+            sources: DataSources::new(),
+            permissions: Permissions::new(),
+            protection: Protection::new(),
+            formats: HashMap::new(),
+            styles: HashMap::new(),
+            settings: CalcSettings::default(),
+            calc_chains: Vec::new(),
+            outline: Outline::new(),
+            sparklines: Vec::new(),
+            windows: Vec::new(),
+            row_ids: None,
+            frozen: HashMap::new(),
+            evaluating: RefCell::new(HashSet::new()),
+            memo: RefCell::new(None),
+            warnings: RefCell::new(HashMap::new()),
+            recording: RefCell::new(None),
+            index_lookups: false,
+            lookup_indexes: RefCell::new(HashMap::new()),
+            compile_after: None,
+            hot: RefCell::new(HashMap::new()),
+            #[cfg(feature = "gpu")]
+            offload: None,
+        }
+    }
+}
+
+impl<T: Arithmetic> Worksheet<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Copy the cells of this sheet with their number formats, styles,
+    /// permissions, protection, settings, calculation chains, outline,
+    /// sparklines, rolling windows, row IDs and frozen formulas. Hooks and
+    /// data sources belong to the embedder and are not copied.
+    pub fn duplicate(&self) -> Self {
+        Self{
+            cells: self.cells.clone(),
+            spill_anchors: self.spill_anchors.clone(),
+            permissions: self.permissions.clone(),
+            protection: self.protection.clone(),
+            formats: self.formats.clone(),
+            styles: self.styles.clone(),
+            settings: self.settings.clone(),
+            calc_chains: self.calc_chains.clone(),
+            outline: self.outline.clone(),
+            sparklines: self.sparklines.clone(),
+            windows: self.windows.clone(),
+            row_ids: self.row_ids.clone(),
+            frozen: self.frozen.clone(),
+            index_lookups: self.index_lookups,
+            compile_after: self.compile_after,
+            ..Self::default()
+        }
+    }
+
+    /// Rebuild this sheet under another number type, re-parsing the text of
+    /// every cell so numbers are read at the precision of the new type.
+    /// Hooks and data sources work with one number type and are not kept.
+    pub fn convert<U: Arithmetic>(&self) -> Worksheet<U> {
+        Worksheet{
+            cells: self.cells.iter().map(|(cell_id, cell)| (*cell_id, Cell::from(cell.raw().to_string()))).collect(),
+            spill_anchors: self.spill_anchors.clone(),
+            permissions: self.permissions.clone(),
+            protection: self.protection.clone(),
+            formats: self.formats.clone(),
+            styles: self.styles.clone(),
+            settings: self.settings.clone(),
+            calc_chains: self.calc_chains.clone(),
+            outline: self.outline.clone(),
+            sparklines: self.sparklines.clone(),
+            windows: self.windows.clone(),
+            row_ids: self.row_ids.clone(),
+            frozen: self.frozen.clone(),
+            index_lookups: self.index_lookups,
+            compile_after: self.compile_after,
+            ..Worksheet::default()
+        }
+    }
+
+    /// Get a cell without cloning it.
+    pub fn cell(&self, cell_id: CellId) -> Option<&Cell<T>> {
+        self.cells.get(&cell_id)
+    }
+
+    /// Iterate the non-empty cells of this sheet in no particular order.
+    pub fn cells(&self) -> impl Iterator<Item=(CellId, &Cell<T>)> {
+        self.cells.iter().map(|(cell_id, cell)| (*cell_id, cell))
+    }
+
+    /// Get the ids of the non-empty cells, row by row.
+    pub fn cell_ids(&self) -> Vec<CellId> {
+        let mut ids = self.cells.keys().copied().collect::<Vec<_>>();
+        ids.sort_by_key(|cell_id| (cell_id.row(), cell_id.col()));
+        ids
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    /// Set a cell to text, which is kept as text even if it looks like a
+    /// number or formula.
+    pub fn set_text(&mut self, cell_id: CellId, text: &str) {
+        self.set_cell(cell_id, escape_text(text));
+    }
+
+    /// Set a cell to a computed value, such as the result of a formula
+    /// written in its place. The raw contents are written from the value,
+    /// with numbers in the precision of the settings of this sheet.
+    pub fn set_value(&mut self, cell_id: CellId, value: Value<T>) {
+        self.set_value_with(cell_id, value, self.settings.precision);
+    }
+
+    /// Set a cell to a computed value, see `set_value`, with numbers
+    /// written in a precision such as that of a workbook.
+    pub(crate) fn set_value_with(&mut self, cell_id: CellId, value: Value<T>, precision: Precision) {
+        self.warnings.get_mut().remove(&cell_id);
+        self.frozen.remove(&cell_id);
+        match value {
+            Value::Empty => {
+                self.cells.remove(&cell_id);
+            },
+            value => {
+                self.cells.insert(cell_id, Cell::from_value_with(value, precision));
+                self.assign_row_id(cell_id.row());
+            },
+        }
+        self.forget(cell_id);
+    }
+
+    pub fn clear_cell(&mut self, cell_id: CellId) -> Option<Cell<T>> {
+        self.warnings.get_mut().remove(&cell_id);
+        self.frozen.remove(&cell_id);
+        let cell = self.cells.remove(&cell_id);
+        self.forget(cell_id);
+        cell
+    }
+
+    pub fn hooks(&self) -> &EvalHooks<T> {
+        &self.hooks
+    }
+
+    pub fn hooks_mut(&mut self) -> &mut EvalHooks<T> {
+        &mut self.hooks
+    }
+
+    pub fn sources(&self) -> &DataSources<T> {
+        &self.sources
+    }
+
+    pub fn sources_mut(&mut self) -> &mut DataSources<T> {
+        &mut self.sources
+    }
+
+    /// Get the number format code of a cell, `General` unless set.
+    pub fn number_format(&self, cell_id: CellId) -> &str {
+        self.formats.get(&cell_id).map(|code| code.as_str()).unwrap_or("General")
+    }
+
+    /// Set the number format code of a cell. Formats belong to the position
+    /// and are kept when the contents of the cell change.
+    pub fn set_number_format(&mut self, cell_id: CellId, code: &str) {
+        if code.eq_ignore_ascii_case("General") {
+            self.formats.remove(&cell_id);
+        } else {
+            self.formats.insert(cell_id, code.to_string());
+        }
+    }
+
+    /// Get the style of a cell in the styles of its workbook, None unless
+    /// set. Styles belong to the position like number formats.
+    pub fn style(&self, cell_id: CellId) -> Option<StyleId> {
+        self.styles.get(&cell_id).copied()
+    }
+
+    /// Set the style of a cell, see `Workbook::add_style`, or clear it.
+    pub fn set_style(&mut self, cell_id: CellId, style: Option<StyleId>) {
+        match style {
+            Some(style) => self.styles.insert(cell_id, style),
+            None => self.styles.remove(&cell_id),
+        };
+    }
+
+    /// Iterate the cells with a style in no particular order.
+    pub fn styled_cells(&self) -> impl Iterator<Item=(CellId, StyleId)> + '_ {
+        self.styles.iter().map(|(cell_id, style)| (*cell_id, *style))
+    }
+
+    pub(crate) fn styles_mut(&mut self) -> &mut HashMap<CellId, StyleId> {
+        &mut self.styles
+    }
+
+    pub(crate) fn style_map(&self) -> &HashMap<CellId, StyleId> {
+        &self.styles
+    }
+
+    pub(crate) fn format_map(&self) -> &HashMap<CellId, String> {
+        &self.formats
+    }
+
+    pub(crate) fn cell_map(&self) -> &HashMap<CellId, Cell<T>> {
+        &self.cells
+    }
+
+    /// Give every cell without a number format the one its contents imply,
+    /// like `0%` for `50%`, and count the cells formatted.
+    pub fn infer_number_formats(&mut self) -> usize {
+        let inferred = self.cells.iter()
+            .filter(|(cell_id, _)| !self.formats.contains_key(cell_id))
+            .filter_map(|(cell_id, cell)| infer_number_format(cell.raw(), cell.value()).map(|code| (*cell_id, code)))
+            .collect::<Vec<_>>();
+        let count = inferred.len();
+        self.formats.extend(inferred);
+        count
+    }
+
+    /// Get the text a cell displays, its value formatted with its number
+    /// format. Text is displayed as is.
+    pub fn formatted(&self, cell_id: CellId, locale: &Locale) -> Result<String, SheetError> {
+        self.formatted_through(self, cell_id, locale)
+    }
+
+    pub(crate) fn formatted_through<K>(&self, kernel: &K, cell_id: CellId, locale: &Locale) -> Result<String, SheetError>
+    where K: Kernel<SheetError, T> {
+        match self.cells.get(&cell_id) {
+            None => Ok(String::new()),
+            Some(cell) if matches!(cell.value(), Value::Raw) => Ok(cell.text().to_string()),
+            Some(_) => Ok(format_value(&self.evaluate_through(kernel, cell_id)?, self.number_format(cell_id), locale)),
+        }
+    }
+
+    /// Get the calculation settings of this sheet. Sheets in a workbook are
+    /// calculated with the settings of the workbook instead.
+    pub fn settings(&self) -> &CalcSettings {
+        &self.settings
+    }
+
+    pub fn settings_mut(&mut self) -> &mut CalcSettings {
+        &mut self.settings
+    }
+
+    /// Get the calculation chains pinning the order `calculate` takes
+    /// formulas in.
+    pub fn calc_chains(&self) -> &[CalcChain] {
+        &self.calc_chains
+    }
+
+    pub fn calc_chains_mut(&mut self) -> &mut Vec<CalcChain> {
+        &mut self.calc_chains
+    }
+
+    /// Get the row and column groups of this sheet.
+    pub fn outline(&self) -> &Outline {
+        &self.outline
+    }
+
+    pub fn outline_mut(&mut self) -> &mut Outline {
+        &mut self.outline
+    }
+
+    /// Get the sparkline groups of this sheet.
+    pub fn sparklines(&self) -> &[SparklineGroup] {
+        &self.sparklines
+    }
+
+    pub fn sparklines_mut(&mut self) -> &mut Vec<SparklineGroup> {
+        &mut self.sparklines
+    }
+
+    /// Get the IDs of the rows of this sheet, or None if rows get none, see
+    /// `set_row_ids`.
+    pub fn row_ids(&self) -> Option<&RowIds> {
+        self.row_ids.as_ref()
+    }
+
+    pub(crate) fn row_ids_mut(&mut self) -> &mut Option<RowIds> {
+        &mut self.row_ids
+    }
+
+    /// Get the formula a cell held before it was frozen into its value,
+    /// see `freeze_formulas`. Entering anything in the cell forgets it.
+    pub fn frozen_formula(&self, cell_id: CellId) -> Option<&FrozenFormula> {
+        self.frozen.get(&cell_id)
+    }
+
+    /// Iterate the frozen cells with their formulas in no particular order.
+    pub fn frozen_formulas(&self) -> impl Iterator<Item=(CellId, &FrozenFormula)> {
+        self.frozen.iter().map(|(cell_id, frozen)| (*cell_id, frozen))
+    }
+
+    pub(crate) fn frozen_mut(&mut self) -> &mut HashMap<CellId, FrozenFormula> {
+        &mut self.frozen
+    }
+
+    /// Get the rolling windows of this sheet, see `evict_windows`.
+    pub fn windows(&self) -> &[RollingWindow] {
+        &self.windows
+    }
+
+    pub fn windows_mut(&mut self) -> &mut Vec<RollingWindow> {
+        &mut self.windows
+    }
+
+    pub fn permissions(&self) -> &Permissions {
+        &self.permissions
+    }
+
+    pub fn permissions_mut(&mut self) -> &mut Permissions {
+        &mut self.permissions
+    }
+
+    /// Get the protection of this sheet and the formulas it hides.
+    pub fn protection(&self) -> &Protection {
+        &self.protection
+    }
+
+    pub fn protection_mut(&mut self) -> &mut Protection {
+        &mut self.protection
+    }
+
+    /// Share one copy of each long raw text among the cells of this sheet
+    /// holding it, and with the cells of other sheets deduplicated into the
+    /// same pool, and get the bytes this freed. Short texts are stored in
+    /// the cells already. Cells written later get copies of their own.
+    pub fn deduplicate_strings(&mut self, pool: &mut StringPool) -> usize {
+        self.cells.values_mut().map(|cell| pool.share(cell.raw_text_mut())).sum()
+    }
+
+    /// Get how the raw contents of the cells of this sheet are stored.
+    pub fn string_stats(&self) -> StringStats {
+        StringStats::of(self.cells.values().map(|cell| cell.raw_text()))
+    }
+
+    /// Get after how many evaluations a formula is compiled.
+    pub fn compile_after(&self) -> Option<u32> {
+        self.compile_after
+    }
+
+    /// Compile the formulas of this sheet to bytecode once they have been
+    /// evaluated `evaluations` times, so formulas evaluated over and over,
+    /// as in simulations, run without walking their tree. Formulas evaluated
+    /// less often, and formulas without operators or `IF`, are interpreted.
+    /// None, the default, interprets every formula.
+    pub fn set_compile_after(&mut self, evaluations: Option<u32>) {
+        self.compile_after = evaluations;
+        self.hot.get_mut().clear();
+    }
+
+    /// Get the number of formulas compiled.
+    pub fn compiled_count(&self) -> usize {
+        self.hot.borrow().values().filter(|hot| matches!(hot, Hot::Compiled(_))).count()
+    }
+
+    /// Count an evaluation of the formula of a cell, and get its program
+    /// once it is hot.
+    fn program(&self, cell_id: CellId, formula: &Formula<T>) -> Option<Rc<Program<T>>> {
+        let after = self.compile_after?;
+        let mut hot = self.hot.borrow_mut();
+        let entry = hot.entry(cell_id).or_insert(Hot::Evaluations(0));
+        match entry {
+            Hot::Evaluations(count) if *count + 1 >= after => {
+                *entry = match Program::compile(formula) {
+                    Some(program) => Hot::Compiled(Rc::new(program)),
+                    None => Hot::Interpreted,
+                };
+                match entry {
+                    Hot::Compiled(program) => Some(program.clone()),
+                    _ => None,
+                }
+            },
+            Hot::Evaluations(count) => {
+                *count += 1;
+                None
+            },
+            Hot::Compiled(program) => Some(program.clone()),
+            Hot::Interpreted => None,
+        }
+    }
+
+    /// Get where large aggregations of this sheet are offloaded to.
+    #[cfg(feature = "gpu")]
+    pub fn offload(&self) -> Option<&Offload> {
+        self.offload.as_ref()
+    }
+
+    /// Offload `SUM`s and `SUMPRODUCT`s of large dense numeric ranges to a
+    /// compute backend, or stop offloading with None. Like hooks, the
+    /// backend belongs to the embedder and is not copied with the sheet.
+    #[cfg(feature = "gpu")]
+    pub fn set_offload(&mut self, offload: Option<Offload>) {
+        self.offload = offload;
+    }
+
+    /// Whether lookups into this sheet are indexed.
+    pub fn index_lookups(&self) -> bool {
+        self.index_lookups
+    }
+
+    /// Index the columns of constants which `MATCH` and `VLOOKUP` look keys
+    /// up in, the first time they do, so later lookups into the same range
+    /// take a hash lookup, or a binary search for approximate matches into
+    /// sorted keys, instead of a scan. An index is dropped when a cell of
+    /// its range changes. Off by default, as every index costs memory and a
+    /// scan to build, which only pays off for sheets doing many lookups
+    /// into the same tables.
+    pub fn set_index_lookups(&mut self, enabled: bool) {
+        self.index_lookups = enabled;
+        if !enabled {
+            self.lookup_indexes.get_mut().clear();
+        }
+    }
+
+    /// Get the number of lookup indexes built.
+    pub fn lookup_index_count(&self) -> usize {
+        self.lookup_indexes.borrow().values().filter(|index| index.is_some()).count()
+    }
+
+    /// Get the bytes the lookup indexes and compiled formulas kept take on
+    /// the heap.
+    pub(crate) fn cache_bytes(&self) -> usize {
+        let indexes = self.lookup_indexes.borrow();
+        let indexes = map_bytes(&indexes) + indexes.values().flatten().map(|index| size_of::<LookupIndex<T>>() + index.heap_bytes()).sum::<usize>();
+        let hot = self.hot.borrow();
+        let programs = hot.values().map(|hot| match hot {
+            Hot::Compiled(program) => size_of::<Program<T>>() + program.heap_bytes(),
+            _ => 0,
+        });
+        indexes + map_bytes(&hot) + programs.sum::<usize>() + set_bytes(&self.spill_anchors)
+    }
+
+    /// Get the index of a column between two rows, building it if lookups
+    /// are indexed, or None if they are not or the column holds formulas.
+    pub(crate) fn lookup_index(&self, col: u32, first_row: u32, last_row: u32) -> Option<Rc<LookupIndex<T>>> {
+        if !self.index_lookups {
+            return None;
+        }
+        let key = (col, first_row.min(last_row), first_row.max(last_row));
+        if let Some(index) = self.lookup_indexes.borrow().get(&key) {
+            return index.clone();
+        }
+        let index = self.dense_column(col, key.1, key.2).map(|values| Rc::new(LookupIndex::new(&values)));
+        self.lookup_indexes.borrow_mut().insert(key, index.clone());
+        index
+    }
+
+    /// Drop what was cached about a cell which changed: its program, and the
+    /// lookup indexes of ranges holding it and, if it spills a dynamic
+    /// array, of those it may spill into. Columns which could not be
+    /// indexed are tried again.
+    fn forget(&mut self, cell_id: CellId) {
+        self.hot.get_mut().remove(&cell_id);
+        let spills = self.cells.get(&cell_id)
+            .is_some_and(|cell| matches!(cell.formula(), Some(Formula::Function{kind, ..}) if kind.returns_array()));
+        match spills {
+            true => self.spill_anchors.insert(cell_id),
+            false => self.spill_anchors.remove(&cell_id),
+        };
+        self.lookup_indexes.get_mut().retain(|(col, first_row, last_row), index| {
+            let inside = *col == cell_id.col() && (*first_row..=*last_row).contains(&cell_id.row());
+            let spilled = spills && cell_id.col() <= *col && cell_id.row() <= *last_row;
+            index.is_some() && !inside && !spilled
+        });
+    }
+}
+
+impl<T: Arithmetic> Worksheet<T> {
+    /// Evaluate a cell of this sheet, resolving its references through
+    /// `kernel`. This lets a workbook resolve references to other sheets.
+    pub(crate) fn evaluate_through<K>(&self, kernel: &K, cell_id: CellId) -> Result<Value<T>, SheetError>
+    where K: Kernel<SheetError, T> {
+        if !self.cells.contains_key(&cell_id) {
+            return self.spilled(kernel, cell_id).map(|value| value.unwrap_or(Value::Empty));
+        }
+        self.memoized(|| {
+            self.evaluate_precedents(kernel, cell_id);
+            Ok(match self.evaluate_formula(kernel, cell_id)? {
+                Value::Array(array) if self.is_blocked(cell_id, &array) => Value::Error(CellError::Spill),
+                Value::Array(array) => array.get(0, 0).cloned().unwrap_or(Value::Empty),
+                value => value,
+            })
+        })
+    }
+
+    /// Whether a cell holds a formula which is neither memoized nor being
+    /// evaluated.
+    fn is_pending(&self, cell_id: CellId) -> bool {
+        self.cells.get(&cell_id).is_some_and(|cell| cell.formula().is_some())
+            && !self.memo.borrow().as_ref().is_some_and(|memo| memo.contains_key(&cell_id))
+            && !self.evaluating.borrow().contains(&cell_id)
+    }
+
+    /// Get the precedents of a cell which are pending evaluation.
+    fn pending_precedents(&self, cell_id: CellId) -> Vec<CellId> {
+        let Some(formula) = self.cells.get(&cell_id).and_then(|cell| cell.formula()) else { return Vec::new() };
+        let mut precedents = formula.precedents();
+        precedents.retain(|precedent| self.is_pending(*precedent));
+        // Popped from the end, so the first precedent is evaluated first.
+        precedents.reverse();
+        precedents
+    }
+
+    /// Evaluate the formulas on this sheet a cell depends on, deepest
+    /// first, so that evaluating the cell finds them memoized rather than
+    /// recursing into each in turn. Precedents are walked with a stack of
+    /// their own, so long chains of references cannot overflow the call
+    /// stack. Errors are left for evaluating the cell to report.
+    fn evaluate_precedents<K>(&self, kernel: &K, cell_id: CellId)
+    where K: Kernel<SheetError, T> {
+        if !self.is_pending(cell_id) {
+            return;
+        }
+        let mut visited = HashSet::from([cell_id]);
+        let mut stack = vec![(cell_id, self.pending_precedents(cell_id))];
+        while let Some((current, precedents)) = stack.last_mut() {
+            match precedents.pop() {
+                Some(precedent) => {
+                    if visited.insert(precedent) && self.is_pending(precedent) {
+                        let precedents = self.pending_precedents(precedent);
+                        stack.push((precedent, precedents));
+                    }
+                },
+                None => {
+                    let current = *current;
+                    stack.pop();
+                    if current != cell_id {
+                        let _ = self.evaluate_formula(kernel, current);
+                    }
+                },
+            }
+        }
+    }
+
+    /// Get the constant values of a column between two rows, or None if the
+    /// column is sparse, holds formulas or may have a dynamic array spilled
+    /// into it, so that evaluating it cell by cell is cheaper or needed.
+    pub(crate) fn dense_column(&self, col: u32, first_row: u32, last_row: u32) -> Option<Vec<Value<T>>> {
+        let (first_row, last_row) = (first_row.min(last_row), first_row.max(last_row));
+        let rows = (last_row - first_row) as usize + 1;
+        if rows > DENSE_FACTOR * self.cells.len().max(1) {
+            return None;
+        }
+        if self.spill_anchors.iter().any(|anchor| anchor.row() <= last_row && anchor.col() <= col) {
+            return None;
+        }
+        (first_row..=last_row)
+            .map(|row| match self.cells.get(&CellId::new(row, col)) {
+                None => Some(Value::Empty),
+                Some(cell) => match cell.value() {
+                    Value::Formula(_) => None,
+                    Value::Raw => Some(Value::Primitive(Primitive::Text(cell.text().to_string()))),
+                    Value::FormulaParseError(_) => Some(Value::Error(CellError::Name)),
+                    value => Some(value.clone()),
+                },
+            })
+            .collect()
+    }
+
+    /// Whether cells a dynamic array would spill into are not empty.
+    fn is_blocked(&self, anchor: CellId, array: &Array2D<Value<T>>) -> bool {
+        let last = CellId::new(
+            anchor.row().saturating_add(array.rows() as u32 - 1),
+            anchor.col().saturating_add(array.cols() as u32 - 1),
+        );
+        CellId::range(anchor, last).any(|cell_id| cell_id != anchor && self.cells.contains_key(&cell_id))
+    }
+
+    /// Get the value a dynamic array spills into an empty cell, if any. The
+    /// formula spilling must be above and left of the cell, and may produce
+    /// an array only if its outermost function does.
+    fn spilled<K>(&self, kernel: &K, cell_id: CellId) -> Result<Option<Value<T>>, SheetError>
+    where K: Kernel<SheetError, T> {
+        let anchors = self.spill_anchors.iter()
+            .filter(|anchor| anchor.row() <= cell_id.row() && anchor.col() <= cell_id.col())
+            .copied()
+            .collect::<Vec<_>>();
+        for anchor in anchors {
+            // A formula cannot spill into a cell it is evaluating.
+            if self.evaluating.borrow().contains(&anchor) {
+                continue;
+            }
+            if let Value::Array(array) = self.evaluate_formula(kernel, anchor)? {
+                let value = array.get((cell_id.row() - anchor.row()) as usize, (cell_id.col() - anchor.col()) as usize);
+                if let Some(value) = value {
+                    if !self.is_blocked(anchor, &array) {
+                        return Ok(Some(value.clone()));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Evaluate a cell, keeping the dynamic array a formula produces as is.
+    pub(crate) fn evaluate_formula<K>(&self, kernel: &K, cell_id: CellId) -> Result<Value<T>, SheetError>
+    where K: Kernel<SheetError, T> {
+        let formula = match self.cells.get(&cell_id) {
+            None => return Ok(Value::Empty),
+            Some(cell) => match cell.formula() {
+                Some(formula) => formula,
+                None => return Evaluator::new(kernel).with_settings(kernel.calc_settings()).evaluate_value(cell.value()),
+            },
+        };
+
+        if let Some(value) = self.memo.borrow().as_ref().and_then(|memo| memo.get(&cell_id)) {
+            return Ok(value.clone());
+        }
+        if !self.evaluating.borrow_mut().insert(cell_id) {
+            return Err(SheetError::CircularReference(cell_id));
+        }
+        let events = RefCell::new(Vec::new());
+        let mut evaluator = Evaluator::new(kernel)
+            .with_settings(kernel.calc_settings())
+            .with_hooks(&self.hooks)
+            .with_sources(&self.sources);
+        #[cfg(feature = "gpu")]
+        if let Some(offload) = &self.offload {
+            evaluator = evaluator.with_offload(offload);
+        }
+        let recording = self.recording.borrow().is_some();
+        let program = self.program(cell_id, formula);
+        if recording {
+            evaluator = evaluator.with_recording(&events);
+        } else if let Some(program) = &program {
+            evaluator = evaluator.with_program(program);
+        }
+        let result = evaluator.evaluate_cell(cell_id, formula);
+        if let Some(recording) = self.recording.borrow_mut().as_mut() {
+            recording.insert(cell_id, events.take());
+        }
+        let warnings = evaluator.take_warnings();
+        match warnings.is_empty() {
+            true => self.warnings.borrow_mut().remove(&cell_id),
+            false => self.warnings.borrow_mut().insert(cell_id, warnings),
+        };
+        self.evaluating.borrow_mut().remove(&cell_id);
+        if let (Ok(value), Some(memo)) = (&result, self.memo.borrow_mut().as_mut()) {
+            memo.insert(cell_id, value.clone());
+        }
+        result
+    }
+
+    /// Run `f` recording every step of the formulas it evaluates on this
+    /// sheet, such as a recalculation, to inspect how a cell got its value.
+    /// Each formula is evaluated at most once while recording, and nested
+    /// calls leave the steps to the outermost.
+    pub fn record<R>(&self, f: impl FnOnce() -> R) -> (R, Recording<T>) {
+        let outermost = self.recording.borrow().is_none();
+        if outermost {
+            *self.recording.borrow_mut() = Some(Recording::new());
+        }
+        let result = self.memoized(f);
+        let recording = match outermost {
+            true => self.recording.borrow_mut().take().unwrap_or_default(),
+            false => Recording::new(),
+        };
+        (result, recording)
+    }
+
+    /// Get the warnings found the last time a cell was evaluated.
+    pub fn cell_warnings(&self, cell_id: CellId) -> Vec<CalcWarning> {
+        self.warnings.borrow().get(&cell_id).cloned().unwrap_or_default()
+    }
+
+    /// Get the warnings of every formula as last evaluated, row by row. After
+    /// a recalculation these are the warnings of that recalculation.
+    pub fn warnings(&self) -> Vec<(CellId, CalcWarning)> {
+        let mut warnings = self.warnings.borrow().iter()
+            .flat_map(|(cell_id, warnings)| warnings.iter().map(|warning| (*cell_id, warning.clone())))
+            .collect::<Vec<_>>();
+        warnings.sort_by_key(|(cell_id, _)| (cell_id.row(), cell_id.col()));
+        warnings
+    }
+
+    pub fn clear_warnings(&self) {
+        self.warnings.borrow_mut().clear();
+    }
+
+    /// Run `f` remembering the result of every formula it evaluates on this
+    /// sheet, so each formula is evaluated at most once. Cells must not
+    /// change while `f` runs.
+    pub(crate) fn memoized<R>(&self, f: impl FnOnce() -> R) -> R {
+        let outermost = self.memo.borrow().is_none();
+        if outermost {
+            *self.memo.borrow_mut() = Some(HashMap::new());
+        }
+        let result = f();
+        if outermost {
+            *self.memo.borrow_mut() = None;
+        }
+        result
+    }
+}
+
+impl<T: Arithmetic> Kernel<SheetError, T> for Worksheet<T> {
+    fn get_cell(&self, cell_id: CellId) -> Option<Cell<T>> {
+        self.cells.get(&cell_id).cloned()
+    }
+
+    fn evaluate_cell(&self, cell_id: CellId) -> Result<Value<T>, SheetError> {
+        self.evaluate_through(self, cell_id)
+    }
+
+    fn calc_settings(&self) -> CalcSettings {
+        self.settings.clone()
+    }
+
+    fn is_row_hidden(&self, sheet: Option<&str>, row: u32) -> bool {
+        sheet.is_none() && self.outline.is_row_hidden(row)
+    }
+
+    /// Evaluate a range, evaluating formulas its cells share as precedents
+    /// only once.
+    fn evaluate_range(&self, first: CellId, second: CellId) -> Result<Array2D<Value<T>>, SheetError> {
+        self.memoized(|| evaluate_rectangle(first, second, |cell_id| self.evaluate_cell(cell_id)))
+    }
+
+    fn column_slice(&self, sheet: Option<&str>, col: u32, first_row: u32, last_row: u32) -> Option<Cow<'_, [Value<T>]>> {
+        match sheet {
+            Some(_) => None,
+            None => self.dense_column(col, first_row, last_row).map(Cow::Owned),
+        }
+    }
+
+    fn lookup_index(&self, sheet: Option<&str>, col: u32, first_row: u32, last_row: u32) -> Option<Rc<LookupIndex<T>>> {
+        match sheet {
+            Some(_) => None,
+            None => Worksheet::lookup_index(self, col, first_row, last_row),
+        }
+    }
+
+    fn set_cell(&mut self, cell_id: CellId, data: String) {
+        self.warnings.get_mut().remove(&cell_id);
+        self.frozen.remove(&cell_id);
+        if data.trim().is_empty() {
+            self.cells.remove(&cell_id);
+        } else {
+            self.cells.insert(cell_id, Cell::from(data));
+            self.assign_row_id(cell_id.row());
+        }
+        self.forget(cell_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::kernel::Primitive;
+
+    fn number(value: Value<f64>) -> Option<f64> {
+        match value {
+            Value::Primitive(Primitive::Number(number)) => Some(number.value()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn formulas_evaluate_through_their_references() {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), "2".to_string());
+        sheet.set_cell(CellId::new(0, 1), "=A1*3".to_string());
+        sheet.set_cell(CellId::new(0, 2), "=B1+A1".to_string());
+        assert_eq!(number(sheet.evaluate_cell(CellId::new(0, 2)).unwrap()), Some(8.0));
+        assert!(matches!(sheet.evaluate_cell(CellId::new(5, 5)), Ok(Value::Empty)));
+        assert_eq!(sheet.cell_ids(), vec![CellId::new(0, 0), CellId::new(0, 1), CellId::new(0, 2)]);
+    }
+
+    #[test]
+    fn circular_references_are_errors() {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), "=B1".to_string());
+        sheet.set_cell(CellId::new(0, 1), "=A1+1".to_string());
+        assert!(matches!(sheet.evaluate_cell(CellId::new(0, 0)), Err(SheetError::CircularReference(_))));
+        sheet.set_cell(CellId::new(0, 1), "1".to_string());
+        assert_eq!(number(sheet.evaluate_cell(CellId::new(0, 0)).unwrap()), Some(1.0));
+    }
+
+    #[test]
+    fn long_chains_of_references_evaluate() {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), "1".to_string());
+        for row in 1..10_000 {
+            sheet.set_cell(CellId::new(row, 0), format!("=A{}+1", row));
+        }
+        assert_eq!(number(sheet.evaluate_cell(CellId::new(9_999, 0)).unwrap()), Some(10_000.0));
+        sheet.set_cell(CellId::new(10_000, 0), "=SUM(A1:A10000)".to_string());
+        assert_eq!(number(sheet.evaluate_cell(CellId::new(10_000, 0)).unwrap()), Some(50_005_000.0));
+    }
+
+    #[test]
+    fn dynamic_arrays_spill_into_empty_cells() {
+        let mut sheet: Worksheet = Worksheet::new();
+        for (row, data) in ["1", "2", "3"].iter().enumerate() {
+            sheet.set_cell(CellId::new(row as u32, 0), data.to_string());
+            sheet.set_cell(CellId::new(row as u32, 1), format!("={}*10", data));
+        }
+        sheet.set_cell(CellId::new(0, 3), "=VSTACK(TAKE(A1:B3,-1),CHOOSECOLS(A1:B3,2,1))".to_string());
+        let at = |sheet: &Worksheet, row, col| number(sheet.evaluate_cell(CellId::new(row, col)).unwrap());
+        assert_eq!(at(&sheet, 0, 3), Some(3.0));
+        assert_eq!(at(&sheet, 0, 4), Some(30.0));
+        assert_eq!(at(&sheet, 1, 3), Some(10.0));
+        assert_eq!(at(&sheet, 3, 4), Some(3.0));
+        assert!(matches!(sheet.evaluate_cell(CellId::new(4, 3)), Ok(Value::Empty)));
+        sheet.set_cell(CellId::new(0, 6), "=CHOOSE(2,A1,DROP(A1:A3,1))".to_string());
+        assert_eq!(at(&sheet, 1, 6), Some(3.0));
+        sheet.set_cell(CellId::new(1, 6), "x".to_string());
+        assert!(matches!(sheet.evaluate_cell(CellId::new(0, 6)), Ok(Value::Error(CellError::Spill))));
+        sheet.set_cell(CellId::new(5, 0), "=HSTACK(A1,A1:A2)".to_string());
+        assert!(matches!(sheet.evaluate_cell(CellId::new(6, 0)), Ok(Value::Error(CellError::NA))));
+    }
+
+    #[test]
+    fn inferred_formats_leave_formatted_cells_alone() {
+        let mut sheet: Worksheet = Worksheet::new();
+        sheet.set_cell(CellId::new(0, 0), "50%".to_string());
+        sheet.set_cell(CellId::new(1, 0), "25%".to_string());
+        sheet.set_cell(CellId::new(2, 0), "7".to_string());
+        sheet.set_number_format(CellId::new(1, 0), "0.000%");
+        assert_eq!(sheet.infer_number_formats(), 1);
+        assert_eq!(sheet.number_format(CellId::new(0, 0)), "0%");
+        assert_eq!(sheet.number_format(CellId::new(1, 0)), "0.000%");
+        assert_eq!(sheet.number_format(CellId::new(2, 0)), "General");
+    }
+
+    #[test]
+    fn dense_columns_track_spill_anchors() {
+        let mut sheet: Worksheet = Worksheet::default();
+        sheet.set_cell(CellId::new(0, 3), "=SUM(B1:B3)".to_string());
+        sheet.set_cell(CellId::new(0, 1), "=VSTACK(10,20,30)".to_string());
+        assert!(sheet.dense_column(1, 0, 2).is_none());
+        assert_eq!(number(sheet.evaluate_cell(CellId::new(0, 3)).unwrap()), Some(60.0));
+        assert!(sheet.duplicate().dense_column(1, 0, 2).is_none());
+
+        sheet.set_cell(CellId::new(0, 1), "5".to_string());
+        assert_eq!(sheet.dense_column(1, 0, 2).map(|values| values.len()), Some(3));
+        assert_eq!(number(sheet.evaluate_cell(CellId::new(0, 3)).unwrap()), Some(5.0));
+
+        sheet.set_cell(CellId::new(0, 0), "=VSTACK(1,2)".to_string());
+        assert!(sheet.dense_column(1, 0, 2).is_none());
+        sheet.clear_cell(CellId::new(0, 0));
+        assert!(sheet.dense_column(1, 0, 2).is_some());
+    }
+}
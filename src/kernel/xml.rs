@@ -0,0 +1,59 @@
+/// Tag is an element tag of an XML document, as far as themes and package
+/// signatures need.
+pub(crate) struct Tag<'a> {
+    /// The name without its namespace prefix.
+    pub(crate) name: &'a str,
+    pub(crate) attributes: &'a str,
+    pub(crate) closing: bool,
+    pub(crate) self_closing: bool,
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+}
+
+impl Tag<'_> {
+    pub(crate) fn attribute(&self, name: &str) -> Option<String> {
+        let mut rest = self.attributes;
+        while let Some(equals) = rest.find('=') {
+            let key = rest[..equals].trim();
+            let value = rest[equals + 1..].trim_start();
+            let quote = value.chars().next()?;
+            let value = &value[1..];
+            let close = value.find(quote)?;
+            if key == name {
+                return Some(unescape_xml(&value[..close]));
+            }
+            rest = &value[close + 1..];
+        }
+        None
+    }
+}
+
+/// Split an XML document into its element tags, skipping declarations and
+/// comments.
+pub(crate) fn tags(xml: &str) -> Vec<Tag<'_>> {
+    let mut tags = Vec::new();
+    let mut offset = 0;
+    while let Some(open) = xml[offset..].find('<').map(|open| offset + open) {
+        let Some(close) = xml[open..].find('>').map(|close| open + close) else { break };
+        offset = close + 1;
+        let inner = &xml[open + 1..close];
+        if inner.starts_with('?') || inner.starts_with('!') {
+            continue;
+        }
+        let closing = inner.starts_with('/');
+        let self_closing = inner.ends_with('/');
+        let inner = inner.trim_start_matches('/').trim_end_matches('/');
+        let (name, attributes) = inner.split_once(char::is_whitespace).unwrap_or((inner, ""));
+        let name = name.rsplit(':').next().unwrap_or(name);
+        tags.push(Tag{name, attributes, closing, self_closing, start: open, end: close + 1});
+    }
+    tags
+}
+
+pub(crate) fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
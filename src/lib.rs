@@ -1 +1,11 @@
 pub mod kernel;
+
+/// prelude re-exports the types most programs working with workbooks need,
+/// for a single `use xlnt::prelude::*`.
+pub mod prelude;
+
+/// testing generates random formulas, cell contents and workbooks, and
+/// checks that they survive round trips, for property tests of code built
+/// on this crate.
+#[cfg(feature = "testing")]
+pub mod testing;
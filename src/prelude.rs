@@ -0,0 +1,6 @@
+pub use crate::kernel::builder::{CellData, SheetBuilder, WorkbookBuilder};
+pub use crate::kernel::format::Locale;
+pub use crate::kernel::kernel::{Cell, CellError, CellId, Formula, Kernel, Numeric, Primitive, Value};
+pub use crate::kernel::settings::CalcSettings;
+pub use crate::kernel::workbook::{Workbook, WorkbookError};
+pub use crate::kernel::worksheet::{SheetError, Worksheet};
@@ -0,0 +1,362 @@
+use crate::kernel::arithmetic::{Arithmetic, Floating};
+use crate::kernel::autosave::{load_autosave, save_workbook};
+use crate::kernel::journal::JournalError;
+use crate::kernel::kernel::{escape_text, Cell, CellId, Formula, FunctionKind, Kernel, Numeric, NumericAttribute, Value};
+use crate::kernel::workbook::{Workbook, WorkbookError};
+use thiserror::Error;
+use std::path::Path;
+
+#[derive(Error, Debug)]
+pub enum RoundTripError {
+    #[error("{text} does not parse: {message}")]
+    Parse{text: String, message: String},
+
+    /// Writing what was read back gave other text than was first written.
+    #[error("{first} came back as {second}")]
+    Unstable{first: String, second: String},
+
+    #[error("{sheet}!{cell_id} was {before:?} before saving and {after:?} after loading")]
+    Cell{sheet: String, cell_id: CellId, before: Option<String>, after: Option<String>},
+
+    #[error("the sheets were {before:?} before saving and {after:?} after loading")]
+    Sheets{before: Vec<String>, after: Vec<String>},
+
+    #[error(transparent)]
+    Workbook(#[from] WorkbookError),
+
+    #[error(transparent)]
+    Journal(#[from] JournalError),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// The functions generated formulas call, with the fewest and most
+/// arguments they are given.
+const FUNCTIONS: [(FunctionKind, usize, usize); 12] = [
+    (FunctionKind::Sum, 1, 4),
+    (FunctionKind::Average, 1, 3),
+    (FunctionKind::Count, 1, 3),
+    (FunctionKind::Prod, 1, 3),
+    (FunctionKind::If, 2, 3),
+    (FunctionKind::Sqrt, 1, 1),
+    (FunctionKind::Text, 2, 2),
+    (FunctionKind::Proper, 1, 1),
+    (FunctionKind::Exact, 2, 2),
+    (FunctionKind::Rept, 2, 2),
+    (FunctionKind::Char, 1, 1),
+    (FunctionKind::Code, 1, 1),
+];
+
+const SHEETS: [&str; 3] = ["Sheet1", "Data", "Q1 Results"];
+
+/// GeneratorOptions bounds what a `Generator` produces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GeneratorOptions {
+    /// The deepest formulas nest operators and function calls.
+    pub max_depth: usize,
+    /// The rows and columns generated references and cells fall within.
+    pub rows: u32,
+    pub cols: u32,
+    /// The most sheets and cells per sheet of generated workbooks.
+    pub max_sheets: usize,
+    pub max_cells: usize,
+}
+
+impl Default for GeneratorOptions {
+    fn default() -> Self {
+        Self{max_depth: 4, rows: 100, cols: 26, max_sheets: 3, max_cells: 50}
+    }
+}
+
+/// Generator produces random valid cell ids, numbers, formulas, cell
+/// contents and workbooks from a seed, to property-test code working with
+/// them. The same seed always produces the same values.
+#[derive(Debug, Clone)]
+pub struct Generator {
+    state: u64,
+    options: GeneratorOptions,
+}
+
+impl Generator {
+    pub fn new(seed: u64) -> Self {
+        Self{state: seed, options: GeneratorOptions::default()}
+    }
+
+    pub fn with_options(mut self, options: GeneratorOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Get the next random number, by splitmix64.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Get a random number from `low` to `high`, both included.
+    pub fn between(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next_u64() % (high - low + 1)
+    }
+
+    fn chance(&mut self, one_in: u64) -> bool {
+        self.next_u64().is_multiple_of(one_in)
+    }
+
+    fn pick<'a, X>(&mut self, items: &'a [X]) -> &'a X {
+        &items[self.between(0, items.len() as u64 - 1) as usize]
+    }
+
+    pub fn cell_id(&mut self) -> CellId {
+        CellId::new(self.between(0, self.options.rows as u64 - 1) as u32, self.between(0, self.options.cols as u64 - 1) as u32)
+    }
+
+    /// Get a non-negative number of up to four decimals, as percent now and
+    /// then.
+    pub fn numeric<T: Arithmetic>(&mut self) -> Numeric<T> {
+        let number = match self.between(0, 3) {
+            0 => self.between(0, 10) as f64,
+            1 => self.between(0, 100000) as f64,
+            _ => self.between(0, 10000000) as f64 / 10000.0,
+        };
+        let attr = self.chance(8).then_some(NumericAttribute::Percent);
+        Numeric::new(Floating::from_f64(number), attr)
+    }
+
+    /// Get short text, with quotes and spaces now and then.
+    pub fn text(&mut self) -> String {
+        let len = self.between(0, 8);
+        (0..len).map(|_| *self.pick(&['a', 'b', 'x', 'Z', ' ', '"', '1', 'é'])).collect()
+    }
+
+    /// Get a formula of up to the maximum depth.
+    pub fn formula<T: Arithmetic>(&mut self) -> Formula<T> {
+        self.formula_of_depth(self.options.max_depth)
+    }
+
+    fn operand<T: Arithmetic>(&mut self, depth: usize) -> Box<Value<T>> {
+        Box::new(Value::Formula(self.formula_of_depth(depth)))
+    }
+
+    fn formula_of_depth<T: Arithmetic>(&mut self, depth: usize) -> Formula<T> {
+        if depth == 0 || self.chance(3) {
+            return match self.between(0, 6) {
+                0 => Formula::NumberLit(self.numeric()),
+                1 => Formula::TextLit(self.text()),
+                2 => Formula::BoolLit(self.chance(2)),
+                3 => {
+                    let (a, b) = (self.cell_id(), self.cell_id());
+                    let start = CellId::new(a.row().min(b.row()), a.col().min(b.col()));
+                    let end = CellId::new(a.row().max(b.row()), a.col().max(b.col()));
+                    Formula::CellRange(start, end)
+                },
+                4 => Formula::SheetRef(self.pick(&SHEETS).to_string(), Box::new(Formula::CellRef(self.cell_id()))),
+                5 => Formula::Name(format!("n_{}", self.between(0, 999))),
+                _ => Formula::CellRef(self.cell_id()),
+            };
+        }
+        let depth = depth - 1;
+        match self.between(0, 16) {
+            0 => Formula::Add(self.operand(depth), self.operand(depth)),
+            1 => Formula::Sub(self.operand(depth), self.operand(depth)),
+            2 => Formula::Mul(self.operand(depth), self.operand(depth)),
+            3 => Formula::Div(self.operand(depth), self.operand(depth)),
+            4 => Formula::Pow(self.operand(depth), self.operand(depth)),
+            5 => Formula::Neg(self.operand(depth)),
+            6 => Formula::Percent(self.operand(depth)),
+            7 => Formula::Concat(self.operand(depth), self.operand(depth)),
+            8 => Formula::Cmp(self.operand(depth), self.operand(depth)),
+            9 => Formula::Lt(self.operand(depth), self.operand(depth)),
+            10 => Formula::Ge(self.operand(depth), self.operand(depth)),
+            11 => Formula::Ne(self.operand(depth), self.operand(depth)),
+            12 => {
+                let arguments = (0..self.between(0, 2)).map(|_| *self.operand(depth)).collect();
+                Formula::Custom{name: format!("MYORG.F{}", self.between(0, 9)), arguments}
+            },
+            _ => {
+                let (kind, min, max) = *self.pick(&FUNCTIONS);
+                let arguments = (0..self.between(min as u64, max as u64)).map(|_| *self.operand(depth)).collect();
+                Formula::Function{kind, arguments}
+            },
+        }
+    }
+
+    /// Get the raw contents of a cell: a number, a boolean, text or a
+    /// formula, never empty.
+    pub fn raw<T: Arithmetic>(&mut self) -> String {
+        match self.between(0, 3) {
+            0 => Formula::NumberLit(self.numeric::<T>()).to_string(),
+            1 => if self.chance(2) { "TRUE" } else { "FALSE" }.to_string(),
+            2 => match escape_text(&self.text()) {
+                text if text.is_empty() => "'".to_string(),
+                text => text,
+            },
+            _ => format!("={}", self.formula::<T>()),
+        }
+    }
+
+    /// Get a workbook of up to the maximum sheets and cells.
+    pub fn workbook<T: Arithmetic>(&mut self) -> Workbook<T> {
+        let mut workbook = Workbook::new();
+        for name in &SHEETS[..self.between(1, self.options.max_sheets.clamp(1, SHEETS.len()) as u64) as usize] {
+            let cells = self.between(0, self.options.max_cells as u64);
+            let sheet = workbook.add_sheet(name).expect("generated sheet names are distinct");
+            for _ in 0..cells {
+                let cell_id = self.cell_id();
+                sheet.set_cell(cell_id, self.raw::<T>());
+            }
+        }
+        workbook
+    }
+}
+
+/// Counterexample is a case a property failed for, with the seed which
+/// generates it again.
+#[derive(Debug)]
+pub struct Counterexample {
+    pub case: usize,
+    pub seed: u64,
+    pub error: RoundTripError,
+}
+
+/// Check a property for `cases` generators seeded from `seed`, stopping at
+/// the first case it fails for.
+pub fn check(seed: u64, cases: usize, mut property: impl FnMut(&mut Generator) -> Result<(), RoundTripError>) -> Result<(), Counterexample> {
+    let mut seeds = Generator::new(seed);
+    for case in 0..cases {
+        let seed = seeds.next_u64();
+        property(&mut Generator::new(seed)).map_err(|error| Counterexample{case, seed, error})?;
+    }
+    Ok(())
+}
+
+/// Check that a formula is written as text which parses back into a formula
+/// written as the same text.
+pub fn formula_round_trip<T: Arithmetic>(formula: &Formula<T>) -> Result<(), RoundTripError> {
+    let first = formula.to_string();
+    let parsed = Formula::<T>::try_from(first.as_str())
+        .map_err(|e| RoundTripError::Parse{text: first.clone(), message: e.to_string()})?;
+    let second = parsed.to_string();
+    if first != second {
+        return Err(RoundTripError::Unstable{first, second});
+    }
+    Ok(())
+}
+
+/// Check that the raw contents of a cell parse into a value whose raw
+/// contents parse into the same value again.
+pub fn raw_round_trip<T: Arithmetic>(raw: &str) -> Result<(), RoundTripError> {
+    let mut cell = Cell::<T>::from(raw.to_string());
+    cell.refresh_raw();
+    let first = cell.raw().to_string();
+    let mut again = Cell::<T>::from(first.clone());
+    again.refresh_raw();
+    let second = again.raw().to_string();
+    if first != second {
+        return Err(RoundTripError::Unstable{first, second});
+    }
+    Ok(())
+}
+
+/// Check that a workbook saved to a directory loads back with the same
+/// sheets and cells, and saves again to the same files.
+pub fn save_round_trip<T: Arithmetic>(workbook: &Workbook<T>, dir: &Path) -> Result<(), RoundTripError> {
+    let (first, second) = (dir.join("first"), dir.join("second"));
+    save_workbook(workbook, &first, 0)?;
+    let (loaded, _) = load_autosave::<T>(&first)?;
+    let (before, after) = (workbook.sheet_names().collect::<Vec<_>>(), loaded.sheet_names().collect::<Vec<_>>());
+    if before != after {
+        return Err(RoundTripError::Sheets{
+            before: before.into_iter().map(String::from).collect(),
+            after: after.into_iter().map(String::from).collect(),
+        });
+    }
+    for (name, sheet) in workbook.sheets() {
+        let other = loaded.sheet(name).expect("the sheets are the same");
+        let mut cell_ids = sheet.cell_ids();
+        cell_ids.extend(other.cell_ids());
+        cell_ids.sort_by_key(|cell_id| (cell_id.row(), cell_id.col()));
+        cell_ids.dedup();
+        for cell_id in cell_ids {
+            let before = sheet.cell(cell_id).map(|cell| cell.raw().to_string());
+            let after = other.cell(cell_id).map(|cell| cell.raw().to_string());
+            if before != after {
+                return Err(RoundTripError::Cell{sheet: name.to_string(), cell_id, before, after});
+            }
+        }
+    }
+    save_workbook(&loaded, &second, 0)?;
+    let mut files = std::fs::read_dir(&first)?.map(|entry| entry.map(|entry| entry.file_name())).collect::<Result<Vec<_>, _>>()?;
+    files.sort();
+    for file in files {
+        let (a, b) = (std::fs::read(first.join(&file))?, std::fs::read(second.join(&file))?);
+        if a != b {
+            return Err(RoundTripError::Unstable{
+                first: String::from_utf8_lossy(&a).into_owned(),
+                second: String::from_utf8_lossy(&b).into_owned(),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generators_are_deterministic_and_bounded() {
+        let options = GeneratorOptions{rows: 5, cols: 3, ..GeneratorOptions::default()};
+        let (mut a, mut b) = (Generator::new(42).with_options(options), Generator::new(42).with_options(options));
+        for _ in 0..200 {
+            assert_eq!(a.next_u64(), b.next_u64());
+            let between = a.between(3, 7);
+            assert!((3..=7).contains(&between));
+            assert_eq!(between, b.between(3, 7));
+            let cell_id = a.cell_id();
+            assert!(cell_id.row() < 5 && cell_id.col() < 3);
+            assert_eq!(cell_id, b.cell_id());
+            assert_eq!(a.raw::<f64>(), b.raw::<f64>());
+        }
+        assert_ne!(Generator::new(1).next_u64(), Generator::new(2).next_u64());
+    }
+
+    #[test]
+    fn generated_formulas_and_contents_round_trip() {
+        let result = check(7, 500, |generator| {
+            formula_round_trip(&generator.formula::<f64>())?;
+            raw_round_trip::<f64>(&generator.raw::<f64>())
+        });
+        if let Err(counterexample) = result {
+            panic!("case {} (seed {}): {}", counterexample.case, counterexample.seed, counterexample.error);
+        }
+    }
+
+    #[test]
+    fn generated_workbooks_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("xlnt-testing-{}", std::process::id()));
+        let result = check(11, 5, |generator| {
+            let _ = std::fs::remove_dir_all(&dir);
+            save_round_trip(&generator.workbook::<f64>(), &dir)
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+        if let Err(counterexample) = result {
+            panic!("case {} (seed {}): {}", counterexample.case, counterexample.seed, counterexample.error);
+        }
+    }
+
+    #[test]
+    fn check_reports_a_reproducible_counterexample() {
+        let failing = |generator: &mut Generator| match generator.between(0, 9) {
+            3 => Err(RoundTripError::Unstable{first: "a".to_string(), second: "b".to_string()}),
+            _ => Ok(()),
+        };
+        let counterexample = check(5, 1000, failing).unwrap_err();
+        assert_eq!(Generator::new(counterexample.seed).between(0, 9), 3);
+        assert!(check(5, counterexample.case, failing).is_ok());
+        assert!(matches!(formula_round_trip(&Formula::<f64>::Name("1x".to_string())), Err(RoundTripError::Parse{..} | RoundTripError::Unstable{..})));
+    }
+}